@@ -17,6 +17,16 @@ pub struct Resolver {
     buf: Vec<u8>,
     qnum: u16,
     timeout: Duration,
+    family: Family,
+}
+
+/// Address families which queries are resolved to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Family {
+    /// Try A records, then AAAA records
+    Any,
+    V4,
+    V6,
 }
 
 struct Query {
@@ -55,6 +65,7 @@ impl Resolver {
             timeout: Duration::from_secs(3),
             buf,
             qnum: 0,
+            family: Family::Any,
         }
     }
 
@@ -62,6 +73,13 @@ impl Resolver {
         self.cache.clear();
     }
 
+    /// Restricts resolution to a single address family, clearing
+    /// any cached results.
+    pub fn set_family(&mut self, family: Family) {
+        self.family = family;
+        self.cache.clear();
+    }
+
     pub fn from_resolv() -> io::Result<Resolver> {
         let buf = vec![0u8; 512];
         let mut conf = Vec::with_capacity(4096);
@@ -95,6 +113,7 @@ impl Resolver {
             timeout: Duration::from_secs(cfg.timeout as u64),
             buf,
             qnum: 0,
+            family: Family::Any,
         })
     }
 
@@ -114,14 +133,26 @@ impl Resolver {
         if let Some(entry) = self.cache.get(domain) {
             return Ok(Some(entry.ip));
         }
-        if let Ok(entry) = domain.parse() {
-            return Ok(Some(entry));
+        if let Ok(entry) = domain.parse::<IpAddr>() {
+            return match (self.family, entry) {
+                (Family::V4, IpAddr::V6(_)) | (Family::V6, IpAddr::V4(_)) => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Address family is disabled",
+                )),
+                _ => Ok(Some(entry)),
+            };
         }
         if self.responses.get(domain).is_none() {
             let qn = self.qnum;
             self.qnum = self.qnum.wrapping_add(1);
+            let v4 = self.family != Family::V6;
+            let qtype = if v4 {
+                dns_parser::QueryType::A
+            } else {
+                dns_parser::QueryType::AAAA
+            };
             let mut query = dns_parser::Builder::new_query(qn, true);
-            query.add_question(domain, dns_parser::QueryType::A, dns_parser::QueryClass::IN);
+            query.add_question(domain, qtype, dns_parser::QueryClass::IN);
             let packet = query.build().unwrap_or_else(|d| d);
             sock.send_to(&packet, self.servers[0])?;
 
@@ -130,7 +161,7 @@ impl Resolver {
             self.queries.insert(
                 qn,
                 Query {
-                    v4: true,
+                    v4,
                     server: 0,
                     domain: domain.to_string(),
                     deadline: now + self.timeout,
@@ -158,7 +189,7 @@ impl Resolver {
                             let now = Instant::now();
                             for answer in packet.answers {
                                 match answer.data {
-                                    dns_parser::RRData::A(addr) => {
+                                    dns_parser::RRData::A(addr) if self.family != Family::V6 => {
                                         for id in self.responses.remove(&q.domain).unwrap() {
                                             f(Response {
                                                 id,
@@ -175,7 +206,7 @@ impl Resolver {
                                         );
                                         continue 'process;
                                     }
-                                    dns_parser::RRData::AAAA(addr) => {
+                                    dns_parser::RRData::AAAA(addr) if self.family != Family::V4 => {
                                         for id in self.responses.remove(&q.domain).unwrap() {
                                             f(Response {
                                                 id,
@@ -195,7 +226,7 @@ impl Resolver {
                                     _ => continue,
                                 }
                             }
-                            let pkt = q.next(qn);
+                            let pkt = q.next(qn, self.family);
                             if q.server != self.servers.len() {
                                 sock.send_to(&pkt, self.servers[q.server])?;
                                 self.queries.insert(qn, q);
@@ -226,6 +257,7 @@ impl Resolver {
         let now = Instant::now();
         let responses = &mut self.responses;
         let servers = &self.servers;
+        let family = self.family;
         let mut res = Ok(());
         self.cache.retain(|_, entry| now < entry.deadline);
         self.queries.retain(|qn, query| {
@@ -238,7 +270,7 @@ impl Resolver {
                         });
                     }
                 } else {
-                    let pkt = query.next(*qn);
+                    let pkt = query.next(*qn, family);
                     if query.server != servers.len() {
                         res = sock.send_to(&pkt, servers[query.server]).map(|_| ());
                         return true;
@@ -261,10 +293,14 @@ impl Resolver {
 }
 
 impl Query {
-    pub fn next(&mut self, qn: u16) -> Vec<u8> {
+    pub fn next(&mut self, qn: u16, family: Family) -> Vec<u8> {
         self.query_deadline = Instant::now() + Duration::from_millis(QUERY_TIMEOUT_MS);
-        if self.v4 {
-            self.v4 = false;
+        if family == Family::V6 || (family == Family::Any && self.v4) {
+            if self.v4 {
+                self.v4 = false;
+            } else {
+                self.server += 1;
+            }
             let mut query = dns_parser::Builder::new_query(qn, true);
             query.add_question(
                 &self.domain,
@@ -337,4 +373,18 @@ mod tests {
         #[cfg(not(target_os = "macos"))]
         assert!(processed);
     }
+
+    #[test]
+    fn test_family_literal() {
+        let mut resolver = Resolver::new(&["127.0.0.1:53".parse().unwrap()]);
+        let mut sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        resolver.set_family(Family::V4);
+        assert_eq!(
+            resolver.query(&mut sock, 0, "127.0.0.1").unwrap(),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        assert!(resolver.query(&mut sock, 0, "::1").is_err());
+        resolver.set_family(Family::V6);
+        assert!(resolver.query(&mut sock, 0, "127.0.0.1").is_err());
+    }
 }
@@ -3,7 +3,9 @@ use std::borrow::Cow;
 use chrono::{DateTime, Utc};
 
 use super::criterion::Criterion;
-use super::resource::{CResourceUpdate, ResourceKind, SResourceUpdate};
+use super::resource::{
+    Allocation, CResourceUpdate, ResourceKind, SResourceUpdate, Status, Torrent,
+};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Version {
@@ -61,6 +63,10 @@ pub enum CMessage {
         start: bool,
         #[serde(default = "default_false")]
         import: bool,
+        #[serde(default)]
+        files: Option<Vec<u32>>,
+        #[serde(default)]
+        allocation: Option<Allocation>,
     },
     UploadMagnet {
         serial: u64,
@@ -68,6 +74,8 @@ pub enum CMessage {
         path: Option<String>,
         #[serde(default = "default_true")]
         start: bool,
+        #[serde(default)]
+        allocation: Option<Allocation>,
     },
     UploadFiles {
         serial: u64,
@@ -86,6 +94,11 @@ pub enum CMessage {
         serial: u64,
         id: String,
     },
+    ReannounceTracker {
+        serial: u64,
+        id: String,
+        event: AnnounceEvent,
+    },
     AddTracker {
         serial: u64,
         id: String,
@@ -95,14 +108,100 @@ pub enum CMessage {
         serial: u64,
         id: String,
         ip: String,
+        #[serde(default = "default_false")]
+        preferred: bool,
+    },
+    RenameFile {
+        serial: u64,
+        id: String,
+        path: String,
+    },
+    RenameRoot {
+        serial: u64,
+        id: String,
+        name: String,
+    },
+    MoveTorrent {
+        serial: u64,
+        id: String,
+        path: String,
+    },
+    DownloadPieces {
+        serial: u64,
+        id: String,
+        pieces: Vec<u32>,
+        #[serde(default = "default_false")]
+        pause: bool,
+    },
+    CreateTorrent {
+        serial: u64,
+        path: String,
+        #[serde(default)]
+        trackers: Vec<String>,
+        #[serde(default)]
+        piece_length: Option<u32>,
+        #[serde(default = "default_false")]
+        private: bool,
     },
     ValidateResources {
         serial: u64,
         ids: Vec<String>,
+        #[serde(default = "default_false")]
+        low_impact: bool,
+        #[serde(default = "default_false")]
+        force_recheck: bool,
     },
     PurgeDns {
         serial: u64,
     },
+    BulkAction {
+        serial: u64,
+        #[serde(default)]
+        filter: BulkFilter,
+        action: BulkAction,
+        #[serde(default = "default_false")]
+        confirm: bool,
+    },
+    GetConnectivity {
+        serial: u64,
+    },
+    ExportSession {
+        serial: u64,
+        path: String,
+        #[serde(default)]
+        format: SessionFormat,
+    },
+    ImportSession {
+        serial: u64,
+        path: String,
+    },
+    Checkpoint {
+        serial: u64,
+    },
+    ResumeCheckpoint {
+        serial: u64,
+    },
+    GetRequests {
+        serial: u64,
+        id: String,
+    },
+    GetMagnet {
+        serial: u64,
+        id: String,
+    },
+    GetPieceState {
+        serial: u64,
+        id: String,
+    },
+    #[cfg(feature = "testing")]
+    InjectFault {
+        serial: u64,
+        id: String,
+        #[serde(default)]
+        latency_ms: u64,
+        #[serde(default)]
+        loss: f32,
+    },
 }
 
 /// Server -> client message
@@ -119,6 +218,9 @@ pub enum SMessage<'a> {
     ResourcesRemoved {
         serial: u64,
         ids: Vec<String>,
+        /// Where the metainfo of a removed torrent was archived
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        archived: Option<String>,
     },
     UpdateResources {
         serial: Option<u64>,
@@ -137,6 +239,57 @@ pub enum SMessage<'a> {
         serial: u64,
         id: String,
     },
+    TorrentMerged {
+        serial: u64,
+        id: String,
+        files: Vec<String>,
+    },
+    BulkActionResult {
+        serial: u64,
+        affected: u64,
+    },
+    Connectivity {
+        serial: u64,
+        port: u16,
+        dht_port: u16,
+        external_ip: Option<String>,
+        incoming: u64,
+        last_incoming: Option<DateTime<Utc>>,
+        firewalled: Option<bool>,
+    },
+    SessionResult {
+        serial: u64,
+        torrents: u64,
+    },
+    Requests {
+        serial: u64,
+        id: String,
+        requests: Vec<InFlightRequest>,
+    },
+    Magnet {
+        serial: u64,
+        id: String,
+        uri: String,
+    },
+    PieceState {
+        serial: u64,
+        id: String,
+        /// Base64 encoded bitfield of the pieces we have
+        have: String,
+        availability: Vec<AvailabilityRun>,
+    },
+    TorrentCreating {
+        serial: u64,
+        /// Fraction of the torrent's pieces hashed so far
+        progress: f32,
+    },
+    TorrentCreated {
+        serial: u64,
+        /// Info hash of the torrent
+        hash: String,
+        /// Base64 encoded .torrent file
+        metainfo: String,
+    },
 
     // Error messages
     UnknownResource(Error),
@@ -147,6 +300,70 @@ pub enum SMessage<'a> {
     TransferFailed(Error),
 }
 
+/// A block requested from peers which hasn't been received yet
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InFlightRequest {
+    pub piece: u32,
+    pub offset: u32,
+    /// IDs of the peers the block is requested from
+    pub peers: Vec<String>,
+    /// Milliseconds since the block was last requested
+    pub pending_ms: u64,
+    /// Whether the request timed out, allowing it to be
+    /// requested from another peer
+    pub stalled: bool,
+}
+
+/// Run of consecutive pieces held by the same number of connected
+/// peers, serialized as `[pieces, peers]`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AvailabilityRun(pub u32, pub u32);
+
+/// Torrent selection criteria for bulk actions, unset
+/// fields match any torrent.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkFilter {
+    #[serde(default)]
+    pub status: Option<Status>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tracker: Option<String>,
+}
+
+/// Encoding of an exported session
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionFormat {
+    Binary,
+    Json,
+}
+
+impl Default for SessionFormat {
+    fn default() -> SessionFormat {
+        SessionFormat::Binary
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    None,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkAction {
+    Pause,
+    Resume,
+    Remove,
+    Reannounce,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Error {
@@ -163,6 +380,26 @@ impl Version {
     }
 }
 
+impl BulkFilter {
+    /// Checks a torrent against the filter. The category is read from
+    /// the "category" key of the torrent's user data, and the tracker
+    /// is compared against the torrent's tracker hosts.
+    pub fn matches(&self, t: &Torrent) -> bool {
+        let status = self.status.map(|s| s == t.status).unwrap_or(true);
+        let category = self
+            .category
+            .as_ref()
+            .map(|c| t.user_data.pointer("/category").and_then(|v| v.as_str()) == Some(c.as_str()))
+            .unwrap_or(true);
+        let tracker = self
+            .tracker
+            .as_ref()
+            .map(|h| t.tracker_urls.iter().any(|u| u.eq_ignore_ascii_case(h)))
+            .unwrap_or(true);
+        status && category && tracker
+    }
+}
+
 fn default_true() -> bool {
     true
 }
@@ -207,4 +444,46 @@ mod tests {
             unreachable!();
         }
     }
+
+    #[test]
+    fn test_bulk_filter() {
+        let data = r#"
+            {
+                "type": "BULK_ACTION",
+                "serial": 0,
+                "filter": { "status": "seeding", "tracker": "Foo.org" },
+                "action": "pause"
+            }
+            "#;
+        let m = serde_json::from_str(data).unwrap();
+        let filter = if let CMessage::BulkAction {
+            filter,
+            action: BulkAction::Pause,
+            confirm: false,
+            ..
+        } = m
+        {
+            filter
+        } else {
+            unreachable!();
+        };
+
+        let mut t = resource::Torrent {
+            status: resource::Status::Seeding,
+            tracker_urls: vec!["bar.org".to_owned(), "foo.org".to_owned()],
+            ..Default::default()
+        };
+        assert!(filter.matches(&t));
+        assert!(BulkFilter::default().matches(&t));
+        t.status = resource::Status::Paused;
+        assert!(!filter.matches(&t));
+
+        let filter = BulkFilter {
+            category: Some("linux".to_owned()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&t));
+        t.user_data = serde_json::from_str(r#"{ "category": "linux" }"#).unwrap();
+        assert!(filter.matches(&t));
+    }
 }
@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::mem;
 
@@ -49,6 +50,7 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         throttle_up: Option<i64>,
         throttle_down: Option<i64>,
+        throttle_up_seeding: Option<i64>,
     },
     Rate {
         id: String,
@@ -87,6 +89,101 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         download_token: String,
     },
+    ServerConnQueue {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        conn_queue: u64,
+        conn_queue_dropped: u64,
+        conn_limit: u64,
+        conns_reclaimed: u64,
+    },
+    ServerMetered {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        metered: bool,
+    },
+    ServerLowSpace {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        low_space: bool,
+    },
+    ServerReadOnly {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        read_only: bool,
+    },
+    ServerAltSpeed {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        alt_speed: AltSpeed,
+        alt_speed_active: bool,
+    },
+    ServerAnnounceKey {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        key_rotated: Option<DateTime<Utc>>,
+    },
+    ServerPieceAffinity {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        piece_affinity: bool,
+    },
+    ServerQuiesced {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        quiesced: bool,
+        write_buffer_full: bool,
+    },
+    ServerCheckpoint {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        checkpoint: CheckpointState,
+    },
+    ServerWriteCache {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        write_cache_hits: u64,
+        write_cache_misses: u64,
+        write_cache_bytes: u64,
+    },
+    ServerDiskTransfer {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        disk_read: u64,
+        disk_written: u64,
+    },
+    ServerDht {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        dht_nodes: u64,
+    },
+    ServerThrottleBurst {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        throttle_burst: u64,
+    },
+    ServerJobs {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        tracker_job_secs: u64,
+        unchoke_job_secs: u64,
+        session_job_secs: u64,
+        tx_job_ms: u64,
+    },
 
     TorrentStatus {
         id: String,
@@ -136,6 +233,124 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         piece_field: String,
     },
+    TorrentConflicts {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        path_conflicts: Vec<String>,
+    },
+    TorrentPreservedPeers {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        preserved_peers: u16,
+    },
+    TorrentTarget {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        target_pieces: u64,
+    },
+    TorrentMetadata {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        metadata_progress: f32,
+    },
+    TorrentUploadSlots {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        upload_slots: u16,
+        tracker_group: Option<String>,
+        warm_bias: i16,
+        availability_boost: i16,
+    },
+    TorrentPex {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        pex_sent: u64,
+        pex_received: u64,
+    },
+    TorrentWebSeeds {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        web_seeds_engaged: bool,
+        web_seed_downloaded: u64,
+    },
+    TorrentIntegrity {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        integrity_check: bool,
+        last_sweep: Option<DateTime<Utc>>,
+        corrupt_pieces: u64,
+    },
+    TorrentSources {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        dht: bool,
+        pex: bool,
+        lpd: bool,
+        use_web_seeds: bool,
+    },
+    TorrentFlush {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        flush_policy: FlushPolicy,
+        flush_every: u64,
+    },
+    TorrentSeedDelay {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        seed_delay: u64,
+    },
+    TorrentSeedLimits {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        ratio_limit: f32,
+        seed_time_limit: u64,
+    },
+    TorrentThrottleActive {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        throttle_up_active: Option<i64>,
+    },
+    TorrentEndgame {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        endgame: bool,
+    },
+    TorrentActivity {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        completed: Option<DateTime<Utc>>,
+        last_active: Option<DateTime<Utc>>,
+        active_secs: u64,
+        seeding_secs: u64,
+    },
+    TorrentResyncs {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        peer_resyncs: u64,
+    },
+    TorrentSuperSeed {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        super_seed: bool,
+        pieces_revealed: u64,
+    },
 
     TrackerStatus {
         id: String,
@@ -143,6 +358,23 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         last_report: DateTime<Utc>,
         error: Option<String>,
+        warning: Option<String>,
+        failures: u32,
+        dead: bool,
+    },
+    TrackerHeaders {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        headers: Vec<String>,
+    },
+    TrackerScrape {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        seeders: u32,
+        leechers: u32,
+        downloaded: u32,
     },
 
     FilePriority {
@@ -151,6 +383,12 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         priority: u8,
     },
+    FilePath {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        path: String,
+    },
     FileProgress {
         id: String,
         #[serde(rename = "type")]
@@ -177,6 +415,19 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         availability: f32,
     },
+    PeerTransfer {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        transferred_up: u64,
+        transferred_down: u64,
+    },
+    PeerFreeloader {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        freeloader: bool,
+    },
 }
 
 /// Collection of mutable fields that clients
@@ -194,7 +445,31 @@ pub struct CResourceUpdate {
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_down: Option<Option<i64>>,
+    #[serde(deserialize_with = "deserialize_throttle")]
+    #[serde(default)]
+    pub throttle_up_seeding: Option<Option<i64>>,
     pub user_data: Option<json::Value>,
+    pub integrity_check: Option<bool>,
+    pub dht: Option<bool>,
+    pub pex: Option<bool>,
+    pub lpd: Option<bool>,
+    pub use_web_seeds: Option<bool>,
+    pub super_seed: Option<bool>,
+    pub flush_policy: Option<FlushPolicy>,
+    pub flush_every: Option<u64>,
+    pub seed_delay: Option<u64>,
+    pub ratio_limit: Option<f32>,
+    pub seed_time_limit: Option<u64>,
+    pub metered: Option<bool>,
+    pub piece_affinity: Option<bool>,
+    pub quiesced: Option<bool>,
+    pub throttle_burst: Option<u64>,
+    pub alt_speed: Option<AltSpeed>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub tracker_job_secs: Option<u64>,
+    pub unchoke_job_secs: Option<u64>,
+    pub session_job_secs: Option<u64>,
+    pub tx_job_ms: Option<u64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -206,12 +481,44 @@ pub struct Server {
     pub rate_down: u64,
     pub throttle_up: Option<i64>,
     pub throttle_down: Option<i64>,
+    pub throttle_up_seeding: Option<i64>,
+    pub throttle_burst: u64,
+    pub throttle_interval_ms: u64,
     pub transferred_up: u64,
     pub transferred_down: u64,
     pub ses_transferred_up: u64,
     pub ses_transferred_down: u64,
     pub free_space: u64,
+    pub conn_queue: u64,
+    pub conn_queue_dropped: u64,
+    pub conn_limit: u64,
+    pub conns_reclaimed: u64,
+    pub metered: bool,
+    pub low_space: bool,
+    pub read_only: bool,
+    pub alt_speed: AltSpeed,
+    pub alt_speed_active: bool,
+    pub piece_affinity: bool,
+    pub quiesced: bool,
+    pub write_buffer_full: bool,
+    pub checkpoint: CheckpointState,
+    pub write_cache_hits: u64,
+    pub write_cache_misses: u64,
+    pub write_cache_bytes: u64,
+    pub disk_read: u64,
+    pub disk_written: u64,
+    pub dht_nodes: u64,
+    pub web_seed_strategy: WebSeedStrategy,
+    pub ip_family: IpFamily,
+    pub key_rotation: KeyRotation,
+    pub key_rotated: Option<DateTime<Utc>>,
+    pub tracker_job_secs: u64,
+    pub unchoke_job_secs: u64,
+    pub session_job_secs: u64,
+    pub tx_job_ms: u64,
     pub started: DateTime<Utc>,
+    pub quarantined: Vec<String>,
+    pub recovered: Vec<String>,
     pub user_data: json::Value,
 }
 
@@ -221,10 +528,12 @@ impl Server {
             SResourceUpdate::Throttle {
                 throttle_up,
                 throttle_down,
+                throttle_up_seeding,
                 ..
             } => {
                 self.throttle_up = throttle_up;
                 self.throttle_down = throttle_down;
+                self.throttle_up_seeding = throttle_up_seeding;
             }
             SResourceUpdate::ServerTransfer {
                 rate_up,
@@ -248,6 +557,88 @@ impl Server {
             SResourceUpdate::ServerSpace { free_space, .. } => {
                 self.free_space = free_space;
             }
+            SResourceUpdate::ServerConnQueue {
+                conn_queue,
+                conn_queue_dropped,
+                conn_limit,
+                conns_reclaimed,
+                ..
+            } => {
+                self.conn_queue = conn_queue;
+                self.conn_queue_dropped = conn_queue_dropped;
+                self.conn_limit = conn_limit;
+                self.conns_reclaimed = conns_reclaimed;
+            }
+            SResourceUpdate::ServerMetered { metered, .. } => {
+                self.metered = metered;
+            }
+            SResourceUpdate::ServerLowSpace { low_space, .. } => {
+                self.low_space = low_space;
+            }
+            SResourceUpdate::ServerReadOnly { read_only, .. } => {
+                self.read_only = read_only;
+            }
+            SResourceUpdate::ServerAltSpeed {
+                alt_speed,
+                alt_speed_active,
+                ..
+            } => {
+                self.alt_speed = alt_speed;
+                self.alt_speed_active = alt_speed_active;
+            }
+            SResourceUpdate::ServerAnnounceKey { key_rotated, .. } => {
+                self.key_rotated = key_rotated;
+            }
+            SResourceUpdate::ServerPieceAffinity { piece_affinity, .. } => {
+                self.piece_affinity = piece_affinity;
+            }
+            SResourceUpdate::ServerQuiesced {
+                quiesced,
+                write_buffer_full,
+                ..
+            } => {
+                self.quiesced = quiesced;
+                self.write_buffer_full = write_buffer_full;
+            }
+            SResourceUpdate::ServerCheckpoint { checkpoint, .. } => {
+                self.checkpoint = checkpoint;
+            }
+            SResourceUpdate::ServerWriteCache {
+                write_cache_hits,
+                write_cache_misses,
+                write_cache_bytes,
+                ..
+            } => {
+                self.write_cache_hits = write_cache_hits;
+                self.write_cache_misses = write_cache_misses;
+                self.write_cache_bytes = write_cache_bytes;
+            }
+            SResourceUpdate::ServerDiskTransfer {
+                disk_read,
+                disk_written,
+                ..
+            } => {
+                self.disk_read = disk_read;
+                self.disk_written = disk_written;
+            }
+            SResourceUpdate::ServerDht { dht_nodes, .. } => {
+                self.dht_nodes = dht_nodes;
+            }
+            SResourceUpdate::ServerThrottleBurst { throttle_burst, .. } => {
+                self.throttle_burst = throttle_burst;
+            }
+            SResourceUpdate::ServerJobs {
+                tracker_job_secs,
+                unchoke_job_secs,
+                session_job_secs,
+                tx_job_ms,
+                ..
+            } => {
+                self.tracker_job_secs = tracker_job_secs;
+                self.unchoke_job_secs = unchoke_job_secs;
+                self.session_job_secs = session_job_secs;
+                self.tx_job_ms = tx_job_ms;
+            }
             SResourceUpdate::Rate {
                 rate_up, rate_down, ..
             } => {
@@ -267,6 +658,10 @@ pub struct Torrent {
     pub creator: Option<String>,
     pub comment: Option<String>,
     pub private: bool,
+    pub dht: bool,
+    pub pex: bool,
+    pub lpd: bool,
+    pub use_web_seeds: bool,
     pub path: String,
     pub created: DateTime<Utc>,
     pub modified: DateTime<Utc>,
@@ -280,6 +675,8 @@ pub struct Torrent {
     pub rate_down: u64,
     pub throttle_up: Option<i64>,
     pub throttle_down: Option<i64>,
+    pub throttle_up_seeding: Option<i64>,
+    pub throttle_up_active: Option<i64>,
     pub transferred_up: u64,
     pub transferred_down: u64,
     pub peers: u16,
@@ -290,6 +687,36 @@ pub struct Torrent {
     pub piece_size: Option<u32>,
     pub piece_field: String,
     pub files: Option<u32>,
+    pub path_conflicts: Vec<String>,
+    pub integrity_check: bool,
+    pub last_sweep: Option<DateTime<Utc>>,
+    pub corrupt_pieces: u64,
+    pub preserved_peers: u16,
+    pub target_pieces: u64,
+    pub web_seeds: u8,
+    pub web_seeds_engaged: bool,
+    pub web_seed_downloaded: u64,
+    pub upload_slots: u16,
+    pub tracker_group: Option<String>,
+    pub warm_bias: i16,
+    pub availability_boost: i16,
+    pub metadata_progress: f32,
+    pub pex_sent: u64,
+    pub pex_received: u64,
+    pub flush_policy: FlushPolicy,
+    pub flush_every: u64,
+    pub seed_delay: u64,
+    pub ratio_limit: f32,
+    pub seed_time_limit: u64,
+    pub endgame: bool,
+    pub super_seed: bool,
+    pub pieces_revealed: u64,
+    pub completed: Option<DateTime<Utc>>,
+    pub last_active: Option<DateTime<Utc>>,
+    pub active_secs: u64,
+    pub seeding_secs: u64,
+    pub peer_resyncs: u64,
+    pub allocation: Allocation,
     pub user_data: json::Value,
 }
 
@@ -300,10 +727,12 @@ impl Torrent {
             SResourceUpdate::Throttle {
                 throttle_up,
                 throttle_down,
+                throttle_up_seeding,
                 ..
             } => {
                 self.throttle_up = throttle_up;
                 self.throttle_down = throttle_down;
+                self.throttle_up_seeding = throttle_up_seeding;
             }
             SResourceUpdate::TorrentStatus { error, status, .. } => {
                 self.error = error;
@@ -340,6 +769,122 @@ impl Torrent {
             SResourceUpdate::TorrentPieces { piece_field, .. } => {
                 self.piece_field = piece_field;
             }
+            SResourceUpdate::TorrentConflicts { path_conflicts, .. } => {
+                self.path_conflicts = path_conflicts;
+            }
+            SResourceUpdate::TorrentPreservedPeers {
+                preserved_peers, ..
+            } => {
+                self.preserved_peers = preserved_peers;
+            }
+            SResourceUpdate::TorrentTarget { target_pieces, .. } => {
+                self.target_pieces = target_pieces;
+            }
+            SResourceUpdate::TorrentMetadata {
+                metadata_progress, ..
+            } => {
+                self.metadata_progress = metadata_progress;
+            }
+            SResourceUpdate::TorrentUploadSlots {
+                upload_slots,
+                tracker_group,
+                warm_bias,
+                availability_boost,
+                ..
+            } => {
+                self.upload_slots = upload_slots;
+                self.tracker_group = tracker_group;
+                self.warm_bias = warm_bias;
+                self.availability_boost = availability_boost;
+            }
+            SResourceUpdate::TorrentPex {
+                pex_sent,
+                pex_received,
+                ..
+            } => {
+                self.pex_sent = pex_sent;
+                self.pex_received = pex_received;
+            }
+            SResourceUpdate::TorrentWebSeeds {
+                web_seeds_engaged,
+                web_seed_downloaded,
+                ..
+            } => {
+                self.web_seeds_engaged = web_seeds_engaged;
+                self.web_seed_downloaded = web_seed_downloaded;
+            }
+            SResourceUpdate::TorrentIntegrity {
+                integrity_check,
+                last_sweep,
+                corrupt_pieces,
+                ..
+            } => {
+                self.integrity_check = integrity_check;
+                self.last_sweep = last_sweep;
+                self.corrupt_pieces = corrupt_pieces;
+            }
+            SResourceUpdate::TorrentSources {
+                dht,
+                pex,
+                lpd,
+                use_web_seeds,
+                ..
+            } => {
+                self.dht = dht;
+                self.pex = pex;
+                self.lpd = lpd;
+                self.use_web_seeds = use_web_seeds;
+            }
+            SResourceUpdate::TorrentFlush {
+                flush_policy,
+                flush_every,
+                ..
+            } => {
+                self.flush_policy = flush_policy;
+                self.flush_every = flush_every;
+            }
+            SResourceUpdate::TorrentSeedDelay { seed_delay, .. } => {
+                self.seed_delay = seed_delay;
+            }
+            SResourceUpdate::TorrentSeedLimits {
+                ratio_limit,
+                seed_time_limit,
+                ..
+            } => {
+                self.ratio_limit = ratio_limit;
+                self.seed_time_limit = seed_time_limit;
+            }
+            SResourceUpdate::TorrentThrottleActive {
+                throttle_up_active, ..
+            } => {
+                self.throttle_up_active = throttle_up_active;
+            }
+            SResourceUpdate::TorrentEndgame { endgame, .. } => {
+                self.endgame = endgame;
+            }
+            SResourceUpdate::TorrentActivity {
+                completed,
+                last_active,
+                active_secs,
+                seeding_secs,
+                ..
+            } => {
+                self.completed = completed;
+                self.last_active = last_active;
+                self.active_secs = active_secs;
+                self.seeding_secs = seeding_secs;
+            }
+            SResourceUpdate::TorrentResyncs { peer_resyncs, .. } => {
+                self.peer_resyncs = peer_resyncs;
+            }
+            SResourceUpdate::TorrentSuperSeed {
+                super_seed,
+                pieces_revealed,
+                ..
+            } => {
+                self.super_seed = super_seed;
+                self.pieces_revealed = pieces_revealed;
+            }
             _ => {}
         }
     }
@@ -351,11 +896,16 @@ impl Torrent {
 pub enum Status {
     Pending,
     Magnet,
+    #[serde(rename = "metadata_queued")]
+    MetadataQueued,
     Paused,
     Leeching,
     Idle,
     Seeding,
     Hashing,
+    Moving,
+    Completing,
+    Unregistered,
     Error,
 }
 
@@ -376,6 +926,191 @@ impl Strategy {
     }
 }
 
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum WebSeedStrategy {
+    Always,
+    Underutilized,
+    LastResort,
+}
+
+impl WebSeedStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &WebSeedStrategy::Always => "always",
+            &WebSeedStrategy::Underutilized => "underutilized",
+            &WebSeedStrategy::LastResort => "last_resort",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum IpFamily {
+    Dual,
+    Ipv4,
+    Ipv6,
+}
+
+impl IpFamily {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &IpFamily::Dual => "dual",
+            &IpFamily::Ipv4 => "ipv4",
+            &IpFamily::Ipv6 => "ipv6",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum KeyRotation {
+    Stable,
+    Session,
+    Interval,
+}
+
+impl KeyRotation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &KeyRotation::Stable => "stable",
+            &KeyRotation::Session => "session",
+            &KeyRotation::Interval => "interval",
+        }
+    }
+}
+
+/// Progress of a checkpoint of the session for backups
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum CheckpointState {
+    None,
+    Draining,
+    Ready,
+}
+
+impl CheckpointState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &CheckpointState::None => "none",
+            &CheckpointState::Draining => "draining",
+            &CheckpointState::Ready => "ready",
+        }
+    }
+}
+
+/// Daily schedule of alternate global rate limits, which replace the
+/// regular ones while it's active
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AltSpeed {
+    /// Minutes after local midnight the alternate limits start
+    pub start: u16,
+    /// Minutes after local midnight they end, on the next day if
+    /// before the start
+    pub end: u16,
+    /// Days of the week they start on, 0 being Monday. Empty disables them
+    pub days: Vec<u8>,
+    pub throttle_up: Option<i64>,
+    pub throttle_down: Option<i64>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum FlushPolicy {
+    Piece,
+    Pieces,
+    Interval,
+}
+
+impl FlushPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &FlushPolicy::Piece => "piece",
+            &FlushPolicy::Pieces => "pieces",
+            &FlushPolicy::Interval => "interval",
+        }
+    }
+}
+
+/// How a torrent's files are allocated
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Allocation {
+    Sparse,
+    Full,
+}
+
+impl Allocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &Allocation::Sparse => "sparse",
+            &Allocation::Full => "full",
+        }
+    }
+}
+
+/// How a peer was found
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    Incoming,
+    Manual,
+}
+
+impl PeerSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &PeerSource::Tracker => "tracker",
+            &PeerSource::Dht => "dht",
+            &PeerSource::Pex => "pex",
+            &PeerSource::Lsd => "lsd",
+            &PeerSource::Incoming => "incoming",
+            &PeerSource::Manual => "manual",
+        }
+    }
+}
+
+impl Default for PeerSource {
+    fn default() -> PeerSource {
+        PeerSource::Manual
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Transport {
+    Tcp,
+    Utp,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &Transport::Tcp => "tcp",
+            &Transport::Utp => "utp",
+        }
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Transport {
+        Transport::Tcp
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Piece {
@@ -410,6 +1145,7 @@ pub struct File {
     pub progress: f32,
     pub availability: f32,
     pub priority: u8,
+    pub priority_rule: Option<String>,
     pub size: u64,
     pub user_data: json::Value,
 }
@@ -423,6 +1159,9 @@ impl File {
             SResourceUpdate::FileProgress { progress, .. } => {
                 self.progress = progress;
             }
+            SResourceUpdate::FilePath { path, .. } => {
+                self.path = path;
+            }
             _ => {}
         }
     }
@@ -437,7 +1176,13 @@ pub struct Peer {
     pub ip: String,
     pub rate_up: u64,
     pub rate_down: u64,
+    pub transferred_up: u64,
+    pub transferred_down: u64,
     pub availability: f32,
+    pub freeloader: bool,
+    pub source: PeerSource,
+    pub transport: Transport,
+    pub preferred: bool,
     pub user_data: json::Value,
 }
 
@@ -453,6 +1198,17 @@ impl Peer {
             SResourceUpdate::PeerAvailability { availability, .. } => {
                 self.availability = availability;
             }
+            SResourceUpdate::PeerTransfer {
+                transferred_up,
+                transferred_down,
+                ..
+            } => {
+                self.transferred_up = transferred_up;
+                self.transferred_down = transferred_down;
+            }
+            SResourceUpdate::PeerFreeloader { freeloader, .. } => {
+                self.freeloader = freeloader;
+            }
             _ => {}
         }
     }
@@ -467,6 +1223,20 @@ pub struct Tracker {
     pub url: Url,
     pub last_report: DateTime<Utc>,
     pub error: Option<String>,
+    pub warning: Option<String>,
+    /// Names of the extra HTTP headers sent to the tracker
+    pub headers: Vec<String>,
+    /// Consecutive failed announces
+    pub failures: u32,
+    /// Whether automatic announces have stopped due to repeated failures
+    pub dead: bool,
+    /// Announce list tier
+    pub tier: u32,
+    /// Statistics of the last scrape, null until the tracker was scraped
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    /// Number of times the torrent was completed
+    pub downloaded: Option<u32>,
     pub user_data: json::Value,
 }
 
@@ -474,10 +1244,31 @@ impl Tracker {
     pub fn update(&mut self, update: SResourceUpdate) {
         match update {
             SResourceUpdate::TrackerStatus {
-                last_report, error, ..
+                last_report,
+                error,
+                warning,
+                failures,
+                dead,
+                ..
             } => {
                 self.last_report = last_report;
                 self.error = error;
+                self.warning = warning;
+                self.failures = failures;
+                self.dead = dead;
+            }
+            SResourceUpdate::TrackerHeaders { headers, .. } => {
+                self.headers = headers;
+            }
+            SResourceUpdate::TrackerScrape {
+                seeders,
+                leechers,
+                downloaded,
+                ..
+            } => {
+                self.seeders = Some(seeders);
+                self.leechers = Some(leechers);
+                self.downloaded = Some(downloaded);
             }
             _ => {}
         }
@@ -494,6 +1285,20 @@ impl<'a> SResourceUpdate<'a> {
             | &SResourceUpdate::ServerTransfer { ref id, .. }
             | &SResourceUpdate::ServerToken { ref id, .. }
             | &SResourceUpdate::ServerSpace { ref id, .. }
+            | &SResourceUpdate::ServerConnQueue { ref id, .. }
+            | &SResourceUpdate::ServerMetered { ref id, .. }
+            | &SResourceUpdate::ServerLowSpace { ref id, .. }
+            | &SResourceUpdate::ServerReadOnly { ref id, .. }
+            | &SResourceUpdate::ServerAltSpeed { ref id, .. }
+            | &SResourceUpdate::ServerAnnounceKey { ref id, .. }
+            | &SResourceUpdate::ServerPieceAffinity { ref id, .. }
+            | &SResourceUpdate::ServerQuiesced { ref id, .. }
+            | &SResourceUpdate::ServerCheckpoint { ref id, .. }
+            | &SResourceUpdate::ServerWriteCache { ref id, .. }
+            | &SResourceUpdate::ServerDiskTransfer { ref id, .. }
+            | &SResourceUpdate::ServerDht { ref id, .. }
+            | &SResourceUpdate::ServerThrottleBurst { ref id, .. }
+            | &SResourceUpdate::ServerJobs { ref id, .. }
             | &SResourceUpdate::TorrentStatus { ref id, .. }
             | &SResourceUpdate::TorrentTransfer { ref id, .. }
             | &SResourceUpdate::TorrentPeers { ref id, .. }
@@ -501,10 +1306,32 @@ impl<'a> SResourceUpdate<'a> {
             | &SResourceUpdate::TorrentPriority { ref id, .. }
             | &SResourceUpdate::TorrentPath { ref id, .. }
             | &SResourceUpdate::TorrentPieces { ref id, .. }
+            | &SResourceUpdate::TorrentConflicts { ref id, .. }
+            | &SResourceUpdate::TorrentIntegrity { ref id, .. }
+            | &SResourceUpdate::TorrentSources { ref id, .. }
+            | &SResourceUpdate::TorrentPreservedPeers { ref id, .. }
+            | &SResourceUpdate::TorrentTarget { ref id, .. }
+            | &SResourceUpdate::TorrentWebSeeds { ref id, .. }
+            | &SResourceUpdate::TorrentUploadSlots { ref id, .. }
+            | &SResourceUpdate::TorrentMetadata { ref id, .. }
+            | &SResourceUpdate::TorrentPex { ref id, .. }
+            | &SResourceUpdate::TorrentFlush { ref id, .. }
+            | &SResourceUpdate::TorrentSeedDelay { ref id, .. }
+            | &SResourceUpdate::TorrentSeedLimits { ref id, .. }
+            | &SResourceUpdate::TorrentThrottleActive { ref id, .. }
+            | &SResourceUpdate::TorrentEndgame { ref id, .. }
+            | &SResourceUpdate::TorrentActivity { ref id, .. }
+            | &SResourceUpdate::TorrentResyncs { ref id, .. }
+            | &SResourceUpdate::TorrentSuperSeed { ref id, .. }
             | &SResourceUpdate::FilePriority { ref id, .. }
             | &SResourceUpdate::FileProgress { ref id, .. }
+            | &SResourceUpdate::FilePath { ref id, .. }
             | &SResourceUpdate::TrackerStatus { ref id, .. }
+            | &SResourceUpdate::TrackerHeaders { ref id, .. }
+            | &SResourceUpdate::TrackerScrape { ref id, .. }
             | &SResourceUpdate::PeerAvailability { ref id, .. }
+            | &SResourceUpdate::PeerTransfer { ref id, .. }
+            | &SResourceUpdate::PeerFreeloader { ref id, .. }
             | &SResourceUpdate::PieceAvailable { ref id, .. }
             | &SResourceUpdate::PieceDownloaded { ref id, .. } => id,
         }
@@ -672,6 +1499,63 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  session download: {} B", t.ses_transferred_down)?;
                 write!(f, "\n")?;
+                write!(
+                    f,
+                    "  connection queue: {} ({} dropped)",
+                    t.conn_queue, t.conn_queue_dropped
+                )?;
+                write!(f, "\n")?;
+                write!(f, "  connection limit: {}", t.conn_limit)?;
+                write!(f, "\n")?;
+                write!(f, "  connections reclaimed: {}", t.conns_reclaimed)?;
+                write!(f, "\n")?;
+                write!(f, "  metered: {}", t.metered)?;
+                write!(f, "\n")?;
+                write!(f, "  low space: {}", t.low_space)?;
+                write!(f, "\n")?;
+                write!(f, "  read-only: {}", t.read_only)?;
+                write!(f, "\n")?;
+                write!(f, "  alternate speed limits: {}", t.alt_speed_active)?;
+                write!(f, "\n")?;
+                write!(
+                    f,
+                    "  disk writes quiesced: {}{}",
+                    t.quiesced,
+                    if t.write_buffer_full {
+                        " (buffer full)"
+                    } else {
+                        ""
+                    }
+                )?;
+                write!(f, "\n")?;
+                write!(f, "  checkpoint: {}", t.checkpoint.as_str())?;
+                write!(f, "\n")?;
+                write!(
+                    f,
+                    "  write cache: {} bytes buffered, {} hits, {} misses",
+                    t.write_cache_bytes, t.write_cache_hits, t.write_cache_misses
+                )?;
+                write!(f, "\n")?;
+                write!(
+                    f,
+                    "  disk: {} B read, {} B written",
+                    t.disk_read, t.disk_written
+                )?;
+                write!(f, "\n")?;
+                write!(f, "  DHT nodes: {}", t.dht_nodes)?;
+                write!(f, "\n")?;
+                write!(f, "  web seed strategy: {}", t.web_seed_strategy.as_str())?;
+                write!(f, "\n")?;
+                write!(f, "  IP family: {}", t.ip_family.as_str())?;
+                write!(f, "\n")?;
+                write!(f, "  announce key rotation: {}", t.key_rotation.as_str())?;
+                write!(f, "\n")?;
+                write!(
+                    f,
+                    "  job intervals: tracker {} s, unchoke {} s, session {} s, transfer {} ms",
+                    t.tracker_job_secs, t.unchoke_job_secs, t.session_job_secs, t.tx_job_ms
+                )?;
+                write!(f, "\n")?;
                 write!(f, "  started at: {}", t.started)?;
                 write!(f, "\n")?;
                 write!(f, "}}")?;
@@ -849,13 +1733,52 @@ impl Queryable for Server {
             "rate_down" => Some(Field::N(self.rate_down as i64)),
             "throttle_up" => Some(self.throttle_up.map(|v| Field::N(v)).unwrap_or(FNULL)),
             "throttle_down" => Some(self.throttle_down.map(|v| Field::N(v)).unwrap_or(FNULL)),
+            "throttle_up_seeding" => Some(
+                self.throttle_up_seeding
+                    .map(|v| Field::N(v))
+                    .unwrap_or(FNULL),
+            ),
+            "throttle_burst" => Some(Field::N(self.throttle_burst as i64)),
+            "throttle_interval_ms" => Some(Field::N(self.throttle_interval_ms as i64)),
             "transferred_up" => Some(Field::N(self.transferred_up as i64)),
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
             "ses_transferred_up" => Some(Field::N(self.ses_transferred_up as i64)),
             "ses_transferred_down" => Some(Field::N(self.ses_transferred_down as i64)),
             "free_space" => Some(Field::N(self.free_space as i64)),
+            "conn_queue" => Some(Field::N(self.conn_queue as i64)),
+            "conn_queue_dropped" => Some(Field::N(self.conn_queue_dropped as i64)),
+            "conn_limit" => Some(Field::N(self.conn_limit as i64)),
+            "conns_reclaimed" => Some(Field::N(self.conns_reclaimed as i64)),
+            "metered" => Some(Field::B(self.metered)),
+            "low_space" => Some(Field::B(self.low_space)),
+            "read_only" => Some(Field::B(self.read_only)),
+            "alt_speed_active" => Some(Field::B(self.alt_speed_active)),
+            "piece_affinity" => Some(Field::B(self.piece_affinity)),
+            "quiesced" => Some(Field::B(self.quiesced)),
+            "write_buffer_full" => Some(Field::B(self.write_buffer_full)),
+            "checkpoint" => Some(Field::S(self.checkpoint.as_str())),
+            "write_cache_hits" => Some(Field::N(self.write_cache_hits as i64)),
+            "write_cache_misses" => Some(Field::N(self.write_cache_misses as i64)),
+            "write_cache_bytes" => Some(Field::N(self.write_cache_bytes as i64)),
+            "disk_read" => Some(Field::N(self.disk_read as i64)),
+            "disk_written" => Some(Field::N(self.disk_written as i64)),
+            "dht_nodes" => Some(Field::N(self.dht_nodes as i64)),
+            "web_seed_strategy" => Some(Field::S(self.web_seed_strategy.as_str())),
+            "ip_family" => Some(Field::S(self.ip_family.as_str())),
+            "key_rotation" => Some(Field::S(self.key_rotation.as_str())),
+            "key_rotated" => Some(self.key_rotated.map(Field::D).unwrap_or(FNULL)),
+            "tracker_job_secs" => Some(Field::N(self.tracker_job_secs as i64)),
+            "unchoke_job_secs" => Some(Field::N(self.unchoke_job_secs as i64)),
+            "session_job_secs" => Some(Field::N(self.session_job_secs as i64)),
+            "tx_job_ms" => Some(Field::N(self.tx_job_ms as i64)),
 
             "started" => Some(Field::D(self.started)),
+            "quarantined" => Some(Field::V(
+                self.quarantined.iter().map(|id| Field::S(id)).collect(),
+            )),
+            "recovered" => Some(Field::V(
+                self.recovered.iter().map(|id| Field::S(id)).collect(),
+            )),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -875,6 +1798,10 @@ impl Queryable for Torrent {
                     .unwrap_or(FNULL),
             ),
             "private" => Some(Field::B(self.private)),
+            "dht" => Some(Field::B(self.dht)),
+            "pex" => Some(Field::B(self.pex)),
+            "lpd" => Some(Field::B(self.lpd)),
+            "use_web_seeds" => Some(Field::B(self.use_web_seeds)),
             "creator" => Some(
                 self.creator
                     .as_ref()
@@ -901,6 +1828,16 @@ impl Queryable for Torrent {
             "rate_down" => Some(Field::N(self.rate_down as i64)),
             "throttle_up" => Some(self.throttle_up.map(|v| Field::N(v)).unwrap_or(FNULL)),
             "throttle_down" => Some(self.throttle_down.map(|v| Field::N(v)).unwrap_or(FNULL)),
+            "throttle_up_seeding" => Some(
+                self.throttle_up_seeding
+                    .map(|v| Field::N(v))
+                    .unwrap_or(FNULL),
+            ),
+            "throttle_up_active" => Some(
+                self.throttle_up_active
+                    .map(|v| Field::N(v))
+                    .unwrap_or(FNULL),
+            ),
             "transferred_up" => Some(Field::N(self.transferred_up as i64)),
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
             "peers" => Some(Field::N(self.peers as i64)),
@@ -912,14 +1849,51 @@ impl Queryable for Torrent {
             "pieces" => Some(self.pieces.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "piece_size" => Some(self.piece_size.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "files" => Some(self.files.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+            "path_conflicts" => Some(Field::V(
+                self.path_conflicts.iter().map(|id| Field::S(id)).collect(),
+            )),
+            "integrity_check" => Some(Field::B(self.integrity_check)),
+            "corrupt_pieces" => Some(Field::N(self.corrupt_pieces as i64)),
+            "preserved_peers" => Some(Field::N(self.preserved_peers as i64)),
+            "target_pieces" => Some(Field::N(self.target_pieces as i64)),
+            "web_seeds" => Some(Field::N(self.web_seeds as i64)),
+            "web_seeds_engaged" => Some(Field::B(self.web_seeds_engaged)),
+            "web_seed_downloaded" => Some(Field::N(self.web_seed_downloaded as i64)),
+            "upload_slots" => Some(Field::N(self.upload_slots as i64)),
+            "warm_bias" => Some(Field::N(self.warm_bias as i64)),
+            "availability_boost" => Some(Field::N(self.availability_boost as i64)),
+            "metadata_progress" => Some(Field::F(self.metadata_progress)),
+            "pex_sent" => Some(Field::N(self.pex_sent as i64)),
+            "pex_received" => Some(Field::N(self.pex_received as i64)),
+            "flush_every" => Some(Field::N(self.flush_every as i64)),
+            "seed_delay" => Some(Field::N(self.seed_delay as i64)),
+            "ratio_limit" => Some(Field::F(self.ratio_limit)),
+            "seed_time_limit" => Some(Field::N(self.seed_time_limit as i64)),
+            "endgame" => Some(Field::B(self.endgame)),
+            "super_seed" => Some(Field::B(self.super_seed)),
+            "pieces_revealed" => Some(Field::N(self.pieces_revealed as i64)),
+            "tracker_group" => Some(
+                self.tracker_group
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
 
             "created" => Some(Field::D(self.created)),
             "modified" => Some(Field::D(self.modified)),
+            "last_sweep" => Some(self.last_sweep.map(Field::D).unwrap_or(FNULL)),
+            "completed" => Some(self.completed.map(Field::D).unwrap_or(FNULL)),
+            "last_active" => Some(self.last_active.map(Field::D).unwrap_or(FNULL)),
+            "active_secs" => Some(Field::N(self.active_secs as i64)),
+            "seeding_secs" => Some(Field::N(self.seeding_secs as i64)),
+            "peer_resyncs" => Some(Field::N(self.peer_resyncs as i64)),
 
             "progress" => Some(Field::F(self.progress)),
             "availability" => Some(Field::F(self.availability)),
 
             "strategy" => Some(Field::S(self.strategy.as_str())),
+            "flush_policy" => Some(Field::S(self.flush_policy.as_str())),
+            "allocation" => Some(Field::S(self.allocation.as_str())),
 
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
@@ -956,6 +1930,12 @@ impl Queryable for File {
             "path" => Some(Field::S(&self.path)),
 
             "priority" => Some(Field::N(self.priority as i64)),
+            "priority_rule" => Some(
+                self.priority_rule
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
 
             "progress" => Some(Field::F(self.progress)),
 
@@ -975,8 +1955,14 @@ impl Queryable for Peer {
 
             "rate_up" => Some(Field::N(self.rate_up as i64)),
             "rate_down" => Some(Field::N(self.rate_down as i64)),
+            "transferred_up" => Some(Field::N(self.transferred_up as i64)),
+            "transferred_down" => Some(Field::N(self.transferred_down as i64)),
 
             "availability" => Some(Field::F(self.availability)),
+            "freeloader" => Some(Field::B(self.freeloader)),
+            "preferred" => Some(Field::B(self.preferred)),
+            "source" => Some(Field::S(self.source.as_str())),
+            "transport" => Some(Field::S(self.transport.as_str())),
 
             "client_id" => Some(Field::S(&self.client_id)),
 
@@ -999,6 +1985,19 @@ impl Queryable for Tracker {
                     .map(|v| Field::S(v.as_str()))
                     .unwrap_or(FNULL),
             ),
+            "warning" => Some(
+                self.warning
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "headers" => Some(Field::V(self.headers.iter().map(|h| Field::S(h)).collect())),
+            "failures" => Some(Field::N(self.failures as i64)),
+            "dead" => Some(Field::B(self.dead)),
+            "tier" => Some(Field::N(self.tier as i64)),
+            "seeders" => Some(self.seeders.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+            "leechers" => Some(self.leechers.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
+            "downloaded" => Some(self.downloaded.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
 
             "last_report" => Some(Field::D(self.last_report)),
 
@@ -1018,7 +2017,11 @@ impl Status {
             Status::Idle => "idle",
             Status::Seeding => "seeding",
             Status::Hashing => "hashing",
+            Status::Moving => "moving",
+            Status::Completing => "completing",
             Status::Magnet => "magnet",
+            Status::MetadataQueued => "metadata_queued",
+            Status::Unregistered => "unregistered",
             Status::Error => "error",
         }
     }
@@ -1058,13 +2061,45 @@ impl Default for Server {
             rate_down: 0,
             throttle_up: None,
             throttle_down: None,
+            throttle_up_seeding: None,
+            throttle_burst: 0,
+            throttle_interval_ms: 0,
             transferred_up: 0,
             transferred_down: 0,
             ses_transferred_up: 0,
             ses_transferred_down: 0,
             free_space: 0,
+            conn_queue: 0,
+            conn_queue_dropped: 0,
+            conn_limit: 0,
+            conns_reclaimed: 0,
+            metered: false,
+            low_space: false,
+            read_only: false,
+            alt_speed: AltSpeed::default(),
+            alt_speed_active: false,
+            piece_affinity: false,
+            quiesced: false,
+            write_buffer_full: false,
+            checkpoint: CheckpointState::None,
+            write_cache_hits: 0,
+            write_cache_misses: 0,
+            write_cache_bytes: 0,
+            disk_read: 0,
+            disk_written: 0,
+            dht_nodes: 0,
+            web_seed_strategy: WebSeedStrategy::Underutilized,
+            ip_family: IpFamily::Dual,
+            key_rotation: KeyRotation::Stable,
+            key_rotated: None,
+            tracker_job_secs: 0,
+            unchoke_job_secs: 0,
+            session_job_secs: 0,
+            tx_job_ms: 0,
             download_token: "".to_owned(),
             started: Utc::now(),
+            quarantined: vec![],
+            recovered: vec![],
             user_data: json::Value::Null,
         }
     }
@@ -1078,6 +2113,10 @@ impl Default for Torrent {
             comment: None,
             creator: None,
             private: false,
+            dht: false,
+            pex: false,
+            lpd: false,
+            use_web_seeds: false,
             path: "".to_owned(),
             created: Utc::now(),
             modified: Utc::now(),
@@ -1091,6 +2130,8 @@ impl Default for Torrent {
             rate_down: 0,
             throttle_up: None,
             throttle_down: None,
+            throttle_up_seeding: None,
+            throttle_up_active: None,
             transferred_up: 0,
             transferred_down: 0,
             peers: 0,
@@ -1101,6 +2142,36 @@ impl Default for Torrent {
             piece_size: None,
             piece_field: "".to_owned(),
             files: None,
+            path_conflicts: vec![],
+            integrity_check: true,
+            last_sweep: None,
+            corrupt_pieces: 0,
+            preserved_peers: 0,
+            target_pieces: 0,
+            web_seeds: 0,
+            web_seeds_engaged: false,
+            web_seed_downloaded: 0,
+            upload_slots: 0,
+            tracker_group: None,
+            warm_bias: 0,
+            availability_boost: 0,
+            metadata_progress: 0.,
+            pex_sent: 0,
+            pex_received: 0,
+            flush_policy: FlushPolicy::Piece,
+            flush_every: 0,
+            seed_delay: 0,
+            ratio_limit: 0.,
+            seed_time_limit: 0,
+            endgame: false,
+            super_seed: false,
+            pieces_revealed: 0,
+            completed: None,
+            last_active: None,
+            active_secs: 0,
+            seeding_secs: 0,
+            peer_resyncs: 0,
+            allocation: Allocation::Sparse,
             user_data: json::Value::Null,
         }
     }
@@ -1114,6 +2185,14 @@ impl Default for Tracker {
             url: Url::parse("http://my.tracker/announce").unwrap(),
             last_report: Utc::now(),
             error: None,
+            warning: None,
+            headers: Vec::new(),
+            failures: 0,
+            dead: false,
+            tier: 0,
+            seeders: None,
+            leechers: None,
+            downloaded: None,
             user_data: json::Value::Null,
         }
     }
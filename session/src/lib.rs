@@ -6,7 +6,7 @@ extern crate serde_derive;
 
 pub mod torrent {
     pub use self::current::Session;
-    pub use self::ver_fa1b6f as current;
+    pub use self::ver_a7d3f0 as current;
     use bincode;
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -16,8 +16,40 @@ pub mod torrent {
     }
 
     pub fn load(data: &[u8]) -> Option<Session> {
-        if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+        if let Ok(m) = bincode::deserialize::<ver_a7d3f0::Session>(data) {
             Some(m)
+        } else if let Ok(m) = bincode::deserialize::<ver_3e8f05::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_c27d4e::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_8a41c7::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_5f3a21::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_0e9b47::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_7c52e8::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_4d0c8e::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_b61e3d::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_9d5f72::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_e3a7c1::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_1c9e54::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_7b2e90::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_5a0c93::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_d84e07::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_3c41d2::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+            Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_6e27af::Session>(data) {
             Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_249b1b::Session>(data) {
@@ -29,21 +61,1161 @@ pub mod torrent {
         } else {
             None
         }
-    }
+    }
+
+    impl Session {
+        pub fn migrate(self) -> Self {
+            self
+        }
+    }
+
+    pub mod ver_a7d3f0 {
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            /// Announce list tier of each tracker
+            pub tracker_tiers: Vec<u32>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            /// Tracker ids returned by trackers, by URL
+            pub tracker_ids: Vec<(String, Vec<u8>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+            /// Seconds between completion and seeding, if set over RPC
+            pub seed_delay: Option<u64>,
+            /// When all pieces were first downloaded
+            pub completed: Option<DateTime<Utc>>,
+            /// When a piece was last received or uploaded
+            pub last_active: Option<DateTime<Utc>>,
+            /// Seconds spent transferring data
+            pub active_secs: u64,
+            /// Upload ratio at which seeding stops, if set over RPC
+            pub ratio_limit: Option<f32>,
+            /// Seconds of seeding after which it stops, if set over RPC
+            pub seed_time_limit: Option<u64>,
+            /// Seconds spent seeding
+            pub seeding_secs: u64,
+            /// Stats of each file when the session was saved, None for
+            /// files which didn't exist. Empty if unknown.
+            pub file_stats: Vec<Option<FileStat>>,
+            /// How the torrent's files are allocated
+            pub allocation: Allocation,
+            /// Info dict as it was encoded in the metainfo, None if
+            /// it wasn't kept
+            pub info_bytes: Option<Vec<u8>>,
+        }
+
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum Allocation {
+            Sparse,
+            Full,
+        }
+
+        /// Size and modification time of a file
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub struct FileStat {
+            pub len: u64,
+            pub mtime_secs: i64,
+            pub mtime_nanos: u32,
+        }
+
+        /// Flush schedule set for the torrent over RPC
+        #[derive(Serialize, Deserialize)]
+        pub struct Flush {
+            pub policy: FlushPolicy,
+            pub every: u64,
+        }
+
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum FlushPolicy {
+            Piece,
+            Pieces,
+            Interval,
+        }
+
+        /// Peer sources enabled for the torrent
+        #[derive(Serialize, Deserialize)]
+        pub struct Sources {
+            pub dht: bool,
+            pub pex: bool,
+            pub lpd: bool,
+            pub web_seeds: bool,
+        }
+
+        /// Partially fetched info dictionary of a magnet
+        #[derive(Serialize, Deserialize)]
+        pub struct Metadata {
+            pub bytes: Vec<u8>,
+            pub have: Bitfield,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+        }
+
+        #[derive(Clone, Debug, Serialize, Deserialize)]
+        pub struct Status {
+            pub paused: bool,
+            pub validating: bool,
+            pub error: Option<String>,
+            pub state: StatusState,
+        }
+
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum StatusState {
+            Magnet,
+            // Torrent has not acquired all pieces
+            Incomplete,
+            // Torrent has acquired all pieces, regardless of validity
+            Complete,
+        }
+    }
+
+    pub mod ver_3e8f05 {
+        pub use self::next::{
+            Allocation, File, FileStat, Flush, FlushPolicy, Info, Metadata, Sources, Status,
+            StatusState,
+        };
+        pub use super::ver_a7d3f0 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            /// Announce list tier of each tracker
+            pub tracker_tiers: Vec<u32>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            /// Tracker ids returned by trackers, by URL
+            pub tracker_ids: Vec<(String, Vec<u8>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+            /// Seconds between completion and seeding, if set over RPC
+            pub seed_delay: Option<u64>,
+            /// When all pieces were first downloaded
+            pub completed: Option<DateTime<Utc>>,
+            /// When a piece was last received or uploaded
+            pub last_active: Option<DateTime<Utc>>,
+            /// Seconds spent transferring data
+            pub active_secs: u64,
+            /// Upload ratio at which seeding stops, if set over RPC
+            pub ratio_limit: Option<f32>,
+            /// Seconds of seeding after which it stops, if set over RPC
+            pub seed_time_limit: Option<u64>,
+            /// Seconds spent seeding
+            pub seeding_secs: u64,
+            /// Stats of each file when the session was saved, None for
+            /// files which didn't exist. Empty if unknown.
+            pub file_stats: Vec<Option<FileStat>>,
+            /// How the torrent's files are allocated
+            pub allocation: Allocation,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: self.throttle_ul_seeding,
+                    trackers: self.trackers,
+                    tracker_tiers: self.tracker_tiers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    tracker_ids: self.tracker_ids,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                    seed_delay: self.seed_delay,
+                    completed: self.completed,
+                    last_active: self.last_active,
+                    active_secs: self.active_secs,
+                    ratio_limit: self.ratio_limit,
+                    seed_time_limit: self.seed_time_limit,
+                    seeding_secs: self.seeding_secs,
+                    file_stats: self.file_stats,
+                    allocation: self.allocation,
+                    info_bytes: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_c27d4e {
+        pub use self::next::{
+            File, FileStat, Flush, FlushPolicy, Info, Metadata, Sources, Status, StatusState,
+        };
+        pub use super::ver_3e8f05 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            /// Announce list tier of each tracker
+            pub tracker_tiers: Vec<u32>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            /// Tracker ids returned by trackers, by URL
+            pub tracker_ids: Vec<(String, Vec<u8>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+            /// Seconds between completion and seeding, if set over RPC
+            pub seed_delay: Option<u64>,
+            /// When all pieces were first downloaded
+            pub completed: Option<DateTime<Utc>>,
+            /// When a piece was last received or uploaded
+            pub last_active: Option<DateTime<Utc>>,
+            /// Seconds spent transferring data
+            pub active_secs: u64,
+            /// Upload ratio at which seeding stops, if set over RPC
+            pub ratio_limit: Option<f32>,
+            /// Seconds of seeding after which it stops, if set over RPC
+            pub seed_time_limit: Option<u64>,
+            /// Seconds spent seeding
+            pub seeding_secs: u64,
+            /// Stats of each file when the session was saved, None for
+            /// files which didn't exist. Empty if unknown.
+            pub file_stats: Vec<Option<FileStat>>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: self.throttle_ul_seeding,
+                    trackers: self.trackers,
+                    tracker_tiers: self.tracker_tiers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    tracker_ids: self.tracker_ids,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                    seed_delay: self.seed_delay,
+                    completed: self.completed,
+                    last_active: self.last_active,
+                    active_secs: self.active_secs,
+                    ratio_limit: self.ratio_limit,
+                    seed_time_limit: self.seed_time_limit,
+                    seeding_secs: self.seeding_secs,
+                    file_stats: self.file_stats,
+                    // Files were always allocated as they were written
+                    allocation: next::Allocation::Sparse,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_8a41c7 {
+        pub use self::next::{
+            File, Flush, FlushPolicy, Info, Metadata, Sources, Status, StatusState,
+        };
+        pub use super::ver_c27d4e as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            /// Announce list tier of each tracker
+            pub tracker_tiers: Vec<u32>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            /// Tracker ids returned by trackers, by URL
+            pub tracker_ids: Vec<(String, Vec<u8>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+            /// Seconds between completion and seeding, if set over RPC
+            pub seed_delay: Option<u64>,
+            /// When all pieces were first downloaded
+            pub completed: Option<DateTime<Utc>>,
+            /// When a piece was last received or uploaded
+            pub last_active: Option<DateTime<Utc>>,
+            /// Seconds spent transferring data
+            pub active_secs: u64,
+            /// Upload ratio at which seeding stops, if set over RPC
+            pub ratio_limit: Option<f32>,
+            /// Seconds of seeding after which it stops, if set over RPC
+            pub seed_time_limit: Option<u64>,
+            /// Seconds spent seeding
+            pub seeding_secs: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: self.throttle_ul_seeding,
+                    trackers: self.trackers,
+                    tracker_tiers: self.tracker_tiers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    tracker_ids: self.tracker_ids,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                    seed_delay: self.seed_delay,
+                    completed: self.completed,
+                    last_active: self.last_active,
+                    active_secs: self.active_secs,
+                    ratio_limit: self.ratio_limit,
+                    seed_time_limit: self.seed_time_limit,
+                    seeding_secs: self.seeding_secs,
+                    // Unknown for sessions predating them
+                    file_stats: Vec::new(),
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_5f3a21 {
+        pub use self::next::{
+            File, Flush, FlushPolicy, Info, Metadata, Sources, Status, StatusState,
+        };
+        pub use super::ver_8a41c7 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            /// Announce list tier of each tracker
+            pub tracker_tiers: Vec<u32>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            /// Tracker ids returned by trackers, by URL
+            pub tracker_ids: Vec<(String, Vec<u8>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+            /// Seconds between completion and seeding, if set over RPC
+            pub seed_delay: Option<u64>,
+            /// When all pieces were first downloaded
+            pub completed: Option<DateTime<Utc>>,
+            /// When a piece was last received or uploaded
+            pub last_active: Option<DateTime<Utc>>,
+            /// Seconds spent transferring data
+            pub active_secs: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: self.throttle_ul_seeding,
+                    trackers: self.trackers,
+                    tracker_tiers: self.tracker_tiers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    tracker_ids: self.tracker_ids,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                    seed_delay: self.seed_delay,
+                    completed: self.completed,
+                    last_active: self.last_active,
+                    active_secs: self.active_secs,
+                    // Unknown for sessions predating them
+                    ratio_limit: None,
+                    seed_time_limit: None,
+                    seeding_secs: 0,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_0e9b47 {
+        pub use self::next::{
+            File, Flush, FlushPolicy, Info, Metadata, Sources, Status, StatusState,
+        };
+        pub use super::ver_5f3a21 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            /// Announce list tier of each tracker
+            pub tracker_tiers: Vec<u32>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            /// Tracker ids returned by trackers, by URL
+            pub tracker_ids: Vec<(String, Vec<u8>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+            /// Seconds between completion and seeding, if set over RPC
+            pub seed_delay: Option<u64>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: self.throttle_ul_seeding,
+                    trackers: self.trackers,
+                    tracker_tiers: self.tracker_tiers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    tracker_ids: self.tracker_ids,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                    seed_delay: self.seed_delay,
+                    // Unknown for sessions predating them
+                    completed: None,
+                    last_active: None,
+                    active_secs: 0,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_7c52e8 {
+        pub use self::next::{
+            File, Flush, FlushPolicy, Info, Metadata, Sources, Status, StatusState,
+        };
+        pub use super::ver_0e9b47 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            /// Announce list tier of each tracker
+            pub tracker_tiers: Vec<u32>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+            /// Seconds between completion and seeding, if set over RPC
+            pub seed_delay: Option<u64>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: self.throttle_ul_seeding,
+                    trackers: self.trackers,
+                    tracker_tiers: self.tracker_tiers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    tracker_ids: Vec::new(),
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                    seed_delay: self.seed_delay,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_4d0c8e {
+        pub use self::next::{
+            File, Flush, FlushPolicy, Info, Metadata, Sources, Status, StatusState,
+        };
+        pub use super::ver_7c52e8 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+            /// Seconds between completion and seeding, if set over RPC
+            pub seed_delay: Option<u64>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: self.throttle_ul_seeding,
+                    trackers: self.trackers,
+                    tracker_tiers: Vec::new(),
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                    seed_delay: self.seed_delay,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_b61e3d {
+        pub use self::next::{
+            File, Flush, FlushPolicy, Info, Metadata, Sources, Status, StatusState,
+        };
+        pub use super::ver_4d0c8e as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            /// Upload rate used in place of throttle_ul once complete
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: self.throttle_ul_seeding,
+                    trackers: self.trackers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                    seed_delay: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_9d5f72 {
+        pub use self::next::{
+            File, Flush, FlushPolicy, Info, Metadata, Sources, Status, StatusState,
+        };
+        pub use super::ver_b61e3d as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+            pub flush: Option<Flush>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    throttle_ul_seeding: None,
+                    trackers: self.trackers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: self.flush,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_e3a7c1 {
+        pub use self::next::{File, Info, Metadata, Sources, Status, StatusState};
+        pub use super::ver_9d5f72 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+            pub sources: Sources,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: self.sources,
+                    flush: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_1c9e54 {
+        pub use self::next::{File, Info, Metadata, Status, StatusState};
+        pub use super::ver_e3a7c1 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+            pub metadata: Option<Metadata>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: self.metadata,
+                    sources: next::Sources {
+                        dht: true,
+                        pex: true,
+                        lpd: true,
+                        web_seeds: true,
+                    },
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_7b2e90 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_1c9e54 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub web_seeds: Vec<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    file_renames: self.file_renames,
+                    web_seeds: self.web_seeds,
+                    metadata: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_5a0c93 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_7b2e90 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+        }
 
-    impl Session {
-        pub fn migrate(self) -> Self {
-            self
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    file_renames: self.file_renames,
+                    web_seeds: Vec::new(),
+                }
+                .migrate()
+            }
         }
     }
 
-    pub mod ver_fa1b6f {
+    pub mod ver_d84e07 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_5a0c93 as next;
+
         use super::Bitfield;
 
         use chrono::{DateTime, Utc};
 
-        use std::path::PathBuf;
-
         #[derive(Serialize, Deserialize)]
         pub struct Session {
             pub info: Info,
@@ -58,45 +1230,130 @@ pub mod torrent {
             pub throttle_ul: Option<i64>,
             pub throttle_dl: Option<i64>,
             pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
+            pub tracker_headers: Vec<(String, Vec<(String, String)>)>,
         }
 
-        #[derive(Clone, Serialize, Deserialize)]
-        pub struct Info {
-            pub name: String,
-            pub announce: Option<String>,
-            pub creator: Option<String>,
-            pub comment: Option<String>,
-            pub piece_len: u32,
-            pub total_len: u64,
-            pub hashes: Vec<Vec<u8>>,
-            pub hash: [u8; 20],
-            pub files: Vec<File>,
-            pub private: bool,
-            pub be_name: Option<Vec<u8>>,
-            pub piece_idx: Vec<(usize, u64)>,
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: self.tracker_headers,
+                    file_renames: Vec::new(),
+                }
+                .migrate()
+            }
         }
+    }
 
-        #[derive(Serialize, Deserialize, Clone, Debug)]
-        pub struct File {
-            pub path: PathBuf,
-            pub length: u64,
+    pub mod ver_3c41d2 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_d84e07 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+            pub integrity_check: bool,
+            pub last_sweep: Option<DateTime<Utc>>,
         }
 
-        #[derive(Clone, Debug, Serialize, Deserialize)]
-        pub struct Status {
-            pub paused: bool,
-            pub validating: bool,
-            pub error: Option<String>,
-            pub state: StatusState,
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    integrity_check: self.integrity_check,
+                    last_sweep: self.last_sweep,
+                    tracker_headers: Vec::new(),
+                }
+                .migrate()
+            }
         }
+    }
 
-        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-        pub enum StatusState {
-            Magnet,
-            // Torrent has not acquired all pieces
-            Incomplete,
-            // Torrent has acquired all pieces, regardless of validity
-            Complete,
+    pub mod ver_fa1b6f {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_3c41d2 as next;
+
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    integrity_check: true,
+                    last_sweep: None,
+                }
+                .migrate()
+            }
         }
     }
 
@@ -392,3 +1649,288 @@ pub mod torrent {
         }
     }
 }
+
+pub mod bundle {
+    pub use self::current::Bundle;
+    pub use self::ver_4d1f3a as current;
+    use bincode;
+
+    pub fn load(data: &[u8]) -> Option<Bundle> {
+        match bincode::deserialize::<ver_4d1f3a::Bundle>(data) {
+            Ok(ref b) if b.version != ver_4d1f3a::VERSION => None,
+            Ok(b) => Some(b),
+            Err(_) => None,
+        }
+    }
+
+    pub mod ver_4d1f3a {
+        pub const VERSION: u32 = 1;
+
+        /// Exported session of a client, used to move it to another
+        /// instance.
+        #[derive(Serialize, Deserialize)]
+        pub struct Bundle {
+            pub version: u32,
+            /// Serialized server data
+            pub server: Vec<u8>,
+            /// Infohash and versioned session data of each torrent
+            pub torrents: Vec<([u8; 20], Vec<u8>)>,
+        }
+    }
+}
+
+/// Sessions exported as JSON. Unlike the binary session data, which
+/// changes along with the client, this layout only changes along with
+/// its version, so that archives stay readable by hand and by other
+/// instances.
+pub mod json {
+    pub use self::current::{Archive, Torrent};
+    pub use self::ver_1 as current;
+
+    pub mod ver_1 {
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        use torrent::{current as session, Bitfield};
+
+        pub const VERSION: u32 = 1;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Archive<S> {
+            pub version: u32,
+            pub server: S,
+            pub torrents: Vec<Torrent>,
+        }
+
+        /// Exported torrent. Tracker headers are left out since they
+        /// often carry credentials, as is state which is rebuilt after
+        /// an import, such as file stats and partial metadata.
+        #[derive(Serialize, Deserialize)]
+        pub struct Torrent {
+            pub info: Info,
+            /// Info dict as it was encoded in the metainfo, if kept
+            pub info_bytes: Option<Vec<u8>>,
+            pub pieces: Bitfield,
+            pub state: State,
+            pub paused: bool,
+            pub path: Option<String>,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub completed: Option<DateTime<Utc>>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub throttle_ul_seeding: Option<i64>,
+            pub trackers: Vec<Tracker>,
+            pub web_seeds: Vec<String>,
+            pub file_renames: Vec<(u32, PathBuf)>,
+            pub sources: Sources,
+            pub integrity_check: bool,
+            pub seed_delay: Option<u64>,
+            pub ratio_limit: Option<f32>,
+            pub seed_time_limit: Option<u64>,
+            pub active_secs: u64,
+            pub seeding_secs: u64,
+            pub full_allocation: bool,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Tracker {
+            pub url: String,
+            /// Announce list tier
+            pub tier: u32,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Sources {
+            pub dht: bool,
+            pub pex: bool,
+            pub lpd: bool,
+            pub web_seeds: bool,
+        }
+
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum State {
+            Magnet,
+            Incomplete,
+            Complete,
+        }
+
+        impl<'a> From<&'a session::Session> for Torrent {
+            fn from(s: &session::Session) -> Torrent {
+                Torrent {
+                    info: Info {
+                        name: s.info.name.clone(),
+                        announce: s.info.announce.clone(),
+                        creator: s.info.creator.clone(),
+                        comment: s.info.comment.clone(),
+                        piece_len: s.info.piece_len,
+                        total_len: s.info.total_len,
+                        hashes: s.info.hashes.clone(),
+                        hash: s.info.hash,
+                        files: s
+                            .info
+                            .files
+                            .iter()
+                            .map(|f| File {
+                                path: f.path.clone(),
+                                length: f.length,
+                            })
+                            .collect(),
+                        private: s.info.private,
+                        be_name: s.info.be_name.clone(),
+                        piece_idx: s.info.piece_idx.clone(),
+                    },
+                    info_bytes: s.info_bytes.clone(),
+                    pieces: s.pieces.clone(),
+                    state: match s.status.state {
+                        session::StatusState::Magnet => State::Magnet,
+                        session::StatusState::Incomplete => State::Incomplete,
+                        session::StatusState::Complete => State::Complete,
+                    },
+                    paused: s.status.paused,
+                    path: s.path.clone(),
+                    uploaded: s.uploaded,
+                    downloaded: s.downloaded,
+                    priority: s.priority,
+                    priorities: s.priorities.clone(),
+                    created: s.created,
+                    completed: s.completed,
+                    throttle_ul: s.throttle_ul,
+                    throttle_dl: s.throttle_dl,
+                    throttle_ul_seeding: s.throttle_ul_seeding,
+                    trackers: s
+                        .trackers
+                        .iter()
+                        .zip(s.tracker_tiers.iter())
+                        .map(|(url, &tier)| Tracker {
+                            url: url.clone(),
+                            tier,
+                        })
+                        .collect(),
+                    web_seeds: s.web_seeds.clone(),
+                    file_renames: s.file_renames.clone(),
+                    sources: Sources {
+                        dht: s.sources.dht,
+                        pex: s.sources.pex,
+                        lpd: s.sources.lpd,
+                        web_seeds: s.sources.web_seeds,
+                    },
+                    integrity_check: s.integrity_check,
+                    seed_delay: s.seed_delay,
+                    ratio_limit: s.ratio_limit,
+                    seed_time_limit: s.seed_time_limit,
+                    active_secs: s.active_secs,
+                    seeding_secs: s.seeding_secs,
+                    full_allocation: s.allocation == session::Allocation::Full,
+                }
+            }
+        }
+
+        impl From<Torrent> for session::Session {
+            fn from(t: Torrent) -> session::Session {
+                let (trackers, tracker_tiers) =
+                    t.trackers.into_iter().map(|t| (t.url, t.tier)).unzip();
+                session::Session {
+                    info: session::Info {
+                        name: t.info.name,
+                        announce: t.info.announce,
+                        creator: t.info.creator,
+                        comment: t.info.comment,
+                        piece_len: t.info.piece_len,
+                        total_len: t.info.total_len,
+                        hashes: t.info.hashes,
+                        hash: t.info.hash,
+                        files: t
+                            .info
+                            .files
+                            .into_iter()
+                            .map(|f| session::File {
+                                path: f.path,
+                                length: f.length,
+                            })
+                            .collect(),
+                        private: t.info.private,
+                        be_name: t.info.be_name,
+                        piece_idx: t.info.piece_idx,
+                    },
+                    pieces: t.pieces,
+                    uploaded: t.uploaded,
+                    downloaded: t.downloaded,
+                    status: session::Status {
+                        paused: t.paused,
+                        validating: false,
+                        error: None,
+                        state: match t.state {
+                            State::Magnet => session::StatusState::Magnet,
+                            State::Incomplete => session::StatusState::Incomplete,
+                            State::Complete => session::StatusState::Complete,
+                        },
+                    },
+                    path: t.path,
+                    priority: t.priority,
+                    priorities: t.priorities,
+                    created: t.created,
+                    throttle_ul: t.throttle_ul,
+                    throttle_dl: t.throttle_dl,
+                    throttle_ul_seeding: t.throttle_ul_seeding,
+                    trackers,
+                    tracker_tiers,
+                    integrity_check: t.integrity_check,
+                    last_sweep: None,
+                    tracker_headers: Vec::new(),
+                    tracker_ids: Vec::new(),
+                    file_renames: t.file_renames,
+                    web_seeds: t.web_seeds,
+                    metadata: None,
+                    sources: session::Sources {
+                        dht: t.sources.dht,
+                        pex: t.sources.pex,
+                        lpd: t.sources.lpd,
+                        web_seeds: t.sources.web_seeds,
+                    },
+                    flush: None,
+                    seed_delay: t.seed_delay,
+                    completed: t.completed,
+                    last_active: None,
+                    active_secs: t.active_secs,
+                    ratio_limit: t.ratio_limit,
+                    seed_time_limit: t.seed_time_limit,
+                    seeding_secs: t.seeding_secs,
+                    file_stats: Vec::new(),
+                    allocation: if t.full_allocation {
+                        session::Allocation::Full
+                    } else {
+                        session::Allocation::Sparse
+                    },
+                    info_bytes: t.info_bytes,
+                }
+            }
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use std::io::Read;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::{fs, process};
 
 use shellexpand;
 use toml;
 
 use args;
+use protocol::SUPPORTED_RSV;
+use throttle;
 
 error_chain! {
     errors {
@@ -36,6 +38,8 @@ pub struct Config {
     pub disk: DiskConfig,
     pub net: NetConfig,
     pub peer: PeerConfig,
+    pub jobs: JobConfig,
+    pub notify: NotifyConfig,
 }
 
 #[derive(Debug, Clone)]
@@ -62,6 +66,10 @@ pub struct ConfigFile {
     pub net: NetConfig,
     #[serde(default)]
     pub peer: PeerConfig,
+    #[serde(default)]
+    pub jobs: JobConfig,
+    #[serde(default)]
+    pub notify: NotifyConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,12 +86,49 @@ pub struct RpcConfig {
     pub ssl_cert: String,
     #[serde(default = "default_ssl")]
     pub ssl_key: String,
+    #[serde(default = "default_metrics_max_torrents")]
+    pub metrics_max_torrents: usize,
+    #[serde(default = "default_rpc_tcp")]
+    pub tcp: bool,
+    #[serde(default = "default_rpc_socket")]
+    pub socket: String,
+    #[serde(default = "default_rpc_socket_mode")]
+    pub socket_mode: String,
+}
+
+impl RpcConfig {
+    /// Permissions of the RPC socket file
+    pub fn socket_mode(&self) -> u32 {
+        u32::from_str_radix(&self.socket_mode, 8).unwrap_or(0o600)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrkConfig {
     #[serde(default = "default_trk_port")]
     pub port: u16,
+    #[serde(default = "default_dead_failures")]
+    pub dead_failures: u32,
+    #[serde(default = "default_dead_cooldown_hours")]
+    pub dead_cooldown_hours: u64,
+    #[serde(default = "default_announce_jitter")]
+    pub announce_jitter: u32,
+    #[serde(default = "default_unregistered_reasons")]
+    pub unregistered_reasons: Vec<String>,
+    #[serde(default = "default_scrape_secs")]
+    pub scrape_secs: u64,
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    #[serde(default = "default_read_timeout_secs")]
+    pub read_timeout_secs: u64,
+    #[serde(default = "default_udp_timeout_secs")]
+    pub udp_timeout_secs: u64,
+    #[serde(default = "default_key_rotation")]
+    pub key_rotation: KeyRotation,
+    #[serde(default = "default_key_rotation_hours")]
+    pub key_rotation_hours: u64,
+    #[serde(default = "default_stable_key_trackers")]
+    pub stable_key_trackers: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -102,6 +147,100 @@ pub struct DiskConfig {
     pub directory: String,
     #[serde(default = "default_validate")]
     pub validate: bool,
+    #[serde(default = "default_path_conflict")]
+    pub path_conflict: PathConflict,
+    #[serde(default = "default_duplicate_add")]
+    pub duplicate_add: DuplicateAdd,
+    #[serde(default = "default_allocation")]
+    pub allocation: Allocation,
+    #[serde(default = "default_integrity_sweep_hours")]
+    pub integrity_sweep_hours: u64,
+    #[serde(default = "default_integrity_max_pieces")]
+    pub integrity_max_pieces: u32,
+    #[serde(default = "default_low_space_mb")]
+    pub low_space_mb: u64,
+    #[serde(default = "default_resume_writable")]
+    pub resume_writable: bool,
+    #[serde(default = "default_warn_pieces")]
+    pub warn_pieces: u32,
+    #[serde(default = "default_max_pieces")]
+    pub max_pieces: u32,
+    #[serde(default = "default_min_piece_len")]
+    pub min_piece_len: u32,
+    #[serde(default = "default_max_piece_len")]
+    pub max_piece_len: u32,
+    #[serde(default = "default_flush_policy")]
+    pub flush_policy: FlushPolicy,
+    #[serde(default = "default_flush_every")]
+    pub flush_every: u64,
+    #[serde(default = "default_archive_removed")]
+    pub archive_removed: bool,
+    #[serde(default = "default_archive_dir")]
+    pub archive_dir: String,
+    #[serde(default = "default_piece_affinity")]
+    pub piece_affinity: bool,
+    #[serde(default = "default_remove_empty_dirs")]
+    pub remove_empty_dirs: bool,
+    #[serde(default = "default_file_priorities")]
+    pub file_priorities: Vec<FilePriorityRule>,
+    #[serde(default = "default_quiesce_buffer_mb")]
+    pub quiesce_buffer_mb: u64,
+    #[serde(default = "default_low_impact_validate_rate")]
+    pub low_impact_validate_rate: u32,
+    #[serde(default = "default_write_cache_mb")]
+    pub write_cache_mb: u64,
+    #[serde(default = "default_write_cache_secs")]
+    pub write_cache_secs: u64,
+}
+
+/// Initial priority of the files of added torrents matching a pattern
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilePriorityRule {
+    pub pattern: String,
+    pub priority: u8,
+}
+
+/// Action taken when a torrent's files would overlap
+/// with the files of another torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathConflict {
+    Reject,
+    Warn,
+}
+
+/// Action taken when a torrent which already exists is added again.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateAdd {
+    Reject,
+    /// Also download the files selected by the new add
+    Merge,
+}
+
+/// How the files of added torrents are allocated. Torrents use the
+/// configured mode unless another is given when they're added.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Allocation {
+    /// Files take up space as they're written
+    Sparse,
+    /// Space for every selected file is reserved when the torrent is
+    /// added, writing zeros if the filesystem can't allocate it
+    Full,
+}
+
+/// When downloaded data is fsynced to disk. Torrents use the
+/// configured policy unless it is overridden over RPC.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushPolicy {
+    /// Flush after every piece
+    Piece,
+    /// Flush after every `flush_every` pieces
+    Pieces,
+    /// Flush every `flush_every` seconds
+    Interval,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -112,12 +251,258 @@ pub struct NetConfig {
     pub max_open_sockets: usize,
     #[serde(default = "default_max_announces")]
     pub max_open_announces: usize,
+    #[serde(default = "default_max_metadata_fetches")]
+    pub max_metadata_fetches: usize,
+    #[serde(default = "default_max_conn_queue")]
+    pub max_conn_queue: usize,
+    #[serde(default = "default_conn_queue_policy")]
+    pub conn_queue_policy: ConnQueuePolicy,
+    #[serde(default = "default_connect_rate")]
+    pub connect_rate: usize,
+    #[serde(default = "default_pause_when_metered")]
+    pub pause_when_metered: bool,
+    #[serde(default = "default_web_seed_strategy")]
+    pub web_seed_strategy: WebSeedStrategy,
+    #[serde(default = "default_web_seed_rate")]
+    pub web_seed_rate: u64,
+    #[serde(default = "default_ip_family")]
+    pub ip_family: IpFamily,
+    #[serde(default = "default_seed_upload_rate")]
+    pub seed_upload_rate: Option<i64>,
+    #[serde(default = "default_seed_delay_secs")]
+    pub seed_delay_secs: u64,
+    #[serde(default = "default_seed_ratio_limit")]
+    pub seed_ratio_limit: f32,
+    #[serde(default = "default_seed_time_limit_secs")]
+    pub seed_time_limit_secs: u64,
+    #[serde(default = "default_conns_per_mib")]
+    pub conns_per_mib: usize,
+    #[serde(default = "default_min_conns")]
+    pub min_conns: usize,
+    #[serde(default = "default_reclaim_conns")]
+    pub reclaim_conns: bool,
+    #[serde(default = "default_reclaim_interval_secs")]
+    pub reclaim_interval_secs: u64,
+    #[serde(default = "default_lsd")]
+    pub lsd: bool,
+    #[serde(default = "default_lsd_interval_secs")]
+    pub lsd_interval_secs: u64,
+    #[serde(default = "default_throttle_burst_kb")]
+    pub throttle_burst_kb: usize,
+    #[serde(default = "default_throttle_interval_ms")]
+    pub throttle_interval_ms: usize,
+}
+
+/// Policy used to pick which pending connection is discarded
+/// when the connection queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnQueuePolicy {
+    /// Discard the oldest queued peer
+    DropOldest,
+    /// Discard the oldest peer from the least reliable source,
+    /// i.e. PEX before DHT before trackers
+    PreferSource,
+}
+
+/// Use of the encrypted handshake for peer connections.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encryption {
+    /// Only plaintext connections are made and accepted
+    Disabled,
+    /// Outgoing connections are encrypted, falling back to plaintext
+    /// if that fails, and both kinds of incoming connections are accepted
+    Prefer,
+    /// Only encrypted connections are made and accepted
+    Require,
+}
+
+/// When a torrent's web seeds are used alongside its peers.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebSeedStrategy {
+    /// Always download from web seeds
+    Always,
+    /// Download from web seeds when peers are slower than
+    /// `web_seed_rate` or don't have every piece
+    Underutilized,
+    /// Only download from web seeds when no useful peers are connected
+    LastResort,
+}
+
+/// Address families used for peers, trackers and the DHT.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpFamily {
+    /// Use both IPv4 and IPv6
+    Dual,
+    /// Ignore IPv6 addresses
+    Ipv4,
+    /// Ignore IPv4 addresses
+    Ipv6,
+}
+
+/// How requests for pieces we don't have are answered.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnheldRequests {
+    /// Send a reject to peers supporting the fast extension, ignore
+    /// the request otherwise
+    Reject,
+    /// Never answer the request
+    Ignore,
+}
+
+/// How the key sent in announces changes over time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRotation {
+    /// Keep the same key forever
+    Stable,
+    /// Pick a new key every time synapse starts
+    Session,
+    /// Pick a new key every `key_rotation_hours`
+    Interval,
+}
+
+/// Torrent events which can trigger notifications.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    /// A torrent finished downloading
+    Completed,
+    /// A torrent encountered an error
+    Error,
+    /// A tracker announce failed
+    TrackerError,
+}
+
+impl NotifyEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotifyEvent::Completed => "completed",
+            NotifyEvent::Error => "error",
+            NotifyEvent::TrackerError => "tracker_error",
+        }
+    }
+}
+
+impl IpFamily {
+    /// Whether addresses of `ip`'s family may be used
+    pub fn allows(self, ip: &IpAddr) -> bool {
+        match (self, ip) {
+            (IpFamily::Dual, _) | (IpFamily::Ipv4, &IpAddr::V4(_)) => true,
+            (IpFamily::Ipv6, &IpAddr::V6(_)) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerConfig {
     #[serde(default = "default_prune_timeout")]
     pub prune_timeout: u64,
+    #[serde(default = "default_reqq")]
+    pub default_reqq: u16,
+    #[serde(default = "default_ratio_groups")]
+    pub ratio_groups: bool,
+    #[serde(default = "default_group_upload_slots")]
+    pub group_upload_slots: usize,
+    #[serde(default = "default_allowed_fast")]
+    pub allowed_fast: usize,
+    #[serde(default = "default_freeloader_rate")]
+    pub freeloader_rate: u64,
+    #[serde(default = "default_freeloader_penalty")]
+    pub freeloader_penalty: u32,
+    #[serde(default = "default_pex_max_peers")]
+    pub pex_max_peers: usize,
+    #[serde(default = "default_pex_interval_secs")]
+    pub pex_interval_secs: u64,
+    #[serde(default = "default_pex_peer_limit")]
+    pub pex_peer_limit: usize,
+    #[serde(default = "default_max_message_len")]
+    pub max_message_len: u32,
+    #[serde(default = "default_violation_strikes")]
+    pub violation_strikes: u32,
+    #[serde(default = "default_violation_ban_secs")]
+    pub violation_ban_secs: u64,
+    #[serde(default = "default_unheld_requests")]
+    pub unheld_requests: UnheldRequests,
+    #[serde(default = "default_unheld_request_strikes")]
+    pub unheld_request_strikes: u32,
+    #[serde(default = "default_max_corrupt_mb")]
+    pub max_corrupt_mb: u64,
+    #[serde(default = "default_max_corrupt_ratio")]
+    pub max_corrupt_ratio: f64,
+    #[serde(default = "default_prefer_warm")]
+    pub prefer_warm: bool,
+    #[serde(default = "default_availability_boost")]
+    pub availability_boost: bool,
+    #[serde(default = "default_underseeded_seeders")]
+    pub underseeded_seeders: u32,
+    #[serde(default = "default_strict_bitfield")]
+    pub strict_bitfield: bool,
+    #[serde(default = "default_resync_secs")]
+    pub resync_secs: u64,
+    #[serde(default = "default_reserved_bits")]
+    pub reserved_bits: String,
+    #[serde(default = "default_encryption")]
+    pub encryption: Encryption,
+    #[serde(default = "default_endgame_min_peers")]
+    pub endgame_min_peers: usize,
+    #[serde(default = "default_utp")]
+    pub utp: bool,
+}
+
+impl PeerConfig {
+    /// Reserved handshake bits advertised to peers
+    pub fn reserved(&self) -> [u8; 8] {
+        parse_reserved(&self.reserved_bits).unwrap_or(SUPPORTED_RSV)
+    }
+
+    /// Whether the extension bit `ext` is advertised in our handshake
+    pub fn advertises(&self, ext: (usize, u8)) -> bool {
+        self.reserved()[ext.0] & ext.1 != 0
+    }
+}
+
+/// Parses a 16 hex digit mask, most significant byte first
+fn parse_reserved(s: &str) -> Option<[u8; 8]> {
+    if s.len() != 16 || !s.is_ascii() {
+        return None;
+    }
+    let mut rsv = [0u8; 8];
+    for (i, b) in rsv.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(rsv)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotifyConfig {
+    #[serde(default = "default_webhook")]
+    pub webhook: Option<String>,
+    #[serde(default = "default_command")]
+    pub command: Option<String>,
+    #[serde(default = "default_notify_events")]
+    pub events: Vec<NotifyEvent>,
+    #[serde(default = "default_notify_retries")]
+    pub retries: u32,
+    #[serde(default = "default_notify_retry_secs")]
+    pub retry_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobConfig {
+    #[serde(default = "default_tracker_secs")]
+    pub tracker_secs: u64,
+    #[serde(default = "default_unchoke_secs")]
+    pub unchoke_secs: u64,
+    #[serde(default = "default_session_secs")]
+    pub session_secs: u64,
+    #[serde(default = "default_tx_ms")]
+    pub tx_ms: u64,
 }
 
 impl ConfigFile {
@@ -144,6 +529,37 @@ impl ConfigFile {
                         error!("Config max_dl must not be 0");
                         process::exit(1);
                     }
+                    if parse_reserved(&cfg.peer.reserved_bits).is_none() {
+                        error!("Config reserved_bits must be 16 hex digits");
+                        process::exit(1);
+                    }
+                    if !throttle::valid_burst(cfg.net.throttle_burst_kb.saturating_mul(1024)) {
+                        error!(
+                            "Config throttle_burst_kb must be between {} and {}",
+                            throttle::MIN_BURST / 1024,
+                            throttle::MAX_BURST / 1024
+                        );
+                        process::exit(1);
+                    }
+                    if !throttle::valid_interval(cfg.net.throttle_interval_ms) {
+                        error!(
+                            "Config throttle_interval_ms must be between {} and {}",
+                            throttle::MIN_INTERVAL_MS,
+                            throttle::MAX_INTERVAL_MS
+                        );
+                        process::exit(1);
+                    }
+                    if !cfg.rpc.tcp && cfg.rpc.socket.is_empty() {
+                        error!("Config rpc must listen on TCP or a socket");
+                        process::exit(1);
+                    }
+                    match u32::from_str_radix(&cfg.rpc.socket_mode, 8) {
+                        Ok(m) if m <= 0o777 => {}
+                        _ => {
+                            error!("Config socket_mode must be octal permissions");
+                            process::exit(1);
+                        }
+                    }
                     if !cfg!(debug_assertions) && !cfg.disk.validate {
                         error!("validation skipping can only be used in development, overriding!");
                         cfg.disk.validate = true;
@@ -190,6 +606,8 @@ impl Config {
         };
         file.disk.session = shellexpand::tilde(&file.disk.session).into();
         file.disk.directory = shellexpand::tilde(&file.disk.directory).into();
+        file.disk.archive_dir = shellexpand::tilde(&file.disk.archive_dir).into();
+        file.rpc.socket = shellexpand::tilde(&file.rpc.socket).into();
         Config {
             port: file.port,
             max_dl: file.max_dl,
@@ -198,6 +616,8 @@ impl Config {
             disk: file.disk,
             net: file.net,
             peer: file.peer,
+            jobs: file.jobs,
+            notify: file.notify,
             dht,
         }
     }
@@ -212,6 +632,44 @@ fn default_max_dl() -> u32 {
 fn default_trk_port() -> u16 {
     16_362
 }
+fn default_dead_failures() -> u32 {
+    10
+}
+fn default_dead_cooldown_hours() -> u64 {
+    24
+}
+fn default_announce_jitter() -> u32 {
+    10
+}
+fn default_unregistered_reasons() -> Vec<String> {
+    vec![
+        "unregistered".to_owned(),
+        "not registered".to_owned(),
+        "not authorized".to_owned(),
+        "unauthorized".to_owned(),
+    ]
+}
+fn default_scrape_secs() -> u64 {
+    900
+}
+fn default_connect_timeout_secs() -> u64 {
+    5
+}
+fn default_read_timeout_secs() -> u64 {
+    15
+}
+fn default_udp_timeout_secs() -> u64 {
+    120
+}
+fn default_key_rotation() -> KeyRotation {
+    KeyRotation::Stable
+}
+fn default_key_rotation_hours() -> u64 {
+    24
+}
+fn default_stable_key_trackers() -> Vec<String> {
+    vec![]
+}
 fn default_dht_port() -> u16 {
     16_309
 }
@@ -227,6 +685,22 @@ fn default_auth() -> bool {
 fn default_password() -> String {
     "hackme".to_owned()
 }
+fn default_metrics_max_torrents() -> usize {
+    100
+}
+
+fn default_rpc_tcp() -> bool {
+    true
+}
+
+fn default_rpc_socket() -> String {
+    "".to_owned()
+}
+
+fn default_rpc_socket_mode() -> String {
+    "600".to_owned()
+}
+
 fn default_ssl() -> String {
     "".to_owned()
 }
@@ -244,6 +718,74 @@ fn default_directory_dir() -> String {
 fn default_validate() -> bool {
     true
 }
+fn default_path_conflict() -> PathConflict {
+    PathConflict::Reject
+}
+fn default_duplicate_add() -> DuplicateAdd {
+    DuplicateAdd::Reject
+}
+fn default_allocation() -> Allocation {
+    Allocation::Sparse
+}
+fn default_integrity_sweep_hours() -> u64 {
+    0
+}
+fn default_integrity_max_pieces() -> u32 {
+    4
+}
+fn default_archive_removed() -> bool {
+    false
+}
+fn default_archive_dir() -> String {
+    shellexpand::full("$XDG_DATA_HOME/synapse/archive")
+        .unwrap_or_else(|_| shellexpand::tilde("~/.local/share/synapse/archive"))
+        .into()
+}
+fn default_piece_affinity() -> bool {
+    false
+}
+fn default_remove_empty_dirs() -> bool {
+    true
+}
+fn default_file_priorities() -> Vec<FilePriorityRule> {
+    Vec::new()
+}
+fn default_quiesce_buffer_mb() -> u64 {
+    32
+}
+fn default_low_impact_validate_rate() -> u32 {
+    10
+}
+fn default_write_cache_mb() -> u64 {
+    16
+}
+fn default_write_cache_secs() -> u64 {
+    5
+}
+fn default_low_space_mb() -> u64 {
+    256
+}
+fn default_resume_writable() -> bool {
+    true
+}
+fn default_warn_pieces() -> u32 {
+    1_000_000
+}
+fn default_max_pieces() -> u32 {
+    4_000_000
+}
+fn default_min_piece_len() -> u32 {
+    16_384
+}
+fn default_max_piece_len() -> u32 {
+    64 * 1024 * 1024
+}
+fn default_flush_policy() -> FlushPolicy {
+    FlushPolicy::Piece
+}
+fn default_flush_every() -> u64 {
+    16
+}
 fn default_max_files() -> usize {
     500
 }
@@ -253,9 +795,171 @@ fn default_max_sockets() -> usize {
 fn default_max_announces() -> usize {
     50
 }
+fn default_max_metadata_fetches() -> usize {
+    0
+}
+fn default_max_conn_queue() -> usize {
+    1000
+}
+fn default_conn_queue_policy() -> ConnQueuePolicy {
+    ConnQueuePolicy::PreferSource
+}
+fn default_connect_rate() -> usize {
+    25
+}
+fn default_pause_when_metered() -> bool {
+    true
+}
+fn default_web_seed_strategy() -> WebSeedStrategy {
+    WebSeedStrategy::Underutilized
+}
+fn default_web_seed_rate() -> u64 {
+    512 * 1024
+}
+fn default_ip_family() -> IpFamily {
+    IpFamily::Dual
+}
+fn default_seed_upload_rate() -> Option<i64> {
+    None
+}
+fn default_seed_delay_secs() -> u64 {
+    0
+}
+fn default_seed_ratio_limit() -> f32 {
+    0.
+}
+fn default_seed_time_limit_secs() -> u64 {
+    0
+}
+fn default_conns_per_mib() -> usize {
+    0
+}
+fn default_min_conns() -> usize {
+    50
+}
+fn default_reclaim_conns() -> bool {
+    false
+}
+fn default_reclaim_interval_secs() -> u64 {
+    30
+}
+fn default_lsd() -> bool {
+    true
+}
+fn default_lsd_interval_secs() -> u64 {
+    300
+}
+fn default_throttle_burst_kb() -> usize {
+    2048
+}
+fn default_throttle_interval_ms() -> usize {
+    15
+}
 fn default_prune_timeout() -> u64 {
     15
 }
+fn default_ratio_groups() -> bool {
+    false
+}
+fn default_group_upload_slots() -> usize {
+    20
+}
+fn default_allowed_fast() -> usize {
+    10
+}
+fn default_freeloader_rate() -> u64 {
+    0
+}
+fn default_freeloader_penalty() -> u32 {
+    6
+}
+fn default_pex_max_peers() -> usize {
+    50
+}
+fn default_pex_interval_secs() -> u64 {
+    60
+}
+fn default_pex_peer_limit() -> usize {
+    500
+}
+fn default_max_message_len() -> u32 {
+    1_048_576
+}
+fn default_violation_strikes() -> u32 {
+    3
+}
+fn default_violation_ban_secs() -> u64 {
+    60 * 60
+}
+fn default_unheld_requests() -> UnheldRequests {
+    UnheldRequests::Reject
+}
+fn default_unheld_request_strikes() -> u32 {
+    8
+}
+fn default_max_corrupt_mb() -> u64 {
+    0
+}
+fn default_max_corrupt_ratio() -> f64 {
+    0.
+}
+fn default_prefer_warm() -> bool {
+    true
+}
+fn default_availability_boost() -> bool {
+    false
+}
+fn default_underseeded_seeders() -> u32 {
+    2
+}
+fn default_strict_bitfield() -> bool {
+    true
+}
+fn default_resync_secs() -> u64 {
+    0
+}
+fn default_reserved_bits() -> String {
+    SUPPORTED_RSV.iter().map(|b| format!("{:02x}", b)).collect()
+}
+fn default_encryption() -> Encryption {
+    Encryption::Disabled
+}
+fn default_endgame_min_peers() -> usize {
+    2
+}
+fn default_utp() -> bool {
+    false
+}
+fn default_reqq() -> u16 {
+    250
+}
+fn default_tracker_secs() -> u64 {
+    60
+}
+fn default_unchoke_secs() -> u64 {
+    15
+}
+fn default_session_secs() -> u64 {
+    60
+}
+fn default_tx_ms() -> u64 {
+    500
+}
+fn default_webhook() -> Option<String> {
+    None
+}
+fn default_command() -> Option<String> {
+    None
+}
+fn default_notify_events() -> Vec<NotifyEvent> {
+    vec![NotifyEvent::Completed, NotifyEvent::Error]
+}
+fn default_notify_retries() -> u32 {
+    3
+}
+fn default_notify_retry_secs() -> u64 {
+    5
+}
 
 impl Default for Config {
     fn default() -> Self {
@@ -268,6 +972,8 @@ impl Default for Config {
             net: Default::default(),
             dht: Default::default(),
             peer: Default::default(),
+            jobs: Default::default(),
+            notify: Default::default(),
         }
     }
 }
@@ -281,6 +987,10 @@ impl Default for RpcConfig {
             password: default_password(),
             ssl_cert: default_ssl(),
             ssl_key: default_ssl(),
+            metrics_max_torrents: default_metrics_max_torrents(),
+            tcp: default_rpc_tcp(),
+            socket: default_rpc_socket(),
+            socket_mode: default_rpc_socket_mode(),
         }
     }
 }
@@ -289,6 +999,17 @@ impl Default for TrkConfig {
     fn default() -> TrkConfig {
         TrkConfig {
             port: default_trk_port(),
+            dead_failures: default_dead_failures(),
+            dead_cooldown_hours: default_dead_cooldown_hours(),
+            announce_jitter: default_announce_jitter(),
+            unregistered_reasons: default_unregistered_reasons(),
+            scrape_secs: default_scrape_secs(),
+            connect_timeout_secs: default_connect_timeout_secs(),
+            read_timeout_secs: default_read_timeout_secs(),
+            udp_timeout_secs: default_udp_timeout_secs(),
+            key_rotation: default_key_rotation(),
+            key_rotation_hours: default_key_rotation_hours(),
+            stable_key_trackers: default_stable_key_trackers(),
         }
     }
 }
@@ -317,6 +1038,28 @@ impl Default for DiskConfig {
             session: default_session_dir(),
             directory: default_directory_dir(),
             validate: default_validate(),
+            path_conflict: default_path_conflict(),
+            duplicate_add: default_duplicate_add(),
+            allocation: default_allocation(),
+            integrity_sweep_hours: default_integrity_sweep_hours(),
+            integrity_max_pieces: default_integrity_max_pieces(),
+            low_space_mb: default_low_space_mb(),
+            resume_writable: default_resume_writable(),
+            warn_pieces: default_warn_pieces(),
+            max_pieces: default_max_pieces(),
+            min_piece_len: default_min_piece_len(),
+            max_piece_len: default_max_piece_len(),
+            flush_policy: default_flush_policy(),
+            flush_every: default_flush_every(),
+            archive_removed: default_archive_removed(),
+            archive_dir: default_archive_dir(),
+            piece_affinity: default_piece_affinity(),
+            remove_empty_dirs: default_remove_empty_dirs(),
+            file_priorities: default_file_priorities(),
+            quiesce_buffer_mb: default_quiesce_buffer_mb(),
+            low_impact_validate_rate: default_low_impact_validate_rate(),
+            write_cache_mb: default_write_cache_mb(),
+            write_cache_secs: default_write_cache_secs(),
         }
     }
 }
@@ -327,6 +1070,26 @@ impl Default for NetConfig {
             max_open_files: default_max_files(),
             max_open_sockets: default_max_sockets(),
             max_open_announces: default_max_announces(),
+            max_metadata_fetches: default_max_metadata_fetches(),
+            max_conn_queue: default_max_conn_queue(),
+            conn_queue_policy: default_conn_queue_policy(),
+            connect_rate: default_connect_rate(),
+            pause_when_metered: default_pause_when_metered(),
+            web_seed_strategy: default_web_seed_strategy(),
+            web_seed_rate: default_web_seed_rate(),
+            ip_family: default_ip_family(),
+            seed_upload_rate: default_seed_upload_rate(),
+            seed_delay_secs: default_seed_delay_secs(),
+            seed_ratio_limit: default_seed_ratio_limit(),
+            seed_time_limit_secs: default_seed_time_limit_secs(),
+            conns_per_mib: default_conns_per_mib(),
+            min_conns: default_min_conns(),
+            reclaim_conns: default_reclaim_conns(),
+            reclaim_interval_secs: default_reclaim_interval_secs(),
+            lsd: default_lsd(),
+            lsd_interval_secs: default_lsd_interval_secs(),
+            throttle_burst_kb: default_throttle_burst_kb(),
+            throttle_interval_ms: default_throttle_interval_ms(),
         }
     }
 }
@@ -335,6 +1098,97 @@ impl Default for PeerConfig {
     fn default() -> PeerConfig {
         PeerConfig {
             prune_timeout: default_prune_timeout(),
+            default_reqq: default_reqq(),
+            ratio_groups: default_ratio_groups(),
+            group_upload_slots: default_group_upload_slots(),
+            allowed_fast: default_allowed_fast(),
+            freeloader_rate: default_freeloader_rate(),
+            freeloader_penalty: default_freeloader_penalty(),
+            pex_max_peers: default_pex_max_peers(),
+            pex_interval_secs: default_pex_interval_secs(),
+            pex_peer_limit: default_pex_peer_limit(),
+            max_message_len: default_max_message_len(),
+            violation_strikes: default_violation_strikes(),
+            violation_ban_secs: default_violation_ban_secs(),
+            unheld_requests: default_unheld_requests(),
+            unheld_request_strikes: default_unheld_request_strikes(),
+            max_corrupt_mb: default_max_corrupt_mb(),
+            max_corrupt_ratio: default_max_corrupt_ratio(),
+            prefer_warm: default_prefer_warm(),
+            availability_boost: default_availability_boost(),
+            underseeded_seeders: default_underseeded_seeders(),
+            strict_bitfield: default_strict_bitfield(),
+            resync_secs: default_resync_secs(),
+            reserved_bits: default_reserved_bits(),
+            encryption: default_encryption(),
+            endgame_min_peers: default_endgame_min_peers(),
+            utp: default_utp(),
         }
     }
 }
+
+impl Default for JobConfig {
+    fn default() -> JobConfig {
+        JobConfig {
+            tracker_secs: default_tracker_secs(),
+            unchoke_secs: default_unchoke_secs(),
+            session_secs: default_session_secs(),
+            tx_ms: default_tx_ms(),
+        }
+    }
+}
+
+impl Default for NotifyConfig {
+    fn default() -> NotifyConfig {
+        NotifyConfig {
+            webhook: default_webhook(),
+            command: default_command(),
+            events: default_notify_events(),
+            retries: default_notify_retries(),
+            retry_secs: default_notify_retry_secs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{DHT_EXT, EXT_PROTO, FAST_EXT};
+    use torrent::peer::Message;
+
+    #[test]
+    fn test_default_reserved_bits() {
+        let cfg = PeerConfig::default();
+        assert_eq!(cfg.reserved_bits, "0000000000100005");
+        assert_eq!(cfg.reserved(), SUPPORTED_RSV);
+        assert!(cfg.advertises(DHT_EXT));
+        assert!(cfg.advertises(EXT_PROTO));
+        assert!(cfg.advertises(FAST_EXT));
+    }
+
+    #[test]
+    fn test_reserved_bits_mask() {
+        let cfg = PeerConfig {
+            reserved_bits: "8000000000000004".to_owned(),
+            ..Default::default()
+        };
+        let rsv = cfg.reserved();
+        assert_eq!(rsv, [0x80, 0, 0, 0, 0, 0, 0, 0x04]);
+        assert!(!cfg.advertises(DHT_EXT));
+        assert!(!cfg.advertises(EXT_PROTO));
+        assert!(cfg.advertises(FAST_EXT));
+
+        match Message::handshake(&[1; 20], &[2; 20], rsv) {
+            Message::Handshake { rsv: sent, .. } => assert_eq!(sent, rsv),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_parse_reserved_invalid() {
+        assert!(parse_reserved("").is_none());
+        assert!(parse_reserved("00000000001000").is_none());
+        assert!(parse_reserved("000000000010000g").is_none());
+        assert!(parse_reserved("000000000010000001").is_none());
+    }
+}
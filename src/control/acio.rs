@@ -5,7 +5,9 @@ use std::time;
 use amy::{self, ChannelError};
 
 use control::cio::{self, Error, ErrorKind, Result, ResultExt};
-use torrent::peer::reader::RRes;
+#[cfg(feature = "testing")]
+use control::fault;
+use torrent::peer::reader::{self, RRes};
 use util::UHashMap;
 use CONFIG;
 use {disk, listener, rpc, torrent, tracker};
@@ -39,6 +41,8 @@ struct ACIOData {
     events: Vec<cio::Event>,
     chans: ACChans,
     crashed: bool,
+    #[cfg(feature = "testing")]
+    faults: fault::Injector,
 }
 
 impl ACIO {
@@ -50,6 +54,8 @@ impl ACIO {
             peers: UHashMap::default(),
             events: Vec::new(),
             crashed: false,
+            #[cfg(feature = "testing")]
+            faults: fault::Injector::default(),
         };
         ACIO {
             data: Rc::new(RefCell::new(data)),
@@ -137,6 +143,17 @@ impl ACIO {
                             }
                             break;
                         }
+                        RRes::Err(ref e) if reader::is_violation(e) => {
+                            return Err(ErrorKind::Violation.into());
+                        }
+                        RRes::Err(ref e) if peer.sock().unconfirmed() => {
+                            debug!("uTP connection failed: {}", e);
+                            return Err(ErrorKind::Transport.into());
+                        }
+                        RRes::Err(ref e) if peer.sock().negotiating() => {
+                            debug!("Encrypted handshake failed: {}", e);
+                            return Err(ErrorKind::Encryption.into());
+                        }
                         RRes::Err(e) => {
                             return Err(Error::with_chain(e, ErrorKind::IO));
                         }
@@ -176,6 +193,11 @@ impl cio::CIO for ACIO {
                 error!("Failed to poll for events: {}", e);
             }
         }
+        #[cfg(feature = "testing")]
+        self.data
+            .borrow_mut()
+            .faults
+            .apply(events, time::Instant::now());
         Ok(())
     }
 
@@ -324,6 +346,11 @@ impl cio::CIO for ACIO {
             data: self.data.clone(),
         }
     }
+
+    #[cfg(feature = "testing")]
+    fn set_fault(&mut self, peer: cio::PID, fault: Option<fault::Fault>) {
+        self.data.borrow_mut().faults.set(peer, fault);
+    }
 }
 
 impl ACIOData {
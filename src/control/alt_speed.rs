@@ -0,0 +1,121 @@
+use chrono::{DateTime, Datelike, TimeZone, Timelike};
+
+use rpc::resource::AltSpeed;
+
+const MINS_PER_DAY: u16 = 24 * 60;
+
+/// Daily window during which alternate global rate limits replace
+/// the regular ones.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Minutes after local midnight the window opens
+    pub start: u16,
+    /// Minutes after local midnight the window closes, on the next
+    /// day if before the start
+    pub end: u16,
+    /// Days the window opens on, bit 0 being Monday
+    pub days: u8,
+    pub throttle_up: Option<i64>,
+    pub throttle_down: Option<i64>,
+}
+
+impl Schedule {
+    /// Converts a schedule set over RPC, or returns why it's invalid
+    pub fn from_rpc(a: &AltSpeed) -> Result<Schedule, String> {
+        if a.start >= MINS_PER_DAY || a.end >= MINS_PER_DAY {
+            return Err(format!(
+                "Alternate speed times must be below {} minutes",
+                MINS_PER_DAY
+            ));
+        }
+        let mut days = 0;
+        for &d in &a.days {
+            if d > 6 {
+                return Err(format!("Invalid day of the week {}", d));
+            }
+            days |= 1 << d;
+        }
+        Ok(Schedule {
+            start: a.start,
+            end: a.end,
+            days,
+            throttle_up: a.throttle_up,
+            throttle_down: a.throttle_down,
+        })
+    }
+
+    pub fn to_rpc(&self) -> AltSpeed {
+        AltSpeed {
+            start: self.start,
+            end: self.end,
+            days: (0..7).filter(|d| self.opens_on(*d)).collect(),
+            throttle_up: self.throttle_up,
+            throttle_down: self.throttle_down,
+        }
+    }
+
+    fn opens_on(&self, day: u8) -> bool {
+        self.days & (1 << day) != 0
+    }
+
+    /// Whether the window is open at `minute` of `day`, 0 being Monday
+    pub fn active(&self, day: u8, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.opens_on(day) && minute >= self.start && minute < self.end
+        } else {
+            // Windows past midnight may have opened the day before
+            (self.opens_on(day) && minute >= self.start)
+                || (self.opens_on((day + 6) % 7) && minute < self.end)
+        }
+    }
+
+    pub fn active_at<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let day = time.weekday().num_days_from_monday() as u8;
+        let minute = (time.hour() * 60 + time.minute()) as u16;
+        self.active(day, minute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use chrono::{TimeZone, Utc};
+    use rpc::resource::AltSpeed;
+
+    #[test]
+    fn test_schedule() {
+        // Weekdays from 9:00 to 17:00
+        let work = AltSpeed {
+            start: 9 * 60,
+            end: 17 * 60,
+            days: vec![0, 1, 2, 3, 4],
+            throttle_up: Some(10_000),
+            throttle_down: Some(100_000),
+        };
+        let s = Schedule::from_rpc(&work).unwrap();
+        assert_eq!(s.to_rpc(), work);
+        // 2018-01-01 was a Monday
+        assert!(s.active_at(&Utc.ymd(2018, 1, 1).and_hms(9, 0, 0)));
+        assert!(!s.active_at(&Utc.ymd(2018, 1, 1).and_hms(17, 0, 0)));
+        assert!(!s.active_at(&Utc.ymd(2018, 1, 6).and_hms(12, 0, 0)));
+
+        // Friday nights until 2:00
+        let night = Schedule {
+            start: 22 * 60,
+            end: 2 * 60,
+            days: 1 << 4,
+            ..Default::default()
+        };
+        assert!(night.active(4, 23 * 60));
+        assert!(night.active(5, 60));
+        assert!(!night.active(5, 23 * 60));
+        assert!(!night.active(4, 60));
+
+        assert!(!Schedule::default().active(0, 0));
+        assert!(Schedule::from_rpc(&AltSpeed {
+            days: vec![7],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use util::MHashMap;
+
+/// Counts protocol violations by IP, banning addresses which
+/// repeatedly misbehave.
+pub struct Bans {
+    strikes: MHashMap<IpAddr, u32>,
+    banned: MHashMap<IpAddr, Instant>,
+    max_strikes: u32,
+    duration: Duration,
+}
+
+impl Bans {
+    pub fn new(max_strikes: u32, duration: Duration) -> Bans {
+        Bans {
+            strikes: MHashMap::default(),
+            banned: MHashMap::default(),
+            max_strikes,
+            duration,
+        }
+    }
+
+    /// Records a violation by `ip`, returning whether it is now banned.
+    pub fn violation(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.max_strikes == 0 {
+            return false;
+        }
+        let strikes = {
+            let s = self.strikes.entry(ip).or_insert(0);
+            *s += 1;
+            *s
+        };
+        if strikes < self.max_strikes {
+            return false;
+        }
+        self.strikes.remove(&ip);
+        self.banned.insert(ip, now + self.duration);
+        true
+    }
+
+    /// Whether connections to or from `ip` should be refused.
+    pub fn banned(&mut self, ip: &IpAddr, now: Instant) -> bool {
+        match self.banned.get(ip).cloned() {
+            Some(until) if until > now => true,
+            Some(_) => {
+                self.banned.remove(ip);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bans;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_ban_after_strikes() {
+        let now = Instant::now();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut b = Bans::new(3, Duration::from_secs(60));
+        assert!(!b.violation(ip, now));
+        assert!(!b.violation(other, now));
+        assert!(!b.violation(ip, now));
+        assert!(!b.banned(&ip, now));
+        assert!(b.violation(ip, now));
+        assert!(b.banned(&ip, now));
+        assert!(!b.banned(&other, now));
+
+        // Bans expire
+        assert!(!b.banned(&ip, now + Duration::from_secs(60)));
+
+        let mut b = Bans::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            assert!(!b.violation(ip, now));
+        }
+        assert!(!b.banned(&ip, now));
+    }
+}
@@ -0,0 +1,123 @@
+use bincode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use session::bundle::{self, current, Bundle};
+use session::{json, torrent};
+
+/// Packs server data and the session data of every torrent into a
+/// single archive which can be imported by another instance.
+pub fn export<S: Serialize>(server: &S, torrents: Vec<([u8; 20], Vec<u8>)>) -> Option<Vec<u8>> {
+    let server = bincode::serialize(server).ok()?;
+    let b = Bundle {
+        version: current::VERSION,
+        server,
+        torrents,
+    };
+    bincode::serialize(&b).ok()
+}
+
+/// Packs server data and the session of every torrent into a JSON
+/// archive, which unlike the binary one can be read and repaired by
+/// hand. Torrents are converted to the JSON layout, which leaves out
+/// tracker headers.
+pub fn export_json<S: Serialize>(server: &S, torrents: &[torrent::Session]) -> Option<Vec<u8>> {
+    let b = json::Archive {
+        version: json::current::VERSION,
+        server,
+        torrents: torrents.iter().map(json::Torrent::from).collect(),
+    };
+    serde_json::to_vec_pretty(&b).ok()
+}
+
+/// Unpacks an archive produced by `export_json`, dropping torrents for
+/// which `exists` returns true.
+pub fn import_json<S, F>(data: &[u8], exists: F) -> Option<(S, Vec<torrent::Session>)>
+where
+    S: DeserializeOwned,
+    F: Fn(&[u8; 20]) -> bool,
+{
+    let b: json::Archive<S> = serde_json::from_slice(data).ok()?;
+    if b.version != json::current::VERSION {
+        return None;
+    }
+    let torrents = b
+        .torrents
+        .into_iter()
+        .filter(|t| !exists(&t.info.hash))
+        .map(torrent::Session::from)
+        .collect();
+    Some((b.server, torrents))
+}
+
+/// Unpacks an archive produced by `export`, dropping torrents for which
+/// `exists` returns true.
+pub fn import<S, F>(data: &[u8], exists: F) -> Option<(S, Vec<Vec<u8>>)>
+where
+    S: DeserializeOwned,
+    F: Fn(&[u8; 20]) -> bool,
+{
+    let b = bundle::load(data)?;
+    let server = bincode::deserialize(&b.server).ok()?;
+    let torrents = b
+        .torrents
+        .into_iter()
+        .filter(|(hash, _)| !exists(hash))
+        .map(|(_, data)| data)
+        .collect();
+    Some((server, torrents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, export_json, import, import_json};
+
+    #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+    struct Data {
+        id: String,
+        ul: u64,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = Data {
+            id: "server".to_owned(),
+            ul: 10,
+        };
+        let torrents = vec![([1u8; 20], vec![1, 2, 3]), ([2u8; 20], vec![4, 5])];
+        let b = export(&data, torrents).unwrap();
+
+        let (d, t) = import::<Data, _>(&b, |_| false).unwrap();
+        assert_eq!(d, data);
+        assert_eq!(t, vec![vec![1, 2, 3], vec![4, 5]]);
+
+        // Torrents already present are skipped
+        let (_, t) = import::<Data, _>(&b, |h| h == &[1u8; 20]).unwrap();
+        assert_eq!(t, vec![vec![4, 5]]);
+
+        assert!(import::<Data, _>(&b[..b.len() - 1], |_| false).is_none());
+    }
+
+    #[test]
+    fn test_json_version() {
+        let data = Data {
+            id: "server".to_owned(),
+            ul: 10,
+        };
+        let b = export_json(&data, &[]).unwrap();
+        let (d, t) = import_json::<Data, _>(&b, |_| false).unwrap();
+        assert_eq!(d, data);
+        assert!(t.is_empty());
+
+        // Archives of other versions aren't misread
+        let mut v: ::serde_json::Value = ::serde_json::from_slice(&b).unwrap();
+        v["version"] = 2.into();
+        let b2 = ::serde_json::to_vec(&v).unwrap();
+        assert!(import_json::<Data, _>(&b2, |_| false).is_none());
+
+        // Binary archives aren't mistaken for JSON ones, nor vice versa
+        assert!(import_json::<Data, _>(&export(&data, vec![]).unwrap(), |_| false).is_none());
+        assert!(import::<Data, _>(&b, |_| false).is_none());
+    }
+}
@@ -0,0 +1,154 @@
+use rpc::resource::CheckpointState;
+
+/// Drains the session so it can be safely backed up. Running torrents
+/// are paused and the disk thread is synced twice: once so that every
+/// write and hash check already queued completes, and again after the
+/// resulting state was serialized, at which point the session directory
+/// is consistent until the checkpoint is ended.
+pub struct Checkpoint {
+    stage: Option<Stage>,
+    /// Torrents paused by the checkpoint, to be resumed once it ends
+    paused: Vec<usize>,
+    /// Clients waiting for the checkpoint, by client and serial
+    waiting: Vec<(usize, u64)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Draining,
+    Writing,
+    Ready,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Begin {
+    /// Torrents should be paused and the disk synced
+    Start,
+    /// A checkpoint is already underway
+    Wait,
+    /// The session is already checkpointed
+    Ready,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Synced {
+    /// Pending disk work is done, state should be serialized and
+    /// the disk synced again
+    Serialize,
+    /// The checkpoint is complete, the waiting clients are returned
+    Ready(Vec<(usize, u64)>),
+    /// No checkpoint is underway
+    Ignored,
+}
+
+impl Checkpoint {
+    pub fn new() -> Checkpoint {
+        Checkpoint {
+            stage: None,
+            paused: Vec::new(),
+            waiting: Vec::new(),
+        }
+    }
+
+    /// Whether the session directory shouldn't be written to
+    pub fn active(&self) -> bool {
+        self.stage.is_some()
+    }
+
+    pub fn state(&self) -> CheckpointState {
+        match self.stage {
+            None => CheckpointState::None,
+            Some(Stage::Draining) | Some(Stage::Writing) => CheckpointState::Draining,
+            Some(Stage::Ready) => CheckpointState::Ready,
+        }
+    }
+
+    /// Requests a checkpoint on behalf of a client.
+    pub fn begin(&mut self, client: usize, serial: u64) -> Begin {
+        match self.stage {
+            None => {
+                self.stage = Some(Stage::Draining);
+                self.waiting.push((client, serial));
+                Begin::Start
+            }
+            Some(Stage::Ready) => Begin::Ready,
+            Some(_) => {
+                self.waiting.push((client, serial));
+                Begin::Wait
+            }
+        }
+    }
+
+    /// Records the torrents which were paused to start the checkpoint.
+    pub fn paused(&mut self, tids: Vec<usize>) {
+        self.paused.extend(tids);
+    }
+
+    #[cfg(test)]
+    pub fn paused_count(&self) -> u64 {
+        self.paused.len() as u64
+    }
+
+    /// Advances the checkpoint once the disk thread has finished
+    /// everything queued before it was synced.
+    pub fn synced(&mut self) -> Synced {
+        match self.stage {
+            Some(Stage::Draining) => {
+                self.stage = Some(Stage::Writing);
+                Synced::Serialize
+            }
+            Some(Stage::Writing) => {
+                self.stage = Some(Stage::Ready);
+                Synced::Ready(self.waiting.drain(..).collect())
+            }
+            Some(Stage::Ready) | None => Synced::Ignored,
+        }
+    }
+
+    /// Ends the checkpoint, returning the torrents to resume and any
+    /// clients still waiting for it to complete.
+    pub fn end(&mut self) -> (Vec<usize>, Vec<(usize, u64)>) {
+        self.stage = None;
+        (
+            self.paused.drain(..).collect(),
+            self.waiting.drain(..).collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Begin, Checkpoint, Synced};
+    use rpc::resource::CheckpointState;
+
+    #[test]
+    fn test_checkpoint() {
+        let mut c = Checkpoint::new();
+        assert_eq!(c.synced(), Synced::Ignored);
+
+        assert_eq!(c.begin(0, 1), Begin::Start);
+        c.paused(vec![3, 5]);
+        assert!(c.active());
+        assert_eq!(c.state(), CheckpointState::Draining);
+        assert_eq!(c.begin(1, 7), Begin::Wait);
+
+        // The state is only acknowledged once it was serialized after
+        // the queued disk work completed
+        assert_eq!(c.synced(), Synced::Serialize);
+        assert_eq!(c.state(), CheckpointState::Draining);
+        assert_eq!(c.synced(), Synced::Ready(vec![(0, 1), (1, 7)]));
+        assert_eq!(c.state(), CheckpointState::Ready);
+        assert_eq!(c.synced(), Synced::Ignored);
+        assert_eq!(c.begin(2, 2), Begin::Ready);
+        assert_eq!(c.paused_count(), 2);
+
+        assert_eq!(c.end(), (vec![3, 5], vec![]));
+        assert!(!c.active());
+        assert_eq!(c.state(), CheckpointState::None);
+
+        // Clients waiting on a checkpoint ended early are returned
+        assert_eq!(c.begin(0, 9), Begin::Start);
+        assert_eq!(c.end(), (vec![], vec![(0, 9)]));
+        assert_eq!(c.synced(), Synced::Ignored);
+    }
+}
@@ -1,4 +1,8 @@
-use {disk, listener, rpc, torrent, tracker};
+use std::net::SocketAddr;
+
+#[cfg(feature = "testing")]
+use control::fault;
+use {disk, listener, notify, rpc, torrent, tracker};
 
 error_chain! {
     errors {
@@ -16,6 +20,21 @@ error_chain! {
             description("removal requested")
                 display("removal requested")
         }
+
+        Violation {
+            description("protocol violation")
+                display("Peer violated the protocol")
+        }
+
+        Encryption {
+            description("encrypted handshake failed")
+                display("Peer dropped the encrypted handshake")
+        }
+
+        Transport {
+            description("uTP connection failed")
+                display("Peer didn't respond over uTP")
+        }
     }
 }
 
@@ -32,6 +51,9 @@ pub enum Event {
     Tracker(Result<tracker::Response>),
     Disk(Result<disk::Response>),
     Listener(Result<Box<listener::Message>>),
+    Notify(notify::Event),
+    /// A peer broke the protocol in a way only detected by its torrent
+    Violation(SocketAddr),
 }
 
 /// Control IO trait used as an abstraction boundary between
@@ -79,13 +101,17 @@ pub trait CIO {
 
     /// Creates a copy of the IO object, which has the same underlying data
     fn new_handle(&self) -> Self;
+
+    /// Sets or clears an artificial fault on a peer's incoming messages
+    #[cfg(feature = "testing")]
+    fn set_fault(&mut self, peer: PID, fault: Option<fault::Fault>);
 }
 
 #[cfg(test)]
 pub mod test {
     use super::{Event, Result, CIO, PID, TID};
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Mutex, MutexGuard};
     use {disk, listener, rpc, torrent, tracker};
 
     pub struct TCIO {
@@ -122,6 +148,11 @@ pub mod test {
                 data: Arc::new(Mutex::new(d)),
             }
         }
+
+        /// Locks the messages and peers recorded by the mock
+        pub fn data(&self) -> MutexGuard<TCIOD> {
+            self.data.lock().unwrap()
+        }
     }
 
     impl CIO for TCIO {
@@ -202,5 +233,8 @@ pub mod test {
                 data: self.data.clone(),
             }
         }
+
+        #[cfg(feature = "testing")]
+        fn set_fault(&mut self, _: PID, _: Option<::control::fault::Fault>) {}
     }
 }
@@ -0,0 +1,117 @@
+use std::cmp;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Scales the number of peer connections with the available bandwidth,
+/// so that a small pipe isn't spread across more peers than it can
+/// serve. The limit always stays between a floor and the hard cap.
+pub struct ConnBudget {
+    per_mib: usize,
+    min: usize,
+    max: usize,
+    /// Highest observed upload and download rates, decaying on
+    /// every update so that the budget recovers from bursts
+    peak: (u64, u64),
+    limit: usize,
+}
+
+impl ConnBudget {
+    /// Creates the budget, a `per_mib` of 0 leaves the limit at `max`.
+    pub fn new(per_mib: usize, min: usize, max: usize) -> ConnBudget {
+        let min = cmp::min(min, max);
+        ConnBudget {
+            per_mib,
+            min,
+            max,
+            peak: (0, 0),
+            limit: if per_mib == 0 { max } else { min },
+        }
+    }
+
+    pub fn adaptive(&self) -> bool {
+        self.per_mib != 0
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Updates the limit from the configured upload and download rates,
+    /// None or negative if unlimited, and the observed rates in bytes/s.
+    /// Returns whether the limit changed.
+    pub fn update(&mut self, rates: (Option<i64>, Option<i64>), observed: (u64, u64)) -> bool {
+        if !self.adaptive() {
+            return false;
+        }
+        self.peak.0 = cmp::max(self.peak.0 - self.peak.0 / 100, observed.0);
+        self.peak.1 = cmp::max(self.peak.1 - self.peak.1 / 100, observed.1);
+        let bandwidth = cmp::max(
+            capacity(rates.0, self.peak.0),
+            capacity(rates.1, self.peak.1),
+        );
+        // Rounded down, so that the limit follows a decaying peak
+        // rather than rounding back up to where it was
+        let wanted = self.per_mib as u64 * bandwidth / MIB;
+        let limit = cmp::max(self.min, cmp::min(self.max as u64, wanted) as usize);
+        if limit == self.limit {
+            return false;
+        }
+        debug!("Adjusting connection limit to {}", limit);
+        self.limit = limit;
+        true
+    }
+}
+
+/// Bandwidth usable in one direction, its throttle if there is one
+fn capacity(rate: Option<i64>, peak: u64) -> u64 {
+    match rate {
+        Some(r) if r >= 0 => r as u64,
+        _ => peak,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnBudget, MIB};
+
+    #[test]
+    fn test_throttle_scales_limit() {
+        let mut b = ConnBudget::new(100, 20, 400);
+        assert!(b.update((Some(2 * MIB as i64), None), (0, 0)));
+        assert_eq!(b.limit(), 200);
+
+        // Lowering the throttle shrinks the budget, down to the floor
+        assert!(b.update((Some(MIB as i64 / 2), None), (0, 0)));
+        assert_eq!(b.limit(), 50);
+        assert!(b.update((Some(1024), None), (0, 0)));
+        assert_eq!(b.limit(), 20);
+
+        // The hard cap still applies to a fast pipe
+        assert!(b.update((Some(100 * MIB as i64), None), (0, 0)));
+        assert_eq!(b.limit(), 400);
+    }
+
+    #[test]
+    fn test_unthrottled_follows_peak() {
+        let mut b = ConnBudget::new(100, 20, 400);
+        assert_eq!(b.limit(), 20);
+        assert!(b.update((None, Some(-1)), (0, MIB)));
+        assert_eq!(b.limit(), 100);
+
+        // A lull only lets the budget decay slowly
+        assert!(b.update((None, None), (0, 0)));
+        assert_eq!(b.limit(), 99);
+        for _ in 0..500 {
+            b.update((None, None), (0, 0));
+        }
+        assert_eq!(b.limit(), 20);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut b = ConnBudget::new(0, 20, 400);
+        assert!(!b.adaptive());
+        assert!(!b.update((Some(1024), None), (0, 0)));
+        assert_eq!(b.limit(), 400);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use config::{ConnQueuePolicy, IpFamily};
+use rpc::resource;
+
+/// Where a queued peer was discovered, ordered from
+/// least to most reliable.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PeerSource {
+    PEX,
+    DHT,
+    Tracker,
+    /// Announced by the peer itself on the LAN
+    LSD,
+}
+
+impl PeerSource {
+    pub fn as_rpc(self) -> resource::PeerSource {
+        match self {
+            PeerSource::PEX => resource::PeerSource::Pex,
+            PeerSource::DHT => resource::PeerSource::Dht,
+            PeerSource::Tracker => resource::PeerSource::Tracker,
+            PeerSource::LSD => resource::PeerSource::Lsd,
+        }
+    }
+}
+
+/// Bounded queue of peers awaiting an outgoing connection attempt.
+pub struct ConnQueue {
+    queue: VecDeque<Pending>,
+    max_len: usize,
+    policy: ConnQueuePolicy,
+    family: IpFamily,
+    dropped: u64,
+}
+
+struct Pending {
+    tid: usize,
+    addr: SocketAddr,
+    source: PeerSource,
+}
+
+impl ConnQueue {
+    pub fn new(max_len: usize, policy: ConnQueuePolicy, family: IpFamily) -> ConnQueue {
+        ConnQueue {
+            queue: VecDeque::new(),
+            max_len,
+            policy,
+            family,
+            dropped: 0,
+        }
+    }
+
+    /// Queues a peer for connection, evicting an existing entry
+    /// according to the queue policy if the queue is full. Peers
+    /// of a disabled address family are ignored.
+    pub fn push(&mut self, tid: usize, addr: SocketAddr, source: PeerSource) {
+        if !self.family.allows(&addr.ip()) {
+            return;
+        }
+        if let Some(p) = self
+            .queue
+            .iter_mut()
+            .find(|p| p.tid == tid && p.addr == addr)
+        {
+            if source > p.source {
+                p.source = source;
+            }
+            return;
+        }
+
+        if self.queue.len() >= self.max_len {
+            let victim = match self.policy {
+                ConnQueuePolicy::DropOldest => Some(0),
+                ConnQueuePolicy::PreferSource => self
+                    .queue
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|&(i, p)| (p.source, i))
+                    .filter(|&(_, p)| p.source <= source)
+                    .map(|(i, _)| i),
+            };
+            self.dropped += 1;
+            match victim {
+                Some(i) if i < self.queue.len() => {
+                    self.queue.remove(i);
+                }
+                _ => return,
+            }
+        }
+
+        self.queue.push_back(Pending { tid, addr, source });
+    }
+
+    /// Torrent of the peer to be popped next
+    pub fn peek(&self) -> Option<usize> {
+        self.queue.front().map(|p| p.tid)
+    }
+
+    pub fn pop(&mut self) -> Option<(usize, SocketAddr, PeerSource)> {
+        self.queue.pop_front().map(|p| (p.tid, p.addr, p.source))
+    }
+
+    /// Removes all queued peers for a torrent.
+    pub fn remove_torrent(&mut self, tid: usize) {
+        self.queue.retain(|p| p.tid != tid);
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Number of peers discarded due to the queue being full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnQueue, PeerSource};
+    use config::{ConnQueuePolicy, IpFamily};
+    use std::net::SocketAddr;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut q = ConnQueue::new(3, ConnQueuePolicy::DropOldest, IpFamily::Dual);
+        for p in 0..5 {
+            q.push(0, addr(p), PeerSource::Tracker);
+        }
+        assert_eq!(q.len(), 3);
+        assert_eq!(q.dropped(), 2);
+        assert_eq!(q.pop(), Some((0, addr(2), PeerSource::Tracker)));
+
+        // Duplicates are ignored
+        q.push(0, addr(4), PeerSource::PEX);
+        assert_eq!(q.len(), 2);
+    }
+
+    #[test]
+    fn test_prefer_source() {
+        let mut q = ConnQueue::new(3, ConnQueuePolicy::PreferSource, IpFamily::Dual);
+        q.push(0, addr(0), PeerSource::Tracker);
+        q.push(0, addr(1), PeerSource::PEX);
+        q.push(0, addr(2), PeerSource::DHT);
+
+        // Tracker peer evicts the PEX peer
+        q.push(0, addr(3), PeerSource::Tracker);
+        assert_eq!(q.len(), 3);
+        assert_eq!(q.dropped(), 1);
+
+        // A PEX peer is rejected since only better sources are queued
+        q.push(0, addr(4), PeerSource::PEX);
+        assert_eq!(q.len(), 3);
+        assert_eq!(q.dropped(), 2);
+
+        // A DHT peer evicts the older DHT peer
+        q.push(1, addr(5), PeerSource::DHT);
+        assert_eq!(q.dropped(), 3);
+
+        assert_eq!(q.pop(), Some((0, addr(0), PeerSource::Tracker)));
+        assert_eq!(q.pop(), Some((0, addr(3), PeerSource::Tracker)));
+        assert_eq!(q.pop(), Some((1, addr(5), PeerSource::DHT)));
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn test_source_kept() {
+        let mut q = ConnQueue::new(3, ConnQueuePolicy::DropOldest, IpFamily::Dual);
+        q.push(0, addr(0), PeerSource::PEX);
+        q.push(1, addr(1), PeerSource::DHT);
+        // Rediscovering a peer through a better source upgrades it
+        q.push(0, addr(0), PeerSource::Tracker);
+        q.push(1, addr(1), PeerSource::PEX);
+        assert_eq!(q.pop(), Some((0, addr(0), PeerSource::Tracker)));
+        assert_eq!(q.pop(), Some((1, addr(1), PeerSource::DHT)));
+    }
+
+    #[test]
+    fn test_ip_family() {
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let mut q = ConnQueue::new(3, ConnQueuePolicy::DropOldest, IpFamily::Ipv4);
+        q.push(0, v6, PeerSource::Tracker);
+        q.push(0, addr(0), PeerSource::Tracker);
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.dropped(), 0);
+        // The IPv6 peer is never handed out to be dialed
+        assert_eq!(q.pop(), Some((0, addr(0), PeerSource::Tracker)));
+        assert_eq!(q.pop(), None);
+
+        let mut q = ConnQueue::new(3, ConnQueuePolicy::DropOldest, IpFamily::Ipv6);
+        q.push(0, addr(0), PeerSource::DHT);
+        q.push(0, v6, PeerSource::Tracker);
+        assert_eq!(q.pop(), Some((0, v6, PeerSource::Tracker)));
+        assert_eq!(q.pop(), None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+/// Duration without any incoming connections after which
+/// we are assumed to be unreachable.
+const FIREWALL_WINDOW_SECS: u64 = 15 * 60;
+
+/// Information used to determine whether peers can reach us.
+pub struct Connectivity {
+    started: Instant,
+    incoming: u64,
+    last_incoming: Option<DateTime<Utc>>,
+    external_ip: Option<IpAddr>,
+}
+
+impl Connectivity {
+    pub fn new(started: Instant) -> Connectivity {
+        Connectivity {
+            started,
+            incoming: 0,
+            last_incoming: None,
+            external_ip: None,
+        }
+    }
+
+    /// Records a successfully established incoming connection.
+    pub fn add_incoming(&mut self) {
+        self.incoming += 1;
+        self.last_incoming = Some(Utc::now());
+    }
+
+    pub fn set_external_ip(&mut self, ip: IpAddr) {
+        self.external_ip = Some(ip);
+    }
+
+    pub fn incoming(&self) -> u64 {
+        self.incoming
+    }
+
+    pub fn last_incoming(&self) -> Option<DateTime<Utc>> {
+        self.last_incoming
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.external_ip
+    }
+
+    /// Guesses whether we are firewalled, based on whether any peer has
+    /// ever connected to us. Returns None until enough time has passed
+    /// to tell.
+    pub fn firewalled(&self, now: Instant) -> Option<bool> {
+        if self.incoming > 0 {
+            Some(false)
+        } else if now.duration_since(self.started) >= Duration::from_secs(FIREWALL_WINDOW_SECS) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connectivity, FIREWALL_WINDOW_SECS};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_firewalled() {
+        let start = Instant::now();
+        let mut c = Connectivity::new(start);
+        assert_eq!(c.firewalled(start), None);
+        let window = start + Duration::from_secs(FIREWALL_WINDOW_SECS);
+        assert_eq!(c.firewalled(window - Duration::from_secs(1)), None);
+        assert_eq!(c.firewalled(window), Some(true));
+
+        c.add_incoming();
+        assert_eq!(c.firewalled(window), Some(false));
+        assert_eq!(c.incoming(), 1);
+        assert!(c.last_incoming().is_some());
+    }
+}
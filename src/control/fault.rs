@@ -0,0 +1,223 @@
+//! Artificial latency and loss on peer connections, used to exercise
+//! endgame, stall and timeout handling against real swarms. Only built
+//! with the `testing` feature.
+
+use std::collections::VecDeque;
+use std::time;
+
+use rand::{self, Rng};
+
+use control::cio;
+use torrent;
+use util::UHashMap;
+
+/// Fault applied to the messages received from a peer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fault {
+    /// Delay before a received message is delivered
+    pub latency: time::Duration,
+    /// Probability in [0, 1] that a received block is dropped
+    pub loss: f32,
+}
+
+/// Holds back or drops the messages of peers with a fault set.
+/// Messages of a peer are always delivered in the order received.
+#[derive(Default)]
+pub struct Injector {
+    faults: UHashMap<Fault>,
+    delayed: VecDeque<(time::Instant, cio::PID, torrent::Message)>,
+}
+
+impl Fault {
+    pub fn new(latency_ms: u64, loss: f32) -> Option<Fault> {
+        if latency_ms == 0 && loss <= 0. {
+            None
+        } else {
+            Some(Fault {
+                latency: time::Duration::from_millis(latency_ms),
+                loss: loss.min(1.),
+            })
+        }
+    }
+}
+
+impl Injector {
+    pub fn set(&mut self, peer: cio::PID, fault: Option<Fault>) {
+        match fault {
+            Some(f) => {
+                debug!("Injecting {:?} on peer {}", f, peer);
+                self.faults.insert(peer, f);
+            }
+            None => {
+                self.faults.remove(&peer);
+            }
+        }
+    }
+
+    /// Filters the peer messages of `events`, delaying or dropping those
+    /// of faulty peers and appending any whose delay has elapsed.
+    pub fn apply(&mut self, events: &mut Vec<cio::Event>, now: time::Instant) {
+        if self.faults.is_empty() && self.delayed.is_empty() {
+            return;
+        }
+        let mut pass = Vec::with_capacity(events.len());
+        for event in events.drain(..) {
+            match event {
+                cio::Event::Peer {
+                    peer,
+                    event: Ok(msg),
+                } => {
+                    if let Some(msg) = self.recv(peer, msg, now) {
+                        pass.push(cio::Event::Peer {
+                            peer,
+                            event: Ok(msg),
+                        });
+                    }
+                }
+                cio::Event::Peer { peer, event } => {
+                    self.remove_peer(peer);
+                    pass.push(cio::Event::Peer { peer, event });
+                }
+                event => pass.push(event),
+            }
+        }
+        while self.delayed.front().map(|d| d.0 <= now).unwrap_or(false) {
+            let (_, peer, msg) = self.delayed.pop_front().unwrap();
+            pass.push(cio::Event::Peer {
+                peer,
+                event: Ok(msg),
+            });
+        }
+        *events = pass;
+    }
+
+    /// Returns `msg` if it should be delivered immediately
+    fn recv(
+        &mut self,
+        peer: cio::PID,
+        msg: torrent::Message,
+        now: time::Instant,
+    ) -> Option<torrent::Message> {
+        let fault = match self.faults.get(&peer) {
+            Some(f) => *f,
+            // Don't let new messages overtake ones still held back
+            None if self.delayed.iter().any(|d| d.1 == peer) => Fault {
+                latency: time::Duration::from_secs(0),
+                loss: 0.,
+            },
+            None => return Some(msg),
+        };
+        // Only blocks are dropped, losing anything else would desync
+        // the connection rather than just stall requests.
+        if let torrent::Message::Piece { .. } = msg {
+            if fault.loss > 0. && rand::thread_rng().gen::<f32>() < fault.loss {
+                return None;
+            }
+        }
+        self.delayed.push_back((now + fault.latency, peer, msg));
+        None
+    }
+
+    fn remove_peer(&mut self, peer: cio::PID) {
+        self.faults.remove(&peer);
+        self.delayed.retain(|d| d.1 != peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fault, Injector};
+    use buffers::Buffer;
+    use control::cio::Event;
+    use std::time::{Duration, Instant};
+    use torrent::Message;
+
+    fn msg(peer: usize, msg: Message) -> Event {
+        Event::Peer {
+            peer,
+            event: Ok(msg),
+        }
+    }
+
+    fn peer_msgs(events: &[Event]) -> Vec<(usize, u64)> {
+        events
+            .iter()
+            .filter_map(|e| match *e {
+                Event::Peer {
+                    peer,
+                    event: Ok(Message::Have(i)),
+                } => Some((peer, u64::from(i))),
+                Event::Peer {
+                    peer,
+                    event: Ok(Message::Piece { index, .. }),
+                } => Some((peer, u64::from(index))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut inj = Injector::default();
+        inj.set(1, Fault::new(500, 0.));
+        let now = Instant::now();
+        let mut events = vec![msg(0, Message::Have(0)), msg(1, Message::Have(1))];
+        inj.apply(&mut events, now);
+        assert_eq!(peer_msgs(&events), vec![(0, 0)]);
+
+        let mut events = vec![];
+        inj.apply(&mut events, now + Duration::from_millis(499));
+        assert!(events.is_empty());
+
+        // Clearing the fault must not let new messages overtake held ones
+        inj.set(1, None);
+        let mut events = vec![msg(1, Message::Have(2))];
+        inj.apply(&mut events, now + Duration::from_millis(499));
+        assert!(events.is_empty());
+        inj.apply(&mut events, now + Duration::from_millis(500));
+        assert_eq!(peer_msgs(&events), vec![(1, 1), (1, 2)]);
+
+        let mut events = vec![msg(1, Message::Have(3))];
+        inj.apply(&mut events, now + Duration::from_millis(500));
+        assert_eq!(peer_msgs(&events), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn test_loss() {
+        let mut inj = Injector::default();
+        inj.set(0, Fault::new(0, 1.));
+        let piece = Message::Piece {
+            index: 4,
+            begin: 0,
+            data: Buffer::get().unwrap(),
+            length: 16_384,
+        };
+        let mut events = vec![msg(0, Message::Have(4)), msg(0, piece)];
+        inj.apply(&mut events, Instant::now());
+        assert_eq!(peer_msgs(&events), vec![(0, 4)]);
+        match events[0] {
+            Event::Peer {
+                event: Ok(Message::Have(4)),
+                ..
+            } => {}
+            _ => panic!("only blocks should be dropped"),
+        }
+    }
+
+    #[test]
+    fn test_removed_peer() {
+        let mut inj = Injector::default();
+        inj.set(0, Fault::new(100, 0.));
+        let now = Instant::now();
+        let mut events = vec![msg(0, Message::Have(0))];
+        inj.apply(&mut events, now);
+        let mut events = vec![Event::Peer {
+            peer: 0,
+            event: Err(::control::cio::ErrorKind::Request.into()),
+        }];
+        inj.apply(&mut events, now + Duration::from_millis(100));
+        assert_eq!(events.len(), 1);
+        assert!(peer_msgs(&events).is_empty());
+        assert!(inj.faults.is_empty());
+    }
+}
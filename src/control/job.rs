@@ -1,10 +1,13 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::net::SocketAddr;
-use std::time;
+use std::{cmp, time};
 
+use super::ratio_group::{self, Member};
+use super::INTEGRITY_JOB_SECS;
 use control::cio;
-use torrent::Torrent;
+use torrent::{pex, Torrent};
 use util::UHashMap;
+use CONFIG;
 
 pub trait Job<T: cio::CIO> {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>);
@@ -20,6 +23,17 @@ impl<T: cio::CIO> Job<T> for TrackerUpdate {
     }
 }
 
+/// Scrapes the trackers of active torrents for their statistics
+pub struct ScrapeUpdate;
+
+impl<T: cio::CIO> Job<T> for ScrapeUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.scrape_trackers();
+        }
+    }
+}
+
 pub struct UnchokeUpdate;
 
 impl<T: cio::CIO> Job<T> for UnchokeUpdate {
@@ -30,6 +44,48 @@ impl<T: cio::CIO> Job<T> for UnchokeUpdate {
     }
 }
 
+/// Shares upload slots between the seeding torrents of each tracker
+pub struct RatioGroupUpdate;
+
+impl<T: cio::CIO> Job<T> for RatioGroupUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        let mut groups = BTreeMap::new();
+        for (id, torrent) in torrents.iter() {
+            if let Some((group, seeders, leechers)) = torrent.group_demand() {
+                groups.entry(group).or_insert_with(Vec::new).push(Member {
+                    tid: *id,
+                    seeders,
+                    leechers,
+                    warm: torrent.warm_peers(),
+                    underseeded: torrent.underseeded(),
+                });
+            }
+        }
+
+        let mut grouped = HashSet::new();
+        for (group, members) in groups {
+            let slots = CONFIG.peer.group_upload_slots;
+            let (warm, boost) = (CONFIG.peer.prefer_warm, CONFIG.peer.availability_boost);
+            let alloc = ratio_group::allocate(&members, slots, warm, boost);
+            // Allocations without the warm bias or the availability boost,
+            // to report their effect
+            let cold = ratio_group::allocate(&members, slots, false, boost);
+            let flat = ratio_group::allocate(&members, slots, warm, false);
+            for (i, m) in members.iter().enumerate() {
+                let bias = alloc[i] as i16 - cold[i] as i16;
+                let boosted = alloc[i] as i16 - flat[i] as i16;
+                if let Some(torrent) = torrents.get_mut(&m.tid) {
+                    torrent.set_upload_group(Some((group.clone(), alloc[i], bias, boosted)));
+                }
+                grouped.insert(m.tid);
+            }
+        }
+        for (_, torrent) in torrents.iter_mut().filter(|&(id, _)| !grouped.contains(id)) {
+            torrent.set_upload_group(None);
+        }
+    }
+}
+
 pub struct SessionUpdate;
 
 impl<T: cio::CIO> Job<T> for SessionUpdate {
@@ -42,6 +98,43 @@ impl<T: cio::CIO> Job<T> for SessionUpdate {
     }
 }
 
+pub struct IntegrityUpdate;
+
+impl<T: cio::CIO> Job<T> for IntegrityUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        let period = CONFIG.disk.integrity_sweep_hours * 60 * 60;
+        let max = u64::from(CONFIG.disk.integrity_max_pieces);
+        for (_, torrent) in torrents.iter_mut() {
+            // Spread a full sweep evenly over the configured period
+            let pieces = u64::from(torrent.info().pieces());
+            let count = (pieces * INTEGRITY_JOB_SECS + period - 1) / period;
+            torrent.check_integrity(cmp::min(count, max) as u32);
+        }
+    }
+}
+
+/// Pauses seeding torrents which reached their ratio or time limit
+pub struct SeedLimitUpdate;
+
+impl<T: cio::CIO> Job<T> for SeedLimitUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.check_seed_limits();
+        }
+    }
+}
+
+/// Announces torrents to peers on the LAN
+pub struct LSDUpdate;
+
+impl<T: cio::CIO> Job<T> for LSDUpdate {
+    fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
+        for (_, torrent) in torrents.iter_mut() {
+            torrent.lsd_announce();
+        }
+    }
+}
+
 pub struct TorrentTxUpdate {
     piece_update: time::Instant,
     active: UHashMap<bool>,
@@ -100,7 +193,7 @@ impl PEXUpdate {
 
 impl<T: cio::CIO> Job<T> for PEXUpdate {
     fn update(&mut self, torrents: &mut UHashMap<Torrent<T>>) {
-        for (id, torrent) in torrents.iter_mut().filter(|&(_, ref t)| !t.info().private) {
+        for (id, torrent) in torrents.iter_mut().filter(|&(_, ref t)| t.sources().pex) {
             if !self.peers.contains_key(id) {
                 self.peers.insert(*id, HashSet::new());
             }
@@ -108,11 +201,7 @@ impl<T: cio::CIO> Job<T> for PEXUpdate {
             let (added, removed) = {
                 let peers: HashSet<_> = torrent.peers().values().map(|p| p.addr()).collect();
                 let prev = self.peers.get_mut(id).unwrap();
-                let mut add: Vec<_> = peers.difference(prev).cloned().collect();
-                let mut rem: Vec<_> = prev.difference(&peers).cloned().collect();
-                add.truncate(50);
-                rem.truncate(50 - add.len());
-                (add, rem)
+                pex::diff(prev, &peers, CONFIG.peer.pex_max_peers)
             };
             torrent.update_pex(&added, &removed);
         }
@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+
+use util::FHashSet;
+
+/// Limits how many magnets fetch their metadata at once. Magnets
+/// beyond the limit wait for a slot in the order they were added.
+pub struct MetaSlots {
+    limit: usize,
+    active: FHashSet<usize>,
+    waiting: VecDeque<usize>,
+}
+
+impl MetaSlots {
+    /// Creates the slots, a limit of 0 leaves fetches unlimited.
+    pub fn new(limit: usize) -> MetaSlots {
+        MetaSlots {
+            limit,
+            active: FHashSet::default(),
+            waiting: VecDeque::new(),
+        }
+    }
+
+    /// Adds a magnet, returning whether it may start fetching
+    /// metadata now rather than waiting for a slot.
+    pub fn add(&mut self, tid: usize) -> bool {
+        if self.limit != 0 && self.active.len() >= self.limit {
+            self.waiting.push_back(tid);
+            false
+        } else {
+            self.active.insert(tid);
+            true
+        }
+    }
+
+    pub fn waiting(&self, tid: usize) -> bool {
+        self.waiting.contains(&tid)
+    }
+
+    /// Frees the slots of torrents for which `fetching` no longer
+    /// holds, e.g. because their metadata was fetched or they were
+    /// removed, and returns the waiting magnets which may now start.
+    pub fn update<F: FnMut(usize) -> bool>(&mut self, mut fetching: F) -> Vec<usize> {
+        self.active.retain(|&tid| fetching(tid));
+        self.waiting.retain(|&tid| fetching(tid));
+        let mut started = Vec::new();
+        while self.limit == 0 || self.active.len() < self.limit {
+            match self.waiting.pop_front() {
+                Some(tid) => {
+                    self.active.insert(tid);
+                    started.push(tid);
+                }
+                None => break,
+            }
+        }
+        started
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetaSlots;
+
+    #[test]
+    fn test_queue_excess() {
+        let mut s = MetaSlots::new(2);
+        let added: Vec<_> = (0..5).map(|tid| s.add(tid)).collect();
+        assert_eq!(added, vec![true, true, false, false, false]);
+        assert!(!s.waiting(1));
+        assert!(s.waiting(2));
+
+        // Nothing resolved yet
+        assert!(s.update(|_| true).is_empty());
+
+        // 0 fetched its metadata and the waiting 3 was removed
+        assert_eq!(s.update(|tid| tid != 0 && tid != 3), vec![2]);
+        assert!(!s.waiting(2));
+        assert!(!s.waiting(3));
+
+        // Once the rest resolve the queue drains in order
+        assert_eq!(s.update(|tid| tid > 2), vec![4]);
+        assert!(s.update(|_| false).is_empty());
+        assert!(s.add(5));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut s = MetaSlots::new(0);
+        assert!((0..100).all(|tid| s.add(tid)));
+        assert!(s.update(|_| true).is_empty());
+    }
+}
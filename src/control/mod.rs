@@ -1,36 +1,66 @@
 use std::io::Read;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::atomic;
-use std::{fs, io, mem, process, time};
+use std::{cmp, fs, io, mem, time};
 
-use chrono::Utc;
-use {amy, bincode};
+use byteorder::{ByteOrder, LittleEndian};
+use chrono::{DateTime, Local, Utc};
+use {amy, base64, bincode};
 
+use config::{Allocation, Encryption, IpFamily, KeyRotation, PathConflict, WebSeedStrategy};
+use notify::Notifier;
+use rpc::proto::message::{AnnounceEvent, BulkAction, SessionFormat};
 use throttle::Throttler;
 use torrent::{self, peer, Torrent};
-use util::{
-    self, hash_to_id, id_to_hash, io_err, io_err_val, random_string, FHashSet, MHashMap, UHashMap,
-};
-use {disk, listener, rpc, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
+use util::{self, hash_to_id, id_to_hash, io_err_val, random_string, FHashSet, MHashMap, UHashMap};
+use {disk, listener, mse, rpc, session, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
 
 pub mod acio;
+pub mod alt_speed;
+mod bans;
+mod bundle;
+mod checkpoint;
 pub mod cio;
+mod conn_budget;
+mod conn_queue;
+mod connectivity;
+#[cfg(feature = "testing")]
+pub mod fault;
 mod job;
+mod meta_slots;
+mod pauses;
+mod persist;
+mod quarantine;
+mod ratio_group;
+mod reclaim;
+
+use self::alt_speed::Schedule;
+use self::bans::Bans;
+use self::checkpoint::Checkpoint;
+use self::conn_budget::ConnBudget;
+use self::conn_queue::{ConnQueue, PeerSource};
+use self::connectivity::Connectivity;
+use self::meta_slots::MetaSlots;
+use self::pauses::{Pauses, Reason};
+use self::persist::Persist;
+use self::reclaim::Reclaim;
 
-/// Tracker update job interval
-const TRK_JOB_SECS: u64 = 60;
-/// Unchoke rotation job interval
-const UNCHK_JOB_SECS: u64 = 15;
-/// Session serialization job interval
-const SES_JOB_SECS: u64 = 60;
-/// Interval to update RPC of transfer stats
-const TX_JOB_MS: u64 = 500;
 /// Interval to check space on disk
 const SPACE_JOB_SECS: u64 = 10;
-/// Interval to send PEX updates
-const PEX_JOB_SECS: u64 = 60 * 5;
 /// Interval to enqueue new torrents
 const ENQUEUE_JOB_SECS: u64 = 5;
+/// Interval to dial queued peers
+const CONN_JOB_SECS: u64 = 1;
+/// Interval to hand out free metadata fetch slots
+const META_JOB_SECS: u64 = 1;
+/// Interval to rehash pieces of seeding torrents
+const INTEGRITY_JOB_SECS: u64 = 60;
+/// Interval to check seeding torrents against their limits
+const SEED_LIMIT_JOB_SECS: u64 = 30;
+/// Interval to check the alternate speed schedule
+const ALT_SPEED_JOB_SECS: u64 = 60;
 
 /// Interval to requery all jobs and execute if needed
 const JOB_INT_MS: usize = 500;
@@ -44,6 +74,34 @@ pub struct Control<T: cio::CIO> {
     jobs: JobManager<T>,
     torrents: UHashMap<Torrent<T>>,
     queue: Queue,
+    conn_queue: ConnQueue,
+    conn_budget: ConnBudget,
+    reclaim: Reclaim,
+    pauses: Pauses,
+    meta_slots: MetaSlots,
+    persist: Persist,
+    checkpoint: Checkpoint,
+    connectivity: Connectivity,
+    bans: Bans,
+    notifier: Notifier,
+    /// Piece affinity applied to every torrent, see disk.piece_affinity
+    piece_affinity: bool,
+    /// Whether disk writes are held in memory
+    quiesced: bool,
+    /// Whether the alternate speed limits are in use
+    alt_speed_active: bool,
+    /// When the announce key last rotated, if it rotates
+    key_rotated: Option<DateTime<Utc>>,
+    /// Whether held writes filled the buffer, stopping downloads
+    write_buffer_full: bool,
+    /// Last reported statistics of the disk's write cache
+    write_cache: disk::CacheStats,
+    /// Nodes in the DHT routing table
+    dht_nodes: usize,
+    /// Torrents whose session data was corrupt at startup
+    quarantined: Vec<String>,
+    /// Quarantined torrents which were added again
+    recovered: Vec<String>,
     peers: UHashMap<usize>,
     hash_idx: MHashMap<[u8; 20], usize>,
     data: ServerData,
@@ -63,6 +121,17 @@ struct ServerData {
     free_space: u64,
     throttle_ul: Option<i64>,
     throttle_dl: Option<i64>,
+    alt_speed: Schedule,
+}
+
+/// Server data as serialized before alternate speed schedules
+#[derive(Deserialize)]
+struct LegacyServerData {
+    id: String,
+    ul: u64,
+    dl: u64,
+    throttle_ul: Option<i64>,
+    throttle_dl: Option<i64>,
 }
 
 struct Queue {
@@ -81,39 +150,102 @@ struct JobManager<T: cio::CIO> {
 
 struct JobData<T> {
     job: T,
+    kind: Option<JobKind>,
     last_updated: time::Instant,
     interval: time::Duration,
 }
 
+/// Jobs whose interval may be adjusted at runtime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobKind {
+    /// Tracker updates
+    Tracker,
+    /// Unchoke rotation
+    Unchoke,
+    /// Session serialization
+    Session,
+    /// RPC updates of transfer stats
+    Transfer,
+}
+
 impl<T: cio::CIO> Control<T> {
     pub fn new(
         mut cio: T,
-        throttler: Throttler,
+        mut throttler: Throttler,
         db: amy::Sender<disk::Request>,
     ) -> io::Result<Control<T>> {
+        throttler.set_ul_seed_rate(CONFIG.net.seed_upload_rate);
         let torrents = UHashMap::default();
         let peers = UHashMap::default();
         let hash_idx = MHashMap::default();
         let mut jobs = JobManager::new();
 
-        jobs.add_job(job::TrackerUpdate, time::Duration::from_secs(TRK_JOB_SECS));
-        jobs.add_job(
-            job::UnchokeUpdate,
-            time::Duration::from_secs(UNCHK_JOB_SECS),
-        );
-        jobs.add_job(job::SessionUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        let trk_int = JobKind::Tracker.clamp(time::Duration::from_secs(CONFIG.jobs.tracker_secs));
+        let unchk_int = JobKind::Unchoke.clamp(time::Duration::from_secs(CONFIG.jobs.unchoke_secs));
+        let ses_int = JobKind::Session.clamp(time::Duration::from_secs(CONFIG.jobs.session_secs));
+        let tx_int = JobKind::Transfer.clamp(time::Duration::from_millis(CONFIG.jobs.tx_ms));
+
+        jobs.add_job(job::TrackerUpdate, Some(JobKind::Tracker), trk_int);
+        if CONFIG.trk.scrape_secs != 0 {
+            jobs.add_job(
+                job::ScrapeUpdate,
+                None,
+                time::Duration::from_secs(CONFIG.trk.scrape_secs),
+            );
+        }
+        jobs.add_job(job::UnchokeUpdate, Some(JobKind::Unchoke), unchk_int);
+        if CONFIG.peer.ratio_groups {
+            jobs.add_job(job::RatioGroupUpdate, Some(JobKind::Unchoke), unchk_int);
+        }
+        jobs.add_job(job::SessionUpdate, Some(JobKind::Session), ses_int);
+        jobs.add_job(job::TorrentTxUpdate::new(), Some(JobKind::Transfer), tx_int);
         jobs.add_job(
-            job::TorrentTxUpdate::new(),
-            time::Duration::from_millis(TX_JOB_MS),
+            job::PEXUpdate::new(),
+            None,
+            time::Duration::from_secs(CONFIG.peer.pex_interval_secs),
         );
+        if CONFIG.disk.integrity_sweep_hours != 0 {
+            jobs.add_job(
+                job::IntegrityUpdate,
+                None,
+                time::Duration::from_secs(INTEGRITY_JOB_SECS),
+            );
+        }
         jobs.add_job(
-            job::PEXUpdate::new(),
-            time::Duration::from_secs(PEX_JOB_SECS),
+            job::SeedLimitUpdate,
+            None,
+            time::Duration::from_secs(SEED_LIMIT_JOB_SECS),
         );
+        if CONFIG.net.lsd {
+            jobs.add_job(
+                job::LSDUpdate,
+                None,
+                time::Duration::from_secs(CONFIG.net.lsd_interval_secs),
+            );
+        }
 
-        jobs.add_cjob(SpaceUpdate, time::Duration::from_secs(SPACE_JOB_SECS));
-        jobs.add_cjob(EnqueueUpdate, time::Duration::from_secs(ENQUEUE_JOB_SECS));
-        jobs.add_cjob(SerializeUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        jobs.add_cjob(SpaceUpdate, None, time::Duration::from_secs(SPACE_JOB_SECS));
+        jobs.add_cjob(
+            EnqueueUpdate,
+            None,
+            time::Duration::from_secs(ENQUEUE_JOB_SECS),
+        );
+        jobs.add_cjob(SerializeUpdate, Some(JobKind::Session), ses_int);
+        jobs.add_cjob(
+            MetaSlotUpdate,
+            None,
+            time::Duration::from_secs(META_JOB_SECS),
+        );
+        jobs.add_cjob(
+            ConnectUpdate::new(),
+            None,
+            time::Duration::from_secs(CONN_JOB_SECS),
+        );
+        jobs.add_cjob(
+            AltSpeedUpdate,
+            None,
+            time::Duration::from_secs(ALT_SPEED_JOB_SECS),
+        );
         let job_timer = cio
             .set_timer(JOB_INT_MS)
             .map_err(|_| io_err_val("timer failure!"))?;
@@ -130,6 +262,39 @@ impl<T: cio::CIO> Control<T> {
             data: Default::default(),
             db,
             queue: Queue::new(),
+            conn_queue: ConnQueue::new(
+                CONFIG.net.max_conn_queue,
+                CONFIG.net.conn_queue_policy,
+                CONFIG.net.ip_family,
+            ),
+            conn_budget: ConnBudget::new(
+                CONFIG.net.conns_per_mib,
+                CONFIG.net.min_conns,
+                CONFIG.net.max_open_sockets,
+            ),
+            reclaim: Reclaim::new(
+                CONFIG.net.reclaim_conns,
+                time::Duration::from_secs(CONFIG.net.reclaim_interval_secs),
+            ),
+            pauses: Pauses::new(),
+            meta_slots: MetaSlots::new(CONFIG.net.max_metadata_fetches),
+            persist: Persist::new(),
+            checkpoint: Checkpoint::new(),
+            connectivity: Connectivity::new(time::Instant::now()),
+            bans: Bans::new(
+                CONFIG.peer.violation_strikes,
+                time::Duration::from_secs(CONFIG.peer.violation_ban_secs),
+            ),
+            notifier: Notifier::start(),
+            piece_affinity: CONFIG.disk.piece_affinity,
+            quiesced: false,
+            alt_speed_active: false,
+            key_rotated: None,
+            write_buffer_full: false,
+            write_cache: disk::CacheStats::default(),
+            dht_nodes: 0,
+            quarantined: Vec::new(),
+            recovered: Vec::new(),
         })
     }
 
@@ -158,6 +323,30 @@ impl<T: cio::CIO> Control<T> {
     }
 
     fn serialize(&mut self) {
+        self.serialize_data();
+        debug!("Serializing torrents!");
+        for torrent in self.torrents.values_mut() {
+            torrent.serialize();
+        }
+    }
+
+    /// Serializes server state if it changed, along with any modified
+    /// torrents after a significant change. `periodic` indicates that
+    /// the session interval has elapsed.
+    fn serialize_changes(&mut self, periodic: bool) {
+        // The session directory may be being backed up
+        if self.checkpoint.active() || !self.persist.due(periodic) {
+            return;
+        }
+        if self.persist.urgent() {
+            for torrent in self.torrents.values_mut().filter(|t| t.dirty()) {
+                torrent.serialize();
+            }
+        }
+        self.serialize_data();
+    }
+
+    fn serialize_data(&mut self) {
         let sd = &CONFIG.disk.session;
         debug!("Serializing server data!");
         let mut path = PathBuf::from(sd);
@@ -170,10 +359,7 @@ impl<T: cio::CIO> Control<T> {
                 error!("Failed to serialize server data");
             }
         }
-        debug!("Serializing torrents!");
-        for torrent in self.torrents.values_mut() {
-            torrent.serialize();
-        }
+        self.persist.serialized();
     }
 
     fn deserialize(&mut self) -> io::Result<()> {
@@ -181,56 +367,189 @@ impl<T: cio::CIO> Control<T> {
         debug!("Deserializing server data!");
         let mut pb = PathBuf::from(sd);
         pb.push("syn_data");
-        if let Ok(Ok(data)) = fs::File::open(pb).map(|mut f| bincode::deserialize_from(&mut f)) {
-            self.data = data;
-            self.throttler.set_ul_rate(self.data.throttle_ul);
-            self.throttler.set_dl_rate(self.data.throttle_dl);
-        } else {
-            error!("No server data found, regenerating!");
-            self.data = ServerData::new();
+        let bytes = fs::read(&pb).ok();
+        let loaded = bytes.as_ref().and_then(|b| ServerData::load(b));
+        match (loaded, bytes) {
+            (Some(data), _) => {
+                self.data = data;
+                self.alt_speed_active = self.data.alt_speed.active_at(&Local::now());
+                self.apply_throttle();
+            }
+            (None, Some(bytes)) => {
+                // Keep the corrupt file around, it'll be overwritten soon
+                let mut backup = pb.clone();
+                backup.set_file_name(format!("syn_data.bak.{}", Utc::now().timestamp()));
+                match fs::copy(&pb, &backup) {
+                    Ok(_) => error!("Server data is corrupt, backed it up to {:?}", backup),
+                    Err(e) => error!("Server data is corrupt, failed to back it up: {}", e),
+                }
+                self.data = ServerData::recover(&bytes).unwrap_or_else(|| {
+                    error!("Couldn't recover server data, regenerating!");
+                    ServerData::new()
+                });
+                self.persist.changed();
+            }
+            (None, None) => {
+                error!("No server data found, regenerating!");
+                self.data = ServerData::new();
+                self.persist.changed();
+            }
         }
 
         debug!("Deserializing torrents!");
-        for entry in fs::read_dir(sd)? {
-            if self.deserialize_torrent(entry).is_err() {
-                error!(
-                    "Please ensure that session data is not corrupted and not past version {}",
-                    env!("CARGO_PKG_VERSION")
-                );
-                process::exit(1);
+        let quarantined = quarantine::load_dir(Path::new(sd), |hash, data| {
+            // A file holding another torrent's data is as good as corrupt
+            match session::torrent::load(data) {
+                Some(ref s) if s.info.hash == *hash => self.load_torrent(data).is_some(),
+                _ => false,
             }
+        })?;
+        for id in &quarantined {
+            self.recover_torrent(id);
         }
+        self.quarantined = quarantined;
         Ok(())
     }
 
-    fn deserialize_torrent(&mut self, entry: io::Result<fs::DirEntry>) -> io::Result<()> {
-        let dir = entry?;
-        // TODO: We probably should improve this heuristic with and not rely
-        // on directory entries, but this is good enough for now.
-        if dir.file_name().len() != 40 {
-            return Ok(());
+    /// Adds a torrent whose session data was quarantined again, from its
+    /// .torrent or as a magnet. Its download path and progress are lost,
+    /// so it's left paused for the path to be checked, and its files are
+    /// validated to recover whatever was already downloaded.
+    fn recover_torrent(&mut self, id: &str) {
+        let info = match quarantine::recover(Path::new(&CONFIG.disk.session), id) {
+            Some(info) => info,
+            None => {
+                error!("Couldn't recover torrent {}, it must be added again", id);
+                return;
+            }
+        };
+        if self.hash_idx.contains_key(&info.hash) {
+            return;
         }
-        trace!("Attempting to deserialize file {:?}", dir);
-        let mut f = fs::File::open(dir.path())?;
-        let mut data = Vec::new();
-        f.read_to_end(&mut data)?;
-        trace!("Succesfully read file");
-
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
-        if let Some(t) = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle()) {
-            trace!("Succesfully parsed torrent file {:?}", dir.path());
-            self.hash_idx.insert(t.info().hash, tid);
-            self.tid_cnt += 1;
-            if t.status().leeching() {
-                self.queue.add(tid, t.priority());
-            }
-            self.torrents.insert(tid, t);
+        let mut t = Torrent::new(
+            tid,
+            None,
+            info,
+            throttle,
+            self.cio.new_handle(),
+            false,
+            false,
+        );
+        t.set_piece_affinity(self.piece_affinity);
+        t.set_buffer_full(self.write_buffer_full);
+        if t.status().magnet() {
+            warn!("Recovered torrent {} as a magnet", id);
+            if !self.meta_slots.add(tid) {
+                t.set_meta_queued(true);
+            }
         } else {
-            error!("Failed to deserialize torrent {:?}", dir.file_name());
-            return io_err("Torrent data invalid!");
+            warn!("Recovered torrent {}, validating its files", id);
+            t.validate(false);
         }
-        Ok(())
+        self.hash_idx.insert(t.info().hash, tid);
+        self.cio
+            .msg_listener(listener::Request::AddTorrent(t.info().hash));
+        self.tid_cnt += 1;
+        self.queue.add(tid, t.priority());
+        self.torrents.insert(tid, t);
+        self.recovered.push(id.to_owned());
+        self.persist.changed_significantly();
+    }
+
+    /// Adds a torrent from its session data, returning its ID.
+    fn load_torrent(&mut self, data: &[u8]) -> Option<usize> {
+        let tid = self.tid_cnt;
+        let throttle = self.throttler.get_throttle(tid);
+        let mut t = Torrent::deserialize(tid, data, throttle, self.cio.new_handle())?;
+        t.set_piece_affinity(self.piece_affinity);
+        t.set_buffer_full(self.write_buffer_full);
+        if t.status().magnet() && !self.meta_slots.add(tid) {
+            t.set_meta_queued(true);
+        }
+        self.hash_idx.insert(t.info().hash, tid);
+        self.cio
+            .msg_listener(listener::Request::AddTorrent(t.info().hash));
+        self.tid_cnt += 1;
+        if t.status().leeching() {
+            self.queue.add(tid, t.priority());
+        }
+        self.torrents.insert(tid, t);
+        Some(tid)
+    }
+
+    /// Writes server data and the state of every torrent to a single
+    /// archive at `path` in the given format, returning the number of
+    /// torrents exported. Binary archives hold tracker headers, which
+    /// may carry credentials, so only we may read the archive.
+    fn export_session(&mut self, path: PathBuf, format: SessionFormat) -> Result<u64, String> {
+        let torrents: Vec<_> = self
+            .torrents
+            .values()
+            .map(|t| (t.info().hash, t.session_data()))
+            .collect();
+        let count = torrents.len() as u64;
+        let data = match format {
+            SessionFormat::Binary => bundle::export(&self.data, torrents),
+            SessionFormat::Json => torrents
+                .iter()
+                .map(|(_, data)| session::torrent::load(data))
+                .collect::<Option<Vec<_>>>()
+                .and_then(|sessions| bundle::export_json(&self.data, &sessions)),
+        }
+        .ok_or_else(|| "Failed to serialize session".to_owned())?;
+        disk::write_atomic(&path, &data, 0o600)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        Ok(count)
+    }
+
+    /// Adds the torrents of an archive created by `export_session`,
+    /// skipping those which already exist, and merges its server data.
+    /// Returns the number of torrents imported.
+    fn import_session(&mut self, path: &Path) -> Result<u64, String> {
+        let mut data = Vec::new();
+        fs::File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let (server, torrents): (ServerData, _) = {
+            let hash_idx = &self.hash_idx;
+            let exists = |hash: &[u8; 20]| hash_idx.contains_key(hash);
+            bundle::import(&data, exists)
+                .or_else(|| {
+                    bundle::import::<LegacyServerData, _>(&data, exists)
+                        .map(|(s, t)| (ServerData::from(s), t))
+                })
+                .or_else(|| {
+                    // Torrents are loaded from their binary session data
+                    let (s, t) = bundle::import_json(&data, exists)?;
+                    let t = t.iter().filter_map(|t| bincode::serialize(t).ok());
+                    Some((s, t.collect()))
+                })
+                .ok_or_else(|| format!("{} is not a valid session archive", path.display()))?
+        };
+
+        // Keep our own ID so that clients can still refer to the server
+        self.data.ul += server.ul;
+        self.data.dl += server.dl;
+        self.data.throttle_ul = server.throttle_ul;
+        self.data.throttle_dl = server.throttle_dl;
+        self.data.alt_speed = server.alt_speed;
+        self.update_alt_speed(true);
+
+        let mut count = 0;
+        for data in torrents {
+            match self.load_torrent(&data) {
+                Some(tid) => {
+                    // Store the torrent in our own session directory
+                    self.torrents.get_mut(&tid).unwrap().serialize();
+                    count += 1;
+                }
+                None => error!("Failed to import torrent from {}", path.display()),
+            }
+        }
+        self.persist.changed_significantly();
+        Ok(count)
     }
 
     fn handle_event(&mut self, event: cio::Event) -> bool {
@@ -272,10 +591,14 @@ impl<T: cio::CIO> Control<T> {
                     self.data.session_dl += dl;
                     self.stat.add_ul(ul);
                     self.stat.add_dl(dl);
+                    if ul != 0 || dl != 0 {
+                        self.persist.changed();
+                    }
                 } else if t == self.throttler.fid() {
                     self.flush_blocked_peers();
                 } else if t == self.job_timer {
                     self.update_jobs();
+                    self.serialize_changes(false);
                     self.update_rpc_tx();
                 } else {
                     error!("unknown timer id {} reported", t);
@@ -284,18 +607,27 @@ impl<T: cio::CIO> Control<T> {
             cio::Event::Peer { peer, event } => {
                 self.handle_peer_ev(peer, event);
             }
+            cio::Event::Notify(e) => {
+                self.notifier.notify(e);
+            }
+            cio::Event::Violation(addr) => {
+                self.record_violation(addr);
+            }
         }
         false
     }
 
     fn handle_trk_ev(&mut self, tr: tracker::Response) {
-        let (id, peers) = match tr {
+        let (id, peers, source) = match tr {
             tracker::Response::Tracker { tid, url, resp } => {
                 debug!("Handling tracker response for {:?}", url);
                 if let Some(torrent) = self.torrents.get_mut(&tid) {
                     torrent.set_tracker_response(url.as_ref(), &resp);
                     if let Ok(r) = resp {
-                        (tid, r.peers)
+                        if let Some(ip) = r.external_ip {
+                            self.connectivity.set_external_ip(ip);
+                        }
+                        (tid, r.peers, PeerSource::Tracker)
                     } else {
                         return;
                     }
@@ -303,15 +635,93 @@ impl<T: cio::CIO> Control<T> {
                     return;
                 }
             }
-            tracker::Response::DHT { tid, peers } | tracker::Response::PEX { tid, peers } => {
-                (tid, peers)
+            tracker::Response::Scrape { tid, url, resp } => {
+                debug!("Handling scrape response for {:?}", url);
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.set_scrape_response(url.as_ref(), resp);
+                }
+                return;
+            }
+            tracker::Response::DHT { tid, peers } => (tid, peers, PeerSource::DHT),
+            tracker::Response::PEX { tid, peers } => (tid, peers, PeerSource::PEX),
+            tracker::Response::LSD { hash, peer } => {
+                let tid = match self.hash_idx.get(&hash) {
+                    Some(&tid) => tid,
+                    None => return,
+                };
+                match self.torrents.get(&tid) {
+                    Some(t) if t.sources().lpd => (tid, vec![peer], PeerSource::LSD),
+                    _ => return,
+                }
+            }
+            tracker::Response::Utp { conn, addr } => {
+                self.cio.msg_listener(listener::Request::Utp(conn, addr));
+                return;
+            }
+            tracker::Response::WebSeed {
+                tid,
+                seed,
+                piece,
+                start,
+                resp,
+            } => {
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.handle_web_seed(&seed, piece, start, resp);
+                }
+                return;
+            }
+            tracker::Response::DHTNodes(nodes) => {
+                self.dht_nodes = nodes;
+                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                    rpc::resource::SResourceUpdate::ServerDht {
+                        id: self.data.id.clone(),
+                        kind: rpc::resource::ResourceKind::Server,
+                        dht_nodes: nodes as u64,
+                    },
+                ]));
+                return;
+            }
+            tracker::Response::AnnounceKey(rotated) => {
+                self.key_rotated = rotated;
+                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                    rpc::resource::SResourceUpdate::ServerAnnounceKey {
+                        id: self.data.id.clone(),
+                        kind: rpc::resource::ResourceKind::Server,
+                        key_rotated: rotated,
+                    },
+                ]));
+                return;
             }
         };
-        for ip in &peers {
+        for ip in peers {
+            trace!("Queueing peer({:?})!", ip);
+            self.conn_queue.push(id, ip, source);
+        }
+    }
+
+    fn connect_queued(&mut self) {
+        for _ in 0..CONFIG.net.connect_rate {
+            if self.peers.len() >= self.conn_budget.limit() {
+                let reclaimed = match self.conn_queue.peek() {
+                    Some(tid) => self.reclaim_conn(tid),
+                    None => break,
+                };
+                if !reclaimed && self.conn_budget.adaptive() {
+                    break;
+                }
+            }
+            let (tid, ip, source) = match self.conn_queue.pop() {
+                Some(p) => p,
+                None => break,
+            };
+            if self.bans.banned(&ip.ip(), time::Instant::now()) {
+                debug!("Skipping banned peer {}", ip);
+                continue;
+            }
             trace!("Adding peer({:?})!", ip);
-            if let Ok(peer) = peer::PeerConn::new_outgoing(ip) {
+            if let Ok(peer) = self.dial(tid, &ip, CONFIG.peer.utp) {
                 trace!("Added peer({:?})!", ip);
-                self.add_peer(id, peer);
+                self.add_peer(tid, peer, source.as_rpc());
             }
         }
     }
@@ -325,27 +735,96 @@ impl<T: cio::CIO> Control<T> {
 
     fn handle_disk_ev(&mut self, resp: disk::Response) {
         trace!("Got disk response {:?}!", resp);
-        if let disk::Response::FreeSpace(space) = resp {
+        if let disk::Response::FreeSpace { space, read_only } = resp {
             if space / 1_000_000 != self.data.free_space / 1_000_000 {
                 self.data.free_space = space;
                 self.update_rpc_space();
             }
-        } else if let Some(torrent) = self.torrents.get_mut(&resp.tid()) {
+            let threshold = CONFIG.disk.low_space_mb * 1_000_000;
+            let low = self.pauses.active(Reason::LowSpace);
+            if let Some(low) = pauses::check_space(low, space, threshold) {
+                self.set_low_space(low);
+            }
+            if !read_only && self.pauses.active(Reason::ReadOnly) {
+                self.set_read_only(false);
+            }
+        } else if let disk::Response::BufferFull(full) = resp {
+            self.set_write_buffer_full(full);
+        } else if let disk::Response::CacheStats(stats) = resp {
+            if stats != self.write_cache {
+                self.write_cache = stats;
+                self.update_rpc_write_cache();
+            }
+        } else if let disk::Response::Checkpoint = resp {
+            self.checkpoint_synced();
+        } else if let disk::Response::CreateUpdate {
+            req: (client, serial),
+            percent,
+        } = resp
+        {
+            self.cio.msg_rpc(rpc::CtlMessage::Creating {
+                progress: percent,
+                client,
+                serial,
+            });
+        } else if let disk::Response::Created {
+            req: (client, serial),
+            info,
+        } = resp
+        {
+            let (data, hash) = torrent::creation::finish(*info);
+            info!("Created torrent {}", hash_to_id(&hash));
+            self.cio.msg_rpc(rpc::CtlMessage::Created {
+                hash: hash_to_id(&hash),
+                metainfo: base64::encode(&data),
+                client,
+                serial,
+            });
+        } else if let disk::Response::CreateFailed {
+            req: (client, serial),
+            err,
+        } = resp
+        {
+            self.cio.msg_rpc(rpc::CtlMessage::Error {
+                reason: format!("Failed to create torrent: {}", err),
+                client,
+                serial,
+            });
+        } else {
+            if let disk::Response::Error { tid, ref err } = resp {
+                if util::is_read_only(err) {
+                    self.write_read_only(tid);
+                }
+            }
+            let torrent = match self.torrents.get_mut(&resp.tid()) {
+                Some(t) => t,
+                None => return,
+            };
             torrent.handle_disk_resp(resp);
+            // Peers sending corrupt data are banned before the torrent
+            // reaches its corruption limit, if possible
+            for ip in torrent.take_bad_peers() {
+                if self.bans.violation(ip, time::Instant::now()) {
+                    info!("Banning peer {} for sending corrupt data", ip);
+                    torrent.remove_peers_from(ip);
+                }
+            }
         }
     }
 
     fn handle_lst_ev(&mut self, msg: listener::Message) {
         debug!("Adding peer for torrent with hash {:?}!", msg.hash);
         if let Some(tid) = self.hash_idx.get(&msg.hash).cloned() {
+            let addr = msg.conn.addr();
+            if self.bans.banned(&addr.ip(), time::Instant::now()) {
+                debug!("Rejecting banned peer {}", addr);
+                return;
+            }
             let id = msg.id;
             let rsv = msg.rsv;
-            match peer::PeerConn::new_incoming(msg.conn, msg.reader) {
-                Ok(p) => self.add_inc_peer(tid, p, id, rsv),
-                Err(e) => {
-                    error!("Failed to create peer connection: {:?}", e);
-                }
-            };
+            let p = peer::PeerConn::new_incoming(msg.conn.attach(), msg.reader);
+            self.connectivity.add_incoming();
+            self.add_inc_peer(tid, p, id, rsv)
         } else {
             let h = msg.hash;
             error!("Couldn't add peer, torrent {} doesn't exist", hash_to_id(&h));
@@ -353,15 +832,91 @@ impl<T: cio::CIO> Control<T> {
     }
 
     fn handle_peer_ev(&mut self, peer: cio::PID, ev: cio::Result<torrent::Message>) {
-        let p = &mut self.peers;
-        let t = &mut self.torrents;
+        let mut violation = None;
+        let mut redial = None;
+        {
+            let p = &mut self.peers;
+            let t = &mut self.torrents;
+
+            if let Some(tid) = p.get(&peer).cloned() {
+                let torrent = match t.get_mut(&tid) {
+                    Some(torrent) => torrent,
+                    None => return,
+                };
+                if let Err(ref e) = ev {
+                    match *e.kind() {
+                        cio::ErrorKind::Violation => {
+                            violation = torrent.peers().get(&peer).map(|pr| pr.addr());
+                        }
+                        // Peers not supporting encryption usually just
+                        // drop the handshake, so retry them in plaintext
+                        cio::ErrorKind::Encryption
+                            if CONFIG.peer.encryption == Encryption::Prefer =>
+                        {
+                            redial = torrent
+                                .peers()
+                                .get(&peer)
+                                .map(|pr| (tid, pr.addr(), pr.source(), false));
+                        }
+                        // Likewise many peers don't support uTP
+                        cio::ErrorKind::Transport => {
+                            redial = torrent
+                                .peers()
+                                .get(&peer)
+                                .map(|pr| (tid, pr.addr(), pr.source(), true));
+                        }
+                        _ => {}
+                    }
+                }
+                if torrent.peer_ev(peer, ev).is_err() {
+                    p.remove(&peer);
+                    torrent.update_rpc_peers();
+                }
+            }
+        }
+        if let Some(addr) = violation {
+            self.record_violation(addr);
+        }
+        if let Some((tid, addr, source, utp)) = redial {
+            let pc = if utp {
+                debug!("Retrying peer {} over TCP", addr);
+                self.dial(tid, &addr, false)
+            } else {
+                debug!("Retrying peer {} without encryption", addr);
+                peer::PeerConn::new_outgoing(&addr)
+            };
+            if let Ok(pc) = pc {
+                self.add_peer(tid, pc, source);
+            }
+        }
+    }
 
-        if let Some(torrent) = p.get(&peer).cloned().and_then(|id| t.get_mut(&id)) {
-            if torrent.peer_ev(peer, ev).is_err() {
-                p.remove(&peer);
-                torrent.update_rpc_peers();
+    /// Connects to a peer of torrent `tid`, starting the encrypted
+    /// handshake if enabled.
+    /// Connects to a peer, over uTP if `utp` is set and the peer
+    /// is reachable through the IPv4 only DHT socket.
+    fn dial(&mut self, tid: usize, addr: &SocketAddr, utp: bool) -> io::Result<peer::PeerConn> {
+        let mut pc = if utp && addr.is_ipv4() {
+            let (local, remote) = UnixStream::pair()?;
+            let pc = peer::PeerConn::new_utp(local, addr)?;
+            self.cio
+                .msg_trk(tracker::Request::UtpConnect(*addr, remote));
+            pc
+        } else {
+            peer::PeerConn::new_outgoing(addr)?
+        };
+        if let Some(methods) = mse::methods(CONFIG.peer.encryption) {
+            if let Some(t) = self.torrents.get(&tid) {
+                pc.sock_mut().initiate_mse(t.info().hash, methods);
             }
         }
+        Ok(pc)
+    }
+
+    fn record_violation(&mut self, addr: SocketAddr) {
+        if self.bans.violation(addr.ip(), time::Instant::now()) {
+            info!("Banning peer {} for protocol violations", addr);
+        }
     }
 
     fn flush_blocked_peers(&mut self) {
@@ -370,29 +925,124 @@ impl<T: cio::CIO> Control<T> {
         self.cio.flush_peers(self.throttler.flush_ul());
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_torrent(
         &mut self,
         info: torrent::Info,
         path: Option<String>,
         start: bool,
         import: bool,
+        files: Option<Vec<u32>>,
+        allocation: Allocation,
         client: usize,
         serial: u64,
     ) {
         debug!("Adding {:?}, start: {}!", info, start);
         let id = hash_to_id(&info.hash);
-        if self.hash_idx.contains_key(&info.hash) {
-            debug!("Tried to add torrent that already exists!");
+        if let Some(ref files) = files {
+            let count = info.files.len() as u32;
+            let reason = if files.is_empty() {
+                Some("No files selected".to_owned())
+            } else {
+                files
+                    .iter()
+                    .find(|&&f| f >= count)
+                    .map(|f| format!("Torrent {} has no file {}", id, f))
+            };
+            if let Some(reason) = reason {
+                self.cio.msg_rpc(rpc::CtlMessage::Error {
+                    client,
+                    serial,
+                    reason,
+                });
+                return;
+            }
+        }
+        if let Some(&tid) = self.hash_idx.get(&info.hash) {
+            let merged = self
+                .torrents
+                .get_mut(&tid)
+                .unwrap()
+                .add_duplicate(CONFIG.disk.duplicate_add, files.as_ref().map(Vec::as_slice));
+            match merged {
+                Some(files) => {
+                    debug!("Merged file selection of duplicate torrent {}", id);
+                    self.cio.msg_rpc(rpc::CtlMessage::Merged {
+                        id,
+                        files,
+                        client,
+                        serial,
+                    });
+                }
+                None => {
+                    debug!("Tried to add torrent that already exists!");
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason: format!("Torrent {} already exists", id),
+                    });
+                }
+            }
+            return;
+        }
+        let valid = info
+            .check_piece_count(CONFIG.disk.warn_pieces, CONFIG.disk.max_pieces)
+            .and_then(|_| {
+                info.check_piece_len(CONFIG.disk.min_piece_len, CONFIG.disk.max_piece_len)
+            });
+        if let Err(reason) = valid {
             self.cio.msg_rpc(rpc::CtlMessage::Error {
                 client,
                 serial,
-                reason: format!("Torrent {} already exists", id),
+                reason,
             });
             return;
         }
+        let conflicts = {
+            let dir = path.as_ref().unwrap_or(&CONFIG.disk.directory);
+            self.path_conflicts(&info, Path::new(dir), None)
+        };
+        if !conflicts.is_empty() {
+            match CONFIG.disk.path_conflict {
+                PathConflict::Reject => {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason: format!(
+                            "Torrent {} would overwrite files of {}",
+                            id,
+                            conflicts.join(", ")
+                        ),
+                    });
+                    return;
+                }
+                PathConflict::Warn => {
+                    warn!("Torrent {} overlaps with {}", id, conflicts.join(", "));
+                }
+            }
+        }
+        // The size of magnets isn't known until their metadata is
+        if allocation == Allocation::Full && info.complete() {
+            let dir = Path::new(path.as_ref().unwrap_or(&CONFIG.disk.directory));
+            let needed = torrent::allocation_needed(&info, dir, files.as_ref().map(Vec::as_slice));
+            match disk::available_space(dir) {
+                Some(space) if space < needed => {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason: format!(
+                            "Not enough free space to allocate torrent {}: {} bytes needed, {} available",
+                            id, needed, space
+                        ),
+                    });
+                    return;
+                }
+                _ => {}
+            }
+        }
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
-        let t = Torrent::new(
+        let mut t = Torrent::new(
             tid,
             path,
             info,
@@ -401,24 +1051,99 @@ impl<T: cio::CIO> Control<T> {
             start,
             import,
         );
+        t.set_conflicts(conflicts);
+        t.set_piece_affinity(self.piece_affinity);
+        t.set_buffer_full(self.write_buffer_full);
+        t.apply_file_rules();
+        if let Some(ref files) = files {
+            t.select_files(files);
+        }
+        t.set_allocation(allocation);
+        if !t.status().stopped() {
+            let metered = CONFIG.net.pause_when_metered && self.pauses.add(tid, Reason::Metered);
+            let low_space = !t.complete() && self.pauses.add(tid, Reason::LowSpace);
+            if !t.complete() && self.pauses.add(tid, Reason::ReadOnly) {
+                t.set_read_only(true);
+            } else if metered || low_space {
+                t.pause();
+            }
+        }
+        if t.status().magnet() && !self.meta_slots.add(tid) {
+            t.set_meta_queued(true);
+        }
         self.hash_idx.insert(t.info().hash, tid);
+        self.cio
+            .msg_listener(listener::Request::AddTorrent(t.info().hash));
         self.tid_cnt += 1;
         self.queue.add(tid, t.priority());
         self.torrents.insert(tid, t);
+        self.persist.changed_significantly();
         self.cio
             .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial })
     }
 
+    /// Returns the IDs of torrents whose files would overlap with those
+    /// of `info` if it were stored in `dir`.
+    fn path_conflicts(
+        &self,
+        info: &torrent::Info,
+        dir: &Path,
+        exclude: Option<usize>,
+    ) -> Vec<String> {
+        self.torrents
+            .iter()
+            .filter(|&(tid, _)| Some(*tid) != exclude)
+            .filter(|&(_, t)| {
+                !info
+                    .overlapping_files(dir, t.info(), Path::new(t.path()))
+                    .is_empty()
+            })
+            .map(|(_, t)| t.rpc_id())
+            .collect()
+    }
+
     fn handle_rpc_ev(&mut self, req: rpc::Message) -> bool {
         debug!("Handling rpc reqest!");
         match req {
-            rpc::Message::UpdateTorrent(u) => {
-                let hash_idx = &self.hash_idx;
-                let torrents = &mut self.torrents;
-                let res = id_to_hash(&u.id)
-                    .and_then(|d| hash_idx.get(d.as_ref()))
-                    .and_then(|i| torrents.get_mut(i));
-                if let Some(t) = res {
+            rpc::Message::UpdateTorrent {
+                resource: mut u,
+                client,
+                serial,
+            } => {
+                let tid = match id_to_hash(&u.id).and_then(|d| self.hash_idx.get(d.as_ref())) {
+                    Some(tid) => *tid,
+                    None => return false,
+                };
+                let conflicts = match (u.path.as_ref(), self.torrents.get(&tid)) {
+                    (Some(p), Some(t)) => {
+                        Some(self.path_conflicts(t.info(), Path::new(p), Some(tid)))
+                    }
+                    _ => None,
+                };
+                if let Some(ref c) = conflicts {
+                    if !c.is_empty() {
+                        if CONFIG.disk.path_conflict == PathConflict::Reject {
+                            self.cio.msg_rpc(rpc::CtlMessage::Error {
+                                client,
+                                serial,
+                                reason: format!(
+                                    "Not moving torrent {}, it would overwrite files of {}",
+                                    u.id,
+                                    c.join(", ")
+                                ),
+                            });
+                            u.path = None;
+                        } else {
+                            warn!("Moving torrent {} overlaps with {}", u.id, c.join(", "));
+                        }
+                    }
+                }
+                if let Some(t) = self.torrents.get_mut(&tid) {
+                    if u.path.is_some() {
+                        if let Some(c) = conflicts {
+                            t.set_conflicts(c);
+                        }
+                    }
                     let old_pri = t.priority();
                     t.rpc_update(u);
                     let new_pri = t.priority();
@@ -430,9 +1155,18 @@ impl<T: cio::CIO> Control<T> {
                 path,
                 start,
                 import,
+                files,
+                allocation,
                 client,
                 serial,
-            } => self.add_torrent(info, path, start, import, client, serial),
+            } => {
+                let allocation = match allocation {
+                    Some(rpc::resource::Allocation::Sparse) => Allocation::Sparse,
+                    Some(rpc::resource::Allocation::Full) => Allocation::Full,
+                    None => CONFIG.disk.allocation,
+                };
+                self.add_torrent(info, path, start, import, files, allocation, client, serial)
+            }
             rpc::Message::UpdateFile {
                 id,
                 torrent_id,
@@ -452,14 +1186,20 @@ impl<T: cio::CIO> Control<T> {
                 client,
                 serial,
                 peer,
+                preferred,
             } => {
                 let res = id_to_hash(&id)
                     .and_then(|d| self.hash_idx.get(d.as_ref()))
                     .cloned();
-                let pres = peer::PeerConn::new_outgoing(&peer);
                 if let Some(tid) = res {
-                    if let Ok(pc) = pres {
-                        if let Some(id) = self.add_peer_rpc(tid, pc) {
+                    if !CONFIG.net.ip_family.allows(&peer.ip()) {
+                        self.cio.msg_rpc(rpc::CtlMessage::Error {
+                            client,
+                            serial,
+                            reason: format!("Peer {} uses a disabled address family", peer),
+                        });
+                    } else if let Ok(pc) = self.dial(tid, &peer, CONFIG.peer.utp) {
+                        if let Some(id) = self.add_peer_rpc(tid, pc, preferred) {
                             self.cio
                                 .msg_rpc(rpc::CtlMessage::Pending { id, client, serial });
                         } else {
@@ -508,24 +1248,54 @@ impl<T: cio::CIO> Control<T> {
                     });
             }
             rpc::Message::UpdateServer {
-                id,
                 throttle_up,
                 throttle_down,
+                throttle_up_seeding,
+                metered,
+                piece_affinity,
+                quiesced,
+                throttle_burst,
+                alt_speed,
+                job_intervals,
             } => {
-                let tu = throttle_up.unwrap_or_else(|| self.throttler.ul_rate());
-                let td = throttle_down.unwrap_or_else(|| self.throttler.dl_rate());
-                self.throttler.set_ul_rate(tu);
-                self.throttler.set_dl_rate(td);
-                self.data.throttle_ul = tu;
-                self.data.throttle_dl = td;
-                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                    rpc::resource::SResourceUpdate::Throttle {
-                        id,
-                        kind: rpc::resource::ResourceKind::Server,
-                        throttle_up: tu,
-                        throttle_down: td,
-                    },
-                ]));
+                if let Some(m) = metered {
+                    self.set_metered(m);
+                }
+                if let Some(a) = piece_affinity {
+                    self.set_piece_affinity(a);
+                }
+                if let Some(q) = quiesced {
+                    self.set_quiesced(q);
+                }
+                if let Some(b) = throttle_burst {
+                    self.set_throttle_burst(b);
+                }
+                if !job_intervals.is_empty() {
+                    for (kind, interval) in job_intervals {
+                        debug!("Setting {:?} job interval to {:?}", kind, interval);
+                        self.jobs.set_interval(kind, interval);
+                    }
+                    self.update_rpc_jobs();
+                }
+                if let Some(schedule) = alt_speed {
+                    self.data.alt_speed = schedule;
+                    self.persist.changed();
+                    self.update_alt_speed(true);
+                }
+                if throttle_up.is_none() && throttle_down.is_none() && throttle_up_seeding.is_none()
+                {
+                    return false;
+                }
+                // The regular limits are kept while the alternate ones
+                // are in use, taking effect once the window closes
+                self.data.throttle_ul = throttle_up.unwrap_or(self.data.throttle_ul);
+                self.data.throttle_dl = throttle_down.unwrap_or(self.data.throttle_dl);
+                if let Some(ts) = throttle_up_seeding {
+                    self.throttler.set_ul_seed_rate(ts);
+                }
+                self.persist.changed();
+                self.apply_throttle();
+                self.update_rpc_throttle();
             }
             rpc::Message::RemoveTorrent {
                 id,
@@ -535,13 +1305,33 @@ impl<T: cio::CIO> Control<T> {
             } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
+                let conn_queue = &mut self.conn_queue;
+                let pauses = &mut self.pauses;
+                let persist = &mut self.persist;
                 let cio = &mut self.cio;
                 let reason = format!("Torrent {} does not exist", id);
                 id_to_hash(&id)
                     .and_then(|d| hash_idx.remove(d.as_ref()))
-                    .and_then(|i| torrents.remove(&i))
-                    .map(|mut t| t.delete(artifacts))
-                    .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
+                    .and_then(|i| {
+                        conn_queue.remove_torrent(i);
+                        pauses.forget(i);
+                        persist.changed_significantly();
+                        torrents.remove(&i)
+                    })
+                    .map(|mut t| {
+                        cio.msg_listener(listener::Request::RemoveTorrent(t.info().hash));
+                        let archived = t.archive();
+                        t.delete(artifacts);
+                        archived
+                    })
+                    .map(|archived| {
+                        cio.msg_rpc(rpc::CtlMessage::ClientRemoved {
+                            id,
+                            client,
+                            serial,
+                            archived: archived.map(|p| p.display().to_string()),
+                        })
+                    })
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
                             client,
@@ -553,24 +1343,32 @@ impl<T: cio::CIO> Control<T> {
             rpc::Message::Pause(id) => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
+                let pauses = &mut self.pauses;
                 if let Some(t) = id_to_hash(&id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
                 {
+                    pauses.forget(t.id());
                     t.pause()
                 }
             }
             rpc::Message::Resume(id) => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
+                let pauses = &mut self.pauses;
                 if let Some(t) = id_to_hash(&id)
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
                 {
+                    pauses.forget(t.id());
                     t.resume();
                 }
             }
-            rpc::Message::Validate(ids) => {
+            rpc::Message::Validate {
+                ids,
+                low_impact,
+                force_recheck,
+            } => {
                 let hash_idx = &mut self.hash_idx;
                 let torrents = &mut self.torrents;
                 for id in ids {
@@ -578,11 +1376,99 @@ impl<T: cio::CIO> Control<T> {
                         .and_then(|d| hash_idx.get(d.as_ref()))
                         .and_then(|i| torrents.get_mut(i))
                     {
-                        t.validate();
+                        t.recheck(low_impact, force_recheck);
                     }
                 }
             }
-            rpc::Message::RemovePeer {
+            rpc::Message::RenameFile {
+                id,
+                torrent_id,
+                path,
+                client,
+                serial,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .ok_or_else(|| format!("Torrent {} does not exist", torrent_id))
+                    .and_then(|t| t.rename_file(&id, path));
+                if let Err(reason) = res {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason,
+                    });
+                }
+            }
+            rpc::Message::RenameRoot {
+                id,
+                name,
+                client,
+                serial,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .ok_or_else(|| format!("Torrent {} does not exist", id))
+                    .and_then(|t| t.rename_root(&name));
+                if let Err(reason) = res {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason,
+                    });
+                }
+            }
+            rpc::Message::MoveTorrent {
+                id,
+                new_path,
+                client,
+                serial,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .ok_or_else(|| format!("Torrent {} does not exist", id))
+                    .and_then(|t| t.move_to(new_path));
+                if let Err(reason) = res {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason,
+                    });
+                }
+            }
+            rpc::Message::DownloadPieces { id, pieces, pause } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                if let Some(t) = id_to_hash(&id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                {
+                    t.set_target_pieces(pieces, pause);
+                }
+            }
+            rpc::Message::Stream {
+                id,
+                torrent_id,
+                download,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                if let Some(t) = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                {
+                    t.stream(&id, download);
+                }
+            }
+            rpc::Message::RemovePeer {
                 id,
                 torrent_id,
                 client,
@@ -596,7 +1482,14 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
                     .map(|t| t.remove_peer(&id))
-                    .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
+                    .map(|_| {
+                        cio.msg_rpc(rpc::CtlMessage::ClientRemoved {
+                            id,
+                            client,
+                            serial,
+                            archived: None,
+                        })
+                    })
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
                             client,
@@ -619,7 +1512,14 @@ impl<T: cio::CIO> Control<T> {
                     .and_then(|d| hash_idx.get(d.as_ref()))
                     .and_then(|i| torrents.get_mut(i))
                     .map(|t| t.remove_tracker(&id))
-                    .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
+                    .map(|_| {
+                        cio.msg_rpc(rpc::CtlMessage::ClientRemoved {
+                            id,
+                            client,
+                            serial,
+                            archived: None,
+                        })
+                    })
                     .unwrap_or_else(|| {
                         cio.msg_rpc(rpc::CtlMessage::Error {
                             client,
@@ -638,17 +1538,244 @@ impl<T: cio::CIO> Control<T> {
                     t.update_tracker_req(&id);
                 }
             }
+            rpc::Message::ReannounceTracker {
+                id,
+                torrent_id,
+                event,
+                client,
+                serial,
+            } => {
+                let event = match event {
+                    AnnounceEvent::Started => Some(tracker::Event::Started),
+                    AnnounceEvent::Completed => Some(tracker::Event::Completed),
+                    AnnounceEvent::None => None,
+                };
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .ok_or_else(|| format!("Torrent {} does not exist", torrent_id))
+                    .and_then(|t| t.reannounce(&id, event));
+                if let Err(reason) = res {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        reason,
+                        client,
+                        serial,
+                    });
+                }
+            }
+            rpc::Message::SetTrackerHeaders {
+                id,
+                torrent_id,
+                headers,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let found = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i))
+                    .map(|t| t.set_tracker_headers(&id, headers))
+                    .unwrap_or(false);
+                if !found {
+                    warn!("Tried to set headers of nonexistent tracker {}", id);
+                }
+            }
             rpc::Message::PurgeDNS => {
                 self.cio.msg_trk(tracker::Request::PurgeDNS);
             }
+            rpc::Message::GetConnectivity { client, serial } => {
+                self.cio.msg_rpc(rpc::CtlMessage::Connectivity {
+                    external_ip: self.connectivity.external_ip(),
+                    incoming: self.connectivity.incoming(),
+                    last_incoming: self.connectivity.last_incoming(),
+                    firewalled: self.connectivity.firewalled(time::Instant::now()),
+                    client,
+                    serial,
+                });
+            }
+            rpc::Message::GetRequests { id, client, serial } => {
+                let requests = id_to_hash(&id)
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .and_then(|i| self.torrents.get(i))
+                    .map(|t| t.in_flight_requests());
+                match requests {
+                    Some(requests) => self.cio.msg_rpc(rpc::CtlMessage::Requests {
+                        id,
+                        requests,
+                        client,
+                        serial,
+                    }),
+                    None => self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        reason: format!("Torrent {} does not exist", id),
+                        client,
+                        serial,
+                    }),
+                }
+            }
+            rpc::Message::GetMagnet { id, client, serial } => {
+                let uri = id_to_hash(&id)
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .and_then(|i| self.torrents.get(i))
+                    .map(|t| t.magnet());
+                match uri {
+                    Some(uri) => self.cio.msg_rpc(rpc::CtlMessage::Magnet {
+                        id,
+                        uri,
+                        client,
+                        serial,
+                    }),
+                    None => self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        reason: format!("Torrent {} does not exist", id),
+                        client,
+                        serial,
+                    }),
+                }
+            }
+            rpc::Message::CreateTorrent {
+                path,
+                trackers,
+                piece_length,
+                private,
+                client,
+                serial,
+            } => match torrent::create(&path, &trackers, piece_length, private) {
+                Ok((info, dir)) => {
+                    info!("Creating torrent of {:?}", path);
+                    self.cio
+                        .msg_disk(disk::Request::create(info, dir, client, serial));
+                }
+                Err(reason) => self.cio.msg_rpc(rpc::CtlMessage::Error {
+                    reason,
+                    client,
+                    serial,
+                }),
+            },
+            rpc::Message::GetPieceState { id, client, serial } => {
+                let state = id_to_hash(&id)
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .and_then(|i| self.torrents.get(i))
+                    .map(|t| t.piece_state());
+                match state {
+                    Some((have, availability)) => self.cio.msg_rpc(rpc::CtlMessage::PieceState {
+                        id,
+                        have,
+                        availability,
+                        client,
+                        serial,
+                    }),
+                    None => self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        reason: format!("Torrent {} does not exist", id),
+                        client,
+                        serial,
+                    }),
+                }
+            }
+            #[cfg(feature = "testing")]
+            rpc::Message::InjectFault {
+                id,
+                torrent_id,
+                latency_ms,
+                loss,
+                client,
+                serial,
+            } => {
+                let pid = id_to_hash(&torrent_id)
+                    .and_then(|d| self.hash_idx.get(d.as_ref()))
+                    .and_then(|i| self.torrents.get(i))
+                    .and_then(|t| t.peer_id(&id));
+                match pid {
+                    Some(pid) => {
+                        let fault = fault::Fault::new(latency_ms, loss);
+                        self.cio.set_fault(pid, fault);
+                    }
+                    None => self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        reason: format!("Peer {} does not exist", id),
+                        client,
+                        serial,
+                    }),
+                }
+            }
+            rpc::Message::ExportSession {
+                path,
+                format,
+                client,
+                serial,
+            } => {
+                let res = self.export_session(path, format);
+                self.send_session_result(res, client, serial);
+            }
+            rpc::Message::ImportSession {
+                path,
+                client,
+                serial,
+            } => {
+                let res = self.import_session(&path);
+                self.send_session_result(res, client, serial);
+            }
+            rpc::Message::Checkpoint { client, serial } => {
+                self.begin_checkpoint(client, serial);
+            }
+            rpc::Message::ResumeCheckpoint { client, serial } => {
+                let res = self.end_checkpoint();
+                self.send_session_result(res, client, serial);
+            }
+            rpc::Message::BulkAction {
+                ids,
+                action,
+                client,
+                serial,
+            } => {
+                let mut affected = 0;
+                for id in ids {
+                    let tid = match id_to_hash(&id).and_then(|d| self.hash_idx.get(d.as_ref())) {
+                        Some(tid) => *tid,
+                        None => continue,
+                    };
+                    if action == BulkAction::Remove {
+                        if let Some(mut t) = self.torrents.remove(&tid) {
+                            self.hash_idx.remove(&t.info().hash);
+                            self.cio
+                                .msg_listener(listener::Request::RemoveTorrent(t.info().hash));
+                            self.conn_queue.remove_torrent(tid);
+                            self.pauses.forget(tid);
+                            self.persist.changed_significantly();
+                            t.archive();
+                            t.delete(false);
+                            affected += 1;
+                        }
+                        continue;
+                    }
+                    if action == BulkAction::Pause || action == BulkAction::Resume {
+                        self.pauses.forget(tid);
+                    }
+                    if let Some(t) = self.torrents.get_mut(&tid) {
+                        match action {
+                            BulkAction::Pause => t.pause(),
+                            BulkAction::Resume => t.resume(),
+                            BulkAction::Reannounce => t.update_tracker(),
+                            BulkAction::Remove => unreachable!(),
+                        }
+                        affected += 1;
+                    }
+                }
+                self.cio.msg_rpc(rpc::CtlMessage::BulkResult {
+                    affected,
+                    client,
+                    serial,
+                });
+            }
         }
         false
     }
 
-    fn add_peer_rpc(&mut self, id: usize, peer: peer::PeerConn) -> Option<String> {
+    fn add_peer_rpc(&mut self, id: usize, peer: peer::PeerConn, preferred: bool) -> Option<String> {
         trace!("Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if let Some(pid) = torrent.add_peer(peer) {
+            if let Some(pid) = torrent.add_peer(peer, rpc::resource::PeerSource::Manual) {
+                if preferred {
+                    torrent.set_preferred(pid);
+                }
                 self.peers.insert(pid, id);
                 return Some(util::peer_rpc_id(&torrent.info().hash, pid as u64));
             }
@@ -656,14 +1783,17 @@ impl<T: cio::CIO> Control<T> {
         None
     }
 
-    fn add_peer(&mut self, id: usize, peer: peer::PeerConn) {
+    fn add_peer(&mut self, id: usize, peer: peer::PeerConn, source: rpc::resource::PeerSource) {
         trace!("Adding peer to torrent {:?}!", id);
+        if self.meta_slots.waiting(id) {
+            return;
+        }
         if let Some(torrent) = self.torrents.get_mut(&id) {
             if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
                 self.queue.add(id, torrent.priority());
                 return;
             }
-            if let Some(pid) = torrent.add_peer(peer) {
+            if let Some(pid) = torrent.add_peer(peer, source) {
                 self.peers.insert(pid, id);
             }
         }
@@ -671,6 +1801,16 @@ impl<T: cio::CIO> Control<T> {
 
     fn add_inc_peer(&mut self, id: usize, peer: peer::PeerConn, cid: [u8; 20], rsv: [u8; 8]) {
         trace!("Adding peer to torrent {:?}!", id);
+        if self.meta_slots.waiting(id) {
+            return;
+        }
+        if self.conn_budget.adaptive()
+            && self.peers.len() >= self.conn_budget.limit()
+            && !self.reclaim_conn(id)
+        {
+            debug!("Connection limit reached, rejecting incoming peer");
+            return;
+        }
         if let Some(torrent) = self.torrents.get_mut(&id) {
             if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
                 self.queue.add(id, torrent.priority());
@@ -682,6 +1822,108 @@ impl<T: cio::CIO> Control<T> {
         }
     }
 
+    fn send_session_result(&mut self, res: Result<u64, String>, client: usize, serial: u64) {
+        match res {
+            Ok(torrents) => self.cio.msg_rpc(rpc::CtlMessage::SessionResult {
+                torrents,
+                client,
+                serial,
+            }),
+            Err(reason) => self.cio.msg_rpc(rpc::CtlMessage::Error {
+                reason,
+                client,
+                serial,
+            }),
+        }
+    }
+
+    /// Pauses running torrents and syncs the disk so that the session
+    /// directory can be backed up, replying once it's consistent.
+    fn begin_checkpoint(&mut self, client: usize, serial: u64) {
+        if self.quiesced {
+            self.cio.msg_rpc(rpc::CtlMessage::Error {
+                reason: "Disk writes are quiesced".to_owned(),
+                client,
+                serial,
+            });
+            return;
+        }
+        match self.checkpoint.begin(client, serial) {
+            checkpoint::Begin::Start => {
+                info!("Checkpointing session");
+                let mut paused = Vec::new();
+                for (tid, t) in self.torrents.iter_mut() {
+                    if !t.status().stopped() {
+                        t.pause();
+                        paused.push(*tid);
+                    }
+                }
+                self.checkpoint.paused(paused);
+                self.cio.msg_disk(disk::Request::checkpoint());
+                self.update_rpc_checkpoint();
+            }
+            checkpoint::Begin::Wait => {}
+            checkpoint::Begin::Ready => {
+                let torrents = self.torrents.len() as u64;
+                self.send_session_result(Ok(torrents), client, serial);
+            }
+        }
+    }
+
+    fn checkpoint_synced(&mut self) {
+        match self.checkpoint.synced() {
+            checkpoint::Synced::Serialize => {
+                self.serialize();
+                self.cio.msg_disk(disk::Request::checkpoint());
+            }
+            checkpoint::Synced::Ready(waiting) => {
+                info!("Session checkpointed, it can now be backed up");
+                let torrents = self.torrents.len() as u64;
+                for (client, serial) in waiting {
+                    self.send_session_result(Ok(torrents), client, serial);
+                }
+                self.update_rpc_checkpoint();
+            }
+            checkpoint::Synced::Ignored => {}
+        }
+    }
+
+    /// Ends a checkpoint, resuming the torrents it paused and
+    /// returning how many were resumed.
+    fn end_checkpoint(&mut self) -> Result<u64, String> {
+        if !self.checkpoint.active() {
+            return Err("No checkpoint is active".to_owned());
+        }
+        let (paused, waiting) = self.checkpoint.end();
+        for (client, serial) in waiting {
+            self.cio.msg_rpc(rpc::CtlMessage::Error {
+                reason: "Checkpoint was resumed before completing".to_owned(),
+                client,
+                serial,
+            });
+        }
+        let mut resumed = 0;
+        for tid in paused {
+            if let Some(t) = self.torrents.get_mut(&tid) {
+                t.resume();
+                resumed += 1;
+            }
+        }
+        info!("Checkpoint ended, resumed {} torrents", resumed);
+        self.update_rpc_checkpoint();
+        Ok(resumed)
+    }
+
+    fn update_rpc_checkpoint(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerCheckpoint {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                checkpoint: self.checkpoint.state(),
+            },
+        ]));
+    }
+
     fn update_rpc_space(&mut self) {
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             rpc::resource::SResourceUpdate::ServerSpace {
@@ -692,8 +1934,266 @@ impl<T: cio::CIO> Control<T> {
         ]));
     }
 
+    /// Drops the slowest peer of a lower priority torrent, if allowed,
+    /// so that torrent `tid` can connect to a peer at the connection
+    /// limit. Returns whether a connection was freed.
+    fn reclaim_conn(&mut self, tid: usize) -> bool {
+        let priority = match self.torrents.get(&tid) {
+            Some(t) if t.wants_peers() => t.priority(),
+            _ => return false,
+        };
+        let others = self
+            .torrents
+            .iter()
+            .filter(|&(id, _)| *id != tid)
+            .map(|(id, t)| (*id, t.priority(), t.num_peers()));
+        let victim = match self.reclaim.victim(priority, others, time::Instant::now()) {
+            Some(victim) => victim,
+            None => return false,
+        };
+        let dropped = self
+            .torrents
+            .get_mut(&victim)
+            .map(|t| t.drop_slowest_peer())
+            .unwrap_or(false);
+        if dropped {
+            info!(
+                "Reclaimed a connection of torrent {} for higher priority torrent {}",
+                victim, tid
+            );
+            self.update_rpc_conn_queue();
+        }
+        dropped
+    }
+
+    fn update_rpc_conn_queue(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerConnQueue {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                conn_queue: self.conn_queue.len() as u64,
+                conn_queue_dropped: self.conn_queue.dropped(),
+                conn_limit: self.conn_budget.limit() as u64,
+                conns_reclaimed: self.reclaim.reclaimed(),
+            },
+        ]));
+    }
+
+    fn set_throttle_burst(&mut self, burst: usize) {
+        if burst == self.throttler.burst() {
+            return;
+        }
+        debug!("Setting throttle burst to {} bytes", burst);
+        self.throttler.set_burst(burst);
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerThrottleBurst {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                throttle_burst: burst as u64,
+            },
+        ]));
+    }
+
+    fn set_piece_affinity(&mut self, affinity: bool) {
+        if affinity == self.piece_affinity {
+            return;
+        }
+        self.piece_affinity = affinity;
+        for t in self.torrents.values_mut() {
+            t.set_piece_affinity(affinity);
+        }
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerPieceAffinity {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                piece_affinity: affinity,
+            },
+        ]));
+    }
+
+    /// Holds disk writes in memory, or writes out those held.
+    fn set_quiesced(&mut self, quiesced: bool) {
+        if quiesced == self.quiesced {
+            return;
+        }
+        self.quiesced = quiesced;
+        self.cio.msg_disk(disk::Request::quiesce(quiesced));
+        self.update_rpc_quiesced();
+    }
+
+    /// Stops torrents from requesting pieces while held writes fill
+    /// the buffer, letting them continue once it's drained.
+    fn set_write_buffer_full(&mut self, full: bool) {
+        if full {
+            warn!(
+                "Quiesced writes reached {} MB, pausing piece requests",
+                CONFIG.disk.quiesce_buffer_mb
+            );
+        }
+        self.write_buffer_full = full;
+        for t in self.torrents.values_mut() {
+            t.set_buffer_full(full);
+        }
+        self.update_rpc_quiesced();
+    }
+
+    fn update_rpc_quiesced(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerQuiesced {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                quiesced: self.quiesced,
+                write_buffer_full: self.write_buffer_full,
+            },
+        ]));
+    }
+
+    fn update_rpc_write_cache(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerWriteCache {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                write_cache_hits: self.write_cache.hits,
+                write_cache_misses: self.write_cache.misses,
+                write_cache_bytes: self.write_cache.bytes,
+            },
+            rpc::resource::SResourceUpdate::ServerDiskTransfer {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                disk_read: self.write_cache.read,
+                disk_written: self.write_cache.written,
+            },
+        ]));
+    }
+
+    /// Pauses running torrents when the connection becomes metered,
+    /// resuming them once it no longer is.
+    fn set_metered(&mut self, metered: bool) {
+        if metered == self.pauses.active(Reason::Metered) {
+            return;
+        }
+        if metered {
+            info!("Connection is metered");
+            let affected = if CONFIG.net.pause_when_metered {
+                self.pausable(false)
+            } else {
+                Vec::new()
+            };
+            for tid in self.pauses.enter(Reason::Metered, affected) {
+                if let Some(t) = self.torrents.get_mut(&tid) {
+                    t.pause();
+                }
+            }
+        } else {
+            info!("Connection is no longer metered");
+            let resumed = self.pauses.leave(Reason::Metered);
+            self.resume_released(resumed);
+        }
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerMetered {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                metered,
+            },
+        ]));
+    }
+
+    /// Pauses downloading torrents when free space on the download
+    /// volume runs low, resuming them once space is freed.
+    fn set_low_space(&mut self, low: bool) {
+        if low {
+            warn!(
+                "Free space is below {} MB, pausing downloads",
+                CONFIG.disk.low_space_mb
+            );
+            let downloading = self.pausable(true);
+            for tid in self.pauses.enter(Reason::LowSpace, downloading) {
+                if let Some(t) = self.torrents.get_mut(&tid) {
+                    t.pause();
+                }
+            }
+        } else {
+            info!("Free space is no longer low, resuming downloads");
+            let resumed = self.pauses.leave(Reason::LowSpace);
+            self.resume_released(resumed);
+        }
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerLowSpace {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                low_space: low,
+            },
+        ]));
+    }
+
+    /// Handles a write of a torrent failing on a read-only filesystem.
+    fn write_read_only(&mut self, tid: usize) {
+        if !self.pauses.active(Reason::ReadOnly) {
+            self.set_read_only(true);
+        } else if let Some(t) = self.torrents.get_mut(&tid) {
+            // Resumed by the user while still read-only
+            if !t.status().stopped() && !t.complete() && self.pauses.add(tid, Reason::ReadOnly) {
+                t.set_read_only(true);
+            }
+        }
+    }
+
+    /// Pauses downloading torrents while the download volume is
+    /// read-only, resuming them once it's writable again if
+    /// disk.resume_writable is set. Seeding torrents are unaffected.
+    fn set_read_only(&mut self, read_only: bool) {
+        if read_only {
+            warn!("Download volume is read-only, pausing downloads");
+            let downloading = self.pausable(true);
+            for tid in self.pauses.enter(Reason::ReadOnly, downloading) {
+                if let Some(t) = self.torrents.get_mut(&tid) {
+                    t.set_read_only(true);
+                }
+            }
+        } else if CONFIG.disk.resume_writable {
+            info!("Download volume is writable again, resuming downloads");
+            let resumed = self.pauses.leave(Reason::ReadOnly);
+            self.resume_released(resumed);
+        } else {
+            info!("Download volume is writable again");
+            self.pauses.abandon(Reason::ReadOnly);
+        }
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerReadOnly {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                read_only,
+            },
+        ]));
+    }
+
+    /// Torrents which a newly entered pause condition applies to, being
+    /// those running or already paused by another condition.
+    fn pausable(&self, incomplete: bool) -> Vec<usize> {
+        self.torrents
+            .iter()
+            .filter(|&(tid, t)| !t.status().stopped() || self.pauses.held(*tid))
+            .filter(|&(_, t)| !incomplete || !t.complete())
+            .map(|(tid, _)| *tid)
+            .collect()
+    }
+
+    /// Resumes torrents which no pause condition holds anymore.
+    fn resume_released(&mut self, tids: Vec<usize>) {
+        for tid in tids {
+            if let Some(t) = self.torrents.get_mut(&tid) {
+                t.resume();
+            }
+        }
+    }
+
     fn update_rpc_tx(&mut self) {
         self.stat.tick();
+        let rates = (self.throttler.ul_rate(), self.throttler.dl_rate());
+        let observed = (self.stat.avg_ul(), self.stat.avg_dl());
+        if self.conn_budget.update(rates, observed) {
+            self.update_rpc_conn_queue();
+        }
         if self.stat.active() {
             let (ul, dl) = (self.stat.avg_ul(), self.stat.avg_dl());
             self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
@@ -711,20 +2211,146 @@ impl<T: cio::CIO> Control<T> {
         }
     }
 
+    /// Switches between the regular and alternate global rate limits
+    /// when the schedule calls for it, or unconditionally re-applies
+    /// them if `force` is set.
+    fn update_alt_speed(&mut self, force: bool) {
+        let active = self.data.alt_speed.active_at(&Local::now());
+        if active == self.alt_speed_active && !force {
+            return;
+        }
+        if active != self.alt_speed_active {
+            info!(
+                "Switching to {} speed limits",
+                if active { "alternate" } else { "regular" }
+            );
+        }
+        self.alt_speed_active = active;
+        self.apply_throttle();
+        self.update_rpc_throttle();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerAltSpeed {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                alt_speed: self.data.alt_speed.to_rpc(),
+                alt_speed_active: active,
+            },
+        ]));
+    }
+
+    /// Sets the global rate limits in use, being the alternate ones
+    /// while their scheduled window is open.
+    fn apply_throttle(&mut self) {
+        let (ul, dl) = if self.alt_speed_active {
+            (
+                self.data.alt_speed.throttle_up,
+                self.data.alt_speed.throttle_down,
+            )
+        } else {
+            (self.data.throttle_ul, self.data.throttle_dl)
+        };
+        self.throttler.set_ul_rate(ul);
+        self.throttler.set_dl_rate(dl);
+    }
+
+    fn update_rpc_throttle(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::Throttle {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                throttle_up: self.throttler.ul_rate(),
+                throttle_down: self.throttler.dl_rate(),
+                throttle_up_seeding: self.throttler.ul_seed_rate(),
+            },
+        ]));
+    }
+
+    fn update_rpc_jobs(&mut self) {
+        let (trk, unchk, ses, tx) = self.job_intervals();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerJobs {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                tracker_job_secs: trk,
+                unchoke_job_secs: unchk,
+                session_job_secs: ses,
+                tx_job_ms: tx,
+            },
+        ]));
+    }
+
+    /// Returns the tracker, unchoke and session job intervals in
+    /// seconds and the transfer job interval in milliseconds.
+    fn job_intervals(&self) -> (u64, u64, u64, u64) {
+        let get = |kind| self.jobs.interval(kind).unwrap_or_default();
+        let tx = get(JobKind::Transfer);
+        (
+            get(JobKind::Tracker).as_secs(),
+            get(JobKind::Unchoke).as_secs(),
+            get(JobKind::Session).as_secs(),
+            tx.as_secs() * 1000 + u64::from(tx.subsec_millis()),
+        )
+    }
+
     fn send_rpc_info(&mut self) {
+        let (trk, unchk, ses, tx) = self.job_intervals();
         let res = rpc::resource::Resource::Server(rpc::resource::Server {
             id: self.data.id.clone(),
             rate_up: 0,
             rate_down: 0,
             throttle_up: self.throttler.ul_rate(),
             throttle_down: self.throttler.dl_rate(),
+            throttle_up_seeding: self.throttler.ul_seed_rate(),
+            throttle_burst: self.throttler.burst() as u64,
+            throttle_interval_ms: self.throttler.interval() as u64,
             transferred_up: self.data.ul,
             transferred_down: self.data.dl,
             ses_transferred_up: self.data.session_ul,
             ses_transferred_down: self.data.session_dl,
             free_space: self.data.free_space,
+            conn_queue: self.conn_queue.len() as u64,
+            conn_queue_dropped: self.conn_queue.dropped(),
+            conn_limit: self.conn_budget.limit() as u64,
+            conns_reclaimed: self.reclaim.reclaimed(),
+            metered: self.pauses.active(Reason::Metered),
+            low_space: self.pauses.active(Reason::LowSpace),
+            read_only: self.pauses.active(Reason::ReadOnly),
+            piece_affinity: self.piece_affinity,
+            quiesced: self.quiesced,
+            write_buffer_full: self.write_buffer_full,
+            checkpoint: self.checkpoint.state(),
+            write_cache_hits: self.write_cache.hits,
+            write_cache_misses: self.write_cache.misses,
+            write_cache_bytes: self.write_cache.bytes,
+            disk_read: self.write_cache.read,
+            disk_written: self.write_cache.written,
+            dht_nodes: self.dht_nodes as u64,
+            alt_speed: self.data.alt_speed.to_rpc(),
+            alt_speed_active: self.alt_speed_active,
+            web_seed_strategy: match CONFIG.net.web_seed_strategy {
+                WebSeedStrategy::Always => rpc::resource::WebSeedStrategy::Always,
+                WebSeedStrategy::Underutilized => rpc::resource::WebSeedStrategy::Underutilized,
+                WebSeedStrategy::LastResort => rpc::resource::WebSeedStrategy::LastResort,
+            },
+            ip_family: match CONFIG.net.ip_family {
+                IpFamily::Dual => rpc::resource::IpFamily::Dual,
+                IpFamily::Ipv4 => rpc::resource::IpFamily::Ipv4,
+                IpFamily::Ipv6 => rpc::resource::IpFamily::Ipv6,
+            },
+            key_rotation: match CONFIG.trk.key_rotation {
+                KeyRotation::Stable => rpc::resource::KeyRotation::Stable,
+                KeyRotation::Session => rpc::resource::KeyRotation::Session,
+                KeyRotation::Interval => rpc::resource::KeyRotation::Interval,
+            },
+            key_rotated: self.key_rotated,
+            tracker_job_secs: trk,
+            unchoke_job_secs: unchk,
+            session_job_secs: ses,
+            tx_job_ms: tx,
             started: Utc::now(),
             download_token: DL_TOKEN.clone(),
+            quarantined: self.quarantined.clone(),
+            recovered: self.recovered.clone(),
             ..Default::default()
         });
         self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![res]));
@@ -753,6 +2379,50 @@ impl ServerData {
             free_space: 0,
             throttle_ul: Some(-1),
             throttle_dl: Some(-1),
+            alt_speed: Schedule::default(),
+        }
+    }
+
+    /// Reads serialized server data, including that saved by older
+    /// versions.
+    fn load(data: &[u8]) -> Option<ServerData> {
+        bincode::deserialize(data).ok().or_else(|| {
+            bincode::deserialize::<LegacyServerData>(data)
+                .ok()
+                .map(ServerData::from)
+        })
+    }
+
+    /// Salvages the ID and transfer totals from the start of server
+    /// data which otherwise fails to load. Every version lays them out
+    /// the same way.
+    fn recover(data: &[u8]) -> Option<ServerData> {
+        let id_len = LittleEndian::read_u64(data.get(..8)?);
+        let rest = &data[8..];
+        if id_len > rest.len() as u64 {
+            return None;
+        }
+        let (id, rest) = rest.split_at(id_len as usize);
+        let totals = rest.get(..16)?;
+        let mut sd = ServerData::new();
+        if let Ok(id) = ::std::str::from_utf8(id) {
+            sd.id = id.to_owned();
+        }
+        sd.ul = LittleEndian::read_u64(&totals[..8]);
+        sd.dl = LittleEndian::read_u64(&totals[8..]);
+        Some(sd)
+    }
+}
+
+impl From<LegacyServerData> for ServerData {
+    fn from(d: LegacyServerData) -> ServerData {
+        ServerData {
+            id: d.id,
+            ul: d.ul,
+            dl: d.dl,
+            throttle_ul: d.throttle_ul,
+            throttle_dl: d.throttle_dl,
+            ..Default::default()
         }
     }
 }
@@ -816,24 +2486,76 @@ impl<T: cio::CIO> JobManager<T> {
         }
     }
 
-    pub fn add_job<J: job::Job<T> + 'static>(&mut self, job: J, interval: time::Duration) {
+    pub fn add_job<J: job::Job<T> + 'static>(
+        &mut self,
+        job: J,
+        kind: Option<JobKind>,
+        interval: time::Duration,
+    ) {
         self.jobs.push(JobData {
             job: Box::new(job),
+            kind,
             interval,
             last_updated: time::Instant::now(),
         })
     }
 
-    pub fn add_cjob<J: CJob<T> + 'static>(&mut self, job: J, interval: time::Duration) {
+    pub fn add_cjob<J: CJob<T> + 'static>(
+        &mut self,
+        job: J,
+        kind: Option<JobKind>,
+        interval: time::Duration,
+    ) {
         self.cjobs.push(JobData {
             job: Box::new(job),
+            kind,
             interval,
             last_updated: time::Instant::now(),
         })
     }
 
+    /// Changes the interval of all jobs of the given kind, taking
+    /// effect from their next run onwards.
+    pub fn set_interval(&mut self, kind: JobKind, interval: time::Duration) {
+        for j in self.jobs.iter_mut().filter(|j| j.kind == Some(kind)) {
+            j.interval = interval;
+        }
+        for j in self.cjobs.iter_mut().filter(|j| j.kind == Some(kind)) {
+            j.interval = interval;
+        }
+    }
+
+    pub fn interval(&self, kind: JobKind) -> Option<time::Duration> {
+        self.jobs
+            .iter()
+            .map(|j| (j.kind, j.interval))
+            .chain(self.cjobs.iter().map(|j| (j.kind, j.interval)))
+            .find(|&(k, _)| k == Some(kind))
+            .map(|(_, i)| i)
+    }
+
+    /// Earliest time at which a job of the given kind will next run.
+    #[cfg(test)]
+    pub fn next_run(&self, kind: JobKind) -> Option<time::Instant> {
+        self.jobs
+            .iter()
+            .map(|j| (j.kind, j.last_updated + j.interval))
+            .chain(
+                self.cjobs
+                    .iter()
+                    .map(|j| (j.kind, j.last_updated + j.interval)),
+            )
+            .filter(|&(k, _)| k == Some(kind))
+            .map(|(_, t)| t)
+            .min()
+    }
+
     pub fn update(&mut self, control: &mut Control<T>) {
         for j in &mut self.jobs {
+            // The session directory may be being backed up
+            if j.kind == Some(JobKind::Session) && control.checkpoint.active() {
+                continue;
+            }
             if j.last_updated.elapsed() > j.interval {
                 j.job.update(&mut control.torrents);
                 j.last_updated = time::Instant::now();
@@ -848,11 +2570,51 @@ impl<T: cio::CIO> JobManager<T> {
     }
 }
 
+impl JobKind {
+    /// Range of intervals the job may be run at.
+    pub fn bounds(&self) -> (time::Duration, time::Duration) {
+        match *self {
+            JobKind::Tracker => (
+                time::Duration::from_secs(10),
+                time::Duration::from_secs(3600),
+            ),
+            JobKind::Unchoke => (time::Duration::from_secs(5), time::Duration::from_secs(300)),
+            JobKind::Session => (
+                time::Duration::from_secs(10),
+                time::Duration::from_secs(3600),
+            ),
+            // Jobs can't run any faster than they're polled
+            JobKind::Transfer => (
+                time::Duration::from_millis(JOB_INT_MS as u64),
+                time::Duration::from_secs(10),
+            ),
+        }
+    }
+
+    pub fn valid(&self, interval: time::Duration) -> bool {
+        let (min, max) = self.bounds();
+        interval >= min && interval <= max
+    }
+
+    /// Restricts an interval to the job's bounds.
+    pub fn clamp(&self, interval: time::Duration) -> time::Duration {
+        let (min, max) = self.bounds();
+        if !self.valid(interval) {
+            warn!(
+                "{:?} job interval {:?} is out of bounds, must be between {:?} and {:?}",
+                self, interval, min, max
+            );
+        }
+        cmp::min(cmp::max(interval, min), max)
+    }
+}
+
 pub struct SpaceUpdate;
 
 impl<T: cio::CIO> CJob<T> for SpaceUpdate {
     fn update(&mut self, control: &mut Control<T>) {
         control.cio.msg_disk(disk::Request::FreeSpace);
+        control.cio.msg_disk(disk::Request::CacheStats);
     }
 }
 
@@ -878,6 +2640,347 @@ pub struct SerializeUpdate;
 
 impl<T: cio::CIO> CJob<T> for SerializeUpdate {
     fn update(&mut self, control: &mut Control<T>) {
-        control.serialize();
+        control.serialize_changes(true);
+    }
+}
+
+pub struct MetaSlotUpdate;
+
+impl<T: cio::CIO> CJob<T> for MetaSlotUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        let torrents = &mut control.torrents;
+        let started = control.meta_slots.update(|tid| match torrents.get(&tid) {
+            Some(t) => t.status().magnet() && t.status().error.is_none(),
+            None => false,
+        });
+        for tid in started {
+            debug!("Torrent {} got a metadata fetch slot", tid);
+            if let Some(t) = torrents.get_mut(&tid) {
+                t.set_meta_queued(false);
+            }
+        }
+    }
+}
+
+pub struct ConnectUpdate {
+    reported: (usize, u64),
+}
+
+impl ConnectUpdate {
+    fn new() -> ConnectUpdate {
+        ConnectUpdate { reported: (0, 0) }
+    }
+}
+
+impl<T: cio::CIO> CJob<T> for ConnectUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.connect_queued();
+        let current = (control.conn_queue.len(), control.conn_queue.dropped());
+        if current != self.reported {
+            self.reported = current;
+            control.update_rpc_conn_queue();
+        }
+    }
+}
+
+pub struct AltSpeedUpdate;
+
+impl<T: cio::CIO> CJob<T> for AltSpeedUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        control.update_alt_speed(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cio::test::TCIO;
+    use super::cio::CIO;
+    use super::{cio, job, Control, JobKind, JobManager, ServerData};
+    use amy;
+    use bincode;
+    use config::Allocation;
+    use rpc;
+    use rpc::proto::message::{BulkAction, SessionFormat};
+    use rpc::resource::{CResourceUpdate, SResourceUpdate};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use std::{env, fs, process};
+    use throttle::Throttler;
+    use torrent::Info;
+    use url::Url;
+    use util::{self, hash_to_id};
+    use {tracker, CONFIG};
+
+    /// A control over the mock IO, whose recorded messages can be
+    /// read from the returned handle.
+    fn test_control() -> (Control<TCIO>, TCIO) {
+        let poll = amy::Poller::new().unwrap();
+        let mut reg = poll.get_registrar();
+        let throttler = Throttler::new(None, None, 1024, 100, &reg).unwrap();
+        let (db, _) = reg.channel().unwrap();
+        let cio = TCIO::new();
+        let control = Control::new(cio.new_handle(), throttler, db).unwrap();
+        (control, cio)
+    }
+
+    /// A single piece torrent with a file named after `n`
+    fn test_info(n: u8) -> Info {
+        let mut info = Info::with_pieces(1);
+        info.name = format!("t{}", n);
+        info.files[0].path = PathBuf::from(&info.name);
+        info.hash = [n; 20];
+        info.piece_idx =
+            Info::generate_piece_idx(info.hashes.len(), u64::from(info.piece_len), &info.files);
+        info
+    }
+
+    fn add_test_torrent(control: &mut Control<TCIO>, n: u8) {
+        let info = test_info(n);
+        control.add_torrent(info, None, true, false, None, Allocation::Sparse, 0, 0);
+    }
+
+    fn paused(control: &Control<TCIO>, n: u8) -> Option<bool> {
+        let tid = control.hash_idx.get(&[n; 20])?;
+        control.torrents.get(tid).map(|t| t.status().paused)
+    }
+
+    fn bulk_affected(cio: &TCIO) -> Option<u64> {
+        match cio.data().rpc_msgs.last() {
+            Some(&rpc::CtlMessage::BulkResult { affected, .. }) => Some(affected),
+            _ => None,
+        }
+    }
+
+    fn error_serial(cio: &TCIO) -> Option<u64> {
+        match cio.data().rpc_msgs.last() {
+            Some(&rpc::CtlMessage::Error { serial, .. }) => Some(serial),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_path_conflict() {
+        // Conflicts are rejected by default
+        let (mut control, cio) = test_control();
+        add_test_torrent(&mut control, 1);
+
+        let mut info = test_info(2);
+        info.files[0].path = PathBuf::from("t1");
+        control.add_torrent(info, None, true, false, None, Allocation::Sparse, 0, 1);
+        assert_eq!(error_serial(&cio), Some(1));
+        assert_eq!(control.torrents.len(), 1);
+
+        // Moving a torrent onto another's files is rejected as well
+        let mut info = test_info(3);
+        info.files[0].path = PathBuf::from("t1");
+        let elsewhere = Some("/elsewhere".to_owned());
+        control.add_torrent(info, elsewhere, true, false, None, Allocation::Sparse, 0, 2);
+        assert_eq!(control.torrents.len(), 2);
+        control.handle_rpc_ev(rpc::Message::UpdateTorrent {
+            resource: CResourceUpdate {
+                id: hash_to_id(&[3; 20]),
+                path: Some(CONFIG.disk.directory.clone()),
+                ..Default::default()
+            },
+            client: 0,
+            serial: 3,
+        });
+        assert_eq!(error_serial(&cio), Some(3));
+        let tid = control.hash_idx[&[3; 20]];
+        assert_eq!(control.torrents[&tid].path(), "/elsewhere");
+    }
+
+    #[test]
+    fn test_session_round_trip() {
+        let (mut control, _cio) = test_control();
+        add_test_torrent(&mut control, 1);
+        add_test_torrent(&mut control, 2);
+        control.handle_rpc_ev(rpc::Message::Pause(hash_to_id(&[2; 20])));
+        let url = "http://tracker.example/announce";
+        control.handle_rpc_ev(rpc::Message::AddTracker {
+            id: hash_to_id(&[1; 20]),
+            client: 0,
+            serial: 0,
+            tracker: Url::parse(url).unwrap(),
+        });
+        control.handle_rpc_ev(rpc::Message::SetTrackerHeaders {
+            id: util::trk_rpc_id(&[1; 20], url),
+            torrent_id: hash_to_id(&[1; 20]),
+            headers: tracker::Headers::new(vec![("Cookie".to_owned(), "secret".to_owned())])
+                .unwrap(),
+        });
+
+        let dir = env::temp_dir().join(format!("synapse-export-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for &(name, format) in &[
+            ("bin", SessionFormat::Binary),
+            ("json", SessionFormat::Json),
+        ] {
+            let path = dir.join(name);
+            assert_eq!(control.export_session(path.clone(), format), Ok(2));
+            // Tracker headers are exported, so nobody else may read them
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            let (mut fresh, _fresh_cio) = test_control();
+            add_test_torrent(&mut fresh, 1);
+            assert_eq!(fresh.import_session(&path), Ok(1));
+            assert_eq!(fresh.torrents.len(), 2);
+            assert_eq!(paused(&fresh, 2), Some(true));
+        }
+
+        // Only the binary archive holds tracker headers
+        let has_secret = |name| {
+            let data = fs::read(dir.join(name)).unwrap();
+            data.windows(6).any(|w| w == b"secret")
+        };
+        assert!(has_secret("bin"));
+        assert!(!has_secret("json"));
+
+        // Failed writes are reported rather than the torrents exported
+        let missing = dir.join("missing").join("bin");
+        let res = control.export_session(missing, SessionFormat::Binary);
+        assert!(res.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_action() {
+        let (mut control, cio) = test_control();
+        for n in 1..4 {
+            add_test_torrent(&mut control, n);
+        }
+        let ids = |ns: &[u8]| -> Vec<String> { ns.iter().map(|n| hash_to_id(&[*n; 20])).collect() };
+
+        // Only the matching torrents are paused, unknown IDs are skipped
+        let mut unknown = ids(&[1, 2]);
+        unknown.push(hash_to_id(&[9; 20]));
+        control.handle_rpc_ev(rpc::Message::BulkAction {
+            ids: unknown,
+            action: BulkAction::Pause,
+            client: 0,
+            serial: 0,
+        });
+        assert_eq!(bulk_affected(&cio), Some(2));
+        assert_eq!(paused(&control, 1), Some(true));
+        assert_eq!(paused(&control, 2), Some(true));
+        assert_eq!(paused(&control, 3), Some(false));
+
+        control.handle_rpc_ev(rpc::Message::BulkAction {
+            ids: ids(&[2]),
+            action: BulkAction::Resume,
+            client: 0,
+            serial: 1,
+        });
+        assert_eq!(bulk_affected(&cio), Some(1));
+        assert_eq!(paused(&control, 1), Some(true));
+        assert_eq!(paused(&control, 2), Some(false));
+
+        // Removal drops the torrents and deletes their files
+        let deletions = cio.data().disk_msgs.len();
+        control.handle_rpc_ev(rpc::Message::BulkAction {
+            ids: ids(&[1, 3]),
+            action: BulkAction::Remove,
+            client: 0,
+            serial: 2,
+        });
+        assert_eq!(bulk_affected(&cio), Some(2));
+        assert_eq!(paused(&control, 1), None);
+        assert_eq!(paused(&control, 2), Some(false));
+        assert_eq!(paused(&control, 3), None);
+        assert_eq!(control.torrents.len(), 1);
+        assert!(cio.data().disk_msgs.len() > deletions);
+    }
+
+    #[test]
+    fn test_recover_server_data() {
+        let mut sd = ServerData::new();
+        sd.ul = 1234;
+        sd.dl = 5678;
+        let data = bincode::serialize(&sd).unwrap();
+        // Cut off within the throttle settings
+        let truncated = &data[..8 + sd.id.len() + 17];
+        assert!(ServerData::load(truncated).is_none());
+
+        let recovered = ServerData::recover(truncated).unwrap();
+        assert_eq!(recovered.id, sd.id);
+        assert_eq!((recovered.ul, recovered.dl), (1234, 5678));
+        assert!(ServerData::recover(&data[..8 + sd.id.len() + 8]).is_none());
+        assert!(ServerData::recover(&[0xFF; 32]).is_none());
+    }
+
+    #[test]
+    fn test_pex_per_torrent() {
+        let (mut control, cio) = test_control();
+        add_test_torrent(&mut control, 1);
+        add_test_torrent(&mut control, 2);
+        control.handle_rpc_ev(rpc::Message::UpdateTorrent {
+            resource: CResourceUpdate {
+                id: hash_to_id(&[1; 20]),
+                pex: Some(false),
+                ..Default::default()
+            },
+            client: 0,
+            serial: 0,
+        });
+        cio.data().rpc_msgs.clear();
+
+        // Only the torrent which still uses PEX gets an update
+        let mut pex = job::PEXUpdate::new();
+        job::Job::update(&mut pex, &mut control.torrents);
+        let updated: Vec<_> = cio
+            .data()
+            .rpc_msgs
+            .iter()
+            .flat_map(|m| match *m {
+                rpc::CtlMessage::Update(ref u) => u.clone(),
+                _ => vec![],
+            })
+            .filter_map(|u| match u {
+                SResourceUpdate::TorrentPex { id, .. } => Some(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(updated, vec![hash_to_id(&[2; 20])]);
+    }
+
+    #[test]
+    fn test_set_interval() {
+        let mut jobs: JobManager<cio::test::TCIO> = JobManager::new();
+        jobs.add_job(
+            job::SessionUpdate,
+            Some(JobKind::Session),
+            Duration::from_secs(60),
+        );
+        jobs.add_job(
+            job::UnchokeUpdate,
+            Some(JobKind::Unchoke),
+            Duration::from_secs(15),
+        );
+        let next = jobs.next_run(JobKind::Session).unwrap();
+
+        jobs.set_interval(JobKind::Session, Duration::from_secs(600));
+        assert_eq!(
+            jobs.interval(JobKind::Session),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            jobs.next_run(JobKind::Session).unwrap(),
+            next + Duration::from_secs(540)
+        );
+        // Other jobs are unaffected
+        assert_eq!(
+            jobs.interval(JobKind::Unchoke),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(jobs.interval(JobKind::Tracker), None);
+
+        assert!(!JobKind::Session.valid(Duration::from_secs(1)));
+        assert!(JobKind::Session.valid(Duration::from_secs(600)));
+        assert_eq!(
+            JobKind::Transfer.clamp(Duration::from_millis(1)),
+            JobKind::Transfer.bounds().0
+        );
     }
 }
@@ -0,0 +1,203 @@
+use util::{FHashSet, UHashMap};
+
+/// Condition under which torrents are paused by the client itself,
+/// rather than by the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reason {
+    /// The connection is metered
+    Metered,
+    /// Free space on the download volume is below the threshold
+    LowSpace,
+    /// Writes to the download volume failed with a read-only filesystem
+    ReadOnly,
+}
+
+/// Tracks which conditions are in effect and which torrents each of
+/// them holds paused. A torrent may be held by several conditions at
+/// once, and is only resumed once none of them holds it anymore.
+pub struct Pauses {
+    active: FHashSet<Reason>,
+    held: UHashMap<FHashSet<Reason>>,
+}
+
+impl Pauses {
+    pub fn new() -> Pauses {
+        Pauses {
+            active: FHashSet::default(),
+            held: UHashMap::default(),
+        }
+    }
+
+    pub fn active(&self, reason: Reason) -> bool {
+        self.active.contains(&reason)
+    }
+
+    /// Whether a torrent is held paused by any condition
+    pub fn held(&self, tid: usize) -> bool {
+        self.held.contains_key(&tid)
+    }
+
+    /// Marks `reason` as in effect, holding the torrents `affected` by
+    /// it. Torrents which weren't already held by another condition
+    /// are returned, as these should now be paused.
+    pub fn enter<I: IntoIterator<Item = usize>>(
+        &mut self,
+        reason: Reason,
+        affected: I,
+    ) -> Vec<usize> {
+        if !self.active.insert(reason) {
+            return Vec::new();
+        }
+        let mut paused = Vec::new();
+        for tid in affected {
+            let reasons = self.held.entry(tid).or_default();
+            if reasons.is_empty() {
+                paused.push(tid);
+            }
+            reasons.insert(reason);
+        }
+        paused
+    }
+
+    /// Marks `reason` as no longer in effect, returning the torrents
+    /// which no condition holds anymore, as these should be resumed.
+    pub fn leave(&mut self, reason: Reason) -> Vec<usize> {
+        self.active.remove(&reason);
+        let mut resumed = Vec::new();
+        self.held.retain(|tid, reasons| {
+            if reasons.remove(&reason) && reasons.is_empty() {
+                resumed.push(*tid);
+            }
+            !reasons.is_empty()
+        });
+        resumed
+    }
+
+    /// Marks `reason` as no longer in effect without resuming anything.
+    /// Torrents it held stay paused until the user resumes them.
+    pub fn abandon(&mut self, reason: Reason) {
+        self.active.remove(&reason);
+        self.held.retain(|_, reasons| !reasons.contains(&reason));
+    }
+
+    /// Holds a torrent started while `reason` is in effect, returning
+    /// whether it should be paused.
+    pub fn add(&mut self, tid: usize, reason: Reason) -> bool {
+        if !self.active(reason) {
+            return false;
+        }
+        self.held.entry(tid).or_default().insert(reason);
+        true
+    }
+
+    /// Stops holding a torrent, e.g. because the user explicitly
+    /// paused or resumed it.
+    pub fn forget(&mut self, tid: usize) {
+        self.held.remove(&tid);
+    }
+}
+
+/// Checks the current free space against the threshold, returning
+/// whether space should now be considered low if that changed. Space
+/// must rise 10% above the threshold before it no longer is, so that
+/// torrents aren't repeatedly paused and resumed around it. A
+/// threshold of 0 disables the check.
+pub fn check_space(low: bool, space: u64, threshold: u64) -> Option<bool> {
+    if threshold == 0 {
+        return if low { Some(false) } else { None };
+    }
+    if !low && space < threshold {
+        Some(true)
+    } else if low && space > threshold + threshold / 10 {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_space, Pauses, Reason};
+    use nix::libc;
+    use std::io;
+    use util::is_read_only;
+
+    fn sorted(mut v: Vec<usize>) -> Vec<usize> {
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn test_restore_paused() {
+        let mut p = Pauses::new();
+        // Torrent 2 was paused by the user beforehand
+        assert_eq!(
+            sorted(p.enter(Reason::Metered, vec![0, 1, 3])),
+            vec![0, 1, 3]
+        );
+        assert!(p.active(Reason::Metered));
+        assert!(p.enter(Reason::Metered, vec![4]).is_empty());
+
+        // A new torrent is held back, while the user
+        // explicitly pauses 1 and resumes 3
+        assert!(p.add(4, Reason::Metered));
+        assert!(!p.add(4, Reason::LowSpace));
+        p.forget(1);
+        p.forget(3);
+
+        assert_eq!(sorted(p.leave(Reason::Metered)), vec![0, 4]);
+        assert!(!p.active(Reason::Metered));
+        assert!(!p.add(5, Reason::Metered));
+        assert!(p.leave(Reason::Metered).is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_reasons() {
+        let mut p = Pauses::new();
+        assert_eq!(sorted(p.enter(Reason::Metered, vec![0, 1])), vec![0, 1]);
+
+        // Torrents already held are held for the new reason as well,
+        // only those running before are paused
+        assert_eq!(p.enter(Reason::LowSpace, vec![1, 2]), vec![2]);
+        assert!(p.add(3, Reason::Metered) && p.add(3, Reason::LowSpace));
+
+        // Nothing still held by low space is resumed
+        assert_eq!(p.leave(Reason::Metered), vec![0]);
+        assert!(p.held(1) && p.held(2) && p.held(3));
+        assert_eq!(sorted(p.leave(Reason::LowSpace)), vec![1, 2, 3]);
+        assert!(!p.held(1));
+
+        // Read-only failures stack the same way
+        assert!(!is_read_only(&io::Error::from_raw_os_error(libc::ENOSPC)));
+        assert!(is_read_only(&io::Error::from_raw_os_error(libc::EROFS)));
+        p.enter(Reason::ReadOnly, vec![0]);
+        p.enter(Reason::Metered, vec![0, 1]);
+        assert!(p.leave(Reason::ReadOnly).is_empty());
+        assert_eq!(sorted(p.leave(Reason::Metered)), vec![0, 1]);
+
+        // Abandoned torrents are left paused
+        p.enter(Reason::ReadOnly, vec![0, 1]);
+        p.enter(Reason::Metered, vec![1]);
+        p.abandon(Reason::ReadOnly);
+        assert!(!p.active(Reason::ReadOnly));
+        assert!(p.leave(Reason::Metered).is_empty());
+    }
+
+    #[test]
+    fn test_threshold_crossing() {
+        let threshold = 1000;
+        assert_eq!(check_space(false, 5000, threshold), None);
+
+        // Space drops below the threshold
+        assert_eq!(check_space(false, 900, threshold), Some(true));
+        assert_eq!(check_space(true, 900, threshold), None);
+
+        // Freeing a little space isn't enough to leave the low state
+        assert_eq!(check_space(true, 1050, threshold), None);
+        assert_eq!(check_space(true, 1200, threshold), Some(false));
+
+        // Disabling the check releases the pause
+        assert_eq!(check_space(true, 900, 0), Some(false));
+        assert_eq!(check_space(false, 900, 0), None);
+    }
+}
@@ -0,0 +1,72 @@
+/// Tracks changes to server state, so that serialization happens
+/// promptly after significant changes and is skipped when nothing
+/// has changed.
+pub struct Persist {
+    dirty: bool,
+    urgent: bool,
+}
+
+impl Persist {
+    pub fn new() -> Persist {
+        Persist {
+            dirty: false,
+            urgent: false,
+        }
+    }
+
+    /// Records a change which can wait for the next periodic
+    /// serialization.
+    pub fn changed(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Records a significant change, e.g. a torrent being added
+    /// or removed, which should be serialized right away.
+    pub fn changed_significantly(&mut self) {
+        self.dirty = true;
+        self.urgent = true;
+    }
+
+    pub fn urgent(&self) -> bool {
+        self.urgent
+    }
+
+    /// Returns whether state should be serialized now, where `periodic`
+    /// indicates that the session interval has elapsed.
+    pub fn due(&self, periodic: bool) -> bool {
+        self.urgent || (periodic && self.dirty)
+    }
+
+    pub fn serialized(&mut self) {
+        self.dirty = false;
+        self.urgent = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Persist;
+
+    #[test]
+    fn test_serialize_changes() {
+        let mut p = Persist::new();
+        // Nothing changed, so intervals shouldn't rewrite anything
+        assert!(!p.due(false));
+        assert!(!p.due(true));
+
+        // Minor changes wait for the interval
+        p.changed();
+        assert!(!p.due(false));
+        assert!(p.due(true));
+        p.serialized();
+        assert!(!p.due(true));
+
+        // Adding a torrent is written out on the next tick
+        p.changed_significantly();
+        assert!(p.urgent());
+        assert!(p.due(false));
+        p.serialized();
+        assert!(!p.due(false));
+        assert!(!p.due(true));
+    }
+}
@@ -0,0 +1,155 @@
+//! Handling of torrent session files which fail to deserialize. Rather
+//! than failing on every startup, such files are moved to the `corrupt`
+//! subdirectory of the session directory and the torrent is recovered
+//! from what else is known about it.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use bencode;
+use torrent::Info;
+use util::{hash_to_id, id_to_hash};
+
+/// Subdirectory of the session directory corrupt files are moved to
+const DIR: &str = "corrupt";
+
+/// Loads the torrent session files of `dir` with `load`, which is given
+/// the infohash named by the file and returns whether the data was valid
+/// for it. Invalid files are quarantined and their torrent IDs returned,
+/// so that loading the rest can proceed.
+pub fn load_dir<F>(dir: &Path, mut load: F) -> io::Result<Vec<String>>
+where
+    F: FnMut(&[u8; 20], &[u8]) -> bool,
+{
+    let mut corrupt = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Session files are named by the hex infohash of their torrent
+        let (id, hash) = match entry.file_name().into_string() {
+            Ok(id) => match id_to_hash(&id) {
+                Some(hash) => (id, hash),
+                None => continue,
+            },
+            Err(_) => continue,
+        };
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        trace!("Attempting to deserialize file {:?}", entry.path());
+        let mut data = Vec::new();
+        // A file we can't read may well be fine, so leave it be
+        if let Err(e) = fs::File::open(entry.path()).and_then(|mut f| f.read_to_end(&mut data)) {
+            error!("Failed to read session data of torrent {}: {}", id, e);
+            continue;
+        }
+        if load(&hash, &data) {
+            trace!("Succesfully parsed torrent file {:?}", entry.path());
+            continue;
+        }
+        match quarantine(dir, &id) {
+            Ok(path) => error!(
+                "Session data of torrent {} is corrupt or past version {}, moved it to {}",
+                id,
+                env!("CARGO_PKG_VERSION"),
+                path.display()
+            ),
+            Err(e) => error!(
+                "Session data of torrent {} is corrupt, failed to move it: {}",
+                id, e
+            ),
+        }
+        corrupt.push(id);
+    }
+    Ok(corrupt)
+}
+
+/// Moves the session file of torrent `id` into the corrupt directory,
+/// returning its new path.
+fn quarantine(dir: &Path, id: &str) -> io::Result<PathBuf> {
+    let qdir = dir.join(DIR);
+    fs::create_dir_all(&qdir)?;
+    let path = qdir.join(id);
+    fs::rename(dir.join(id), &path)?;
+    Ok(path)
+}
+
+/// Returns the metainfo of torrent `id` from the .torrent written
+/// alongside its session file, falling back to a magnet of its hash.
+pub fn recover(dir: &Path, id: &str) -> Option<Info> {
+    let mut path = dir.join(id);
+    path.set_extension("torrent");
+    let mut data = Vec::new();
+    let sidecar = fs::File::open(&path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .ok()
+        .and_then(|_| bencode::decode_buf(&data).ok())
+        .and_then(|b| Info::from_bencode(b).ok());
+    if let Some(info) = sidecar {
+        if hash_to_id(&info.hash) == id {
+            return Some(info);
+        }
+    }
+    Info::from_magnet(&format!("magnet:?xt=urn:btih:{}", id)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_dir, recover};
+    use bencode;
+    use std::{env, fs, process};
+    use torrent::Info;
+    use util::{hash_to_id, id_to_hash};
+
+    const TORRENT: &[u8] =
+        b"d4:infod6:lengthi16384e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+
+    #[test]
+    fn test_quarantine() {
+        let dir = env::temp_dir().join(format!("synapse-quarantine-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let hash = Info::from_bencode(bencode::decode_buf(TORRENT).unwrap())
+            .unwrap()
+            .hash;
+        let (good, bad, magnet) = ("0".repeat(40), hash_to_id(&hash), "1".repeat(40));
+        fs::write(dir.join(&good), b"valid").unwrap();
+        fs::write(dir.join(&bad), b"garbage").unwrap();
+        fs::write(dir.join(&magnet), b"garbage").unwrap();
+        fs::write(dir.join(format!("{}.torrent", bad)), TORRENT).unwrap();
+        // Not named by an infohash, despite the length
+        fs::write(dir.join("g".repeat(40)), b"garbage").unwrap();
+
+        let mut loaded = Vec::new();
+        let corrupt = load_dir(&dir, |hash, data| {
+            loaded.push(*hash);
+            data == b"valid"
+        })
+        .unwrap();
+
+        // Every file was tried despite the corrupt ones
+        assert_eq!(loaded.len(), 3);
+        assert!(loaded.contains(&id_to_hash(&good).unwrap()));
+        assert!(loaded.contains(&hash));
+        assert!(dir.join("g".repeat(40)).exists());
+        assert_eq!(corrupt.len(), 2);
+        assert!(corrupt.contains(&bad) && corrupt.contains(&magnet));
+        assert!(dir.join(&good).exists());
+        assert!(!dir.join(&bad).exists());
+        assert!(dir.join("corrupt").join(&bad).exists());
+        assert!(dir.join("corrupt").join(&magnet).exists());
+
+        // Nothing is left to fail on the next startup
+        assert!(load_dir(&dir, |_, data| data == b"valid")
+            .unwrap()
+            .is_empty());
+
+        let info = recover(&dir, &bad).unwrap();
+        assert!(info.complete());
+        assert_eq!(info.hash, hash);
+        let info = recover(&dir, &magnet).unwrap();
+        assert!(!info.complete());
+        assert_eq!(hash_to_id(&info.hash), magnet);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
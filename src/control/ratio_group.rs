@@ -0,0 +1,137 @@
+use std::cmp;
+
+/// Factor applied to the demand of underseeded members when
+/// boosting them
+const UNDERSEEDED_BOOST: f64 = 4.;
+
+/// A seeding torrent announcing to a tracker group, along with the
+/// swarm size last reported by the tracker.
+pub struct Member {
+    pub tid: usize,
+    pub seeders: u32,
+    pub leechers: u32,
+    /// Established connections to peers being uploaded to
+    pub warm: u32,
+    /// Whether few other peers seed the torrent
+    pub underseeded: bool,
+}
+
+impl Member {
+    /// Leechers competing for each seeder, uploading where this is
+    /// highest gains the most ratio. With `boost`, the demand of
+    /// underseeded members is scaled up so the swarms which depend on
+    /// us most take slots from well seeded ones.
+    fn demand(&self, boost: bool) -> f64 {
+        let demand = f64::from(self.leechers) / f64::from(self.seeders + 1);
+        if boost && self.underseeded {
+            demand * UNDERSEEDED_BOOST
+        } else {
+            demand
+        }
+    }
+}
+
+/// Splits `slots` upload slots between the members of a tracker group
+/// in proportion to their demand, returning the slots of each member.
+/// Every member keeps at least one slot. With `prefer_warm`, members
+/// with more warm connections win ties for the remaining slots, and
+/// with `boost` underseeded members are favoured.
+pub fn allocate(members: &[Member], slots: usize, prefer_warm: bool, boost: bool) -> Vec<usize> {
+    let mut alloc = vec![1; members.len()];
+    if slots <= members.len() {
+        return alloc;
+    }
+    let spare = slots - members.len();
+    let total: f64 = members.iter().map(|m| m.demand(boost)).sum();
+    let shares: Vec<f64> = members
+        .iter()
+        .map(|m| {
+            if total > 0. {
+                m.demand(boost) / total * spare as f64
+            } else {
+                spare as f64 / members.len() as f64
+            }
+        })
+        .collect();
+
+    let mut given = 0;
+    for (a, share) in alloc.iter_mut().zip(shares.iter()) {
+        *a += share.floor() as usize;
+        given += share.floor() as usize;
+    }
+    // Hand out what's left by largest remainder
+    // Remainders are rounded so that equal shares compare as ties
+    let mut rem: Vec<_> = (0..members.len()).collect();
+    rem.sort_by_key(|&i| {
+        let r = ((shares[i] - shares[i].floor()) * 1e6).round() as i64;
+        let warm = if prefer_warm { members[i].warm } else { 0 };
+        (-r, cmp::Reverse(warm))
+    });
+    for idx in rem.into_iter().take(spare.saturating_sub(given)) {
+        alloc[idx] += 1;
+    }
+    alloc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allocate, Member};
+
+    fn member(tid: usize, seeders: u32, leechers: u32) -> Member {
+        Member {
+            tid,
+            seeders,
+            leechers,
+            warm: 0,
+            underseeded: false,
+        }
+    }
+
+    #[test]
+    fn test_skew_to_demand() {
+        // 90 leechers on 9 seeders vs 10 leechers on 9 seeders
+        let group = vec![member(0, 9, 90), member(1, 9, 10)];
+        let alloc = allocate(&group, 12, false, false);
+        assert_eq!(alloc.iter().sum::<usize>(), 12);
+        assert_eq!(alloc, vec![10, 2]);
+
+        // Without any demand slots are split evenly
+        let idle = vec![member(0, 5, 0), member(1, 5, 0), member(2, 5, 0)];
+        assert_eq!(allocate(&idle, 9, false, false), vec![3, 3, 3]);
+
+        // Every member keeps a slot even when there are too few
+        assert_eq!(allocate(&group, 1, false, false), vec![1, 1]);
+    }
+
+    #[test]
+    fn test_warm_tiebreak() {
+        let mut group = vec![member(0, 5, 10), member(1, 5, 10), member(2, 5, 10)];
+        group[2].warm = 4;
+        // Without the bias ties go to the first members
+        assert_eq!(allocate(&group, 5, false, false), vec![2, 2, 1]);
+        // The warm member wins the tie
+        assert_eq!(allocate(&group, 5, true, false), vec![2, 1, 2]);
+
+        // But doesn't outweigh demand
+        let mut group = vec![member(0, 1, 20), member(1, 5, 10)];
+        group[1].warm = 10;
+        assert_eq!(
+            allocate(&group, 5, true, false),
+            allocate(&group, 5, false, false)
+        );
+    }
+
+    #[test]
+    fn test_underseeded_boost() {
+        // Equal demand, but only one other peer seeds the first torrent
+        let mut group = vec![member(0, 2, 6), member(1, 2, 6)];
+        group[0].underseeded = true;
+        assert_eq!(allocate(&group, 10, false, false), vec![5, 5]);
+        let alloc = allocate(&group, 10, false, true);
+        assert_eq!(alloc.iter().sum::<usize>(), 10);
+        assert_eq!(alloc, vec![7, 3]);
+
+        // Without contention every member keeps a slot
+        assert_eq!(allocate(&group, 2, false, true), vec![1, 1]);
+    }
+}
@@ -0,0 +1,87 @@
+use std::cmp::Reverse;
+use std::time;
+
+/// Peers a torrent always keeps, even when of low priority
+const MIN_PEERS: usize = 2;
+
+/// Decides which torrent gives up a connection when a torrent of higher
+/// priority needs one at the connection limit. Reclaims are rate limited
+/// so that connections aren't churned between torrents.
+pub struct Reclaim {
+    enabled: bool,
+    interval: time::Duration,
+    last: Option<time::Instant>,
+    reclaimed: u64,
+}
+
+impl Reclaim {
+    pub fn new(enabled: bool, interval: time::Duration) -> Reclaim {
+        Reclaim {
+            enabled,
+            interval,
+            last: None,
+            reclaimed: 0,
+        }
+    }
+
+    /// Number of connections reclaimed so far
+    pub fn reclaimed(&self) -> u64 {
+        self.reclaimed
+    }
+
+    /// Picks the torrent to drop a peer from so that a torrent of
+    /// `priority` can connect to one, out of the (id, priority, peers)
+    /// of the other torrents. The lowest priority torrent with the most
+    /// peers is picked, and the reclaim is counted.
+    pub fn victim<I>(&mut self, priority: u8, torrents: I, now: time::Instant) -> Option<usize>
+    where
+        I: IntoIterator<Item = (usize, u8, usize)>,
+    {
+        if !self.enabled {
+            return None;
+        }
+        if let Some(last) = self.last {
+            if now.duration_since(last) < self.interval {
+                return None;
+            }
+        }
+        let victim = torrents
+            .into_iter()
+            .filter(|&(_, p, peers)| p < priority && peers > MIN_PEERS)
+            .min_by_key(|&(tid, p, peers)| (p, Reverse(peers), tid))
+            .map(|(tid, _, _)| tid)?;
+        self.last = Some(now);
+        self.reclaimed += 1;
+        Some(victim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reclaim;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_reclaim_at_cap() {
+        let now = Instant::now();
+        let torrents = vec![(0, 1, 10), (1, 3, 20), (2, 5, 20)];
+        let mut r = Reclaim::new(true, Duration::from_secs(30));
+
+        // The high priority torrent takes a slot from the low priority one
+        assert_eq!(r.victim(5, torrents.clone(), now), Some(0));
+        assert_eq!(r.reclaimed(), 1);
+
+        // Not again until the interval passed
+        assert_eq!(r.victim(5, torrents.clone(), now), None);
+        let later = now + Duration::from_secs(30);
+        // Torrents of equal or higher priority and those down to
+        // their last peers are left alone
+        assert_eq!(r.victim(1, torrents.clone(), later), None);
+        assert_eq!(r.victim(3, vec![(0, 1, 2), (1, 3, 20)], later), None);
+        assert_eq!(r.victim(4, torrents.clone(), later), Some(0));
+        assert_eq!(r.reclaimed(), 2);
+
+        let mut r = Reclaim::new(false, Duration::from_secs(0));
+        assert_eq!(r.victim(5, torrents, now), None);
+    }
+}
@@ -1,14 +1,18 @@
 use std::ffi::OsString;
-use std::{fs, io, mem, path};
+use std::time::Duration;
+use std::{cmp, fs, io, mem, path};
 
 use std::os::unix::fs::MetadataExt;
 
 use std::io::{Read, Seek, SeekFrom, Write};
 
+use super::coalesce::{CacheStats, WriteCache};
 use util::{native, MHashMap};
 use CONFIG;
 
 const PB_LEN: usize = 256;
+/// Bytes of zeros written at a time when preallocating files
+const ZERO_CHUNK: usize = 1_048_576;
 
 pub struct BufCache {
     path_a: OsString,
@@ -18,12 +22,19 @@ pub struct BufCache {
 
 pub struct FileCache {
     files: MHashMap<path::PathBuf, Entry>,
+    writes: WriteCache,
+    /// Buffered writes which failed, by torrent
+    errors: Vec<(usize, io::Error)>,
+    read: u64,
+    written: u64,
 }
 
 pub struct Entry {
     used: bool,
     alloc_failed: bool,
     sparse: bool,
+    /// Written to since it was last flushed
+    dirty: bool,
     file: fs::File,
 }
 
@@ -98,6 +109,13 @@ impl FileCache {
     pub fn new() -> FileCache {
         FileCache {
             files: MHashMap::default(),
+            writes: WriteCache::new(
+                CONFIG.disk.write_cache_mb * 1_000_000,
+                Duration::from_secs(CONFIG.disk.write_cache_secs),
+            ),
+            errors: Vec::new(),
+            read: 0,
+            written: 0,
         }
     }
 
@@ -107,14 +125,44 @@ impl FileCache {
         offset: u64,
         buf: &mut [u8],
     ) -> io::Result<()> {
+        if self.writes.read(path, offset, buf) {
+            return Ok(());
+        }
+        if self.writes.overlaps(path, offset, buf.len() as u64) {
+            self.write_back(path);
+        }
         self.ensure_exists(path, Err(0))?;
         let entry = self.files.get_mut(path).unwrap();
         entry.file.seek(SeekFrom::Start(offset))?;
         entry.file.read_exact(buf)?;
+        self.read += buf.len() as u64;
         Ok(())
     }
 
+    /// Writes data to a file, buffering it first if the write cache
+    /// is enabled.
     pub fn write_file_range(
+        &mut self,
+        tid: usize,
+        path: &path::Path,
+        size: Result<u64, u64>,
+        offset: u64,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        if !self.writes.enabled() {
+            return self.write_direct(path, size, offset, buf);
+        }
+        self.writes.insert(tid, path, size, offset, buf);
+        while self.writes.full() {
+            match self.writes.largest() {
+                Some(f) => self.write_back(&f),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_direct(
         &mut self,
         path: &path::Path,
         size: Result<u64, u64>,
@@ -125,15 +173,110 @@ impl FileCache {
         let entry = self.files.get_mut(path).unwrap();
         entry.file.seek(SeekFrom::Start(offset))?;
         entry.file.write_all(&buf)?;
+        entry.dirty = true;
+        self.written += buf.len() as u64;
         Ok(())
     }
 
+    /// Reserves space for the full length of a file. Where the
+    /// filesystem can't allocate space, zeros are written past the end
+    /// of the file a chunk at a time, so data already written is never
+    /// overwritten. Returns whether the file is fully allocated, being
+    /// called again until it is.
+    pub fn preallocate(&mut self, path: &path::Path, len: u64) -> io::Result<bool> {
+        self.ensure_exists(path, Err(0))?;
+        let entry = self.files.get_mut(path).unwrap();
+        let cur = entry.file.metadata()?.len();
+        if cur > len {
+            return Ok(true);
+        }
+        if !entry.alloc_failed {
+            if native::fallocate(&entry.file, len)? {
+                entry.sparse = false;
+                return Ok(true);
+            }
+            // The fallback extended the file without allocating it
+            entry.file.set_len(cur)?;
+            entry.alloc_failed = true;
+        }
+        if cur == len {
+            return Ok(true);
+        }
+        let chunk = cmp::min(len - cur, ZERO_CHUNK as u64) as usize;
+        entry.file.seek(SeekFrom::Start(cur))?;
+        entry.file.write_all(&vec![0u8; chunk])?;
+        entry.dirty = true;
+        Ok(cur + chunk as u64 >= len)
+    }
+
+    /// Writes a file's buffered data to disk. Failures are reported
+    /// through `take_errors`, as the write they belong to has already
+    /// completed.
+    pub fn write_back(&mut self, path: &path::Path) {
+        let pending = match self.writes.take(path) {
+            Some(p) => p,
+            None => return,
+        };
+        for (offset, run) in pending.runs {
+            if let Err(e) = self.write_direct(path, pending.size, offset, &run) {
+                error!("Failed to write buffered data to {:?}: {}", path, e);
+                self.errors.push((pending.tid, e));
+                break;
+            }
+        }
+    }
+
+    pub fn write_back_all(&mut self) {
+        for path in self.writes.paths() {
+            self.write_back(&path);
+        }
+    }
+
+    /// Writes buffered data which has waited long enough.
+    pub fn write_back_expired(&mut self) {
+        for path in self.writes.expired() {
+            self.write_back(&path);
+        }
+    }
+
+    /// Drops the buffered data of a file about to be deleted.
+    pub fn discard_writes(&mut self, path: &path::Path) {
+        self.writes.discard(path);
+    }
+
+    pub fn take_errors(&mut self) -> Vec<(usize, io::Error)> {
+        mem::replace(&mut self.errors, Vec::new())
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            read: self.read,
+            written: self.written,
+            ..self.writes.stats()
+        }
+    }
+
     pub fn remove_file(&mut self, path: &path::Path) {
+        self.write_back(path);
         self.files.remove(path);
     }
 
     pub fn flush_file(&mut self, path: &path::Path) {
-        self.files.get_mut(path).map(|e| e.file.sync_all().ok());
+        self.write_back(path);
+        if let Some(e) = self.files.get_mut(path) {
+            if e.dirty {
+                e.file.sync_all().ok();
+                e.dirty = false;
+            }
+        }
+    }
+
+    pub fn flush_all(&mut self) {
+        self.write_back_all();
+        for e in self.files.values_mut().filter(|e| e.dirty) {
+            e.file.sync_all().ok();
+            e.dirty = false;
+        }
     }
 
     fn ensure_exists(&mut self, path: &path::Path, len: Result<u64, u64>) -> io::Result<()> {
@@ -155,6 +298,9 @@ impl FileCache {
                     }
                 }
                 if let Some(f) = removal {
+                    // Unflushed writes mustn't be lost track of when the
+                    // handle is closed
+                    self.flush_file(&f);
                     self.remove_file(&f);
                 }
             }
@@ -187,6 +333,7 @@ impl FileCache {
                     used: true,
                     sparse,
                     alloc_failed,
+                    dirty: false,
                 },
             );
         } else if len.is_ok() {
@@ -206,6 +353,7 @@ impl FileCache {
 
 impl Drop for FileCache {
     fn drop(&mut self) {
+        self.write_back_all();
         for (_, entry) in self.files.drain() {
             entry.file.sync_all().ok();
         }
@@ -215,6 +363,64 @@ impl Drop for FileCache {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn test_buffered_writes() {
+        let dir = env::temp_dir().join(format!("synapse-wcache-{}", process::id()));
+        let path = dir.join("data");
+        let mut fc = FileCache::new();
+        fc.write_file_range(0, &path, Err(64), 16, &[1; 16])
+            .unwrap();
+        fc.write_file_range(0, &path, Err(64), 32, &[2; 16])
+            .unwrap();
+
+        // Reads of buffered blocks don't touch the disk
+        let mut buf = [0; 24];
+        fc.read_file_range(&path, 20, &mut buf).unwrap();
+        assert_eq!(&buf[..12], &[1; 12]);
+        assert_eq!(&buf[12..], &[2; 12]);
+        assert!(!path.exists());
+        assert_eq!(fc.cache_stats().hits, 1);
+        assert_eq!((fc.cache_stats().read, fc.cache_stats().written), (0, 0));
+
+        // Partially buffered reads see the data once it's written
+        let mut buf = [9; 32];
+        fc.read_file_range(&path, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..16], &[0; 16]);
+        assert_eq!(&buf[16..], &[1; 16]);
+        assert_eq!(fc.cache_stats().bytes, 0);
+        assert_eq!((fc.cache_stats().read, fc.cache_stats().written), (32, 32));
+
+        fc.write_file_range(0, &path, Err(64), 48, &[3; 16])
+            .unwrap();
+        fc.flush_all();
+        let data = fs::read(&path).unwrap();
+        assert_eq!(&data[32..48], &[2; 16]);
+        assert_eq!(&data[48..], &[3; 16]);
+        assert!(fc.take_errors().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let dir = env::temp_dir().join(format!("synapse-prealloc-{}", process::id()));
+        let path = dir.join("data");
+        let mut fc = FileCache::new();
+        fc.write_file_range(0, &path, Err(0), 0, &[1; 16]).unwrap();
+        fc.flush_all();
+        while !fc.preallocate(&path, ZERO_CHUNK as u64 + 16).unwrap() {}
+        fc.flush_all();
+
+        // Data already written is kept
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), ZERO_CHUNK + 16);
+        assert_eq!(&data[..16], &[1; 16]);
+        assert!(data[16..].iter().all(|&b| b == 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
     #[test]
     fn test_tempbuf() {
@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use util::MHashMap;
+
+/// Downloaded data buffered in memory so that adjacent blocks can be
+/// written to disk together as larger, sequential writes.
+pub struct WriteCache {
+    files: MHashMap<PathBuf, Pending>,
+    /// Bytes buffered across all files
+    bytes: u64,
+    limit: u64,
+    delay: Duration,
+    hits: u64,
+    misses: u64,
+}
+
+/// Buffered data of a single file
+pub struct Pending {
+    /// Torrent the data belongs to, which failed writes are reported to
+    pub tid: usize,
+    /// Size of the file, Ok if it should be allocated
+    pub size: Result<u64, u64>,
+    /// Disjoint, non adjacent runs of data by their offset
+    pub runs: BTreeMap<u64, Vec<u8>>,
+    bytes: u64,
+    since: Instant,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// Reads served entirely from buffered data
+    pub hits: u64,
+    pub misses: u64,
+    /// Bytes currently buffered
+    pub bytes: u64,
+    /// Bytes read from and written to files, past the cache
+    pub read: u64,
+    pub written: u64,
+}
+
+impl WriteCache {
+    pub fn new(limit: u64, delay: Duration) -> WriteCache {
+        WriteCache {
+            files: MHashMap::default(),
+            bytes: 0,
+            limit,
+            delay,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Whether writes are buffered at all
+    pub fn enabled(&self) -> bool {
+        self.limit != 0
+    }
+
+    pub fn full(&self) -> bool {
+        self.bytes > self.limit
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            bytes: self.bytes,
+            read: 0,
+            written: 0,
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        tid: usize,
+        path: &Path,
+        size: Result<u64, u64>,
+        offset: u64,
+        data: &[u8],
+    ) {
+        let pending = self
+            .files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Pending {
+                tid,
+                size,
+                runs: BTreeMap::new(),
+                bytes: 0,
+                since: Instant::now(),
+            });
+        pending.tid = tid;
+        pending.size = size;
+        self.bytes -= pending.bytes;
+        pending.write(offset, data);
+        self.bytes += pending.bytes;
+    }
+
+    /// Fills `buf` from buffered data if it's entirely buffered.
+    /// Returns whether it was, otherwise the file's buffered data
+    /// should be written before reading it from disk.
+    pub fn read(&mut self, path: &Path, offset: u64, buf: &mut [u8]) -> bool {
+        let end = offset + buf.len() as u64;
+        let hit = match self
+            .files
+            .get(path)
+            .and_then(|p| p.runs.range(..=offset).next_back())
+        {
+            Some((&start, run)) if start + run.len() as u64 >= end => {
+                let from = (offset - start) as usize;
+                buf.copy_from_slice(&run[from..from + buf.len()]);
+                true
+            }
+            _ => false,
+        };
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        hit
+    }
+
+    /// Whether any buffered data of the file overlaps the range
+    pub fn overlaps(&self, path: &Path, offset: u64, len: u64) -> bool {
+        match self.files.get(path) {
+            Some(p) => p
+                .runs
+                .range(..offset + len)
+                .next_back()
+                .map(|(&start, run)| start + run.len() as u64 > offset)
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Takes the buffered data of a file to be written.
+    pub fn take(&mut self, path: &Path) -> Option<Pending> {
+        let pending = self.files.remove(path)?;
+        self.bytes -= pending.bytes;
+        Some(pending)
+    }
+
+    /// Drops the buffered data of a file which is being deleted.
+    pub fn discard(&mut self, path: &Path) {
+        self.take(path);
+    }
+
+    /// The file with the most data buffered, to be written when the
+    /// cache is full.
+    pub fn largest(&self) -> Option<PathBuf> {
+        self.files
+            .iter()
+            .max_by_key(|&(_, p)| p.bytes)
+            .map(|(path, _)| path.clone())
+    }
+
+    /// Files whose oldest buffered data has waited for the delay.
+    pub fn expired(&self) -> Vec<PathBuf> {
+        self.files
+            .iter()
+            .filter(|&(_, p)| p.since.elapsed() >= self.delay)
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.keys().cloned().collect()
+    }
+}
+
+impl Pending {
+    /// Buffers data, merging it with the runs it overlaps or adjoins.
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        let merged: Vec<u64> = self
+            .runs
+            .range(..=end)
+            .rev()
+            .take_while(|&(&start, run)| start + run.len() as u64 >= offset)
+            .map(|(&start, _)| start)
+            .collect();
+        // Blocks arriving in order extend the run before them in place
+        let (start, mut buf) = match merged.last() {
+            Some(&s) if s <= offset => (s, self.runs.remove(&s).unwrap()),
+            _ => (offset, Vec::new()),
+        };
+        self.bytes -= buf.len() as u64;
+        let mut runs = Vec::new();
+        for s in merged {
+            if let Some(run) = self.runs.remove(&s) {
+                self.bytes -= run.len() as u64;
+                runs.push((s, run));
+            }
+        }
+        let run_end = runs
+            .iter()
+            .map(|&(s, ref run)| s + run.len() as u64)
+            .chain(Some(start + buf.len() as u64))
+            .max()
+            .unwrap();
+        let len = (run_end.max(end) - start) as usize;
+        buf.resize(len, 0);
+        for (s, run) in runs {
+            let from = (s - start) as usize;
+            buf[from..from + run.len()].copy_from_slice(&run);
+        }
+        let from = (offset - start) as usize;
+        buf[from..from + data.len()].copy_from_slice(data);
+        self.bytes += buf.len() as u64;
+        self.runs.insert(start, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WriteCache;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn test_coalesce() {
+        let mut cache = WriteCache::new(1_000, Duration::from_secs(60));
+        let path = Path::new("a");
+        cache.insert(0, path, Err(100), 10, &[1; 10]);
+        cache.insert(0, path, Err(100), 20, &[2; 10]);
+        cache.insert(0, path, Err(100), 0, &[3; 5]);
+        cache.insert(0, path, Err(100), 40, &[4; 10]);
+        assert_eq!(cache.stats().bytes, 35);
+
+        // Adjacent blocks are merged into a single run
+        let mut buf = [0; 20];
+        assert!(cache.read(path, 10, &mut buf));
+        assert_eq!(&buf[..10], &[1; 10]);
+        assert_eq!(&buf[10..], &[2; 10]);
+        assert!(!cache.read(path, 25, &mut buf));
+        assert!(cache.overlaps(path, 25, 20));
+        assert!(!cache.overlaps(path, 30, 10));
+        assert!(!cache.read(Path::new("b"), 0, &mut buf));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+
+        // Filling a gap joins the runs around it, newer data winning
+        cache.insert(0, path, Err(100), 4, &[5; 40]);
+        assert_eq!(cache.stats().bytes, 50);
+        assert!(!cache.full());
+        let pending = cache.take(path).unwrap();
+        assert_eq!(pending.runs.len(), 1);
+        let run = &pending.runs[&0];
+        assert_eq!(&run[..4], &[3; 4]);
+        assert_eq!(&run[4..44], &[5; 40][..]);
+        assert_eq!(&run[44..], &[4; 6]);
+        assert_eq!(cache.stats().bytes, 0);
+        assert!(cache.take(path).is_none());
+    }
+}
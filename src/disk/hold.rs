@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+
+use super::Request;
+
+/// Jobs held back while disk writes are quiesced. Anything which
+/// modifies data on disk, or depends on such data being written, is
+/// held in order until writes are resumed.
+pub struct Hold {
+    quiesced: bool,
+    held: VecDeque<Request>,
+    /// Bytes of downloaded data being held
+    bytes: u64,
+    limit: u64,
+}
+
+impl Hold {
+    pub fn new(limit: u64) -> Hold {
+        Hold {
+            quiesced: false,
+            held: VecDeque::new(),
+            bytes: 0,
+            limit,
+        }
+    }
+
+    pub fn quiesced(&self) -> bool {
+        self.quiesced
+    }
+
+    /// Whether held data has reached the buffer limit. Nothing is
+    /// dropped past it, but no more data should be requested.
+    pub fn full(&self) -> bool {
+        self.quiesced && self.bytes >= self.limit
+    }
+
+    /// Holds `req` if writes are quiesced and it must wait for them,
+    /// returning it otherwise.
+    pub fn hold(&mut self, req: Request) -> Option<Request> {
+        if !self.quiesced || !held(&req) {
+            return Some(req);
+        }
+        if let Request::Write { ref data, .. } = req {
+            self.bytes += data.len() as u64;
+        }
+        self.held.push_back(req);
+        None
+    }
+
+    /// Quiesces or resumes writes, returning the held jobs in the
+    /// order they were received if resuming.
+    pub fn set_quiesced(&mut self, quiesced: bool) -> Vec<Request> {
+        self.quiesced = quiesced;
+        if quiesced {
+            return Vec::new();
+        }
+        self.bytes = 0;
+        self.held.drain(..).collect()
+    }
+}
+
+fn held(req: &Request) -> bool {
+    match *req {
+        Request::Write { .. }
+        | Request::Serialize { .. }
+        | Request::WriteFile { .. }
+        | Request::Allocate { .. }
+        | Request::Delete { .. }
+        | Request::Move { .. }
+        | Request::Rename { .. }
+        | Request::Flush { .. }
+        | Request::Checkpoint
+        | Request::Validate { .. }
+        | Request::ValidatePiece { .. }
+        | Request::CheckPieces { .. } => true,
+        Request::Read { .. }
+        | Request::Create { .. }
+        | Request::Download { .. }
+        | Request::FreeSpace
+        | Request::CacheStats
+        | Request::PieceReady { .. }
+        | Request::Ping
+        | Request::Quiesce(_)
+        | Request::Shutdown => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hold;
+    use buffers::Buffer;
+    use disk::{Ctx, Request};
+    use std::sync::Arc;
+    use torrent::Info;
+
+    #[test]
+    fn test_quiesce() {
+        let mut info = Info::with_pieces(4);
+        info.piece_idx = Info::generate_piece_idx(4, u64::from(info.piece_len), &info.files);
+        let info = Arc::new(info);
+        let write = |piece: u32| {
+            let mut data = Buffer::get().unwrap();
+            for b in data.iter_mut() {
+                *b = piece as u8 + 1;
+            }
+            let locs = Info::block_disk_locs(&info, piece, 0);
+            Request::write(0, data, locs, None)
+        };
+
+        let mut hold = Hold::new(3 * 16_384);
+        assert!(hold.hold(write(0)).is_some());
+
+        hold.set_quiesced(true);
+        assert!(hold.quiesced());
+        for piece in 0..3 {
+            assert!(hold.hold(write(piece)).is_none());
+        }
+        assert!(hold
+            .hold(Request::ValidatePiece {
+                tid: 0,
+                info: info.clone(),
+                path: None,
+                piece: 2,
+            })
+            .is_none());
+        assert!(hold.full());
+
+        // Reads of data already on disk carry on
+        let ctx = Ctx::new(0, 0, 3, 0, 16_384);
+        let read = Request::read(
+            ctx,
+            Buffer::get().unwrap(),
+            Info::block_disk_locs(&info, 3, 0),
+            None,
+        );
+        assert!(hold.hold(read).is_some());
+
+        // Everything is released as it was received
+        let held = hold.set_quiesced(false);
+        assert!(!hold.quiesced() && !hold.full());
+        assert_eq!(held.len(), 4);
+        for (piece, req) in held[..3].iter().enumerate() {
+            match *req {
+                Request::Write { ref data, .. } => {
+                    assert!(data.iter().all(|b| *b == piece as u8 + 1));
+                }
+                _ => panic!("expected write"),
+            }
+        }
+        match held[3] {
+            Request::ValidatePiece { piece: 2, .. } => {}
+            _ => panic!("expected validation"),
+        }
+        assert!(hold.hold(write(3)).is_some());
+    }
+}
@@ -1,23 +1,29 @@
-use std::io::{self, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, fs, path, time};
 
 use amy;
-use fs_extra;
+use bincode;
 use http_range::HttpRange;
 use nix::libc;
 use nix::sys::statvfs;
 use openssl::sha;
 
-use super::{BufCache, FileCache, JOB_TIME_SLICE};
+use super::{BufCache, CacheStats, FileCache, JOB_TIME_SLICE};
 use buffers::Buffer;
+use session::torrent::current::{FileStat, Session};
 use socket::TSocket;
-use torrent::{Info, LocIter};
+use torrent::{file_stat, Bitfield, Info, LocIter};
 use util::{awrite, hash_to_id, io_err, IOR};
 use CONFIG;
 
 static MP_BOUNDARY: &str = "qxyllcqgNchqyob";
+/// Extension of files being written before they're renamed into place
+const TEMP_EXT: &str = "temp";
+/// Size of the chunks copied files are compared in
+const COMPARE_CHUNK: usize = 65_536;
 
 pub struct Location {
     /// Info file index
@@ -49,8 +55,11 @@ pub enum Request {
     },
     Serialize {
         tid: usize,
-        data: Vec<u8>,
+        session: Box<Session>,
         hash: [u8; 20],
+        path: Option<String>,
+        /// Files of the torrent, whose stats are recorded in the session
+        files: Vec<PathBuf>,
     },
     Delete {
         tid: usize,
@@ -63,7 +72,19 @@ pub enum Request {
         tid: usize,
         from: String,
         to: String,
-        target: String,
+        /// Files of the torrent and their lengths
+        files: Vec<(PathBuf, u64)>,
+        idx: usize,
+        /// Files which were copied rather than renamed, whose originals
+        /// are removed once every file has been moved
+        copied: Vec<usize>,
+    },
+    Rename {
+        tid: usize,
+        path: Option<String>,
+        from: PathBuf,
+        to: PathBuf,
+        files: Vec<PathBuf>,
     },
     Validate {
         tid: usize,
@@ -71,6 +92,8 @@ pub enum Request {
         path: Option<String>,
         idx: u32,
         invalid: Vec<u32>,
+        /// Hash at a limited rate, yielding to other work
+        low_impact: bool,
     },
     ValidatePiece {
         tid: usize,
@@ -78,10 +101,37 @@ pub enum Request {
         path: Option<String>,
         piece: u32,
     },
+    CheckPieces {
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        pieces: Vec<u32>,
+    },
+    /// Reserves space for the given files and their lengths
+    Allocate {
+        tid: usize,
+        path: Option<String>,
+        files: Vec<(PathBuf, u64)>,
+        idx: usize,
+    },
     WriteFile {
         data: Vec<u8>,
         path: PathBuf,
     },
+    /// Hashes the pieces of a torrent being created from the files in `dir`
+    Create {
+        info: Arc<Info>,
+        dir: PathBuf,
+        hashes: Vec<Vec<u8>>,
+        /// The file being read and its index
+        file: Option<(usize, fs::File)>,
+        client: usize,
+        serial: u64,
+    },
+    Flush {
+        path: Option<String>,
+        files: Vec<PathBuf>,
+    },
     Download {
         client: TSocket,
         path: String,
@@ -94,9 +144,22 @@ pub enum Request {
         buf_max: usize,
         buf: Box<[u8; 16_384]>,
         file_len: u64,
+        stream: Option<Stream>,
+    },
+    /// Lets streams of a torrent serve a piece which was downloaded
+    PieceReady {
+        tid: usize,
+        piece: u32,
     },
     FreeSpace,
+    /// Reports the write cache's statistics
+    CacheStats,
     Ping,
+    /// Fsyncs every file written to, answered once the jobs
+    /// received before it are done
+    Checkpoint,
+    /// Holds back or resumes writes
+    Quiesce(bool),
     Shutdown,
 }
 
@@ -105,11 +168,73 @@ pub enum Response {
     ValidationComplete { tid: usize, invalid: Vec<u32> },
     PieceValidated { tid: usize, piece: u32, valid: bool },
     ValidationUpdate { tid: usize, percent: f32 },
+    PiecesChecked { tid: usize, invalid: Vec<u32> },
     Moved { tid: usize, path: String },
-    FreeSpace(u64),
+    MoveUpdate { tid: usize, percent: f32 },
+    MoveFailed { tid: usize, err: MoveError },
+    RenameFailed { tid: usize, err: RenameError },
+    FreeSpace { space: u64, read_only: bool },
+    CacheStats(CacheStats),
+    BufferFull(bool),
+    Checkpoint,
+    Serialized { tid: usize, stats: FileStats },
+    StreamDone { tid: usize, stream: usize },
+    CreateUpdate { req: Requester, percent: f32 },
+    Created { req: Requester, info: Box<Info> },
+    CreateFailed { req: Requester, err: io::Error },
     Error { tid: usize, err: io::Error },
 }
 
+/// Client and serial of the RPC request which a torrent is created for
+pub type Requester = (usize, u64);
+
+/// A download of a file whose torrent is still downloading, which only
+/// serves the pieces it has and waits for the others.
+pub struct Stream {
+    pub tid: usize,
+    /// Identifies the stream to its torrent
+    pub id: usize,
+    /// Offset of the file within the torrent
+    pub offset: u64,
+    pub piece_len: u64,
+    /// Pieces which were downloaded and validated
+    pub have: Bitfield,
+}
+
+impl Stream {
+    /// Bytes of the file from `start` up to `len` which can be served.
+    fn available(&self, start: u64, len: u64) -> u64 {
+        let mut pos = start;
+        while pos < start + len {
+            let piece = (self.offset + pos) / self.piece_len;
+            if !self.have.has_bit(piece) {
+                break;
+            }
+            pos = (piece + 1) * self.piece_len - self.offset;
+        }
+        cmp::min(pos, start + len) - start
+    }
+}
+
+/// Stats of a torrent's files as of its serialization
+pub type FileStats = Vec<Option<FileStat>>;
+
+/// A move which failed part way. Files moved before the failure are
+/// moved back to where they were.
+pub struct MoveError {
+    pub to: String,
+    /// Files which couldn't be moved back and remain at `to`
+    pub moved: Vec<PathBuf>,
+    pub err: io::Error,
+}
+
+/// A rename which couldn't be applied on disk
+pub struct RenameError {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub err: io::Error,
+}
+
 pub struct Ctx {
     pub pid: usize,
     pub tid: usize,
@@ -124,6 +249,8 @@ pub enum JobRes {
     Done,
     Paused(Request),
     Blocked((usize, Request)),
+    /// Job to be resumed after the given number of milliseconds
+    Delayed(usize, Request, Response),
 }
 
 impl Request {
@@ -145,17 +272,35 @@ impl Request {
         }
     }
 
-    pub fn serialize(tid: usize, data: Vec<u8>, hash: [u8; 20]) -> Request {
-        Request::Serialize { tid, data, hash }
+    pub fn serialize(
+        tid: usize,
+        session: Box<Session>,
+        hash: [u8; 20],
+        path: Option<String>,
+        files: Vec<PathBuf>,
+    ) -> Request {
+        Request::Serialize {
+            tid,
+            session,
+            hash,
+            path,
+            files,
+        }
     }
 
-    pub fn validate(tid: usize, info: Arc<Info>, path: Option<String>) -> Request {
+    pub fn validate(
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        low_impact: bool,
+    ) -> Request {
         Request::Validate {
             tid,
             info,
             path,
             idx: 0,
             invalid: Vec::new(),
+            low_impact,
         }
     }
 
@@ -173,6 +318,20 @@ impl Request {
         }
     }
 
+    pub fn check_pieces(
+        tid: usize,
+        info: Arc<Info>,
+        path: Option<String>,
+        pieces: Vec<u32>,
+    ) -> Request {
+        Request::CheckPieces {
+            tid,
+            info,
+            path,
+            pieces,
+        }
+    }
+
     pub fn delete(
         tid: usize,
         hash: [u8; 20],
@@ -267,16 +426,130 @@ impl Request {
             buf_idx: 0,
             buf_max: data.len(),
             file_len: len,
+            stream: None,
+        }
+    }
+
+    /// Makes a download serve only the pieces its torrent has, waiting
+    /// for the rest to be downloaded.
+    pub fn set_stream(&mut self, s: Stream) {
+        if let Request::Download { ref mut stream, .. } = *self {
+            *stream = Some(s);
+        }
+    }
+
+    /// Ranges of the file a download serves
+    pub fn ranges(&self) -> &[HttpRange] {
+        match *self {
+            Request::Download { ref ranges, .. } => ranges,
+            _ => &[],
+        }
+    }
+
+    /// The torrent and ID of a download's stream
+    pub fn stream(&self) -> Option<(usize, usize)> {
+        match *self {
+            Request::Download {
+                stream: Some(ref s),
+                ..
+            } => Some((s.tid, s.id)),
+            _ => None,
+        }
+    }
+
+    /// Records a piece of the torrent `tid` as downloaded, returning
+    /// whether this is a stream of the torrent.
+    pub fn mark_ready(&mut self, tid: usize, piece: u32) -> bool {
+        match *self {
+            Request::Download {
+                stream: Some(ref mut s),
+                ..
+            } if s.tid == tid => {
+                s.have.set_bit(u64::from(piece));
+                true
+            }
+            _ => false,
         }
     }
 
+    pub fn piece_ready(tid: usize, piece: u32) -> Request {
+        Request::PieceReady { tid, piece }
+    }
+
+    /// Renames a file or directory of a torrent, where `files` are
+    /// the current paths of all files affected by the rename.
+    pub fn rename(
+        tid: usize,
+        path: Option<String>,
+        from: PathBuf,
+        to: PathBuf,
+        files: Vec<PathBuf>,
+    ) -> Request {
+        Request::Rename {
+            tid,
+            path,
+            from,
+            to,
+            files,
+        }
+    }
+
+    /// Fsyncs the given files of a torrent, which are skipped
+    /// if they haven't been written to since the last flush.
+    pub fn flush(path: Option<String>, files: Vec<PathBuf>) -> Request {
+        Request::Flush { path, files }
+    }
+
+    /// Moves the torrent's `files` from the directory `from` to `to`,
+    /// a file at a time so that progress can be reported.
+    pub fn move_files(tid: usize, from: String, to: String, files: Vec<(PathBuf, u64)>) -> Request {
+        Request::Move {
+            tid,
+            from,
+            to,
+            files,
+            idx: 0,
+            copied: Vec::new(),
+        }
+    }
+
+    pub fn allocate(tid: usize, path: Option<String>, files: Vec<(PathBuf, u64)>) -> Request {
+        Request::Allocate {
+            tid,
+            path,
+            files,
+            idx: 0,
+        }
+    }
+
+    /// Hashes the pieces of `info`, a torrent created from `dir`, for
+    /// the RPC client which requested it.
+    pub fn create(info: Info, dir: PathBuf, client: usize, serial: u64) -> Request {
+        Request::Create {
+            hashes: Vec::with_capacity(info.pieces() as usize),
+            info: Arc::new(info),
+            dir,
+            file: None,
+            client,
+            serial,
+        }
+    }
+
+    pub fn checkpoint() -> Request {
+        Request::Checkpoint
+    }
+
+    pub fn quiesce(quiesced: bool) -> Request {
+        Request::Quiesce(quiesced)
+    }
+
     pub fn shutdown() -> Request {
         Request::Shutdown
     }
 
     pub fn concurrent(&self) -> bool {
         match self {
-            Request::Validate { .. } => false,
+            Request::Validate { .. } | Request::Create { .. } => false,
             _ => true,
         }
     }
@@ -287,45 +560,41 @@ impl Request {
         let (mut tb, mut tpb, mut tpb2) = bc.data();
         match self {
             Request::Ping => {}
+            Request::Checkpoint => {
+                fc.flush_all();
+                return Ok(JobRes::Resp(Response::Checkpoint));
+            }
+            Request::CacheStats => {
+                return Ok(JobRes::Resp(Response::CacheStats(fc.cache_stats())));
+            }
             Request::FreeSpace => {
                 if let Ok(stat) = statvfs::statvfs(dd.as_str()) {
                     let space = stat.fragment_size() as u64 * stat.blocks_available() as u64;
-                    return Ok(JobRes::Resp(Response::FreeSpace(space)));
+                    let read_only = stat.flags().contains(statvfs::FsFlags::ST_RDONLY);
+                    return Ok(JobRes::Resp(Response::FreeSpace { space, read_only }));
                 } else {
                     return io_err("couldn't stat fs");
                 }
             }
             Request::WriteFile { path, data } => {
-                let p = tpb.get(path.iter());
-                p.set_extension("temp");
-                let res = fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(&p)
-                    .map(|mut f| f.write(&data[..]));
-                match res {
-                    Ok(Ok(_)) => {
-                        fs::rename(&p, &path).ok();
-                    }
-                    Ok(Err(e)) => {
-                        error!("Failed to write disk job: {}", e);
-                        fs::remove_file(&p).ok();
-                    }
-                    Err(e) => {
-                        error!("Failed to write disk job: {}", e);
-                    }
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).ok();
+                }
+                if let Err(e) = write_atomic(&path, &data, 0o666) {
+                    error!("Failed to write disk job: {}", e);
                 }
             }
             Request::Write {
+                tid,
                 data,
                 locations,
                 path,
-                ..
             } => {
                 for loc in locations {
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
                     fc.write_file_range(
+                        tid,
                         &pb,
                         if loc.allocate {
                             Ok(loc.file_len)
@@ -335,10 +604,39 @@ impl Request {
                         loc.offset,
                         &data[loc.start..loc.end],
                     )?;
-                    if loc.end - loc.start != 16_384 {
-                        fc.flush_file(&pb);
+                }
+            }
+            Request::Allocate {
+                tid,
+                path,
+                files,
+                mut idx,
+            } => {
+                let start = time::Instant::now();
+                while idx < files.len()
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                {
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(&files[idx].0);
+                    if fc.preallocate(pb, files[idx].1)? {
+                        idx += 1;
                     }
                 }
+                if idx < files.len() {
+                    return Ok(JobRes::Paused(Request::Allocate {
+                        tid,
+                        path,
+                        files,
+                        idx,
+                    }));
+                }
+            }
+            Request::Flush { path, files } => {
+                for file in files {
+                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                    pb.push(file);
+                    fc.flush_file(pb);
+                }
             }
             Request::Read {
                 context,
@@ -358,45 +656,109 @@ impl Request {
                 tid,
                 from,
                 to,
-                target,
+                files,
+                mut idx,
+                mut copied,
             } => {
-                let fp = tpb.get(&from);
-                let tp = tpb2.get(&to);
-                fp.push(target.clone());
-                tp.push(target);
-                match fs::rename(&fp, &tp) {
-                    Ok(_) => {}
-                    // Cross filesystem move, try to copy then delete
-                    Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
-                        match fs_extra::dir::copy(&fp, &tp, &fs_extra::dir::CopyOptions::new()) {
-                            Ok(_) => {
-                                fs::remove_dir_all(&fp)?;
-                            }
-                            Err(e) => {
-                                fs::remove_dir_all(&tp)?;
-                                error!("FS copy failed: {:?}", e);
-                                return io_err("Failed to copy directory across filesystems!");
-                            }
+                let start = time::Instant::now();
+                while idx < files.len()
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                {
+                    let fp = tpb.get(&from);
+                    let tp = tpb2.get(&to);
+                    fp.push(&files[idx].0);
+                    tp.push(&files[idx].0);
+                    match move_file(fc, fp, tp) {
+                        Ok(true) => copied.push(idx),
+                        Ok(false) => {}
+                        Err(err) => {
+                            error!("Failed to move {:?} to {:?}: {}", fp, tp, err);
+                            let moved = undo_moves(
+                                Path::new(&from),
+                                Path::new(&to),
+                                &files[..idx],
+                                &copied,
+                            );
+                            let err = MoveError { to, moved, err };
+                            return Ok(JobRes::Resp(Response::MoveFailed { tid, err }));
                         }
                     }
-                    Err(e) => {
-                        error!("FS rename failed: {:?}", e);
-                        return Err(e);
+                    idx += 1;
+                }
+                if idx < files.len() {
+                    let total: u64 = files.iter().map(|f| f.1).sum();
+                    let done: u64 = files[..idx].iter().map(|f| f.1).sum();
+                    let percent = if total == 0 {
+                        0.
+                    } else {
+                        done as f32 / total as f32
+                    };
+                    let job = Request::Move {
+                        tid,
+                        from,
+                        to,
+                        files,
+                        idx,
+                        copied,
+                    };
+                    return Ok(JobRes::Update(job, Response::MoveUpdate { tid, percent }));
+                }
+                // Originals are only removed once the whole torrent is
+                // in its new location, so a failure can't split it up
+                for &i in &copied {
+                    let fp = Path::new(&from).join(&files[i].0);
+                    if let Err(e) = fs::remove_file(&fp) {
+                        warn!("Failed to remove {:?} after copying it: {}", fp, e);
                     }
                 }
+                let paths: Vec<_> = files.into_iter().map(|f| f.0).collect();
+                remove_empty_dirs(Path::new(&from), &paths);
                 return Ok(JobRes::Resp(Response::moved(tid, to)));
             }
-            Request::Serialize { data, hash, .. } => {
-                let temp = tpb.get(sd);
-                temp.push(hash_to_id(&hash) + ".temp");
-                let mut f = fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(&temp)?;
-                f.write_all(&data)?;
-                let actual = tpb2.get(sd);
+            Request::Rename {
+                tid,
+                path,
+                from,
+                to,
+                files,
+            } => {
+                let base = Path::new(path.as_ref().unwrap_or(dd));
+                let files: Vec<_> = files.iter().map(|f| base.join(f)).collect();
+                if let Err(err) = rename_path(fc, &base.join(&from), &base.join(&to), &files) {
+                    error!("Failed to rename {:?} to {:?}: {}", from, to, err);
+                    let err = RenameError { from, to, err };
+                    return Ok(JobRes::Resp(Response::RenameFailed { tid, err }));
+                }
+            }
+            Request::Serialize {
+                tid,
+                mut session,
+                hash,
+                path,
+                files,
+            } => {
+                // Every write queued before this has been performed, so
+                // the stats match the piece state being serialized
+                let stats: Vec<_> = {
+                    let pb = tpb2.get(path.as_ref().unwrap_or(dd));
+                    files
+                        .iter()
+                        .map(|file| {
+                            pb.push(file);
+                            fc.write_back(pb);
+                            let stat = file_stat(pb);
+                            pb.pop();
+                            stat
+                        })
+                        .collect()
+                };
+                session.file_stats = stats.clone();
+                let data = bincode::serialize(&*session).expect("Serialization failed!");
+                let actual = tpb.get(sd);
                 actual.push(hash_to_id(&hash));
-                fs::rename(temp, actual)?;
+                // Session data may contain tracker credentials
+                write_atomic(actual, &data, 0o600)?;
+                return Ok(JobRes::Resp(Response::Serialized { tid, stats }));
             }
             Request::Delete {
                 hash,
@@ -416,6 +778,9 @@ impl Request {
                 for file in &files {
                     let pb = tpb2.get(path.as_ref().unwrap_or(dd));
                     pb.push(&file);
+                    if artifacts {
+                        fc.discard_writes(pb);
+                    }
                     fc.remove_file(&pb);
                     if artifacts {
                         if let Err(e) = fs::remove_file(&pb) {
@@ -424,12 +789,9 @@ impl Request {
                     }
                 }
 
-                if let Some(p) = files.get(0) {
-                    let comp = p.components().next().unwrap();
-                    let dirp: &Path = comp.as_os_str().as_ref();
+                if CONFIG.disk.remove_empty_dirs {
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                    pb.push(&dirp);
-                    fs::remove_dir(&pb).ok();
+                    remove_empty_dirs(pb, &files);
                 }
             }
             Request::ValidatePiece {
@@ -447,6 +809,9 @@ impl Request {
                     fc.read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
                         .map(|_| ctx.update(&buf[loc.start..loc.end]))
                         .ok();
+                    // The piece is complete, so its buffered blocks are
+                    // written together
+                    fc.write_back(pb);
                 }
                 let digest = ctx.finish();
                 return Ok(JobRes::Resp(Response::PieceValidated {
@@ -455,12 +820,32 @@ impl Request {
                     valid: digest[..] == info.hashes[piece as usize][..],
                 }));
             }
+            Request::CheckPieces {
+                tid,
+                info,
+                path,
+                pieces,
+            } => {
+                let buf = tb.get(info.piece_len as usize);
+                let invalid = pieces
+                    .into_iter()
+                    .filter(|&piece| {
+                        !piece_valid(&info, piece, buf, |loc, data| {
+                            let pb = tpb.get(path.as_ref().unwrap_or(dd));
+                            pb.push(loc.path());
+                            fc.read_file_range(pb, loc.offset, data)
+                        })
+                    })
+                    .collect();
+                return Ok(JobRes::Resp(Response::PiecesChecked { tid, invalid }));
+            }
             Request::Validate {
                 tid,
                 info,
                 path,
                 mut idx,
                 mut invalid,
+                low_impact,
             } => {
                 let buf = tb.get(info.piece_len as usize);
                 let start = time::Instant::now();
@@ -488,26 +873,84 @@ impl Request {
                     }
 
                     idx += 1;
+                    if low_impact {
+                        break;
+                    }
                 }
                 if idx == info.pieces() {
                     return Ok(JobRes::Resp(Response::validation_complete(tid, invalid)));
                 } else {
                     let pieces = info.pieces();
-                    return Ok(JobRes::Update(
-                        Request::Validate {
-                            tid,
-                            info,
-                            path,
-                            idx,
-                            invalid,
-                        },
-                        Response::ValidationUpdate {
-                            tid,
-                            percent: idx as f32 / pieces as f32,
-                        },
-                    ));
+                    let job = Request::Validate {
+                        tid,
+                        info,
+                        path,
+                        idx,
+                        invalid,
+                        low_impact,
+                    };
+                    let update = Response::ValidationUpdate {
+                        tid,
+                        percent: idx as f32 / pieces as f32,
+                    };
+                    if low_impact {
+                        let rate = cmp::max(CONFIG.disk.low_impact_validate_rate, 1);
+                        return Ok(JobRes::Delayed(1000 / rate as usize, job, update));
+                    }
+                    return Ok(JobRes::Update(job, update));
                 }
             }
+            Request::Create {
+                info,
+                dir,
+                mut hashes,
+                mut file,
+                client,
+                serial,
+            } => {
+                let buf = tb.get(info.piece_len as usize);
+                let start = time::Instant::now();
+                while hashes.len() < info.pieces() as usize
+                    && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
+                {
+                    let mut ctx = sha::Sha1::new();
+                    for loc in Info::piece_disk_locs(&info, hashes.len() as u32) {
+                        let data = &mut buf[loc.start..loc.end];
+                        if let Err(err) = read_created(&dir, &loc, &mut file, data) {
+                            let err = io::Error::new(
+                                err.kind(),
+                                format!("couldn't read {:?}: {}", loc.path(), err),
+                            );
+                            let req = (client, serial);
+                            return Ok(JobRes::Resp(Response::CreateFailed { req, err }));
+                        }
+                        ctx.update(data);
+                    }
+                    hashes.push(ctx.finish().to_vec());
+                }
+                if hashes.len() == info.pieces() as usize {
+                    let mut info = Arc::try_unwrap(info).unwrap_or_else(|i| (*i).clone());
+                    info.hashes = hashes;
+                    return Ok(JobRes::Resp(Response::Created {
+                        req: (client, serial),
+                        info: Box::new(info),
+                    }));
+                }
+                let percent = hashes.len() as f32 / info.pieces() as f32;
+                let job = Request::Create {
+                    info,
+                    dir,
+                    hashes,
+                    file,
+                    client,
+                    serial,
+                };
+                let update = Response::CreateUpdate {
+                    req: (client, serial),
+                    percent,
+                };
+                return Ok(JobRes::Update(job, update));
+            }
             Request::Download {
                 mut client,
                 path,
@@ -520,6 +963,7 @@ impl Request {
                 mut buf_idx,
                 mut buf_max,
                 mut buf,
+                stream,
             } => {
                 let start = time::Instant::now();
                 while start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
@@ -548,6 +992,7 @@ impl Request {
                                             buf_max,
                                             buf,
                                             file_len,
+                                            stream,
                                         },
                                     )))
                                 }
@@ -591,7 +1036,30 @@ impl Request {
                     } else {
                         let offset = ranges[range_idx].start;
                         let len = ranges[range_idx].length;
-                        let amnt = cmp::min(len, 16_384);
+                        let mut amnt = cmp::min(len, 16_384);
+                        if let Some(ref s) = stream {
+                            amnt = s.available(offset, amnt);
+                        }
+                        if amnt == 0 {
+                            // Resumed once the torrent has the piece
+                            return Ok(JobRes::Blocked((
+                                id,
+                                Request::Download {
+                                    client,
+                                    path,
+                                    range_idx,
+                                    id,
+                                    ranges,
+                                    ranged,
+                                    writing,
+                                    buf_idx,
+                                    buf_max,
+                                    buf,
+                                    file_len,
+                                    stream,
+                                },
+                            )));
+                        }
 
                         fc.read_file_range(
                             path::Path::new(&path),
@@ -617,9 +1085,12 @@ impl Request {
                     buf_idx,
                     buf_max,
                     buf,
+                    stream,
                 }));
             }
-            Request::Shutdown => unreachable!(),
+            Request::PieceReady { .. } | Request::Quiesce(_) | Request::Shutdown => {
+                unreachable!()
+            }
         }
         Ok(JobRes::Done)
     }
@@ -644,18 +1115,246 @@ impl Request {
             Request::Serialize { tid, .. }
             | Request::Validate { tid, .. }
             | Request::ValidatePiece { tid, .. }
+            | Request::CheckPieces { tid, .. }
             | Request::Delete { tid, .. }
             | Request::Move { tid, .. }
+            | Request::Rename { tid, .. }
+            | Request::Allocate { tid, .. }
+            | Request::PieceReady { tid, .. }
             | Request::Write { tid, .. } => Some(tid),
             Request::WriteFile { .. }
+            | Request::Flush { .. }
+            | Request::Create { .. }
             | Request::Download { .. }
             | Request::Shutdown
             | Request::Ping
+            | Request::Checkpoint
+            | Request::Quiesce(_)
+            | Request::CacheStats
             | Request::FreeSpace => None,
         }
     }
 }
 
+/// Free space available to unprivileged users on the filesystem of
+/// `path`, or of its closest existing ancestor.
+pub fn available_space(path: &Path) -> Option<u64> {
+    path.ancestors()
+        .chain(Some(Path::new(".")))
+        .filter_map(|p| statvfs::statvfs(p).ok())
+        .next()
+        .map(|stat| stat.fragment_size() as u64 * stat.blocks_available() as u64)
+}
+
+/// Moves a file to `to`, copying it if `to` is on another filesystem.
+/// Returns whether it was copied, in which case the original is left in
+/// place. Files which were never created are skipped.
+fn move_file(fc: &mut FileCache, from: &Path, to: &Path) -> io::Result<bool> {
+    fc.flush_file(from);
+    fc.remove_file(from);
+    if !from.exists() {
+        return Ok(false);
+    }
+    if to.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "move target already exists",
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Ok(()) => Ok(false),
+        Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => copy_file(from, to).map(|_| true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copies a file, checking that the copy reads back the same as the
+/// original before it's relied upon.
+fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let res = fs::copy(from, to)
+        .and_then(|_| fs::File::open(to)?.sync_all())
+        .and_then(|_| files_equal(from, to))
+        .and_then(|equal| {
+            if equal {
+                Ok(())
+            } else {
+                io_err("Copied file does not match its original")
+            }
+        });
+    if res.is_err() {
+        fs::remove_file(to).ok();
+    }
+    res
+}
+
+fn files_equal(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = fs::File::open(a)?;
+    let mut b = fs::File::open(b)?;
+    let len = a.metadata()?.len();
+    if b.metadata()?.len() != len {
+        return Ok(false);
+    }
+    let mut abuf = vec![0u8; COMPARE_CHUNK];
+    let mut bbuf = vec![0u8; COMPARE_CHUNK];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = cmp::min(remaining, COMPARE_CHUNK as u64) as usize;
+        a.read_exact(&mut abuf[..n])?;
+        b.read_exact(&mut bbuf[..n])?;
+        if abuf[..n] != bbuf[..n] {
+            return Ok(false);
+        }
+        remaining -= n as u64;
+    }
+    Ok(true)
+}
+
+/// Moves the `files` already moved to `to` back to `from`, removing
+/// the copies of those in `copied`. Returns the files which couldn't
+/// be moved back.
+fn undo_moves(from: &Path, to: &Path, files: &[(PathBuf, u64)], copied: &[usize]) -> Vec<PathBuf> {
+    let mut moved = Vec::new();
+    for (i, file) in files.iter().enumerate().rev() {
+        let tp = to.join(&file.0);
+        if !tp.exists() {
+            continue;
+        }
+        if copied.contains(&i) {
+            fs::remove_file(&tp).ok();
+        } else if let Err(e) = fs::rename(&tp, from.join(&file.0)) {
+            error!("Failed to move {:?} back: {}", tp, e);
+            moved.push(file.0.clone());
+        }
+    }
+    let paths: Vec<_> = files.iter().map(|f| f.0.clone()).collect();
+    remove_empty_dirs(to, &paths);
+    moved
+}
+
+/// Moves a file or directory, closing any cached handles to the
+/// affected `files` first. Files which were never created are
+/// left for the new path to be used once they are written.
+fn rename_path(fc: &mut FileCache, from: &Path, to: &Path, files: &[PathBuf]) -> io::Result<()> {
+    for file in files {
+        fc.remove_file(file);
+    }
+    if to.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "rename target already exists",
+        ));
+    }
+    if !from.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(from, to)
+}
+
+/// Removes the directories of a torrent's files under `root` which are
+/// empty, deepest first so that emptied parents go too. Directories still
+/// holding anything, e.g. another torrent's files, are left in place, as
+/// is `root` itself.
+fn remove_empty_dirs(root: &Path, files: &[PathBuf]) {
+    let mut dirs: Vec<&Path> = files
+        .iter()
+        .flat_map(|f| f.ancestors().skip(1))
+        .filter(|d| d.components().next().is_some())
+        .collect();
+    dirs.sort_by_key(|d| (cmp::Reverse(d.components().count()), *d));
+    dirs.dedup();
+    for dir in dirs {
+        if fs::remove_dir(root.join(dir)).is_ok() {
+            debug!("Removed empty directory {:?}", dir);
+        }
+    }
+}
+
+/// Writes `data` to `path` through a temporary file which is synced and
+/// renamed into place, so that a crash leaves either the old or the new
+/// contents behind.
+pub fn write_atomic(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
+    let temp = path.with_extension(TEMP_EXT);
+    let res = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&temp)
+        .and_then(|mut f| {
+            f.write_all(data)?;
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if res.is_err() {
+        fs::remove_file(&temp).ok();
+        return res;
+    }
+    // Make the rename itself durable
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir).and_then(|d| d.sync_all()).ok();
+    }
+    Ok(())
+}
+
+/// Removes temporary files of `dir` left behind by writes which were
+/// interrupted by a crash.
+pub fn remove_temp_files(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some(TEMP_EXT) && path.is_file() {
+            warn!("Removing incomplete session file {:?}", path);
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hashes a piece, using `read` to load each of its disk locations,
+/// and checks it against the expected hash.
+fn piece_valid<F>(info: &Arc<Info>, piece: u32, buf: &mut [u8], mut read: F) -> bool
+where
+    F: FnMut(&Location, &mut [u8]) -> io::Result<()>,
+{
+    let mut ctx = sha::Sha1::new();
+    for loc in Info::piece_disk_locs(info, piece) {
+        let data = &mut buf[loc.start..loc.end];
+        if read(&loc, data).is_err() {
+            return false;
+        }
+        ctx.update(data);
+    }
+    ctx.finish()[..] == info.hashes[piece as usize][..]
+}
+
+/// Reads a location of a torrent being created from `dir`, opening its
+/// files read only as they're reached.
+fn read_created(
+    dir: &Path,
+    loc: &Location,
+    file: &mut Option<(usize, fs::File)>,
+    data: &mut [u8],
+) -> io::Result<()> {
+    let open = match *file {
+        Some((idx, _)) => idx == loc.file,
+        None => false,
+    };
+    if !open {
+        *file = Some((loc.file, fs::File::open(dir.join(loc.path()))?));
+    }
+    let f = &mut file.as_mut().unwrap().1;
+    f.seek(SeekFrom::Start(loc.offset))?;
+    f.read_exact(data)
+}
+
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "disk::Request")
@@ -684,7 +1383,7 @@ impl Location {
     }
 
     pub fn path(&self) -> &Path {
-        &self.info.files[self.file].path
+        self.info.files[self.file].disk_path()
     }
 }
 
@@ -720,10 +1419,22 @@ impl Response {
             Response::Read { ref context, .. } => context.tid,
             Response::ValidationComplete { tid, .. }
             | Response::Moved { tid, .. }
+            | Response::MoveUpdate { tid, .. }
+            | Response::MoveFailed { tid, .. }
+            | Response::RenameFailed { tid, .. }
             | Response::ValidationUpdate { tid, .. }
             | Response::PieceValidated { tid, .. }
+            | Response::PiecesChecked { tid, .. }
+            | Response::Serialized { tid, .. }
+            | Response::StreamDone { tid, .. }
             | Response::Error { tid, .. } => tid,
-            Response::FreeSpace(_) => unreachable!(),
+            Response::FreeSpace { .. }
+            | Response::CreateUpdate { .. }
+            | Response::Created { .. }
+            | Response::CreateFailed { .. }
+            | Response::CacheStats(_)
+            | Response::BufferFull(_)
+            | Response::Checkpoint => unreachable!(),
         }
     }
 }
@@ -745,3 +1456,262 @@ impl Ctx {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::FileCache;
+    use super::{
+        copy_file, move_file, piece_valid, read_created, remove_empty_dirs, remove_temp_files,
+        rename_path, undo_moves, write_atomic, Stream,
+    };
+    use openssl::sha;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::{env, fs, process};
+    use torrent::info::File;
+    use torrent::Info;
+    use torrent::{self, Bitfield};
+
+    #[test]
+    fn test_detect_corrupt_piece() {
+        let pieces = 3;
+        let mut data = vec![0u8; 16_384 * pieces];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+
+        let mut info = Info::with_pieces(pieces);
+        info.files = vec![File {
+            path: PathBuf::from("data"),
+            length: data.len() as u64,
+            renamed: None,
+        }];
+        info.hashes = data.chunks(16_384).map(|c| sha::sha1(c).to_vec()).collect();
+        info.piece_idx = Info::generate_piece_idx(pieces, u64::from(info.piece_len), &info.files);
+        let info = Arc::new(info);
+
+        let dir = env::temp_dir().join(format!("synapse-check-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        fs::write(&path, &data).unwrap();
+
+        let check = |piece| {
+            let mut buf = vec![0u8; 16_384];
+            piece_valid(&info, piece, &mut buf, |loc, data| {
+                let mut f = fs::File::open(dir.join(loc.path()))?;
+                f.seek(SeekFrom::Start(loc.offset))?;
+                f.read_exact(data)
+            })
+        };
+        assert!((0..pieces as u32).all(&check));
+
+        // Flip a byte in the middle piece behind our back
+        {
+            let mut f = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            f.seek(SeekFrom::Start(16_384 + 100)).unwrap();
+            f.write_all(&[!data[16_384 + 100]]).unwrap();
+        }
+        assert!(check(0));
+        assert!(!check(1));
+        assert!(check(2));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rename_file() {
+        let mut data = vec![0u8; 16_384 * 2];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        let mut info = Info::with_pieces(2);
+        info.files = vec![
+            File {
+                path: PathBuf::from("t/a"),
+                length: 16_384,
+                renamed: None,
+            },
+            File {
+                path: PathBuf::from("t/b"),
+                length: 16_384,
+                renamed: None,
+            },
+        ];
+        info.hashes = data.chunks(16_384).map(|c| sha::sha1(c).to_vec()).collect();
+        info.piece_idx = Info::generate_piece_idx(2, u64::from(info.piece_len), &info.files);
+
+        let dir = env::temp_dir().join(format!("synapse-rename-{}", process::id()));
+        fs::create_dir_all(dir.join("t")).unwrap();
+        fs::write(dir.join("t/a"), &data[..16_384]).unwrap();
+        fs::write(dir.join("t/b"), &data[16_384..]).unwrap();
+
+        let mut fc = FileCache::new();
+        let (from, to) = info.rename_file(0, PathBuf::from("t/sub/c")).unwrap();
+        let files = [dir.join(&from)];
+        rename_path(&mut fc, &files[0], &dir.join(&to), &files).unwrap();
+        assert!(!dir.join("t/a").exists());
+        assert!(dir.join("t/sub/c").exists());
+
+        // Pieces are read from the new location and still map to the
+        // same files, so per file progress is unaffected
+        let info = Arc::new(info);
+        for piece in 0..2 {
+            let mut buf = vec![0u8; 16_384];
+            assert!(piece_valid(&info, piece, &mut buf, |loc, data| {
+                assert_eq!(loc.file, piece as usize);
+                let mut f = fs::File::open(dir.join(loc.path()))?;
+                f.seek(SeekFrom::Start(loc.offset))?;
+                f.read_exact(data)
+            }));
+        }
+
+        // Existing files aren't overwritten
+        let err = rename_path(&mut fc, &dir.join("t/b"), &dir.join("t/sub/c"), &[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(dir.join("t/b").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_move_files() {
+        let dir = env::temp_dir().join(format!("synapse-move-{}", process::id()));
+        let (from, to) = (dir.join("from"), dir.join("to"));
+        let files = [
+            (PathBuf::from("t/a"), 4),
+            (PathBuf::from("t/sub/b"), 4),
+            (PathBuf::from("t/c"), 4),
+        ];
+        fs::create_dir_all(from.join("t/sub")).unwrap();
+        fs::write(from.join("t/a"), b"aaaa").unwrap();
+        fs::write(from.join("t/sub/b"), b"bbbb").unwrap();
+
+        let mut fc = FileCache::new();
+        assert!(!move_file(&mut fc, &from.join("t/a"), &to.join("t/a")).unwrap());
+        assert_eq!(fs::read(to.join("t/a")).unwrap(), b"aaaa");
+        assert!(!from.join("t/a").exists());
+        // Files which were never written are skipped
+        assert!(!move_file(&mut fc, &from.join("t/c"), &to.join("t/c")).unwrap());
+        assert!(!to.join("t/c").exists());
+
+        // Copies are checked against their original and kept apart
+        copy_file(&from.join("t/sub/b"), &to.join("t/sub/b")).unwrap();
+        assert_eq!(fs::read(to.join("t/sub/b")).unwrap(), b"bbbb");
+        let err = move_file(&mut fc, &from.join("t/sub/b"), &to.join("t/sub/b")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // Undoing moves renamed files back and drops copies
+        assert!(undo_moves(&from, &to, &files[..2], &[1]).is_empty());
+        assert_eq!(fs::read(from.join("t/a")).unwrap(), b"aaaa");
+        assert_eq!(fs::read(from.join("t/sub/b")).unwrap(), b"bbbb");
+        assert!(!to.join("t").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stream_available() {
+        // A file starting 10 bytes into a torrent of 16 byte pieces
+        let mut stream = Stream {
+            tid: 0,
+            id: 0,
+            offset: 10,
+            piece_len: 16,
+            have: Bitfield::new(4),
+        };
+        assert_eq!(stream.available(0, 16), 0);
+        stream.have.set_bit(0);
+        stream.have.set_bit(1);
+        stream.have.set_bit(3);
+        assert_eq!(stream.available(0, 100), 22);
+        assert_eq!(stream.available(20, 5), 2);
+        assert_eq!(stream.available(22, 5), 0);
+        assert_eq!(stream.available(38, 10), 10);
+    }
+
+    #[test]
+    fn test_create_hashes() {
+        let dir = env::temp_dir().join(format!("synapse-create-hash-{}", process::id()));
+        fs::create_dir_all(dir.join("t")).unwrap();
+        let mut data = vec![0u8; 40_000];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i % 251) as u8;
+        }
+        fs::write(dir.join("t/a"), &data[..10_000]).unwrap();
+        fs::write(dir.join("t/b"), &data[10_000..]).unwrap();
+
+        // Pieces spanning both files are read from each in turn
+        let (info, base) = torrent::create(&dir.join("t"), &[], None, false).unwrap();
+        assert_eq!(base, dir);
+        let info = Arc::new(info);
+        let mut file = None;
+        let mut buf = vec![0u8; 16_384];
+        for (piece, chunk) in data.chunks(16_384).enumerate() {
+            let mut hashed = Vec::new();
+            for loc in Info::piece_disk_locs(&info, piece as u32) {
+                let data = &mut buf[loc.start..loc.end];
+                read_created(&base, &loc, &mut file, data).unwrap();
+                hashed.extend_from_slice(data);
+            }
+            assert_eq!(&hashed[..], chunk);
+        }
+
+        // Files which shrank fail to be read
+        fs::write(dir.join("t/b"), &data[10_000..20_000]).unwrap();
+        let mut file = None;
+        let loc = Info::piece_disk_locs(&info, 2).next().unwrap();
+        assert!(read_created(&base, &loc, &mut file, &mut buf[loc.start..loc.end]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = env::temp_dir().join(format!("synapse-atomic-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("syn_data");
+
+        // A longer write left behind by a crash
+        fs::write(dir.join("syn_data.temp"), vec![1u8; 64]).unwrap();
+        fs::write(dir.join("other"), b"data").unwrap();
+        write_atomic(&path, b"first", 0o600).unwrap();
+        write_atomic(&path, b"second", 0o600).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert!(!dir.join("syn_data.temp").exists());
+
+        fs::write(dir.join("abcd.temp"), b"partial").unwrap();
+        remove_temp_files(&dir).unwrap();
+        assert!(!dir.join("abcd.temp").exists());
+        assert!(path.exists() && dir.join("other").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_empty_dirs() {
+        let dir = env::temp_dir().join(format!("synapse-rmdirs-{}", process::id()));
+        let files = vec![
+            PathBuf::from("t/a"),
+            PathBuf::from("t/sub/b"),
+            PathBuf::from("t/sub/deeper/c"),
+            PathBuf::from("u/a"),
+        ];
+        for f in &files {
+            fs::create_dir_all(dir.join(f).parent().unwrap()).unwrap();
+            fs::write(dir.join(f), b"data").unwrap();
+        }
+        // Another torrent's file sharing a directory
+        fs::write(dir.join("u/other"), b"data").unwrap();
+
+        for f in &files {
+            fs::remove_file(dir.join(f)).unwrap();
+        }
+        remove_empty_dirs(&dir, &files);
+        assert!(!dir.join("t").exists());
+        assert!(dir.join("u/other").exists());
+        assert!(dir.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
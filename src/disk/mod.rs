@@ -1,17 +1,25 @@
 mod cache;
+mod coalesce;
+mod hold;
 mod job;
 
+pub use self::coalesce::CacheStats;
+pub use self::job::available_space;
+pub use self::job::write_atomic;
 pub use self::job::Ctx;
 pub use self::job::Location;
 pub use self::job::Request;
 pub use self::job::Response;
+pub use self::job::Stream;
 
 use std::collections::VecDeque;
+use std::path::Path;
 use std::{fs, io, thread};
 
 use amy;
 
 use self::cache::{BufCache, FileCache};
+use self::hold::Hold;
 use self::job::JobRes;
 use util::UHashMap;
 use {handle, CONFIG};
@@ -29,6 +37,7 @@ pub struct Disk {
     sequential: VecDeque<Request>,
     blocked: UHashMap<Request>,
     bufs: BufCache,
+    hold: Hold,
 }
 
 impl Disk {
@@ -48,12 +57,16 @@ impl Disk {
             active: VecDeque::new(),
             sequential: VecDeque::new(),
             blocked: UHashMap::default(),
+            hold: Hold::new(CONFIG.disk.quiesce_buffer_mb * 1_000_000),
         }
     }
 
     pub fn run(&mut self) {
         let sd = &CONFIG.disk.session;
         fs::create_dir_all(sd).unwrap();
+        if let Err(e) = job::remove_temp_files(Path::new(sd)) {
+            error!("Failed to clean up session directory: {}", e);
+        }
 
         loop {
             match self.poll.wait(POLL_INT_MS) {
@@ -74,6 +87,7 @@ impl Disk {
             if !self.active.is_empty() && self.handle_active() {
                 break;
             }
+            self.write_back_expired();
         }
 
         // Try to finish up remaining jobs
@@ -96,6 +110,7 @@ impl Disk {
         let mut rotate = 1;
         while let Some(j) = self.active.pop_front() {
             let tid = j.tid();
+            let stream = j.stream();
             let seq = !j.concurrent();
             let mut done = false;
             match j.execute(&mut self.files, &mut self.bufs) {
@@ -121,6 +136,18 @@ impl Disk {
                 Ok(JobRes::Blocked((id, s))) => {
                     self.blocked.insert(id, s);
                 }
+                Ok(JobRes::Delayed(ms, s, r)) => {
+                    self.ch.send(r).ok();
+                    match self.reg.set_timeout(ms) {
+                        Ok(id) => {
+                            self.blocked.insert(id, s);
+                        }
+                        Err(e) => {
+                            error!("Failed to set disk job timer: {}", e);
+                            self.active.push_back(s);
+                        }
+                    }
+                }
                 Ok(JobRes::Done) => {
                     done = true;
                 }
@@ -133,6 +160,13 @@ impl Disk {
                     }
                 }
             }
+            match stream {
+                Some((tid, stream)) if done => {
+                    self.ch.send(Response::StreamDone { tid, stream }).ok();
+                }
+                _ => {}
+            }
+            self.write_back_expired();
             if done && seq {
                 if let Some(r) = self.sequential.pop_front() {
                     self.active.push_back(r);
@@ -159,11 +193,20 @@ impl Disk {
     }
 
     pub fn handle_events(&mut self) -> bool {
+        let full = self.hold.full();
         loop {
             match self.ch.recv() {
                 Ok(Request::Shutdown) => {
+                    // Held writes still make it to disk
+                    self.quiesce(false);
                     return true;
                 }
+                Ok(Request::Quiesce(q)) => {
+                    self.quiesce(q);
+                }
+                Ok(Request::PieceReady { tid, piece }) => {
+                    self.piece_ready(tid, piece);
+                }
                 Ok(mut r) => {
                     trace!("Handling disk job!");
                     let tid = r.tid();
@@ -172,7 +215,9 @@ impl Disk {
                             self.ch.send(Response::error(t, e)).ok();
                         }
                     }
-                    self.enqueue_req(r);
+                    if let Some(r) = self.hold.hold(r) {
+                        self.enqueue_req(r);
+                    }
                 }
                 _ => break,
             }
@@ -181,10 +226,64 @@ impl Disk {
             if r.register(&self.reg).is_err() {
                 continue;
             }
-            self.enqueue_req(r);
+            if let Some(r) = self.hold.hold(r) {
+                self.enqueue_req(r);
+            }
+        }
+        if self.hold.full() != full {
+            self.ch.send(Response::BufferFull(!full)).ok();
         }
         false
     }
+
+    fn quiesce(&mut self, quiesced: bool) {
+        if quiesced == self.hold.quiesced() {
+            return;
+        }
+        if quiesced {
+            info!("Quiescing disk writes");
+            // Nothing may be written until writes are resumed
+            self.files.write_back_all();
+            self.report_write_errors();
+        } else {
+            info!("Resuming disk writes");
+        }
+        for r in self.hold.set_quiesced(quiesced) {
+            self.enqueue_req(r);
+        }
+    }
+
+    /// Lets streams of the torrent `tid` serve `piece`, resuming those
+    /// waiting for a piece.
+    fn piece_ready(&mut self, tid: usize, piece: u32) {
+        for job in self.active.iter_mut() {
+            job.mark_ready(tid, piece);
+        }
+        let mut waiting = Vec::new();
+        for (&id, job) in self.blocked.iter_mut() {
+            if job.mark_ready(tid, piece) {
+                waiting.push(id);
+            }
+        }
+        for id in waiting {
+            if let Some(job) = self.blocked.remove(&id) {
+                self.enqueue_req(job);
+            }
+        }
+    }
+
+    /// Writes buffered data which has waited long enough.
+    fn write_back_expired(&mut self) {
+        self.files.write_back_expired();
+        self.report_write_errors();
+    }
+
+    /// Reports writes of buffered data which failed to their torrents.
+    fn report_write_errors(&mut self) {
+        for (tid, err) in self.files.take_errors() {
+            self.ch.send(Response::error(tid, err)).ok();
+        }
+    }
 }
 
 pub fn start(
@@ -201,3 +300,81 @@ pub fn start(
     let h = dh.run("disk", move |h| Disk::new(poll, reg, h, rx).run())?;
     Ok((ch, tx, h))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Disk, Request, Response, POLL_INT_MS};
+    use amy;
+    use handle;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use std::{env, fs, process};
+    use torrent::info::File;
+    use torrent::Info;
+    use CONFIG;
+
+    #[test]
+    fn test_low_impact_validate() {
+        let pieces = 6;
+        let mut info = Info::with_pieces(pieces);
+        info.files = vec![File {
+            path: PathBuf::from("data"),
+            length: 16_384 * pieces as u64,
+            renamed: None,
+        }];
+        info.piece_idx = Info::generate_piece_idx(pieces, u64::from(info.piece_len), &info.files);
+        let info = Arc::new(info);
+
+        let dir = env::temp_dir().join(format!("synapse-validate-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data"), vec![0u8; 16_384 * pieces]).unwrap();
+
+        let poll = amy::Poller::new().unwrap();
+        let mut reg = poll.get_registrar();
+        let cpoll = amy::Poller::new().unwrap();
+        let mut creg = cpoll.get_registrar();
+        let (mut ch, dh) = handle::Handle::new(&mut creg, &mut reg).unwrap();
+        let (_tx, rx) = reg.channel().unwrap();
+        let tick = reg.set_interval(10).unwrap();
+        let mut disk = Disk::new(poll, reg, dh, rx);
+
+        let path = Some(dir.to_string_lossy().into_owned());
+        ch.send(Request::validate(0, info, path, true)).unwrap();
+
+        // Drive the disk's event loop, checking the timer keeps firing
+        // while validation is underway
+        let start = Instant::now();
+        let mut last_tick = start;
+        let mut max_gap = Duration::from_millis(0);
+        let mut invalid = None;
+        while invalid.is_none() && start.elapsed() < Duration::from_secs(10) {
+            let events = disk.poll.wait(POLL_INT_MS).unwrap();
+            disk.handle_events();
+            for ev in events {
+                if ev.id == tick {
+                    max_gap = max_gap.max(last_tick.elapsed());
+                    last_tick = Instant::now();
+                } else if let Some(r) = disk.blocked.remove(&ev.id) {
+                    disk.enqueue_req(r);
+                }
+            }
+            if !disk.active.is_empty() {
+                disk.handle_active();
+            }
+            while let Ok(r) = ch.recv() {
+                if let Response::ValidationComplete { invalid: i, .. } = r {
+                    invalid = Some(i);
+                }
+            }
+        }
+        fs::remove_dir_all(&dir).ok();
+
+        // Every piece hashes to something other than the dummy hashes
+        assert_eq!(invalid.unwrap().len(), pieces);
+        // Hashing was paced rather than done in one go
+        let rate = u64::from(CONFIG.disk.low_impact_validate_rate);
+        assert!(start.elapsed() >= Duration::from_millis((pieces as u64 - 1) * 1000 / rate));
+        assert!(max_gap < Duration::from_millis(50));
+    }
+}
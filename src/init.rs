@@ -8,7 +8,7 @@ use nix::{self, fcntl, libc, unistd};
 
 use control::acio;
 use {args, control, disk, listener, log, rpc, throttle, tracker};
-use {CONFIG, SHUTDOWN, THROT_TOKS};
+use {CONFIG, SHUTDOWN};
 
 static mut PIPE: (RawFd, RawFd) = (-1, -1);
 
@@ -74,7 +74,14 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
     let chj = thread::Builder::new()
         .name("control".to_string())
         .spawn(move || {
-            let throttler = throttle::Throttler::new(None, None, THROT_TOKS, &creg).unwrap();
+            let throttler = throttle::Throttler::new(
+                None,
+                None,
+                CONFIG.net.throttle_burst_kb * 1024,
+                CONFIG.net.throttle_interval_ms,
+                &creg,
+            )
+            .unwrap();
             let acio = acio::ACIO::new(cpoll, creg, chans);
             match control::Control::new(acio, throttler, cdb) {
                 Ok(mut c) => {
@@ -1,24 +1,31 @@
-use std::io::{self, ErrorKind};
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::io::{self, ErrorKind, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
 use std::{fmt, thread};
 
 use amy::{self, Poller, Registrar};
+use net2::TcpBuilder;
 
+use config::IpFamily;
+use socket::{Detached, Socket};
 use torrent::peer::reader::{RRes, Reader};
 use util::UHashMap;
-use {handle, CONFIG};
+use {handle, mse, CONFIG};
 
 pub struct Listener {
     listener: TcpListener,
     lid: usize,
-    incoming: UHashMap<(TcpStream, Reader)>,
+    incoming: UHashMap<(Socket, Reader)>,
+    /// Torrents incoming encrypted handshakes may be for
+    skeys: Arc<mse::Skeys>,
     poll: Poller,
     reg: Registrar,
     ch: handle::Handle<Request, Message>,
 }
 
 pub struct Message {
-    pub conn: TcpStream,
+    pub conn: Detached,
     pub reader: Reader,
     pub id: [u8; 20],
     pub hash: [u8; 20],
@@ -37,6 +44,10 @@ impl fmt::Debug for Message {
 
 #[derive(Debug)]
 pub enum Request {
+    AddTorrent([u8; 20]),
+    RemoveTorrent([u8; 20]),
+    /// Incoming uTP connection, relayed through the stream
+    Utp(UnixStream, SocketAddr),
     Ping,
     Shutdown,
 }
@@ -49,9 +60,26 @@ impl Listener {
     ) -> io::Result<(handle::Handle<Message, Request>, thread::JoinHandle<()>)> {
         let poll = Poller::new()?;
         let mut reg = poll.get_registrar();
-        let ip = Ipv4Addr::new(0, 0, 0, 0);
         let port = CONFIG.port;
-        let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
+        let listener = match CONFIG.net.ip_family {
+            // Don't accept IPv4 connections as mapped addresses
+            IpFamily::Ipv6 => TcpBuilder::new_v6()?
+                .only_v6(true)?
+                .reuse_address(true)?
+                .bind((Ipv6Addr::from([0u8; 16]), port))?
+                .listen(128)?,
+            // IPv4 peers connect through mapped addresses, which are
+            // converted back by the socket
+            IpFamily::Dual => TcpBuilder::new_v6()?
+                .only_v6(false)?
+                .reuse_address(true)?
+                .bind((Ipv6Addr::from([0u8; 16]), port))?
+                .listen(128)?,
+            IpFamily::Ipv4 => {
+                let ip = Ipv4Addr::new(0, 0, 0, 0);
+                TcpListener::bind(SocketAddrV4::new(ip, port))?
+            }
+        };
         listener.set_nonblocking(true)?;
         let lid = reg.register(&listener, amy::Event::Both)?;
 
@@ -61,6 +89,7 @@ impl Listener {
                 listener,
                 lid,
                 incoming: UHashMap::default(),
+                skeys: Arc::new(mse::Skeys::default()),
                 poll,
                 reg,
                 ch: h,
@@ -80,6 +109,19 @@ impl Listener {
                             id if id == self.lid => self.handle_conn(),
                             id if id == self.ch.rx.get_id() => loop {
                                 match self.ch.recv() {
+                                    Ok(Request::AddTorrent(hash)) => {
+                                        Arc::make_mut(&mut self.skeys)
+                                            .insert(mse::req2(&hash), hash);
+                                    }
+                                    Ok(Request::RemoveTorrent(hash)) => {
+                                        Arc::make_mut(&mut self.skeys).remove(&mse::req2(&hash));
+                                    }
+                                    Ok(Request::Utp(conn, addr)) => {
+                                        debug!("Accepted new uTP connection from {:?}!", addr);
+                                        if let Ok(conn) = Socket::utp(conn, addr) {
+                                            self.add_incoming(conn);
+                                        }
+                                    }
                                     Ok(Request::Ping) => continue,
                                     Ok(Request::Shutdown) => return,
                                     _ => break,
@@ -102,10 +144,8 @@ impl Listener {
                     if conn.set_nonblocking(true).is_err() {
                         continue;
                     }
-                    if let Ok(pid) = self.reg.register(&conn, amy::Event::Read) {
-                        self.incoming.insert(pid, (conn, Reader::new()));
-                    } else {
-                        error!("IO poll error, dropping connection!");
+                    if let Ok(conn) = Socket::from_stream(conn) {
+                        self.add_incoming(conn);
                     }
                 }
                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
@@ -118,12 +158,31 @@ impl Listener {
         }
     }
 
+    fn add_incoming(&mut self, mut conn: Socket) {
+        if let Some(methods) = mse::methods(CONFIG.peer.encryption) {
+            conn.accept_mse(self.skeys.clone(), methods);
+        }
+        if let Ok(pid) = self.reg.register(&conn, amy::Event::Both) {
+            self.incoming.insert(pid, (conn, Reader::new()));
+        } else {
+            error!("IO poll error, dropping connection!");
+        }
+    }
+
     fn handle_peer(&mut self, not: amy::Notification) {
         let pid = not.id;
 
         let res = {
             let &mut (ref mut conn, ref mut reader) = self.incoming.get_mut(&pid).unwrap();
-            reader.readable(conn)
+            // Sends the rest of an encrypted handshake reply
+            let flushed = match conn.flush() {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+                res => res,
+            };
+            match flushed {
+                Ok(()) => reader.readable(conn),
+                Err(e) => RRes::Err(e),
+            }
         };
 
         match res {
@@ -138,7 +197,7 @@ impl Listener {
                 if self
                     .ch
                     .send(Message {
-                        conn,
+                        conn: conn.detach(),
                         reader,
                         hash: hsd.0,
                         id: hsd.1,
@@ -3,6 +3,7 @@ use std::fmt;
 #[derive(PartialEq, PartialOrd)]
 pub enum LogLevel {
     Error = 0,
+    Warn,
     Info,
     Debug,
     Trace,
@@ -12,6 +13,7 @@ impl fmt::Display for LogLevel {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             LogLevel::Error => write!(f, "E"),
+            LogLevel::Warn => write!(f, "W"),
             LogLevel::Info => write!(f, "I"),
             LogLevel::Debug => write!(f, "D"),
             LogLevel::Trace => write!(f, "T"),
@@ -61,6 +63,16 @@ macro_rules! info(
     };
 );
 
+#[macro_export]
+macro_rules! warn(
+    ($fmt:expr) => {
+        log!($crate::LogLevel::Warn, $fmt)
+    };
+    ($fmt:expr, $($args:tt)*) => {
+        log!($crate::LogLevel::Warn, $fmt, $($args)*)
+    };
+);
+
 #[macro_export]
 macro_rules! error(
     ($fmt:expr) => {
@@ -22,7 +22,6 @@ extern crate chrono;
 #[macro_use]
 extern crate error_chain;
 extern crate fnv;
-extern crate fs_extra;
 extern crate getopts;
 extern crate http_range;
 extern crate httparse;
@@ -58,6 +57,8 @@ mod disk;
 mod handle;
 mod init;
 mod listener;
+mod mse;
+mod notify;
 mod rpc;
 mod socket;
 mod stat;
@@ -65,6 +66,7 @@ mod throttle;
 mod torrent;
 mod tracker;
 mod util;
+mod utp;
 
 // We need to do this for the log macros
 use log::LogLevel;
@@ -73,12 +75,10 @@ use std::sync::atomic;
 
 pub use protocol::DHT_EXT;
 pub use protocol::EXT_PROTO;
+pub use protocol::FAST_EXT;
 pub use protocol::UT_META_ID;
 pub use protocol::UT_PEX_ID;
 
-/// Throttler max token amount
-pub const THROT_TOKS: usize = 2 * 1024 * 1024;
-
 pub static SHUTDOWN: atomic::AtomicBool = atomic::AtomicBool::new(false);
 
 lazy_static! {
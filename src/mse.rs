@@ -0,0 +1,591 @@
+//! Message stream encryption, the obfuscated handshake negotiating RC4
+//! encryption of peer connections. It precedes the regular bittorrent
+//! handshake, see http://wiki.vuze.com/w/Message_Stream_Encryption.
+
+use std::sync::Arc;
+use std::{cmp, io, mem};
+
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::sha::Sha1;
+use rand::{self, Rng};
+
+use config::Encryption;
+use util::{find_subseq, io_err_val, FHashMap};
+
+/// Prime of the Diffie-Hellman key exchange, the generator is 2
+const P: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                 020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                 4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+
+pub const CRYPTO_PLAIN: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Torrents an incoming handshake may be for, keyed by
+/// `HASH('req2', infohash)` as sent by the initiator.
+pub type Skeys = FHashMap<[u8; 20], [u8; 20]>;
+
+/// Crypto methods offered or accepted under an encryption policy,
+/// None if the encrypted handshake isn't used at all.
+pub fn methods(policy: Encryption) -> Option<u32> {
+    match policy {
+        Encryption::Disabled => None,
+        Encryption::Prefer => Some(CRYPTO_RC4 | CRYPTO_PLAIN),
+        Encryption::Require => Some(CRYPTO_RC4),
+    }
+}
+
+/// Key under which torrent `hash` is found in `Skeys`
+pub fn req2(hash: &[u8; 20]) -> [u8; 20] {
+    hash_of(&[b"req2", hash])
+}
+
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Rc4 {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    /// Creates the cipher used for a stream, which discards
+    /// the first 1KiB of keystream.
+    fn stream(key: &[u8]) -> Rc4 {
+        let mut rc4 = Rc4::new(key);
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *b ^= self.s[k as usize];
+        }
+    }
+}
+
+/// Ciphers of an encrypted connection, one per direction
+pub struct Cipher {
+    enc: Rc4,
+    dec: Rc4,
+}
+
+impl Cipher {
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        self.enc.apply(data);
+    }
+
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.dec.apply(data);
+    }
+}
+
+/// Outcome of a completed handshake
+pub struct Done {
+    /// Cipher of the connection, None if plaintext was selected
+    pub cipher: Option<Cipher>,
+    /// Plaintext received past the handshake
+    pub rest: Vec<u8>,
+}
+
+/// Handshake state machine, fed with the bytes received. The
+/// initiator sends no initial payload, so the bittorrent handshake
+/// only follows once this completes.
+pub struct Handshake {
+    role: Role,
+    /// Crypto methods offered by the initiator, or accepted by the
+    /// responder
+    methods: u32,
+    private: BigNum,
+    state: State,
+    secret: Vec<u8>,
+    cipher: Option<Cipher>,
+    /// Received bytes not yet processed
+    buf: Vec<u8>,
+    /// Initial payload received from the initiator
+    ia: Vec<u8>,
+    out: Vec<u8>,
+}
+
+enum Role {
+    Initiator { hash: [u8; 20] },
+    Responder { skeys: Arc<Skeys> },
+}
+
+enum State {
+    /// Responder only, distinguishes a plaintext handshake
+    Detect,
+    AwaitKey,
+    /// Initiator, looking for the responder's encrypted VC
+    SyncVc([u8; 8]),
+    AwaitSelect,
+    AwaitPadD(usize, u32),
+    /// Responder, looking for `HASH('req1', S)`
+    SyncReq1([u8; 20]),
+    AwaitSkey,
+    AwaitProvide,
+    AwaitPadC(usize, u32),
+    AwaitIa(usize, u32),
+    Done(Option<u32>),
+    Failed,
+}
+
+impl Handshake {
+    /// Starts a handshake for torrent `hash`, offering `methods`.
+    pub fn initiate(hash: [u8; 20], methods: u32) -> Handshake {
+        let mut hs = Handshake::new(Role::Initiator { hash }, methods, State::AwaitKey);
+        hs.send_key();
+        hs
+    }
+
+    /// Awaits a handshake for one of the torrents in `skeys`, which
+    /// may also be a plaintext one if `methods` allows for it.
+    pub fn respond(skeys: Arc<Skeys>, methods: u32) -> Handshake {
+        Handshake::new(Role::Responder { skeys }, methods, State::Detect)
+    }
+
+    fn new(role: Role, methods: u32, state: State) -> Handshake {
+        let mut key = [0u8; 20];
+        rand::thread_rng().fill(&mut key);
+        Handshake {
+            role,
+            methods,
+            private: BigNum::from_slice(&key).unwrap(),
+            state,
+            secret: Vec::new(),
+            cipher: None,
+            buf: Vec::new(),
+            ia: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    /// Bytes to be sent to the peer
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.out, Vec::new())
+    }
+
+    /// Processes received bytes, returning the outcome once the
+    /// handshake is complete.
+    pub fn feed(&mut self, data: &[u8]) -> io::Result<Option<Done>> {
+        self.buf.extend_from_slice(data);
+        while self.step()? {}
+        let selected = match self.state {
+            State::Done(selected) => selected,
+            _ => return Ok(None),
+        };
+        let mut data = mem::replace(&mut self.buf, Vec::new());
+        let cipher = if selected == Some(CRYPTO_RC4) {
+            let mut cipher = self.cipher.take();
+            if let Some(ref mut c) = cipher {
+                c.decrypt(&mut data);
+            }
+            cipher
+        } else {
+            None
+        };
+        let mut rest = mem::replace(&mut self.ia, Vec::new());
+        rest.extend_from_slice(&data);
+        Ok(Some(Done { cipher, rest }))
+    }
+
+    /// Advances the handshake as far as buffered data allows,
+    /// returning whether further progress can be made.
+    fn step(&mut self) -> io::Result<bool> {
+        let state = mem::replace(&mut self.state, State::Failed);
+        let (next, progress) = match state {
+            State::Detect => {
+                if self.buf.len() < 20 {
+                    (State::Detect, false)
+                } else if self.buf[0] == 19 && &self.buf[1..20] == b"BitTorrent protocol" {
+                    if self.methods & CRYPTO_PLAIN == 0 {
+                        return Err(io_err_val("Plaintext connections are disabled"));
+                    }
+                    (State::Done(None), false)
+                } else {
+                    (State::AwaitKey, true)
+                }
+            }
+            State::AwaitKey => {
+                if self.buf.len() < KEY_LEN {
+                    (State::AwaitKey, false)
+                } else {
+                    let key: Vec<_> = self.buf.drain(..KEY_LEN).collect();
+                    self.secret = self.shared_secret(&key)?;
+                    let next = match self.role {
+                        Role::Initiator { hash } => {
+                            let vc = self.send_provide(hash);
+                            State::SyncVc(vc)
+                        }
+                        Role::Responder { .. } => {
+                            self.send_key();
+                            State::SyncReq1(hash_of(&[b"req1", &self.secret]))
+                        }
+                    };
+                    (next, true)
+                }
+            }
+            State::SyncVc(vc) => match find_subseq(&self.buf, &vc) {
+                Some(i) => {
+                    self.buf.drain(..i);
+                    self.decrypt(vc.len());
+                    (State::AwaitSelect, true)
+                }
+                None if self.buf.len() >= MAX_PAD + vc.len() => {
+                    return Err(io_err_val("Encrypted handshake not synchronized"));
+                }
+                None => (State::SyncVc(vc), false),
+            },
+            State::AwaitSelect => {
+                if self.buf.len() < 6 {
+                    (State::AwaitSelect, false)
+                } else {
+                    let data = self.decrypt(6);
+                    let select = read_u32(&data[..4]);
+                    if select.count_ones() != 1 || select & self.methods == 0 {
+                        return Err(io_err_val("Peer selected an unoffered crypto method"));
+                    }
+                    (State::AwaitPadD(pad_len(&data[4..])?, select), true)
+                }
+            }
+            State::AwaitPadD(len, select) => {
+                if self.buf.len() < len {
+                    (State::AwaitPadD(len, select), false)
+                } else {
+                    self.decrypt(len);
+                    (State::Done(Some(select)), false)
+                }
+            }
+            State::SyncReq1(req1) => match find_subseq(&self.buf, &req1) {
+                Some(i) => {
+                    self.buf.drain(..i + req1.len());
+                    (State::AwaitSkey, true)
+                }
+                None if self.buf.len() >= MAX_PAD + req1.len() => {
+                    return Err(io_err_val("Encrypted handshake not synchronized"));
+                }
+                None => (State::SyncReq1(req1), false),
+            },
+            State::AwaitSkey => {
+                if self.buf.len() < 20 {
+                    (State::AwaitSkey, false)
+                } else {
+                    let mut skey = hash_of(&[b"req3", &self.secret]);
+                    for (k, b) in skey.iter_mut().zip(self.buf.drain(..20)) {
+                        *k ^= b;
+                    }
+                    let hash = match self.role {
+                        Role::Responder { ref skeys } => skeys.get(&skey).cloned(),
+                        Role::Initiator { .. } => None,
+                    }
+                    .ok_or_else(|| io_err_val("Handshake for an unknown torrent"))?;
+                    self.cipher = Some(self.keys(&hash, false));
+                    (State::AwaitProvide, true)
+                }
+            }
+            State::AwaitProvide => {
+                if self.buf.len() < 14 {
+                    (State::AwaitProvide, false)
+                } else {
+                    let data = self.decrypt(14);
+                    if data[..8] != VC {
+                        return Err(io_err_val("Invalid encrypted handshake"));
+                    }
+                    let provide = read_u32(&data[8..12]);
+                    (State::AwaitPadC(pad_len(&data[12..])?, provide), true)
+                }
+            }
+            State::AwaitPadC(len, provide) => {
+                if self.buf.len() < len + 2 {
+                    (State::AwaitPadC(len, provide), false)
+                } else {
+                    let data = self.decrypt(len + 2);
+                    let ia_len = (usize::from(data[len]) << 8) | usize::from(data[len + 1]);
+                    (State::AwaitIa(ia_len, provide), true)
+                }
+            }
+            State::AwaitIa(len, provide) => {
+                if self.buf.len() < len {
+                    (State::AwaitIa(len, provide), false)
+                } else {
+                    // The initial payload is always encrypted, unlike the
+                    // data following it if plaintext is selected
+                    self.ia = self.decrypt(len);
+                    let both = provide & self.methods;
+                    let select = if both & CRYPTO_RC4 != 0 {
+                        CRYPTO_RC4
+                    } else if both & CRYPTO_PLAIN != 0 {
+                        CRYPTO_PLAIN
+                    } else {
+                        return Err(io_err_val("No crypto method in common with peer"));
+                    };
+                    self.send_select(select);
+                    (State::Done(Some(select)), false)
+                }
+            }
+            State::Done(selected) => (State::Done(selected), false),
+            State::Failed => return Err(io_err_val("Encrypted handshake failed")),
+        };
+        self.state = next;
+        Ok(progress)
+    }
+
+    fn send_key(&mut self) {
+        let ctx = &mut BigNumContext::new().unwrap();
+        let p = BigNum::from_hex_str(P).unwrap();
+        let g = BigNum::from_u32(2).unwrap();
+        let mut public = BigNum::new().unwrap();
+        public.mod_exp(&g, &self.private, &p, ctx).unwrap();
+        self.out.extend_from_slice(&pad_key(&public.to_vec()));
+        self.send_pad(false);
+    }
+
+    /// Sends the initiator's half of the encrypted handshake, returning
+    /// the encrypted VC expected from the responder.
+    fn send_provide(&mut self, hash: [u8; 20]) -> [u8; 8] {
+        let cipher = self.keys(&hash, true);
+        let req1 = hash_of(&[b"req1", &self.secret]);
+        self.out.extend_from_slice(&req1);
+        let mut skey = req2(&hash);
+        for (k, b) in skey.iter_mut().zip(&hash_of(&[b"req3", &self.secret])) {
+            *k ^= b;
+        }
+        self.out.extend_from_slice(&skey);
+
+        let mut vc = VC;
+        cipher.dec.clone().apply(&mut vc);
+        self.cipher = Some(cipher);
+
+        let start = self.out.len();
+        self.out.extend_from_slice(&VC);
+        self.out.extend_from_slice(&write_u32(self.methods));
+        self.send_pad(true);
+        // No initial payload
+        self.out.extend_from_slice(&[0, 0]);
+        self.encrypt_from(start);
+        vc
+    }
+
+    fn send_select(&mut self, select: u32) {
+        let start = self.out.len();
+        self.out.extend_from_slice(&VC);
+        self.out.extend_from_slice(&write_u32(select));
+        self.send_pad(true);
+        self.encrypt_from(start);
+    }
+
+    /// Appends random padding, prefixed with its length if the
+    /// padding is part of the encrypted handshake.
+    fn send_pad(&mut self, encrypted: bool) {
+        let mut rng = rand::thread_rng();
+        let len = rng.gen_range(0, MAX_PAD + 1);
+        if encrypted {
+            self.out.push((len >> 8) as u8);
+            self.out.push(len as u8);
+            let new_len = self.out.len() + len;
+            self.out.resize(new_len, 0);
+        } else {
+            let start = self.out.len();
+            self.out.resize(start + len, 0);
+            rng.fill(&mut self.out[start..]);
+        }
+    }
+
+    fn encrypt_from(&mut self, start: usize) {
+        if let Some(ref mut c) = self.cipher {
+            c.encrypt(&mut self.out[start..]);
+        }
+    }
+
+    /// Removes and decrypts `len` buffered bytes
+    fn decrypt(&mut self, len: usize) -> Vec<u8> {
+        let mut data: Vec<_> = self.buf.drain(..len).collect();
+        if let Some(ref mut c) = self.cipher {
+            c.decrypt(&mut data);
+        }
+        data
+    }
+
+    fn shared_secret(&self, key: &[u8]) -> io::Result<Vec<u8>> {
+        let ctx = &mut BigNumContext::new().unwrap();
+        let p = BigNum::from_hex_str(P).unwrap();
+        let remote = BigNum::from_slice(key).unwrap();
+        let one = BigNum::from_u32(1).unwrap();
+        if remote <= one || remote >= p {
+            return Err(io_err_val("Invalid encryption key"));
+        }
+        let mut secret = BigNum::new().unwrap();
+        secret.mod_exp(&remote, &self.private, &p, ctx).unwrap();
+        Ok(pad_key(&secret.to_vec()))
+    }
+
+    fn keys(&self, hash: &[u8; 20], initiator: bool) -> Cipher {
+        let a = Rc4::stream(&hash_of(&[b"keyA", &self.secret, hash]));
+        let b = Rc4::stream(&hash_of(&[b"keyB", &self.secret, hash]));
+        if initiator {
+            Cipher { enc: a, dec: b }
+        } else {
+            Cipher { enc: b, dec: a }
+        }
+    }
+}
+
+fn hash_of(parts: &[&[u8]]) -> [u8; 20] {
+    let mut ctx = Sha1::new();
+    for part in parts {
+        ctx.update(part);
+    }
+    ctx.finish()
+}
+
+fn pad_key(key: &[u8]) -> Vec<u8> {
+    let mut padded = vec![0u8; KEY_LEN - cmp::min(key.len(), KEY_LEN)];
+    padded.extend_from_slice(key);
+    padded
+}
+
+fn pad_len(data: &[u8]) -> io::Result<usize> {
+    let len = (usize::from(data[0]) << 8) | usize::from(data[1]);
+    if len > MAX_PAD {
+        return Err(io_err_val("Encrypted handshake padding too long"));
+    }
+    Ok(len)
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |n, &b| (n << 8) | u32::from(b))
+}
+
+fn write_u32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{req2, Done, Handshake, Rc4, Skeys, CRYPTO_PLAIN, CRYPTO_RC4};
+    use std::sync::Arc;
+
+    const HASH: [u8; 20] = [7u8; 20];
+
+    fn skeys() -> Arc<Skeys> {
+        let mut skeys = Skeys::default();
+        skeys.insert(req2(&HASH), HASH);
+        Arc::new(skeys)
+    }
+
+    /// Passes data back and forth until both sides are done
+    fn exchange(init: &mut Handshake, resp: &mut Handshake) -> (Done, Done) {
+        let (mut di, mut dr) = (None, None);
+        for _ in 0..3 {
+            if dr.is_none() {
+                dr = resp.feed(&init.take_output()).unwrap();
+            }
+            if di.is_none() {
+                di = init.feed(&resp.take_output()).unwrap();
+            }
+        }
+        (di.unwrap(), dr.unwrap())
+    }
+
+    #[test]
+    fn test_rc4() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+        let mut data = b"pedia".to_vec();
+        Rc4::new(b"Wiki").apply(&mut data);
+        assert_eq!(data, [0x10, 0x21, 0xBF, 0x04, 0x20]);
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let mut init = Handshake::initiate(HASH, CRYPTO_RC4 | CRYPTO_PLAIN);
+        let mut resp = Handshake::respond(skeys(), CRYPTO_RC4 | CRYPTO_PLAIN);
+        let (mut di, mut dr) = exchange(&mut init, &mut resp);
+        assert!(di.rest.is_empty() && dr.rest.is_empty());
+
+        let (ci, cr) = (di.cipher.as_mut().unwrap(), dr.cipher.as_mut().unwrap());
+        let mut data = b"\x13BitTorrent protocol".to_vec();
+        ci.encrypt(&mut data);
+        assert_ne!(&data[1..], b"BitTorrent protocol");
+        cr.decrypt(&mut data);
+        assert_eq!(&data[1..], b"BitTorrent protocol");
+        let mut data = b"reply".to_vec();
+        cr.encrypt(&mut data);
+        ci.decrypt(&mut data);
+        assert_eq!(data, b"reply");
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut init = Handshake::initiate(HASH, CRYPTO_RC4);
+        let mut resp = Handshake::respond(skeys(), CRYPTO_RC4);
+        let data = init.take_output();
+        resp.feed(&data).unwrap();
+        init.feed(&resp.take_output()).unwrap();
+        let dr = resp.feed(&init.take_output()).unwrap().unwrap();
+
+        // The responder's first message arrives with its handshake
+        let mut cr = dr.cipher.unwrap();
+        let mut data = resp.take_output();
+        let mut msg = b"message".to_vec();
+        cr.encrypt(&mut msg);
+        data.extend_from_slice(&msg);
+        let di = init.feed(&data).unwrap().unwrap();
+        assert_eq!(di.rest, b"message");
+    }
+
+    #[test]
+    fn test_plaintext_selected() {
+        let mut init = Handshake::initiate(HASH, CRYPTO_RC4 | CRYPTO_PLAIN);
+        let mut resp = Handshake::respond(skeys(), CRYPTO_PLAIN);
+        let (di, dr) = exchange(&mut init, &mut resp);
+        assert!(di.cipher.is_none() && dr.cipher.is_none());
+
+        // Only plaintext is accepted, but the peer requires encryption
+        let mut init = Handshake::initiate(HASH, CRYPTO_RC4);
+        let mut resp = Handshake::respond(skeys(), CRYPTO_PLAIN);
+        resp.feed(&init.take_output()).unwrap();
+        init.feed(&resp.take_output()).unwrap();
+        assert!(resp.feed(&init.take_output()).is_err());
+    }
+
+    #[test]
+    fn test_plain_handshake() {
+        let hs = b"\x13BitTorrent protocol\0\0\0\0\0\0\0\0";
+        let mut resp = Handshake::respond(skeys(), CRYPTO_RC4 | CRYPTO_PLAIN);
+        let done = resp.feed(hs).unwrap().unwrap();
+        assert!(done.cipher.is_none());
+        assert_eq!(&done.rest[..], &hs[..]);
+
+        let mut resp = Handshake::respond(skeys(), CRYPTO_RC4);
+        assert!(resp.feed(hs).is_err());
+    }
+
+    #[test]
+    fn test_unknown_torrent() {
+        let mut init = Handshake::initiate([1u8; 20], CRYPTO_RC4);
+        let mut resp = Handshake::respond(skeys(), CRYPTO_RC4);
+        resp.feed(&init.take_output()).unwrap();
+        init.feed(&resp.take_output()).unwrap();
+        assert!(resp.feed(&init.take_output()).is_err());
+    }
+}
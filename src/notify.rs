@@ -0,0 +1,424 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Child, Command};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use httparse;
+use serde_json;
+use url::Url;
+
+use config::{NotifyConfig, NotifyEvent};
+use util::io_err_val;
+use CONFIG;
+
+/// Timeout for connecting to and communicating with a webhook
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+/// Time a notification command may run before it's killed
+const COMMAND_TIMEOUT_SECS: u64 = 60;
+/// Interval at which running commands are checked for exit
+const COMMAND_POLL_MS: u64 = 250;
+
+/// A notable event in a torrent's lifetime
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Event {
+    pub event: NotifyEvent,
+    /// RPC ID of the torrent
+    pub torrent: String,
+    pub name: Option<String>,
+    /// Error or tracker failure reason
+    pub detail: Option<String>,
+}
+
+/// Dispatches events to the configured webhook and command from
+/// a separate thread, so slow endpoints never block the event loop.
+pub struct Notifier {
+    tx: Option<mpsc::Sender<Event>>,
+}
+
+impl Notifier {
+    pub fn start() -> Notifier {
+        let cfg = CONFIG.notify.clone();
+        if cfg.webhook.is_none() && cfg.command.is_none() {
+            return Notifier { tx: None };
+        }
+        let (tx, rx) = mpsc::channel::<Event>();
+        let res = thread::Builder::new()
+            .name("notify".to_owned())
+            .spawn(move || Dispatcher::new(cfg).run(&rx));
+        match res {
+            Ok(_) => Notifier { tx: Some(tx) },
+            Err(e) => {
+                error!("Failed to start notification thread: {}", e);
+                Notifier { tx: None }
+            }
+        }
+    }
+
+    /// Queues `event` for delivery if its type is enabled.
+    pub fn notify(&self, event: Event) {
+        if !CONFIG.notify.events.contains(&event.event) {
+            return;
+        }
+        if let Some(ref tx) = self.tx {
+            tx.send(event).ok();
+        }
+    }
+}
+
+/// A webhook delivery waiting for its next attempt
+struct Retry {
+    body: Vec<u8>,
+    attempt: u32,
+    delay: Duration,
+    due: Instant,
+}
+
+/// A notification command which hasn't exited yet
+struct Running {
+    child: Child,
+    deadline: Instant,
+}
+
+/// Delivers events without ever waiting on a single one: failed
+/// webhooks are retried once due while other events keep being
+/// delivered, and commands are reaped, or killed once they run too
+/// long, in between.
+struct Dispatcher {
+    cfg: NotifyConfig,
+    retries: Vec<Retry>,
+    running: Vec<Running>,
+}
+
+impl Dispatcher {
+    fn new(cfg: NotifyConfig) -> Dispatcher {
+        Dispatcher {
+            cfg,
+            retries: Vec::new(),
+            running: Vec::new(),
+        }
+    }
+
+    fn run(mut self, rx: &mpsc::Receiver<Event>) {
+        loop {
+            let res = match self.next_wakeup(Instant::now()) {
+                Some(wait) => rx.recv_timeout(wait),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match res {
+                Ok(event) => self.deliver(&event, Instant::now()),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let now = Instant::now();
+            self.retry_due(now);
+            self.reap(now);
+        }
+    }
+
+    /// Time until retries or running commands need attention, if any.
+    fn next_wakeup(&self, now: Instant) -> Option<Duration> {
+        let retry = self
+            .retries
+            .iter()
+            .map(|r| {
+                if r.due > now {
+                    r.due - now
+                } else {
+                    Duration::from_secs(0)
+                }
+            })
+            .min();
+        let poll = if self.running.is_empty() {
+            None
+        } else {
+            Some(Duration::from_millis(COMMAND_POLL_MS))
+        };
+        match (retry, poll) {
+            (Some(r), Some(p)) => Some(if r < p { r } else { p }),
+            (r, p) => r.or(p),
+        }
+    }
+
+    fn deliver(&mut self, event: &Event, now: Instant) {
+        if let Some(ref url) = self.cfg.webhook {
+            let retry = Retry {
+                body: serde_json::to_vec(event).expect("Events are always serializable"),
+                attempt: 0,
+                delay: Duration::from_secs(self.cfg.retry_secs),
+                due: now,
+            };
+            if let Some(r) = attempt(url, self.cfg.retries, retry, now) {
+                self.retries.push(r);
+            }
+        }
+
+        if let Some(ref cmd) = self.cfg.command {
+            let res = Command::new(cmd)
+                .env("SYNAPSE_EVENT", event.event.as_str())
+                .env("SYNAPSE_TORRENT", &event.torrent)
+                .env(
+                    "SYNAPSE_NAME",
+                    event.name.as_ref().map(String::as_str).unwrap_or(""),
+                )
+                .env(
+                    "SYNAPSE_DETAIL",
+                    event.detail.as_ref().map(String::as_str).unwrap_or(""),
+                )
+                .spawn();
+            match res {
+                Ok(child) => self.running.push(Running {
+                    child,
+                    deadline: now + Duration::from_secs(COMMAND_TIMEOUT_SECS),
+                }),
+                Err(e) => error!("Failed to run notification command {}: {}", cmd, e),
+            }
+        }
+    }
+
+    /// Makes the next attempt of every webhook delivery which is due.
+    fn retry_due(&mut self, now: Instant) {
+        let url = match self.cfg.webhook {
+            Some(ref url) => url,
+            None => return,
+        };
+        let retries = self.cfg.retries;
+        let (due, waiting): (Vec<_>, Vec<_>) = self.retries.drain(..).partition(|r| r.due <= now);
+        self.retries = waiting;
+        for r in due {
+            if let Some(r) = attempt(url, retries, r, now) {
+                self.retries.push(r);
+            }
+        }
+    }
+
+    /// Reaps commands which exited, killing those past their deadline.
+    fn reap(&mut self, now: Instant) {
+        let cmd = self.cfg.command.as_ref().map(String::as_str).unwrap_or("");
+        let mut i = 0;
+        while i < self.running.len() {
+            let done = {
+                let r = &mut self.running[i];
+                match r.child.try_wait() {
+                    Ok(Some(status)) => {
+                        if !status.success() {
+                            warn!("Notification command {} failed: {}", cmd, status);
+                        }
+                        true
+                    }
+                    Ok(None) if now >= r.deadline => {
+                        warn!("Notification command {} timed out, killing it", cmd);
+                        r.child.kill().ok();
+                        r.child.wait().ok();
+                        true
+                    }
+                    Ok(None) => false,
+                    Err(e) => {
+                        error!("Failed to wait on notification command {}: {}", cmd, e);
+                        true
+                    }
+                }
+            };
+            if done {
+                self.running.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Attempts a webhook delivery, returning the retry to schedule if it
+/// failed and attempts remain.
+fn attempt(url: &str, retries: u32, mut r: Retry, now: Instant) -> Option<Retry> {
+    match post(url, &r.body) {
+        Ok(()) => None,
+        Err(e) => {
+            if r.attempt >= retries {
+                error!("Failed to deliver webhook to {}: {}", url, e);
+                return None;
+            }
+            warn!("Failed to deliver webhook to {}, retrying: {}", url, e);
+            r.due = now + r.delay;
+            r.delay *= 2;
+            r.attempt += 1;
+            Some(r)
+        }
+    }
+}
+
+/// POSTs a JSON body to a http webhook, failing unless it
+/// responds with a 2xx status.
+fn post(url: &str, body: &[u8]) -> io::Result<()> {
+    let url = Url::parse(url).map_err(|_| io_err_val("Invalid webhook URL"))?;
+    if url.scheme() != "http" {
+        return Err(io_err_val("Only http webhooks are supported"));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| io_err_val("Webhook URL has no host"))?;
+    let port = url.port().unwrap_or(80);
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io_err_val("Couldn't resolve webhook host"))?;
+
+    let timeout = Duration::from_secs(WEBHOOK_TIMEOUT_SECS);
+    let mut conn = TcpStream::connect_timeout(&addr, timeout)?;
+    conn.set_read_timeout(Some(timeout))?;
+    conn.set_write_timeout(Some(timeout))?;
+
+    let mut path = url.path().to_owned();
+    if let Some(q) = url.query() {
+        path.push('?');
+        path.push_str(q);
+    }
+    write!(
+        conn,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        port,
+        body.len()
+    )?;
+    conn.write_all(body)?;
+
+    let mut resp = Vec::new();
+    conn.read_to_end(&mut resp)?;
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut r = httparse::Response::new(&mut headers);
+    match r.parse(&resp) {
+        Ok(_) => match r.code {
+            Some(code) if (200..300).contains(&code) => Ok(()),
+            Some(_) => Err(io_err_val("Webhook returned an error status")),
+            None => Err(io_err_val("Incomplete webhook response")),
+        },
+        Err(_) => Err(io_err_val("Invalid webhook response")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dispatcher, Event};
+    use config::{NotifyConfig, NotifyEvent};
+    use httparse;
+    use serde_json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn test_event() -> Event {
+        Event {
+            event: NotifyEvent::Completed,
+            torrent: "ab".repeat(20),
+            name: Some("ubuntu.iso".to_owned()),
+            detail: None,
+        }
+    }
+
+    /// Accepts a single request, returning its path and body.
+    fn serve(lst: TcpListener) -> (String, Vec<u8>) {
+        let (mut conn, _) = lst.accept().unwrap();
+        let mut data = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = conn.read(&mut buf).unwrap();
+            assert!(n > 0, "Connection closed before the request was complete");
+            data.extend_from_slice(&buf[..n]);
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut req = httparse::Request::new(&mut headers);
+            if let Ok(httparse::Status::Complete(len)) = req.parse(&data) {
+                assert_eq!(req.method, Some("POST"));
+                let clen: usize = req
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+                    .map(|h| String::from_utf8_lossy(h.value).parse().unwrap())
+                    .unwrap();
+                if data.len() >= len + clen {
+                    conn.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+                    let path = req.path.unwrap().to_owned();
+                    return (path, data[len..len + clen].to_vec());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_completion_webhook() {
+        let lst = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = lst.local_addr().unwrap().port();
+        let server = thread::spawn(move || serve(lst));
+
+        let cfg = NotifyConfig {
+            webhook: Some(format!("http://127.0.0.1:{}/hook?k=v", port)),
+            command: None,
+            events: vec![NotifyEvent::Completed],
+            retries: 0,
+            retry_secs: 0,
+        };
+        Dispatcher::new(cfg).deliver(&test_event(), Instant::now());
+
+        let (path, body) = server.join().unwrap();
+        assert_eq!(path, "/hook?k=v");
+        let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(v["event"], "completed");
+        assert_eq!(v["torrent"], "ab".repeat(20));
+        assert_eq!(v["name"], "ubuntu.iso");
+        assert!(v["detail"].is_null());
+    }
+
+    #[test]
+    fn test_retry_doesnt_block() {
+        // Nothing listens on the port once the listener is dropped
+        let port = {
+            let lst = TcpListener::bind("127.0.0.1:0").unwrap();
+            lst.local_addr().unwrap().port()
+        };
+        let cfg = NotifyConfig {
+            webhook: Some(format!("http://127.0.0.1:{}/hook", port)),
+            command: None,
+            events: vec![NotifyEvent::Completed],
+            retries: 1,
+            retry_secs: 3600,
+        };
+        let mut d = Dispatcher::new(cfg);
+        let now = Instant::now();
+
+        // Failed deliveries are queued rather than waited on
+        d.deliver(&test_event(), now);
+        d.deliver(&test_event(), now);
+        assert_eq!(d.retries.len(), 2);
+        assert_eq!(d.next_wakeup(now), Some(Duration::from_secs(3600)));
+        d.retry_due(now);
+        assert_eq!(d.retries.len(), 2);
+
+        // The last attempt fails for good
+        d.retry_due(now + Duration::from_secs(3600));
+        assert!(d.retries.is_empty());
+        assert_eq!(d.next_wakeup(now), None);
+    }
+
+    #[test]
+    fn test_command_reaped() {
+        let cfg = NotifyConfig {
+            webhook: None,
+            command: Some("true".to_owned()),
+            events: vec![NotifyEvent::Completed],
+            retries: 0,
+            retry_secs: 0,
+        };
+        let mut d = Dispatcher::new(cfg);
+        let now = Instant::now();
+        d.deliver(&test_event(), now);
+        assert_eq!(d.running.len(), 1);
+        assert!(d.next_wakeup(now).is_some());
+
+        // Whether it exited or is killed, it's gone past its deadline
+        d.reap(now + Duration::from_secs(60));
+        assert!(d.running.is_empty());
+    }
+}
@@ -35,8 +35,18 @@ pub struct Incoming {
 pub enum IncomingStatus {
     Incomplete,
     Upgrade,
-    Transfer { data: Vec<u8>, token: String },
-    DL { id: String, range: Option<String> },
+    Transfer {
+        data: Vec<u8>,
+        token: String,
+    },
+    DL {
+        id: String,
+        range: Option<String>,
+        /// Whether data is served as the torrent downloads it
+        stream: bool,
+    },
+    #[cfg(feature = "metrics")]
+    Metrics,
 }
 
 enum FragBuf {
@@ -230,13 +240,22 @@ impl Incoming {
                     }
                     Err(false) => {}
                 }
+                #[cfg(feature = "metrics")]
+                match validate_metrics(&req) {
+                    Ok(()) => return Ok(Some(IncomingStatus::Metrics)),
+                    Err(true) => {
+                        self.conn.write(&UNAUTH_HTTP_RESP).ok();
+                        return Err(io::ErrorKind::InvalidData.into());
+                    }
+                    Err(false) => {}
+                }
                 if let Some(token) = validate_tx(&req) {
                     Ok(Some(IncomingStatus::Transfer {
                         data: self.buf[idx..self.pos].to_owned(),
                         token,
                     }))
-                } else if let Some((id, range)) = validate_dl(&req) {
-                    Ok(Some(IncomingStatus::DL { id, range }))
+                } else if let Some((id, range, stream)) = validate_dl(&req) {
+                    Ok(Some(IncomingStatus::DL { id, range, stream }))
                 } else {
                     // Ignore error, we're DCing anyways
                     self.conn.write(&EMPTY_HTTP_RESP).ok();
@@ -290,11 +309,12 @@ impl FragBuf {
     }
 }
 
-fn validate_dl(req: &httparse::Request) -> Option<(String, Option<String>)> {
+fn validate_dl(req: &httparse::Request) -> Option<(String, Option<String>, bool)> {
     req.path
         .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok())
         .and_then(|url| {
-            let id = if url.path().contains("/dl/") {
+            let stream = url.path().contains("/stream/");
+            let id = if url.path().contains("/dl/") || stream {
                 url.path_segments().unwrap().last().map(|v| v.to_owned())
             } else {
                 return None;
@@ -321,16 +341,16 @@ fn validate_dl(req: &httparse::Request) -> Option<(String, Option<String>)> {
                     return None;
                 }
             }
-            id
+            id.map(|id| (id, stream))
         })
-        .map(|id| {
+        .map(|(id, stream)| {
             let range = req
                 .headers
                 .iter()
                 .find(|header| header.name.to_lowercase() == "range")
                 .and_then(|header| str::from_utf8(header.value).ok())
                 .map(str::to_owned);
-            (id, range)
+            (id, range, stream)
         })
 }
 
@@ -381,41 +401,8 @@ fn validate_upgrade(req: &httparse::Request) -> result::Result<String, bool> {
         return Err(false);
     }
 
-    if CONFIG.rpc.auth {
-        let auth = req
-            .path
-            .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok())
-            .and_then(|url| {
-                url.query_pairs()
-                    .find(|&(ref k, _)| k == "password")
-                    .map(|(_, v)| format!("{}", v))
-                    .map(|p| p == CONFIG.rpc.password)
-            })
-            .or_else(|| {
-                req.headers
-                    .iter()
-                    .find(|header| header.name.to_lowercase() == "authorization")
-                    .and_then(|header| str::from_utf8(header.value).ok())
-                    .and_then(|value| {
-                        if value.to_lowercase().starts_with("basic ") {
-                            let (_, auth) = value.split_at(6);
-                            Some(auth)
-                        } else {
-                            None
-                        }
-                    })
-                    .and_then(|auth| base64::decode(auth).ok())
-                    .and_then(|auth| String::from_utf8(auth).ok())
-                    .and_then(|auth| {
-                        auth.split_terminator(':')
-                            .last()
-                            .map(|password| password == CONFIG.rpc.password)
-                    })
-            })
-            .unwrap_or(false);
-        if !auth {
-            return Err(true);
-        }
+    if !authorized(req) {
+        return Err(true);
     }
 
     if let Some(k) = key {
@@ -424,3 +411,126 @@ fn validate_upgrade(req: &httparse::Request) -> result::Result<String, bool> {
         Err(false)
     }
 }
+
+#[cfg(feature = "metrics")]
+fn validate_metrics(req: &httparse::Request) -> result::Result<(), bool> {
+    if !req.method.map(|m| m == "GET").unwrap_or(false) {
+        return Err(false);
+    }
+    let path = req
+        .path
+        .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok());
+    if path.map(|url| url.path() != "/metrics").unwrap_or(true) {
+        return Err(false);
+    }
+    if !authorized(req) {
+        return Err(true);
+    }
+    Ok(())
+}
+
+/// Checks the RPC password, given either as a query parameter or
+/// through basic auth.
+fn authorized(req: &httparse::Request) -> bool {
+    if !CONFIG.rpc.auth {
+        return true;
+    }
+    req.path
+        .and_then(|path| Url::parse(&format!("http://localhost{}", path)).ok())
+        .and_then(|url| {
+            url.query_pairs()
+                .find(|&(ref k, _)| k == "password")
+                .map(|(_, v)| format!("{}", v))
+                .map(|p| p == CONFIG.rpc.password)
+        })
+        .or_else(|| {
+            req.headers
+                .iter()
+                .find(|header| header.name.to_lowercase() == "authorization")
+                .and_then(|header| str::from_utf8(header.value).ok())
+                .and_then(|value| {
+                    if value.to_lowercase().starts_with("basic ") {
+                        let (_, auth) = value.split_at(6);
+                        Some(auth)
+                    } else {
+                        None
+                    }
+                })
+                .and_then(|auth| base64::decode(auth).ok())
+                .and_then(|auth| String::from_utf8(auth).ok())
+                .and_then(|auth| {
+                    auth.split_terminator(':')
+                        .last()
+                        .map(|password| password == CONFIG.rpc.password)
+                })
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::bind_unix;
+    use super::{Client, Incoming, IncomingStatus};
+    use rpc::proto::message::CMessage;
+    use rpc::proto::ws::Frame;
+    use serde_json;
+    use socket::TSocket;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::{env, fs, process, time};
+
+    #[test]
+    fn test_unix_socket_rpc() {
+        let path = env::temp_dir().join(format!("synapse-rpc-{}.sock", process::id()));
+        let path = path.to_str().unwrap();
+        drop(bind_unix(path, 0o600).unwrap());
+        // A stale socket from a previous run is replaced
+        let listener = bind_unix(path, 0o600).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut local = UnixStream::connect(path).unwrap();
+        local
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut incoming = Incoming::new(TSocket::from_unix(conn).unwrap());
+
+        local
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                  Host: localhost\r\n\
+                  Upgrade: websocket\r\n\
+                  Connection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        match incoming.readable().unwrap() {
+            IncomingStatus::Upgrade => {}
+            _ => panic!("Upgrade over the socket failed"),
+        }
+        let mut client: Client = incoming.into();
+        let mut resp = [0u8; 1024];
+        let n = local.read(&mut resp).unwrap();
+        assert!(resp[..n].starts_with(b"HTTP/1.1 101"));
+
+        // Masked text frame carrying a command
+        let cmd = br#"{"type":"GET_RESOURCES","serial":1,"ids":[]}"#;
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![0x81, 0x80 | cmd.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(cmd.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        local.write_all(&frame).unwrap();
+
+        match client.read().unwrap() {
+            Some(Frame::Text(t)) => match serde_json::from_str(&t).unwrap() {
+                CMessage::GetResources { serial: 1, .. } => {}
+                m => panic!("Unexpected message {:?}", m),
+            },
+            _ => panic!("Command not received"),
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+//! Renders the RPC resources in the Prometheus text exposition format,
+//! served at `/metrics` when built with the `metrics` feature.
+
+use std::fmt::Write;
+
+use super::proto::resource::{Resource, Server, Status, Torrent};
+
+const STATUSES: [Status; 12] = [
+    Status::Pending,
+    Status::Magnet,
+    Status::MetadataQueued,
+    Status::Paused,
+    Status::Leeching,
+    Status::Idle,
+    Status::Seeding,
+    Status::Hashing,
+    Status::Moving,
+    Status::Completing,
+    Status::Unregistered,
+    Status::Error,
+];
+
+/// Renders metrics for the given resources. Per torrent metrics are
+/// only emitted for the `max_torrents` most active torrents, to bound
+/// the number of series.
+pub fn render<'a, I: Iterator<Item = &'a Resource>>(resources: I, max_torrents: usize) -> String {
+    let mut server = None;
+    let mut torrents = Vec::new();
+    let mut trackers = 0;
+    let mut failing = 0;
+    for r in resources {
+        match *r {
+            Resource::Server(ref s) => server = Some(s),
+            Resource::Torrent(ref t) => torrents.push(t),
+            Resource::Tracker(ref t) => {
+                trackers += 1;
+                if t.error.is_some() {
+                    failing += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut out = String::new();
+    if let Some(s) = server {
+        render_server(&mut out, s);
+    }
+
+    header(&mut out, "synapse_torrents", "gauge", "Torrents by status");
+    for status in &STATUSES {
+        let count = torrents.iter().filter(|t| t.status == *status).count();
+        writeln!(
+            out,
+            "synapse_torrents{{status=\"{}\"}} {}",
+            status.as_str(),
+            count
+        )
+        .unwrap();
+    }
+    header(&mut out, "synapse_peers", "gauge", "Connected peers");
+    let peers: u64 = torrents.iter().map(|t| u64::from(t.peers)).sum();
+    writeln!(out, "synapse_peers {}", peers).unwrap();
+    header(&mut out, "synapse_trackers", "gauge", "Trackers");
+    writeln!(out, "synapse_trackers {}", trackers).unwrap();
+    header(
+        &mut out,
+        "synapse_trackers_failing",
+        "gauge",
+        "Trackers whose last announce failed",
+    );
+    writeln!(out, "synapse_trackers_failing {}", failing).unwrap();
+
+    torrents.sort_by(|a, b| {
+        (b.rate_up + b.rate_down)
+            .cmp(&(a.rate_up + a.rate_down))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    let omitted = torrents.len().saturating_sub(max_torrents);
+    torrents.truncate(max_torrents);
+    header(
+        &mut out,
+        "synapse_torrents_omitted",
+        "gauge",
+        "Torrents left out of the per torrent metrics",
+    );
+    writeln!(out, "synapse_torrents_omitted {}", omitted).unwrap();
+    render_torrents(&mut out, &torrents);
+    out
+}
+
+fn render_server(out: &mut String, s: &Server) {
+    header(
+        out,
+        "synapse_rate_bytes",
+        "gauge",
+        "Transfer rate in bytes/s",
+    );
+    writeln!(out, "synapse_rate_bytes{{direction=\"up\"}} {}", s.rate_up).unwrap();
+    writeln!(
+        out,
+        "synapse_rate_bytes{{direction=\"down\"}} {}",
+        s.rate_down
+    )
+    .unwrap();
+    header(
+        out,
+        "synapse_transferred_bytes_total",
+        "counter",
+        "Bytes transferred over all sessions",
+    );
+    writeln!(
+        out,
+        "synapse_transferred_bytes_total{{direction=\"up\"}} {}",
+        s.transferred_up
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "synapse_transferred_bytes_total{{direction=\"down\"}} {}",
+        s.transferred_down
+    )
+    .unwrap();
+    header(
+        out,
+        "synapse_free_space_bytes",
+        "gauge",
+        "Free space in the download directory",
+    );
+    writeln!(out, "synapse_free_space_bytes {}", s.free_space).unwrap();
+    header(
+        out,
+        "synapse_write_cache_reads_total",
+        "counter",
+        "Disk reads by whether they were served from the write cache",
+    );
+    writeln!(
+        out,
+        "synapse_write_cache_reads_total{{result=\"hit\"}} {}",
+        s.write_cache_hits
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "synapse_write_cache_reads_total{{result=\"miss\"}} {}",
+        s.write_cache_misses
+    )
+    .unwrap();
+    header(
+        out,
+        "synapse_disk_bytes_total",
+        "counter",
+        "Bytes read from and written to downloaded files",
+    );
+    writeln!(
+        out,
+        "synapse_disk_bytes_total{{direction=\"read\"}} {}",
+        s.disk_read
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "synapse_disk_bytes_total{{direction=\"write\"}} {}",
+        s.disk_written
+    )
+    .unwrap();
+    header(
+        out,
+        "synapse_dht_nodes",
+        "gauge",
+        "Nodes in the DHT routing table",
+    );
+    writeln!(out, "synapse_dht_nodes {}", s.dht_nodes).unwrap();
+    header(
+        out,
+        "synapse_conn_queue",
+        "gauge",
+        "Peers waiting to be connected to",
+    );
+    writeln!(out, "synapse_conn_queue {}", s.conn_queue).unwrap();
+    header(
+        out,
+        "synapse_conn_queue_dropped_total",
+        "counter",
+        "Peers dropped from a full connection queue",
+    );
+    writeln!(
+        out,
+        "synapse_conn_queue_dropped_total {}",
+        s.conn_queue_dropped
+    )
+    .unwrap();
+    header(
+        out,
+        "synapse_conn_limit",
+        "gauge",
+        "Maximum number of peer connections",
+    );
+    writeln!(out, "synapse_conn_limit {}", s.conn_limit).unwrap();
+    header(
+        out,
+        "synapse_conns_reclaimed_total",
+        "counter",
+        "Connections reclaimed for higher priority torrents",
+    );
+    writeln!(out, "synapse_conns_reclaimed_total {}", s.conns_reclaimed).unwrap();
+}
+
+fn render_torrents(out: &mut String, torrents: &[&Torrent]) {
+    let labels: Vec<_> = torrents
+        .iter()
+        .map(|t| {
+            format!(
+                "id=\"{}\",name=\"{}\"",
+                t.id,
+                escape(t.name.as_ref().map(String::as_str).unwrap_or(""))
+            )
+        })
+        .collect();
+
+    header(
+        out,
+        "synapse_torrent_info",
+        "gauge",
+        "Torrent status, always 1",
+    );
+    for (t, l) in torrents.iter().zip(&labels) {
+        writeln!(
+            out,
+            "synapse_torrent_info{{{},status=\"{}\"}} 1",
+            l,
+            t.status.as_str()
+        )
+        .unwrap();
+    }
+    header(
+        out,
+        "synapse_torrent_rate_bytes",
+        "gauge",
+        "Torrent transfer rate in bytes/s",
+    );
+    for (t, l) in torrents.iter().zip(&labels) {
+        writeln!(
+            out,
+            "synapse_torrent_rate_bytes{{{},direction=\"up\"}} {}",
+            l, t.rate_up
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "synapse_torrent_rate_bytes{{{},direction=\"down\"}} {}",
+            l, t.rate_down
+        )
+        .unwrap();
+    }
+    header(
+        out,
+        "synapse_torrent_transferred_bytes_total",
+        "counter",
+        "Bytes transferred by a torrent",
+    );
+    for (t, l) in torrents.iter().zip(&labels) {
+        writeln!(
+            out,
+            "synapse_torrent_transferred_bytes_total{{{},direction=\"up\"}} {}",
+            l, t.transferred_up
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "synapse_torrent_transferred_bytes_total{{{},direction=\"down\"}} {}",
+            l, t.transferred_down
+        )
+        .unwrap();
+    }
+    header(out, "synapse_torrent_peers", "gauge", "Torrent peers");
+    for (t, l) in torrents.iter().zip(&labels) {
+        writeln!(out, "synapse_torrent_peers{{{}}} {}", l, t.peers).unwrap();
+    }
+    header(
+        out,
+        "synapse_torrent_progress",
+        "gauge",
+        "Torrent download progress, 0 to 1",
+    );
+    for (t, l) in torrents.iter().zip(&labels) {
+        writeln!(out, "synapse_torrent_progress{{{}}} {}", l, t.progress).unwrap();
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Escapes a label value
+fn escape(v: &str) -> String {
+    let mut s = String::with_capacity(v.len());
+    for c in v.chars() {
+        match c {
+            '\\' => s.push_str("\\\\"),
+            '"' => s.push_str("\\\""),
+            '\n' => s.push_str("\\n"),
+            c => s.push(c),
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use rpc::proto::resource::{Resource, Server, Status, Torrent, Tracker};
+    use std::collections::{HashMap, HashSet};
+
+    /// Parses the text format, checking that every sample belongs to a
+    /// declared metric and returning the samples by name and labels.
+    fn parse(text: &str) -> HashMap<String, f64> {
+        let mut types = HashSet::new();
+        let mut samples = HashMap::new();
+        for line in text.lines() {
+            if line.starts_with("# TYPE ") {
+                let mut parts = line[7..].split(' ');
+                let name = parts.next().unwrap();
+                let kind = parts.next().unwrap();
+                assert!(["gauge", "counter"].contains(&kind));
+                assert!(types.insert(name.to_owned()), "{} declared twice", name);
+                continue;
+            }
+            if line.starts_with("# HELP ") {
+                continue;
+            }
+            let split = line.rfind(' ').unwrap();
+            let (series, value) = (&line[..split], &line[split + 1..]);
+            let name = series.split('{').next().unwrap();
+            assert!(
+                name.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':'),
+                "invalid metric name {}",
+                name
+            );
+            assert!(types.contains(name), "undeclared metric {}", name);
+            if series.contains('{') {
+                assert!(series.ends_with('}'));
+                let labels = &series[name.len() + 1..series.len() - 1];
+                let mut in_str = false;
+                let mut escaped = false;
+                for c in labels.chars() {
+                    match (in_str, escaped, c) {
+                        (true, false, '\\') => escaped = true,
+                        (true, true, _) => escaped = false,
+                        (_, false, '"') => in_str = !in_str,
+                        (true, _, '\n') => panic!("raw newline in label"),
+                        _ => {}
+                    }
+                }
+                assert!(!in_str, "unterminated label in {}", series);
+            }
+            let value: f64 = value.parse().unwrap();
+            assert!(samples.insert(series.to_owned(), value).is_none());
+        }
+        samples
+    }
+
+    fn torrent(id: &str, name: &str, status: Status, rate: u64) -> Resource {
+        let mut t = Torrent::default();
+        t.id = id.to_owned();
+        t.name = Some(name.to_owned());
+        t.status = status;
+        t.rate_down = rate;
+        t.peers = 2;
+        t.progress = 0.5;
+        Resource::Torrent(t)
+    }
+
+    #[test]
+    fn test_render() {
+        let mut server = Server::default();
+        server.rate_up = 10;
+        server.transferred_down = 1234;
+        server.disk_written = 4096;
+        server.dht_nodes = 150;
+        let mut tracker = Tracker::default();
+        tracker.error = Some("unreachable".to_owned());
+        let resources = vec![
+            Resource::Server(server),
+            torrent("a", "plain", Status::Leeching, 5),
+            torrent("b", "with \"quotes\"\nand \\", Status::Seeding, 50),
+            torrent("c", "slow", Status::Paused, 0),
+            Resource::Tracker(tracker),
+        ];
+        let m = parse(&render(resources.iter(), 2));
+
+        assert_eq!(m["synapse_rate_bytes{direction=\"up\"}"], 10.);
+        assert_eq!(
+            m["synapse_transferred_bytes_total{direction=\"down\"}"],
+            1234.
+        );
+        assert_eq!(m["synapse_disk_bytes_total{direction=\"write\"}"], 4096.);
+        assert_eq!(m["synapse_dht_nodes"], 150.);
+        assert_eq!(m["synapse_torrents{status=\"leeching\"}"], 1.);
+        assert_eq!(m["synapse_torrents{status=\"magnet\"}"], 0.);
+        assert_eq!(m["synapse_peers"], 6.);
+        assert_eq!(m["synapse_trackers_failing"], 1.);
+
+        // Only the two most active torrents get their own series
+        assert_eq!(m["synapse_torrents_omitted"], 1.);
+        let b = "id=\"b\",name=\"with \\\"quotes\\\"\\nand \\\\\"";
+        assert_eq!(
+            m[&format!("synapse_torrent_rate_bytes{{{},direction=\"down\"}}", b)],
+            50.
+        );
+        assert_eq!(m["synapse_torrent_progress{id=\"a\",name=\"plain\"}"], 0.5);
+        assert!(!m.keys().any(|k| k.contains("id=\"c\"")));
+    }
+}
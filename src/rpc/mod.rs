@@ -1,5 +1,7 @@
 mod client;
 mod errors;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod processor;
 pub mod proto;
 mod reader;
@@ -7,10 +9,14 @@ mod transfer;
 mod writer;
 
 use std::io::Write;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
-use std::{io, result, str, thread};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::{fs, io, result, str, thread, time};
 
 use amy;
+use chrono::{DateTime, Utc};
 use http_range::HttpRange;
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 use serde_json;
@@ -24,11 +30,14 @@ pub use self::proto::resource;
 use self::proto::ws;
 use self::transfer::{TransferResult, Transfers};
 use bencode;
+use control::alt_speed::Schedule;
+use control::JobKind;
 use disk;
 use handle;
 use socket::TSocket;
 use torrent;
-use util::UHashMap;
+use tracker;
+use util::{random_string, UHashMap};
 use CONFIG;
 
 const POLL_INT_MS: usize = 1000;
@@ -85,6 +94,7 @@ pub enum CtlMessage {
         id: String,
         client: usize,
         serial: u64,
+        archived: Option<String>,
     },
     Uploaded {
         id: String,
@@ -101,17 +111,81 @@ pub enum CtlMessage {
         client: usize,
         serial: u64,
     },
+    Merged {
+        id: String,
+        files: Vec<String>,
+        client: usize,
+        serial: u64,
+    },
+    BulkResult {
+        affected: u64,
+        client: usize,
+        serial: u64,
+    },
+    Connectivity {
+        external_ip: Option<IpAddr>,
+        incoming: u64,
+        last_incoming: Option<DateTime<Utc>>,
+        firewalled: Option<bool>,
+        client: usize,
+        serial: u64,
+    },
+    SessionResult {
+        torrents: u64,
+        client: usize,
+        serial: u64,
+    },
+    Requests {
+        id: String,
+        requests: Vec<message::InFlightRequest>,
+        client: usize,
+        serial: u64,
+    },
+    Magnet {
+        id: String,
+        uri: String,
+        client: usize,
+        serial: u64,
+    },
+    PieceState {
+        id: String,
+        have: String,
+        availability: Vec<message::AvailabilityRun>,
+        client: usize,
+        serial: u64,
+    },
+    Creating {
+        progress: f32,
+        client: usize,
+        serial: u64,
+    },
+    Created {
+        hash: String,
+        metainfo: String,
+        client: usize,
+        serial: u64,
+    },
     Ping,
     Shutdown,
 }
 
 #[derive(Debug)]
 pub enum Message {
-    UpdateTorrent(resource::CResourceUpdate),
+    UpdateTorrent {
+        resource: resource::CResourceUpdate,
+        client: usize,
+        serial: u64,
+    },
     UpdateServer {
-        id: String,
         throttle_up: Option<Option<i64>>,
         throttle_down: Option<Option<i64>>,
+        throttle_up_seeding: Option<Option<i64>>,
+        metered: Option<bool>,
+        piece_affinity: Option<bool>,
+        quiesced: Option<bool>,
+        throttle_burst: Option<usize>,
+        alt_speed: Option<Schedule>,
+        job_intervals: Vec<(JobKind, time::Duration)>,
     },
     UpdateFile {
         id: String,
@@ -126,12 +200,54 @@ pub enum Message {
     },
     Pause(String),
     Resume(String),
-    Validate(Vec<String>),
+    Validate {
+        ids: Vec<String>,
+        low_impact: bool,
+        force_recheck: bool,
+    },
+    RenameFile {
+        id: String,
+        torrent_id: String,
+        path: PathBuf,
+        client: usize,
+        serial: u64,
+    },
+    RenameRoot {
+        id: String,
+        name: String,
+        client: usize,
+        serial: u64,
+    },
+    MoveTorrent {
+        id: String,
+        new_path: String,
+        client: usize,
+        serial: u64,
+    },
+    DownloadPieces {
+        id: String,
+        pieces: Vec<u32>,
+        pause: bool,
+    },
+    Stream {
+        id: String,
+        torrent_id: String,
+        download: disk::Request,
+    },
+    CreateTorrent {
+        path: PathBuf,
+        trackers: Vec<Url>,
+        piece_length: Option<u32>,
+        private: bool,
+        client: usize,
+        serial: u64,
+    },
     AddPeer {
         id: String,
         client: usize,
         serial: u64,
         peer: SocketAddr,
+        preferred: bool,
     },
     RemovePeer {
         id: String,
@@ -149,6 +265,18 @@ pub enum Message {
         id: String,
         torrent_id: String,
     },
+    ReannounceTracker {
+        id: String,
+        torrent_id: String,
+        event: message::AnnounceEvent,
+        client: usize,
+        serial: u64,
+    },
+    SetTrackerHeaders {
+        id: String,
+        torrent_id: String,
+        headers: tracker::Headers,
+    },
     RemoveTracker {
         id: String,
         torrent_id: String,
@@ -162,8 +290,63 @@ pub enum Message {
         path: Option<String>,
         start: bool,
         import: bool,
+        files: Option<Vec<u32>>,
+        allocation: Option<resource::Allocation>,
     },
     PurgeDNS,
+    BulkAction {
+        ids: Vec<String>,
+        action: message::BulkAction,
+        client: usize,
+        serial: u64,
+    },
+    GetConnectivity {
+        client: usize,
+        serial: u64,
+    },
+    GetRequests {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    GetMagnet {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    GetPieceState {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
+    #[cfg(feature = "testing")]
+    InjectFault {
+        id: String,
+        torrent_id: String,
+        latency_ms: u64,
+        loss: f32,
+        client: usize,
+        serial: u64,
+    },
+    ExportSession {
+        path: PathBuf,
+        format: message::SessionFormat,
+        client: usize,
+        serial: u64,
+    },
+    ImportSession {
+        path: PathBuf,
+        client: usize,
+        serial: u64,
+    },
+    Checkpoint {
+        client: usize,
+        serial: u64,
+    },
+    ResumeCheckpoint {
+        client: usize,
+        serial: u64,
+    },
 }
 
 #[allow(dead_code)]
@@ -171,9 +354,12 @@ pub struct RPC {
     poll: amy::Poller,
     reg: amy::Registrar,
     ch: handle::Handle<CtlMessage, Message>,
-    listener: TcpListener,
+    listener: Option<TcpListener>,
+    lid: Option<usize>,
+    /// Listener on the configured Unix socket
+    unix: Option<UnixListener>,
+    ulid: Option<usize>,
     acceptor: Option<SslAcceptor>,
-    lid: usize,
     cleanup: usize,
     processor: Processor,
     transfers: Transfers,
@@ -198,9 +384,21 @@ impl RPC {
             Ipv4Addr::new(0, 0, 0, 0)
         };
         let port = CONFIG.rpc.port;
-        let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
-        listener.set_nonblocking(true)?;
-        let lid = reg.register(&listener, amy::Event::Both)?;
+        let (listener, lid) = if CONFIG.rpc.tcp {
+            let listener = TcpListener::bind(SocketAddrV4::new(ip, port))?;
+            listener.set_nonblocking(true)?;
+            let lid = reg.register(&listener, amy::Event::Both)?;
+            (Some(listener), Some(lid))
+        } else {
+            (None, None)
+        };
+        let (unix, ulid) = if CONFIG.rpc.socket.is_empty() {
+            (None, None)
+        } else {
+            let listener = bind_unix(&CONFIG.rpc.socket, CONFIG.rpc.socket_mode())?;
+            let ulid = reg.register(&listener, amy::Event::Both)?;
+            (Some(listener), Some(ulid))
+        };
 
         let disk = db.clone();
         let th = dh.run("rpc", move |ch| {
@@ -211,6 +409,8 @@ impl RPC {
                 reg,
                 listener,
                 lid,
+                unix,
+                ulid,
                 cleanup,
                 clients: UHashMap::default(),
                 incoming: UHashMap::default(),
@@ -235,9 +435,11 @@ impl RPC {
             };
             for not in res {
                 match not.id {
-                    id if id == self.lid => self.handle_accept(),
+                    id if Some(id) == self.lid => self.handle_accept(),
+                    id if Some(id) == self.ulid => self.handle_accept_unix(),
                     id if id == self.ch.rx.get_id() => {
                         if self.handle_ctl() {
+                            self.remove_socket();
                             return;
                         }
                     }
@@ -293,6 +495,8 @@ impl RPC {
                 serial,
                 start,
                 import,
+                files,
+                allocation,
             } => {
                 debug!("Got torrent via HTTP transfer!");
                 if self.reg.deregister(&conn).is_err() {
@@ -309,6 +513,8 @@ impl RPC {
                                     path,
                                     start,
                                     import,
+                                    files,
+                                    allocation,
                                     client,
                                     serial,
                                 })
@@ -373,8 +579,12 @@ impl RPC {
     }
 
     fn handle_accept(&mut self) {
+        let listener = match self.listener {
+            Some(ref l) => l,
+            None => return,
+        };
         loop {
-            match self.listener.accept() {
+            match listener.accept() {
                 Ok((conn, ip)) => {
                     debug!("Accepted new connection from {:?}!", ip);
                     let id = self.reg.register(&conn, amy::Event::Both);
@@ -397,6 +607,38 @@ impl RPC {
         }
     }
 
+    fn handle_accept_unix(&mut self) {
+        let listener = match self.unix {
+            Some(ref l) => l,
+            None => return,
+        };
+        loop {
+            match listener.accept() {
+                Ok((conn, _)) => {
+                    debug!("Accepted new local connection!");
+                    let id = self.reg.register(&conn, amy::Event::Both);
+                    if let (Ok(id), Ok(conn)) = (id, TSocket::from_unix(conn)) {
+                        self.incoming.insert(id, Incoming::new(conn));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to accept local conn: {}", e);
+                }
+            }
+        }
+    }
+
+    fn remove_socket(&mut self) {
+        if self.unix.take().is_some() {
+            if let Err(e) = fs::remove_file(&CONFIG.rpc.socket) {
+                error!("Failed to remove RPC socket: {}", e);
+            }
+        }
+    }
+
     fn handle_incoming(&mut self, id: usize) {
         if let Some(mut i) = self.incoming.remove(&id) {
             match i.readable() {
@@ -418,6 +660,8 @@ impl RPC {
                                 size,
                                 start,
                                 import,
+                                files,
+                                allocation,
                             },
                         )) => {
                             debug!("Torrent transfer initiated");
@@ -431,6 +675,8 @@ impl RPC {
                                 size,
                                 start,
                                 import,
+                                files,
+                                allocation,
                             );
                             // Since a succesful result means the buffer hasn't been flushed,
                             // immediatly attempt to handle the transfer as if it was ready
@@ -445,7 +691,21 @@ impl RPC {
                         }
                     }
                 }
-                Ok(IncomingStatus::DL { id, range }) => {
+                #[cfg(feature = "metrics")]
+                Ok(IncomingStatus::Metrics) => {
+                    let body = self.processor.metrics();
+                    let lines = vec![
+                        format!("HTTP/1.1 {} {}", 200, "OK"),
+                        format!("Connection: {}", "Close"),
+                        format!("Content-Type: {}", "text/plain; version=0.0.4"),
+                        format!("Content-Length: {}", body.len()),
+                        "\r\n".to_string(),
+                    ];
+                    let mut conn: TSocket = i.into();
+                    conn.write(&lines.join("\r\n").into_bytes()).ok();
+                    conn.write(body.as_bytes()).ok();
+                }
+                Ok(IncomingStatus::DL { id, range, stream }) => {
                     debug!("Attempting DL of {}", id);
                     let mut conn: TSocket = i.into();
                     if let Some((path, size)) = self.processor.get_dl(&id) {
@@ -470,10 +730,23 @@ impl RPC {
                                 length: size,
                             }]
                         };
-                        debug!("Initiating DL");
-                        self.disk
-                            .send(disk::Request::download(conn, path, r, ranged, size))
-                            .ok();
+                        let req = disk::Request::download(conn, path, r, ranged, size);
+                        match self.processor.get_torrent_id(&id) {
+                            Some(torrent_id) if stream => {
+                                debug!("Initiating stream");
+                                self.ch
+                                    .send(Message::Stream {
+                                        id,
+                                        torrent_id,
+                                        download: req,
+                                    })
+                                    .ok();
+                            }
+                            _ => {
+                                debug!("Initiating DL");
+                                self.disk.send(req).ok();
+                            }
+                        }
                     } else {
                         debug!("ID {} invalid, stopping DL", id);
                         conn.write(&EMPTY_HTTP_RESP).ok();
@@ -598,6 +871,37 @@ impl RPC {
     }
 }
 
+/// Binds the RPC socket, replacing a stale socket file left by a previous
+/// run, and restricts access to it to the given permissions. The socket is
+/// bound in a private directory and only moved into place once its
+/// permissions are set, so it's never reachable with looser ones.
+fn bind_unix(path: &str, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(ref m) if m.file_type().is_socket() => {}
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path),
+            ))
+        }
+        Err(_) => {}
+    }
+    let dir = PathBuf::from(format!("{}.{}", path, random_string(8)));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let res = bind_private(&dir, path, mode);
+    fs::remove_dir_all(&dir).ok();
+    res
+}
+
+fn bind_private(dir: &Path, path: &str, mode: u32) -> io::Result<UnixListener> {
+    let tmp = dir.join("rpc.sock");
+    let listener = UnixListener::bind(&tmp)?;
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+    fs::rename(&tmp, path)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
 fn build_acceptor(cert_file: &str, key_file: &str) -> Option<SslAcceptor> {
     if cert_file == "" || key_file == "" {
         info!("RPC SSL parameters not specified, using insecure connections!");
@@ -626,3 +930,33 @@ fn build_acceptor(cert_file: &str, key_file: &str) -> Option<SslAcceptor> {
     info!("SSL initialized!");
     Some(builder.build())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::bind_unix;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_bind_unix() {
+        let dir = env::temp_dir().join(format!("synapse-rpc-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rpc.sock");
+        let path = path.to_str().unwrap();
+
+        // Stale sockets are replaced, with the requested permissions
+        drop(bind_unix(path, 0o600).unwrap());
+        let _listener = bind_unix(path, 0o660).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        UnixStream::connect(path).unwrap();
+        // Nothing but the socket is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_file(path).unwrap();
+        fs::write(path, b"").unwrap();
+        assert!(bind_unix(path, 0o600).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
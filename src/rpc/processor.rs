@@ -3,7 +3,8 @@ use std::collections::{HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time;
 
 use amy;
 use bincode;
@@ -12,12 +13,18 @@ use rpc_lib;
 use serde_json as json;
 use url::Url;
 
+#[cfg(feature = "metrics")]
+use super::metrics;
 use super::proto::criterion::{self, Criterion, Operation};
-use super::proto::message::{CMessage, Error, SMessage};
-use super::proto::resource::{merge_json, Resource, ResourceKind, SResourceUpdate};
+use super::proto::message::{BulkAction, CMessage, Error, SMessage};
+use super::proto::resource::{merge_json, Allocation, Resource, ResourceKind, SResourceUpdate};
 use super::{CtlMessage, Message};
+use control::alt_speed::Schedule;
+use control::JobKind;
 use disk;
+use throttle;
 use torrent::info::Info;
+use tracker::Headers;
 use util::{random_string, FHashMap, FHashSet, MHashSet, SHashMap};
 use CONFIG;
 
@@ -61,6 +68,8 @@ pub enum TransferKind {
         path: Option<String>,
         start: bool,
         import: bool,
+        files: Option<Vec<u32>>,
+        allocation: Option<Allocation>,
     },
     UploadFiles {
         size: u64,
@@ -134,6 +143,19 @@ impl Processor {
         }
     }
 
+    /// The torrent a file belongs to
+    pub fn get_torrent_id(&self, id: &str) -> Option<String> {
+        match self.resources.get(id) {
+            Some(Resource::File(f)) => Some(f.torrent_id.clone()),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> String {
+        metrics::render(self.resources.values(), CONFIG.rpc.metrics_max_torrents)
+    }
+
     pub fn get_transfer(&mut self, tok: String) -> Option<(usize, u64, TransferKind)> {
         let mut res = None;
         let rem = match self.tokens.get(&tok) {
@@ -233,7 +255,11 @@ impl Processor {
 
                 match self.resources.get(&resource.id) {
                     Some(&Resource::Torrent(_)) => {
-                        rmsg = Some(Message::UpdateTorrent(resource));
+                        rmsg = Some(Message::UpdateTorrent {
+                            resource,
+                            client,
+                            serial,
+                        });
                     }
                     Some(&Resource::File(ref f)) => {
                         // TODO: Validate other fields(make sure they're not present)
@@ -245,11 +271,92 @@ impl Processor {
                             });
                         }
                     }
+                    Some(Resource::Tracker(t)) => {
+                        if let Some(h) = resource.headers {
+                            match Headers::new(h.into_iter().collect()) {
+                                Some(headers) => {
+                                    rmsg = Some(Message::SetTrackerHeaders {
+                                        id: resource.id,
+                                        torrent_id: t.torrent_id.clone(),
+                                        headers,
+                                    });
+                                }
+                                None => resp.push(SMessage::InvalidRequest(Error {
+                                    serial: Some(serial),
+                                    reason: "Invalid tracker header name or value".to_owned(),
+                                })),
+                            }
+                        }
+                    }
                     Some(&Resource::Server(_)) => {
+                        let intervals = [
+                            (
+                                JobKind::Tracker,
+                                resource.tracker_job_secs.map(time::Duration::from_secs),
+                            ),
+                            (
+                                JobKind::Unchoke,
+                                resource.unchoke_job_secs.map(time::Duration::from_secs),
+                            ),
+                            (
+                                JobKind::Session,
+                                resource.session_job_secs.map(time::Duration::from_secs),
+                            ),
+                            (
+                                JobKind::Transfer,
+                                resource.tx_job_ms.map(time::Duration::from_millis),
+                            ),
+                        ];
+                        let mut job_intervals = Vec::new();
+                        for &(kind, interval) in &intervals {
+                            if let Some(i) = interval {
+                                if !kind.valid(i) {
+                                    let (min, max) = kind.bounds();
+                                    resp.push(SMessage::InvalidRequest(Error {
+                                        serial: Some(serial),
+                                        reason: format!(
+                                            "{:?} job interval must be between {:?} and {:?}",
+                                            kind, min, max
+                                        ),
+                                    }));
+                                    return (resp, rmsg);
+                                }
+                                job_intervals.push((kind, i));
+                            }
+                        }
+                        let throttle_burst = resource.throttle_burst.map(|b| b as usize);
+                        if throttle_burst.map(throttle::valid_burst) == Some(false) {
+                            resp.push(SMessage::InvalidRequest(Error {
+                                serial: Some(serial),
+                                reason: format!(
+                                    "throttle_burst must be between {} and {}",
+                                    throttle::MIN_BURST,
+                                    throttle::MAX_BURST
+                                ),
+                            }));
+                            return (resp, rmsg);
+                        }
+                        let alt_speed = match resource.alt_speed.as_ref().map(Schedule::from_rpc) {
+                            Some(Err(reason)) => {
+                                resp.push(SMessage::InvalidRequest(Error {
+                                    serial: Some(serial),
+                                    reason,
+                                }));
+                                return (resp, rmsg);
+                            }
+                            Some(Ok(s)) => Some(s),
+                            None => None,
+                        };
                         rmsg = Some(Message::UpdateServer {
-                            id: resource.id,
                             throttle_up: resource.throttle_up,
                             throttle_down: resource.throttle_down,
+                            throttle_up_seeding: resource.throttle_up_seeding,
+                            metered: resource.metered,
+                            piece_affinity: resource.piece_affinity,
+                            quiesced: resource.quiesced,
+                            throttle_burst,
+                            alt_speed,
+                            job_intervals,
                         });
                     }
                     Some(_) => {}
@@ -360,6 +467,7 @@ impl Processor {
                         resp.push(SMessage::ResourcesRemoved {
                             serial,
                             ids: removed,
+                            archived: None,
                         });
                     }
                 } else {
@@ -395,7 +503,12 @@ impl Processor {
                     reason: format!("Unknown resource {}", id),
                 })),
             },
-            CMessage::AddPeer { serial, id, ip } => match self.resources.get(&id) {
+            CMessage::AddPeer {
+                serial,
+                id,
+                ip,
+                preferred,
+            } => match self.resources.get(&id) {
                 Some(&Resource::Torrent(_)) => match ip.parse() {
                     Ok(peer) => {
                         rmsg = Some(Message::AddPeer {
@@ -403,6 +516,7 @@ impl Processor {
                             client,
                             serial,
                             peer,
+                            preferred,
                         })
                     }
                     Err(_) => resp.push(SMessage::InvalidRequest(Error {
@@ -459,7 +573,144 @@ impl Processor {
                     reason: format!("Unknown resource {}", id),
                 })),
             },
-            CMessage::ValidateResources { serial, mut ids } => {
+            CMessage::ReannounceTracker { serial, id, event } => match self.resources.get(&id) {
+                Some(Resource::Tracker(t)) => {
+                    rmsg = Some(Message::ReannounceTracker {
+                        id,
+                        torrent_id: t.torrent_id.clone(),
+                        event,
+                        client,
+                        serial,
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "REANNOUNCE_TRACKER not used with tracker".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::RenameFile { serial, id, path } => match self.resources.get(&id) {
+                Some(Resource::File(f)) => {
+                    rmsg = Some(Message::RenameFile {
+                        id,
+                        torrent_id: f.torrent_id.clone(),
+                        path: PathBuf::from(path),
+                        client,
+                        serial,
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "RENAME_FILE not used with file".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::RenameRoot { serial, id, name } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::RenameRoot {
+                        id,
+                        name,
+                        client,
+                        serial,
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "RENAME_ROOT not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::MoveTorrent { serial, id, path } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::MoveTorrent {
+                        id,
+                        new_path: path,
+                        client,
+                        serial,
+                    })
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "MOVE_TORRENT not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::CreateTorrent {
+                serial,
+                path,
+                trackers,
+                piece_length,
+                private,
+            } => match trackers
+                .iter()
+                .map(|uri| Url::parse(uri).map_err(|_| uri))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(trackers) => {
+                    rmsg = Some(Message::CreateTorrent {
+                        path: PathBuf::from(path),
+                        trackers,
+                        piece_length,
+                        private,
+                        client,
+                        serial,
+                    })
+                }
+                Err(uri) => resp.push(SMessage::InvalidRequest(Error {
+                    serial: Some(serial),
+                    reason: format!("Invalid tracker URI: {}", uri),
+                })),
+            },
+            CMessage::DownloadPieces {
+                serial,
+                id,
+                pieces,
+                pause,
+            } => match self.resources.get(&id) {
+                Some(Resource::Torrent(t)) => match t.pieces {
+                    Some(_) if pieces.is_empty() => resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "No pieces specified".to_owned(),
+                    })),
+                    Some(n) => match pieces.iter().find(|&&p| u64::from(p) >= n) {
+                        Some(p) => resp.push(SMessage::InvalidRequest(Error {
+                            serial: Some(serial),
+                            reason: format!("Invalid piece index {}, torrent has {} pieces", p, n),
+                        })),
+                        None => rmsg = Some(Message::DownloadPieces { id, pieces, pause }),
+                    },
+                    None => resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Torrent metadata has not been fetched".to_owned(),
+                    })),
+                },
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "DOWNLOAD_PIECES not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::ValidateResources {
+                serial,
+                mut ids,
+                low_impact,
+                force_recheck,
+            } => {
                 ids.retain(|id| match self.resources.get(id) {
                     Some(&Resource::Torrent(_)) => true,
                     Some(_) => {
@@ -477,7 +728,11 @@ impl Processor {
                         false
                     }
                 });
-                rmsg = Some(Message::Validate(ids));
+                rmsg = Some(Message::Validate {
+                    ids,
+                    low_impact,
+                    force_recheck,
+                });
             }
             CMessage::UploadTorrent {
                 serial,
@@ -485,6 +740,8 @@ impl Processor {
                 path,
                 start,
                 import,
+                files,
+                allocation,
             } => {
                 resp.push(self.new_transfer(
                     client,
@@ -494,6 +751,8 @@ impl Processor {
                         path,
                         start,
                         import,
+                        files,
+                        allocation,
                     },
                 ));
             }
@@ -502,6 +761,7 @@ impl Processor {
                 uri,
                 path,
                 start,
+                allocation,
             } => match Info::from_magnet(&uri) {
                 Ok(info) => {
                     rmsg = Some(Message::Torrent {
@@ -509,6 +769,8 @@ impl Processor {
                         path,
                         start,
                         import: false,
+                        files: None,
+                        allocation,
                         client,
                         serial,
                     })
@@ -530,6 +792,125 @@ impl Processor {
             CMessage::PurgeDns { .. } => {
                 rmsg = Some(Message::PurgeDNS);
             }
+            CMessage::BulkAction {
+                serial,
+                filter,
+                action,
+                confirm,
+            } => {
+                if action == BulkAction::Remove && !confirm {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Bulk removal must be confirmed".to_owned(),
+                    }));
+                } else {
+                    let resources = &self.resources;
+                    let ids = self.kinds[ResourceKind::Torrent as usize]
+                        .iter()
+                        .filter(|id| filter.matches(resources.get(*id).unwrap().as_torrent()))
+                        .cloned()
+                        .collect();
+                    rmsg = Some(Message::BulkAction {
+                        ids,
+                        action,
+                        client,
+                        serial,
+                    });
+                }
+            }
+            CMessage::GetConnectivity { serial } => {
+                rmsg = Some(Message::GetConnectivity { client, serial });
+            }
+            CMessage::ExportSession {
+                serial,
+                path,
+                format,
+            } => {
+                rmsg = Some(Message::ExportSession {
+                    path: PathBuf::from(path),
+                    format,
+                    client,
+                    serial,
+                });
+            }
+            CMessage::ImportSession { serial, path } => {
+                rmsg = Some(Message::ImportSession {
+                    path: PathBuf::from(path),
+                    client,
+                    serial,
+                });
+            }
+            CMessage::Checkpoint { serial } => {
+                rmsg = Some(Message::Checkpoint { client, serial });
+            }
+            CMessage::ResumeCheckpoint { serial } => {
+                rmsg = Some(Message::ResumeCheckpoint { client, serial });
+            }
+            CMessage::GetRequests { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetRequests { id, client, serial });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "GET_REQUESTS not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetMagnet { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetMagnet { id, client, serial });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "GET_MAGNET not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            CMessage::GetPieceState { serial, id } => match self.resources.get(&id) {
+                Some(&Resource::Torrent(_)) => {
+                    rmsg = Some(Message::GetPieceState { id, client, serial });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "GET_PIECE_STATE not used with torrent".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
+            #[cfg(feature = "testing")]
+            CMessage::InjectFault {
+                serial,
+                id,
+                latency_ms,
+                loss,
+            } => match self.resources.get(&id) {
+                Some(&Resource::Peer(ref p)) => {
+                    rmsg = Some(Message::InjectFault {
+                        torrent_id: p.torrent_id.to_owned(),
+                        id,
+                        latency_ms,
+                        loss,
+                        client,
+                        serial,
+                    });
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "INJECT_FAULT not used with peer".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
         }
         (resp, rmsg)
     }
@@ -612,6 +993,7 @@ impl Processor {
                         SMessage::ResourcesRemoved {
                             serial,
                             ids: ids.into_iter().map(|s| s.into_owned()).collect(),
+                            archived: None,
                         },
                     ));
                 }
@@ -642,12 +1024,18 @@ impl Processor {
                     }
                 }
             }
-            CtlMessage::ClientRemoved { id, client, serial } => {
+            CtlMessage::ClientRemoved {
+                id,
+                client,
+                serial,
+                archived,
+            } => {
                 msgs.push((
                     client,
                     SMessage::ResourcesRemoved {
                         serial,
                         ids: vec![id],
+                        archived,
                     },
                 ));
             }
@@ -680,6 +1068,111 @@ impl Processor {
             CtlMessage::Pending { id, serial, client } => {
                 msgs.push((client, SMessage::ResourcePending { serial, id }));
             }
+            CtlMessage::Merged {
+                id,
+                files,
+                serial,
+                client,
+            } => {
+                msgs.push((client, SMessage::TorrentMerged { serial, id, files }));
+            }
+            CtlMessage::BulkResult {
+                affected,
+                serial,
+                client,
+            } => {
+                msgs.push((client, SMessage::BulkActionResult { serial, affected }));
+            }
+            CtlMessage::Connectivity {
+                external_ip,
+                incoming,
+                last_incoming,
+                firewalled,
+                client,
+                serial,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::Connectivity {
+                        serial,
+                        port: CONFIG.port,
+                        dht_port: CONFIG.dht.port,
+                        external_ip: external_ip.map(|ip| ip.to_string()),
+                        incoming,
+                        last_incoming,
+                        firewalled,
+                    },
+                ));
+            }
+            CtlMessage::SessionResult {
+                torrents,
+                serial,
+                client,
+            } => {
+                msgs.push((client, SMessage::SessionResult { serial, torrents }));
+            }
+            CtlMessage::Requests {
+                id,
+                requests,
+                client,
+                serial,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::Requests {
+                        serial,
+                        id,
+                        requests,
+                    },
+                ));
+            }
+            CtlMessage::Magnet {
+                id,
+                uri,
+                client,
+                serial,
+            } => {
+                msgs.push((client, SMessage::Magnet { serial, id, uri }));
+            }
+            CtlMessage::Creating {
+                progress,
+                client,
+                serial,
+            } => {
+                msgs.push((client, SMessage::TorrentCreating { serial, progress }));
+            }
+            CtlMessage::Created {
+                hash,
+                metainfo,
+                client,
+                serial,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::TorrentCreated {
+                        serial,
+                        hash,
+                        metainfo,
+                    },
+                ));
+            }
+            CtlMessage::PieceState {
+                id,
+                have,
+                availability,
+                client,
+                serial,
+            } => {
+                msgs.push((
+                    client,
+                    SMessage::PieceState {
+                        serial,
+                        id,
+                        have,
+                        availability,
+                    },
+                ));
+            }
             CtlMessage::Ping => unreachable!("ping must be handled before rpc processor"),
             CtlMessage::Shutdown => unreachable!("shutdown must be handled before rpc processor"),
         }
@@ -802,3 +1295,84 @@ impl Filter {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::proto::message::{BulkAction, BulkFilter, CMessage, SMessage};
+    use super::super::proto::resource::{self, Resource};
+    use super::super::{CtlMessage, Message};
+    use super::Processor;
+    use amy;
+
+    fn torrent(id: &str, status: resource::Status) -> Resource {
+        Resource::Torrent(resource::Torrent {
+            id: id.to_owned(),
+            status,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_bulk_action() {
+        let poll = amy::Poller::new().unwrap();
+        let mut reg = poll.get_registrar();
+        let (db, _) = reg.channel().unwrap();
+        let mut p = Processor::new(db);
+        p.handle_ctl(CtlMessage::Extant(vec![
+            torrent("a", resource::Status::Seeding),
+            torrent("b", resource::Status::Leeching),
+            torrent("c", resource::Status::Seeding),
+        ]));
+        let filter = BulkFilter {
+            status: Some(resource::Status::Seeding),
+            ..Default::default()
+        };
+
+        // The action is sent to control for the matching torrents
+        let (resp, msg) = p.handle_client(
+            0,
+            CMessage::BulkAction {
+                serial: 0,
+                filter: filter.clone(),
+                action: BulkAction::Pause,
+                confirm: false,
+            },
+        );
+        assert!(resp.is_empty());
+        match msg {
+            Some(Message::BulkAction {
+                mut ids,
+                action: BulkAction::Pause,
+                ..
+            }) => {
+                ids.sort();
+                assert_eq!(ids, vec!["a", "c"]);
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+
+        // Removal is only done once confirmed
+        let remove = |confirm| CMessage::BulkAction {
+            serial: 1,
+            filter: filter.clone(),
+            action: BulkAction::Remove,
+            confirm,
+        };
+        let (resp, msg) = p.handle_client(0, remove(false));
+        assert!(msg.is_none());
+        match resp.first() {
+            Some(SMessage::InvalidRequest(e)) => assert_eq!(e.serial, Some(1)),
+            _ => panic!("unconfirmed removal wasn't rejected"),
+        }
+        let (resp, msg) = p.handle_client(0, remove(true));
+        assert!(resp.is_empty());
+        match msg {
+            Some(Message::BulkAction {
+                ids,
+                action: BulkAction::Remove,
+                ..
+            }) => assert_eq!(ids.len(), 2),
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+}
@@ -2,6 +2,7 @@ use std::io::Write;
 use std::time;
 
 use super::proto::message::Error;
+use super::proto::resource::Allocation;
 use super::EMPTY_HTTP_RESP;
 
 use socket::TSocket;
@@ -16,6 +17,8 @@ pub enum TransferResult {
         conn: TSocket,
         start: bool,
         import: bool,
+        files: Option<Vec<u32>>,
+        allocation: Option<Allocation>,
         data: Vec<u8>,
         path: Option<String>,
         client: usize,
@@ -37,6 +40,8 @@ struct TorrentTx {
     buf: Vec<u8>,
     start: bool,
     import: bool,
+    files: Option<Vec<u32>>,
+    allocation: Option<Allocation>,
     path: Option<String>,
     last_action: time::Instant,
 }
@@ -61,6 +66,8 @@ impl Transfers {
         size: u64,
         start: bool,
         import: bool,
+        files: Option<Vec<u32>>,
+        allocation: Option<Allocation>,
     ) {
         let pos = data.len();
         // TODO: validate size
@@ -76,6 +83,8 @@ impl Transfers {
                 path,
                 start,
                 import,
+                files,
+                allocation,
                 last_action: time::Instant::now(),
             },
         );
@@ -101,6 +110,8 @@ impl Transfers {
                     serial: tx.serial,
                     start: tx.start,
                     import: tx.import,
+                    files: tx.files,
+                    allocation: tx.allocation,
                 }
             }
             Some(Ok(false)) => TransferResult::Incomplete,
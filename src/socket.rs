@@ -1,7 +1,9 @@
-use std::io::{self, ErrorKind};
-use std::mem;
+use std::io::{self, ErrorKind, Read, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::{cmp, mem};
 
 use net2::{TcpBuilder, TcpStreamExt};
 use nix::libc;
@@ -9,15 +11,56 @@ use openssl::ssl::{
     HandshakeError, MidHandshakeSslStream, SslAcceptor, SslConnector, SslMethod, SslStream,
 };
 
+use mse;
+use rpc::resource::Transport;
 use throttle::Throttle;
 use util;
 
 /// Wrapper type over Mio sockets, allowing for use of UDP/TCP, encryption,
 /// rate limiting, etc.
 pub struct Socket {
-    conn: TcpStream,
+    conn: Stream,
     addr: SocketAddr,
     pub throttle: Option<Throttle>,
+    crypt: Crypt,
+    /// Data to be sent, already encrypted if needed
+    out: Vec<u8>,
+    /// Data written while the encrypted handshake is in progress
+    pending: Vec<u8>,
+    /// Data received along with the encrypted handshake
+    received: Vec<u8>,
+    /// Whether any of the encrypted handshake was sent, i.e. the
+    /// connection was established
+    sent: bool,
+    /// Whether any data was received
+    received_any: bool,
+}
+
+/// A socket apart from its throttle, which unlike `Socket` may be sent
+/// between threads. Incoming connections are handed from the listener to
+/// control in this form, which then throttles them.
+pub struct Detached {
+    conn: Stream,
+    addr: SocketAddr,
+    crypt: Crypt,
+    out: Vec<u8>,
+    pending: Vec<u8>,
+    received: Vec<u8>,
+    sent: bool,
+    received_any: bool,
+}
+
+/// uTP connections are relayed by the tracker thread, which owns
+/// the UDP socket, through a Unix socket.
+enum Stream {
+    Tcp(TcpStream),
+    Utp(UnixStream),
+}
+
+enum Crypt {
+    Plain,
+    Handshake(Box<mse::Handshake>),
+    Encrypted(Box<mse::Cipher>),
 }
 
 impl Socket {
@@ -36,20 +79,32 @@ impl Socket {
                 return Err(e);
             }
         }
-        Ok(Socket {
-            conn,
-            throttle: None,
-            addr: *addr,
-        })
+        Ok(Socket::with_conn(Stream::Tcp(conn), *addr))
+    }
+
+    /// Wraps the relayed stream of a uTP connection to `addr`
+    pub fn utp(conn: UnixStream, addr: SocketAddr) -> io::Result<Socket> {
+        conn.set_nonblocking(true)?;
+        Ok(Socket::with_conn(Stream::Utp(conn), addr))
     }
 
     #[cfg(test)]
     pub fn empty() -> Socket {
         let conn = TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
+        Socket::with_conn(Stream::Tcp(conn), "127.0.0.1:0".parse().unwrap())
+    }
+
+    fn with_conn(conn: Stream, addr: SocketAddr) -> Socket {
         Socket {
             conn,
+            addr,
             throttle: None,
-            addr: "127.0.0.1:0".parse().unwrap(),
+            crypt: Crypt::Plain,
+            out: Vec::new(),
+            pending: Vec::new(),
+            received: Vec::new(),
+            sent: false,
+            received_any: false,
         }
     }
 
@@ -57,25 +112,131 @@ impl Socket {
         self.addr
     }
 
+    /// Separates the connection and its encryption state from the
+    /// throttle, so it may be passed to another thread.
+    pub fn detach(self) -> Detached {
+        Detached {
+            conn: self.conn,
+            addr: self.addr,
+            crypt: self.crypt,
+            out: self.out,
+            pending: self.pending,
+            received: self.received,
+            sent: self.sent,
+            received_any: self.received_any,
+        }
+    }
+
     pub fn from_stream(conn: TcpStream) -> io::Result<Socket> {
         conn.set_nonblocking(true)?;
-        let addr = conn.peer_addr()?;
-        Ok(Socket {
-            conn,
-            throttle: None,
-            addr,
-        })
+        let addr = util::unmap_addr(conn.peer_addr()?);
+        Ok(Socket::with_conn(Stream::Tcp(conn), addr))
     }
-}
 
-impl AsRawFd for Socket {
-    fn as_raw_fd(&self) -> RawFd {
-        self.conn.as_raw_fd()
+    pub fn transport(&self) -> Transport {
+        match self.conn {
+            Stream::Tcp(_) => Transport::Tcp,
+            Stream::Utp(_) => Transport::Utp,
+        }
     }
-}
 
-impl io::Read for Socket {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    /// Whether this is a uTP connection which nothing was received
+    /// over, so it may never have been established.
+    pub fn unconfirmed(&self) -> bool {
+        match self.conn {
+            Stream::Utp(_) => !self.received_any,
+            Stream::Tcp(_) => false,
+        }
+    }
+
+    /// Starts the encrypted handshake of an outgoing connection for
+    /// torrent `hash`, offering the given crypto methods.
+    pub fn initiate_mse(&mut self, hash: [u8; 20], methods: u32) {
+        let mut hs = mse::Handshake::initiate(hash, methods);
+        self.out = hs.take_output();
+        self.crypt = Crypt::Handshake(Box::new(hs));
+    }
+
+    /// Expects the encrypted handshake on an incoming connection, or
+    /// a plaintext one if `methods` allows for it.
+    pub fn accept_mse(&mut self, skeys: Arc<mse::Skeys>, methods: u32) {
+        let hs = mse::Handshake::respond(skeys, methods);
+        self.crypt = Crypt::Handshake(Box::new(hs));
+    }
+
+    /// Whether the connection was established, but the encrypted
+    /// handshake didn't complete yet.
+    pub fn negotiating(&self) -> bool {
+        match self.crypt {
+            Crypt::Handshake(_) => self.sent,
+            _ => false,
+        }
+    }
+
+    /// Progresses the encrypted handshake with all data available
+    fn negotiate(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 1024];
+        loop {
+            let amnt = match self.conn.read(&mut buf) {
+                Ok(0) => return util::io_err("EOF"),
+                Ok(amnt) => amnt,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            self.sent = true;
+            self.received_any = true;
+            let done = match self.crypt {
+                Crypt::Handshake(ref mut hs) => {
+                    let done = hs.feed(&buf[..amnt])?;
+                    self.out.extend_from_slice(&hs.take_output());
+                    done
+                }
+                _ => None,
+            };
+            if let Some(done) = done {
+                debug!("Encrypted handshake with {} completed", self.addr);
+                let mut pending = mem::replace(&mut self.pending, Vec::new());
+                self.crypt = match done.cipher {
+                    Some(mut c) => {
+                        c.encrypt(&mut pending);
+                        Crypt::Encrypted(Box::new(c))
+                    }
+                    None => Crypt::Plain,
+                };
+                self.out.extend_from_slice(&pending);
+                self.received = done.rest;
+                break;
+            }
+        }
+        self.try_send_out()
+    }
+
+    /// Sends as much of the queued output as possible, failing with
+    /// WouldBlock if any remains.
+    fn send_out(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            match self.conn.write(&self.out) {
+                Ok(0) => return util::io_err("EOF"),
+                Ok(amnt) => {
+                    self.sent = true;
+                    self.out.drain(..amnt);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Like send_out, but leaves whatever remains for the next
+    /// writable event.
+    fn try_send_out(&mut self) -> io::Result<()> {
+        match self.send_out() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            res => res,
+        }
+    }
+
+    fn read_conn(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // Don't bother rate limiting small requests
         if buf.len() < 20 {
             return self.conn.read(buf);
@@ -98,10 +259,8 @@ impl io::Read for Socket {
             self.conn.read(buf)
         }
     }
-}
 
-impl io::Write for Socket {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    fn write_conn(&mut self, buf: &[u8]) -> io::Result<usize> {
         if buf.len() < 20 {
             return self.conn.write(buf);
         }
@@ -124,7 +283,130 @@ impl io::Write for Socket {
         }
     }
 
+    /// Encrypts and sends data, queueing whatever can't be sent
+    /// immediately. The data is accepted in full unless the queue
+    /// from a previous write didn't drain yet.
+    fn write_encrypted(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_out()?;
+        if buf.len() >= 20 {
+            if let Some(ref mut t) = self.throttle {
+                if t.get_bytes_ul(buf.len()).is_err() {
+                    return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+                }
+            }
+        }
+        let mut data = buf.to_vec();
+        if let Crypt::Encrypted(ref mut c) = self.crypt {
+            c.encrypt(&mut data);
+        }
+        self.out = data;
+        self.try_send_out()?;
+        Ok(buf.len())
+    }
+}
+
+impl Detached {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Rejoins the socket on the receiving thread, which is unthrottled
+    /// until a throttle is set.
+    pub fn attach(self) -> Socket {
+        Socket {
+            conn: self.conn,
+            addr: self.addr,
+            throttle: None,
+            crypt: self.crypt,
+            out: self.out,
+            pending: self.pending,
+            received: self.received,
+            sent: self.sent,
+            received_any: self.received_any,
+        }
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut c) => c.read(buf),
+            Stream::Utp(ref mut c) => c.read(buf),
+        }
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut c) => c.write(buf),
+            Stream::Utp(ref mut c) => c.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut c) => c.flush(),
+            Stream::Utp(ref mut c) => c.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref c) => c.as_raw_fd(),
+            Stream::Utp(ref c) => c.as_raw_fd(),
+        }
+    }
+}
+
+impl io::Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.received.is_empty() {
+            let amnt = cmp::min(buf.len(), self.received.len());
+            buf[..amnt].copy_from_slice(&self.received[..amnt]);
+            self.received.drain(..amnt);
+            return Ok(amnt);
+        }
+        if let Crypt::Handshake(_) = self.crypt {
+            self.negotiate()?;
+            if let Crypt::Handshake(_) = self.crypt {
+                return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+            }
+            return self.read(buf);
+        }
+        let amnt = self.read_conn(buf)?;
+        self.received_any |= amnt > 0;
+        if let Crypt::Encrypted(ref mut c) = self.crypt {
+            c.decrypt(&mut buf[..amnt]);
+        }
+        Ok(amnt)
+    }
+}
+
+impl io::Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.crypt {
+            Crypt::Plain if self.out.is_empty() => self.write_conn(buf),
+            Crypt::Handshake(_) => {
+                self.pending.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            _ => self.write_encrypted(buf),
+        }
+    }
+
+    /// Sends data queued by the encrypted handshake or
+    /// encrypted writes.
     fn flush(&mut self) -> io::Result<()> {
+        self.send_out()?;
         self.conn.flush()
     }
 }
@@ -137,6 +419,8 @@ pub struct TSocket {
 enum TConn {
     Empty,
     Plain(TcpStream),
+    // Local RPC connection
+    Unix(UnixStream),
     // SSL Preconnection state
     SSLP { host: String, conn: TcpStream },
     // SSL Connecting state
@@ -171,6 +455,15 @@ impl TSocket {
         })
     }
 
+    pub fn from_unix(stream: UnixStream) -> io::Result<TSocket> {
+        stream.set_nonblocking(true)?;
+        let fd = stream.as_raw_fd();
+        Ok(TSocket {
+            conn: TConn::Unix(stream),
+            fd,
+        })
+    }
+
     pub fn from_ssl(stream: TcpStream, acceptor: &SslAcceptor) -> io::Result<TSocket> {
         stream.set_nonblocking(true)?;
         let fd = stream.as_raw_fd();
@@ -226,6 +519,10 @@ impl io::Read for TSocket {
                 res = c.read(buf);
                 TConn::Plain(c)
             }
+            TConn::Unix(mut c) => {
+                res = c.read(buf);
+                TConn::Unix(c)
+            }
             TConn::SSLC(conn) => match conn.handshake() {
                 Ok(s) => {
                     res = Ok(std::usize::MAX);
@@ -265,6 +562,10 @@ impl io::Write for TSocket {
                 res = c.write(buf);
                 TConn::Plain(c)
             }
+            TConn::Unix(mut c) => {
+                res = c.write(buf);
+                TConn::Unix(c)
+            }
             TConn::SSLC(conn) => match conn.handshake() {
                 Ok(s) => {
                     res = Ok(std::usize::MAX);
@@ -294,6 +595,7 @@ impl io::Write for TSocket {
     fn flush(&mut self) -> io::Result<()> {
         match self.conn {
             TConn::Plain(ref mut c) => c.flush(),
+            TConn::Unix(ref mut c) => c.flush(),
             TConn::SSL(ref mut c) => c.flush(),
             _ => Ok(()),
         }
@@ -305,3 +607,23 @@ impl AsRawFd for TSocket {
         self.fd
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Socket;
+    use listener;
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_detach() {
+        // Incoming connections cross from the listener thread
+        assert_send::<listener::Message>();
+
+        let sock = Socket::empty();
+        let addr = sock.addr();
+        let sock = sock.detach().attach();
+        assert_eq!(sock.addr(), addr);
+        assert!(sock.throttle.is_none());
+    }
+}
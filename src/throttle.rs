@@ -1,5 +1,6 @@
 use amy::Registrar;
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashSet;
 use std::rc::Rc;
 
@@ -12,54 +13,92 @@ pub struct Throttler {
     fid: usize,
     dl_data: Rc<RefCell<ThrottleData>>,
     ul_data: Rc<RefCell<ThrottleData>>,
+    /// Upload budget shared by seeding torrents, used in place
+    /// of ul_data when it has a rate set.
+    ul_seed_data: Rc<RefCell<ThrottleData>>,
 }
 
-const URATE: usize = 15;
+/// Bounds of the token bucket capacity, which must fit a block
+/// along with its message header.
+pub const MIN_BURST: usize = 64 * 1024;
+pub const MAX_BURST: usize = 256 * 1024 * 1024;
+/// Bounds of the interval in milliseconds at which tokens are added
+pub const MIN_INTERVAL_MS: usize = 1;
+pub const MAX_INTERVAL_MS: usize = 1000;
+
+pub fn valid_burst(burst: usize) -> bool {
+    (MIN_BURST..=MAX_BURST).contains(&burst)
+}
+
+pub fn valid_interval(interval: usize) -> bool {
+    (MIN_INTERVAL_MS..=MAX_INTERVAL_MS).contains(&interval)
+}
 
 impl Throttler {
     /// Creates a new throttler and sets two timers on reg,
-    /// one for updating the tokens, the other for flushing out
-    /// blocked peers.
+    /// one for adding tokens every `interval` milliseconds, the
+    /// other for flushing out blocked peers. `max_tokens` is the
+    /// capacity of the buckets, i.e. the largest burst allowed.
     pub fn new(
         dl_rate: Option<i64>,
         ul_rate: Option<i64>,
         max_tokens: usize,
+        interval: usize,
         reg: &Registrar,
     ) -> Option<Throttler> {
-        let id = reg.set_interval(URATE).ok()?;
+        let id = reg.set_interval(interval).ok()?;
         let fid = reg.set_interval(50).ok()?;
-        let ut = ThrottleData::new(ul_rate, max_tokens);
-        let dt = ThrottleData::new(dl_rate, max_tokens);
+        let ut = ThrottleData::new(ul_rate, max_tokens, interval);
+        let dt = ThrottleData::new(dl_rate, max_tokens, interval);
+        let st = ThrottleData::new(None, max_tokens, interval);
         Some(Throttler {
             id,
             fid,
             ul_data: Rc::new(RefCell::new(ut)),
             dl_data: Rc::new(RefCell::new(dt)),
+            ul_seed_data: Rc::new(RefCell::new(st)),
         })
     }
 
     pub fn update(&self) -> (u64, u64) {
-        let ul = self.ul_data.borrow_mut().add_tokens();
+        let ul =
+            self.ul_data.borrow_mut().add_tokens() + self.ul_seed_data.borrow_mut().add_tokens();
         let dl = self.dl_data.borrow_mut().add_tokens();
         (ul, dl)
     }
 
     pub fn get_throttle(&self, id: usize) -> Throttle {
+        let (max_tokens, interval) = (self.burst(), self.interval());
         Throttle {
             ul_data: self.ul_data.clone(),
-            ul_tier: Rc::new(RefCell::new(ThrottleData::new(
-                None,
-                self.ul_data.borrow().max_tokens,
-            ))),
+            ul_seed_data: self.ul_seed_data.clone(),
+            ul_tier: Rc::new(RefCell::new(ThrottleData::new(None, max_tokens, interval))),
             dl_data: self.dl_data.clone(),
-            dl_tier: Rc::new(RefCell::new(ThrottleData::new(
-                None,
-                self.dl_data.borrow().max_tokens,
-            ))),
+            dl_tier: Rc::new(RefCell::new(ThrottleData::new(None, max_tokens, interval))),
             id,
         }
     }
 
+    /// Capacity of the token buckets in bytes
+    pub fn burst(&self) -> usize {
+        self.ul_data.borrow().max_tokens
+    }
+
+    /// Interval in milliseconds at which tokens are added
+    pub fn interval(&self) -> usize {
+        self.ul_data.borrow().interval
+    }
+
+    /// Sets the capacity of the token buckets, torrents picking it
+    /// up the next time they take tokens.
+    pub fn set_burst(&mut self, burst: usize) {
+        for data in &[&self.ul_data, &self.ul_seed_data, &self.dl_data] {
+            let mut data = data.borrow_mut();
+            data.max_tokens = burst;
+            data.tokens = cmp::min(data.tokens, burst);
+        }
+    }
+
     pub fn ul_rate(&mut self) -> Option<i64> {
         self.ul_data.borrow().rate
     }
@@ -68,6 +107,12 @@ impl Throttler {
         self.dl_data.borrow().rate
     }
 
+    /// Upload rate shared by seeding torrents, or None if
+    /// they're subject to the regular upload rate.
+    pub fn ul_seed_rate(&mut self) -> Option<i64> {
+        self.ul_seed_data.borrow().rate
+    }
+
     pub fn set_ul_rate(&mut self, rate: Option<i64>) {
         self.ul_data.borrow_mut().rate = rate;
     }
@@ -76,6 +121,10 @@ impl Throttler {
         self.dl_data.borrow_mut().rate = rate;
     }
 
+    pub fn set_ul_seed_rate(&mut self, rate: Option<i64>) {
+        self.ul_seed_data.borrow_mut().rate = rate;
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
@@ -86,7 +135,12 @@ impl Throttler {
 
     pub fn flush_ul(&mut self) -> Vec<usize> {
         let mut ul_data = self.ul_data.borrow_mut();
-        let flushed = ul_data.throttled.drain().collect();
+        let mut ul_seed_data = self.ul_seed_data.borrow_mut();
+        let flushed = ul_data
+            .throttled
+            .drain()
+            .chain(ul_seed_data.throttled.drain())
+            .collect();
         flushed
     }
 
@@ -99,9 +153,14 @@ impl Throttler {
 
 struct ThrottleData {
     rate: Option<i64>,
+    /// Rate used instead of rate while seeding, None to keep using rate
+    seed_rate: Option<i64>,
+    seeding: bool,
     tokens: usize,
     epoch: usize,
     max_tokens: usize,
+    /// Milliseconds between calls to add_tokens
+    interval: usize,
     last_used: u64,
     throttled: HashSet<usize>,
 }
@@ -115,6 +174,7 @@ pub struct Throttle {
     ul_tier: Rc<RefCell<ThrottleData>>,
     dl_tier: Rc<RefCell<ThrottleData>>,
     ul_data: Rc<RefCell<ThrottleData>>,
+    ul_seed_data: Rc<RefCell<ThrottleData>>,
     dl_data: Rc<RefCell<ThrottleData>>,
 }
 
@@ -122,6 +182,7 @@ impl Throttle {
     pub fn new_sibling(&self, id: usize) -> Throttle {
         Throttle {
             ul_data: self.ul_data.clone(),
+            ul_seed_data: self.ul_seed_data.clone(),
             ul_tier: self.ul_tier.clone(),
             dl_data: self.dl_data.clone(),
             dl_tier: self.dl_tier.clone(),
@@ -130,9 +191,7 @@ impl Throttle {
     }
 
     pub fn get_bytes_dl(&mut self, amnt: usize) -> Result<(), ()> {
-        while self.dl_tier.borrow().epoch != self.dl_data.borrow().epoch {
-            self.dl_tier.borrow_mut().add_tokens();
-        }
+        self.dl_tier.borrow_mut().catch_up(&self.dl_data.borrow());
         if self.dl_rate() == Some(-1) {
             self.dl_tier.borrow_mut().last_used += amnt as u64;
             self.dl_data.borrow_mut().last_used += amnt as u64;
@@ -153,30 +212,48 @@ impl Throttle {
         Ok(())
     }
 
-    pub fn get_bytes_ul(&mut self, amnt: usize) -> Result<(), ()> {
-        while self.ul_tier.borrow().epoch != self.ul_data.borrow().epoch {
-            self.ul_tier.borrow_mut().add_tokens();
+    /// Takes download tokens like get_bytes_dl, but for sources which
+    /// poll for them again later rather than waiting to be flushed.
+    pub fn poll_bytes_dl(&mut self, amnt: usize) -> Result<(), ()> {
+        let res = self.get_bytes_dl(amnt);
+        if res.is_err() {
+            self.dl_data.borrow_mut().throttled.remove(&self.id);
         }
-        if self.ul_rate() == Some(-1) {
+        res
+    }
+
+    pub fn get_bytes_ul(&mut self, amnt: usize) -> Result<(), ()> {
+        self.ul_tier.borrow_mut().catch_up(&self.ul_data.borrow());
+        let ul_data = self.ul_global();
+        if self.active_ul_rate() == Some(-1) {
             self.ul_tier.borrow_mut().last_used += amnt as u64;
-            self.ul_data.borrow_mut().last_used += amnt as u64;
+            ul_data.borrow_mut().last_used += amnt as u64;
             return Ok(());
         }
-        let pres = self.ul_data.borrow_mut().get_tokens(amnt);
+        let pres = ul_data.borrow_mut().get_tokens(amnt);
         if pres.is_err() {
-            self.ul_data.borrow_mut().throttled.insert(self.id);
+            ul_data.borrow_mut().throttled.insert(self.id);
             return Err(());
         }
 
         let res = self.ul_tier.borrow_mut().get_tokens(amnt);
         if res.is_err() {
-            self.ul_data.borrow_mut().restore_tokens(amnt);
-            self.ul_data.borrow_mut().throttled.insert(self.id);
+            ul_data.borrow_mut().restore_tokens(amnt);
+            ul_data.borrow_mut().throttled.insert(self.id);
             return Err(());
         }
         Ok(())
     }
 
+    /// The global upload data used for the torrent's current role
+    fn ul_global(&self) -> &Rc<RefCell<ThrottleData>> {
+        if self.ul_tier.borrow().seeding && self.ul_seed_data.borrow().rate.is_some() {
+            &self.ul_seed_data
+        } else {
+            &self.ul_data
+        }
+    }
+
     pub fn set_stalled_dl(&mut self) {
         self.dl_data.borrow_mut().throttled.insert(self.id);
     }
@@ -189,6 +266,20 @@ impl Throttle {
         self.dl_tier.borrow_mut().rate
     }
 
+    pub fn ul_seed_rate(&self) -> Option<i64> {
+        self.ul_tier.borrow().seed_rate
+    }
+
+    /// The upload rate currently enforced, depending on whether
+    /// the torrent is seeding.
+    pub fn active_ul_rate(&self) -> Option<i64> {
+        self.ul_tier.borrow().active_rate()
+    }
+
+    pub fn seeding(&self) -> bool {
+        self.ul_tier.borrow().seeding
+    }
+
     pub fn set_ul_rate(&mut self, rate: Option<i64>) {
         self.ul_tier.borrow_mut().rate = rate;
     }
@@ -197,13 +288,22 @@ impl Throttle {
         self.dl_tier.borrow_mut().rate = rate;
     }
 
+    pub fn set_ul_seed_rate(&mut self, rate: Option<i64>) {
+        self.ul_tier.borrow_mut().seed_rate = rate;
+    }
+
+    /// Switches between the downloading and seeding upload rates.
+    pub fn set_seeding(&mut self, seeding: bool) {
+        self.ul_tier.borrow_mut().seeding = seeding;
+    }
+
     pub fn restore_bytes_dl(&mut self, amnt: usize) {
         self.dl_data.borrow_mut().restore_tokens(amnt);
         self.dl_tier.borrow_mut().restore_tokens(amnt);
     }
 
     pub fn restore_bytes_ul(&mut self, amnt: usize) {
-        self.ul_data.borrow_mut().restore_tokens(amnt);
+        self.ul_global().borrow_mut().restore_tokens(amnt);
         self.ul_tier.borrow_mut().restore_tokens(amnt);
     }
 }
@@ -211,38 +311,60 @@ impl Throttle {
 impl Drop for Throttle {
     fn drop(&mut self) {
         self.ul_data.borrow_mut().throttled.remove(&self.id);
+        self.ul_seed_data.borrow_mut().throttled.remove(&self.id);
         self.dl_data.borrow_mut().throttled.remove(&self.id);
     }
 }
 
 impl ThrottleData {
-    /// Creates a new Throttle with the given rate and max token amount.
-    fn new(rate: Option<i64>, max_tokens: usize) -> ThrottleData {
+    /// Creates a new Throttle with the given rate, max token amount
+    /// and token interval.
+    fn new(rate: Option<i64>, max_tokens: usize, interval: usize) -> ThrottleData {
         ThrottleData {
             tokens: 0,
             rate,
+            seed_rate: None,
+            seeding: false,
             max_tokens,
+            interval,
             throttled: HashSet::with_capacity(0),
             last_used: 0,
             epoch: 0,
         }
     }
 
+    fn active_rate(&self) -> Option<i64> {
+        if self.seeding {
+            self.seed_rate.or(self.rate)
+        } else {
+            self.rate
+        }
+    }
+
+    /// Adds the tokens of a torrent's tier which it missed since
+    /// last drawing from `global`, taking on its capacity.
+    fn catch_up(&mut self, global: &ThrottleData) {
+        self.max_tokens = global.max_tokens;
+        while self.epoch != global.epoch {
+            self.add_tokens();
+        }
+    }
+
     /// Adds some amount of tokens back.
     fn restore_tokens(&mut self, amnt: usize) {
         self.last_used -= amnt as u64;
         self.tokens += amnt;
     }
 
-    /// This method must be called every URATE milliseconds and returns
-    /// (self.last_used) * 1000/URATE - the bits/s, clearing self.last_used
+    /// This method must be called every self.interval milliseconds and returns
+    /// (self.last_used) * 1000/self.interval - the bits/s, clearing self.last_used
     fn add_tokens(&mut self) -> u64 {
         self.epoch = self.epoch.wrapping_add(1);
         let drained = self.last_used as u64;
         self.last_used = 0;
-        self.tokens += if let Some(r) = self.rate {
+        self.tokens += if let Some(r) = self.active_rate() {
             if r > 0 {
-                (r as usize * URATE) / 1000
+                (r as usize * self.interval) / 1000
             } else {
                 0
             }
@@ -257,7 +379,7 @@ impl ThrottleData {
 
     /// Attempt to extract amnt tokens from the throttler.
     fn get_tokens(&mut self, amnt: usize) -> Result<(), ()> {
-        match self.rate {
+        match self.active_rate() {
             None => {
                 self.last_used += amnt as u64;
                 Ok(())
@@ -278,3 +400,130 @@ impl ThrottleData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Throttle, ThrottleData};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const URATE: usize = 15;
+
+    fn data(rate: Option<i64>) -> Rc<RefCell<ThrottleData>> {
+        Rc::new(RefCell::new(ThrottleData::new(rate, 1_000_000, URATE)))
+    }
+
+    fn tick(t: &Throttle) {
+        t.ul_data.borrow_mut().add_tokens();
+        t.ul_seed_data.borrow_mut().add_tokens();
+    }
+
+    #[test]
+    fn test_seeding_upload_cap() {
+        let mut t = Throttle {
+            id: 0,
+            ul_tier: data(None),
+            dl_tier: data(None),
+            ul_data: data(None),
+            ul_seed_data: data(None),
+            dl_data: data(None),
+        };
+        let per_tick = |rate: usize| rate * URATE / 1000;
+        t.set_ul_rate(Some(10_000));
+        t.set_ul_seed_rate(Some(40_000));
+        assert_eq!(t.active_ul_rate(), Some(10_000));
+
+        tick(&t);
+        assert!(t.get_bytes_ul(per_tick(10_000) + 1).is_err());
+        assert!(t.get_bytes_ul(per_tick(10_000)).is_ok());
+
+        // Completing the torrent switches to the seeding cap
+        t.set_seeding(true);
+        assert_eq!(t.active_ul_rate(), Some(40_000));
+        tick(&t);
+        assert!(t.get_bytes_ul(per_tick(40_000)).is_ok());
+        assert!(t.get_bytes_ul(1).is_err());
+
+        // Seeding torrents draw from their own global budget once set
+        t.set_ul_seed_rate(None);
+        t.ul_data.borrow_mut().rate = Some(1_000_000);
+        t.ul_seed_data.borrow_mut().rate = Some(20_000);
+        tick(&t);
+        assert!(t.get_bytes_ul(per_tick(20_000) + 1).is_err());
+        assert!(t.ul_seed_data.borrow().throttled.contains(&0));
+        t.set_seeding(false);
+        assert!(t.get_bytes_ul(per_tick(10_000)).is_ok());
+    }
+
+    #[test]
+    fn test_torrent_download_cap() {
+        let mut t = Throttle {
+            id: 0,
+            ul_tier: data(None),
+            dl_tier: data(None),
+            ul_data: data(None),
+            ul_seed_data: data(None),
+            dl_data: data(Some(40_000)),
+        };
+        let per_tick = |rate: usize| rate * URATE / 1000;
+        let tick = |t: &Throttle| {
+            t.dl_data.borrow_mut().add_tokens();
+        };
+
+        // Without a limit of its own only the global one applies
+        tick(&t);
+        assert!(t.get_bytes_dl(per_tick(40_000) + 1).is_err());
+        assert!(t.get_bytes_dl(per_tick(40_000)).is_ok());
+
+        // The torrent's limit applies under the global one
+        t.set_dl_rate(Some(10_000));
+        tick(&t);
+        assert!(t.get_bytes_dl(per_tick(10_000) + 1).is_err());
+        assert!(t.get_bytes_dl(per_tick(10_000)).is_ok());
+        assert!(t.dl_data.borrow().throttled.contains(&0));
+
+        // Tokens taken from the global budget are given back when
+        // the torrent's own budget runs out
+        assert_eq!(
+            t.dl_data.borrow().tokens,
+            per_tick(40_000) - per_tick(10_000)
+        );
+
+        // Polling sources aren't flushed
+        t.dl_data.borrow_mut().throttled.clear();
+        assert!(t.poll_bytes_dl(1).is_err());
+        assert!(t.dl_data.borrow().throttled.is_empty());
+    }
+
+    #[test]
+    fn test_burst_size() {
+        let mut t = Throttle {
+            id: 0,
+            ul_tier: data(None),
+            dl_tier: data(None),
+            ul_data: data(None),
+            ul_seed_data: data(None),
+            dl_data: data(Some(1_000_000)),
+        };
+        let fill = |t: &Throttle| {
+            for _ in 0..1000 {
+                t.dl_data.borrow_mut().add_tokens();
+            }
+        };
+
+        // An idle torrent may burst up to the bucket's capacity
+        t.dl_data.borrow_mut().max_tokens = 64 * 1024;
+        fill(&t);
+        assert!(t.get_bytes_dl(64 * 1024 + 1).is_err());
+        assert!(t.get_bytes_dl(64 * 1024).is_ok());
+        assert!(t.get_bytes_dl(1).is_err());
+
+        // A larger bucket allows a larger burst before throttling,
+        // with the torrent's tier following along
+        t.dl_data.borrow_mut().max_tokens = 256 * 1024;
+        fill(&t);
+        assert!(t.get_bytes_dl(256 * 1024).is_ok());
+        assert_eq!(t.dl_tier.borrow().max_tokens, 256 * 1024);
+        assert!(t.get_bytes_dl(1).is_err());
+    }
+}
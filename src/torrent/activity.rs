@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+/// When a torrent completed and was last active, along with the time
+/// it spent transferring data and seeding.
+pub struct Activity {
+    completed: Option<DateTime<Utc>>,
+    last_active: Option<DateTime<Utc>>,
+    active: Duration,
+    seeding: Duration,
+    last_tick: Instant,
+}
+
+impl Activity {
+    pub fn new(
+        completed: Option<DateTime<Utc>>,
+        last_active: Option<DateTime<Utc>>,
+        active_secs: u64,
+        seeding_secs: u64,
+        now: Instant,
+    ) -> Activity {
+        Activity {
+            completed,
+            last_active,
+            active: Duration::from_secs(active_secs),
+            seeding: Duration::from_secs(seeding_secs),
+            last_tick: now,
+        }
+    }
+
+    pub fn completed(&self) -> Option<DateTime<Utc>> {
+        self.completed
+    }
+
+    pub fn last_active(&self) -> Option<DateTime<Utc>> {
+        self.last_active
+    }
+
+    pub fn active_secs(&self) -> u64 {
+        self.active.as_secs()
+    }
+
+    pub fn seeding_secs(&self) -> u64 {
+        self.seeding.as_secs()
+    }
+
+    /// Records the torrent completing, returning whether it's the
+    /// first time. Later completions, e.g. after files were selected
+    /// or failed validation, keep the original time.
+    pub fn complete(&mut self, at: DateTime<Utc>) -> bool {
+        if self.completed.is_some() {
+            return false;
+        }
+        self.completed = Some(at);
+        true
+    }
+
+    /// Records a piece being received or uploaded
+    pub fn transferred(&mut self, at: DateTime<Utc>) {
+        self.last_active = Some(at);
+    }
+
+    /// Counts the time since the last tick as active if data was
+    /// transferred meanwhile, and as seeding if the torrent was.
+    pub fn tick(&mut self, active: bool, seeding: bool, now: Instant) {
+        let elapsed = now.duration_since(self.last_tick);
+        if active {
+            self.active += elapsed;
+        }
+        if seeding {
+            self.seeding += elapsed;
+        }
+        self.last_tick = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Activity;
+    use chrono::{Duration as CDuration, Utc};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_completed_once() {
+        let now = Instant::now();
+        let mut a = Activity::new(None, None, 10, 0, now);
+        assert_eq!(a.completed(), None);
+
+        let done = Utc::now();
+        assert!(a.complete(done));
+        assert!(!a.complete(done + CDuration::seconds(60)));
+        assert_eq!(a.completed(), Some(done));
+
+        a.transferred(done);
+        assert_eq!(a.last_active(), Some(done));
+        a.tick(true, false, now + Duration::from_secs(5));
+        a.tick(false, true, now + Duration::from_secs(20));
+        assert_eq!(a.active_secs(), 15);
+        assert_eq!(a.seeding_secs(), 15);
+    }
+}
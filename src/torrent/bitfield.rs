@@ -127,8 +127,10 @@ impl Bitfield {
                     let block_pos = pos / 8;
                     let index = 7 - (pos % 8);
                     let block = data[block_pos as usize];
-                    data[block_pos as usize] = block | (1 << index);
-                    *set += 1;
+                    if block & (1 << index) == 0 {
+                        data[block_pos as usize] = block | (1 << index);
+                        *set += 1;
+                    }
                 }
                 Bitfield::C { .. } => {}
             }
@@ -153,8 +155,10 @@ impl Bitfield {
                     let block_pos = pos / 8;
                     let index = 7 - (pos % 8);
                     let block = data[block_pos as usize];
-                    data[block_pos as usize] = block & !(1 << index);
-                    *set -= 1;
+                    if block & (1 << index) != 0 {
+                        data[block_pos as usize] = block & !(1 << index);
+                        *set -= 1;
+                    }
                 }
                 Bitfield::C { .. } => unreachable!(),
             }
@@ -275,6 +279,18 @@ mod tests {
         assert_eq!(pf.iter().count() as u64, pf.len());
     }
 
+    #[test]
+    fn test_repeated_set() {
+        let mut pf = Bitfield::new(10);
+        pf.set_bit(3);
+        pf.set_bit(3);
+        assert_eq!(pf.set(), 1);
+        assert!(!pf.complete());
+        pf.unset_bit(3);
+        pf.unset_bit(3);
+        assert_eq!(pf.set(), 0);
+    }
+
     #[test]
     fn test_create() {
         let pf = Bitfield::new(10);
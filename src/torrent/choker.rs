@@ -1,13 +1,40 @@
 use std::time::{Duration, Instant};
+use std::{cmp, mem};
 
 use control::cio;
 use torrent::Peer;
-use util::{random_sample, FHashSet, UHashMap};
+use util::{random_sample, FHashMap, FHashSet, UHashMap};
+use CONFIG;
+
+/// Number of peers unchoked unless changed by upload slot allocation
+pub const DEFAULT_SLOTS: usize = 5;
+/// Consecutive unchoke rounds a peer must upload to us below the
+/// minimum rate before it's flagged as a freeloader
+const FREELOADER_STRIKES: u8 = 3;
+/// Size of a requested block
+const BLOCK_LEN: u64 = 16_384;
 
 pub struct Choker {
     unchoked: Vec<usize>,
     interested: FHashSet<usize>,
+    /// Preferred peers, unchoked while interested without taking a slot
+    pinned: FHashSet<usize>,
     last_updated: Instant,
+    slots: usize,
+    freeloaders: Freeloaders,
+}
+
+/// Tracks unchoked peers which don't reciprocate while we're downloading.
+struct Freeloaders {
+    /// Rate in bytes/s below which a peer gets a strike, 0 if disabled
+    min_rate: u64,
+    /// Unchoke rounds for which a flagged peer is penalized
+    penalty: u32,
+    strikes: FHashMap<usize, u8>,
+    /// Flagged peers and the rounds left in their penalty
+    flagged: FHashMap<usize, u32>,
+    /// Peers whose flag changed since the last call to `take_changes`
+    changes: Vec<(usize, bool)>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -19,14 +46,65 @@ pub struct SwapRes {
 impl Choker {
     pub fn new() -> Choker {
         Choker {
-            unchoked: Vec::with_capacity(5),
+            unchoked: Vec::with_capacity(DEFAULT_SLOTS),
             interested: FHashSet::default(),
+            pinned: FHashSet::default(),
             last_updated: Instant::now(),
+            slots: DEFAULT_SLOTS,
+            freeloaders: Freeloaders::new(
+                CONFIG.peer.freeloader_rate,
+                CONFIG.peer.freeloader_penalty,
+            ),
+        }
+    }
+
+    /// Returns peers which were flagged as freeloaders or cleared
+    /// since the last call.
+    pub fn take_freeloader_changes(&mut self) -> Vec<(usize, bool)> {
+        mem::replace(&mut self.freeloaders.changes, Vec::new())
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// Changes the number of unchoked peers, immediately choking or
+    /// unchoking peers to match.
+    pub fn set_slots<T: cio::CIO>(&mut self, slots: usize, peers: &mut UHashMap<Peer<T>>) {
+        self.slots = slots;
+        while self.unchoked.len() > slots {
+            if let Some(id) = self.unchoked.pop() {
+                peers.get_mut(&id).map(Peer::choke);
+                self.interested.insert(id);
+            }
         }
+        while self.unchoked.len() < slots && self.unchoke_random(peers).is_some() {}
+    }
+
+    /// Keeps `peer` unchoked whenever it's interested, outside of the
+    /// regular slots and their rotation.
+    pub fn pin<T: cio::CIO>(&mut self, id: usize, peers: &mut UHashMap<Peer<T>>) {
+        self.pinned.insert(id);
+        if let Some(idx) = self.unchoked.iter().position(|&u| u == id) {
+            // Free its slot for someone else
+            self.unchoked.remove(idx);
+            self.unchoke_random(peers);
+        } else if self.interested.remove(&id) {
+            if let Some(peer) = peers.get_mut(&id) {
+                peer.unchoke();
+            }
+        }
+    }
+
+    /// Forgets a disconnected pinned peer
+    pub fn unpin(&mut self, id: usize) {
+        self.pinned.remove(&id);
     }
 
     pub fn add_peer<T: cio::CIO>(&mut self, peer: &mut Peer<T>) {
-        if self.unchoked.len() < 5 {
+        if self.pinned.contains(&peer.id()) {
+            peer.unchoke();
+        } else if self.unchoked.len() < self.slots {
             self.unchoked.push(peer.id());
             peer.flush();
             peer.unchoke();
@@ -36,7 +114,18 @@ impl Choker {
     }
 
     fn unchoke_random<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) -> Option<usize> {
-        if let Some(random_id) = random_sample(self.interested.iter()).cloned() {
+        // Freeloaders are only unchoked if nobody else is interested
+        let random = {
+            let flagged = &self.freeloaders.flagged;
+            random_sample(
+                self.interested
+                    .iter()
+                    .filter(|id| !flagged.contains_key(id)),
+            )
+            .or_else(|| random_sample(self.interested.iter()))
+            .cloned()
+        };
+        if let Some(random_id) = random {
             peers.get_mut(&random_id).map(|mut peer| {
                 self.interested.remove(&random_id);
                 self.add_peer(&mut peer);
@@ -52,7 +141,10 @@ impl Choker {
         peer: &mut Peer<T>,
         peers: &mut UHashMap<Peer<T>>,
     ) -> Option<SwapRes> {
-        if let Some(idx) = self.unchoked.iter().position(|&id| id == peer.id()) {
+        let res = if self.pinned.contains(&peer.id()) {
+            peer.choke();
+            None
+        } else if let Some(idx) = self.unchoked.iter().position(|&id| id == peer.id()) {
             self.unchoked.remove(idx);
             peer.choke();
             self.unchoke_random(peers).map(|unchoked| SwapRes {
@@ -62,12 +154,14 @@ impl Choker {
         } else {
             self.interested.remove(&peer.id());
             None
-        }
+        };
+        self.freeloaders.remove(peer.id());
+        res
     }
 
     fn update_timer(&mut self) -> Result<(), ()> {
         if self.last_updated.elapsed() < Duration::from_secs(10)
-            || self.unchoked.len() < 5
+            || self.unchoked.len() < self.slots
             || self.interested.is_empty()
         {
             Err(())
@@ -78,20 +172,28 @@ impl Choker {
     }
 
     pub fn update_upload<T: cio::CIO>(&mut self, peers: &mut UHashMap<Peer<T>>) -> Option<SwapRes> {
+        let elapsed = self.last_updated.elapsed();
         if self.update_timer().is_err() {
             return None;
         }
         if self.interested.is_empty() {
             return None;
         }
-        let (slowest, _) = self.unchoked.iter().enumerate().fold(
-            (0, std::u32::MAX),
-            |(slowest, min), (idx, id)| match peers.get_mut(id).map(Peer::flush) {
-                Some((ul, _)) if ul < min => (idx, ul),
-                _ => (slowest, min),
-            },
-        );
-        self.swap_peer(slowest, peers)
+        self.freeloaders.tick();
+        let mut slowest = (0, std::u32::MAX);
+        let mut freeloader = None;
+        for (idx, id) in self.unchoked.iter().enumerate() {
+            if let Some((ul, dl)) = peers.get_mut(id).map(Peer::flush) {
+                if ul < slowest.1 {
+                    slowest = (idx, ul);
+                }
+                if self.freeloaders.record(*id, dl, elapsed) && freeloader.is_none() {
+                    freeloader = Some(idx);
+                }
+            }
+        }
+        // Freeloaders lose their slot regardless of how fast we upload to them
+        self.swap_peer(freeloader.unwrap_or(slowest.0), peers)
     }
 
     pub fn update_download<T: cio::CIO>(
@@ -133,9 +235,65 @@ impl Choker {
     }
 }
 
+impl Freeloaders {
+    fn new(min_rate: u64, penalty: u32) -> Freeloaders {
+        Freeloaders {
+            min_rate,
+            penalty,
+            strikes: FHashMap::default(),
+            flagged: FHashMap::default(),
+            changes: Vec::new(),
+        }
+    }
+
+    /// Records the blocks downloaded from an unchoked peer over the last
+    /// round, returning whether it's flagged as a freeloader.
+    fn record(&mut self, id: usize, blocks: u32, elapsed: Duration) -> bool {
+        if self.min_rate == 0 {
+            return false;
+        }
+        let rate = u64::from(blocks) * BLOCK_LEN / cmp::max(elapsed.as_secs(), 1);
+        if rate >= self.min_rate {
+            self.strikes.remove(&id);
+            return self.flagged.contains_key(&id);
+        }
+        let strikes = {
+            let s = self.strikes.entry(id).or_insert(0);
+            *s += 1;
+            *s
+        };
+        if strikes >= FREELOADER_STRIKES {
+            self.strikes.remove(&id);
+            if self.flagged.insert(id, self.penalty).is_none() {
+                self.changes.push((id, true));
+            }
+        }
+        self.flagged.contains_key(&id)
+    }
+
+    /// Counts down penalties at the start of an unchoke round.
+    fn tick(&mut self) {
+        let changes = &mut self.changes;
+        self.flagged.retain(|&id, rounds| {
+            if *rounds == 0 {
+                changes.push((id, false));
+                return false;
+            }
+            *rounds -= 1;
+            true
+        });
+    }
+
+    fn remove(&mut self, id: usize) {
+        self.strikes.remove(&id);
+        self.flagged.remove(&id);
+        self.changes.retain(|&(cid, _)| cid != id);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Choker, SwapRes};
+    use super::{Choker, Freeloaders, SwapRes};
     use std::time::{Duration, Instant};
     use torrent::{Bitfield, Peer};
     use util::UHashMap;
@@ -176,6 +334,23 @@ mod tests {
         assert_eq!(c.unchoked.contains(&v[0].id()), false);
     }
 
+    #[test]
+    fn test_set_slots() {
+        let mut c = Choker::new();
+        let mut h = UHashMap::default();
+        for i in 0..8 {
+            let mut p = Peer::test_from_stats(i, 0, 0);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        c.set_slots(2, &mut h);
+        assert_eq!(c.unchoked.len(), 2);
+        assert_eq!(c.interested.len(), 6);
+        c.set_slots(7, &mut h);
+        assert_eq!(c.unchoked.len(), 7);
+        assert_eq!(c.interested.len(), 1);
+    }
+
     #[test]
     fn test_update_upload() {
         let mut c = Choker::new();
@@ -209,4 +384,74 @@ mod tests {
         assert_eq!(res.choked, 0);
         assert_eq!(res.unchoked, 5);
     }
+
+    #[test]
+    fn test_freeloader_choked() {
+        let mut c = Choker::new();
+        c.freeloaders = Freeloaders::new(1000, 2);
+        // Peer 0 is uploaded to the most, but never uploads to us
+        let stats = |i| {
+            if i == 0 {
+                Peer::test_from_stats(i, 100, 0)
+            } else {
+                Peer::test_from_stats(i, 1, 10)
+            }
+        };
+        let mut h = UHashMap::default();
+        for i in 0..6 {
+            let mut p = stats(i);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        assert!(c.unchoked.contains(&0));
+
+        for round in 0..3 {
+            for i in 0..6 {
+                h.insert(i, stats(i));
+            }
+            c.last_updated = Instant::now() - Duration::from_secs(11);
+            let res = c.update_upload(&mut h).unwrap();
+            if round < 2 {
+                // Plain tit-for-tat keeps the freeloader unchoked
+                assert!(res.choked != 0);
+                assert!(c.unchoked.contains(&0));
+            } else {
+                assert_eq!(res.choked, 0);
+            }
+        }
+        assert!(!c.unchoked.contains(&0));
+        assert_eq!(c.take_freeloader_changes(), vec![(0, true)]);
+    }
+
+    #[test]
+    fn test_pinned_peer() {
+        let mut c = Choker::new();
+        let mut h = UHashMap::default();
+        for i in 0..7 {
+            let mut p = Peer::test_from_stats(i, 0, 7 - i as u32);
+            c.add_peer(&mut p);
+            h.insert(i, p);
+        }
+        // Pinning an unchoked peer hands its slot to someone else
+        c.pin(0, &mut h);
+        assert!(!c.unchoked.contains(&0));
+        assert_eq!(c.unchoked.len(), 5);
+        assert_eq!(c.interested.len(), 1);
+
+        // and it's left out of the rotation from then on
+        for _ in 0..3 {
+            for i in 0..7 {
+                h.insert(i, Peer::test_from_stats(i, 0, 7 - i as u32));
+            }
+            c.last_updated = Instant::now() - Duration::from_secs(11);
+            let res = c.update_upload(&mut h).unwrap();
+            assert!(res.choked != 0 && res.unchoked != 0);
+        }
+        let mut p = Peer::test_from_stats(0, 0, 0);
+        assert!(c.remove_peer(&mut p, &mut h).is_none());
+        c.add_peer(&mut p);
+        assert_eq!(c.unchoked.len(), 5);
+        c.unpin(0);
+        assert!(c.pinned.is_empty());
+    }
 }
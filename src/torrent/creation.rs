@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{cmp, fs, io};
+
+use chrono::Utc;
+use url::Url;
+
+use super::info::{File, Info};
+use bencode::BEncode;
+use util::sha1_hash;
+
+/// Bounds of the piece length of created torrents
+const MIN_PIECE_LEN: u32 = 16_384;
+const MAX_PIECE_LEN: u32 = 16_777_216;
+/// Pieces a created torrent aims to have when no piece length is given
+const TARGET_PIECES: u64 = 1_500;
+
+/// Lays out the file or directory at `path` as the info of a new
+/// torrent, whose piece hashes are computed by a disk job. Returns the
+/// info along with the directory its files are relative to.
+pub fn create(
+    path: &Path,
+    trackers: &[Url],
+    piece_len: Option<u32>,
+    private: bool,
+) -> Result<(Info, PathBuf), String> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| format!("{:?} isn't a file or directory with a UTF8 name", path))?
+        .to_owned();
+    let dir = path
+        .parent()
+        .unwrap_or_else(|| Path::new("/"))
+        .to_path_buf();
+    let mut files = Vec::new();
+    list_files(path, Path::new(&name), &mut files)
+        .map_err(|e| format!("Couldn't read {:?}: {}", path, e))?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let total_len: u64 = files.iter().map(|f| f.length).sum();
+    if total_len == 0 {
+        return Err(format!("{:?} has no data to create a torrent of", path));
+    }
+    let piece_len = match piece_len {
+        Some(len) if len.is_power_of_two() && (MIN_PIECE_LEN..=MAX_PIECE_LEN).contains(&len) => len,
+        Some(len) => {
+            return Err(format!(
+                "Piece length {} must be a power of 2 from {} to {}",
+                len, MIN_PIECE_LEN, MAX_PIECE_LEN
+            ))
+        }
+        None => auto_piece_len(total_len),
+    };
+    let pieces = ((total_len - 1) / u64::from(piece_len) + 1) as usize;
+    let piece_idx = Info::generate_piece_idx(pieces, u64::from(piece_len), &files);
+
+    let info = Info {
+        be_name: Some(name.clone().into_bytes()),
+        raw: None,
+        name,
+        announce: trackers.first().cloned().map(Arc::new),
+        creator: Some(format!("synapse/{}", env!("CARGO_PKG_VERSION"))),
+        comment: None,
+        piece_len,
+        total_len,
+        hashes: vec![Vec::new(); pieces],
+        hash: [0u8; 20],
+        files,
+        private,
+        piece_idx,
+        // Every tracker gets a tier of its own, so all are announced to
+        url_list: if trackers.len() > 1 {
+            trackers
+                .iter()
+                .cloned()
+                .map(|t| vec![Arc::new(t)])
+                .collect()
+        } else {
+            vec![]
+        },
+        web_seeds: vec![],
+    };
+    Ok((info, dir))
+}
+
+/// Encodes the info of a created torrent, whose pieces have been
+/// hashed, as a .torrent file, returning it and the info hash.
+pub fn finish(mut info: Info) -> (Vec<u8>, [u8; 20]) {
+    info.hash = sha1_hash(&info.to_bencode().encode_to_buf());
+    let mut torrent = match info.to_torrent_bencode() {
+        BEncode::Dict(d) => d,
+        _ => BTreeMap::new(),
+    };
+    torrent.insert(
+        "creation date".to_owned(),
+        BEncode::Int(Utc::now().timestamp()),
+    );
+    (BEncode::Dict(torrent).encode_to_buf(), info.hash)
+}
+
+/// Smallest piece length giving no more than TARGET_PIECES pieces
+fn auto_piece_len(total_len: u64) -> u32 {
+    let len = ((total_len - 1) / TARGET_PIECES + 1).next_power_of_two();
+    cmp::min(
+        cmp::max(len, u64::from(MIN_PIECE_LEN)),
+        u64::from(MAX_PIECE_LEN),
+    ) as u32
+}
+
+/// Adds the regular files at `path` to `files` as `rel`, skipping
+/// symlinks so that a torrent can't contain files more than once.
+/// Names must be UTF8 to be stored in the metainfo as they are.
+fn list_files(path: &Path, rel: &Path, files: &mut Vec<File>) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_file() {
+        files.push(File {
+            path: rel.to_path_buf(),
+            length: meta.len(),
+            renamed: None,
+        });
+    } else if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_name().to_str().is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} doesn't have a UTF8 name", entry.path()),
+                ));
+            }
+            list_files(&entry.path(), &rel.join(entry.file_name()), files)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{auto_piece_len, create, finish};
+    use bencode;
+    use std::path::Path;
+    use std::{env, fs, process};
+    use torrent::Info;
+    use url::Url;
+
+    #[test]
+    fn test_auto_piece_len() {
+        assert_eq!(auto_piece_len(1), 16_384);
+        assert_eq!(auto_piece_len(1_500 * 262_144), 262_144);
+        assert_eq!(auto_piece_len(1_500 * 262_144 + 1), 524_288);
+        assert_eq!(auto_piece_len(u64::max_value() / 2), 16_777_216);
+    }
+
+    #[test]
+    fn test_create_layout() {
+        let dir = env::temp_dir().join(format!("synapse-create-{}", process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b"), vec![1u8; 20_000]).unwrap();
+        fs::write(root.join("sub").join("a"), vec![2u8; 30_000]).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+
+        assert!(create(&root, &[], Some(10_000), false).is_err());
+        assert!(create(&dir.join("missing"), &[], None, false).is_err());
+
+        let trackers = vec![
+            Url::parse("http://a.test/announce").unwrap(),
+            Url::parse("http://b.test/announce").unwrap(),
+        ];
+        let (mut info, base) = create(&root, &trackers, None, true).unwrap();
+        assert_eq!(base, dir);
+        assert_eq!(info.name, "root");
+        assert_eq!(info.piece_len, 16_384);
+        assert_eq!(info.pieces(), 4);
+        let paths: Vec<_> = info.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(paths, vec![Path::new("root/b"), Path::new("root/sub/a")]);
+
+        // The encoded torrent is read back with the same layout and hash
+        for (i, h) in info.hashes.iter_mut().enumerate() {
+            *h = vec![i as u8; 20];
+        }
+        let (data, hash) = finish(info);
+        let parsed = Info::from_bencode(bencode::decode_buf(&data).unwrap()).unwrap();
+        assert_eq!(parsed.hash, hash);
+        assert!(parsed.private);
+        assert_eq!(parsed.files.len(), 2);
+        assert_eq!(parsed.files[1].path, Path::new("root/sub/a"));
+        assert_eq!(parsed.url_list.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
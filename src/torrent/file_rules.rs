@@ -0,0 +1,126 @@
+//! Initial priorities of the files of added torrents, set by the
+//! `disk.file_priorities` rules, e.g. to skip samples or `.nfo` files.
+
+use std::cmp;
+
+use config::FilePriorityRule;
+use torrent::info::File;
+
+/// Returns the first rule matching `file`. Patterns containing a `/`
+/// are matched against the file's path in the torrent, others only
+/// against its name, ignoring case.
+pub fn matching<'a>(rules: &'a [FilePriorityRule], file: &File) -> Option<&'a FilePriorityRule> {
+    let path = file.path.to_string_lossy().to_lowercase();
+    let name = file
+        .path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    rules.iter().find(|r| {
+        let pattern = r.pattern.to_lowercase();
+        let text = if pattern.contains('/') { &path } else { &name };
+        glob(pattern.as_bytes(), text.as_bytes())
+    })
+}
+
+/// Priority changes the rules make to `files`, which start out
+/// with the default priority.
+pub fn priorities(rules: &[FilePriorityRule], files: &[File]) -> Vec<(usize, u8)> {
+    files
+        .iter()
+        .enumerate()
+        .filter_map(|(i, f)| matching(rules, f).map(|r| (i, cmp::min(r.priority, 5))))
+        .filter(|&(_, p)| p != 3)
+        .collect()
+}
+
+/// Matches `text` against a pattern of `*`(any sequence) and `?`(any
+/// single byte) wildcards.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    // Position after the last `*` and the text it matched up to,
+    // backtracked to when the rest fails to match
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&b'*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp;
+                    t = st + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob, matching, priorities};
+    use config::FilePriorityRule;
+    use std::path::PathBuf;
+    use torrent::info::File;
+
+    fn rule(pattern: &str, priority: u8) -> FilePriorityRule {
+        FilePriorityRule {
+            pattern: pattern.to_owned(),
+            priority,
+        }
+    }
+
+    fn file(path: &str) -> File {
+        File {
+            path: PathBuf::from(path),
+            length: 1,
+            renamed: None,
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob(b"*.nfo", b"release.nfo"));
+        assert!(glob(b"*sample*", b"sample.mkv"));
+        assert!(glob(b"ep??.mkv", b"ep01.mkv"));
+        assert!(glob(b"*", b""));
+        assert!(!glob(b"*.nfo", b"release.nfo.mkv"));
+        assert!(!glob(b"ep?.mkv", b"ep01.mkv"));
+    }
+
+    #[test]
+    fn test_added_priorities() {
+        let rules = vec![
+            rule("*sample*", 0),
+            rule("*.nfo", 0),
+            rule("*.txt", 1),
+            rule("extras/*", 2),
+        ];
+        let files = vec![
+            file("show/ep01.mkv"),
+            file("show/Sample/ep01-SAMPLE.mkv"),
+            file("show/release.NFO"),
+            file("show/notes.txt"),
+            file("extras/interview.mkv"),
+            file("show/extras/interview.mkv"),
+        ];
+        assert_eq!(
+            priorities(&rules, &files),
+            vec![(1, 0), (2, 0), (3, 1), (4, 2)]
+        );
+        assert_eq!(
+            matching(&rules, &files[3]).map(|r| r.pattern.as_str()),
+            Some("*.txt")
+        );
+        assert!(matching(&rules, &files[0]).is_none());
+        assert!(priorities(&[], &files).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use config::FlushPolicy;
+use CONFIG;
+
+/// How often a torrent's downloaded data is fsynced
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    pub policy: FlushPolicy,
+    /// Pieces or seconds between flushes, depending on the policy
+    pub every: u64,
+}
+
+impl Schedule {
+    /// The schedule used by torrents which don't set their own
+    pub fn configured() -> Schedule {
+        Schedule {
+            policy: CONFIG.disk.flush_policy,
+            every: CONFIG.disk.flush_every,
+        }
+    }
+}
+
+/// Tracks the files written since they were last flushed,
+/// deciding when they should be flushed again.
+pub struct Flusher {
+    default: Schedule,
+    /// Schedule set for this torrent over RPC
+    custom: Option<Schedule>,
+    /// Pieces completed since the last flush
+    pieces: u64,
+    last: Instant,
+    files: BTreeSet<usize>,
+}
+
+impl Flusher {
+    pub fn new(default: Schedule, custom: Option<Schedule>, now: Instant) -> Flusher {
+        Flusher {
+            default,
+            custom,
+            pieces: 0,
+            last: now,
+            files: BTreeSet::new(),
+        }
+    }
+
+    /// The schedule in use
+    pub fn schedule(&self) -> Schedule {
+        self.custom.unwrap_or(self.default)
+    }
+
+    pub fn custom(&self) -> Option<Schedule> {
+        self.custom
+    }
+
+    pub fn set_custom(&mut self, custom: Option<Schedule>) {
+        self.custom = custom;
+    }
+
+    /// Records a completed piece spanning `files`, returning the
+    /// files to flush if the schedule calls for it.
+    pub fn piece_done<I>(&mut self, files: I, now: Instant) -> Option<Vec<usize>>
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        self.files.extend(files);
+        self.pieces += 1;
+        let s = self.schedule();
+        match s.policy {
+            FlushPolicy::Piece => Some(self.take(now)),
+            FlushPolicy::Pieces if self.pieces >= s.every => Some(self.take(now)),
+            _ => None,
+        }
+    }
+
+    /// Periodically called, returning the files to flush
+    /// once the interval of a time based schedule elapses.
+    pub fn tick(&mut self, now: Instant) -> Option<Vec<usize>> {
+        let s = self.schedule();
+        if s.policy != FlushPolicy::Interval || self.files.is_empty() {
+            return None;
+        }
+        if now.duration_since(self.last) < Duration::from_secs(s.every) {
+            return None;
+        }
+        Some(self.take(now))
+    }
+
+    /// Returns every file which hasn't been flushed yet.
+    pub fn take(&mut self, now: Instant) -> Vec<usize> {
+        self.pieces = 0;
+        self.last = now;
+        let files = self.files.iter().cloned().collect();
+        self.files.clear();
+        files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Flusher, Schedule};
+    use config::FlushPolicy;
+    use std::time::{Duration, Instant};
+
+    fn schedule(policy: FlushPolicy, every: u64) -> Schedule {
+        Schedule { policy, every }
+    }
+
+    #[test]
+    fn test_flush_cadence() {
+        let now = Instant::now();
+        let mut f = Flusher::new(schedule(FlushPolicy::Piece, 16), None, now);
+        assert_eq!(f.piece_done(vec![0, 1], now), Some(vec![0, 1]));
+        assert_eq!(f.piece_done(vec![1], now), Some(vec![1]));
+
+        // Every third piece
+        f.set_custom(Some(schedule(FlushPolicy::Pieces, 3)));
+        assert_eq!(f.piece_done(vec![1], now), None);
+        assert_eq!(f.piece_done(vec![2], now), None);
+        assert_eq!(f.piece_done(vec![2], now), Some(vec![1, 2]));
+        assert_eq!(f.piece_done(vec![3], now), None);
+        assert_eq!(f.tick(now + Duration::from_secs(3600)), None);
+
+        // Every 10 seconds, regardless of pieces
+        f.set_custom(Some(schedule(FlushPolicy::Interval, 10)));
+        assert_eq!(f.piece_done(vec![4], now), None);
+        assert_eq!(f.tick(now + Duration::from_secs(5)), None);
+        assert_eq!(f.tick(now + Duration::from_secs(10)), Some(vec![3, 4]));
+        // Nothing was written since
+        assert_eq!(f.tick(now + Duration::from_secs(30)), None);
+
+        f.set_custom(None);
+        assert_eq!(f.schedule(), schedule(FlushPolicy::Piece, 16));
+    }
+}
@@ -1,13 +1,15 @@
-use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::{cmp, fmt, mem};
 
 use base32;
 use rand::{self, Rng};
+use url::form_urlencoded;
 use url::Url;
 
-use bencode::BEncode;
+use bencode::{self, BEncode};
 use disk;
 use util::{hash_to_id, id_to_hash, sha1_hash};
 
@@ -24,9 +26,14 @@ pub struct Info {
     pub files: Vec<File>,
     pub private: bool,
     pub be_name: Option<Vec<u8>>,
+    /// The info dict as it was encoded in the metainfo. Names in it
+    /// may differ from the paths of files, which are sanitized.
+    pub raw: Option<Vec<u8>>,
     /// Maps piece idx -> file idx + file offset
     pub piece_idx: Vec<(usize, u64)>,
     pub url_list: Vec<Vec<Arc<Url>>>,
+    /// BEP 19 web seed URLs
+    pub web_seeds: Vec<Arc<Url>>,
 }
 
 impl fmt::Debug for Info {
@@ -53,32 +60,47 @@ impl fmt::Debug for Info {
 
 #[derive(Clone, Debug)]
 pub struct File {
+    /// Path as given by the info dict
     pub path: PathBuf,
     pub length: u64,
+    /// Path the file was renamed to on disk, if any
+    pub renamed: Option<PathBuf>,
 }
 
 impl File {
+    /// Path of the file relative to the torrent's directory
+    pub fn disk_path(&self) -> &Path {
+        self.renamed.as_ref().unwrap_or(&self.path)
+    }
+
     fn from_bencode(data: BEncode) -> Result<File, &'static str> {
         let mut d = data.into_dict().ok_or("File must be a dictionary type!")?;
-        match (d.remove("name"), d.remove("path"), d.remove("length")) {
+        let path = match d.remove("path.utf-8") {
+            Some(p) => Some(p),
+            None => d.remove("path"),
+        };
+        let name = decode_name(&mut d, "name");
+        match (name, path, d.remove("length")) {
             (Some(v), None, Some(l)) => {
                 let f = File {
-                    path: PathBuf::from(v.into_string().ok_or("Path must be a valid string.")?),
+                    path: PathBuf::from(v.ok_or("Path must be a valid string.")?),
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    renamed: None,
                 };
                 Ok(f)
             }
             (None, Some(path), Some(l)) => {
                 let mut p = PathBuf::new();
                 for dir in path.into_list().ok_or("File path should be a list")? {
-                    p.push(
-                        dir.into_string()
+                    p.push(sanitize_name(
+                        &dir.into_bytes()
                             .ok_or("File path parts should be strings")?,
-                    );
+                    ));
                 }
                 let f = File {
                     path: p,
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    renamed: None,
                 };
                 Ok(f)
             }
@@ -140,8 +162,10 @@ impl Info {
             files: vec![],
             private: false,
             be_name: None,
+            raw: None,
             piece_idx: vec![],
             url_list: vec![url_list],
+            web_seeds: vec![],
         })
     }
 
@@ -149,6 +173,132 @@ impl Info {
         !self.hashes.is_empty()
     }
 
+    /// Returns the on disk paths of files which would be shared between
+    /// this torrent stored in `dir` and `other` stored in `other_dir`.
+    pub fn overlapping_files(&self, dir: &Path, other: &Info, other_dir: &Path) -> Vec<PathBuf> {
+        let ours: HashSet<_> = self.files.iter().map(|f| dir.join(f.disk_path())).collect();
+        other
+            .files
+            .iter()
+            .map(|f| other_dir.join(f.disk_path()))
+            .filter(|p| ours.contains(p))
+            .collect()
+    }
+
+    /// Renames a file on disk, returning its previous and new disk paths.
+    /// Files of multi file torrents must remain within the torrent's
+    /// directory, see `rename_root` to rename the directory itself.
+    pub fn rename_file(&mut self, file: usize, to: PathBuf) -> Result<(PathBuf, PathBuf), String> {
+        let from = match self.files.get(file) {
+            Some(f) => f.disk_path().to_owned(),
+            None => return Err(format!("File {} does not exist", file)),
+        };
+        if !valid_rename(&to) {
+            return Err(format!("Invalid path {}", to.display()));
+        }
+        let root = self.disk_root();
+        if root.is_some() && (to.components().count() < 2 || to.components().next() != root) {
+            return Err(format!(
+                "Path {} must be within the torrent's directory",
+                to.display()
+            ));
+        }
+        self.check_rename_target(&to)?;
+        self.move_disk_path(&from, &to);
+        Ok((from, to))
+    }
+
+    /// Renames the directory which the files of multi file torrents are
+    /// stored in, or the file of single file torrents, returning the
+    /// previous and new disk paths.
+    pub fn rename_root(&mut self, name: &str) -> Result<(PathBuf, PathBuf), String> {
+        let to = PathBuf::from(name);
+        if !valid_rename(&to) || to.components().count() != 1 {
+            return Err(format!("Invalid name {}", name));
+        }
+        let from = match self.disk_root() {
+            Some(root) => PathBuf::from(root.as_os_str()),
+            None => return self.rename_file(0, to),
+        };
+        self.check_rename_target(&to)?;
+        self.move_disk_path(&from, &to);
+        Ok((from, to))
+    }
+
+    /// Relocates all files whose disk path lies under `from` to `to`,
+    /// returning the indices of changed files.
+    pub fn move_disk_path(&mut self, from: &Path, to: &Path) -> Vec<usize> {
+        let mut changed = Vec::new();
+        for (i, f) in self.files.iter_mut().enumerate() {
+            let moved = match f.disk_path().strip_prefix(from) {
+                Ok(rest) if rest.as_os_str().is_empty() => to.to_owned(),
+                Ok(rest) => to.join(rest),
+                Err(_) => continue,
+            };
+            f.renamed = if moved == f.path { None } else { Some(moved) };
+            changed.push(i);
+        }
+        changed
+    }
+
+    /// First path component shared by all files of a multi file torrent
+    fn disk_root(&self) -> Option<Component> {
+        let multi = self.files.len() > 1
+            || self
+                .files
+                .first()
+                .map(|f| f.path.components().count() > 1)
+                .unwrap_or(false);
+        if multi {
+            self.files[0].disk_path().components().next()
+        } else {
+            None
+        }
+    }
+
+    /// Ensures that no file of the torrent would clash with a file or
+    /// directory at `to`.
+    fn check_rename_target(&self, to: &Path) -> Result<(), String> {
+        let clash = self
+            .files
+            .iter()
+            .map(|f| f.disk_path())
+            .any(|p| p.starts_with(to) || to.starts_with(p));
+        if clash {
+            Err(format!("Path {} is already in use", to.display()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether DHT and PEX may be used to find peers. Until a magnet's
+    /// metadata is acquired it could turn out to be private, so only
+    /// tracker peers are used unless there are no trackers at all.
+    pub fn public_sources(&self, has_trackers: bool) -> bool {
+        if self.complete() {
+            !self.private
+        } else {
+            !has_trackers
+        }
+    }
+
+    /// Builds a magnet URI of the torrent with the given trackers and
+    /// peer address hints.
+    pub fn to_magnet(&self, trackers: &[&Url], peers: &[SocketAddr]) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("xt", &format!("urn:btih:{}", hash_to_id(&self.hash)));
+        if !self.name.is_empty() {
+            query.append_pair("dn", &self.name);
+        }
+        for url in trackers {
+            query.append_pair("tr", url.as_str());
+        }
+        for addr in peers {
+            query.append_pair("x.pe", &addr.to_string());
+        }
+        format!("magnet:?{}", query.finish())
+    }
+
     pub fn to_torrent_bencode(&self) -> BEncode {
         let mut torrent = BTreeMap::new();
         let info = self.to_bencode();
@@ -158,11 +308,48 @@ impl Info {
                 BEncode::String(url.as_str().as_bytes().to_owned()),
             )
         });
+        if !self.url_list.is_empty() {
+            let tiers = self
+                .url_list
+                .iter()
+                .map(|tier| {
+                    BEncode::List(
+                        tier.iter()
+                            .map(|url| BEncode::String(url.as_str().as_bytes().to_owned()))
+                            .collect(),
+                    )
+                })
+                .collect();
+            torrent.insert("announce-list".to_owned(), BEncode::List(tiers));
+        }
+        if let Some(ref creator) = self.creator {
+            torrent.insert(
+                "created by".to_owned(),
+                BEncode::String(creator.as_bytes().to_owned()),
+            );
+        }
         torrent.insert("info".to_owned(), info);
         BEncode::Dict(torrent)
     }
 
+    /// Encodes the metainfo as a .torrent file, failing if it can't be
+    /// reproduced exactly, e.g. because names had to be sanitized and
+    /// the original info dict wasn't kept.
+    pub fn to_torrent_file(&self) -> Result<Vec<u8>, &'static str> {
+        let data = self.to_torrent_bencode().encode_to_buf();
+        let decoded = bencode::decode_buf(&data).map_err(|_| "Invalid metainfo encoding")?;
+        if Info::from_bencode(decoded)?.hash != self.hash {
+            return Err("Metainfo can't be reproduced with the same hash");
+        }
+        Ok(data)
+    }
+
     pub fn to_bencode(&self) -> BEncode {
+        // Re-encoding the parsed info would lose names which were
+        // sanitized or read from .utf-8 keys, changing the hash
+        if let Some(b) = self.raw.as_ref().and_then(|r| bencode::decode_buf(r).ok()) {
+            return b;
+        }
         let mut info = BTreeMap::new();
         if let Some(ref n) = self.be_name {
             info.insert("name".to_owned(), BEncode::String(n.clone()));
@@ -179,7 +366,8 @@ impl Info {
             pieces.extend_from_slice(h);
         }
         info.insert("pieces".to_owned(), BEncode::String(pieces));
-        if self.files.len() == 1 {
+        // A single file within a directory is still a multi file torrent
+        if self.files.len() == 1 && self.files[0].path.components().count() == 1 {
             info.insert(
                 "length".to_owned(),
                 BEncode::Int(self.files[0].length as i64),
@@ -191,17 +379,18 @@ impl Info {
                 .map(|f| {
                     let mut fb = BTreeMap::new();
                     fb.insert("length".to_owned(), BEncode::Int(f.length as i64));
-                    fb.insert(
-                        "path".to_owned(),
-                        BEncode::String(
-                            f.path
-                                .clone()
-                                .into_os_string()
-                                .into_string()
-                                .unwrap()
-                                .into_bytes(),
-                        ),
-                    );
+                    // Paths are stored relative to the torrent's name
+                    let path = f
+                        .path
+                        .components()
+                        .skip(1)
+                        .map(|c| {
+                            BEncode::String(
+                                c.as_os_str().to_string_lossy().into_owned().into_bytes(),
+                            )
+                        })
+                        .collect();
+                    fb.insert("path".to_owned(), BEncode::List(path));
                     BEncode::Dict(fb)
                 })
                 .collect();
@@ -228,12 +417,16 @@ impl Info {
                 let pl = i
                     .remove("piece length")
                     .and_then(|i| i.into_int())
-                    .ok_or("Info must specify piece length")? as u64;
+                    .ok_or("Info must specify piece length")?;
+                if pl <= 0 || pl > i64::from(u32::max_value()) {
+                    return Err("Piece length must be positive and fit in 32 bits");
+                }
+                let pl = pl as u64;
                 let hashes = i
                     .remove("pieces")
                     .and_then(|p| p.into_bytes())
                     .and_then(|p| {
-                        let mut v = Vec::new();
+                        let mut v = Vec::with_capacity(p.len() / 20);
                         let mut s = &p[..];
                         while s.len() >= 20 {
                             let mut next = vec![0u8; 20];
@@ -270,6 +463,7 @@ impl Info {
                     None
                 };
 
+                let multi = i.contains_key("files");
                 let files = parse_bencode_files(i)?;
                 let name = if files.is_empty() {
                     files[0]
@@ -312,6 +506,11 @@ impl Info {
                     })
                     .collect();
 
+                let web_seeds = d
+                    .remove("url-list")
+                    .map(|l| parse_web_seeds(l, &name, multi))
+                    .unwrap_or_else(Vec::new);
+
                 Ok(Info {
                     name,
                     comment,
@@ -324,8 +523,10 @@ impl Info {
                     total_len,
                     private,
                     be_name,
+                    raw: Some(info_bytes),
                     piece_idx,
                     url_list,
+                    web_seeds,
                 })
             })
     }
@@ -360,13 +561,16 @@ impl Info {
                 File {
                     path: PathBuf::new(),
                     length: 16_384 * pieces as u64,
+                    renamed: None,
                 };
                 1
             ],
             private: false,
             be_name: None,
+            raw: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
         }
     }
 
@@ -384,8 +588,10 @@ impl Info {
             files: vec![],
             private: false,
             be_name: None,
+            raw: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
         }
     }
 
@@ -420,6 +626,52 @@ impl Info {
         self.hashes.len() as u32
     }
 
+    /// Checks the piece count against the given limits, warning if it
+    /// exceeds `warn` and returning an error if it exceeds `max`.
+    /// A limit of 0 is disabled.
+    pub fn check_piece_count(&self, warn: u32, max: u32) -> Result<(), String> {
+        let pieces = self.pieces();
+        if max != 0 && pieces > max {
+            return Err(format!(
+                "Torrent {} has {} pieces, exceeding the limit of {}",
+                hash_to_id(&self.hash),
+                pieces,
+                max
+            ));
+        }
+        if warn != 0 && pieces > warn {
+            warn!(
+                "Torrent {} has {} pieces, this may use a lot of memory",
+                hash_to_id(&self.hash),
+                pieces
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks the piece length against the given bounds, returning an
+    /// error if it's outside of them. A bound of 0 is disabled. The last
+    /// piece is exempt, being whatever remains of the torrent.
+    pub fn check_piece_len(&self, min: u32, max: u32) -> Result<(), String> {
+        if min != 0 && self.piece_len < min {
+            return Err(format!(
+                "Torrent {} has a piece length of {} bytes, below the minimum of {}",
+                hash_to_id(&self.hash),
+                self.piece_len,
+                min
+            ));
+        }
+        if max != 0 && self.piece_len > max {
+            return Err(format!(
+                "Torrent {} has a piece length of {} bytes, above the maximum of {}",
+                hash_to_id(&self.hash),
+                self.piece_len,
+                max
+            ));
+        }
+        Ok(())
+    }
+
     /// Calculates the file offsets for a given block at index/begin
     pub fn block_disk_locs(info: &Arc<Info>, index: u32, begin: u32) -> LocIter {
         let len = info.block_len(index, begin);
@@ -552,8 +804,8 @@ fn parse_bencode_files(mut data: BTreeMap<String, BEncode>) -> Result<Vec<File>,
         Some(fs) => {
             let mut path = PathBuf::new();
             path.push(
-                data.remove("name")
-                    .and_then(|v| v.into_string())
+                decode_name(&mut data, "name")
+                    .and_then(|n| n)
                     .ok_or("Multifile mode must have a name field")?,
             );
             let mut files = Vec::new();
@@ -568,10 +820,221 @@ fn parse_bencode_files(mut data: BTreeMap<String, BEncode>) -> Result<Vec<File>,
     }
 }
 
+/// Checks that a renamed path is relative and doesn't escape the
+/// torrent's directory.
+fn valid_rename(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path.components().all(|c| match c {
+            Component::Normal(_) => true,
+            _ => false,
+        })
+}
+
+/// Parses a BEP 19 url-list, which may be a single URL or a list of them.
+/// URLs are normalized so that those of multi file torrents refer to the
+/// directory containing the torrent's root, and those of single file
+/// torrents refer to the file itself. Malformed entries are skipped.
+fn parse_web_seeds(list: BEncode, name: &str, multi: bool) -> Vec<Arc<Url>> {
+    let entries = match list {
+        BEncode::List(l) => l,
+        s @ BEncode::String(_) => vec![s],
+        _ => {
+            warn!("Ignoring url-list which isn't a string or list");
+            return Vec::new();
+        }
+    };
+    entries
+        .into_iter()
+        .filter_map(|e| {
+            let url = e
+                .into_string()
+                .and_then(|s| Url::parse(&s).ok())
+                .filter(|u| u.scheme() == "http" || u.scheme() == "https");
+            if url.is_none() {
+                warn!("Ignoring malformed url-list entry");
+            }
+            url
+        })
+        .map(|mut url| {
+            let dir = url.path().ends_with('/');
+            if multi && !dir {
+                let path = format!("{}/", url.path());
+                url.set_path(&path);
+            } else if !multi && dir {
+                if let Ok(mut path) = url.path_segments_mut() {
+                    path.pop_if_empty().push(name);
+                }
+            }
+            Arc::new(url)
+        })
+        .collect()
+}
+
+/// Removes a name field from a dict, preferring its `.utf-8` variant.
+/// Returns None if the field is absent, or Some(None) if it isn't a string.
+fn decode_name(d: &mut BTreeMap<String, BEncode>, key: &str) -> Option<Option<String>> {
+    let utf8 = d
+        .remove(&format!("{}.utf-8", key))
+        .and_then(BEncode::into_string)
+        .map(|n| sanitize_name(n.as_bytes()));
+    match (utf8, d.remove(key)) {
+        (Some(n), _) => Some(Some(n)),
+        (None, Some(v)) => Some(v.into_bytes().map(|b| sanitize_name(&b))),
+        (None, None) => None,
+    }
+}
+
+/// Converts a name into a single path component which is safe to use on
+/// this platform. Invalid UTF8 is replaced, as are separators and
+/// characters which aren't allowed in file names, and any leading BOM
+/// is dropped.
+fn sanitize_name(name: &[u8]) -> String {
+    let name: String = String::from_utf8_lossy(name)
+        .chars()
+        .skip_while(|&c| c == '\u{feff}')
+        .map(|c| match c {
+            '/' | '\0' => '_',
+            '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' if cfg!(windows) => '_',
+            c if cfg!(windows) && c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => name.replace('.', "_") + "_",
+        _ => name,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn torrent(info: BTreeMap<String, BEncode>) -> BEncode {
+        let mut info = info;
+        info.insert("piece length".to_owned(), BEncode::Int(16_384));
+        info.insert("pieces".to_owned(), BEncode::String(vec![0u8; 20]));
+        let mut d = BTreeMap::new();
+        d.insert("info".to_owned(), BEncode::Dict(info));
+        BEncode::Dict(d)
+    }
+
+    #[test]
+    fn utf8_name_fields() {
+        let mut info = BTreeMap::new();
+        info.insert(
+            "name".to_owned(),
+            BEncode::String(b"\xc4\xe3\xba\xc3".to_vec()),
+        );
+        info.insert(
+            "name.utf-8".to_owned(),
+            BEncode::String("你好".as_bytes().to_vec()),
+        );
+        info.insert("length".to_owned(), BEncode::Int(10));
+        let i = Info::from_bencode(torrent(info)).unwrap();
+        assert_eq!(i.name, "你好");
+        assert_eq!(i.files[0].path, PathBuf::from("你好"));
+        // The original name is kept for re-export
+        assert_eq!(i.be_name, Some(b"\xc4\xe3\xba\xc3".to_vec()));
+
+        let mut f = BTreeMap::new();
+        f.insert("length".to_owned(), BEncode::Int(10));
+        f.insert(
+            "path".to_owned(),
+            BEncode::List(vec![BEncode::String(b"\xff".to_vec())]),
+        );
+        f.insert(
+            "path.utf-8".to_owned(),
+            BEncode::List(vec![
+                BEncode::String(b"dir".to_vec()),
+                BEncode::String("ファイル".as_bytes().to_vec()),
+            ]),
+        );
+        let mut info = BTreeMap::new();
+        info.insert(
+            "name".to_owned(),
+            BEncode::String(b"\xef\xbb\xbfmulti".to_vec()),
+        );
+        info.insert("files".to_owned(), BEncode::List(vec![BEncode::Dict(f)]));
+        let i = Info::from_bencode(torrent(info)).unwrap();
+        assert_eq!(i.files[0].path, PathBuf::from("multi/dir/ファイル"));
+        // The info dict served to peers still has the original keys
+        assert_eq!(sha1_hash(&i.to_bencode().encode_to_buf()), i.hash);
+    }
+
+    #[test]
+    fn test_torrent_file_round_trip() {
+        let file = |path: &[&str], len| {
+            let mut f = BTreeMap::new();
+            f.insert("length".to_owned(), BEncode::Int(len));
+            let path = path
+                .iter()
+                .map(|p| BEncode::String(p.as_bytes().to_vec()))
+                .collect();
+            f.insert("path".to_owned(), BEncode::List(path));
+            BEncode::Dict(f)
+        };
+        let mut info = BTreeMap::new();
+        info.insert("name".to_owned(), BEncode::String(b"album".to_vec()));
+        info.insert(
+            "files".to_owned(),
+            BEncode::List(vec![file(&["cd1", "01.flac"], 6), file(&["cover.jpg"], 4)]),
+        );
+        let original = Info::from_bencode(torrent(info)).unwrap();
+
+        // The archived file can be re-added as the same torrent
+        let data = original.to_torrent_file().unwrap();
+        let readded = Info::from_bencode(bencode::decode_buf(&data).unwrap()).unwrap();
+        assert_eq!(readded.hash, original.hash);
+        assert_eq!(readded.files[0].path, PathBuf::from("album/cd1/01.flac"));
+        assert_eq!(readded.files[1].path, PathBuf::from("album/cover.jpg"));
+
+        let mut info = BTreeMap::new();
+        info.insert("name".to_owned(), BEncode::String(b"single".to_vec()));
+        info.insert("length".to_owned(), BEncode::Int(10));
+        let original = Info::from_bencode(torrent(info)).unwrap();
+        let data = original.to_torrent_file().unwrap();
+        let readded = Info::from_bencode(bencode::decode_buf(&data).unwrap()).unwrap();
+        assert_eq!(readded.hash, original.hash);
+
+        // Sanitized names are only reproduced from the original dict
+        let mut info = BTreeMap::new();
+        info.insert("name".to_owned(), BEncode::String(b"single".to_vec()));
+        info.insert(
+            "files".to_owned(),
+            BEncode::List(vec![file(&["..", "x"], 4)]),
+        );
+        let mut original = Info::from_bencode(torrent(info)).unwrap();
+        assert_eq!(original.files[0].path, PathBuf::from("single/___/x"));
+        let data = original.to_torrent_file().unwrap();
+        let readded = Info::from_bencode(bencode::decode_buf(&data).unwrap()).unwrap();
+        assert_eq!(readded.hash, original.hash);
+        original.raw = None;
+        assert!(original.to_torrent_file().is_err());
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        let mut f = BTreeMap::new();
+        f.insert("length".to_owned(), BEncode::Int(10));
+        f.insert(
+            "path".to_owned(),
+            BEncode::List(vec![
+                BEncode::String(b"..".to_vec()),
+                BEncode::String(b"a/b\xff".to_vec()),
+            ]),
+        );
+        let mut info = BTreeMap::new();
+        info.insert("name".to_owned(), BEncode::String(b"caf\xe9".to_vec()));
+        info.insert("files".to_owned(), BEncode::List(vec![BEncode::Dict(f)]));
+        let i = Info::from_bencode(torrent(info)).unwrap();
+        assert_eq!(i.name, "caf\u{fffd}");
+        assert_eq!(
+            i.files[0].path,
+            PathBuf::from("caf\u{fffd}/___/a_b\u{fffd}")
+        );
+        assert_eq!(i.be_name, Some(b"caf\xe9".to_vec()));
+    }
+
     #[test]
     fn correct_piece_len() {
         let scale = 3;
@@ -591,6 +1054,167 @@ mod tests {
         assert_eq!(info.block_len(pieces, 16_384), (end % 16_384) as u32);
     }
 
+    #[test]
+    fn overlapping_files() {
+        let mut a = Info::with_pieces(1);
+        a.files = vec![
+            File {
+                path: PathBuf::from("foo/a"),
+                length: 1,
+                renamed: None,
+            },
+            File {
+                path: PathBuf::from("foo/b"),
+                length: 1,
+                renamed: None,
+            },
+        ];
+        let mut b = Info::with_pieces(1);
+        b.files = vec![File {
+            path: PathBuf::from("b"),
+            length: 1,
+            renamed: None,
+        }];
+
+        let dl = Path::new("/dl");
+        assert!(a.overlapping_files(dl, &b, dl).is_empty());
+        assert_eq!(
+            a.overlapping_files(dl, &b, Path::new("/dl/foo")),
+            vec![PathBuf::from("/dl/foo/b")]
+        );
+        assert!(a.overlapping_files(dl, &a, Path::new("/other")).is_empty());
+    }
+
+    #[test]
+    fn magnet_round_trip() {
+        let mut info = Info::with_pieces(1);
+        info.name = "a name & more".to_owned();
+        info.hash = [0xAB; 20];
+        let a = Url::parse("http://a.example/announce?passkey=1&x=2").unwrap();
+        let b = Url::parse("udp://b.example:80").unwrap();
+        let peer = "10.0.0.1:6881".parse().unwrap();
+        let magnet = info.to_magnet(&[&a, &b], &[peer]);
+        assert!(magnet.contains("&x.pe=10.0.0.1%3A6881"));
+
+        let parsed = Info::from_magnet(&magnet).unwrap();
+        assert_eq!(parsed.hash, info.hash);
+        assert_eq!(parsed.name, info.name);
+        let mut trackers: Vec<_> = parsed.url_list[0].iter().map(|u| u.as_str()).collect();
+        trackers.sort();
+        assert_eq!(trackers, vec![a.as_str(), b.as_str()]);
+
+        // Magnets without metadata have no name yet
+        info.name = String::new();
+        assert!(!info.to_magnet(&[], &[]).contains("dn="));
+    }
+
+    #[test]
+    fn magnet_public_sources() {
+        let mut info = Info::from_magnet(
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567\
+             &tr=http%3A%2F%2Ftracker.example%2Fannounce",
+        )
+        .unwrap();
+        assert!(!info.public_sources(true));
+        assert!(info.public_sources(false));
+
+        // Metadata reveals that the torrent is private
+        info.hashes = vec![vec![0u8; 20]];
+        info.private = true;
+        assert!(!info.public_sources(true));
+        assert!(!info.public_sources(false));
+
+        info.private = false;
+        assert!(info.public_sources(true));
+    }
+
+    #[test]
+    fn rename_paths() {
+        let mut info = Info::with_pieces(1);
+        info.files = vec![
+            File {
+                path: PathBuf::from("foo/a"),
+                length: 1,
+                renamed: None,
+            },
+            File {
+                path: PathBuf::from("foo/sub/b"),
+                length: 1,
+                renamed: None,
+            },
+        ];
+        let be = info.to_bencode().encode_to_buf();
+
+        assert!(info.rename_file(0, PathBuf::from("foo/sub/b")).is_err());
+        assert!(info.rename_file(0, PathBuf::from("bar/a")).is_err());
+        assert!(info.rename_file(0, PathBuf::from("foo/../a")).is_err());
+        assert!(info.rename_file(2, PathBuf::from("foo/c")).is_err());
+        assert_eq!(
+            info.rename_file(0, PathBuf::from("foo/c")),
+            Ok((PathBuf::from("foo/a"), PathBuf::from("foo/c")))
+        );
+
+        assert!(info.rename_root("a/b").is_err());
+        assert_eq!(
+            info.rename_root("bar"),
+            Ok((PathBuf::from("foo"), PathBuf::from("bar")))
+        );
+        assert_eq!(info.files[0].disk_path(), Path::new("bar/c"));
+        assert_eq!(info.files[1].disk_path(), Path::new("bar/sub/b"));
+
+        // Info dict names are left untouched
+        assert_eq!(info.files[0].path, PathBuf::from("foo/a"));
+        assert_eq!(info.to_bencode().encode_to_buf(), be);
+
+        // Moving back restores the original names
+        info.move_disk_path(Path::new("bar"), Path::new("foo"));
+        assert_eq!(info.files[1].renamed, None);
+
+        let mut single = Info::with_pieces(1);
+        single.files[0].path = PathBuf::from("a");
+        assert_eq!(
+            single.rename_root("b"),
+            Ok((PathBuf::from("a"), PathBuf::from("b")))
+        );
+    }
+
+    #[test]
+    fn piece_count_limits() {
+        let info = Info::with_pieces(100);
+        assert!(info.check_piece_count(0, 0).is_ok());
+        assert!(info.check_piece_count(50, 0).is_ok());
+        assert!(info.check_piece_count(50, 100).is_ok());
+        assert!(info.check_piece_count(0, 99).is_err());
+    }
+
+    #[test]
+    fn piece_len_limits() {
+        let kib = 1024;
+        let mut info = Info::with_pieces(1);
+        assert!(info.check_piece_len(16 * kib, 64 * kib * kib).is_ok());
+        assert!(info.check_piece_len(0, 0).is_ok());
+
+        info.piece_len = 1;
+        assert!(info.check_piece_len(16 * kib, 64 * kib * kib).is_err());
+        assert!(info.check_piece_len(0, 64 * kib * kib).is_ok());
+
+        info.piece_len = 128 * kib * kib;
+        assert!(info.check_piece_len(16 * kib, 64 * kib * kib).is_err());
+        assert!(info.check_piece_len(16 * kib, 0).is_ok());
+
+        // Lengths which can't be represented are rejected outright
+        for &pl in &[0, -16_384, 1 << 32] {
+            let mut d = BTreeMap::new();
+            d.insert("length".to_owned(), BEncode::Int(10));
+            d.insert("name".to_owned(), BEncode::String(b"a".to_vec()));
+            let mut t = torrent(d).into_dict().unwrap();
+            if let Some(&mut BEncode::Dict(ref mut i)) = t.get_mut("info") {
+                i.insert("piece length".to_owned(), BEncode::Int(pl));
+            }
+            assert!(Info::from_bencode(BEncode::Dict(t)).is_err());
+        }
+    }
+
     #[test]
     fn loc_iter_bounds() {
         let mut info = Info::with_pieces(4);
@@ -598,10 +1222,12 @@ mod tests {
         info.files.push(File {
             path: PathBuf::from(""),
             length: 40000,
+            renamed: None,
         });
         info.files.push(File {
             path: PathBuf::from(""),
             length: 10000,
+            renamed: None,
         });
         info.total_len = 50000;
         info.piece_idx =
@@ -643,4 +1269,57 @@ mod tests {
         assert_eq!(n.file, 1);
         assert_eq!(n.offset, 16384 - 7232);
     }
+
+    #[test]
+    fn web_seed_forms() {
+        let seeds = |url_list, multi| {
+            let mut info = BTreeMap::new();
+            info.insert("name".to_owned(), BEncode::String(b"a b".to_vec()));
+            if multi {
+                let mut f = BTreeMap::new();
+                f.insert("length".to_owned(), BEncode::Int(10));
+                f.insert(
+                    "path".to_owned(),
+                    BEncode::List(vec![BEncode::String(b"f".to_vec())]),
+                );
+                info.insert("files".to_owned(), BEncode::List(vec![BEncode::Dict(f)]));
+            } else {
+                info.insert("length".to_owned(), BEncode::Int(10));
+            }
+            let mut t = torrent(info).into_dict().unwrap();
+            t.insert("url-list".to_owned(), url_list);
+            Info::from_bencode(BEncode::Dict(t))
+                .unwrap()
+                .web_seeds
+                .iter()
+                .map(|u| u.as_str().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let s = |s: &str| BEncode::String(s.as_bytes().to_vec());
+
+        // A single string
+        assert_eq!(
+            seeds(s("http://a.example/file"), false),
+            vec!["http://a.example/file"]
+        );
+        // Directory style URLs of single file torrents get the name appended
+        assert_eq!(
+            seeds(s("http://a.example/files/"), false),
+            vec!["http://a.example/files/a%20b"]
+        );
+        // A list, with malformed entries skipped
+        assert_eq!(
+            seeds(
+                BEncode::List(vec![
+                    s("http://a.example/files"),
+                    s(""),
+                    BEncode::Int(1),
+                    s("https://b.example/"),
+                ]),
+                true,
+            ),
+            vec!["http://a.example/files/", "https://b.example/"]
+        );
+        assert!(seeds(BEncode::Int(1), false).is_empty());
+    }
 }
@@ -0,0 +1,144 @@
+use torrent::Bitfield;
+
+/// Size of a metadata chunk exchanged with ut_metadata
+pub const CHUNK_LEN: usize = 16_384;
+/// Largest info dictionary accepted from peers, which is enough for
+/// millions of pieces
+pub const MAX_SIZE: usize = 64 * 1024 * 1024;
+
+/// Progress of fetching a magnet's info dictionary from peers.
+pub struct Metadata {
+    /// Raw info dictionary, empty until a peer reports its size
+    bytes: Vec<u8>,
+    /// Chunks received so far
+    have: Bitfield,
+}
+
+impl Metadata {
+    pub fn new() -> Metadata {
+        Metadata {
+            bytes: Vec::new(),
+            have: Bitfield::new(0),
+        }
+    }
+
+    /// Restores progress saved in a session, returning None if
+    /// it's inconsistent.
+    pub fn restore(bytes: Vec<u8>, have: Bitfield) -> Option<Metadata> {
+        if have.len() != chunks(bytes.len()) as u64 {
+            return None;
+        }
+        Some(Metadata { bytes, have })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    /// Sets the size of the info dictionary reported by a peer,
+    /// unless it is already known. Returns false if the size is
+    /// implausible, in which case the peer shouldn't be trusted.
+    pub fn set_size(&mut self, size: i64) -> bool {
+        if size <= 0 || size as u64 > MAX_SIZE as u64 {
+            return false;
+        }
+        if self.bytes.is_empty() {
+            self.bytes = vec![0u8; size as usize];
+            self.have = Bitfield::new(chunks(size as usize) as u64);
+        }
+        true
+    }
+
+    /// Size of the info dictionary, 0 until it is known
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Chunks which haven't been received yet
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.have.len())
+            .filter(|&i| !self.have.has_bit(i))
+            .map(|i| i as usize)
+            .collect()
+    }
+
+    /// Whether `idx` is a valid chunk index
+    pub fn valid(&self, idx: usize) -> bool {
+        (idx as u64) < self.have.len()
+    }
+
+    /// Stores a received chunk, returning whether every
+    /// chunk has now been received.
+    pub fn add(&mut self, idx: usize, data: &[u8]) -> Result<bool, ()> {
+        if !self.valid(idx) {
+            return Err(());
+        }
+        let start = idx * CHUNK_LEN;
+        let len = ::std::cmp::min(CHUNK_LEN, self.bytes.len() - start);
+        if data.len() != len {
+            return Err(());
+        }
+        self.bytes[start..start + len].copy_from_slice(data);
+        self.have.set_bit(idx as u64);
+        Ok(self.have.complete())
+    }
+
+    /// Discards all received chunks, e.g. because the assembled
+    /// dictionary didn't match the infohash.
+    pub fn reset(&mut self) {
+        self.have = Bitfield::new(self.have.len());
+    }
+}
+
+fn chunks(size: usize) -> usize {
+    (size + CHUNK_LEN - 1) / CHUNK_LEN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metadata, CHUNK_LEN, MAX_SIZE};
+    use torrent::Bitfield;
+
+    #[test]
+    fn test_resume_fetch() {
+        let size = CHUNK_LEN * 2 + 100;
+        let mut m = Metadata::new();
+        assert!(m.set_size(size as i64));
+        assert_eq!(m.missing(), vec![0, 1, 2]);
+        assert_eq!(m.add(0, &[1u8; CHUNK_LEN]), Ok(false));
+        assert_eq!(m.add(2, &[3u8; 100]), Ok(false));
+        // Short or out of range chunks are rejected
+        assert!(m.add(1, &[2u8; 10]).is_err());
+        assert!(m.add(3, &[0u8; 10]).is_err());
+
+        // Restart, with progress saved in the session
+        let saved = (m.bytes().to_vec(), m.have().data(), m.have().len());
+        let mut m = Metadata::restore(saved.0, Bitfield::from(saved.1, saved.2)).unwrap();
+        assert!(m.set_size(size as i64 * 2));
+        assert_eq!(m.size(), size);
+        assert_eq!(m.missing(), vec![1]);
+        assert_eq!(m.add(1, &[2u8; CHUNK_LEN]), Ok(true));
+        let bytes = m.bytes();
+        assert_eq!(bytes[0], 1);
+        assert_eq!(bytes[CHUNK_LEN], 2);
+        assert_eq!(bytes[size - 1], 3);
+
+        assert!(Metadata::restore(vec![0u8; size], Bitfield::new(1)).is_none());
+    }
+
+    #[test]
+    fn test_implausible_size() {
+        let mut m = Metadata::new();
+        assert!(!m.set_size(0));
+        assert!(!m.set_size(-(CHUNK_LEN as i64)));
+        assert!(!m.set_size(MAX_SIZE as i64 + 1));
+        assert_eq!(m.size(), 0);
+        assert!(m.missing().is_empty());
+        assert!(m.set_size(CHUNK_LEN as i64 * 3));
+        assert_eq!(m.missing().len(), 3);
+    }
+}
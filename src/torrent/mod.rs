@@ -1,41 +1,67 @@
+mod activity;
 pub mod bitfield;
 mod choker;
+pub mod creation;
+mod file_rules;
+mod flush;
 pub mod info;
+mod metadata;
 pub mod peer;
+pub mod pex;
 mod picker;
+mod sources;
+mod super_seed;
+mod webseed;
 
 use std::borrow::Cow;
 use std::collections::{BTreeMap, VecDeque};
-use std::fmt;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use std::{cmp, fmt, fs, iter, mem};
 
 use bencode::BEncode;
 use bincode;
 use byteorder::{BigEndian, ByteOrder};
 use chrono::{DateTime, Utc};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use rand::{self, Rng};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use url::Url;
 
 pub use self::bitfield::Bitfield;
+pub use self::creation::create;
 pub use self::info::{Info, LocIter};
 pub use self::peer::Message;
 pub use self::peer::{Peer, PeerConn};
 pub use self::picker::Block;
 
-use self::picker::Picker;
+use self::activity::Activity;
+use self::metadata::Metadata;
+use self::picker::{Picker, PieceStrategy};
+use self::super_seed::SuperSeed;
 use buffers::Buffer;
+use config::{Allocation, DuplicateAdd, FlushPolicy, NotifyEvent};
 use control::cio;
 use rpc::resource::{self, Resource, SResourceUpdate};
-use session::torrent::current::Session;
+use session::torrent::current::{FileStat, Session};
 use throttle::Throttle;
-use tracker::{self, TrackerResponse};
-use util::{FHashSet, UHashMap};
-use {bencode, disk, rpc, util, CONFIG, EXT_PROTO, UT_META_ID, UT_PEX_ID};
+use tracker::{self, ScrapeResponse, TrackerResponse};
+use util::{FHashMap, FHashSet, UHashMap};
+use {bencode, disk, notify, rpc, util, CONFIG, EXT_PROTO, UT_META_ID, UT_PEX_ID};
 use {session, stat};
 
 const MAX_PEERS: usize = 50;
+/// Minimum time between manual announces to a tracker which
+/// didn't specify a min interval
+const MIN_REANNOUNCE_SECS: u32 = 60;
+/// Peers included as address hints in magnet links
+const MAGNET_PEERS: usize = 5;
+/// Time after losing a piece during which peers may still request
+/// it without it counting against them
+const LOST_PIECE_GRACE_SECS: u64 = 60;
+/// Time a stream allows for each successive piece it needs
+const STREAM_PIECE_MS: u64 = 500;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TrackerStatus {
@@ -44,6 +70,8 @@ pub enum TrackerStatus {
         seeders: u32,
         leechers: u32,
         interval: u32,
+        min_interval: Option<u32>,
+        warning: Option<String>,
     },
     Failure(String),
 }
@@ -52,11 +80,21 @@ pub struct Torrent<T: cio::CIO> {
     id: usize,
     pieces: Bitfield,
     validating: FHashSet<u32>,
+    /// Pieces which we had but failed a recheck, and when
+    lost: FHashMap<u32, Instant>,
     info: Arc<Info>,
     cio: T,
     uploaded: u64,
     downloaded: u64,
     wasted: u64,
+    /// Bytes of pieces which failed their hash check since the
+    /// corruption limit was last reached
+    corrupt: u64,
+    /// Addresses of the peers which sent blocks of each downloading piece
+    contributors: FHashMap<u32, Vec<IpAddr>>,
+    /// Peers which contributed to pieces failing their hash check,
+    /// to be penalized by control
+    bad_peers: Vec<IpAddr>,
     stat: stat::EMA,
     files: Files,
     priority: u8,
@@ -71,16 +109,94 @@ pub struct Torrent<T: cio::CIO> {
     dirty: bool,
     path: Option<String>,
     info_bytes: Vec<u8>,
-    info_idx: Option<usize>,
+    /// Progress of fetching the info dictionary of a magnet
+    meta: Option<Metadata>,
+    /// Peers asked for a first metadata chunk, which are asked
+    /// for the rest once they respond
+    meta_probed: FHashSet<usize>,
     created: DateTime<Utc>,
+    activity: Activity,
+    conflicts: Vec<String>,
+    integrity: Integrity,
+    /// Connections kept open when the torrent last completed
+    preserved_peers: u16,
+    /// Pieces to be downloaded exclusively, if requested over RPC
+    target: Option<Target>,
+    /// Pieces needed by each stream of a file, and by when
+    streams: UHashMap<Vec<(Instant, u32)>>,
+    next_stream: usize,
+    web_seeds: webseed::WebSeeds,
+    /// Tracker group whose upload slots are shared with this torrent
+    tracker_group: Option<String>,
+    /// Upload slots gained or lost in the group by the warm connection bias
+    warm_bias: i16,
+    /// Upload slots gained or lost in the group by the swarm's availability
+    availability_boost: i16,
+    sources: sources::Sources,
+    /// Peers sent to and received from the swarm over PEX
+    pex_sent: u64,
+    pex_received: u64,
+    flush: flush::Flusher,
+    /// Whether pieces close to recent writes are preferred
+    piece_affinity: bool,
+    /// Seed delay set over RPC, in place of the configured one
+    seed_delay: Option<u64>,
+    /// Seeding limits set over RPC, in place of the configured ones
+    ratio_limit: Option<f32>,
+    seed_time_limit: Option<u64>,
+    /// Whether endgame was active as last reported over RPC
+    endgame: bool,
+    super_seed: SuperSeed,
+    /// Peers whose pieces were re-derived on a suspected desync
+    peer_resyncs: u64,
+    /// Whether writes are quiesced and the data held meanwhile
+    /// has filled the buffer, so no more blocks are requested
+    buffer_full: bool,
+    /// Stats of each file when the torrent was last serialized, used
+    /// to skip validation of files which haven't changed since
+    file_stats: Vec<Option<FileStat>>,
+    allocation: Allocation,
+}
+
+/// Restricted download of a subset of pieces
+struct Target {
+    /// Target pieces which aren't yet downloaded
+    pieces: FHashSet<u32>,
+    /// Whether to pause, rather than continue downloading
+    /// normally, once all pieces are downloaded
+    pause: bool,
+}
+
+/// State of the background integrity sweep
+struct Integrity {
+    /// Whether the torrent takes part in sweeps
+    enabled: bool,
+    /// Next piece to be rehashed
+    idx: u32,
+    /// Whether a batch of pieces is being rehashed
+    checking: bool,
+    last_sweep: Option<DateTime<Utc>>,
+    /// Corrupt pieces found this session
+    corrupt: u64,
 }
 
 #[derive(Clone, Debug)]
 pub struct Status {
     pub paused: bool,
     pub validating: Option<f32>,
+    /// Progress of moving the files to another directory, during
+    /// which none of them are read or written
+    pub moving: Option<f32>,
     pub error: Option<String>,
     pub state: StatusState,
+    /// Magnet waiting for a metadata fetch slot
+    pub meta_queued: bool,
+    /// Whether `error` holds the reason every tracker rejected
+    /// the torrent as unknown
+    pub unregistered: bool,
+    /// When a completed torrent announces completion and starts
+    /// seeding, if it's waiting out its seed delay
+    pub completing: Option<Instant>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -96,6 +212,192 @@ pub struct Tracker {
     pub status: TrackerStatus,
     pub last_announce: DateTime<Utc>,
     pub update: Option<Instant>,
+    pub headers: tracker::Headers,
+    /// Consecutive failed announces
+    pub failures: u32,
+    /// Whether automatic announces are suspended until `update`
+    pub dead: bool,
+    /// Announce list tier, trackers of later tiers are only used
+    /// once every tracker of the earlier ones failed
+    pub tier: u32,
+    /// Tracker id to echo back on announces
+    pub tracker_id: Option<Vec<u8>>,
+    /// Statistics returned by the last successful scrape
+    pub scrape: Option<tracker::ScrapeResponse>,
+}
+
+impl Tracker {
+    /// Records a failed announce made at `now`, marking the tracker dead
+    /// once `tracker.dead_failures` announces in a row have failed.
+    fn fail(&mut self, now: Instant) {
+        let max = CONFIG.trk.dead_failures;
+        let cooldown = Duration::from_secs(CONFIG.trk.dead_cooldown_hours * 60 * 60);
+        self.fail_with(max, cooldown, now);
+    }
+
+    fn fail_with(&mut self, max: u32, cooldown: Duration, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        if max != 0 && self.failures >= max {
+            if !self.dead {
+                warn!(
+                    "Tracker {} failed {} times in a row, marking it dead",
+                    self.url, self.failures
+                );
+            }
+            self.dead = true;
+            self.update = Some(now + cooldown);
+        } else {
+            // Wait 5 minutes before trying again
+            self.update = Some(now + Duration::from_secs(300));
+        }
+    }
+
+    fn succeed(&mut self) {
+        self.failures = 0;
+        self.dead = false;
+    }
+
+    /// Whether automatic announces to the tracker are suspended at `now`
+    fn skipped(&self, now: Instant) -> bool {
+        self.dead && self.update.map(|u| now < u).unwrap_or(false)
+    }
+}
+
+/// Returns the seconds until a tracker may be announced to manually, based
+/// on its min interval, or a default if it didn't send one. Trackers whose
+/// last announce failed may be announced to at any time.
+fn reannounce_wait(status: &TrackerStatus, last: DateTime<Utc>, now: DateTime<Utc>) -> i64 {
+    let min = match *status {
+        TrackerStatus::Ok { min_interval, .. } => {
+            i64::from(min_interval.unwrap_or(MIN_REANNOUNCE_SECS))
+        }
+        _ => return 0,
+    };
+    cmp::max(min - now.signed_duration_since(last).num_seconds(), 0)
+}
+
+/// Returns the delay until the next announce, adding a random jitter of
+/// up to `jitter` percent of the interval so that torrents sharing a
+/// tracker don't announce in lockstep. Announces are only ever delayed,
+/// so the min interval is always respected.
+fn announce_delay<R: Rng>(
+    interval: u32,
+    min_interval: Option<u32>,
+    jitter: u32,
+    rng: &mut R,
+) -> Duration {
+    let base = u64::from(cmp::max(interval, min_interval.unwrap_or(0)));
+    let max = base * u64::from(cmp::min(jitter, 100)) / 100;
+    let extra = if max == 0 {
+        0
+    } else {
+        rng.gen_range(0, max + 1)
+    };
+    Duration::from_secs(base + extra)
+}
+
+/// Returns when a torrent completed at `now` should announce completion
+/// and start seeding, or None if it should right away.
+fn seed_deadline(delay_secs: u64, now: Instant) -> Option<Instant> {
+    if delay_secs == 0 {
+        None
+    } else {
+        Some(now + Duration::from_secs(delay_secs))
+    }
+}
+
+/// Whether a torrent which uploaded `ul` bytes, downloaded `dl` and is
+/// `size` bytes long reached `ratio_limit`, or was seeded for at least
+/// `limit_secs`. The ratio of a torrent added complete is relative to
+/// its size. Limits of 0 are disabled.
+fn seed_limit_reached(
+    ul: u64,
+    dl: u64,
+    size: u64,
+    ratio_limit: f32,
+    seeding_secs: u64,
+    limit_secs: u64,
+) -> bool {
+    let base = if dl == 0 { size } else { dl };
+    (ratio_limit > 0. && base > 0 && ul as f64 / base as f64 >= f64::from(ratio_limit))
+        || (limit_secs > 0 && seeding_secs >= limit_secs)
+}
+
+/// Returns the failure reason if every tracker rejected the torrent with
+/// one of the configured unregistered reasons.
+fn unregistered_reason(trackers: &VecDeque<Tracker>, reasons: &[String]) -> Option<String> {
+    let mut reason = None;
+    for trk in trackers {
+        match trk.status {
+            TrackerStatus::Failure(ref r) => {
+                let lower = r.to_lowercase();
+                if !reasons.iter().any(|u| lower.contains(&u.to_lowercase())) {
+                    return None;
+                }
+                reason = reason.or_else(|| Some(format!("{}: {}", trk.url, r)));
+            }
+            _ => return None,
+        }
+    }
+    reason
+}
+
+/// Whether `corrupt` bytes of failed pieces exceed either limit, the
+/// absolute `max_bytes` or `max_ratio` of all `downloaded` bytes. A zero
+/// limit is disabled.
+fn corruption_exceeded(corrupt: u64, downloaded: u64, max_bytes: u64, max_ratio: f64) -> bool {
+    (max_bytes != 0 && corrupt > max_bytes)
+        || (max_ratio > 0. && corrupt as f64 > max_ratio * downloaded as f64)
+}
+
+/// Trackers of an announce list along with their tier, in the order
+/// they're first tried. Trackers within a tier are shuffled, as per
+/// BEP 12.
+fn tiered_trackers(url_list: &[Vec<Arc<Url>>]) -> Vec<(u32, Arc<Url>)> {
+    let mut rng = rand::thread_rng();
+    let mut trackers = Vec::new();
+    for (tier, list) in url_list.iter().enumerate() {
+        let mut list = list.clone();
+        rng.shuffle(&mut list[..]);
+        trackers.extend(list.into_iter().map(|url| (tier as u32, url)));
+    }
+    trackers
+}
+
+/// Restores the announce queue to tier order after an announce to `url`
+/// succeeded, moving it to the front of its tier as per BEP 12. Trackers
+/// which failed were rotated behind the others, so stay behind them in
+/// their tier. The trackers now ahead of it are next announced to when
+/// it would have been, so that announces start from the first tier again.
+fn promote_tracker(trackers: &mut VecDeque<Tracker>, url: &Url) {
+    let mut sorted: Vec<_> = trackers.drain(..).collect();
+    sorted.sort_by_key(|trk| trk.tier);
+    if let Some(idx) = sorted.iter().position(|trk| &*trk.url == url) {
+        let trk = sorted.remove(idx);
+        let front = sorted
+            .iter()
+            .position(|t| t.tier >= trk.tier)
+            .unwrap_or(sorted.len());
+        for ahead in sorted[..front].iter_mut().filter(|t| !t.dead) {
+            ahead.update = trk.update;
+        }
+        sorted.insert(front, trk);
+    }
+    trackers.extend(sorted);
+}
+
+/// Rotates dead trackers to the back of the announce queue,
+/// returning false if every tracker is dead.
+fn skip_dead_trackers(trackers: &mut VecDeque<Tracker>, now: Instant) -> bool {
+    for _ in 0..trackers.len() {
+        if !trackers[0].skipped(now) {
+            return true;
+        }
+        if let Some(front) = trackers.pop_front() {
+            trackers.push_back(front);
+        }
+    }
+    trackers.is_empty()
 }
 
 struct Files {
@@ -103,6 +405,18 @@ struct Files {
     dirty: FHashSet<usize>,
 }
 
+impl Integrity {
+    fn new(enabled: bool, last_sweep: Option<DateTime<Utc>>) -> Integrity {
+        Integrity {
+            enabled,
+            idx: 0,
+            checking: false,
+            last_sweep,
+            corrupt: 0,
+        }
+    }
+}
+
 impl Status {
     pub fn magnet(&self) -> bool {
         match self.state {
@@ -119,7 +433,7 @@ impl Status {
     }
 
     pub fn stopped(&self) -> bool {
-        self.paused || self.error.is_some()
+        self.paused || self.error.is_some() || self.meta_queued || self.moving.is_some()
     }
 
     pub fn completed(&self) -> bool {
@@ -129,20 +443,37 @@ impl Status {
         }
     }
 
+    /// Whether the seed delay of a completing torrent is over at `now`
+    pub fn seeding_due(&self, now: Instant) -> bool {
+        !self.stopped() && self.completing.map(|t| now >= t).unwrap_or(false)
+    }
+
     pub fn should_dl(&self) -> bool {
         self.leeching() && !self.stopped() && self.validating.is_none()
     }
 
     pub fn as_rpc(&self, ul: u64, dl: u64) -> rpc::resource::Status {
+        if self.moving.is_some() {
+            return rpc::resource::Status::Moving;
+        }
         if self.paused {
             return rpc::resource::Status::Paused;
         }
+        if self.meta_queued {
+            return rpc::resource::Status::MetadataQueued;
+        }
         if self.validating.is_some() {
             return rpc::resource::Status::Hashing;
         }
         if self.error.is_some() {
+            if self.unregistered {
+                return rpc::resource::Status::Unregistered;
+            }
             return rpc::resource::Status::Error;
         }
+        if self.completing.is_some() {
+            return rpc::resource::Status::Completing;
+        }
 
         match self.state {
             StatusState::Incomplete | StatusState::Import => {
@@ -223,21 +554,25 @@ impl<T: cio::CIO> Torrent<T> {
         let mut status = Status {
             paused: !start,
             validating: None,
+            moving: None,
             error: None,
             state: if import {
                 StatusState::Import
             } else {
                 StatusState::Incomplete
             },
+            meta_queued: false,
+            unregistered: false,
+            completing: None,
         };
         let priorities = Arc::new(vec![3; info.files.len()]);
-        let info_idx = if info.complete() {
+        let meta = if info.complete() {
             None
         } else {
             status.state = StatusState::Magnet;
-            Some(std::usize::MAX)
+            Some(Metadata::new())
         };
-        let info_bytes = if info_idx.is_none() {
+        let info_bytes = if meta.is_none() {
             info.to_bencode().encode_to_buf()
         } else {
             vec![]
@@ -247,28 +582,39 @@ impl<T: cio::CIO> Torrent<T> {
 
         let mut trackers = VecDeque::with_capacity(1);
         if !info.url_list.is_empty() {
-            for (i, list) in info.url_list.iter().enumerate() {
-                for (j, _) in list.iter().enumerate() {
-                    let tracker = Tracker {
-                        status: TrackerStatus::Updating,
-                        update: None,
-                        last_announce: Utc::now(),
-                        url: Arc::clone(&info.url_list[i][j]),
-                    };
-                    trackers.push_back(tracker);
-                }
+            for (tier, url) in tiered_trackers(&info.url_list) {
+                let tracker = Tracker {
+                    status: TrackerStatus::Updating,
+                    failures: 0,
+                    dead: false,
+                    update: None,
+                    last_announce: Utc::now(),
+                    headers: tracker::Headers::default(),
+                    url,
+                    tier,
+                    tracker_id: None,
+                    scrape: None,
+                };
+                trackers.push_back(tracker);
             }
         } else if let Some(ref announce) = info.announce {
             let tracker = Tracker {
                 status: TrackerStatus::Updating,
+                failures: 0,
+                dead: false,
                 update: None,
                 last_announce: Utc::now(),
+                headers: tracker::Headers::default(),
                 url: announce.clone(),
+                tier: 0,
+                tracker_id: None,
+                scrape: None,
             };
             trackers.push_back(tracker);
         }
 
         let files = Files::new(&info, &pieces);
+        let web_seeds = webseed::WebSeeds::new(info.web_seeds.clone());
 
         let mut t = Torrent {
             id,
@@ -277,12 +623,16 @@ impl<T: cio::CIO> Torrent<T> {
             peers,
             pieces,
             validating: FHashSet::default(),
+            lost: FHashMap::default(),
             picker,
             priority: 3,
             priorities,
             uploaded: 0,
             downloaded: 0,
             wasted: 0,
+            corrupt: 0,
+            contributors: FHashMap::default(),
+            bad_peers: Vec::new(),
             files,
             stat: stat::EMA::new(),
             cio,
@@ -293,9 +643,36 @@ impl<T: cio::CIO> Torrent<T> {
             dirty: true,
             status: status.clone(),
             info_bytes,
-            info_idx,
+            meta,
+            meta_probed: FHashSet::default(),
             created: Utc::now(),
+            activity: Activity::new(None, None, 0, 0, Instant::now()),
+            conflicts: Vec::new(),
+            integrity: Integrity::new(true, None),
+            sources: sources::Sources::new(),
+            preserved_peers: 0,
+            target: None,
+            streams: UHashMap::default(),
+            next_stream: 0,
+            web_seeds,
+            tracker_group: None,
+            warm_bias: 0,
+            availability_boost: 0,
+            pex_sent: 0,
+            pex_received: 0,
+            flush: flush::Flusher::new(flush::Schedule::configured(), None, Instant::now()),
+            piece_affinity: CONFIG.disk.piece_affinity,
+            seed_delay: None,
+            ratio_limit: None,
+            seed_time_limit: None,
+            endgame: false,
+            super_seed: SuperSeed::new(),
+            peer_resyncs: 0,
+            buffer_full: false,
+            file_stats: Vec::new(),
+            allocation: CONFIG.disk.allocation,
         };
+        t.picker.set_affinity(t.piece_affinity);
         t.start();
         if import {
             t.cio.msg_disk(disk::Request::validate_piece(
@@ -305,8 +682,8 @@ impl<T: cio::CIO> Torrent<T> {
                 0,
             ));
             t.validating.insert(0);
-        } else if CONFIG.disk.validate && t.info_idx.is_none() {
-            t.validate();
+        } else if CONFIG.disk.validate && t.meta.is_none() {
+            t.validate(false);
         } else {
             t.announce_start();
             t.announce_status();
@@ -326,9 +703,18 @@ impl<T: cio::CIO> Torrent<T> {
             return None;
         };
         debug!("Torrent data deserialized!");
+        let custom_flush = d.flush.as_ref().map(|f| flush::Schedule {
+            policy: match f.policy {
+                session::torrent::current::FlushPolicy::Piece => FlushPolicy::Piece,
+                session::torrent::current::FlushPolicy::Pieces => FlushPolicy::Pieces,
+                session::torrent::current::FlushPolicy::Interval => FlushPolicy::Interval,
+            },
+            every: f.every,
+        });
         let peers = UHashMap::default();
         let leechers = FHashSet::default();
 
+        let mut renames: BTreeMap<_, _> = d.file_renames.into_iter().collect();
         let info = Arc::new(Info {
             name: d.info.name,
             announce: d
@@ -345,23 +731,34 @@ impl<T: cio::CIO> Torrent<T> {
                 .info
                 .files
                 .into_iter()
-                .map(|f| info::File {
+                .enumerate()
+                .map(|(i, f)| info::File {
                     path: f.path,
                     length: f.length,
+                    renamed: renames.remove(&(i as u32)),
                 })
                 .collect(),
             private: d.info.private,
             be_name: d.info.be_name,
+            raw: d.info_bytes,
             piece_idx: d.info.piece_idx,
             url_list: vec![],
+            web_seeds: d
+                .web_seeds
+                .iter()
+                .filter_map(|u| Url::parse(u).ok().map(Arc::new))
+                .collect(),
         });
 
-        let info_idx = if info.complete() {
+        let meta = if info.complete() {
             None
         } else {
-            Some(std::usize::MAX)
+            // Resume fetching metadata where it was left off
+            d.metadata
+                .and_then(|m| Metadata::restore(m.bytes, Bitfield::from(m.have.data, m.have.len)))
+                .or_else(|| Some(Metadata::new()))
         };
-        let info_bytes = if info_idx.is_none() {
+        let info_bytes = if meta.is_none() {
             info.to_bencode().encode_to_buf()
         } else {
             vec![]
@@ -370,32 +767,57 @@ impl<T: cio::CIO> Torrent<T> {
         let picker = picker::Picker::new(&info, &pieces, &d.priorities);
         throttle.set_ul_rate(d.throttle_ul);
         throttle.set_dl_rate(d.throttle_dl);
-
-        let mut trackers: VecDeque<_> = d
+        throttle.set_ul_seed_rate(d.throttle_ul_seeding);
+        throttle.set_seeding(d.status.state == session::torrent::current::StatusState::Complete);
+
+        let mut tracker_headers: BTreeMap<_, _> = d.tracker_headers.into_iter().collect();
+        let mut tracker_ids: BTreeMap<_, _> = d.tracker_ids.into_iter().collect();
+        // Sessions predating tiers keep every tracker in the first one
+        let tiers = d.tracker_tiers.into_iter().chain(iter::repeat(0));
+        let mut trackers: Vec<_> = d
             .trackers
             .into_iter()
-            .filter_map(|url| Url::parse(&url).ok())
-            .map(|url| Tracker {
+            .zip(tiers)
+            .filter_map(|(url, tier)| Url::parse(&url).ok().map(|url| (url, tier)))
+            .map(|(url, tier)| Tracker {
                 status: TrackerStatus::Updating,
+                failures: 0,
+                dead: false,
                 update: None,
                 last_announce: Utc::now(),
+                headers: tracker_headers
+                    .remove(url.as_str())
+                    .and_then(tracker::Headers::new)
+                    .unwrap_or_default(),
+                tracker_id: tracker_ids.remove(url.as_str()),
+                scrape: None,
                 url: Arc::new(url),
+                tier,
             })
             .collect();
+        trackers.sort_by_key(|trk| trk.tier);
+        let mut trackers: VecDeque<_> = trackers.into_iter().collect();
 
         if trackers.is_empty() {
             if let Some(ref announce) = info.announce {
                 let tracker = Tracker {
                     status: TrackerStatus::Updating,
+                    failures: 0,
+                    dead: false,
                     update: None,
                     last_announce: Utc::now(),
+                    headers: tracker::Headers::default(),
                     url: announce.clone(),
+                    tier: 0,
+                    tracker_id: None,
+                    scrape: None,
                 };
                 trackers.push_back(tracker);
             }
         }
 
         let files = Files::new(&info, &pieces);
+        let web_seeds = webseed::WebSeeds::new(info.web_seeds.clone());
 
         let mut t = Torrent {
             id,
@@ -403,10 +825,14 @@ impl<T: cio::CIO> Torrent<T> {
             peers,
             pieces,
             validating: FHashSet::default(),
+            lost: FHashMap::default(),
             picker,
             uploaded: d.uploaded,
             downloaded: d.downloaded,
             wasted: 0,
+            corrupt: 0,
+            contributors: FHashMap::default(),
+            bad_peers: Vec::new(),
             files,
             stat: stat::EMA::new(),
             priorities: Arc::new(d.priorities),
@@ -420,22 +846,73 @@ impl<T: cio::CIO> Torrent<T> {
             status: Status {
                 paused: d.status.paused,
                 validating: None,
+                moving: None,
                 error: d.status.error,
                 state: match d.status.state {
                     session::torrent::current::StatusState::Magnet => StatusState::Magnet,
                     session::torrent::current::StatusState::Incomplete => StatusState::Incomplete,
                     session::torrent::current::StatusState::Complete => StatusState::Complete,
                 },
+                meta_queued: false,
+                unregistered: false,
+                completing: None,
             },
             path: d.path,
             info_bytes,
-            info_idx,
+            meta,
+            meta_probed: FHashSet::default(),
             created: d.created,
+            activity: Activity::new(
+                d.completed,
+                d.last_active,
+                d.active_secs,
+                d.seeding_secs,
+                Instant::now(),
+            ),
+            conflicts: Vec::new(),
+            integrity: Integrity::new(d.integrity_check, d.last_sweep),
+            sources: sources::Sources {
+                dht: d.sources.dht,
+                pex: d.sources.pex,
+                lpd: d.sources.lpd,
+                web_seeds: d.sources.web_seeds,
+            },
+            preserved_peers: 0,
+            target: None,
+            streams: UHashMap::default(),
+            next_stream: 0,
+            web_seeds,
+            tracker_group: None,
+            warm_bias: 0,
+            availability_boost: 0,
+            pex_sent: 0,
+            pex_received: 0,
+            flush: flush::Flusher::new(flush::Schedule::configured(), custom_flush, Instant::now()),
+            piece_affinity: CONFIG.disk.piece_affinity,
+            seed_delay: d.seed_delay,
+            ratio_limit: d.ratio_limit,
+            seed_time_limit: d.seed_time_limit,
+            endgame: false,
+            super_seed: SuperSeed::new(),
+            peer_resyncs: 0,
+            buffer_full: false,
+            file_stats: d.file_stats,
+            allocation: match d.allocation {
+                session::torrent::current::Allocation::Sparse => Allocation::Sparse,
+                session::torrent::current::Allocation::Full => Allocation::Full,
+            },
         };
+        t.picker.set_affinity(t.piece_affinity);
         t.status.error = None;
         t.start();
         if d.status.validating {
-            t.validate();
+            t.validate(false);
+        } else if t.files_match() == Some(false) {
+            warn!(
+                "Files of torrent {} changed since it was last run, validating",
+                t.rpc_id()
+            );
+            t.validate(false);
         } else {
             t.announce_start();
         }
@@ -443,7 +920,32 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn serialize(&mut self) {
-        let d = Session {
+        let session = Box::new(self.session());
+        let files = self
+            .info
+            .files
+            .iter()
+            .map(|f| f.disk_path().to_path_buf())
+            .collect();
+        debug!("Sending serialization request!");
+        self.cio.msg_disk(disk::Request::serialize(
+            self.id,
+            session,
+            self.info.hash,
+            self.path.clone(),
+            files,
+        ));
+        self.dirty = false;
+    }
+
+    /// Versioned session data of the torrent, as stored in the session
+    /// directory.
+    pub fn session_data(&self) -> Vec<u8> {
+        bincode::serialize(&self.session()).expect("Serialization failed!")
+    }
+
+    fn session(&self) -> Session {
+        Session {
             info: session::torrent::current::Info {
                 name: self.info.name.clone(),
                 announce: self.info.announce.as_ref().map(|a| a.as_str().to_owned()),
@@ -491,17 +993,97 @@ impl<T: cio::CIO> Torrent<T> {
             created: self.created,
             throttle_ul: self.throttle.ul_rate(),
             throttle_dl: self.throttle.dl_rate(),
+            throttle_ul_seeding: self.throttle.ul_seed_rate(),
             trackers: self
                 .trackers
                 .iter()
                 .map(|trk| trk.url.as_str().to_owned())
                 .collect(),
-        };
-        let data = bincode::serialize(&d).expect("Serialization failed!");
-        debug!("Sending serialization request!");
-        self.cio
-            .msg_disk(disk::Request::serialize(self.id, data, self.info.hash));
-        self.dirty = false;
+            tracker_tiers: self.trackers.iter().map(|trk| trk.tier).collect(),
+            integrity_check: self.integrity.enabled,
+            last_sweep: self.integrity.last_sweep,
+            tracker_headers: self
+                .trackers
+                .iter()
+                .filter(|trk| !trk.headers.is_empty())
+                .map(|trk| {
+                    (
+                        trk.url.as_str().to_owned(),
+                        trk.headers.clone().into_inner(),
+                    )
+                })
+                .collect(),
+            tracker_ids: self
+                .trackers
+                .iter()
+                .filter_map(|trk| {
+                    trk.tracker_id
+                        .as_ref()
+                        .map(|id| (trk.url.as_str().to_owned(), id.clone()))
+                })
+                .collect(),
+            file_renames: self
+                .info
+                .files
+                .iter()
+                .enumerate()
+                .filter_map(|(i, f)| f.renamed.clone().map(|p| (i as u32, p)))
+                .collect(),
+            web_seeds: self
+                .info
+                .web_seeds
+                .iter()
+                .map(|u| u.as_str().to_owned())
+                .collect(),
+            metadata: self
+                .meta
+                .as_ref()
+                .map(|m| session::torrent::current::Metadata {
+                    bytes: m.bytes().to_vec(),
+                    have: session::torrent::Bitfield {
+                        data: m.have().data(),
+                        len: m.have().len(),
+                    },
+                }),
+            sources: session::torrent::current::Sources {
+                dht: self.sources.dht,
+                pex: self.sources.pex,
+                lpd: self.sources.lpd,
+                web_seeds: self.sources.web_seeds,
+            },
+            flush: self
+                .flush
+                .custom()
+                .map(|s| session::torrent::current::Flush {
+                    policy: match s.policy {
+                        FlushPolicy::Piece => session::torrent::current::FlushPolicy::Piece,
+                        FlushPolicy::Pieces => session::torrent::current::FlushPolicy::Pieces,
+                        FlushPolicy::Interval => session::torrent::current::FlushPolicy::Interval,
+                    },
+                    every: s.every,
+                }),
+            seed_delay: self.seed_delay,
+            completed: self.activity.completed(),
+            last_active: self.activity.last_active(),
+            active_secs: self.activity.active_secs(),
+            ratio_limit: self.ratio_limit,
+            seed_time_limit: self.seed_time_limit,
+            seeding_secs: self.activity.seeding_secs(),
+            file_stats: self.file_stats.clone(),
+            allocation: match self.allocation {
+                Allocation::Sparse => session::torrent::current::Allocation::Sparse,
+                Allocation::Full => session::torrent::current::Allocation::Full,
+            },
+            info_bytes: self.info.raw.clone(),
+        }
+    }
+
+    /// Whether the files on disk have the size and modification time
+    /// they had when the torrent was last serialized, None if that's
+    /// unknown.
+    pub fn files_match(&self) -> Option<bool> {
+        let files: Vec<_> = self.info.files.iter().map(|f| f.disk_path()).collect();
+        stats_match(Path::new(self.path()), &files, &self.file_stats)
     }
 
     pub fn rpc_id(&self) -> String {
@@ -512,7 +1094,7 @@ impl<T: cio::CIO> Torrent<T> {
         debug!("Sending file deletion request!");
         let mut files = Vec::new();
         for file in &self.info.files {
-            files.push(file.path.clone());
+            files.push(file.disk_path().to_owned());
         }
         self.cio.msg_disk(disk::Request::delete(
             self.id,
@@ -523,10 +1105,172 @@ impl<T: cio::CIO> Torrent<T> {
         ));
     }
 
+    /// Writes the torrent's metainfo to the archive directory if enabled,
+    /// so that it can be re-added after being removed. Returns the path
+    /// of the archived .torrent.
+    pub fn archive(&mut self) -> Option<PathBuf> {
+        if !CONFIG.disk.archive_removed || !self.info.complete() {
+            return None;
+        }
+        let data = match self.info.to_torrent_file() {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Couldn't archive torrent {}: {}", self.rpc_id(), e);
+                return None;
+            }
+        };
+        let mut path = PathBuf::from(&CONFIG.disk.archive_dir);
+        path.push(self.rpc_id());
+        path.set_extension("torrent");
+        self.cio.msg_disk(disk::Request::WriteFile {
+            data,
+            path: path.clone(),
+        });
+        Some(path)
+    }
+
     pub fn pieces(&self) -> &Bitfield {
         &self.pieces
     }
 
+    /// Directory the torrent's files are stored in
+    pub fn path(&self) -> &str {
+        self.path.as_ref().unwrap_or(&CONFIG.disk.directory)
+    }
+
+    /// Renames one of the torrent's files on disk. The info dict's
+    /// name for the file is kept, so the file's ID doesn't change.
+    pub fn rename_file(&mut self, file_id: &str, path: PathBuf) -> Result<(), String> {
+        let hash = self.info.hash;
+        let file = self
+            .info
+            .files
+            .iter()
+            .position(|f| util::file_rpc_id(&hash, f.path.to_string_lossy().as_ref()) == file_id)
+            .ok_or_else(|| format!("File {} does not exist", file_id))?;
+        self.rename(|info| info.rename_file(file, path))
+    }
+
+    /// Renames the directory the torrent's files are stored in, or the
+    /// file itself for single file torrents.
+    pub fn rename_root(&mut self, name: &str) -> Result<(), String> {
+        self.rename(|info| info.rename_root(name))
+    }
+
+    /// Moves the torrent's files to the directory `path`, holding off
+    /// reading or writing any of them until they've all been moved.
+    pub fn move_to(&mut self, path: String) -> Result<(), String> {
+        if self.status.moving.is_some() {
+            return Err("Torrent is already being moved".to_owned());
+        }
+        if self.status.validating.is_some() {
+            return Err("Torrent is being validated".to_owned());
+        }
+        if path == self.path() {
+            return Ok(());
+        }
+        if self.meta.is_some() {
+            // Nothing has been written yet
+            self.set_path(path);
+            return Ok(());
+        }
+        let files = self
+            .info
+            .files
+            .iter()
+            .map(|f| (f.disk_path().to_owned(), f.length))
+            .collect();
+        let from = self.path().to_owned();
+        debug!("Moving torrent from {} to {}", from, path);
+        self.cio
+            .msg_disk(disk::Request::move_files(self.id, from, path, files));
+        self.status.moving = Some(0.);
+        self.announce_status();
+        Ok(())
+    }
+
+    fn set_path(&mut self, path: String) {
+        let id = self.rpc_id();
+        self.path = Some(path.clone());
+        self.dirty = true;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentPath {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                path,
+            },
+        ]));
+    }
+
+    fn rename<F>(&mut self, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Info) -> Result<(PathBuf, PathBuf), String>,
+    {
+        if self.meta.is_some() {
+            return Err("Torrent metadata has not been fetched".to_owned());
+        }
+        let mut info = (*self.info).clone();
+        let (from, to) = f(&mut info)?;
+        if Path::new(self.path()).join(&to).exists() {
+            return Err(format!("{} already exists", to.display()));
+        }
+        let files = self
+            .info
+            .files
+            .iter()
+            .map(|f| f.disk_path())
+            .filter(|p| p.starts_with(&from))
+            .map(|p| p.to_owned())
+            .collect();
+        debug!("Renaming {:?} to {:?}", from, to);
+        // Subsequent disk jobs are queued behind the rename,
+        // so the new paths can be used right away.
+        let prev = mem::replace(&mut self.info, Arc::new(info));
+        self.cio.msg_disk(disk::Request::rename(
+            self.id,
+            self.path.clone(),
+            from,
+            to,
+            files,
+        ));
+        self.update_rpc_paths(&prev);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Sends the disk paths of files which differ from `prev`'s
+    fn update_rpc_paths(&mut self, prev: &Info) {
+        let updates = self
+            .info
+            .files
+            .iter()
+            .zip(prev.files.iter())
+            .filter(|&(f, p)| f.disk_path() != p.disk_path())
+            .map(|(f, _)| SResourceUpdate::FilePath {
+                id: util::file_rpc_id(&self.info.hash, f.path.to_string_lossy().as_ref()),
+                kind: resource::ResourceKind::File,
+                path: f.disk_path().to_string_lossy().into_owned(),
+            })
+            .collect();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+    }
+
+    /// Sets the IDs of torrents whose files overlap with this torrent's
+    pub fn set_conflicts(&mut self, conflicts: Vec<String>) {
+        if conflicts == self.conflicts {
+            return;
+        }
+        self.conflicts = conflicts;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentConflicts {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                path_conflicts: self.conflicts.clone(),
+            },
+        ]));
+    }
+
     pub fn status(&self) -> &Status {
         &self.status
     }
@@ -538,46 +1282,84 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn set_tracker_response(&mut self, url: &Url, resp: &tracker::Result<TrackerResponse>) {
         let mut time = Instant::now();
         let mut empty = false;
+        let mut failure = None;
         match *resp {
             Ok(ref r) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     debug!("Got valid response for {}", tracker.url);
-                    time += Duration::from_secs(u64::from(r.interval));
+                    time += announce_delay(
+                        r.interval,
+                        r.min_interval,
+                        CONFIG.trk.announce_jitter,
+                        &mut rand::thread_rng(),
+                    );
+                    if let Some(ref w) = r.warning {
+                        warn!("Tracker {} returned warning: {}", tracker.url, w);
+                    }
                     tracker.status = TrackerStatus::Ok {
                         seeders: r.seeders,
                         leechers: r.leechers,
                         interval: r.interval,
+                        min_interval: r.min_interval,
+                        warning: r.warning.clone(),
                     };
                     tracker.update = Some(time);
                     tracker.last_announce = Utc::now();
+                    tracker.succeed();
+                    if r.tracker_id.is_some() && r.tracker_id != tracker.tracker_id {
+                        tracker.tracker_id = r.tracker_id.clone();
+                        self.dirty = true;
+                    }
                     if r.peers.is_empty() {
                         empty = true;
                     }
                 }
+                // A manual announce was accepted, so the torrent is known again
+                if self.status.unregistered {
+                    self.status.error = None;
+                    self.status.unregistered = false;
+                    self.announce_status();
+                }
             }
             Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     debug!("Got tracker level error for {}", tracker.url);
-                    time += Duration::from_secs(300);
-                    tracker.update = Some(time);
+                    tracker.fail(time);
                     tracker.status = TrackerStatus::Failure(s.clone());
                     tracker.last_announce = Utc::now();
+                    failure = Some(format!("{}: {}", tracker.url, s));
                 }
             }
             Err(ref e) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
                     error!("Failed to query tracker {}: {}", tracker.url, e);
-                    // Wait 5 minutes before trying again
-                    time += Duration::from_secs(300);
-                    tracker.update = Some(time);
+                    tracker.fail(time);
                     let reason = format!("Couldn't contact tracker: {}", e);
+                    failure = Some(format!("{}: {}", tracker.url, reason));
                     tracker.status = TrackerStatus::Failure(reason);
                     tracker.last_announce = Utc::now();
                 }
             }
         }
+        if failure.is_some() {
+            self.notify(NotifyEvent::TrackerError, failure);
+        }
+        let reasons = &CONFIG.trk.unregistered_reasons;
+        if self.status.error.is_none() {
+            if let Some(reason) = unregistered_reason(&self.trackers, reasons) {
+                warn!("Torrent rejected by all trackers, stopping: {}", reason);
+                self.status.error = Some(reason.clone());
+                self.status.unregistered = true;
+                self.announce_status();
+                self.notify(NotifyEvent::Error, Some(reason));
+                self.update_rpc_tracker();
+                return;
+            }
+        }
 
-        if (resp.is_err() || empty) && self.trackers.iter().any(|t| &*t.url == url) {
+        if resp.is_ok() && !empty {
+            promote_tracker(&mut self.trackers, url);
+        } else if self.trackers.iter().any(|t| &*t.url == url) {
             if let Some(front) = self.trackers.pop_front() {
                 self.trackers.push_back(front);
                 self.try_update_tracker();
@@ -586,10 +1368,54 @@ impl<T: cio::CIO> Torrent<T> {
         self.update_rpc_tracker();
     }
 
+    /// Records the statistics returned by scraping the tracker at `url`.
+    /// Failed scrapes are only logged, since announces already report
+    /// whether a tracker works.
+    pub fn set_scrape_response(&mut self, url: &Url, resp: tracker::Result<ScrapeResponse>) {
+        let stats = match resp {
+            Ok(stats) => stats,
+            Err(e) => {
+                debug!("Failed to scrape tracker {}: {}", url, e);
+                return;
+            }
+        };
+        let mut found = false;
+        for trk in self.trackers.iter_mut().filter(|t| &*t.url == url) {
+            trk.scrape = Some(stats);
+            found = true;
+        }
+        if found {
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                SResourceUpdate::TrackerScrape {
+                    id: util::trk_rpc_id(&self.info.hash, url.as_str()),
+                    kind: resource::ResourceKind::Tracker,
+                    seeders: stats.seeders,
+                    leechers: stats.leechers,
+                    downloaded: stats.downloaded,
+                },
+            ]));
+        }
+    }
+
+    /// Scrapes the trackers of the torrent, unless it's stopped.
+    pub fn scrape_trackers(&mut self) {
+        if self.status.stopped() {
+            return;
+        }
+        for req in tracker::Scrape::for_torrent(self) {
+            self.cio.msg_trk(req);
+        }
+    }
+
     pub fn try_update_tracker(&mut self) {
         if self.status.stopped() {
             return;
         }
+        if !skip_dead_trackers(&mut self.trackers, Instant::now()) {
+            // Every tracker is dead, so only the DHT is announced to
+            self.dht_announce();
+            return;
+        }
         if let Some(end) = self.trackers.front().and_then(|t| t.update) {
             debug!("Updating tracker at interval!");
             let cur = Instant::now();
@@ -612,24 +1438,33 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn remove_peer(&mut self, rpc_id: &str) {
-        let ih = &self.info.hash;
-        let cio = &mut self.cio;
-        if let Some((id, _)) = self
-            .peers
-            .iter()
-            .find(|&(id, _)| util::peer_rpc_id(ih, *id as u64) == rpc_id)
-        {
-            cio.remove_peer(*id)
+        if let Some(id) = self.peer_id(rpc_id) {
+            self.cio.remove_peer(id)
         }
     }
 
+    /// Looks up the connection of the peer with the given RPC id
+    pub fn peer_id(&self, rpc_id: &str) -> Option<usize> {
+        let ih = &self.info.hash;
+        self.peers
+            .keys()
+            .find(|&id| util::peer_rpc_id(ih, *id as u64) == rpc_id)
+            .cloned()
+    }
+
     pub fn add_tracker(&mut self, url: Url) -> String {
         let id = util::trk_rpc_id(&self.info.hash, url.as_str());
         self.trackers.push_front(Tracker {
             status: TrackerStatus::Updating,
+            failures: 0,
+            dead: false,
             update: None,
             last_announce: Utc::now(),
+            headers: tracker::Headers::default(),
             url: Arc::new(url),
+            tier: 0,
+            tracker_id: None,
+            scrape: None,
         });
         {
             let trk = &self.trackers[0];
@@ -639,6 +1474,7 @@ impl<T: cio::CIO> Torrent<T> {
                 url: trk.url.as_ref().clone(),
                 last_report: trk.last_announce,
                 error: None,
+                tier: trk.tier,
                 ..Default::default()
             })];
             self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
@@ -664,7 +1500,78 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Sets the extra HTTP headers sent to a tracker, returning
+    /// whether the tracker exists.
+    pub fn set_tracker_headers(&mut self, rpc_id: &str, headers: tracker::Headers) -> bool {
+        let ih = &self.info.hash;
+        let names = headers.names();
+        let mut found = false;
+        // The same URL may appear in several tiers
+        for trk in self
+            .trackers
+            .iter_mut()
+            .filter(|trk| util::trk_rpc_id(ih, trk.url.as_str()) == rpc_id)
+        {
+            trk.headers = headers.clone();
+            found = true;
+        }
+        if found {
+            debug!("Set headers {:?} for tracker {}", headers, rpc_id);
+            self.dirty = true;
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                SResourceUpdate::TrackerHeaders {
+                    id: rpc_id.to_owned(),
+                    kind: resource::ResourceKind::Tracker,
+                    headers: names,
+                },
+            ]));
+        }
+        found
+    }
+
+    /// Announces to a tracker with an explicit event, e.g. to register as
+    /// a seed after the tracker missed the completed event. Trackers which
+    /// were recently announced to successfully must not be announced to
+    /// again before their min interval has elapsed.
+    pub fn reannounce(
+        &mut self,
+        rpc_id: &str,
+        event: Option<tracker::Event>,
+    ) -> Result<(), String> {
+        let now = Utc::now();
+        let url = {
+            let ih = &self.info.hash;
+            let trk = self
+                .trackers
+                .iter_mut()
+                .find(|trk| util::trk_rpc_id(ih, trk.url.as_str()) == rpc_id)
+                .ok_or_else(|| format!("Unknown tracker {}", rpc_id))?;
+            let wait = reannounce_wait(&trk.status, trk.last_announce, now);
+            if wait > 0 {
+                return Err(format!(
+                    "Tracker {} may not be announced to for another {} seconds",
+                    trk.url, wait
+                ));
+            }
+            trk.succeed();
+            trk.url.clone()
+        };
+        if let Some(req) = tracker::Request::custom_event(self, url, event) {
+            self.cio.msg_trk(req);
+        }
+        Ok(())
+    }
+
     pub fn update_tracker_req(&mut self, rpc_id: &str) {
+        // A manual update revives a dead tracker
+        let ih = &self.info.hash;
+        for trk in self
+            .trackers
+            .iter_mut()
+            .filter(|trk| util::trk_rpc_id(ih, trk.url.as_str()) == rpc_id)
+        {
+            trk.succeed();
+        }
         if let Some(req) = self
             .trackers
             .iter()
@@ -712,38 +1619,70 @@ impl<T: cio::CIO> Torrent<T> {
                     // This may not be 100% accurate, but close enough for now.
                     self.uploaded += u64::from(context.length);
                     self.stat.add_ul(u64::from(context.length));
+                    self.activity.transferred(Utc::now());
                     self.dirty = true;
                     peer.send_message(p);
                 }
             }
             disk::Response::Moved { path, .. } => {
                 debug!("Moved torrent!");
-                let id = self.rpc_id();
-                self.path = Some(path.clone());
-                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                    resource::SResourceUpdate::TorrentPath {
-                        id,
-                        kind: resource::ResourceKind::Torrent,
-                        path,
-                    },
-                ]));
+                self.set_path(path);
+                self.status.moving = None;
+                self.announce_status();
+                self.update_rpc_transfer();
+                self.request_all();
             }
-            disk::Response::PieceValidated { piece, valid, .. } => {
-                self.validating.remove(&piece);
-                if let StatusState::Import = self.status.state {
-                    self.status.state = StatusState::Incomplete;
-                    info!("Torrent imported!");
-                    if valid {
-                        for i in 0..self.info.pieces() {
-                            self.pieces.set_bit(u64::from(i));
-                        }
-                        self.check_complete();
+            disk::Response::StreamDone { stream, .. } => {
+                if self.streams.remove(&stream).is_some() {
+                    self.update_deadlines();
+                }
+            }
+            disk::Response::MoveUpdate { percent, .. } => {
+                self.status.moving = Some(percent);
+                self.update_rpc_transfer();
+            }
+            disk::Response::MoveFailed { err, .. } => {
+                self.status.moving = None;
+                let mut error = format!("Failed to move to {}: {}", err.to, err.err);
+                if !err.moved.is_empty() {
+                    let moved: Vec<_> = err.moved.iter().map(|p| p.to_string_lossy()).collect();
+                    error.push_str(&format!(", files left there: {}", moved.join(", ")));
+                }
+                self.status.error = Some(error);
+                self.announce_status();
+                self.update_rpc_transfer();
+            }
+            disk::Response::RenameFailed { err, .. } => {
+                // Undo the rename so the files are found at their old paths
+                let mut info = (*self.info).clone();
+                info.move_disk_path(&err.to, &err.from);
+                let prev = mem::replace(&mut self.info, Arc::new(info));
+                self.status.error = Some(format!(
+                    "Failed to rename {}: {}",
+                    err.from.display(),
+                    err.err
+                ));
+                self.announce_status();
+                self.update_rpc_paths(&prev);
+                self.dirty = true;
+            }
+            disk::Response::PieceValidated { piece, valid, .. } => {
+                self.validating.remove(&piece);
+                if let StatusState::Import = self.status.state {
+                    self.status.state = StatusState::Incomplete;
+                    info!("Torrent imported!");
+                    if valid {
+                        for i in 0..self.info.pieces() {
+                            self.pieces.set_bit(u64::from(i));
+                        }
+                        self.check_complete();
                     } else {
                         info!("Invalid torrent imported, redownloading!");
                     }
                     self.announce_start();
                     return;
                 }
+                let contributors = self.contributors.remove(&piece).unwrap_or_default();
                 if valid {
                     self.pieces.set_bit(u64::from(piece));
                     // Tell all relevant peers we got the piece
@@ -756,16 +1695,56 @@ impl<T: cio::CIO> Torrent<T> {
                         }
                     }
                     self.files.update(&self.info, piece);
+                    let files = Info::piece_disk_locs(&self.info, piece).map(|loc| loc.file);
+                    if let Some(files) = self.flush.piece_done(files, Instant::now()) {
+                        self.flush_files(files);
+                    }
+                    self.check_target(Some(piece));
+                    if !self.streams.is_empty() {
+                        self.cio
+                            .msg_disk(disk::Request::piece_ready(self.id, piece));
+                    }
                     self.check_complete();
                 } else {
-                    // TODO: trace down the bad peer and block it
                     debug!("Invalid piece downloaded!");
                     self.picker.invalidate_piece(piece);
-                    if !self.stat.active() {
+                    self.bad_peers.extend(contributors);
+                    self.corrupt += u64::from(self.info.piece_len(piece));
+                    let max_bytes = CONFIG.peer.max_corrupt_mb * 1024 * 1024;
+                    let max_ratio = CONFIG.peer.max_corrupt_ratio;
+                    if corruption_exceeded(self.corrupt, self.downloaded, max_bytes, max_ratio) {
+                        self.stop_corrupt();
+                    } else if !self.stat.active() {
                         self.request_all();
                     }
                 }
             }
+            disk::Response::PiecesChecked { invalid, .. } => {
+                self.integrity.checking = false;
+                if !invalid.is_empty() {
+                    error!(
+                        "Integrity check of {} found {} corrupt pieces, redownloading!",
+                        self.rpc_id(),
+                        invalid.len()
+                    );
+                    self.integrity.corrupt += invalid.len() as u64;
+                    for piece in invalid {
+                        self.lose_piece(piece);
+                    }
+                    self.status.state = StatusState::Incomplete;
+                    self.dirty = true;
+                    self.request_all();
+                    self.files.rebuild(&self.info, &self.pieces);
+                    self.update_rpc_transfer();
+                    self.rpc_update_pieces();
+                    self.announce_status();
+                }
+                if self.integrity.idx == self.info.pieces() {
+                    self.finish_sweep();
+                } else {
+                    self.update_rpc_integrity();
+                }
+            }
             disk::Response::ValidationUpdate { percent, .. } => {
                 self.status.validating = Some(percent);
                 self.update_rpc_transfer();
@@ -773,6 +1752,8 @@ impl<T: cio::CIO> Torrent<T> {
             disk::Response::ValidationComplete { mut invalid, .. } => {
                 debug!("Validation completed!");
                 self.status.validating = None;
+                // Record the stats of the files as validated
+                self.dirty = true;
                 // Ignore invalid pieces which are
                 // part of an invalid file(none of the disk locations
                 // refer to files which aren't being downloaded(pri. 1)
@@ -794,7 +1775,7 @@ impl<T: cio::CIO> Torrent<T> {
                 } else {
                     // If this is an initialization hash, start the torrent
                     // immediatly.
-                    if self.pieces().iter().count() == 0 {
+                    if self.pieces.set() == 0 {
                         debug!("validation complete, starting torrent");
                         // If there was some partial completion,
                         // set the pieces appropriately, then reset the
@@ -811,14 +1792,13 @@ impl<T: cio::CIO> Torrent<T> {
                             for piece in invalid {
                                 self.pieces.unset_bit(u64::from(piece));
                             }
-                            let seq = self.picker.is_sequential();
-                            self.change_picker(seq);
+                            let strategy = self.picker.strategy();
+                            self.set_strategy(strategy);
                         }
                         self.announce_start();
                     } else {
                         for piece in invalid {
-                            self.picker.invalidate_piece(piece);
-                            self.pieces.unset_bit(u64::from(piece));
+                            self.lose_piece(piece);
                         }
                         self.request_all();
                     }
@@ -832,17 +1812,40 @@ impl<T: cio::CIO> Torrent<T> {
             }
             disk::Response::Error { err, .. } => {
                 error!("Disk error: {:?}", err);
-                self.status.error = Some(format!("{}", err));
-                self.announce_status();
+                // Control pauses downloads on a read-only filesystem,
+                // while seeding carries on
+                if !util::is_read_only(&err) {
+                    self.status.error = Some(format!("{}", err));
+                    self.announce_status();
+                    self.notify(NotifyEvent::Error, Some(format!("{}", err)));
+                }
                 for piece in self.validating.drain() {
                     self.picker.invalidate_piece(piece);
                     self.pieces.unset_bit(u64::from(piece));
                 }
             }
-            disk::Response::FreeSpace(_) => unreachable!(),
+            disk::Response::Serialized { stats, .. } => {
+                self.file_stats = stats;
+            }
+            disk::Response::FreeSpace { .. }
+            | disk::Response::CreateUpdate { .. }
+            | disk::Response::Created { .. }
+            | disk::Response::CreateFailed { .. }
+            | disk::Response::CacheStats(_)
+            | disk::Response::BufferFull(_)
+            | disk::Response::Checkpoint => unreachable!(),
         }
     }
 
+    /// Drops a piece we had, after it failed a recheck.
+    fn lose_piece(&mut self, piece: u32) {
+        self.picker.invalidate_piece(piece);
+        self.pieces.unset_bit(u64::from(piece));
+        let grace = Duration::from_secs(LOST_PIECE_GRACE_SECS);
+        self.lost.retain(|_, t| t.elapsed() < grace);
+        self.lost.insert(piece, Instant::now());
+    }
+
     fn check_complete(&mut self) {
         let mut complete = true;
         for piece in 0..self.pieces.len() {
@@ -860,23 +1863,119 @@ impl<T: cio::CIO> Torrent<T> {
             if self.status.state != StatusState::Complete {
                 self.status.state = StatusState::Complete;
                 self.picker.done();
-                self.set_finished();
-                self.serialize();
+                match seed_deadline(self.seed_delay(), Instant::now()) {
+                    Some(at) => {
+                        info!("Torrent {} completed, seeding delayed", self.rpc_id());
+                        self.status.completing = Some(at);
+                        self.update_rpc_transfer();
+                        self.announce_status();
+                        self.serialize();
+                    }
+                    None => self.set_finished(),
+                }
+                self.notify(NotifyEvent::Completed, None);
             }
         } else if self.status.state == StatusState::Complete {
             self.status.state = StatusState::Incomplete;
-            let seq = self.picker.is_sequential();
+            self.status.completing = None;
+            let strategy = self.picker.strategy();
             self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
-            self.change_picker(seq);
+            self.set_strategy(strategy);
+            self.update_deadlines();
             self.announce_status();
             self.announce_start();
             self.request_all();
         }
     }
+    /// Reports an event to the configured notification backends
+    fn notify(&mut self, event: NotifyEvent, detail: Option<String>) {
+        let name = if self.info.name.is_empty() {
+            None
+        } else {
+            Some(self.info.name.clone())
+        };
+        let event = notify::Event {
+            event,
+            torrent: self.rpc_id(),
+            name,
+            detail,
+        };
+        self.cio.propagate(cio::Event::Notify(event));
+    }
+
+    fn seed_delay(&self) -> u64 {
+        self.seed_delay.unwrap_or(CONFIG.net.seed_delay_secs)
+    }
+
+    fn set_seed_delay(&mut self, delay: u64) {
+        self.seed_delay = Some(delay);
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentSeedDelay {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                seed_delay: delay,
+            },
+        ]));
+    }
+
+    fn ratio_limit(&self) -> f32 {
+        self.ratio_limit.unwrap_or(CONFIG.net.seed_ratio_limit)
+    }
+
+    fn seed_time_limit(&self) -> u64 {
+        self.seed_time_limit
+            .unwrap_or(CONFIG.net.seed_time_limit_secs)
+    }
+
+    fn update_rpc_seed_limits(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentSeedLimits {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                ratio_limit: self.ratio_limit(),
+                seed_time_limit: self.seed_time_limit(),
+            },
+        ]));
+    }
+
+    /// Pauses a seeding torrent which reached its ratio or seeding
+    /// time limit.
+    pub fn check_seed_limits(&mut self) {
+        if !self.complete() || self.status.stopped() || self.status.completing.is_some() {
+            return;
+        }
+        // Underseeded swarms are kept alive regardless of our ratio
+        let ratio_limit = if self.underseeded() {
+            0.
+        } else {
+            self.ratio_limit()
+        };
+        let reached = seed_limit_reached(
+            self.uploaded,
+            self.downloaded,
+            self.info.total_len,
+            ratio_limit,
+            self.activity.seeding_secs(),
+            self.seed_time_limit(),
+        );
+        if reached {
+            info!(
+                "Torrent {} reached its seeding limit, pausing",
+                self.rpc_id()
+            );
+            self.pause();
+            self.dirty = true;
+        }
+    }
+
     /// Signal that we've downloaded and verified the torrent
     fn set_finished(&mut self) {
         info!("Torrent {} completed!", self.rpc_id());
         debug!("Wasted: {} MiB", (self.wasted * 16_384) / (1024 * 1024));
+        self.activity.complete(Utc::now());
         if let Some(req) = tracker::Request::completed(self) {
             self.cio.msg_trk(req);
         }
@@ -886,29 +1985,38 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.state = StatusState::Complete;
         self.announce_status();
 
-        // Remove all seeding peers.
-        let leechers = &self.leechers;
-        {
-            let seeders = self
-                .peers
-                .iter()
-                .filter(|&(id, _)| !leechers.contains(id))
-                .map(|(id, _)| *id);
-            for seeder in seeders {
-                self.cio.remove_peer(seeder);
-            }
+        // Remove all seeding peers, keeping everyone else around to upload to
+        let seeders = seeders(&self.peers);
+        for seeder in &seeders {
+            self.cio.remove_peer(*seeder);
         }
+        self.preserved_peers = (self.peers.len() - seeders.len()) as u16;
+        debug!("Keeping {} peers for seeding", self.preserved_peers);
 
-        // Due to how we do validation updates, we should tell peers we now have every single piece
-        for pid in leechers {
-            if let Some(peer) = self.peers.get_mut(pid) {
-                for i in 0..self.pieces.len() {
-                    if !peer.pieces().has_bit(i) {
-                        peer.send_message(Message::Have(i as u32));
-                    }
+        for (pid, peer) in self.peers.iter_mut() {
+            if seeders.contains(pid) {
+                continue;
+            }
+            peer.uninterested();
+            // Due to how we do validation updates, we should tell peers we now have every single piece
+            for i in 0..self.pieces.len() {
+                if !peer.pieces().has_bit(i) {
+                    peer.send_message(Message::Have(i as u32));
                 }
             }
         }
+
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentPreservedPeers {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                preserved_peers: self.preserved_peers,
+            },
+        ]));
+        // Persist completion right away rather than waiting for the
+        // next session update
+        self.serialize();
     }
 
     pub fn peer_ev(&mut self, pid: cio::PID, evt: cio::Result<Message>) -> Result<(), ()> {
@@ -916,7 +2024,12 @@ impl<T: cio::CIO> Torrent<T> {
         let mut peer = self.peers.remove(&pid).ok_or(())?;
         match evt {
             Ok(mut msg) => {
-                if peer.handle_msg(&mut msg).is_ok() && self.handle_msg(msg, &mut peer).is_ok() {
+                let res = peer.handle_msg(&mut msg);
+                if let Err(ref e) = res {
+                    debug!("Peer {} violated the protocol: {}", peer.addr(), e);
+                    self.cio.propagate(cio::Event::Violation(peer.addr()));
+                }
+                if res.is_ok() && self.handle_msg(msg, &mut peer).is_ok() {
                     self.peers.insert(pid, peer);
                     return Ok(());
                 } else {
@@ -946,40 +2059,23 @@ impl<T: cio::CIO> Torrent<T> {
         trace!("Received {:?} from peer", msg);
         match msg {
             Message::Handshake { rsv, .. } => {
-                if (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 {
-                    let mut ed = BTreeMap::new();
-                    let mut m = BTreeMap::new();
-
-                    m.insert(
-                        "ut_metadata".to_owned(),
-                        bencode::BEncode::Int(i64::from(UT_META_ID)),
-                    );
-                    if !self.info.private {
-                        m.insert(
-                            "ut_pex".to_owned(),
-                            bencode::BEncode::Int(i64::from(UT_PEX_ID)),
-                        );
-                    }
-
-                    ed.insert("m".to_owned(), bencode::BEncode::Dict(m));
-                    ed.insert(
-                        "metadata_size".to_owned(),
-                        bencode::BEncode::Int(self.info_bytes.len() as i64),
-                    );
-                    let payload = bencode::BEncode::Dict(ed).encode_to_buf();
-
-                    peer.send_message(Message::Extension { id: 0, payload });
+                if (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 && CONFIG.peer.advertises(EXT_PROTO) {
+                    peer.send_message(self.ext_handshake());
                 }
             }
             Message::Extension { id, payload } => {
                 self.handle_ext(id, payload, peer)?;
             }
-            Message::Bitfield(_) => {
+            Message::Bitfield(ref early) => {
                 if self.pieces.usable(peer.pieces()) && self.status.validating.is_none() {
                     peer.interested();
                 }
                 if self.info.complete() {
                     self.picker.add_peer(peer);
+                    // Pieces of HAVEs preceding the bitfield were already counted
+                    for idx in early.iter() {
+                        self.picker.piece_unavailable(idx as u32);
+                    }
                 }
                 if !peer.pieces().complete() {
                     self.leechers.insert(peer.id());
@@ -987,6 +2083,10 @@ impl<T: cio::CIO> Torrent<T> {
                     // Don't waste a connection on a peer if they're also a seeder
                     return Err(());
                 }
+                if self.super_seed.enabled() {
+                    let shared = self.super_seed.bitfield(peer.id(), peer.pieces());
+                    self.reveal_shared(shared, peer);
+                }
             }
             Message::Have(idx) => {
                 if self.info.complete() {
@@ -999,12 +2099,16 @@ impl<T: cio::CIO> Torrent<T> {
                         return Err(());
                     }
                 }
+                if self.super_seed.enabled() {
+                    let shared = self.super_seed.have(peer.id(), idx);
+                    self.reveal_shared(shared, peer);
+                }
                 if self.pieces.usable(peer.pieces()) {
                     peer.interested();
                 }
             }
             Message::Unchoke => {
-                if self.status.should_dl() && self.info.complete() {
+                if self.should_request() && self.info.complete() {
                     Torrent::make_requests(peer, &mut self.picker, &self.info);
                 }
             }
@@ -1031,49 +2135,11 @@ impl<T: cio::CIO> Torrent<T> {
                     return Err(());
                 }
 
-                // We already have this block, don't do anything with it, could happen
-                // from endgame
-                if self.picker.have_block(Block::new(index, begin)) {
-                    return Ok(());
-                }
-
-                let pr = {
-                    let picker = &mut self.picker;
-                    let peers = &mut self.peers;
-
-                    picker.completed(Block::new(index, begin), |pid| {
-                        if let Some(p) = peers.get_mut(&pid) {
-                            p.send_message(Message::Cancel {
-                                index,
-                                begin,
-                                length,
-                            })
-                        }
-                    })
-                };
-                let piece_done = if let Ok(r) = pr {
-                    r
-                } else {
+                if !self.handle_block(index, begin, data, length, Some(peer.addr().ip())) {
                     return Ok(());
-                };
-
-                self.dirty = true;
-                self.write_piece(index, begin, data);
-
-                self.downloaded += u64::from(length);
-                self.stat.add_dl(u64::from(length));
-
-                if piece_done {
-                    self.cio.msg_disk(disk::Request::validate_piece(
-                        self.id,
-                        self.info.clone(),
-                        self.path.clone(),
-                        index,
-                    ));
-                    self.validating.insert(index);
                 }
 
-                if self.status.should_dl() {
+                if self.should_request() {
                     Torrent::make_requests(peer, &mut self.picker, &self.info);
                 }
             }
@@ -1082,8 +2148,24 @@ impl<T: cio::CIO> Torrent<T> {
                 begin,
                 length,
             } => {
+                // Already rejected by the peer
+                if !peer.can_serve(index) {
+                    return Ok(());
+                }
                 if !self.pieces.has_bit(u64::from(index)) {
-                    return Err(());
+                    // The peer may not have noticed we lost the piece yet
+                    let tolerated = self.lost.get(&index).map_or(false, |t| {
+                        t.elapsed() < Duration::from_secs(LOST_PIECE_GRACE_SECS)
+                    });
+                    let policy = CONFIG.peer.unheld_requests;
+                    if peer.unheld_request(index, begin, length, policy, tolerated) {
+                        debug!(
+                            "Peer {} repeatedly requested pieces we don't have",
+                            peer.addr()
+                        );
+                        self.cio.propagate(cio::Event::Violation(peer.addr()));
+                    }
+                    return Ok(());
                 }
                 if length != self.info.block_len(index, begin) {
                     return Err(());
@@ -1096,6 +2178,14 @@ impl<T: cio::CIO> Torrent<T> {
                 }
 
                 // TODO: add this to a queue to fulfill later
+                peer.reject_request(index, begin, length);
+            }
+            Message::RejectRequest { index, begin, .. } => {
+                if self.info.complete() {
+                    // Peers other than the one rejecting it may pick up the block
+                    self.picker.rejected(Block::new(index, begin), peer.id());
+                    self.request_all();
+                }
             }
             Message::Interested => {
                 self.choker.add_peer(peer);
@@ -1106,11 +2196,162 @@ impl<T: cio::CIO> Torrent<T> {
 
             // These messages are all handled at the peer level, not the torrent level,
             // so just ignore here
-            Message::KeepAlive | Message::Choke | Message::Cancel { .. } | Message::Port(_) => {}
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Cancel { .. }
+            | Message::Port(_)
+            | Message::SuggestPiece(_)
+            | Message::AllowedFast(_) => {}
+            // Passed on as a bitfield by the peer
+            Message::HaveAll | Message::HaveNone => unreachable!(),
         }
         Ok(())
     }
 
+    /// Writes a requested block, validating its piece once every
+    /// block of it was received. `ip` is the peer the block came from,
+    /// if any. Returns false if the block was no longer needed.
+    fn handle_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        data: Buffer,
+        length: u32,
+        ip: Option<IpAddr>,
+    ) -> bool {
+        // We already have this block, don't do anything with it, could happen
+        // from endgame
+        if self.picker.have_block(Block::new(index, begin)) {
+            return false;
+        }
+
+        let pr = {
+            let picker = &mut self.picker;
+            let peers = &mut self.peers;
+
+            // The sending peer isn't in peers, so only the
+            // duplicate requests of endgame are cancelled
+            picker.completed(Block::new(index, begin), |pid| {
+                if let Some(p) = peers.get_mut(&pid) {
+                    p.cancel_request(index, begin, length);
+                }
+            })
+        };
+        let piece_done = if let Ok(r) = pr {
+            r
+        } else {
+            return false;
+        };
+
+        self.dirty = true;
+        self.write_piece(index, begin, data);
+        if let Some(ip) = ip {
+            let contributors = self.contributors.entry(index).or_default();
+            if !contributors.contains(&ip) {
+                contributors.push(ip);
+            }
+        }
+
+        self.downloaded += u64::from(length);
+        self.stat.add_dl(u64::from(length));
+        self.activity.transferred(Utc::now());
+
+        if piece_done {
+            self.cio.msg_disk(disk::Request::validate_piece(
+                self.id,
+                self.info.clone(),
+                self.path.clone(),
+                index,
+            ));
+            self.validating.insert(index);
+        }
+        true
+    }
+
+    fn request_meta(peer: &mut Peer<T>, utm_id: u8, idx: usize) {
+        let mut respb = BTreeMap::new();
+        respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
+        respb.insert("piece".to_owned(), bencode::BEncode::Int(idx as i64));
+        let payload = bencode::BEncode::Dict(respb).encode_to_buf();
+        peer.send_message(Message::Extension {
+            id: utm_id,
+            payload,
+        });
+    }
+
+    fn metadata_progress(&self) -> f32 {
+        match self.meta {
+            Some(ref m) if m.have().len() != 0 => m.have().set() as f32 / m.have().len() as f32,
+            Some(_) => 0.,
+            None => 1.,
+        }
+    }
+
+    fn update_rpc_metadata(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentMetadata {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                metadata_progress: self.metadata_progress(),
+            },
+        ]));
+    }
+
+    /// Builds the torrent's info from the fetched metadata once
+    /// every chunk has been received.
+    fn metadata_fetched(&mut self) -> Result<(), ()> {
+        let bytes = match self.meta {
+            Some(ref meta) => meta.bytes().to_vec(),
+            None => return Ok(()),
+        };
+        let ni = bencode::decode_buf(&bytes).ok().and_then(|bni| {
+            let mut b = BTreeMap::new();
+            b.insert(
+                "announce".to_owned(),
+                bencode::BEncode::String(
+                    self.info
+                        .announce
+                        .as_ref()
+                        .map(|u| u.as_str())
+                        .unwrap_or("")
+                        .as_bytes()
+                        .to_vec(),
+                ),
+            );
+            b.insert("info".to_owned(), bni);
+            Info::from_bencode(bencode::BEncode::Dict(b)).ok()
+        });
+        let ni = match ni.filter(|ni| ni.hash == self.info.hash) {
+            Some(ni) => ni,
+            None => {
+                // Some peer sent bad data, start over
+                debug!("Fetched metadata is invalid, refetching");
+                if let Some(ref mut meta) = self.meta {
+                    meta.reset();
+                }
+                return Err(());
+            }
+        };
+        let valid = ni
+            .check_piece_count(CONFIG.disk.warn_pieces, CONFIG.disk.max_pieces)
+            .and_then(|_| ni.check_piece_len(CONFIG.disk.min_piece_len, CONFIG.disk.max_piece_len));
+        if let Err(e) = valid {
+            error!("{}", e);
+            self.status.error = Some(e.clone());
+            self.announce_status();
+            self.notify(NotifyEvent::Error, Some(e));
+            return Ok(());
+        }
+        debug!("Magnet file acquired succesfully!");
+        self.meta = None;
+        self.meta_probed.clear();
+        self.info_bytes = bytes;
+        self.info = Arc::new(ni);
+        self.magnet_complete();
+        Ok(())
+    }
+
     fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
         if id == 0 {
             let b = bencode::decode_buf(&payload).map_err(|_| ())?;
@@ -1121,30 +2362,25 @@ impl<T: cio::CIO> Torrent<T> {
                     .remove("metadata_size")
                     .and_then(|v| v.into_int())
                     .ok_or(())?;
-                if let Some(std::usize::MAX) = self.info_idx {
-                    if size % 16_384 == 0 {
-                        self.info_idx = Some(size as usize / 16_384 - 1);
-                    } else {
-                        self.info_idx = Some(size as usize / 16_384);
+                if let Some(ref mut meta) = self.meta {
+                    if !meta.set_size(size) {
+                        debug!("Peer reported invalid metadata size {}", size);
+                        return Err(());
                     }
-                    // TODO: validate size
-                    self.info_bytes.resize(size as usize, 0u8);
                 }
-                if !self.info.complete() {
-                    // Request the first index chunk to see if they have it
-                    let mut respb = BTreeMap::new();
-                    respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
-                    respb.insert("piece".to_owned(), bencode::BEncode::Int(0));
-                    let payload = bencode::BEncode::Dict(respb).encode_to_buf();
+                let first = self
+                    .meta
+                    .as_ref()
+                    .and_then(|m| m.missing().first().cloned());
+                if let Some(first) = first {
+                    // Request the first missing chunk to see if they have it
                     let utm_id = if let Some(i) = peer.exts().ut_meta {
                         i
                     } else {
                         return Err(());
                     };
-                    peer.send_message(Message::Extension {
-                        id: utm_id,
-                        payload,
-                    });
+                    self.meta_probed.insert(peer.id());
+                    Torrent::request_meta(peer, utm_id, first);
                 }
             }
         } else if id == UT_META_ID {
@@ -1157,17 +2393,17 @@ impl<T: cio::CIO> Torrent<T> {
             let mut d = b.into_dict().ok_or(())?;
             let t = d.remove("msg_type").and_then(|v| v.into_int()).ok_or(())?;
             let p = d.remove("piece").and_then(|v| v.into_int()).ok_or(())? as usize;
-            if p * 16_384 >= self.info_bytes.len() {
-                return Err(());
-            }
             // Our metadata request strategy is as follows: after requesting the first
-            // index chunk, we attempt to request every single subsequent chunk from
+            // missing chunk, we attempt to request every other missing chunk from
             // a peer which responds succesfully. This is slightly wasteful, but
             // simplifies logic (since we don't have to do "index piece picking").
             match t {
                 0 => {
                     let mut respb = BTreeMap::new();
-                    if self.info_idx.is_none() {
+                    if self.meta.is_none() {
+                        if p * 16_384 >= self.info_bytes.len() {
+                            return Err(());
+                        }
                         respb.insert("msg_type".to_owned(), bencode::BEncode::Int(1));
                         respb.insert("piece".to_owned(), bencode::BEncode::Int(p as i64));
                         let size = if self.info_bytes.len() / 16_384 == p {
@@ -1195,60 +2431,35 @@ impl<T: cio::CIO> Torrent<T> {
                     }
                 }
                 1 => {
-                    if let Some(idx) = self.info_idx {
-                        let data_idx = util::find_subseq(&payload[..], b"ee").unwrap() + 2;
-                        if payload.len() - data_idx > self.info_bytes.len() - p * 16_384 {
-                            debug!(
-                                "Metadata bounds invalid, goes to: {}, ibl: {}",
-                                payload.len() - data_idx,
-                                self.info_bytes.len() - p * 16_384,
-                            );
+                    let done = if let Some(ref mut meta) = self.meta {
+                        let total = d.remove("total_size").and_then(|v| v.into_int());
+                        if total.map(|t| t != meta.size() as i64).unwrap_or(false) {
+                            debug!("Metadata chunk {} has mismatched total size", p);
                             return Err(());
                         }
-                        let size = if p == idx {
-                            self.info_bytes.len() - p * 16_384
-                        } else {
-                            16_384
-                        };
-                        (&mut self.info_bytes[p * 16_384..p * 16_384 + size])
-                            .copy_from_slice(&payload[data_idx..]);
-                        if p == idx {
-                            let mut b = BTreeMap::new();
-                            let bni = bencode::decode_buf(&self.info_bytes).map_err(|_| ())?;
-                            b.insert(
-                                "announce".to_owned(),
-                                bencode::BEncode::String(
-                                    self.info
-                                        .announce
-                                        .as_ref()
-                                        .map(|u| u.as_str())
-                                        .unwrap_or("")
-                                        .as_bytes()
-                                        .to_vec(),
-                                ),
-                            );
-                            b.insert("info".to_owned(), bni);
-                            let ni =
-                                Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
-                            if ni.hash == self.info.hash {
-                                debug!("Magnet file acquired succesfully!");
-                                self.info_idx = None;
-                                self.info = Arc::new(ni);
-                                self.magnet_complete();
-                            } else {
+                        let data_idx = util::find_subseq(&payload[..], b"ee").ok_or(())? + 2;
+                        match meta.add(p, &payload[data_idx..]) {
+                            Ok(done) => done,
+                            Err(()) => {
+                                debug!("Invalid metadata chunk {} received", p);
                                 return Err(());
                             }
-                        } else if p == 0 {
-                            for i in 1..=idx {
-                                let mut respb = BTreeMap::new();
-                                respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
-                                respb.insert("piece".to_owned(), bencode::BEncode::Int(i as i64));
-                                let payload = bencode::BEncode::Dict(respb).encode_to_buf();
-                                peer.send_message(Message::Extension {
-                                    id: utm_id,
-                                    payload,
-                                });
-                            }
+                        }
+                    } else {
+                        return Ok(());
+                    };
+                    self.dirty = true;
+                    self.update_rpc_metadata();
+                    if done {
+                        return self.metadata_fetched();
+                    } else if self.meta_probed.remove(&peer.id()) {
+                        let missing = self
+                            .meta
+                            .as_ref()
+                            .map(Metadata::missing)
+                            .unwrap_or_default();
+                        for i in missing {
+                            Torrent::request_meta(peer, utm_id, i);
                         }
                     }
                 }
@@ -1266,6 +2477,9 @@ impl<T: cio::CIO> Torrent<T> {
             if self.info.private {
                 return Err(());
             }
+            if !self.sources().pex {
+                return Ok(());
+            }
             let b = bencode::decode_buf(&payload).map_err(|_| ())?;
             let mut d = b.into_dict().ok_or(())?;
             let mut peers = vec![];
@@ -1287,6 +2501,21 @@ impl<T: cio::CIO> Torrent<T> {
                     peers.push(SocketAddr::V4(socket));
                 }
             }
+            let allowed = peer.pex_budget().take(
+                peers.len(),
+                CONFIG.peer.pex_max_peers,
+                CONFIG.peer.pex_peer_limit,
+                Instant::now(),
+            );
+            if allowed < peers.len() {
+                debug!(
+                    "Ignoring {} PEX peers from {}",
+                    peers.len() - allowed,
+                    peer.addr()
+                );
+                peers.truncate(allowed);
+            }
+            self.pex_received += peers.len() as u64;
             if !peers.is_empty() {
                 self.cio
                     .propagate(cio::Event::Tracker(Ok(tracker::Response::PEX {
@@ -1300,82 +2529,485 @@ impl<T: cio::CIO> Torrent<T> {
         Ok(())
     }
 
-    /// Periodically called to update peers, choking the slowest one and
-    /// optimistically unchoking a new peer
-    pub fn update_unchoked(&mut self) {
-        if self.complete() {
-            self.choker.update_download(&mut self.peers)
-        } else {
-            self.choker.update_upload(&mut self.peers)
-        };
-    }
-
-    pub fn rpc_update(&mut self, u: rpc::proto::resource::CResourceUpdate) {
-        if u.throttle_up.is_some() || u.throttle_down.is_some() {
-            let tu = u.throttle_up.unwrap_or_else(|| self.throttle.ul_rate());
-            let td = u.throttle_down.unwrap_or_else(|| self.throttle.dl_rate());
-            self.set_throttle(tu, td);
-        }
-
-        if let Some(p) = u.path {
-            self.set_path(p);
+    fn ext_handshake(&self) -> Message {
+        let mut ed = BTreeMap::new();
+        let mut m = BTreeMap::new();
+
+        m.insert(
+            "ut_metadata".to_owned(),
+            bencode::BEncode::Int(i64::from(UT_META_ID)),
+        );
+        if self.sources().pex {
+            m.insert(
+                "ut_pex".to_owned(),
+                bencode::BEncode::Int(i64::from(UT_PEX_ID)),
+            );
+        } else if self.public_sources() {
+            // PEX was disabled by the user, tell peers which may have
+            // seen it in an earlier handshake
+            m.insert("ut_pex".to_owned(), bencode::BEncode::Int(0));
         }
 
-        if let Some(p) = u.priority {
-            self.set_priority(p);
-        }
+        ed.insert("m".to_owned(), bencode::BEncode::Dict(m));
+        ed.insert(
+            "metadata_size".to_owned(),
+            bencode::BEncode::Int(self.info_bytes.len() as i64),
+        );
+        let payload = bencode::BEncode::Dict(ed).encode_to_buf();
+        Message::Extension { id: 0, payload }
+    }
 
-        match u.strategy {
-            Some(resource::Strategy::Rarest) => self.change_picker(false),
-            Some(resource::Strategy::Sequential) => self.change_picker(true),
-            None => {}
-        }
+    /// Whether DHT and PEX may be used for this torrent, see `Info::public_sources`.
+    pub fn public_sources(&self) -> bool {
+        self.info.public_sources(!self.trackers.is_empty())
+    }
 
-        if let Some(user_data) = u.user_data {
-            let id = self.rpc_id();
-            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                resource::SResourceUpdate::UserData {
-                    id,
-                    kind: resource::ResourceKind::Torrent,
-                    user_data,
-                },
-            ]));
-        }
+    /// Peer sources in use, taking into account both the user's settings
+    /// and whether the torrent is public.
+    pub fn sources(&self) -> sources::Sources {
+        self.sources.effective(self.public_sources())
     }
 
-    pub fn rpc_update_file(&mut self, id: String, priority: u8) {
-        for (i, f) in self.info.files.iter().enumerate() {
-            let fid =
-                util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
-            if fid == id {
-                Arc::make_mut(&mut self.priorities)[i] = priority;
+    fn set_sources(&mut self, sources: sources::Sources) {
+        let prev = self.sources();
+        self.sources = sources;
+        self.dirty = true;
+        let cur = self.sources();
+        if prev.pex != cur.pex {
+            let handshake = self.ext_handshake();
+            for peer in self.peers.values_mut() {
+                if peer.exts().ut_meta.is_some() {
+                    peer.send_message(handshake.clone());
+                }
             }
         }
-
-        self.picker.set_priorities(&self.priorities, &self.info);
-        self.clear_piece_cache();
-
-        self.check_complete();
-
-        self.dirty = true;
-
-        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-            resource::SResourceUpdate::FilePriority {
-                id,
-                kind: resource::ResourceKind::File,
-                priority,
-            },
-        ]));
+        if !prev.dht && cur.dht {
+            self.dht_announce();
+        }
+        if !prev.lpd && cur.lpd {
+            self.lsd_announce();
+        }
+        if prev.web_seeds != cur.web_seeds {
+            self.update_web_seeds();
+        }
+        self.update_rpc_sources();
     }
 
-    pub fn rpc_update_pieces(&mut self) {
+    fn update_rpc_sources(&mut self) {
         let id = self.rpc_id();
-        let piece_field = self.pieces.b64();
+        let sources = self.sources();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-            resource::SResourceUpdate::TorrentPieces {
+            SResourceUpdate::TorrentSources {
                 id,
                 kind: resource::ResourceKind::Torrent,
-                piece_field,
+                dht: sources.dht,
+                pex: sources.pex,
+                lpd: sources.lpd,
+                use_web_seeds: sources.web_seeds,
+            },
+        ]));
+    }
+
+    /// Returns the tracker group of a seeding torrent, i.e. the host of
+    /// its current tracker, with the seeders and leechers it reported.
+    pub fn group_demand(&self) -> Option<(String, u32, u32)> {
+        if !self.complete() || self.status.stopped() {
+            return None;
+        }
+        let trk = self.trackers.front()?;
+        let group = trk.url.host_str()?.to_owned();
+        match trk.status {
+            TrackerStatus::Ok {
+                seeders, leechers, ..
+            } => Some((group, seeders, leechers)),
+            _ => Some((group, 0, 0)),
+        }
+    }
+
+    /// Builds a magnet URI of the torrent with its trackers in tier order
+    /// and, unless private, a few of its peers as hints.
+    pub fn magnet(&self) -> String {
+        let mut trackers: Vec<_> = self.trackers.iter().collect();
+        trackers.sort_by_key(|trk| trk.tier);
+        let mut urls: Vec<&Url> = Vec::new();
+        for trk in trackers {
+            if !urls.contains(&&*trk.url) {
+                urls.push(&trk.url);
+            }
+        }
+        let peers: Vec<_> = if self.info.private {
+            Vec::new()
+        } else {
+            self.peers
+                .values()
+                .take(MAGNET_PEERS)
+                .map(|p| p.addr())
+                .collect()
+        };
+        self.info.to_magnet(&urls, &peers)
+    }
+
+    /// Pieces we have as a base64 bitfield, along with the number of
+    /// connected peers having each piece.
+    pub fn piece_state(&self) -> (String, Vec<rpc::proto::message::AvailabilityRun>) {
+        let mut counts = vec![0u32; self.pieces.len() as usize];
+        for peer in self.peers.values() {
+            for idx in peer.pieces().iter() {
+                if let Some(c) = counts.get_mut(idx as usize) {
+                    *c += 1;
+                }
+            }
+        }
+        (self.pieces.b64(), availability_runs(&counts))
+    }
+
+    /// Snapshot of the blocks currently requested from peers
+    pub fn in_flight_requests(&self) -> Vec<rpc::proto::message::InFlightRequest> {
+        let ih = &self.info.hash;
+        self.picker
+            .in_flight(Instant::now())
+            .into_iter()
+            .map(|r| rpc::proto::message::InFlightRequest {
+                piece: r.block.index,
+                offset: r.block.offset,
+                peers: r
+                    .peers
+                    .into_iter()
+                    .map(|pid| util::peer_rpc_id(ih, pid as u64))
+                    .collect(),
+                pending_ms: r.pending.as_secs() * 1000 + u64::from(r.pending.subsec_millis()),
+                stalled: r.stalled,
+            })
+            .collect()
+    }
+
+    /// Number of established connections to peers we're uploading to
+    pub fn warm_peers(&self) -> u32 {
+        self.peers.values().filter(|p| p.warm()).count() as u32
+    }
+
+    /// Whether at most `peer.underseeded_seeders` peers other than us
+    /// seed the torrent, going by its trackers' last reports and the
+    /// seeders we're connected to. Always false unless
+    /// `peer.availability_boost` is enabled or while nothing is known
+    /// of the swarm.
+    pub fn underseeded(&self) -> bool {
+        if !CONFIG.peer.availability_boost {
+            return false;
+        }
+        let reported = self
+            .trackers
+            .iter()
+            .filter_map(|trk| match trk.status {
+                TrackerStatus::Ok { seeders, .. } => Some(seeders),
+                _ => trk.scrape.map(|s| s.seeders),
+            })
+            .max();
+        match reported {
+            // Trackers count us among the seeders
+            Some(s) => {
+                let others = cmp::max(s.saturating_sub(1), seeders(&self.peers).len() as u32);
+                others <= CONFIG.peer.underseeded_seeders
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the tracker group of the torrent and the upload slots
+    /// allocated to it, or restores the default without a group.
+    /// `warm_bias` is the number of those slots owed to the warm
+    /// connection tiebreaker, and `availability_boost` those owed to
+    /// the swarm being underseeded.
+    pub fn set_upload_group(&mut self, group: Option<(String, usize, i16, i16)>) {
+        let (group, slots, bias, boost) = match group {
+            Some((group, slots, bias, boost)) => (Some(group), slots, bias, boost),
+            None => (None, choker::DEFAULT_SLOTS, 0, 0),
+        };
+        if group == self.tracker_group
+            && slots == self.choker.slots()
+            && bias == self.warm_bias
+            && boost == self.availability_boost
+        {
+            return;
+        }
+        debug!(
+            "Torrent {} has {} upload slots in group {:?}",
+            self.id, slots, group
+        );
+        self.choker.set_slots(slots, &mut self.peers);
+        self.tracker_group = group;
+        self.warm_bias = bias;
+        self.availability_boost = boost;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentUploadSlots {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                upload_slots: slots as u16,
+                tracker_group: self.tracker_group.clone(),
+                warm_bias: bias,
+                availability_boost: boost,
+            },
+        ]));
+    }
+
+    /// Periodically called to update peers, choking the slowest one and
+    /// optimistically unchoking a new peer
+    pub fn update_unchoked(&mut self) {
+        if self.complete() {
+            self.choker.update_download(&mut self.peers)
+        } else {
+            self.choker.update_upload(&mut self.peers)
+        };
+        let changes = self.choker.take_freeloader_changes();
+        if !changes.is_empty() {
+            let ih = &self.info.hash;
+            let updates = changes
+                .into_iter()
+                .map(|(pid, freeloader)| SResourceUpdate::PeerFreeloader {
+                    id: util::peer_rpc_id(ih, pid as u64),
+                    kind: resource::ResourceKind::Peer,
+                    freeloader,
+                })
+                .collect();
+            self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+        }
+    }
+
+    /// Pins a peer as a primary source: it's kept unchoked and
+    /// requested from as deeply as it allows.
+    pub fn set_preferred(&mut self, pid: usize) {
+        if let Some(peer) = self.peers.get_mut(&pid) {
+            peer.set_preferred();
+        }
+        self.choker.pin(pid, &mut self.peers);
+        if self.peers.contains_key(&pid) {
+            self.make_requests_pid(pid);
+        }
+    }
+
+    pub fn rpc_update(&mut self, u: rpc::proto::resource::CResourceUpdate) {
+        if u.throttle_up.is_some() || u.throttle_down.is_some() || u.throttle_up_seeding.is_some() {
+            let tu = u.throttle_up.unwrap_or_else(|| self.throttle.ul_rate());
+            let td = u.throttle_down.unwrap_or_else(|| self.throttle.dl_rate());
+            let ts = u
+                .throttle_up_seeding
+                .unwrap_or_else(|| self.throttle.ul_seed_rate());
+            self.set_throttle(tu, td, ts);
+        }
+
+        if let Some(p) = u.path {
+            if let Err(e) = self.move_to(p) {
+                warn!("Couldn't move torrent {}: {}", self.rpc_id(), e);
+            }
+        }
+
+        if let Some(p) = u.priority {
+            self.set_priority(p);
+        }
+
+        match u.strategy {
+            Some(resource::Strategy::Rarest) => self.set_strategy(PieceStrategy::Rarest),
+            Some(resource::Strategy::Sequential) => self.set_strategy(PieceStrategy::Sequential),
+            None => {}
+        }
+
+        if let Some(enabled) = u.super_seed {
+            self.set_super_seed(enabled);
+        }
+
+        if let Some(enabled) = u.integrity_check {
+            self.integrity.enabled = enabled;
+            self.dirty = true;
+            self.update_rpc_integrity();
+        }
+
+        if u.dht.is_some() || u.pex.is_some() || u.lpd.is_some() || u.use_web_seeds.is_some() {
+            let s = self.sources;
+            self.set_sources(sources::Sources {
+                dht: u.dht.unwrap_or(s.dht),
+                pex: u.pex.unwrap_or(s.pex),
+                lpd: u.lpd.unwrap_or(s.lpd),
+                web_seeds: u.use_web_seeds.unwrap_or(s.web_seeds),
+            });
+        }
+
+        if u.flush_policy.is_some() || u.flush_every.is_some() {
+            let mut s = self.flush.schedule();
+            match u.flush_policy {
+                Some(resource::FlushPolicy::Piece) => s.policy = FlushPolicy::Piece,
+                Some(resource::FlushPolicy::Pieces) => s.policy = FlushPolicy::Pieces,
+                Some(resource::FlushPolicy::Interval) => s.policy = FlushPolicy::Interval,
+                None => {}
+            }
+            if let Some(every) = u.flush_every {
+                s.every = every;
+            }
+            self.flush.set_custom(Some(s));
+            self.dirty = true;
+            self.update_rpc_flush();
+        }
+
+        if let Some(delay) = u.seed_delay {
+            self.set_seed_delay(delay);
+        }
+
+        if u.ratio_limit.is_some() || u.seed_time_limit.is_some() {
+            if let Some(ratio) = u.ratio_limit {
+                self.ratio_limit = Some(ratio.max(0.));
+            }
+            if u.seed_time_limit.is_some() {
+                self.seed_time_limit = u.seed_time_limit;
+            }
+            self.dirty = true;
+            self.update_rpc_seed_limits();
+        }
+
+        if let Some(user_data) = u.user_data {
+            let id = self.rpc_id();
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                resource::SResourceUpdate::UserData {
+                    id,
+                    kind: resource::ResourceKind::Torrent,
+                    user_data,
+                },
+            ]));
+        }
+    }
+
+    pub fn rpc_update_file(&mut self, id: String, priority: u8) {
+        let changes: Vec<_> = (0..self.info.files.len())
+            .filter(|&i| self.file_rpc_id(i) == id)
+            .map(|i| (i, priority))
+            .collect();
+        self.set_file_priorities(&changes);
+        self.check_complete();
+    }
+
+    /// Sets the initial file priorities from the configured rules,
+    /// used when a torrent is added.
+    pub fn apply_file_rules(&mut self) {
+        let changes = file_rules::priorities(&CONFIG.disk.file_priorities, &self.info.files);
+        self.set_file_priorities(&changes);
+    }
+
+    /// Only downloads the files at the given indices, used when a
+    /// torrent is added with a file selection.
+    pub fn select_files(&mut self, files: &[u32]) {
+        let changes: Vec<_> = (0..self.info.files.len())
+            .filter(|&i| !files.contains(&(i as u32)))
+            .map(|i| (i, 0))
+            .collect();
+        self.set_file_priorities(&changes);
+    }
+
+    /// Handles the torrent being added again with the file selection
+    /// `files`, returning the IDs of the newly selected files or None
+    /// if the duplicate is rejected.
+    pub fn add_duplicate(
+        &mut self,
+        policy: DuplicateAdd,
+        files: Option<&[u32]>,
+    ) -> Option<Vec<String>> {
+        let changes = merge_selection(&self.priorities, policy, files)?;
+        let ids = changes.iter().map(|&(i, _)| self.file_rpc_id(i)).collect();
+        self.set_file_priorities(&changes);
+        self.check_complete();
+        Some(ids)
+    }
+
+    fn set_file_priorities(&mut self, changes: &[(usize, u8)]) {
+        if changes.is_empty() {
+            return;
+        }
+        let mut updates = Vec::with_capacity(changes.len());
+        let mut selected = Vec::new();
+        for &(i, priority) in changes {
+            // Higher priorities are applied, and reported, as the highest
+            let priority = cmp::min(priority, picker::MAX_PRIORITY);
+            if self.priorities[i] == 0 && priority != 0 {
+                selected.push(i);
+            }
+            Arc::make_mut(&mut self.priorities)[i] = priority;
+            updates.push(resource::SResourceUpdate::FilePriority {
+                id: self.file_rpc_id(i),
+                kind: resource::ResourceKind::File,
+                priority,
+            });
+        }
+
+        self.picker.set_priorities(&self.priorities, &self.info);
+        self.clear_piece_cache();
+        self.dirty = true;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
+        self.allocate_files(&selected);
+    }
+
+    fn file_rpc_id(&self, idx: usize) -> String {
+        let path = self.info.files[idx].path.to_string_lossy();
+        util::file_rpc_id(&self.info.hash, path.as_ref())
+    }
+
+    /// Rehashes up to `count` of the next pieces in the integrity sweep
+    /// if the torrent is seeding.
+    pub fn check_integrity(&mut self, count: u32) {
+        if !self.integrity.enabled
+            || self.integrity.checking
+            || count == 0
+            || !self.complete()
+            || self.status.stopped()
+        {
+            return;
+        }
+        let mut pieces = Vec::new();
+        while pieces.len() < count as usize && self.integrity.idx < self.info.pieces() {
+            if self.pieces.has_bit(u64::from(self.integrity.idx)) {
+                pieces.push(self.integrity.idx);
+            }
+            self.integrity.idx += 1;
+        }
+        if pieces.is_empty() {
+            self.finish_sweep();
+            return;
+        }
+        self.integrity.checking = true;
+        self.cio.msg_disk(disk::Request::check_pieces(
+            self.id,
+            self.info.clone(),
+            self.path.clone(),
+            pieces,
+        ));
+    }
+
+    fn finish_sweep(&mut self) {
+        debug!("Integrity sweep of {} complete", self.rpc_id());
+        self.integrity.idx = 0;
+        self.integrity.last_sweep = Some(Utc::now());
+        self.dirty = true;
+        self.update_rpc_integrity();
+    }
+
+    fn update_rpc_integrity(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentIntegrity {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                integrity_check: self.integrity.enabled,
+                last_sweep: self.integrity.last_sweep,
+                corrupt_pieces: self.integrity.corrupt,
+            },
+        ]));
+    }
+
+    pub fn rpc_update_pieces(&mut self) {
+        let id = self.rpc_id();
+        let piece_field = self.pieces.b64();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentPieces {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                piece_field,
             },
         ]));
     }
@@ -1386,11 +3018,11 @@ impl<T: cio::CIO> Torrent<T> {
         let mut resources = Vec::new();
         resources.push(self.rpc_info());
         resources.extend(self.rpc_trk_info());
-        if self.info_idx.is_none() {
+        if self.meta.is_none() {
             resources.extend(self.rpc_rel_info());
         }
         self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
-        if self.info_idx.is_none() {
+        if self.meta.is_none() {
             self.update_rpc_transfer();
         }
         self.serialize();
@@ -1405,13 +3037,14 @@ impl<T: cio::CIO> Torrent<T> {
             self.dump_torrent_file();
         }
         self.dht_announce();
+        self.lsd_announce();
     }
 
     fn dht_announce(&mut self) {
         if self.status.stopped() {
             return;
         }
-        if !self.info.private {
+        if self.sources().dht {
             let mut req = tracker::Request::DHTAnnounce(self.info.hash);
             self.cio.msg_trk(req);
             req = tracker::Request::GetPeers(tracker::GetPeers {
@@ -1422,20 +3055,58 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Announces the torrent to peers on the LAN
+    pub fn lsd_announce(&mut self) {
+        if self.status.stopped() {
+            return;
+        }
+        if CONFIG.net.lsd && self.sources().lpd {
+            self.cio
+                .msg_trk(tracker::Request::LSDAnnounce(self.info.hash));
+        }
+    }
+
     pub fn complete(&self) -> bool {
         self.status.completed()
     }
 
-    fn set_throttle(&mut self, ul: Option<i64>, dl: Option<i64>) {
+    fn set_throttle(&mut self, ul: Option<i64>, dl: Option<i64>, ul_seeding: Option<i64>) {
         self.throttle.set_ul_rate(ul);
         self.throttle.set_dl_rate(dl);
+        self.throttle.set_ul_seed_rate(ul_seeding);
+        self.dirty = true;
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             resource::SResourceUpdate::Throttle {
-                id,
+                id: id.clone(),
                 kind: resource::ResourceKind::Torrent,
                 throttle_up: ul,
                 throttle_down: dl,
+                throttle_up_seeding: ul_seeding,
+            },
+            resource::SResourceUpdate::TorrentThrottleActive {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                throttle_up_active: self.throttle.active_ul_rate(),
+            },
+        ]));
+    }
+
+    /// Applies the seeding upload rate once the torrent is complete,
+    /// and the regular one whenever it's downloading again.
+    fn update_upload_role(&mut self) {
+        let seeding =
+            self.status.state == StatusState::Complete && self.status.completing.is_none();
+        if self.throttle.seeding() == seeding {
+            return;
+        }
+        self.throttle.set_seeding(seeding);
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentThrottleActive {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                throttle_up_active: self.throttle.active_ul_rate(),
             },
         ]));
     }
@@ -1449,13 +3120,33 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn magnet_complete(&mut self) {
+        let public = self.public_sources();
         self.status.state = StatusState::Incomplete;
         self.announce_status();
         self.pieces = Bitfield::new(u64::from(self.info.pieces()));
-        self.priorities = Arc::new(vec![3; self.info.files.len()]);
+        let mut priorities = vec![3; self.info.files.len()];
+        for (i, p) in file_rules::priorities(&CONFIG.disk.file_priorities, &self.info.files) {
+            priorities[i] = p;
+        }
+        self.priorities = Arc::new(priorities);
         for peer in self.peers.values_mut() {
             peer.magnet_complete(&self.info);
         }
+        self.preallocate();
+
+        // Now that the torrent is known to be public, start using DHT and
+        // advertise PEX support to peers which already received our handshake.
+        if !public && self.public_sources() {
+            debug!("Magnet metadata is public, enabling DHT, PEX and LSD");
+            let handshake = self.ext_handshake();
+            for peer in self.peers.values_mut() {
+                if peer.exts().ut_meta.is_some() {
+                    peer.send_message(handshake.clone());
+                }
+            }
+            self.dht_announce();
+            self.lsd_announce();
+        }
 
         let resources = self.rpc_rel_info();
         self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
@@ -1466,28 +3157,14 @@ impl<T: cio::CIO> Torrent<T> {
             )]));
         self.serialize();
 
-        let seq = self.picker.is_sequential();
+        let strategy = self.picker.strategy();
         self.picker = Picker::new(&self.info, &self.pieces, &self.priorities);
-        self.change_picker(seq);
+        self.set_strategy(strategy);
         self.files = Files::new(&self.info, &self.pieces);
-        self.validate();
+        self.validate(false);
         self.dump_torrent_file();
     }
 
-    fn set_path(&mut self, path: String) {
-        let from = if let Some(ref p) = self.path {
-            p.clone()
-        } else {
-            CONFIG.disk.directory.clone()
-        };
-        self.cio.msg_disk(disk::Request::Move {
-            tid: self.id,
-            from,
-            to: path,
-            target: self.info.name.clone(),
-        });
-    }
-
     fn set_priority(&mut self, priority: u8) {
         self.priority = priority;
         let id = self.rpc_id();
@@ -1500,8 +3177,136 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// Restricts downloading to the given pieces. Once they've all
+    /// been downloaded the torrent is either paused or resumes
+    /// downloading its remaining pieces.
+    pub fn set_target_pieces(&mut self, pieces: Vec<u32>, pause: bool) {
+        let pieces: FHashSet<u32> = pieces
+            .into_iter()
+            .filter(|&p| p < self.info.pieces() && !self.pieces.has_bit(u64::from(p)))
+            .collect();
+        debug!("Restricting download to {} pieces", pieces.len());
+        self.target = Some(Target {
+            pieces: pieces.clone(),
+            pause,
+        });
+        self.picker
+            .set_target(Some(pieces), &self.priorities, &self.info);
+        self.clear_piece_cache();
+        // All requested pieces may already be present
+        if !self.check_target(None) {
+            self.update_rpc_target();
+            self.request_all();
+        }
+    }
+
+    /// Marks a piece of the target set as downloaded, returning true
+    /// if this completed the target and the restriction was lifted.
+    fn check_target(&mut self, piece: Option<u32>) -> bool {
+        let done = match self.target {
+            Some(ref mut target) => {
+                if let Some(p) = piece {
+                    target.pieces.remove(&p);
+                }
+                target.pieces.is_empty()
+            }
+            None => return false,
+        };
+        if piece.is_some() {
+            self.update_rpc_target();
+        }
+        if !done {
+            return false;
+        }
+        let target = self.target.take().unwrap();
+        info!("Downloaded all target pieces of {}", self);
+        self.picker.set_target(None, &self.priorities, &self.info);
+        self.clear_piece_cache();
+        self.update_rpc_target();
+        if target.pause {
+            self.pause();
+        } else {
+            self.request_all();
+        }
+        true
+    }
+
+    fn target_pieces(&self) -> u64 {
+        self.target
+            .as_ref()
+            .map(|t| t.pieces.len() as u64)
+            .unwrap_or(0)
+    }
+
+    fn update_rpc_target(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentTarget {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                target_pieces: self.target_pieces(),
+            },
+        ]));
+    }
+
+    /// Serves a download of one of the torrent's files as its pieces
+    /// arrive, picking the pieces it covers in order ahead of others.
+    pub fn stream(&mut self, file_id: &str, mut download: disk::Request) {
+        let file = match (0..self.info.files.len()).find(|&i| self.file_rpc_id(i) == file_id) {
+            Some(f) => f,
+            None => return,
+        };
+        if self.priorities[file] == 0 {
+            // Skipped files are downloaded at the default priority
+            self.set_file_priorities(&[(file, 3)]);
+            self.check_complete();
+        }
+        let offset: u64 = self.info.files[..file].iter().map(|f| f.length).sum();
+        let piece_len = u64::from(self.info.piece_len);
+        let now = Instant::now();
+        let mut deadlines = Vec::new();
+        for range in download.ranges().iter().filter(|r| r.length != 0) {
+            let first = (offset + range.start) / piece_len;
+            let last = (offset + range.start + range.length - 1) / piece_len;
+            for piece in first..=last {
+                if !self.pieces.has_bit(piece) {
+                    let wait = STREAM_PIECE_MS * (deadlines.len() as u64 + 1);
+                    deadlines.push((now + Duration::from_millis(wait), piece as u32));
+                }
+            }
+        }
+        self.next_stream += 1;
+        let id = self.next_stream;
+        download.set_stream(disk::Stream {
+            tid: self.id,
+            id,
+            offset,
+            piece_len,
+            have: self.pieces.clone(),
+        });
+        self.cio.msg_disk(download);
+        if !deadlines.is_empty() {
+            debug!("Streaming {} pieces of {}", deadlines.len(), self);
+            self.streams.insert(id, deadlines);
+            self.update_deadlines();
+            self.request_all();
+        }
+    }
+
+    /// Has the picker prioritize the pieces streams need, each by the
+    /// earliest time any stream needs it.
+    fn update_deadlines(&mut self) {
+        let mut earliest: FHashMap<u32, Instant> = FHashMap::default();
+        for &(deadline, piece) in self.streams.values().flat_map(|s| s.iter()) {
+            let e = earliest.entry(piece).or_insert(deadline);
+            *e = cmp::min(*e, deadline);
+        }
+        self.picker
+            .set_deadlines(earliest.into_iter().map(|(p, d)| (d, p)).collect());
+    }
+
     fn rpc_info(&self) -> resource::Resource {
-        let (name, size, pieces, piece_size, files) = if self.info_idx.is_none() {
+        let (name, size, pieces, piece_size, files) = if self.meta.is_none() {
             (
                 Some(self.info.name.clone()),
                 Some(self.info.total_len),
@@ -1522,7 +3327,7 @@ impl<T: cio::CIO> Torrent<T> {
             name,
             size,
             // TODO: Properly add this
-            path: self.path.as_ref().unwrap_or(&CONFIG.disk.directory).clone(),
+            path: self.path().to_owned(),
             created: self.created,
             modified: Utc::now(),
             status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
@@ -1530,15 +3335,13 @@ impl<T: cio::CIO> Torrent<T> {
             priority: self.priority,
             progress: self.progress(),
             availability: self.availability(),
-            strategy: if self.sequential() {
-                resource::Strategy::Sequential
-            } else {
-                resource::Strategy::Rarest
-            },
+            strategy: self.rpc_strategy(),
             rate_up: 0,
             rate_down: 0,
             throttle_up: self.throttle.ul_rate(),
             throttle_down: self.throttle.dl_rate(),
+            throttle_up_seeding: self.throttle.ul_seed_rate(),
+            throttle_up_active: self.throttle.active_ul_rate(),
             transferred_up: self.uploaded,
             transferred_down: self.downloaded,
             peers: 0,
@@ -1547,9 +3350,46 @@ impl<T: cio::CIO> Torrent<T> {
             piece_size,
             piece_field: self.pieces.b64(),
             private: self.info.private,
+            dht: self.sources().dht,
+            pex: self.sources().pex,
+            lpd: self.sources().lpd,
+            use_web_seeds: self.sources().web_seeds,
             creator: self.info.creator.clone(),
             comment: self.info.comment.clone(),
             files,
+            path_conflicts: self.conflicts.clone(),
+            integrity_check: self.integrity.enabled,
+            last_sweep: self.integrity.last_sweep,
+            corrupt_pieces: self.integrity.corrupt,
+            preserved_peers: self.preserved_peers,
+            target_pieces: self.target_pieces(),
+            web_seeds: self.web_seeds.len() as u8,
+            web_seeds_engaged: self.web_seeds.engaged(),
+            web_seed_downloaded: self.web_seeds.downloaded(),
+            upload_slots: self.choker.slots() as u16,
+            tracker_group: self.tracker_group.clone(),
+            warm_bias: self.warm_bias,
+            availability_boost: self.availability_boost,
+            metadata_progress: self.metadata_progress(),
+            pex_sent: self.pex_sent,
+            pex_received: self.pex_received,
+            flush_policy: self.rpc_flush_policy(),
+            flush_every: self.flush.schedule().every,
+            seed_delay: self.seed_delay(),
+            ratio_limit: self.ratio_limit(),
+            seed_time_limit: self.seed_time_limit(),
+            seeding_secs: self.activity.seeding_secs(),
+            allocation: match self.allocation {
+                Allocation::Sparse => resource::Allocation::Sparse,
+                Allocation::Full => resource::Allocation::Full,
+            },
+            endgame: self.endgame,
+            super_seed: self.super_seed.enabled(),
+            pieces_revealed: self.super_seed.revealed(),
+            completed: self.activity.completed(),
+            last_active: self.activity.last_active(),
+            active_secs: self.activity.active_secs(),
+            peer_resyncs: self.peer_resyncs,
             ..Default::default()
         })
     }
@@ -1583,7 +3423,15 @@ impl<T: cio::CIO> Torrent<T> {
                 availability: 0.,
                 progress,
                 priority: self.priorities[i],
-                path: self.info.files[i].path.to_string_lossy().into_owned(),
+                priority_rule: file_rules::matching(
+                    &CONFIG.disk.file_priorities,
+                    &self.info.files[i],
+                )
+                .map(|r| r.pattern.clone()),
+                path: self.info.files[i]
+                    .disk_path()
+                    .to_string_lossy()
+                    .into_owned(),
                 size: total,
                 ..Default::default()
             }))
@@ -1607,6 +3455,11 @@ impl<T: cio::CIO> Torrent<T> {
                     url: trk.url.as_ref().clone(),
                     last_report: trk.last_announce,
                     error: None,
+                    headers: trk.headers.names(),
+                    tier: trk.tier,
+                    seeders: trk.scrape.map(|s| s.seeders),
+                    leechers: trk.scrape.map(|s| s.leechers),
+                    downloaded: trk.scrape.map(|s| s.downloaded),
                     ..Default::default()
                 }))
             })
@@ -1636,18 +3489,21 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.error.clone()
     }
 
-    fn sequential(&self) -> bool {
-        self.picker.is_sequential()
+    fn rpc_strategy(&self) -> resource::Strategy {
+        match self.picker.strategy() {
+            PieceStrategy::Rarest => resource::Strategy::Rarest,
+            PieceStrategy::Sequential => resource::Strategy::Sequential,
+        }
     }
 
     fn progress(&self) -> f32 {
         if self.status.magnet() {
             return 0.0;
         }
-        if let Some(amnt) = self.status.validating {
+        if let Some(amnt) = self.status.validating.or(self.status.moving) {
             amnt
         } else {
-            self.pieces.iter().count() as f32 / self.info.pieces() as f32
+            self.pieces.set() as f32 / self.info.pieces() as f32
         }
     }
 
@@ -1658,16 +3514,16 @@ impl<T: cio::CIO> Torrent<T> {
         if self.status.magnet() {
             return 0.0;
         }
-        let mut peers_have = FHashSet::default();
+        let mut peers_have = Bitfield::new(self.pieces.len());
         for peer in self.peers.values() {
             for piece in peer.pieces().iter() {
-                peers_have.insert(piece);
+                peers_have.set_bit(piece);
             }
-            if peers_have.len() as u64 == self.pieces.len() {
+            if peers_have.complete() {
                 return 1.0;
             }
         }
-        peers_have.len() as f32 / self.pieces.len() as f32
+        peers_have.set() as f32 / self.pieces.len() as f32
     }
 
     /// Resets the last upload/download statistics, adjusting the internal
@@ -1676,11 +3532,147 @@ impl<T: cio::CIO> Torrent<T> {
         self.stat.tick();
         let mut active = self.stat.active();
         self.picker.tick();
+        let unchoking = self.peers.values().filter(|p| p.unchoking()).count();
+        self.picker
+            .set_unchoking(CONFIG.peer.endgame_min_peers, unchoking);
+        if self.picker.endgame() != self.endgame {
+            self.endgame = self.picker.endgame();
+            let id = self.rpc_id();
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                SResourceUpdate::TorrentEndgame {
+                    id,
+                    kind: resource::ResourceKind::Torrent,
+                    endgame: self.endgame,
+                },
+            ]));
+        }
 
         for (_, peer) in self.peers.iter_mut() {
             active |= peer.tick();
         }
-        active
+        let seeding = self.complete() && !self.status.stopped() && self.status.completing.is_none();
+        self.activity.tick(active, seeding, Instant::now());
+        self.resync_peers(Instant::now());
+        self.update_web_seeds();
+        if let Some(files) = self.flush.tick(Instant::now()) {
+            self.flush_files(files);
+        }
+        if self.status.seeding_due(Instant::now()) {
+            self.status.completing = None;
+            self.set_finished();
+        }
+        active
+    }
+
+    /// Decides whether web seeds should be used alongside peers,
+    /// according to the configured strategy.
+    fn update_web_seeds(&mut self) {
+        if self.web_seeds.is_empty() {
+            return;
+        }
+        let changed = if self.should_request() && self.sources.web_seeds {
+            let swarm = webseed::Swarm {
+                useful_peers: self
+                    .peers
+                    .values()
+                    .filter(|p| self.pieces.usable(p.pieces()))
+                    .count(),
+                available: self.availability() >= 1.0,
+                rate: self.stat.avg_dl(),
+            };
+            self.web_seeds.update(
+                CONFIG.net.web_seed_strategy,
+                CONFIG.net.web_seed_rate,
+                &swarm,
+            )
+        } else {
+            self.web_seeds.disengage()
+        };
+        if !self.web_seeds.engaged() {
+            for (idx, piece) in self.web_seeds.release() {
+                self.picker.release_whole(piece, webseed::id(idx));
+            }
+        }
+        if changed {
+            self.update_rpc_web_seeds();
+        }
+        self.request_web_seeds();
+    }
+
+    /// Starts fetching pieces from idle web seeds, as far as the
+    /// download throttle allows.
+    fn request_web_seeds(&mut self) {
+        let now = Instant::now();
+        while let Some(idx) = self.web_seeds.idle(now) {
+            let len = self.info.piece_len as usize;
+            if !self.should_request() || !self.web_seeds.reserve(&mut self.throttle, len) {
+                break;
+            }
+            let piece = match self.picker.pick_whole(webseed::id(idx)) {
+                Some(piece) => piece,
+                None => break,
+            };
+            for req in self.web_seeds.start(idx, self.id, &self.info, piece) {
+                self.cio.msg_trk(tracker::Request::WebSeed(req));
+            }
+        }
+    }
+
+    /// Handles a range fetched from a web seed, writing its piece
+    /// once every range of it was received.
+    pub fn handle_web_seed(
+        &mut self,
+        seed: &Url,
+        piece: u32,
+        start: usize,
+        resp: tracker::Result<Vec<u8>>,
+    ) {
+        let now = Instant::now();
+        match self.web_seeds.received(seed, piece, start, resp, now) {
+            webseed::Received::Stale | webseed::Received::Pending => return,
+            webseed::Received::Failed(idx) => {
+                self.picker.release_whole(piece, webseed::id(idx));
+            }
+            webseed::Received::Piece(idx, data) => {
+                self.write_web_seed_piece(idx, piece, &data);
+                self.update_rpc_web_seeds();
+            }
+        }
+        self.request_web_seeds();
+    }
+
+    fn write_web_seed_piece(&mut self, idx: usize, index: u32, data: &[u8]) {
+        // Blocks may have come from peers in the meantime
+        if self.pieces.has_bit(u64::from(index))
+            || self.validating.contains(&index)
+            || self.status.stopped()
+        {
+            return;
+        }
+        for (i, chunk) in data.chunks(16_384).enumerate() {
+            let begin = i as u32 * 16_384;
+            match Buffer::get() {
+                Some(mut buf) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    self.handle_block(index, begin, buf, chunk.len() as u32, None);
+                }
+                None => self
+                    .picker
+                    .rejected(Block::new(index, begin), webseed::id(idx)),
+            }
+        }
+    }
+
+    fn update_rpc_web_seeds(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentWebSeeds {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                web_seeds_engaged: self.web_seeds.engaged(),
+                web_seed_downloaded: self.web_seeds.downloaded(),
+            },
+        ]));
     }
 
     pub fn get_last_tx_rate(&self) -> (u64, u64) {
@@ -1706,7 +3698,7 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn make_requests_pid(&mut self, pid: usize) {
-        if self.status.should_dl() {
+        if self.should_request() {
             let peer = self
                 .peers
                 .get_mut(&pid)
@@ -1715,6 +3707,45 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Re-derives our view of peers which have been unchoking us for
+    /// peer.resync_secs with pieces we want, but weren't requested from.
+    fn resync_peers(&mut self, now: Instant) {
+        let idle = Duration::from_secs(CONFIG.peer.resync_secs);
+        if idle == Duration::from_secs(0) || !self.should_request() || !self.info.complete() {
+            return;
+        }
+        let stale: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|&(_, p)| desynced(p, &self.pieces, idle, now))
+            .map(|(pid, _)| *pid)
+            .collect();
+        if stale.is_empty() {
+            return;
+        }
+        for pid in stale {
+            let peer = self.peers.get_mut(&pid).unwrap();
+            debug!("Resyncing pieces of peer {}", peer.addr());
+            peer.resync(now);
+            peer.interested();
+            Torrent::make_requests(peer, &mut self.picker, &self.info);
+            self.peer_resyncs += 1;
+        }
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentResyncs {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                peer_resyncs: self.peer_resyncs,
+            },
+        ]));
+    }
+
+    /// Whether blocks should be requested from peers
+    fn should_request(&self) -> bool {
+        self.status.should_dl() && !self.buffer_full
+    }
+
     fn make_requests(peer: &mut Peer<T>, picker: &mut Picker, info: &Info) {
         if let Some(m) = peer.queue_reqs() {
             for _ in 0..(m) {
@@ -1733,18 +3764,19 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
-    pub fn add_peer(&mut self, conn: PeerConn) -> Option<usize> {
+    pub fn add_peer(&mut self, conn: PeerConn, source: resource::PeerSource) -> Option<usize> {
         if self.peers.len() >= MAX_PEERS {
             return None;
         }
         if self.peers.values().any(|p| p.addr() == conn.sock().addr()) {
             return None;
         }
-        if let Ok(p) = Peer::new(conn, self, None, None) {
+        if let Ok(mut p) = Peer::new(conn, self, None, None, source) {
             let pid = p.id();
-            if self.info_idx.is_none() {
+            if self.meta.is_none() {
                 self.picker.add_peer(&p);
             }
+            self.reveal_piece(&mut p);
             self.peers.insert(pid, p);
             Some(pid)
         } else {
@@ -1756,12 +3788,19 @@ impl<T: cio::CIO> Torrent<T> {
         if self.peers.values().any(|p| p.addr() == conn.sock().addr()) {
             return None;
         }
-        if let Ok(p) = Peer::new(conn, self, Some(id), Some(rsv)) {
+        if let Ok(mut p) = Peer::new(
+            conn,
+            self,
+            Some(id),
+            Some(rsv),
+            resource::PeerSource::Incoming,
+        ) {
             let pid = p.id();
             debug!("Adding peer {:?}!", pid);
-            if self.info_idx.is_none() {
+            if self.meta.is_none() {
                 self.picker.add_peer(&p);
             }
+            self.reveal_piece(&mut p);
             self.peers.insert(pid, p);
             Some(pid)
         } else {
@@ -1770,6 +3809,7 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn announce_status(&mut self) {
+        self.update_upload_role();
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             SResourceUpdate::TorrentStatus {
@@ -1800,15 +3840,19 @@ impl<T: cio::CIO> Torrent<T> {
             .iter()
             .map(|tracker| {
                 let id = util::trk_rpc_id(&self.info.hash, tracker.url.as_str());
-                let error = match tracker.status {
-                    TrackerStatus::Failure(ref r) => Some(r.clone()),
-                    _ => None,
+                let (error, warning) = match tracker.status {
+                    TrackerStatus::Failure(ref r) => (Some(r.clone()), None),
+                    TrackerStatus::Ok { ref warning, .. } => (None, warning.clone()),
+                    TrackerStatus::Updating => (None, None),
                 };
                 SResourceUpdate::TrackerStatus {
                     id,
                     kind: resource::ResourceKind::Tracker,
                     last_report: tracker.last_announce,
                     error,
+                    warning,
+                    failures: tracker.failures,
+                    dead: tracker.dead,
                 }
             })
             .collect();
@@ -1829,18 +3873,34 @@ impl<T: cio::CIO> Torrent<T> {
             transferred_down: self.downloaded,
             progress,
         });
+        updates.push(SResourceUpdate::TorrentActivity {
+            id: id.clone(),
+            kind: resource::ResourceKind::Torrent,
+            completed: self.activity.completed(),
+            last_active: self.activity.last_active(),
+            active_secs: self.activity.active_secs(),
+            seeding_secs: self.activity.seeding_secs(),
+        });
 
         for (pid, p) in &mut self.peers {
             if !p.active() {
                 continue;
             }
+            let id = util::peer_rpc_id(&self.info.hash, *pid as u64);
             let (rate_up, rate_down) = p.get_tx_rates();
+            let (transferred_up, transferred_down) = p.get_tx_bytes();
             updates.push(SResourceUpdate::Rate {
-                id: util::peer_rpc_id(&self.info.hash, *pid as u64),
+                id: id.clone(),
                 kind: resource::ResourceKind::Peer,
                 rate_up,
                 rate_down,
             });
+            updates.push(SResourceUpdate::PeerTransfer {
+                id,
+                kind: resource::ResourceKind::Peer,
+                transferred_up,
+                transferred_down,
+            });
         }
 
         for (idx, done) in self.files.flush() {
@@ -1861,18 +3921,50 @@ impl<T: cio::CIO> Torrent<T> {
     fn cleanup_peer(&mut self, peer: &mut Peer<T>) {
         trace!("Removing {:?}!", peer);
         self.choker.remove_peer(peer, &mut self.peers);
+        self.choker.unpin(peer.id());
         self.leechers.remove(&peer.id());
+        self.meta_probed.remove(&peer.id());
+        self.super_seed.remove_peer(peer.id());
         if self.info.complete() {
             self.picker.remove_peer(peer);
         }
     }
 
+    /// Stops the torrent after it downloaded too much corrupt data,
+    /// resuming it starts counting anew.
+    fn stop_corrupt(&mut self) {
+        let reason = "Too many hash failures".to_owned();
+        warn!(
+            "Torrent {} downloaded {} MiB of corrupt data, stopping",
+            self.rpc_id(),
+            self.corrupt / (1024 * 1024)
+        );
+        self.corrupt = 0;
+        self.status.error = Some(reason.clone());
+        self.announce_status();
+        self.notify(NotifyEvent::Error, Some(reason));
+    }
+
+    /// Takes the addresses of peers which sent data failing a hash check
+    pub fn take_bad_peers(&mut self) -> Vec<IpAddr> {
+        mem::replace(&mut self.bad_peers, Vec::new())
+    }
+
+    /// Disconnects every peer connected from `ip`
+    pub fn remove_peers_from(&mut self, ip: IpAddr) {
+        for (&pid, _) in self.peers.iter().filter(|&(_, p)| p.addr().ip() == ip) {
+            self.cio.remove_peer(pid);
+        }
+    }
+
     pub fn pause(&mut self) {
         debug!("Pausing torrent!");
         if !self.status.paused {
-            debug!("Sending stopped request to trk");
-            if let Some(req) = tracker::Request::stopped(self) {
-                self.cio.msg_trk(req);
+            if !self.status.meta_queued {
+                debug!("Sending stopped request to trk");
+                if let Some(req) = tracker::Request::stopped(self) {
+                    self.cio.msg_trk(req);
+                }
             }
             self.status.paused = true;
             self.announce_status();
@@ -1884,25 +3976,71 @@ impl<T: cio::CIO> Torrent<T> {
         if self.status.error.is_some() || self.status.paused {
             if self.status.error.is_some() {
                 self.status.error = None;
+                self.status.unregistered = false;
             }
             if self.status.paused {
-                debug!("Sending started request to trk");
-                if let Some(req) = tracker::Request::started(self) {
-                    self.cio.msg_trk(req);
+                if !self.status.meta_queued {
+                    debug!("Sending started request to trk");
+                    if let Some(req) = tracker::Request::started(self) {
+                        self.cio.msg_trk(req);
+                    }
                 }
                 self.status.paused = false;
             }
             self.request_all();
             self.announce_status();
             self.dht_announce();
+            self.lsd_announce();
+        }
+    }
+
+    /// Holds a magnet back from fetching metadata until a fetch slot
+    /// frees up, or lets it start once it has one.
+    pub fn set_meta_queued(&mut self, queued: bool) {
+        if self.status.meta_queued == queued {
+            return;
+        }
+        if queued {
+            if !self.status.stopped() {
+                if let Some(req) = tracker::Request::stopped(self) {
+                    self.cio.msg_trk(req);
+                }
+            }
+            self.status.meta_queued = true;
+        } else {
+            self.status.meta_queued = false;
+            self.announce_start();
+        }
+        self.announce_status();
+    }
+
+    /// Rehashes all pieces, at a limited rate if `low_impact`
+    /// Validates the torrent, unless its files are known to be
+    /// unchanged since it was last serialized and `force` isn't set.
+    pub fn recheck(&mut self, low_impact: bool, force: bool) {
+        if self.status.moving.is_some() {
+            warn!(
+                "Not validating torrent {} while it's being moved",
+                self.rpc_id()
+            );
+            return;
+        }
+        if !force && self.files_match() == Some(true) {
+            info!(
+                "Files of torrent {} are unchanged, skipping validation",
+                self.rpc_id()
+            );
+            return;
         }
+        self.validate(low_impact);
     }
 
-    pub fn validate(&mut self) {
+    pub fn validate(&mut self, low_impact: bool) {
         self.cio.msg_disk(disk::Request::validate(
             self.id,
             self.info.clone(),
             self.path.clone(),
+            low_impact,
         ));
         self.status.validating = Some(0.0);
         self.announce_status();
@@ -1912,6 +4050,84 @@ impl<T: cio::CIO> Torrent<T> {
         self.peers.len()
     }
 
+    /// Whether the torrent has room for more peers
+    pub fn wants_peers(&self) -> bool {
+        !self.status.stopped() && self.peers.len() < MAX_PEERS
+    }
+
+    /// Disconnects the peer with the lowest transfer rate, so its
+    /// connection can go to another torrent. Returns whether there
+    /// was one.
+    pub fn drop_slowest_peer(&mut self) -> bool {
+        let slowest = self
+            .peers
+            .iter()
+            .min_by_key(|&(_, p)| {
+                let (ul, dl) = p.get_tx_rates();
+                ul + dl
+            })
+            .map(|(id, _)| *id);
+        match slowest {
+            Some(id) => {
+                self.cio.remove_peer(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether newly connected peers are super seeded, which is
+    /// only done once every piece was downloaded
+    pub fn super_seeding(&self) -> bool {
+        self.super_seed.enabled() && self.info.complete() && self.pieces.complete()
+    }
+
+    fn set_super_seed(&mut self, enabled: bool) {
+        if enabled == self.super_seed.enabled() {
+            return;
+        }
+        // Peers connected beforehand already know all we have, but
+        // those super seeded must now be told of every piece
+        for pid in self.super_seed.set_enabled(enabled) {
+            if let Some(peer) = self.peers.get_mut(&pid) {
+                for i in 0..self.pieces.len() {
+                    if self.pieces.has_bit(i) && !peer.pieces().has_bit(i) {
+                        peer.send_message(Message::Have(i as u32));
+                    }
+                }
+            }
+        }
+        self.update_rpc_super_seed();
+    }
+
+    /// Reveals a single piece to a newly super seeded peer, or another
+    /// one to a peer which shared the last.
+    fn reveal_piece(&mut self, peer: &mut Peer<T>) {
+        if !self.super_seeding() {
+            return;
+        }
+        let revealed = self.super_seed.revealed();
+        if let Some(idx) = self.super_seed.reveal(peer.id(), peer.pieces()) {
+            peer.send_message(Message::Have(idx));
+        }
+        if self.super_seed.revealed() != revealed {
+            self.update_rpc_super_seed();
+        }
+    }
+
+    /// Reveals new pieces to the `shared` peers, `peer` being the
+    /// one whose message is handled and so not in the peer map.
+    fn reveal_shared(&mut self, shared: Vec<usize>, peer: &mut Peer<T>) {
+        for pid in shared {
+            if pid == peer.id() {
+                self.reveal_piece(peer);
+            } else if let Some(mut p) = self.peers.remove(&pid) {
+                self.reveal_piece(&mut p);
+                self.peers.insert(pid, p);
+            }
+        }
+    }
+
     pub fn peers(&self) -> &UHashMap<Peer<T>> {
         &self.peers
     }
@@ -1956,6 +4172,61 @@ impl<T: cio::CIO> Torrent<T> {
                 });
             }
         }
+        self.pex_sent += added.len() as u64;
+        self.update_rpc_pex();
+    }
+
+    fn update_rpc_pex(&mut self) {
+        let id = self.rpc_id();
+        self.cio
+            .msg_rpc(rpc::CtlMessage::Update(vec![SResourceUpdate::TorrentPex {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                pex_sent: self.pex_sent,
+                pex_received: self.pex_received,
+            }]));
+    }
+
+    /// Fsyncs the given files, by index
+    fn flush_files(&mut self, files: Vec<usize>) {
+        let files = files
+            .into_iter()
+            .map(|f| self.info.files[f].disk_path().to_path_buf())
+            .collect();
+        self.cio
+            .msg_disk(disk::Request::flush(self.path.clone(), files));
+    }
+
+    fn rpc_flush_policy(&self) -> resource::FlushPolicy {
+        match self.flush.schedule().policy {
+            FlushPolicy::Piece => resource::FlushPolicy::Piece,
+            FlushPolicy::Pieces => resource::FlushPolicy::Pieces,
+            FlushPolicy::Interval => resource::FlushPolicy::Interval,
+        }
+    }
+
+    fn update_rpc_super_seed(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentSuperSeed {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                super_seed: self.super_seed.enabled(),
+                pieces_revealed: self.super_seed.revealed(),
+            },
+        ]));
+    }
+
+    fn update_rpc_flush(&mut self) {
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentFlush {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                flush_policy: self.rpc_flush_policy(),
+                flush_every: self.flush.schedule().every,
+            },
+        ]));
     }
 
     pub fn rank_peers(&mut self) {
@@ -1974,7 +4245,7 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     fn request_all(&mut self) {
-        if self.status.stopped() || self.info_idx.is_some() {
+        if self.status.stopped() || self.meta.is_some() {
             return;
         }
         for pid in self.pids() {
@@ -1986,32 +4257,88 @@ impl<T: cio::CIO> Torrent<T> {
         self.peers.keys().cloned().collect()
     }
 
-    pub fn change_picker(&mut self, sequential: bool) {
+    pub fn set_strategy(&mut self, strategy: PieceStrategy) {
         debug!("Swapping pickers!");
-        let prev_seq = self.picker.is_sequential();
-        self.picker.change_picker(sequential);
+        let prev = self.picker.strategy();
+        self.picker.set_strategy(strategy);
         for peer in self.peers.values() {
             self.picker.add_peer(peer);
         }
         self.picker.set_priorities(&self.priorities, &self.info);
+        self.picker.set_affinity(self.piece_affinity);
         let id = self.rpc_id();
-        let sequential = self.picker.is_sequential();
         self.clear_piece_cache();
-        if prev_seq != sequential {
+        if prev != strategy {
             self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
                 SResourceUpdate::TorrentPicker {
                     id,
                     kind: resource::ResourceKind::Torrent,
-                    strategy: if sequential {
-                        resource::Strategy::Sequential
-                    } else {
-                        resource::Strategy::Rarest
-                    },
+                    strategy: self.rpc_strategy(),
                 },
             ]));
         }
     }
 
+    /// Sets whether the picker prefers pieces close to recent writes.
+    /// Sets how the torrent's files are allocated when it's added,
+    /// reserving space for its selected files if fully allocated.
+    pub fn set_allocation(&mut self, allocation: Allocation) {
+        self.allocation = allocation;
+        if self.info.complete() {
+            self.preallocate();
+        }
+    }
+
+    fn preallocate(&mut self) {
+        let files: Vec<_> = (0..self.info.files.len())
+            .filter(|&i| self.priorities[i] != 0)
+            .collect();
+        self.allocate_files(&files);
+    }
+
+    /// Reserves space for the files at the given indices if the torrent
+    /// is fully allocated. Skipped files are left sparse until selected.
+    fn allocate_files(&mut self, files: &[usize]) {
+        if self.allocation != Allocation::Full || files.is_empty() {
+            return;
+        }
+        let files = files
+            .iter()
+            .map(|&i| &self.info.files[i])
+            .map(|f| (f.disk_path().to_path_buf(), f.length))
+            .collect();
+        self.cio
+            .msg_disk(disk::Request::allocate(self.id, self.path.clone(), files));
+    }
+
+    pub fn set_piece_affinity(&mut self, affinity: bool) {
+        self.piece_affinity = affinity;
+        self.picker.set_affinity(affinity);
+    }
+
+    /// Stops requesting blocks while the buffer of quiesced writes
+    /// is full, resuming requests once it's drained.
+    pub fn set_buffer_full(&mut self, full: bool) {
+        if full == self.buffer_full {
+            return;
+        }
+        self.buffer_full = full;
+        if !full {
+            self.request_all();
+        }
+    }
+
+    /// Pauses the download with a read-only filesystem status, or
+    /// resumes it once the filesystem is writable again.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        if read_only {
+            self.status.error = Some("read-only filesystem".to_owned());
+            self.pause();
+        } else {
+            self.resume();
+        }
+    }
+
     fn clear_piece_cache(&mut self) {
         for peer in self.peers.values_mut() {
             peer.piece_cache().clear();
@@ -2037,7 +4364,7 @@ impl<T: cio::CIO> Drop for Torrent<T> {
             trace!("Removing peer {:?}", peer);
             self.leechers.remove(&id);
         }
-        if !self.status.paused {
+        if !self.status.paused && !self.status.meta_queued {
             if let Some(msg) = tracker::Request::stopped(self) {
                 self.cio.msg_trk(msg);
             }
@@ -2045,3 +4372,535 @@ impl<T: cio::CIO> Drop for Torrent<T> {
         self.send_rpc_removal();
     }
 }
+
+/// Whether our view of `peer` seems out of sync with its pieces: it has
+/// unchoked us with pieces we want, yet nothing was requested from it
+/// for `idle`.
+fn desynced<T: cio::CIO>(peer: &Peer<T>, pieces: &Bitfield, idle: Duration, now: Instant) -> bool {
+    match peer.request_idle(now) {
+        Some(d) => d >= idle && pieces.usable(peer.pieces()),
+        None => false,
+    }
+}
+
+/// Returns the peers which have every piece, and so are of no use once
+/// we're seeding. Peers whose pieces are still unknown are kept.
+/// Run length encodes the number of peers having each piece
+fn availability_runs(counts: &[u32]) -> Vec<rpc::proto::message::AvailabilityRun> {
+    let mut runs: Vec<rpc::proto::message::AvailabilityRun> = Vec::new();
+    for &count in counts {
+        match runs.last_mut() {
+            Some(run) if run.1 == count => {
+                run.0 += 1;
+                continue;
+            }
+            _ => {}
+        }
+        runs.push(rpc::proto::message::AvailabilityRun(1, count));
+    }
+    runs
+}
+
+/// Size and modification time of a file, None if it can't be read
+pub fn file_stat(path: &Path) -> Option<FileStat> {
+    let meta = fs::metadata(path).ok()?;
+    Some(FileStat {
+        len: meta.len(),
+        mtime_secs: meta.mtime(),
+        mtime_nanos: meta.mtime_nsec() as u32,
+    })
+}
+
+/// Bytes which fully allocating the files of a torrent being added to
+/// `dir` would take up, given the files selected by the add. Space
+/// already taken by existing files is discounted.
+pub fn allocation_needed(info: &Info, dir: &Path, files: Option<&[u32]>) -> u64 {
+    let skipped: Vec<_> = file_rules::priorities(&CONFIG.disk.file_priorities, &info.files)
+        .into_iter()
+        .filter(|&(_, p)| p == 0)
+        .map(|(i, _)| i)
+        .collect();
+    info.files
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| files.map(|s| s.contains(&(i as u32))).unwrap_or(true))
+        .filter(|&(i, _)| !skipped.contains(&i))
+        .map(|(_, f)| {
+            let existing = fs::metadata(dir.join(f.disk_path()))
+                .map(|m| m.len())
+                .unwrap_or(0);
+            f.length.saturating_sub(existing)
+        })
+        .sum()
+}
+
+/// Compares the current stats of files in `dir` against `stats`, None
+/// if the stats weren't recorded for these files.
+fn stats_match(dir: &Path, files: &[&Path], stats: &[Option<FileStat>]) -> Option<bool> {
+    if stats.is_empty() || stats.len() != files.len() {
+        return None;
+    }
+    let changed = files
+        .iter()
+        .zip(stats.iter())
+        .any(|(f, stat)| file_stat(&dir.join(f)) != *stat);
+    Some(!changed)
+}
+
+fn seeders<T: cio::CIO>(peers: &UHashMap<Peer<T>>) -> Vec<usize> {
+    peers
+        .iter()
+        .filter(|&(_, p)| p.pieces().complete())
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+/// Priority changes which add the files in `files`, or every file if
+/// there is no selection, to those being downloaded. Returns None if
+/// duplicate adds are rejected.
+fn merge_selection(
+    priorities: &[u8],
+    policy: DuplicateAdd,
+    files: Option<&[u32]>,
+) -> Option<Vec<(usize, u8)>> {
+    if policy == DuplicateAdd::Reject {
+        return None;
+    }
+    let changes = priorities
+        .iter()
+        .enumerate()
+        .filter(|&(i, p)| *p == 0 && files.map_or(true, |f| f.contains(&(i as u32))))
+        .map(|(i, _)| (i, 3))
+        .collect();
+    Some(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        announce_delay, availability_runs, corruption_exceeded, desynced, file_stat,
+        merge_selection, promote_tracker, reannounce_wait, seed_deadline, seed_limit_reached,
+        seeders, skip_dead_trackers, stats_match, tiered_trackers, unregistered_reason, Bitfield,
+        Message, Peer, Status, StatusState, Tracker, TrackerStatus,
+    };
+    use chrono::{self, Utc};
+    use config::DuplicateAdd;
+    use rand;
+    use rpc::resource;
+    use std::collections::HashSet;
+    use std::collections::VecDeque;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use std::{env, fs, process};
+    use tracker;
+    use url::Url;
+    use util::UHashMap;
+
+    #[test]
+    fn test_desync_recovery() {
+        let mut ours = Bitfield::new(4);
+        ours.set_bit(0);
+        let mut peer = Peer::test_from_pieces(0, Bitfield::new(4));
+        peer.handle_msg(&mut Message::Unchoke).unwrap();
+        let now = Instant::now();
+        let idle = Duration::from_secs(60);
+
+        // A HAVE which never made it into our view of the peer
+        peer.pieces_mut().set_bit(2);
+        assert!(!desynced(&peer, &ours, idle, now));
+        assert!(desynced(&peer, &ours, idle, now + idle));
+
+        // Re-deriving the view recounts its pieces and restarts the wait
+        peer.resync(now + idle);
+        assert_eq!(peer.piece_count(), 1);
+        assert!(!desynced(&peer, &ours, idle, now + idle));
+
+        // Peers which are requested from, or only have pieces we
+        // already have, are left alone
+        peer.request_piece(2, 0, 16_384);
+        assert!(!desynced(&peer, &ours, idle, now + idle * 3));
+        let mut peer = Peer::test_from_pieces(1, ours.clone());
+        peer.handle_msg(&mut Message::Unchoke).unwrap();
+        assert!(!desynced(&peer, &ours, idle, now + idle * 3));
+    }
+
+    #[test]
+    fn test_completion_keeps_peers() {
+        let mut full = Bitfield::new(4);
+        let mut partial = Bitfield::new(4);
+        for i in 0..4 {
+            full.set_bit(i);
+        }
+        partial.set_bit(1);
+
+        let mut peers = UHashMap::default();
+        peers.insert(0, Peer::test_from_pieces(0, full));
+        peers.insert(1, Peer::test_from_pieces(1, partial));
+        // Hasn't sent us a bitfield yet
+        peers.insert(2, Peer::test_from_pieces(2, Bitfield::new(4)));
+        assert_eq!(seeders(&peers), vec![0]);
+    }
+
+    fn new_tracker(url: &str) -> Tracker {
+        Tracker {
+            url: Arc::new(Url::parse(url).unwrap()),
+            status: TrackerStatus::Updating,
+            failures: 0,
+            dead: false,
+            last_announce: Utc::now(),
+            update: None,
+            headers: tracker::Headers::default(),
+            tier: 0,
+            tracker_id: None,
+            scrape: None,
+        }
+    }
+
+    fn hosts(trackers: &VecDeque<Tracker>) -> Vec<&str> {
+        trackers.iter().map(|t| t.url.host_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_tier_failover() {
+        let mut trackers = VecDeque::new();
+        for &(host, tier) in &[("a1", 0), ("a2", 0), ("b1", 1), ("b2", 1)] {
+            let mut trk = new_tracker(&format!("http://{}.example/announce", host));
+            trk.tier = tier;
+            trackers.push_back(trk);
+        }
+
+        // Both trackers of the first tier failed and were rotated away,
+        // so the second tier is tried, where b2 succeeds after b1 failed
+        for _ in 0..3 {
+            let front = trackers.pop_front().unwrap();
+            trackers.push_back(front);
+        }
+        assert_eq!(
+            hosts(&trackers),
+            vec!["b2.example", "a1.example", "a2.example", "b1.example"]
+        );
+        let next = Instant::now() + Duration::from_secs(1800);
+        trackers[0].update = Some(next);
+        trackers[1].dead = true;
+        let url = trackers[0].url.clone();
+        promote_tracker(&mut trackers, &url);
+
+        // The next announce starts from the first tier again, at b2's
+        // interval unless the tracker is dead, and b2 is preferred
+        // within its tier from now on
+        assert_eq!(
+            hosts(&trackers),
+            vec!["a1.example", "a2.example", "b2.example", "b1.example"]
+        );
+        assert_eq!(trackers[0].update, None);
+        assert_eq!(trackers[1].update, Some(next));
+        assert_eq!(trackers[3].update, None);
+
+        // A success in the first tier promotes the tracker within it
+        let url = trackers[1].url.clone();
+        promote_tracker(&mut trackers, &url);
+        assert_eq!(
+            hosts(&trackers),
+            vec!["a2.example", "a1.example", "b2.example", "b1.example"]
+        );
+    }
+
+    #[test]
+    fn test_tier_shuffle() {
+        let tier = |host: &str| -> Vec<Arc<Url>> {
+            (0..4)
+                .map(|i| Arc::new(Url::parse(&format!("http://{}{}/announce", host, i)).unwrap()))
+                .collect()
+        };
+        let url_list = vec![tier("a"), tier("b")];
+        let mut orders = Vec::new();
+        for _ in 0..50 {
+            let trackers = tiered_trackers(&url_list);
+            // Each tier stays whole and in place, only reordered within itself.
+            for (i, list) in url_list.iter().enumerate() {
+                let mut got: Vec<_> = trackers[i * 4..(i + 1) * 4]
+                    .iter()
+                    .map(|&(t, ref url)| {
+                        assert_eq!(t, i as u32);
+                        url.to_string()
+                    })
+                    .collect();
+                let mut want: Vec<_> = list.iter().map(|url| url.to_string()).collect();
+                got.sort();
+                want.sort();
+                assert_eq!(got, want);
+            }
+            orders.push(trackers);
+        }
+        assert!(orders.iter().any(|t| t != &orders[0]));
+    }
+
+    #[test]
+    fn test_dead_tracker_skipped() {
+        let cooldown = Duration::from_secs(3600);
+        let now = Instant::now();
+        let mut trackers = VecDeque::new();
+        trackers.push_back(new_tracker("http://a.example/announce"));
+        trackers.push_back(new_tracker("http://b.example/announce"));
+
+        for _ in 0..2 {
+            trackers[0].fail_with(3, cooldown, now);
+            assert!(!trackers[0].dead);
+        }
+        assert!(skip_dead_trackers(&mut trackers, now));
+        assert_eq!(trackers[0].url.host_str(), Some("a.example"));
+
+        // The third failure in a row kills it, so the next tracker is used
+        trackers[0].fail_with(3, cooldown, now);
+        assert!(trackers[0].dead);
+        assert_eq!(trackers[0].failures, 3);
+        assert!(skip_dead_trackers(&mut trackers, now));
+        assert_eq!(trackers[0].url.host_str(), Some("b.example"));
+
+        // A success resets the count
+        trackers[0].fail_with(3, cooldown, now);
+        trackers[0].succeed();
+        assert_eq!(trackers[0].failures, 0);
+        for _ in 0..3 {
+            trackers[0].fail_with(3, cooldown, now);
+        }
+        assert!(!skip_dead_trackers(&mut trackers, now));
+
+        // Dead trackers are retried after the cooldown
+        assert!(skip_dead_trackers(&mut trackers, now + cooldown));
+
+        // A limit of 0 never kills a tracker
+        let mut t = new_tracker("http://c.example/announce");
+        for _ in 0..100 {
+            t.fail_with(0, cooldown, now);
+        }
+        assert!(!t.dead);
+    }
+
+    #[test]
+    fn test_reannounce_min_interval() {
+        let last = Utc::now();
+        let ok = |min_interval| TrackerStatus::Ok {
+            seeders: 0,
+            leechers: 0,
+            interval: 1800,
+            min_interval,
+            warning: None,
+        };
+        let after = |secs| last + chrono::Duration::seconds(secs);
+
+        assert_eq!(reannounce_wait(&ok(Some(300)), last, after(100)), 200);
+        assert_eq!(reannounce_wait(&ok(Some(300)), last, after(300)), 0);
+        // Without a min interval a default is used
+        assert_eq!(reannounce_wait(&ok(None), last, after(30)), 30);
+        // Failed or pending announces may be retried immediately
+        let failed = TrackerStatus::Failure("unregistered".to_owned());
+        assert_eq!(reannounce_wait(&failed, last, last), 0);
+        assert_eq!(reannounce_wait(&TrackerStatus::Updating, last, last), 0);
+    }
+    #[test]
+    fn test_announce_jitter() {
+        let mut rng = rand::thread_rng();
+        // Announces of many torrents with the same interval are spread out
+        let delays: HashSet<_> = (0..100)
+            .map(|_| announce_delay(1800, None, 10, &mut rng))
+            .collect();
+        assert!(delays.len() > 50);
+        for d in &delays {
+            assert!(d.as_secs() >= 1800 && d.as_secs() <= 1980);
+        }
+
+        // The min interval is never undercut
+        for _ in 0..100 {
+            assert!(announce_delay(60, Some(300), 10, &mut rng) >= Duration::from_secs(300));
+        }
+        assert_eq!(
+            announce_delay(1800, None, 0, &mut rng),
+            Duration::from_secs(1800)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_add() {
+        let priorities = [3, 0, 0, 5];
+        assert_eq!(
+            merge_selection(&priorities, DuplicateAdd::Reject, Some(&[1])),
+            None
+        );
+        // Only deselected files are changed, nothing is deselected
+        assert_eq!(
+            merge_selection(&priorities, DuplicateAdd::Merge, Some(&[1, 3])),
+            Some(vec![(1, 3)])
+        );
+        assert_eq!(
+            merge_selection(&priorities, DuplicateAdd::Merge, Some(&[0])),
+            Some(vec![])
+        );
+        // Adding without a selection wants every file
+        assert_eq!(
+            merge_selection(&priorities, DuplicateAdd::Merge, None),
+            Some(vec![(1, 3), (2, 3)])
+        );
+    }
+
+    #[test]
+    fn test_stats_match() {
+        let dir = env::temp_dir().join(format!("synapse-stats-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"data").unwrap();
+        let files = [Path::new("a"), Path::new("b")];
+        let stats = vec![file_stat(&dir.join("a")), None];
+        assert!(stats[0].is_some());
+
+        assert_eq!(stats_match(&dir, &files, &stats), Some(true));
+        // Sessions without stats, or from before files were renamed
+        // or added, are unknown
+        assert_eq!(stats_match(&dir, &files, &[]), None);
+        assert_eq!(stats_match(&dir, &files, &stats[..1]), None);
+
+        // A size change or a newly created file is detected
+        fs::write(dir.join("a"), b"changed").unwrap();
+        assert_eq!(stats_match(&dir, &files, &stats), Some(false));
+        let stats = vec![file_stat(&dir.join("a")), None];
+        fs::write(dir.join("b"), b"new").unwrap();
+        assert_eq!(stats_match(&dir, &files, &stats), Some(false));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unregistered_stops_announces() {
+        let reasons = vec!["unregistered".to_owned(), "not authorized".to_owned()];
+        let mut trackers: VecDeque<_> = ["http://a.com/announce", "http://b.com/announce"]
+            .iter()
+            .map(|u| new_tracker(u))
+            .collect();
+        trackers[0].status = TrackerStatus::Failure("Unregistered torrent".to_owned());
+        // Waiting on the other tracker
+        assert_eq!(unregistered_reason(&trackers, &reasons), None);
+        // Transient failures don't count
+        trackers[1].status = TrackerStatus::Failure("Couldn't contact tracker".to_owned());
+        assert_eq!(unregistered_reason(&trackers, &reasons), None);
+        assert_eq!(unregistered_reason(&trackers, &[]), None);
+
+        trackers[1].status = TrackerStatus::Failure("Passkey not authorized".to_owned());
+        let reason = unregistered_reason(&trackers, &reasons);
+        assert_eq!(
+            reason.as_ref().map(|s| s.as_str()),
+            Some("http://a.com/announce: Unregistered torrent")
+        );
+
+        let status = Status {
+            paused: false,
+            validating: None,
+            moving: None,
+            error: reason,
+            state: StatusState::Incomplete,
+            meta_queued: false,
+            unregistered: true,
+            completing: None,
+        };
+        // try_update_tracker and update_tracker don't announce stopped torrents
+        assert!(status.stopped());
+        assert_eq!(status.as_rpc(0, 0), resource::Status::Unregistered);
+    }
+
+    #[test]
+    fn test_completed_announce_delayed() {
+        let now = Instant::now();
+        assert_eq!(seed_deadline(0, now), None);
+
+        let mut status = Status {
+            paused: false,
+            validating: None,
+            moving: None,
+            error: None,
+            state: StatusState::Complete,
+            meta_queued: false,
+            unregistered: false,
+            completing: seed_deadline(600, now),
+        };
+        assert_eq!(status.as_rpc(0, 0), resource::Status::Completing);
+        assert!(!status.seeding_due(now));
+        assert!(!status.seeding_due(now + Duration::from_secs(599)));
+        assert!(status.seeding_due(now + Duration::from_secs(600)));
+
+        // A paused torrent waits until resumed
+        status.paused = true;
+        assert!(!status.seeding_due(now + Duration::from_secs(700)));
+    }
+
+    #[test]
+    fn test_availability_runs() {
+        use rpc::proto::message::AvailabilityRun as Run;
+        assert!(availability_runs(&[]).is_empty());
+        assert_eq!(
+            availability_runs(&[0, 0, 3, 3, 3, 1, 0]),
+            vec![Run(2, 0), Run(3, 3), Run(1, 1), Run(1, 0)]
+        );
+    }
+
+    #[test]
+    fn test_seed_limits() {
+        const MIB: u64 = 1024 * 1024;
+        assert!(!seed_limit_reached(100 * MIB, MIB, MIB, 0., 3600, 0));
+
+        // The ratio is of the torrent's own transfers
+        assert!(!seed_limit_reached(19 * MIB, 10 * MIB, 100 * MIB, 2., 0, 0));
+        assert!(seed_limit_reached(20 * MIB, 10 * MIB, 100 * MIB, 2., 0, 0));
+        // or of its size if nothing was downloaded
+        assert!(!seed_limit_reached(20 * MIB, 0, 100 * MIB, 2., 0, 0));
+        assert!(seed_limit_reached(200 * MIB, 0, 100 * MIB, 2., 0, 0));
+
+        assert!(!seed_limit_reached(0, 10 * MIB, 10 * MIB, 2., 3599, 3600));
+        assert!(seed_limit_reached(0, 10 * MIB, 10 * MIB, 2., 3600, 3600));
+    }
+
+    #[test]
+    fn test_corruption_stops_torrent() {
+        const MIB: u64 = 1024 * 1024;
+        // Disabled limits never trigger
+        assert!(!corruption_exceeded(100 * MIB, 100 * MIB, 0, 0.));
+
+        assert!(!corruption_exceeded(10 * MIB, 100 * MIB, 10 * MIB, 0.));
+        assert!(corruption_exceeded(10 * MIB + 1, 100 * MIB, 10 * MIB, 0.));
+        assert!(!corruption_exceeded(50 * MIB, 100 * MIB, 0, 0.5));
+        assert!(corruption_exceeded(51 * MIB, 100 * MIB, 0, 0.5));
+        // Either limit suffices
+        assert!(corruption_exceeded(51 * MIB, 100 * MIB, 100 * MIB, 0.5));
+
+        // Crossing a limit stops the torrent with an error until resumed
+        let status = Status {
+            paused: false,
+            validating: None,
+            moving: None,
+            error: Some("Too many hash failures".to_owned()),
+            state: StatusState::Incomplete,
+            meta_queued: false,
+            unregistered: false,
+            completing: None,
+        };
+        assert!(status.stopped());
+        assert_eq!(status.as_rpc(0, 0), resource::Status::Error);
+    }
+
+    #[test]
+    fn test_moving_stops_torrent() {
+        let mut status = Status {
+            paused: false,
+            validating: None,
+            moving: Some(0.5),
+            error: None,
+            state: StatusState::Incomplete,
+            meta_queued: false,
+            unregistered: false,
+            completing: None,
+        };
+        assert!(status.stopped() && !status.should_dl());
+        assert_eq!(status.as_rpc(0, 0), resource::Status::Moving);
+
+        status.moving = None;
+        assert!(!status.stopped() && status.should_dl());
+    }
+}
@@ -1,23 +1,27 @@
 pub mod reader;
 pub mod writer;
 
-use std::net::SocketAddr;
-use std::net::TcpStream;
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::net::UnixStream;
 use std::{cmp, fmt, io, mem, time};
 
 pub use self::message::Message;
 use self::reader::{RRes, Reader};
 use self::writer::Writer;
 use bencode;
+use byteorder::{BigEndian, ByteOrder};
+use config::UnheldRequests;
 use control::cio;
 use rpc::{self, resource};
 use socket::Socket;
 use stat;
 use throttle::Throttle;
-use torrent::{Bitfield, Info, Torrent};
+use torrent::{pex, Bitfield, Info, Torrent};
 use tracker;
 use util;
-use {CONFIG, DHT_EXT, PEER_ID};
+use {CONFIG, DHT_EXT, FAST_EXT, PEER_ID};
 
 error_chain! {
     errors {
@@ -53,16 +57,43 @@ pub struct Peer<T: cio::CIO> {
     /// Maximum number of requests that can be queued
     /// at a time.
     max_queue: u16,
+    /// Maximum number of outstanding requests the peer
+    /// will accept, as advertised in its ext handshake.
+    reqq: u16,
     pieces_updated: bool,
+    /// When a block was last requested, or the peer last unchoked us
+    last_request: time::Instant,
+    /// Whether a bitfield may still be received, i.e. only messages
+    /// which may precede it were received since the handshake
+    bitfield_expected: bool,
+    got_bitfield: bool,
     tid: usize,
     downloaded: u32,
     uploaded: u32,
+    /// Bytes sent over the connection, including message overhead
+    bytes_sent: u64,
+    /// Bytes received over the connection, including message overhead
+    bytes_received: u64,
     stat: stat::EMA,
     addr: SocketAddr,
     t_hash: [u8; 20],
     cid: Option<[u8; 20]>,
     rsv: Option<[u8; 8]>,
     ext_ids: ExtIDs,
+    /// Pieces the peer may request while choked (BEP 6)
+    allowed_fast: Vec<u32>,
+    /// Requests received for pieces we don't have
+    unheld_requests: u32,
+    /// Whether our bitfield, or HAVE ALL/NONE in its place, was sent
+    pieces_sent: bool,
+    /// Whether the peer sent HAVE ALL before the piece count was known
+    have_all: bool,
+    pex_budget: pex::Budget,
+    /// How the peer was found, reported over RPC
+    source: resource::PeerSource,
+    transport: resource::Transport,
+    /// Whether the peer was pinned as a primary source over RPC
+    preferred: bool,
     pub rank: usize,
 }
 
@@ -126,16 +157,28 @@ impl PeerConn {
         Ok(PeerConn::new(Socket::new(ip)?))
     }
 
+    /// Creates an outgoing peer connected over uTP, relayed
+    /// through `conn`.
+    pub fn new_utp(conn: UnixStream, ip: &SocketAddr) -> io::Result<PeerConn> {
+        Ok(PeerConn::new(Socket::utp(conn, *ip)?))
+    }
+
     /// Creates a peer where we are acting as the server.
     /// Once the handshake is received, set_torrent should be called.
-    pub fn new_incoming(sock: TcpStream, reader: Reader) -> io::Result<PeerConn> {
-        let mut peer = PeerConn::new(Socket::from_stream(sock)?);
+    pub fn new_incoming(sock: Socket, reader: Reader) -> PeerConn {
+        let mut peer = PeerConn::new(sock);
         peer.reader = reader;
-        Ok(peer)
+        peer
     }
 
     pub fn writable(&mut self) -> io::Result<()> {
         self.last_action = time::Instant::now();
+        // Data of the encrypted handshake or encrypted messages
+        // may still be queued in the socket
+        match self.sock.flush() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            res => res?,
+        }
         self.writer.writable(&mut self.sock)
     }
 
@@ -178,11 +221,14 @@ impl Peer<cio::test::TCIO> {
             local_status: Status::new(),
             uploaded,
             downloaded,
+            bytes_sent: 0,
+            bytes_received: 0,
             stat: stat::EMA::new(),
             addr: "127.0.0.1:0".parse().unwrap(),
             cio: cio::test::TCIO::new(),
             queued,
             max_queue: queued,
+            reqq: MAX_QUEUE_CAP,
             pieces,
             piece_cache: Vec::new(),
             piece_count,
@@ -191,7 +237,18 @@ impl Peer<cio::test::TCIO> {
             rsv: None,
             cid: None,
             ext_ids: ExtIDs::new(),
+            allowed_fast: Vec::new(),
+            unheld_requests: 0,
+            pieces_sent: false,
+            have_all: false,
+            pex_budget: pex::Budget::new(time::Instant::now()),
+            source: resource::PeerSource::Manual,
+            transport: resource::Transport::Tcp,
+            preferred: false,
             pieces_updated: false,
+            last_request: time::Instant::now(),
+            bitfield_expected: true,
+            got_bitfield: false,
             rank: 0,
         }
     }
@@ -221,8 +278,10 @@ impl<T: cio::CIO> Peer<T> {
         t: &mut Torrent<T>,
         cid: Option<[u8; 20]>,
         rsv: Option<[u8; 8]>,
+        source: resource::PeerSource,
     ) -> cio::Result<Peer<T>> {
         let addr = conn.sock().addr();
+        let transport = conn.sock().transport();
         conn.set_throttle(t.get_throttle(0));
         let id = t.cio.add_peer(conn)?;
         let mut p = Peer {
@@ -232,10 +291,13 @@ impl<T: cio::CIO> Peer<T> {
             local_status: Status::new(),
             uploaded: 0,
             downloaded: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
             stat: stat::EMA::new(),
             cio: t.cio.new_handle(),
             queued: 0,
             max_queue: INIT_MAX_QUEUE,
+            reqq: CONFIG.peer.default_reqq,
             pieces: Bitfield::new(t.info.hashes.len() as u64),
             piece_cache: Vec::new(),
             piece_count: 0,
@@ -244,13 +306,37 @@ impl<T: cio::CIO> Peer<T> {
             rsv,
             cid,
             ext_ids: ExtIDs::new(),
+            allowed_fast: Vec::new(),
+            unheld_requests: 0,
+            pieces_sent: false,
+            have_all: false,
+            pex_budget: pex::Budget::new(time::Instant::now()),
+            source,
+            transport,
+            preferred: false,
             pieces_updated: false,
+            last_request: time::Instant::now(),
+            bitfield_expected: true,
+            got_bitfield: false,
             rank: t.num_peers(),
         };
-        p.send_message(Message::handshake(&*PEER_ID, &t.info.hash));
         if t.info.complete() {
-            p.send_message(Message::Bitfield(t.pieces.clone()));
+            p.set_allowed_fast(&t.info);
+        }
+        p.send_message(Message::handshake(
+            &*PEER_ID,
+            &t.info.hash,
+            CONFIG.peer.reserved(),
+        ));
+        if t.super_seeding() {
+            // Pieces are revealed one at a time instead
+            p.send_pieces(&Bitfield::new(t.pieces.len()));
+        } else if t.info.complete() {
+            p.send_pieces(&t.pieces);
+        } else if p.fast() {
+            p.send_pieces(&Bitfield::new(0));
         }
+        p.send_allowed_fast();
         p.send_rpc_info();
         Ok(p)
     }
@@ -258,9 +344,63 @@ impl<T: cio::CIO> Peer<T> {
     pub fn magnet_complete(&mut self, info: &Info) {
         if self.pieces.len() == 0 {
             self.pieces = Bitfield::new(u64::from(info.pieces()));
+            if self.have_all {
+                self.set_all();
+            }
         } else {
             self.pieces.cap(u64::from(info.pieces()));
         }
+        self.set_allowed_fast(info);
+        self.send_allowed_fast();
+    }
+
+    /// Whether both sides support the fast extension (BEP 6)
+    pub fn fast(&self) -> bool {
+        match self.rsv {
+            Some(rsv) => rsv[FAST_EXT.0] & FAST_EXT.1 != 0 && CONFIG.peer.advertises(FAST_EXT),
+            None => false,
+        }
+    }
+
+    /// Sends the pieces we have, using HAVE ALL or HAVE NONE in
+    /// place of the bitfield if the peer supports them.
+    fn send_pieces(&mut self, pieces: &Bitfield) {
+        let msg = if !self.fast() {
+            Message::Bitfield(pieces.clone())
+        } else if pieces.len() > 0 && pieces.complete() {
+            Message::HaveAll
+        } else if pieces.set() == 0 {
+            Message::HaveNone
+        } else {
+            Message::Bitfield(pieces.clone())
+        };
+        self.pieces_sent = true;
+        self.send_message(msg);
+    }
+
+    fn send_allowed_fast(&mut self) {
+        if !self.fast() {
+            return;
+        }
+        for i in 0..self.allowed_fast.len() {
+            let idx = self.allowed_fast[i];
+            self.send_message(Message::AllowedFast(idx));
+        }
+    }
+
+    fn set_all(&mut self) {
+        for i in 0..self.pieces.len() {
+            self.pieces.set_bit(i);
+        }
+        self.piece_count = self.pieces.len() as usize;
+    }
+
+    fn set_allowed_fast(&mut self, info: &Info) {
+        self.allowed_fast = match self.addr.ip() {
+            IpAddr::V4(ip) => allowed_fast(ip, &info.hash, info.pieces(), CONFIG.peer.allowed_fast),
+            // BEP 6 only defines the set for IPv4 peers
+            IpAddr::V6(_) => Vec::new(),
+        };
     }
 
     /// Returns whether or not the peer has received a handshake
@@ -268,6 +408,10 @@ impl<T: cio::CIO> Peer<T> {
         self.cid.is_some()
     }
 
+    pub fn pex_budget(&mut self) -> &mut pex::Budget {
+        &mut self.pex_budget
+    }
+
     pub fn exts(&self) -> &ExtIDs {
         &self.ext_ids
     }
@@ -276,6 +420,16 @@ impl<T: cio::CIO> Peer<T> {
         self.addr
     }
 
+    pub fn source(&self) -> resource::PeerSource {
+        self.source
+    }
+
+    /// Pins the peer as a primary source, which is always requested
+    /// from as deeply as it accepts, whatever its recent rate.
+    pub fn set_preferred(&mut self) {
+        self.preferred = true;
+    }
+
     pub fn pieces(&self) -> &Bitfield {
         &self.pieces
     }
@@ -327,38 +481,154 @@ impl<T: cio::CIO> Peer<T> {
         true
     }
 
+    /// Whether the peer is an established upload target, i.e. it's
+    /// interested and we've recently uploaded to it.
+    pub fn warm(&self) -> bool {
+        self.remote_status.interested && self.stat.avg_ul() > 0
+    }
+
+    /// Whether the peer is unchoking us
+    pub fn unchoking(&self) -> bool {
+        !self.remote_status.choked
+    }
+
+    /// How long the peer has been unchoking us without any requests
+    /// outstanding, or None if it's choking us or has requests queued.
+    pub fn request_idle(&self, now: time::Instant) -> Option<time::Duration> {
+        if self.remote_status.choked || self.queued > 0 {
+            return None;
+        }
+        if now < self.last_request {
+            return Some(time::Duration::from_secs(0));
+        }
+        Some(now.duration_since(self.last_request))
+    }
+
+    /// Re-derives the state kept from the peer's pieces, for when it's
+    /// suspected to be out of sync with them.
+    pub fn resync(&mut self, now: time::Instant) {
+        self.piece_cache.clear();
+        self.piece_count = self.pieces.iter().count();
+        self.last_request = now;
+        self.pieces_updated = true;
+    }
+
+    #[cfg(test)]
+    pub fn piece_count(&self) -> usize {
+        self.piece_count
+    }
+
     pub fn get_tx_rates(&self) -> (u64, u64) {
         (self.stat.avg_ul(), self.stat.avg_dl())
     }
 
+    /// Returns the total bytes sent to and received from the peer
+    pub fn get_tx_bytes(&self) -> (u64, u64) {
+        (self.bytes_sent, self.bytes_received)
+    }
+
+    /// Whether a request for a block of `index` may be served, i.e.
+    /// the peer isn't choked or the piece is allowed fast
+    pub fn can_serve(&self, index: u32) -> bool {
+        !self.local_status.choked || self.allowed_fast.contains(&index)
+    }
+
+    /// Tells the peer a request won't be served, if it supports being
+    /// told so rather than having to time it out.
+    pub fn reject_request(&mut self, index: u32, begin: u32, length: u32) {
+        if self.fast() {
+            self.send_message(Message::RejectRequest {
+                index,
+                begin,
+                length,
+            });
+        }
+    }
+
+    /// Answers a request for a piece we don't have according to
+    /// `policy`. Unless the request is `tolerated`, it's counted, and
+    /// true is returned when this adds up to a violation.
+    pub fn unheld_request(
+        &mut self,
+        index: u32,
+        begin: u32,
+        length: u32,
+        policy: UnheldRequests,
+        tolerated: bool,
+    ) -> bool {
+        if policy == UnheldRequests::Reject {
+            self.reject_request(index, begin, length);
+        }
+        if tolerated {
+            return false;
+        }
+        self.unheld_requests += 1;
+        let strikes = CONFIG.peer.unheld_request_strikes;
+        strikes != 0 && self.unheld_requests % strikes == 0
+    }
+
     pub fn queue_reqs(&mut self) -> Option<u16> {
-        if self.remote_status.choked || self.queued > self.max_queue.saturating_sub(16) {
+        // Never queue more than the peer is willing to accept
+        let depth = if self.preferred {
+            MAX_QUEUE_CAP
+        } else {
+            self.max_queue
+        };
+        let max_queue = cmp::min(depth, self.reqq);
+        if self.remote_status.choked || self.queued > max_queue.saturating_sub(16) {
             None
         } else {
-            let amnt = self.max_queue.saturating_sub(self.queued);
+            let amnt = max_queue.saturating_sub(self.queued);
             Some(amnt)
         }
     }
 
     pub fn handle_msg(&mut self, msg: &mut Message) -> Result<()> {
+        self.bytes_received += wire_len(msg);
+        match *msg {
+            Message::HaveAll
+            | Message::HaveNone
+            | Message::SuggestPiece(_)
+            | Message::AllowedFast(_)
+            | Message::RejectRequest { .. }
+                if !self.fast() =>
+            {
+                return Err(ErrorKind::ProtocolError("Fast extension wasn't negotiated!").into());
+            }
+            _ => {}
+        }
         match *msg {
             Message::Handshake { rsv, id, .. } => {
-                if (rsv[DHT_EXT.0] & DHT_EXT.1) != 0 {
-                    self.send_message(Message::Port(CONFIG.dht.port));
-                }
                 self.rsv = Some(rsv);
                 self.cid = Some(id);
+                // Only magnets have nothing sent in place of a bitfield,
+                // which must be the first message once fast is enabled
+                if self.fast() && !self.pieces_sent {
+                    self.send_pieces(&Bitfield::new(0));
+                }
+                if (rsv[DHT_EXT.0] & DHT_EXT.1) != 0 && CONFIG.peer.advertises(DHT_EXT) {
+                    self.send_message(Message::Port(CONFIG.dht.port));
+                }
+                self.send_allowed_fast();
                 self.send_rpc_info();
             }
             Message::Piece { length, .. } => {
                 self.stat.add_dl(u64::from(length));
                 self.downloaded += 1;
-                self.queued -= 1;
+                // May already have been given up on by a cancel
+                self.queued = self.queued.saturating_sub(1);
             }
-            Message::Request { .. } => {
-                if self.local_status.choked {
-                    info!("Got request while choked!");
-                    return Err(ErrorKind::ProtocolError("Peer requested while choked!").into());
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                if !self.can_serve(index) {
+                    if !self.fast() {
+                        info!("Got request while choked!");
+                        return Err(ErrorKind::ProtocolError("Peer requested while choked!").into());
+                    }
+                    self.reject_request(index, begin, length);
                 }
             }
             Message::Choke => {
@@ -366,6 +636,7 @@ impl<T: cio::CIO> Peer<T> {
             }
             Message::Unchoke => {
                 self.remote_status.choked = false;
+                self.last_request = time::Instant::now();
             }
             Message::Interested => {
                 self.remote_status.interested = true;
@@ -387,30 +658,73 @@ impl<T: cio::CIO> Peer<T> {
                 self.pieces_updated = true;
             }
             Message::Bitfield(ref mut pieces) => {
+                self.check_bitfield()?;
+                self.got_bitfield = true;
                 // Set the correct length, then swap the pieces
                 // Don't do this with magnets though
                 if self.pieces.len() > 0 {
                     pieces.cap(self.pieces.len());
                 }
                 mem::swap(pieces, &mut self.pieces);
+                // Keep the pieces of any HAVEs received before a late bitfield,
+                // which are left in the message for the torrent to account for
+                for idx in pieces.iter() {
+                    self.pieces.set_bit(idx);
+                }
                 self.piece_count = self.pieces.iter().count();
                 self.send_rpc_update();
             }
+            Message::HaveAll | Message::HaveNone => {
+                self.check_bitfield()?;
+                self.got_bitfield = true;
+                // Passed on to the torrent as the equivalent bitfield, holding
+                // the pieces of any preceding HAVEs like a late bitfield does
+                let early = self.pieces.clone();
+                if let Message::HaveAll = *msg {
+                    if self.pieces.len() > 0 {
+                        self.set_all();
+                    } else {
+                        self.have_all = true;
+                    }
+                }
+                *msg = Message::Bitfield(early);
+                self.send_rpc_update();
+            }
+            Message::SuggestPiece(idx) | Message::AllowedFast(idx) => {
+                if self.pieces.len() > 0 && u64::from(idx) >= self.pieces.len() {
+                    return Err(ErrorKind::ProtocolError("Invalid piece provided!").into());
+                }
+            }
+            Message::RejectRequest { .. } => {
+                self.queued = self.queued.saturating_sub(1);
+            }
             Message::KeepAlive => {
                 self.send_message(Message::KeepAlive);
             }
-            Message::Cancel { index, begin, .. } => {
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let mut cancelled = false;
                 self.cio.get_peer(self.id, |conn| {
                     conn.writer.write_queue.retain(|m| {
                         if let Message::Piece {
                             index: i, begin: b, ..
                         } = *m
                         {
-                            return !(i == index && b == begin);
+                            if i == index && b == begin {
+                                cancelled = true;
+                                return false;
+                            }
                         }
                         true
                     });
                 });
+                // With the fast extension every request gets an answer
+                if cancelled {
+                    self.reject_request(index, begin, length);
+                }
             }
             Message::Port(p) => {
                 let mut s = self.addr();
@@ -436,18 +750,55 @@ impl<T: cio::CIO> Peer<T> {
                         .remove("ut_pex")
                         .and_then(|v| v.into_int())
                         .map(|v| v as u8);
+                    if let Some(reqq) = d.remove("reqq").and_then(|v| v.into_int()) {
+                        self.reqq = cmp::min(cmp::max(reqq, 1), i64::from(u16::max_value())) as u16;
+                    }
                 }
             }
         }
+        match *msg {
+            Message::Handshake { .. }
+            | Message::Extension { .. }
+            | Message::Port(_)
+            | Message::KeepAlive => {}
+            _ => self.bitfield_expected = false,
+        }
+        Ok(())
+    }
+
+    /// Checks a bitfield, or HAVE ALL/NONE, may be received
+    fn check_bitfield(&self) -> Result<()> {
+        if self.got_bitfield {
+            return Err(ErrorKind::ProtocolError("Duplicate BITFIELD!").into());
+        }
+        if !self.bitfield_expected && CONFIG.peer.strict_bitfield {
+            return Err(ErrorKind::ProtocolError("BITFIELD received out of order!").into());
+        }
         Ok(())
     }
 
     pub fn request_piece(&mut self, idx: u32, offset: u32, len: u32) {
         let m = Message::request(idx, offset, len);
         self.queued += 1;
+        self.last_request = time::Instant::now();
         self.send_message(m);
     }
 
+    /// Cancels a request, e.g. once the block arrived from another peer
+    /// in endgame. Peers without the fast extension may drop it without
+    /// a word, so it stops counting against the queue right away. Fast
+    /// peers still answer it with either the piece or a rejection.
+    pub fn cancel_request(&mut self, idx: u32, offset: u32, len: u32) {
+        if !self.fast() {
+            self.queued = self.queued.saturating_sub(1);
+        }
+        self.send_message(Message::Cancel {
+            index: idx,
+            begin: offset,
+            length: len,
+        });
+    }
+
     pub fn choke(&mut self) {
         if !self.local_status.choked {
             self.local_status.choked = true;
@@ -469,7 +820,15 @@ impl<T: cio::CIO> Peer<T> {
         }
     }
 
+    pub fn uninterested(&mut self) {
+        if self.local_status.interested {
+            self.local_status.interested = false;
+            self.send_message(Message::Uninterested);
+        }
+    }
+
     pub fn send_message(&mut self, msg: Message) {
+        self.bytes_sent += wire_len(&msg);
         match msg {
             Message::Piece { length, .. } => {
                 self.uploaded += 1;
@@ -493,6 +852,9 @@ impl<T: cio::CIO> Peer<T> {
                         rate_up: 0,
                         rate_down: 0,
                         availability: self.piece_count as f32 / self.pieces.len() as f32,
+                        source: self.source,
+                        transport: self.transport,
+                        preferred: self.preferred,
                         ..Default::default()
                     },
                 )]));
@@ -523,6 +885,42 @@ impl<T: cio::CIO> Peer<T> {
     }
 }
 
+/// Generates the allowed fast set of `k` pieces for a peer, using the
+/// canonical algorithm of BEP 6 so that it is stable across connections.
+pub fn allowed_fast(ip: Ipv4Addr, hash: &[u8; 20], pieces: u32, k: usize) -> Vec<u32> {
+    let k = cmp::min(k, pieces as usize);
+    let mut set = Vec::with_capacity(k);
+    let mut seen = HashSet::with_capacity(k);
+    // Only the /24 of the peer is used, so peers behind a NAT share a set
+    let mut x = vec![0u8; 24];
+    BigEndian::write_u32(&mut x[..4], u32::from(ip) & 0xFFFF_FF00);
+    x[4..].copy_from_slice(hash);
+    while set.len() < k {
+        let h = util::sha1_hash(&x);
+        for i in 0..5 {
+            if set.len() >= k {
+                break;
+            }
+            let y = BigEndian::read_u32(&h[i * 4..i * 4 + 4]);
+            let idx = y % pieces;
+            if seen.insert(idx) {
+                set.push(idx);
+            }
+        }
+        x = h.to_vec();
+    }
+    set
+}
+
+/// Size of a message on the wire. Piece buffers may be larger than
+/// the block they hold, so the block length is used instead.
+fn wire_len(msg: &Message) -> u64 {
+    match *msg {
+        Message::Piece { length, .. } => 13 + u64::from(length),
+        _ => msg.len() as u64,
+    }
+}
+
 impl<T: cio::CIO> Drop for Peer<T> {
     fn drop(&mut self) {
         self.send_rpc_removal();
@@ -551,9 +949,37 @@ impl ExtIDs {
 #[cfg(test)]
 mod tests {
     use super::Peer;
+    use bencode::BEncode;
     use buffers::Buffer;
+    use config::UnheldRequests;
     use control::cio::{test, CIO};
-    use torrent::Message;
+    use protocol::SUPPORTED_RSV;
+    use std::collections::BTreeMap;
+    use torrent::{Bitfield, Info, Message};
+    use CONFIG;
+
+    #[test]
+    fn test_bitfield_order() {
+        let mut bf = Bitfield::new(16);
+        bf.set_bit(5);
+
+        // HAVEs before a bitfield are applied, the late bitfield isn't
+        let mut peer = Peer::test_from_pieces(0, Bitfield::new(16));
+        peer.handle_msg(&mut Message::Have(3)).unwrap();
+        assert!(peer.pieces().has_bit(3));
+        assert!(peer.handle_msg(&mut Message::Bitfield(bf.clone())).is_err());
+        assert!(peer.pieces().has_bit(3));
+        assert!(!peer.pieces().has_bit(5));
+
+        // Extension messages may precede the bitfield
+        let mut peer = Peer::test_from_pieces(1, Bitfield::new(16));
+        peer.handle_msg(&mut Message::KeepAlive).unwrap();
+        peer.handle_msg(&mut Message::Bitfield(bf.clone())).unwrap();
+        peer.handle_msg(&mut Message::Have(3)).unwrap();
+        assert!(peer.pieces().has_bit(3));
+        assert!(peer.pieces().has_bit(5));
+        assert!(peer.handle_msg(&mut Message::Bitfield(bf)).is_err());
+    }
 
     #[test]
     fn test_cancel() {
@@ -595,4 +1021,254 @@ mod tests {
         assert_eq!(wq[0], p1);
         assert_eq!(wq[1], p3);
     }
+
+    #[test]
+    fn test_reqq_limit() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.max_queue = 100;
+        peer.handle_msg(&mut Message::Unchoke).unwrap();
+
+        let mut d = BTreeMap::new();
+        d.insert("m".to_owned(), BEncode::Dict(BTreeMap::new()));
+        d.insert("reqq".to_owned(), BEncode::Int(20));
+        let mut ext = Message::Extension {
+            id: 0,
+            payload: BEncode::Dict(d).encode_to_buf(),
+        };
+        peer.handle_msg(&mut ext).unwrap();
+
+        for _ in 0..10 {
+            if let Some(amnt) = peer.queue_reqs() {
+                for _ in 0..amnt {
+                    peer.request_piece(0, 0, 16_384);
+                }
+            }
+            assert!(peer.queued <= 20);
+            peer.handle_msg(&mut Message::Piece {
+                index: 0,
+                begin: 0,
+                data: Buffer::get().unwrap(),
+                length: 16_384,
+            })
+            .unwrap();
+        }
+        assert_eq!(peer.queued, 10);
+    }
+
+    #[test]
+    fn test_preferred_share() {
+        let tcio = test::TCIO::new();
+        let mut peers = Vec::new();
+        for i in 0..4 {
+            let mut peer = Peer::test_with_tcio(tcio.new_handle());
+            peer.max_queue = 100;
+            peer.handle_msg(&mut Message::Unchoke).unwrap();
+            if i == 0 {
+                peer.set_preferred();
+            }
+            peers.push(peer);
+        }
+
+        // With every peer equally fast, the preferred one still gets
+        // the bulk of the requests
+        let mut reqs = [0u32; 4];
+        for _ in 0..5 {
+            for (i, peer) in peers.iter_mut().enumerate() {
+                if let Some(amnt) = peer.queue_reqs() {
+                    for _ in 0..amnt {
+                        peer.request_piece(0, 0, 16_384);
+                    }
+                    reqs[i] += u32::from(amnt);
+                }
+                for _ in 0..10 {
+                    peer.handle_msg(&mut Message::Piece {
+                        index: 0,
+                        begin: 0,
+                        data: Buffer::get().unwrap(),
+                        length: 16_384,
+                    })
+                    .unwrap();
+                }
+            }
+        }
+        assert!(reqs[1..].iter().all(|&r| reqs[0] >= 4 * r));
+    }
+
+    #[test]
+    fn test_tx_bytes() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.send_message(Message::Interested);
+        peer.send_message(Message::Have(3));
+        peer.request_piece(0, 0, 16_384);
+        assert_eq!(peer.get_tx_bytes(), (5 + 9 + 17, 0));
+
+        peer.handle_msg(&mut Message::Unchoke).unwrap();
+        peer.handle_msg(&mut Message::KeepAlive).unwrap();
+        peer.handle_msg(&mut Message::Piece {
+            index: 0,
+            begin: 0,
+            data: Buffer::get().unwrap(),
+            length: 16_384,
+        })
+        .unwrap();
+        // Keep alives are answered in kind
+        assert_eq!(peer.get_tx_bytes(), (5 + 9 + 17 + 4, 5 + 4 + 13 + 16_384));
+    }
+
+    #[test]
+    fn test_fast_ext() {
+        // Fast messages require the extension to be negotiated
+        let mut peer = Peer::test_from_pieces(0, Bitfield::new(16));
+        assert!(peer.handle_msg(&mut Message::HaveAll).is_err());
+
+        let mut peer = Peer::test_from_pieces(0, Bitfield::new(16));
+        peer.rsv = Some(SUPPORTED_RSV);
+        let mut msg = Message::HaveAll;
+        peer.handle_msg(&mut msg).unwrap();
+        assert!(peer.pieces().complete());
+        // The torrent sees the equivalent of a bitfield
+        match msg {
+            Message::Bitfield(ref early) => assert_eq!(early.iter().count(), 0),
+            _ => panic!("HAVE ALL wasn't passed on as a bitfield"),
+        }
+        assert!(peer.handle_msg(&mut Message::HaveNone).is_err());
+
+        // Like a bitfield, HAVE ALL must be the first message (BEP 6)
+        let mut peer = Peer::test_from_pieces(0, Bitfield::new(16));
+        peer.rsv = Some(SUPPORTED_RSV);
+        peer.handle_msg(&mut Message::Have(3)).unwrap();
+        assert!(peer.handle_msg(&mut Message::HaveAll).is_err());
+
+        // Magnets apply HAVE ALL once the piece count is known
+        let mut peer = Peer::test_from_pieces(0, Bitfield::new(0));
+        peer.rsv = Some(SUPPORTED_RSV);
+        peer.handle_msg(&mut Message::HaveAll).unwrap();
+        peer.magnet_complete(&Info::with_pieces(4));
+        assert!(peer.pieces().complete());
+        assert_eq!(peer.pieces().len(), 4);
+    }
+
+    #[test]
+    fn test_reject_request() {
+        let mut tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.rsv = Some(SUPPORTED_RSV);
+        peer.handle_msg(&mut Message::Unchoke).unwrap();
+        peer.request_piece(0, 0, 16_384);
+        peer.request_piece(0, 16_384, 16_384);
+        let mut reject = Message::RejectRequest {
+            index: 0,
+            begin: 0,
+            length: 16_384,
+        };
+        peer.handle_msg(&mut reject).unwrap();
+        assert_eq!(peer.queued, 1);
+
+        // Requests while choked are rejected rather than fatal
+        let mut req = Message::Request {
+            index: 1,
+            begin: 0,
+            length: 16_384,
+        };
+        peer.handle_msg(&mut req).unwrap();
+        assert!(!peer.can_serve(1));
+        let wq = tcio
+            .get_peer(peer.id, |p| p.writer.write_queue.clone())
+            .unwrap();
+        assert_eq!(
+            wq.back(),
+            Some(&Message::RejectRequest {
+                index: 1,
+                begin: 0,
+                length: 16_384,
+            })
+        );
+    }
+
+    #[test]
+    fn test_unheld_request() {
+        let tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.rsv = Some(SUPPORTED_RSV);
+        let reject = Message::RejectRequest {
+            index: 2,
+            begin: 0,
+            length: 16_384,
+        };
+
+        // Ignored requests go unanswered, but still count
+        assert!(!peer.unheld_request(2, 0, 16_384, UnheldRequests::Ignore, false));
+        assert!(tcio.data().peer_msgs.is_empty());
+        assert!(!peer.unheld_request(2, 0, 16_384, UnheldRequests::Reject, false));
+        assert_eq!(tcio.data().peer_msgs, vec![(peer.id, reject)]);
+
+        // Requests racing with us losing the piece aren't counted
+        let strikes = CONFIG.peer.unheld_request_strikes;
+        for _ in 0..strikes {
+            assert!(!peer.unheld_request(2, 0, 16_384, UnheldRequests::Reject, true));
+        }
+        assert_eq!(peer.unheld_requests, 2);
+        for _ in 2..strikes - 1 {
+            assert!(!peer.unheld_request(2, 0, 16_384, UnheldRequests::Ignore, false));
+        }
+        assert!(peer.unheld_request(2, 0, 16_384, UnheldRequests::Ignore, false));
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let mut tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.handle_msg(&mut Message::Unchoke).unwrap();
+        peer.request_piece(0, 0, 16_384);
+        peer.request_piece(0, 16_384, 16_384);
+        peer.cancel_request(0, 0, 16_384);
+        assert_eq!(peer.queued, 1);
+        let wq = tcio
+            .get_peer(peer.id, |p| p.writer.write_queue.clone())
+            .unwrap();
+        assert_eq!(
+            wq.back(),
+            Some(&Message::Cancel {
+                index: 0,
+                begin: 0,
+                length: 16_384,
+            })
+        );
+
+        // The cancelled piece may still arrive
+        let mut piece = Message::Piece {
+            index: 0,
+            begin: 0,
+            length: 16_384,
+            data: Buffer::get().unwrap(),
+        };
+        peer.handle_msg(&mut piece).unwrap();
+        peer.handle_msg(&mut piece).unwrap();
+        assert_eq!(peer.queued, 0);
+
+        // Fast peers answer the cancel, which is counted then
+        peer.rsv = Some(SUPPORTED_RSV);
+        peer.request_piece(1, 0, 16_384);
+        peer.cancel_request(1, 0, 16_384);
+        assert_eq!(peer.queued, 1);
+    }
+
+    #[test]
+    fn test_allowed_fast() {
+        // Example from BEP 6
+        let ip = "80.4.4.200".parse().unwrap();
+        let hash = [0xaa; 20];
+        assert_eq!(
+            super::allowed_fast(ip, &hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            super::allowed_fast(ip, &hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        // The set can't be larger than the torrent
+        assert_eq!(super::allowed_fast(ip, &hash, 3, 10).len(), 3);
+    }
 }
@@ -7,9 +7,15 @@ use buffers::Buffer;
 use torrent::peer::Message;
 use torrent::Bitfield;
 use util::{aread, io_err_val, IOR};
+use CONFIG;
+
+/// Largest block which may be requested or sent in a piece message
+pub const MAX_BLOCK_LEN: u32 = 16_384;
 
 pub struct Reader {
     blocks_read: usize,
+    /// Largest bitfield or extension payload accepted
+    max_len: u32,
 
     state: State,
     prefix: [u8; 17],
@@ -53,6 +59,7 @@ impl Reader {
     pub fn new() -> Reader {
         Reader {
             blocks_read: 0,
+            max_len: CONFIG.peer.max_message_len,
             prefix: [0u8; 17],
             idx: 0,
             state: State::Handshake { data: [0u8; 68] },
@@ -111,32 +118,32 @@ impl Reader {
                 State::ID => match aread(&mut self.prefix[self.idx..len], conn) {
                     IOR::Complete => {
                         self.idx = 5;
+                        let mlen = BigEndian::read_u32(&self.prefix[0..4]);
+                        if let Err(e) = check_len(self.prefix[4], mlen, self.max_len) {
+                            return RRes::Err(e);
+                        }
                         match self.prefix[4] {
-                            0..=3 => {
-                                let id = self.prefix[4];
-                                let msg = if id == 0 {
-                                    Message::Choke
-                                } else if id == 1 {
-                                    Message::Unchoke
-                                } else if id == 2 {
-                                    Message::Interested
-                                } else {
-                                    Message::Uninterested
+                            0..=3 | 0x0E | 0x0F => {
+                                let msg = match self.prefix[4] {
+                                    0 => Message::Choke,
+                                    1 => Message::Unchoke,
+                                    2 => Message::Interested,
+                                    3 => Message::Uninterested,
+                                    0x0E => Message::HaveAll,
+                                    _ => Message::HaveNone,
                                 };
                                 return RRes::Success(msg);
                             }
-                            4 => self.state = State::Have,
+                            4 | 0x0D | 0x11 => self.state = State::Have,
                             5 => {
-                                let mlen = BigEndian::read_u32(&self.prefix[0..4]);
                                 self.idx = 0;
-                                // TODO: validate size
                                 self.state = State::Bitfield {
                                     data: vec![0u8; mlen as usize - 1],
                                 };
                             }
                             6 => self.state = State::Request,
                             7 => self.state = State::PiecePrefix,
-                            8 => self.state = State::Cancel,
+                            8 | 0x10 => self.state = State::Cancel,
                             9 => self.state = State::Port,
                             20 => self.state = State::ExtensionID,
                             _ => return RRes::Err(io_err_val("Invalid ID used!")),
@@ -149,8 +156,12 @@ impl Reader {
                 },
                 State::Have => match aread(&mut self.prefix[self.idx..len], conn) {
                     IOR::Complete => {
-                        let have = BigEndian::read_u32(&self.prefix[5..9]);
-                        return RRes::Success(Message::Have(have));
+                        let piece = BigEndian::read_u32(&self.prefix[5..9]);
+                        return RRes::Success(match self.prefix[4] {
+                            4 => Message::Have(piece),
+                            0x0D => Message::SuggestPiece(piece),
+                            _ => Message::AllowedFast(piece),
+                        });
                     }
                     IOR::Incomplete(a) => self.idx += a,
                     IOR::Blocked => return RRes::Blocked,
@@ -173,6 +184,9 @@ impl Reader {
                         let index = BigEndian::read_u32(&self.prefix[5..9]);
                         let begin = BigEndian::read_u32(&self.prefix[9..13]);
                         let length = BigEndian::read_u32(&self.prefix[13..17]);
+                        if length > MAX_BLOCK_LEN {
+                            return RRes::Err(violation("Requested block is too large"));
+                        }
                         return RRes::Success(Message::Request {
                             index,
                             begin,
@@ -188,7 +202,6 @@ impl Reader {
                     IOR::Complete => {
                         let plen = BigEndian::read_u32(&self.prefix[0..4]) - 9;
                         self.idx = 0;
-                        // TODO: validate size
                         self.state = State::Piece {
                             data: Buffer::get(),
                             len: plen,
@@ -233,6 +246,13 @@ impl Reader {
                         let index = BigEndian::read_u32(&self.prefix[5..9]);
                         let begin = BigEndian::read_u32(&self.prefix[9..13]);
                         let length = BigEndian::read_u32(&self.prefix[13..17]);
+                        if self.prefix[4] == 0x10 {
+                            return RRes::Success(Message::RejectRequest {
+                                index,
+                                begin,
+                                length,
+                            });
+                        }
                         return RRes::Success(Message::Cancel {
                             index,
                             begin,
@@ -259,7 +279,6 @@ impl Reader {
                         let id = self.prefix[5];
                         self.idx = 0;
                         let plen = BigEndian::read_u32(&self.prefix[0..4]) - 2;
-                        // TODO: validate size
                         let payload = vec![0u8; plen as usize];
                         self.state = State::Extension { id, payload };
                     }
@@ -286,6 +305,36 @@ impl Reader {
     }
 }
 
+/// Checks the length prefix of a message with the given ID, so
+/// invalid or oversized messages are rejected before their payload
+/// is read or allocated.
+fn check_len(id: u8, mlen: u32, max_len: u32) -> io::Result<()> {
+    let valid = match id {
+        0..=3 | 0x0E | 0x0F => mlen == 1,
+        4 | 0x0D | 0x11 => mlen == 5,
+        5 => mlen - 1 <= max_len,
+        6 | 8 | 0x10 => mlen == 13,
+        7 => mlen > 9 && mlen - 9 <= MAX_BLOCK_LEN,
+        9 => mlen == 3,
+        20 => mlen >= 2 && mlen - 2 <= max_len,
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(violation("Invalid message length"))
+    }
+}
+
+fn violation(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Whether a read error was caused by the peer violating the protocol
+pub fn is_violation(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::InvalidData
+}
+
 impl State {
     fn len(&self) -> usize {
         match *self {
@@ -471,6 +520,52 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_oversized_rejected() {
+        // Only the message prefixes are available, so oversized messages
+        // must be rejected before their payload is allocated or read
+        let piece = vec![0u8, 0, 0x40, 0x0A, 7, 0, 0, 0, 1, 0, 0, 0, 1];
+        let bitfield = vec![0xffu8, 0xff, 0xff, 0xff, 5];
+        let extension = vec![0x7fu8, 0xff, 0xff, 0xff, 20, 1];
+        let have = vec![0u8, 0, 0, 6, 4, 0, 0, 0, 1, 0];
+        for v in &[piece, bitfield, extension, have] {
+            let mut r = Reader::new();
+            r.state = State::Len;
+            match r.readable(&mut Cursor::new(v)) {
+                RRes::Err(ref e) if is_violation(e) => {}
+                res => panic!("Oversized message accepted: {:?}", res),
+            }
+        }
+
+        // A truncated piece message can't underflow its length
+        let mut r = Reader::new();
+        r.state = State::Len;
+        let v = vec![0u8, 0, 0, 2, 7];
+        match r.readable(&mut Cursor::new(&v)) {
+            RRes::Err(ref e) if is_violation(e) => {}
+            res => panic!("Truncated piece accepted: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_oversized_request_rejected() {
+        let mut r = Reader::new();
+        r.state = State::Len;
+        let v = vec![0u8, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x40, 0x01];
+        match r.readable(&mut Cursor::new(&v)) {
+            RRes::Err(ref e) if is_violation(e) => {}
+            res => panic!("Oversized request accepted: {:?}", res),
+        }
+
+        let mut r = Reader::new();
+        r.state = State::Len;
+        let v = vec![0u8, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x40, 0x00];
+        match r.readable(&mut Cursor::new(&v)) {
+            RRes::Success(Message::Request { length, .. }) => assert_eq!(length, 16_384),
+            res => panic!("Failed to read request: {:?}", res),
+        }
+    }
+
     #[test]
     fn test_read_cancel() {
         let mut r = Reader::new();
@@ -494,6 +589,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_read_fast() {
+        test_message(vec![0u8, 0, 0, 1, 0x0E], Message::HaveAll);
+        test_message(vec![0u8, 0, 0, 1, 0x0F], Message::HaveNone);
+        test_message(
+            vec![0u8, 0, 0, 5, 0x0D, 0, 0, 0, 3],
+            Message::SuggestPiece(3),
+        );
+        test_message(
+            vec![0u8, 0, 0, 5, 0x11, 0, 0, 1, 0],
+            Message::AllowedFast(256),
+        );
+        test_message(
+            vec![
+                0u8, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0,
+            ],
+            Message::RejectRequest {
+                index: 1,
+                begin: 16_384,
+                length: 16_384,
+            },
+        );
+
+        let mut r = Reader::new();
+        r.state = State::Len;
+        let v = vec![0u8, 0, 0, 2, 0x0E, 0];
+        match r.readable(&mut Cursor::new(&v)) {
+            RRes::Err(ref e) if is_violation(e) => {}
+            res => panic!("Invalid HAVE ALL accepted: {:?}", res),
+        }
+    }
+
     #[test]
     fn test_read_port() {
         let mut r = Reader::new();
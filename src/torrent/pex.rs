@@ -0,0 +1,103 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Window over which the peers accepted from a connection's PEX
+/// messages are limited
+const BUDGET_WINDOW_SECS: u64 = 60 * 60;
+
+/// Computes the peers to add and drop in the next PEX message given the
+/// currently connected peers, updating `sent` to what the swarm will have
+/// been told. At most `max` peers are added and dropped per message, the
+/// rest are left for later messages.
+pub fn diff(
+    sent: &mut HashSet<SocketAddr>,
+    current: &HashSet<SocketAddr>,
+    max: usize,
+) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    let added: Vec<_> = current.difference(sent).take(max).cloned().collect();
+    let removed: Vec<_> = sent.difference(current).take(max).cloned().collect();
+    for addr in &added {
+        sent.insert(*addr);
+    }
+    for addr in &removed {
+        sent.remove(addr);
+    }
+    (added, removed)
+}
+
+/// Limits the peers accepted from a single connection's PEX messages,
+/// so a malicious peer can't flood us with bogus addresses.
+pub struct Budget {
+    accepted: usize,
+    window_start: Instant,
+}
+
+impl Budget {
+    /// Creates a budget whose first window starts at `now`
+    pub fn new(now: Instant) -> Budget {
+        Budget {
+            accepted: 0,
+            window_start: now,
+        }
+    }
+
+    /// Returns how many of the `offered` peers of a message should be
+    /// used, given at most `max` per message and `limit` per hour.
+    pub fn take(&mut self, offered: usize, max: usize, limit: usize, now: Instant) -> usize {
+        if now.duration_since(self.window_start) >= Duration::from_secs(BUDGET_WINDOW_SECS) {
+            self.accepted = 0;
+            self.window_start = now;
+        }
+        let n = cmp::min(cmp::min(offered, max), limit.saturating_sub(self.accepted));
+        self.accepted += n;
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, Budget, BUDGET_WINDOW_SECS};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn addrs(range: ::std::ops::Range<u16>) -> HashSet<SocketAddr> {
+        range
+            .map(|p| SocketAddr::from(([10, 0, 0, 1], p)))
+            .collect()
+    }
+
+    #[test]
+    fn test_message_size() {
+        let mut sent = HashSet::new();
+        let (a, r) = diff(&mut sent, &addrs(0..120), 50);
+        assert_eq!((a.len(), r.len()), (50, 0));
+        let (a, _) = diff(&mut sent, &addrs(0..120), 50);
+        assert_eq!(a.len(), 50);
+        let (a, _) = diff(&mut sent, &addrs(0..120), 50);
+        assert_eq!(a.len(), 20);
+
+        // Every peer disconnected
+        let (a, r) = diff(&mut sent, &HashSet::new(), 50);
+        assert_eq!((a.len(), r.len()), (0, 50));
+        assert_eq!(sent.len(), 70);
+    }
+
+    #[test]
+    fn test_flood_limited() {
+        let now = Instant::now();
+        let mut b = Budget::new(now);
+        // Oversized messages are truncated
+        assert_eq!(b.take(500, 50, 120, now), 50);
+        assert_eq!(b.take(50, 50, 120, now), 50);
+        assert_eq!(b.take(50, 50, 120, now), 20);
+        assert_eq!(b.take(50, 50, 120, now), 0);
+        // The budget is restored after a while
+        assert_eq!(
+            b.take(50, 50, 120, now + Duration::from_secs(BUDGET_WINDOW_SECS)),
+            50
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time;
+use std::{cmp, time};
 
 use control::cio;
 use torrent::{Bitfield, Info, Peer};
@@ -33,6 +33,21 @@ pub struct Picker {
     picker: PickerKind,
     /// Piece priorities
     priorities: Vec<u8>,
+    /// Pieces which picking is restricted to, if any
+    target: Option<FHashSet<u32>>,
+    /// Pieces needed by a deadline, e.g. by a stream, which are picked
+    /// ahead of any others, earliest first
+    deadlines: Vec<(time::Instant, u32)>,
+    /// Whether equally rare pieces close to the last written
+    /// block are preferred, reducing seeks on HDDs
+    affinity: bool,
+    last_written: Option<u32>,
+    /// Connected peers unchoking us needed for endgame
+    endgame_min: usize,
+    unchoking: usize,
+    /// Whether blocks were requested from several peers at once
+    /// since the last time a fresh piece was available
+    endgame: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -41,6 +56,28 @@ pub struct Block {
     pub offset: u32,
 }
 
+/// Snapshot of a block which has been requested but not received
+#[derive(Clone, Debug, PartialEq)]
+pub struct InFlight {
+    pub block: Block,
+    /// Peers the block is currently requested from
+    pub peers: Vec<usize>,
+    /// Time since the block was last requested
+    pub pending: time::Duration,
+    /// Whether the request timed out, making the block
+    /// available to be requested from another peer
+    pub stalled: bool,
+}
+
+/// Order in which pieces are picked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PieceStrategy {
+    /// Pieces fewest peers have first, keeping them available
+    Rarest,
+    /// Pieces in order, e.g. for streaming
+    Sequential,
+}
+
 /// Pickers act solely as piece picking algorithm.
 /// They will select the optimal next piece for a peer,
 /// and can be told when a piece is complete(or invalid).
@@ -72,6 +109,8 @@ const MAX_DUP_REQS: usize = 3;
 const MAX_PC_SIZE: usize = 50;
 const MAX_DL_REREQ: usize = 150;
 const REQ_TIMEOUT: u64 = 10;
+/// Highest file priority, 0 skipping a file and 3 being the default
+pub const MAX_PRIORITY: u8 = 5;
 
 impl Picker {
     /// Creates a new picker, which will select over
@@ -108,24 +147,51 @@ impl Picker {
             unpicked: pieces.clone(),
             stalled: FHashSet::default(),
             priorities: vec![3; info.pieces() as usize],
+            target: None,
+            deadlines: Vec::new(),
+            affinity: false,
+            last_written: None,
+            endgame_min: 0,
+            unchoking: 0,
+            endgame: false,
             blocks,
         };
         picker.set_priorities(priorities, info);
         picker
     }
 
-    /// Returns true if the current picker algorithm is sequential
-    pub fn is_sequential(&self) -> bool {
+    pub fn strategy(&self) -> PieceStrategy {
         match self.picker {
-            PickerKind::Sequential(_) => true,
-            _ => false,
+            PickerKind::Rarest(_) => PieceStrategy::Rarest,
+            PickerKind::Sequential(_) => PieceStrategy::Sequential,
+        }
+    }
+
+    pub fn set_affinity(&mut self, affinity: bool) {
+        self.affinity = affinity;
+    }
+
+    /// Sets the number of connected peers unchoking us, and how many
+    /// are needed for endgame. Below that, the last blocks are only
+    /// requested from a single peer.
+    pub fn set_unchoking(&mut self, min: usize, unchoking: usize) {
+        self.endgame_min = min;
+        self.unchoking = unchoking;
+        if unchoking < min {
+            self.endgame = false;
         }
     }
 
+    /// Whether endgame is currently active
+    pub fn endgame(&self) -> bool {
+        self.endgame
+    }
+
     pub fn done(&mut self) {
         self.downloading = HashMap::with_capacity(0);
         self.blocks = vec![];
         self.stalled = FHashSet::default();
+        self.endgame = false;
     }
 
     pub fn tick(&mut self) {
@@ -142,13 +208,29 @@ impl Picker {
                 self.stalled.insert(*block);
             }
         }
+        // Blocks of pieces past their deadline are requested again
+        // from whichever peer can send them first
+        let now = time::Instant::now();
+        let overdue: FHashSet<u32> = self
+            .deadlines
+            .iter()
+            .take_while(|&&(deadline, _)| deadline <= now)
+            .map(|&(_, piece)| piece)
+            .collect();
+        if !overdue.is_empty() {
+            for block in self.downloading.keys() {
+                if overdue.contains(&block.index) && self.stalled.insert(*block) {
+                    expired += 1;
+                }
+            }
+        }
         if expired != 0 {
             debug!("Expired {} chunks!", expired);
         }
         if !self.downloading.is_empty() {
             debug!(
                 "Unpicked: {}/{}, Downloading: {}",
-                self.unpicked.iter().count(),
+                self.unpicked.set(),
                 self.unpicked.len(),
                 self.downloading.len()
             );
@@ -160,6 +242,7 @@ impl Picker {
         if !self.stalled.is_empty() {
             let block = self.stalled.iter().cloned().find(|b| {
                 peer.pieces().has_bit(u64::from(b.index))
+                    && self.priorities[b.index as usize] != 0
                     && !self.downloading[b].has_peer(peer.id())
             });
             if let Some(b) = block {
@@ -171,13 +254,103 @@ impl Picker {
             }
         }
 
+        if let Some(p) = self.pick_deadline(peer) {
+            return Some(self.pick_piece(p, peer.id(), peer.rank));
+        }
+
+        let near = if self.affinity {
+            self.last_written
+        } else {
+            None
+        };
         let piece = match self.picker {
             PickerKind::Sequential(ref mut p) => p.pick(peer),
-            PickerKind::Rarest(ref mut p) => p.pick(peer),
+            PickerKind::Rarest(ref mut p) => p.pick(peer, near),
         };
-        piece
-            .map(|p| self.pick_piece(p, peer.id(), peer.rank))
-            .or_else(|| self.pick_dl(peer))
+        if let Some(p) = piece {
+            return Some(self.pick_piece(p, peer.id(), peer.rank));
+        }
+        if self.unchoking < self.endgame_min {
+            return None;
+        }
+        let block = self.pick_dl(peer);
+        if block.is_some() {
+            self.endgame = true;
+        }
+        block
+    }
+
+    /// Sets the pieces needed by a deadline, replacing any set before.
+    pub fn set_deadlines(&mut self, mut deadlines: Vec<(time::Instant, u32)>) {
+        deadlines.sort();
+        self.deadlines = deadlines;
+    }
+
+    /// Picks the piece with the earliest deadline which the peer has
+    /// and still has blocks to pick.
+    fn pick_deadline<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
+        if self.blocks.is_empty() || self.deadlines.is_empty() {
+            return None;
+        }
+        let (scale, last_piece, last_piece_scale) =
+            (self.scale, self.last_piece, self.last_piece_scale);
+        let blocks = &self.blocks;
+        // Pieces which were received in full don't need picking again,
+        // unless they turn out to be invalid
+        self.deadlines.retain(|&(_, p)| {
+            let scale = if p == last_piece {
+                last_piece_scale
+            } else {
+                scale
+            };
+            blocks[p as usize].1 < scale as usize
+        });
+        let (unpicked, priorities) = (&self.unpicked, &self.priorities);
+        self.deadlines.iter().map(|&(_, p)| p).find(|&p| {
+            !unpicked.has_bit(u64::from(p))
+                && priorities[p as usize] != 0
+                && peer.pieces().has_bit(u64::from(p))
+        })
+    }
+
+    /// Picks every block of a piece none of which were picked yet,
+    /// for a source holding every piece, such as a web seed.
+    pub fn pick_whole(&mut self, id: usize) -> Option<u32> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let piece = {
+            let (blocks, unpicked, priorities) = (&self.blocks, &self.unpicked, &self.priorities);
+            let fresh = |p: u32| {
+                blocks[p as usize].0 == 0
+                    && !unpicked.has_bit(u64::from(p))
+                    && priorities[p as usize] != 0
+            };
+            match self.picker {
+                PickerKind::Sequential(ref p) => p.pick_whole(fresh),
+                PickerKind::Rarest(ref p) => p.pick_whole(fresh),
+            }
+        }?;
+        for _ in 0..self.piece_scale(piece) {
+            self.pick_piece(piece, id, 0);
+        }
+        Some(piece)
+    }
+
+    /// Gives up the blocks of a piece picked whole by `id` which
+    /// weren't received, so that peers may pick them.
+    pub fn release_whole(&mut self, piece: u32, id: usize) {
+        for i in 0..self.piece_scale(piece) {
+            self.rejected(Block::new(piece, i * 16_384), id);
+        }
+    }
+
+    fn piece_scale(&self, piece: u32) -> u32 {
+        if piece == self.last_piece {
+            self.last_piece_scale
+        } else {
+            self.scale
+        }
     }
 
     /// Picks a block from a given piece for a peer
@@ -204,9 +377,11 @@ impl Picker {
 
     /// Attempts to pick the highest priority piece in the dl q
     fn pick_dl<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        let priorities = &self.priorities;
         let mut dl: Vec<_> = self
             .downloading
             .iter_mut()
+            .filter(|&(block, _)| priorities[block.index as usize] != 0)
             .filter(|&(_, ref req)| req.num_reqd < MAX_DUP_REQS && !req.has_peer(peer.id()))
             .take(MAX_DL_REREQ)
             .collect();
@@ -220,7 +395,8 @@ impl Picker {
 
     /// Marks a block as completed. Returns a result indicating if the block
     /// was actually requested, the success value containing a bool indicating
-    /// if the block is complete.
+    /// if the block is complete. `cancel` is called with every peer the block
+    /// is still requested from, including the one it was received from.
     pub fn completed<F: FnMut(usize)>(&mut self, b: Block, mut cancel: F) -> Result<bool, ()> {
         self.stalled.remove(&b);
         let dl = self.downloading.remove(&b);
@@ -228,9 +404,10 @@ impl Picker {
             Some(dl) => dl,
             None => return Err(()),
         };
-        for peer in dl.reqd_from.iter() {
+        for peer in &dl.reqd_from[..dl.num_reqd] {
             cancel(*peer);
         }
+        self.last_written = Some(b.index);
 
         self.blocks[b.index as usize].1 += 1;
         let amnt = self.blocks[b.index as usize].1;
@@ -243,6 +420,42 @@ impl Picker {
         }
     }
 
+    /// Handles a peer rejecting the request of a block. If no other peer
+    /// was asked for it, the block is requested from the next peer able
+    /// to, other than the one rejecting it.
+    pub fn rejected(&mut self, b: Block, peer: usize) {
+        if let Some(req) = self.downloading.get_mut(&b) {
+            if let Some(idx) = req.reqd_from[..req.num_reqd]
+                .iter()
+                .position(|id| *id == peer)
+            {
+                req.num_reqd -= 1;
+                req.reqd_from[idx] = req.reqd_from[req.num_reqd];
+                // Left past the active requests so it isn't picked again
+                req.reqd_from[req.num_reqd] = peer;
+            }
+            if req.num_reqd == 0 {
+                self.stalled.insert(b);
+            }
+        }
+    }
+
+    /// Returns every active request, ordered by block.
+    pub fn in_flight(&self, now: time::Instant) -> Vec<InFlight> {
+        let mut reqs: Vec<_> = self
+            .downloading
+            .iter()
+            .map(|(block, req)| InFlight {
+                block: *block,
+                peers: req.reqd_from[..req.num_reqd].to_vec(),
+                pending: now.duration_since(req.requested_at),
+                stalled: self.stalled.contains(block),
+            })
+            .collect();
+        reqs.sort_by_key(|r| (r.block.index, r.block.offset));
+        reqs
+    }
+
     pub fn have_block(&mut self, b: Block) -> bool {
         !self.downloading.contains_key(&b)
     }
@@ -258,6 +471,7 @@ impl Picker {
         }
         self.blocks[idx as usize] = (0, 0);
         self.unpicked.unset_bit(u64::from(idx));
+        self.endgame = false;
     }
 
     pub fn piece_available(&mut self, idx: u32) {
@@ -266,6 +480,12 @@ impl Picker {
         }
     }
 
+    pub fn piece_unavailable(&mut self, idx: u32) {
+        if let PickerKind::Rarest(ref mut p) = self.picker {
+            p.piece_unavailable(idx);
+        }
+    }
+
     pub fn add_peer<T: cio::CIO>(&mut self, peer: &Peer<T>) {
         if peer.pieces().complete() {
             self.seeders += 1;
@@ -295,25 +515,43 @@ impl Picker {
         }
     }
 
-    /// Alters the picker to sequential/non sequential. If changing
-    /// from sequential to non sequential, peer state will need to be loaded
-    /// after this.
-    pub fn change_picker(&mut self, sequential: bool) {
-        self.picker = if sequential {
-            PickerKind::Sequential(sequential::Picker::new(&self.unpicked))
-        } else {
-            PickerKind::Rarest(rarest::Picker::new(&self.unpicked))
+    /// Changes the picking strategy. Blocks already requested are kept,
+    /// only picking of new ones changes. If changing to rarest first,
+    /// peer state will need to be loaded after this.
+    pub fn set_strategy(&mut self, strategy: PieceStrategy) {
+        self.picker = match strategy {
+            PieceStrategy::Sequential => {
+                PickerKind::Sequential(sequential::Picker::new(&self.unpicked))
+            }
+            PieceStrategy::Rarest => PickerKind::Rarest(rarest::Picker::new(&self.unpicked)),
         };
+        self.apply_priorities();
     }
 
     pub fn set_priorities(&mut self, pri: &[u8], info: &Arc<Info>) {
         self.unapply_priorities();
         self.priorities = generate_piece_pri(pri, info);
+        if let Some(ref target) = self.target {
+            for (piece, pri) in self.priorities.iter_mut().enumerate() {
+                *pri = if target.contains(&(piece as u32)) {
+                    cmp::max(*pri, 1)
+                } else {
+                    0
+                };
+            }
+        }
         self.apply_priorities();
     }
 
+    /// Restricts picking to the given pieces, or removes
+    /// the restriction if None.
+    pub fn set_target(&mut self, target: Option<FHashSet<u32>>, pri: &[u8], info: &Arc<Info>) {
+        self.target = target;
+        self.set_priorities(pri, info);
+    }
+
     pub fn apply_priorities(&mut self) {
-        if self.is_sequential() {
+        if self.strategy() == PieceStrategy::Sequential {
             self.picker = PickerKind::Sequential(sequential::Picker::with_pri(
                 &self.unpicked,
                 &self.priorities,
@@ -321,9 +559,7 @@ impl Picker {
         } else {
             for (piece, pri) in self.priorities.iter().enumerate() {
                 if let PickerKind::Rarest(ref mut p) = self.picker {
-                    for _ in 0..*pri {
-                        p.piece_unavailable(piece as u32);
-                    }
+                    p.set_priority(piece as u32, *pri);
                 }
 
                 if *pri == 0 && !self.unpicked.has_bit(piece as u64) {
@@ -337,14 +573,8 @@ impl Picker {
     }
 
     pub fn unapply_priorities(&mut self) {
-        if !self.is_sequential() {
+        if self.strategy() == PieceStrategy::Rarest {
             for (piece, pri) in self.priorities.iter().enumerate() {
-                if let PickerKind::Rarest(ref mut p) = self.picker {
-                    for _ in 0..*pri {
-                        p.piece_available(piece as u32);
-                    }
-                }
-
                 if *pri == 0 && !self.unpicked.has_bit(piece as u64) {
                     match self.picker {
                         PickerKind::Rarest(ref mut p) => p.incomplete(piece as u32),
@@ -387,7 +617,7 @@ impl Picker {
             pieces,
             &vec![3u8; info.files.len()],
         );
-        p.change_picker(true);
+        p.set_strategy(PieceStrategy::Sequential);
         p
     }
 }
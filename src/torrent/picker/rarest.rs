@@ -1,7 +1,7 @@
 // Implementation based off of http://blog.libtorrent.org/2011/11/writing-a-fast-piece-picker/
 use std::ops::IndexMut;
 
-use super::MAX_PC_SIZE;
+use super::{MAX_PC_SIZE, MAX_PRIORITY};
 use control::cio;
 use torrent::{Bitfield, Peer};
 
@@ -10,7 +10,7 @@ pub struct Picker {
     /// Current order of pieces
     pieces: Vec<u32>,
     /// Indices into pieces which indicate priority bounds
-    priorities: Vec<usize>,
+    priorities: Vec<u32>,
     /// Index mapping a piece to a position in the pieces field
     piece_idx: Vec<PieceInfo>,
 }
@@ -21,29 +21,33 @@ enum PieceStatus {
     Complete,
 }
 
+/// Per piece state, kept to 12 bytes so that torrents
+/// with millions of pieces remain manageable.
 #[derive(Clone, Debug)]
 struct PieceInfo {
-    idx: usize,
-    availability: usize,
+    idx: u32,
+    availability: u32,
     status: PieceStatus,
+    priority: u8,
 }
 
 const PIECE_COMPLETE_DEC: usize = 100;
 
 impl Picker {
     pub fn new(pieces: &Bitfield) -> Picker {
-        let mut piece_idx = Vec::new();
+        let mut piece_idx = Vec::with_capacity(pieces.len() as usize);
         for i in 0..pieces.len() {
             piece_idx.push(PieceInfo {
-                idx: i as usize,
+                idx: i as u32,
                 availability: 0,
                 status: PieceStatus::Incomplete,
+                priority: 3,
             });
         }
         let mut p = Picker {
             pieces: (0..pieces.len() as u32).collect(),
             piece_idx,
-            priorities: vec![pieces.len() as usize],
+            priorities: vec![pieces.len() as u32],
         };
 
         // Start every piece at an availability of 6.
@@ -81,15 +85,15 @@ impl Picker {
     pub fn dec_pri(&mut self, piece: u32) {
         let (idx, avail) = {
             let piece = self.piece_idx.index_mut(piece as usize);
-            self.priorities[piece.availability] -= 1;
+            self.priorities[piece.availability as usize] -= 1;
             piece.availability += 1;
-            if self.priorities.len() == piece.availability {
-                self.priorities.push(self.pieces.len());
+            if self.priorities.len() == piece.availability as usize {
+                self.priorities.push(self.pieces.len() as u32);
             }
             (piece.idx, piece.availability - 1)
         };
 
-        let swap_idx = self.priorities[avail];
+        let swap_idx = self.priorities[avail as usize];
         self.swap_piece(idx, swap_idx);
     }
 
@@ -102,15 +106,17 @@ impl Picker {
         let (idx, avail) = {
             let piece = self.piece_idx.index_mut(piece as usize);
             piece.availability -= 1;
-            self.priorities[piece.availability] += 1;
+            self.priorities[piece.availability as usize] += 1;
             (piece.idx, piece.availability)
         };
 
-        let swap_idx = self.priorities[avail - 1];
+        let swap_idx = self.priorities[avail as usize - 1];
         self.swap_piece(idx, swap_idx);
     }
 
-    pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<u32> {
+    /// Picks a piece for the peer, preferring among equally rare pieces
+    /// the one closest to `near` if given.
+    pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>, near: Option<u32>) -> Option<u32> {
         while !peer.piece_cache().is_empty() {
             let p = peer.piece_cache().last().cloned().unwrap();
             if self.piece_idx[p as usize].status == PieceStatus::Complete {
@@ -121,19 +127,34 @@ impl Picker {
         }
 
         if peer.piece_cache().is_empty() {
+            // Only cache the pieces of the highest priority the peer has,
+            // so that they're all picked before any lower priority piece
+            let mut best = 1;
             for piece in &self.pieces {
-                if peer.pieces().has_bit(u64::from(*piece))
-                    && self.piece_idx[*piece as usize].status == PieceStatus::Incomplete
+                let info = &self.piece_idx[*piece as usize];
+                if info.priority < best
+                    || info.status != PieceStatus::Incomplete
+                    || !peer.pieces().has_bit(u64::from(*piece))
                 {
-                    peer.piece_cache().push(*piece);
+                    continue;
+                }
+                if info.priority > best {
+                    best = info.priority;
+                    peer.piece_cache().clear();
                 }
-                if peer.piece_cache().len() >= MAX_PC_SIZE {
+                if peer.piece_cache().len() < MAX_PC_SIZE {
+                    peer.piece_cache().push(*piece);
+                } else if best == MAX_PRIORITY {
                     break;
                 }
             }
             peer.piece_cache().reverse();
         }
 
+        if let Some(anchor) = near {
+            self.prefer_near(peer.piece_cache(), anchor);
+        }
+
         let piece = peer.piece_cache().last();
         if let Some(p) = piece {
             if (self.piece_idx[*p as usize].availability % 2) == 0 {
@@ -143,6 +164,21 @@ impl Picker {
         piece.cloned()
     }
 
+    /// Finds the rarest piece accepted by `fresh`, for a source
+    /// which holds every piece.
+    pub fn pick_whole<F: Fn(u32) -> bool>(&self, fresh: F) -> Option<u32> {
+        self.pieces
+            .iter()
+            .cloned()
+            .find(|&p| self.piece_idx[p as usize].status == PieceStatus::Incomplete && fresh(p))
+    }
+
+    /// Sets the priority of a piece, pieces of a priority of 0
+    /// never being picked.
+    pub fn set_priority(&mut self, piece: u32, priority: u8) {
+        self.piece_idx[piece as usize].priority = priority;
+    }
+
     pub fn incomplete(&mut self, piece: u32) {
         if self.piece_idx[piece as usize].status != PieceStatus::Incomplete {
             self.piece_idx[piece as usize].status = PieceStatus::Incomplete;
@@ -163,10 +199,36 @@ impl Picker {
         }
     }
 
-    fn swap_piece(&mut self, a: usize, b: usize) {
-        self.piece_idx[self.pieces[a] as usize].idx = b;
-        self.piece_idx[self.pieces[b] as usize].idx = a;
-        self.pieces.swap(a, b);
+    /// Moves the piece in the cache closest to `anchor` out of those
+    /// as rare as the next piece to be picked, so that it's picked next.
+    fn prefer_near(&self, cache: &mut [u32], anchor: u32) {
+        let avail = match cache.last() {
+            Some(&p) => self.piece_idx[p as usize].availability,
+            None => return,
+        };
+        // Keep requesting blocks of a piece which is already picked
+        if avail % 2 == 1 {
+            return;
+        }
+        let closest = cache
+            .iter()
+            .enumerate()
+            .filter(|&(_, &p)| {
+                let info = &self.piece_idx[p as usize];
+                info.availability == avail && info.status == PieceStatus::Incomplete
+            })
+            .min_by_key(|&(_, &p)| (i64::from(p) - i64::from(anchor)).abs())
+            .map(|(i, _)| i);
+        if let Some(i) = closest {
+            let last = cache.len() - 1;
+            cache.swap(i, last);
+        }
+    }
+
+    fn swap_piece(&mut self, a: u32, b: u32) {
+        self.piece_idx[self.pieces[a as usize] as usize].idx = b;
+        self.piece_idx[self.pieces[b as usize] as usize].idx = a;
+        self.pieces.swap(a as usize, b as usize);
     }
 }
 
@@ -184,7 +246,7 @@ mod tests {
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
         ];
-        assert_eq!(picker.pick(&mut peers[0]), None);
+        assert_eq!(picker.pick(&mut peers[0], None), None);
 
         peers[0].pieces_mut().set_bit(0);
         peers[1].pieces_mut().set_bit(0);
@@ -194,13 +256,13 @@ mod tests {
         for peer in peers.iter() {
             picker.add_peer(peer);
         }
-        assert_eq!(picker.pick(&mut peers[1]), Some(2));
+        assert_eq!(picker.pick(&mut peers[1], None), Some(2));
         picker.completed(2);
-        assert_eq!(picker.pick(&mut peers[1]), Some(0));
+        assert_eq!(picker.pick(&mut peers[1], None), Some(0));
         picker.completed(0);
-        assert_eq!(picker.pick(&mut peers[1]), None);
-        assert_eq!(picker.pick(&mut peers[0]), None);
-        assert_eq!(picker.pick(&mut peers[2]), Some(1));
+        assert_eq!(picker.pick(&mut peers[1], None), None);
+        assert_eq!(picker.pick(&mut peers[0], None), None);
+        assert_eq!(picker.pick(&mut peers[2], None), Some(1));
         picker.completed(1);
     }
 
@@ -214,7 +276,7 @@ mod tests {
             Peer::test_from_pieces(0, b.clone()),
             Peer::test_from_pieces(0, b.clone()),
         ];
-        assert_eq!(picker.pick(&mut peers[0]), None);
+        assert_eq!(picker.pick(&mut peers[0], None), None);
 
         peers[0].pieces_mut().set_bit(0);
         peers[0].pieces_mut().set_bit(1);
@@ -228,15 +290,41 @@ mod tests {
         }
         picker.remove_peer(&mut peers[0]);
 
-        assert_eq!(picker.pick(&mut peers[1]), Some(2));
+        assert_eq!(picker.pick(&mut peers[1], None), Some(2));
         picker.completed(2);
-        assert_eq!(picker.pick(&mut peers[2]), Some(0));
+        assert_eq!(picker.pick(&mut peers[2], None), Some(0));
         picker.completed(0);
-        assert_eq!(picker.pick(&mut peers[2]), Some(1));
+        assert_eq!(picker.pick(&mut peers[2], None), Some(1));
         picker.completed(1);
 
-        assert_eq!(picker.pick(&mut peers[1]), None);
+        assert_eq!(picker.pick(&mut peers[1], None), None);
         picker.incomplete(1);
-        assert_eq!(picker.pick(&mut peers[1]), Some(1));
+        assert_eq!(picker.pick(&mut peers[1], None), Some(1));
+    }
+
+    #[test]
+    fn test_priority() {
+        let b = Bitfield::new(4);
+        let mut picker = Picker::new(&b);
+        let mut peer = Peer::test_from_pieces(0, b.clone());
+        let mut other = Peer::test_from_pieces(0, b.clone());
+        for i in 0..4 {
+            peer.pieces_mut().set_bit(i);
+        }
+        other.pieces_mut().set_bit(3);
+        picker.add_peer(&peer);
+        picker.add_peer(&other);
+
+        // Rarity only orders pieces of the same priority
+        picker.set_priority(0, 1);
+        picker.set_priority(1, 0);
+        picker.set_priority(3, 5);
+        assert_eq!(picker.pick(&mut peer, None), Some(3));
+        picker.completed(3);
+        assert_eq!(picker.pick(&mut peer, None), Some(2));
+        picker.completed(2);
+        assert_eq!(picker.pick(&mut peer, None), Some(0));
+        picker.completed(0);
+        assert_eq!(picker.pick(&mut peer, None), None);
     }
 }
@@ -1,6 +1,10 @@
 use control::cio;
 use torrent::{Bitfield, Peer};
 
+/// Number of unpicked pieces past the first one which may be picked,
+/// so that peers lacking the next pieces don't pull picking far ahead
+const LOOKAHEAD: usize = 16;
+
 #[derive(Clone, Debug)]
 pub struct Picker {
     /// The max block index that we've picked up to so far
@@ -72,10 +76,22 @@ impl Picker {
     pub fn pick<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<u32> {
         self.pieces[self.piece_idx..]
             .iter()
+            .filter(|p| p.status == PieceStatus::Incomplete)
+            .take(LOOKAHEAD)
             .find(|p| peer.pieces().has_bit(u64::from(p.pos)))
             .map(|p| p.pos)
     }
 
+    /// Finds the first piece accepted by `fresh`, for a source which
+    /// holds every piece.
+    pub fn pick_whole<F: Fn(u32) -> bool>(&self, fresh: F) -> Option<u32> {
+        self.pieces[self.piece_idx..]
+            .iter()
+            .filter(|p| p.status == PieceStatus::Incomplete)
+            .map(|p| p.pos)
+            .find(|&p| fresh(p))
+    }
+
     /// Returns whether or not the whole piece is complete.
     pub fn completed(&mut self, idx: u32) {
         if let Some(p) = self.pieces[self.piece_idx..]
@@ -101,10 +117,10 @@ impl Picker {
     }
 
     fn update_piece_idx(&mut self) {
-        for i in self.piece_idx..self.pieces.len() {
-            if self.pieces[i].status == PieceStatus::Complete {
-                self.piece_idx += 1;
-            }
+        while self.piece_idx < self.pieces.len()
+            && self.pieces[self.piece_idx].status == PieceStatus::Complete
+        {
+            self.piece_idx += 1;
         }
     }
 }
@@ -134,4 +150,24 @@ mod tests {
         picker.incomplete(1);
         assert_eq!(picker.pick(&peer), Some(1));
     }
+
+    #[test]
+    fn test_lookahead() {
+        let b = Bitfield::new(40);
+        let mut picker = Picker::new(&b);
+        let mut peer = Peer::test_from_pieces(0, b);
+        peer.pieces_mut().set_bit(30);
+        assert_eq!(picker.pick(&peer), None);
+
+        // Pieces picked out of order don't hold back the window
+        for i in (0..20).rev() {
+            picker.completed(i);
+        }
+        assert_eq!(picker.pick(&peer), Some(30));
+        picker.completed(30);
+        peer.pieces_mut().set_bit(5);
+        assert_eq!(picker.pick(&peer), None);
+        picker.incomplete(5);
+        assert_eq!(picker.pick(&peer), Some(5));
+    }
 }
@@ -1,8 +1,15 @@
-use super::{Block, Picker};
+use super::{Block, Picker, PieceStrategy};
 use rand::distributions::{Distribution, Range};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use torrent::info::File;
 use torrent::{Bitfield, Info, Peer as TGPeer};
+use util::FHashSet;
 use {control, rand};
 
 type TPeer = TGPeer<control::cio::test::TCIO>;
@@ -276,3 +283,452 @@ fn test_seq_picker() {
 
     assert_eq!(p.pick(&mut peer), Some(Block::new(5, 0)));
 }
+
+#[test]
+fn test_target_pieces() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let info = Arc::new(i.clone());
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb);
+    p.add_peer(&peer);
+
+    let target: FHashSet<u32> = vec![2, 5, 7].into_iter().collect();
+    p.set_target(Some(target.clone()), &[3], &info);
+    let mut picked = FHashSet::default();
+    while let Some(b) = p.pick(&mut peer) {
+        picked.insert(b.index);
+    }
+    assert_eq!(picked, target);
+
+    // Normal picking resumes once the restriction is lifted
+    p.set_target(None, &[3], &info);
+    let mut picked = FHashSet::default();
+    while let Some(b) = p.pick(&mut peer) {
+        picked.insert(b.index);
+    }
+    assert_eq!(picked, vec![0, 1, 3, 4, 6, 8, 9].into_iter().collect());
+}
+
+#[test]
+fn test_deadline_pieces() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb.clone());
+    let mut other = TPeer::test_from_pieces(1, pb);
+    p.add_peer(&peer);
+    p.add_peer(&other);
+
+    let now = Instant::now();
+    p.set_deadlines(vec![
+        (now + Duration::from_secs(20), 7),
+        (now + Duration::from_secs(10), 4),
+    ]);
+    assert_eq!(p.pick(&mut peer), Some(Block::new(4, 0)));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(7, 0)));
+
+    // Overdue pieces are requested from other peers
+    p.set_deadlines(vec![(now, 4), (now + Duration::from_secs(20), 7)]);
+    p.tick();
+    assert_eq!(p.pick(&mut other), Some(Block::new(4, 0)));
+    assert_eq!(p.completed(Block::new(4, 0), |_| {}), Ok(true));
+    let next = p.pick(&mut other).unwrap();
+    assert!(next.index != 4 && next.index != 7);
+    assert_eq!(p.deadlines, vec![(now + Duration::from_secs(20), 7)]);
+}
+
+#[test]
+fn test_in_flight() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb.clone());
+    let mut other = TPeer::test_from_pieces(1, pb);
+
+    for _ in 0..3 {
+        p.pick(&mut peer).unwrap();
+    }
+    assert_eq!(p.completed(Block::new(1, 0), |_| {}), Ok(true));
+    // Time out the request for piece 2, so the other peer picks it up
+    p.stalled.insert(Block::new(2, 0));
+    let now = Instant::now();
+    let reqs = p.in_flight(now);
+    assert_eq!(reqs.len(), 2);
+    assert!(reqs[1].stalled);
+    assert_eq!(p.pick(&mut other), Some(Block::new(2, 0)));
+
+    let now = Instant::now();
+    let reqs = p.in_flight(now);
+    let blocks: Vec<_> = reqs.iter().map(|r| r.block).collect();
+    assert_eq!(blocks, vec![Block::new(0, 0), Block::new(2, 0)]);
+    assert_eq!(reqs[0].peers, vec![0]);
+    assert_eq!(reqs[1].peers, vec![0, 1]);
+    assert!(!reqs[1].stalled);
+    for r in &reqs {
+        let req = &p.downloading[&r.block];
+        assert_eq!(&req.reqd_from[..req.num_reqd], &r.peers[..]);
+        assert_eq!(now.duration_since(req.requested_at), r.pending);
+    }
+}
+
+#[test]
+fn test_rejected_rerequest() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(1, pb.clone());
+    let mut other = TPeer::test_from_pieces(2, pb);
+
+    let block = p.pick(&mut peer).unwrap();
+    p.rejected(block, peer.id());
+    // The rejected block goes to the next peer, not back to the rejecting one
+    assert_eq!(p.pick(&mut peer), Some(Block::new(1, 0)));
+    assert_eq!(p.pick(&mut other), Some(block));
+    let reqs = p.in_flight(Instant::now());
+    assert_eq!(reqs[0].block, block);
+    assert_eq!(reqs[0].peers, vec![2]);
+    assert!(!reqs[0].stalled);
+}
+
+#[test]
+fn test_pick_whole() {
+    let mut i = Info::with_pieces_scale(4, 2);
+    i.files = vec![File {
+        path: PathBuf::new(),
+        length: i.total_len,
+        renamed: None,
+    }];
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(4);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(4);
+    pb.set_bit(0);
+    let mut peer = TPeer::test_from_pieces(1, pb);
+    p.add_peer(&peer);
+
+    // Pieces a peer started on aren't picked whole
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 0)));
+    let piece = p.pick_whole(100).unwrap();
+    assert_ne!(piece, 0);
+    let reqs = p.in_flight(Instant::now());
+    assert_eq!(reqs.len(), 3);
+    assert!(reqs
+        .iter()
+        .filter(|r| r.block.index == piece)
+        .all(|r| r.peers == vec![100]));
+
+    // Released blocks go to peers having the piece
+    p.release_whole(piece, 100);
+    let mut pb = Bitfield::new(4);
+    pb.set_bit(u64::from(piece));
+    let mut other = TPeer::test_from_pieces(2, pb);
+    assert_eq!(p.pick(&mut other).map(|b| b.index), Some(piece));
+
+    let second = p.pick_whole(100).unwrap();
+    let third = p.pick_whole(100).unwrap();
+    assert!(second != piece && third != piece && second != third);
+    assert_eq!(p.pick_whole(100), None);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_injected_latency_rerequest() {
+    use buffers::Buffer;
+    use control::cio::Event;
+    use control::fault::{Fault, Injector};
+    use torrent::Message;
+
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut slow = TPeer::test_from_pieces(0, pb.clone());
+    let mut fast = TPeer::test_from_pieces(1, pb);
+
+    let block = p.pick(&mut slow).unwrap();
+    let mut inj = Injector::default();
+    inj.set(slow.id(), Fault::new(20_000, 0.));
+    let now = Instant::now();
+    let mut events = vec![Event::Peer {
+        peer: slow.id(),
+        event: Ok(Message::Piece {
+            index: block.index,
+            begin: block.offset,
+            length: 16_384,
+            data: Buffer::get().unwrap(),
+        }),
+    }];
+    inj.apply(&mut events, now);
+    assert!(events.is_empty());
+
+    // The request times out while the block is still held back, so the
+    // block gets re-requested from another peer.
+    let waited = Duration::from_secs(super::REQ_TIMEOUT + 3);
+    p.downloading.get_mut(&block).unwrap().requested_at -= waited;
+    inj.apply(&mut events, now + waited);
+    assert!(events.is_empty());
+    p.tick();
+    assert!(p.stalled.contains(&block));
+    assert_eq!(p.pick(&mut fast), Some(block));
+
+    // The delayed block still arrives and completes the request
+    inj.apply(&mut events, now + Duration::from_secs(20));
+    assert_eq!(events.len(), 1);
+    assert_eq!(p.completed(block, |_| {}), Ok(true));
+}
+
+/// Sum of the distances between consecutively written pieces when
+/// downloading from a swarm with many equally rare pieces.
+fn seek_distance(affinity: bool) -> u64 {
+    let pieces = 256;
+    let mut i = Info::with_pieces(pieces);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(pieces as u64);
+    let mut p = Picker::new_rarest(&i, &b);
+    p.set_affinity(affinity);
+
+    let mut rng = StdRng::from_seed([7; 32]);
+    let mut peers: Vec<_> = (0..4)
+        .map(|id| {
+            let mut pb = Bitfield::new(pieces as u64);
+            for i in 0..pieces as u64 {
+                if rng.gen() {
+                    pb.set_bit(i);
+                }
+            }
+            TPeer::test_from_pieces(id, pb)
+        })
+        .collect();
+    for peer in &peers {
+        p.add_peer(peer);
+    }
+
+    let mut dist = 0u64;
+    let mut last: Option<i64> = None;
+    let mut idle = 0;
+    let mut turn = 0;
+    while idle < peers.len() {
+        let peer = &mut peers[turn % 4];
+        turn += 1;
+        match p.pick(peer) {
+            Some(b) => {
+                idle = 0;
+                assert_eq!(p.completed(b, |_| {}), Ok(true));
+                if let Some(l) = last {
+                    dist += (i64::from(b.index) - l).abs() as u64;
+                }
+                last = Some(i64::from(b.index));
+            }
+            None => idle += 1,
+        }
+    }
+    dist
+}
+
+#[test]
+fn test_affinity_seek_distance() {
+    let scattered = seek_distance(false);
+    let local = seek_distance(true);
+    assert!(
+        local < scattered,
+        "affinity seeks {}, without {}",
+        local,
+        scattered
+    );
+}
+
+/// Times `ops` picks and completions on a fresh picker
+/// over a torrent with the given number of pieces.
+fn time_picks(pieces: usize, ops: usize) -> Duration {
+    let mut i = Info::with_pieces(pieces);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(pieces as u64);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(pieces as u64);
+    for i in 0..pieces as u64 {
+        // Leave one piece out so the peer isn't treated as a seeder
+        if i != 1 {
+            pb.set_bit(i);
+        }
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb);
+    p.add_peer(&peer);
+
+    let start = Instant::now();
+    for _ in 0..ops {
+        let b = p.pick(&mut peer).unwrap();
+        assert_eq!(p.completed(b, |_| {}), Ok(true));
+    }
+    start.elapsed()
+}
+
+/// Benchmark of a synthetic million piece torrent, run with
+/// `cargo test --release -- --ignored bench_million_pieces`.
+/// Picking must not scale with the piece count, so a hundredfold
+/// increase in pieces should only cost a small constant factor.
+#[test]
+#[ignore]
+fn bench_million_pieces() {
+    let ops = 5_000;
+    let small = time_picks(10_000, ops);
+    let large = time_picks(1_000_000, ops);
+    assert!(
+        large < small * 10,
+        "10k pieces: {:?}, 1M pieces: {:?}",
+        small,
+        large
+    );
+}
+
+#[test]
+fn test_endgame_min_peers() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb.clone());
+    let mut other = TPeer::test_from_pieces(1, pb);
+
+    p.set_unchoking(2, 1);
+    for i in 0..10 {
+        assert_eq!(p.pick(&mut peer), Some(Block::new(i, 0)));
+    }
+    // A single unchoking peer gets no duplicate requests
+    assert_eq!(p.pick(&mut other), None);
+    assert!(!p.endgame());
+
+    p.set_unchoking(2, 2);
+    assert!(p.pick(&mut other).is_some());
+    assert!(p.endgame());
+
+    p.set_unchoking(2, 1);
+    assert!(!p.endgame());
+    assert_eq!(p.pick(&mut other), None);
+}
+
+#[test]
+fn test_endgame_cancel() {
+    let mut i = Info::with_pieces(1);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(1);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(1);
+    pb.set_bit(0);
+    let mut peers: Vec<_> = (1..4)
+        .map(|id| TPeer::test_from_pieces(id, pb.clone()))
+        .collect();
+
+    // The last block is requested from every peer
+    p.set_unchoking(2, 3);
+    for peer in &mut peers {
+        assert_eq!(p.pick(peer), Some(Block::new(0, 0)));
+    }
+    assert!(p.endgame());
+    p.rejected(Block::new(0, 0), 3);
+
+    // Once it arrives, only the peers still asked for it are cancelled
+    let mut canceled = Vec::new();
+    assert_eq!(
+        p.completed(Block::new(0, 0), |peer| canceled.push(peer)),
+        Ok(true)
+    );
+    canceled.sort();
+    assert_eq!(canceled, vec![1, 2]);
+}
+
+#[test]
+fn test_strategy_switch_keeps_requests() {
+    let mut i = Info::with_pieces_scale(4, 2);
+    i.files = vec![File {
+        path: PathBuf::new(),
+        length: i.total_len,
+        renamed: None,
+    }];
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(4);
+    let mut p = Picker::new_rarest(&i, &b);
+    let mut pb = Bitfield::new(4);
+    pb.set_bit(0);
+    let mut peer = TPeer::test_from_pieces(0, pb);
+    p.add_peer(&peer);
+
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 0)));
+    p.set_strategy(PieceStrategy::Sequential);
+    assert_eq!(p.strategy(), PieceStrategy::Sequential);
+    let reqs = p.in_flight(Instant::now());
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].block, Block::new(0, 0));
+
+    // The partly picked piece carries on where it left off
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 16_384)));
+    assert_eq!(p.pick(&mut peer), None);
+    assert_eq!(p.completed(Block::new(0, 0), |_| {}), Ok(false));
+    assert_eq!(p.completed(Block::new(0, 16_384), |_| {}), Ok(true));
+}
+
+#[test]
+fn test_file_priorities() {
+    let mut i = Info::with_pieces(6);
+    let file = |length| File {
+        path: PathBuf::new(),
+        length,
+        renamed: None,
+    };
+    i.files = vec![file(40_960), file(24_576), file(32_768)];
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let info = Arc::new(i.clone());
+    let mut pb = Bitfield::new(6);
+    for i in 0..6 {
+        pb.set_bit(i);
+    }
+
+    for &strategy in &[PieceStrategy::Rarest, PieceStrategy::Sequential] {
+        let mut p = Picker::new_rarest(&i, &Bitfield::new(6));
+        let mut peer = TPeer::test_from_pieces(0, pb.clone());
+        p.add_peer(&peer);
+        // Piece 2 spans the first two files, taking the higher priority
+        p.set_priorities(&[1, 5, 0], &info);
+        p.set_strategy(strategy);
+        if strategy == PieceStrategy::Rarest {
+            p.add_peer(&peer);
+        }
+
+        let mut picked = Vec::new();
+        while let Some(b) = p.pick(&mut peer) {
+            picked.push(b.index);
+        }
+        assert_eq!(picked.len(), 4);
+        picked[..2].sort();
+        picked[2..].sort();
+        assert_eq!(picked, vec![2, 3, 0, 1]);
+    }
+}
@@ -0,0 +1,51 @@
+/// Peer sources enabled for a torrent by the user.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sources {
+    pub dht: bool,
+    pub pex: bool,
+    pub lpd: bool,
+    pub web_seeds: bool,
+}
+
+impl Sources {
+    pub fn new() -> Sources {
+        Sources {
+            dht: true,
+            pex: true,
+            lpd: true,
+            web_seeds: true,
+        }
+    }
+
+    /// Sources which are actually used, where `public` is whether the
+    /// torrent may use public peer sources at all, which can't be
+    /// overridden for private torrents.
+    pub fn effective(&self, public: bool) -> Sources {
+        Sources {
+            dht: public && self.dht,
+            pex: public && self.pex,
+            lpd: public && self.lpd,
+            web_seeds: self.web_seeds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sources;
+
+    #[test]
+    fn test_per_torrent() {
+        let a = Sources::new();
+        let mut b = Sources::new();
+        b.pex = false;
+        assert!(a.effective(true).pex);
+        assert!(!b.effective(true).pex);
+        assert!(b.effective(true).dht);
+
+        // Private torrents can't enable public sources
+        let p = Sources::new().effective(false);
+        assert!(!p.dht && !p.pex && !p.lpd);
+        assert!(p.web_seeds);
+    }
+}
@@ -0,0 +1,140 @@
+use torrent::Bitfield;
+use util::{FHashSet, UHashMap};
+
+/// Super seeding (BEP 16) state of a complete torrent. Each peer is
+/// shown a single piece, and only shown another once that piece was
+/// seen announced by some other peer, so that pieces are uploaded
+/// as few times as possible.
+pub struct SuperSeed {
+    enabled: bool,
+    /// Piece revealed to each super seeded peer, if it's yet to be shared
+    peers: UHashMap<Option<u32>>,
+    /// Times each piece was revealed or announced by a peer
+    spread: Vec<u32>,
+    revealed: FHashSet<u32>,
+}
+
+impl SuperSeed {
+    pub fn new() -> SuperSeed {
+        SuperSeed {
+            enabled: false,
+            peers: UHashMap::default(),
+            spread: Vec::new(),
+            revealed: FHashSet::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enables or disables super seeding. When disabled, the peers
+    /// which were super seeded are returned, as they must be told of
+    /// the remaining pieces.
+    pub fn set_enabled(&mut self, enabled: bool) -> Vec<usize> {
+        self.enabled = enabled;
+        if enabled {
+            return Vec::new();
+        }
+        self.revealed.clear();
+        self.spread.clear();
+        self.peers.drain().map(|(pid, _)| pid).collect()
+    }
+
+    /// Number of distinct pieces revealed so far
+    pub fn revealed(&self) -> u64 {
+        self.revealed.len() as u64
+    }
+
+    /// Picks the piece to reveal to peer `pid` which has `pieces`,
+    /// preferring the least spread one.
+    pub fn reveal(&mut self, pid: usize, pieces: &Bitfield) -> Option<u32> {
+        if self.spread.len() < pieces.len() as usize {
+            self.spread.resize(pieces.len() as usize, 0);
+        }
+        let piece = (0..pieces.len())
+            .filter(|&i| !pieces.has_bit(i))
+            .min_by_key(|&i| self.spread[i as usize])
+            .map(|i| i as u32);
+        if let Some(idx) = piece {
+            self.spread[idx as usize] += 1;
+            self.revealed.insert(idx);
+        }
+        self.peers.insert(pid, piece);
+        piece
+    }
+
+    /// Records that peer `pid` announced having `piece`, returning
+    /// the other peers which were revealed it and so have shared it.
+    pub fn have(&mut self, pid: usize, piece: u32) -> Vec<usize> {
+        if let Some(s) = self.spread.get_mut(piece as usize) {
+            *s += 1;
+        }
+        let shared: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|&(id, p)| *id != pid && *p == Some(piece))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &shared {
+            self.peers.insert(*id, None);
+        }
+        shared
+    }
+
+    /// Records the bitfield of peer `pid`, returning the peers which
+    /// should be revealed another piece. This includes `pid` if it
+    /// already had the piece it was revealed.
+    pub fn bitfield(&mut self, pid: usize, pieces: &Bitfield) -> Vec<usize> {
+        let mut shared = Vec::new();
+        for idx in pieces.iter() {
+            shared.extend(self.have(pid, idx as u32));
+        }
+        if let Some(&Some(idx)) = self.peers.get(&pid) {
+            if pieces.has_bit(u64::from(idx)) {
+                shared.push(pid);
+            }
+        }
+        shared
+    }
+
+    pub fn remove_peer(&mut self, pid: usize) {
+        self.peers.remove(&pid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SuperSeed;
+    use torrent::Bitfield;
+
+    #[test]
+    fn test_super_seed() {
+        let mut s = SuperSeed::new();
+        assert!(s.set_enabled(true).is_empty());
+
+        // Peers are revealed different pieces
+        let none = Bitfield::new(4);
+        assert_eq!(s.reveal(0, &none), Some(0));
+        assert_eq!(s.reveal(1, &none), Some(1));
+        assert_eq!(s.revealed(), 2);
+
+        // A peer downloading its own piece doesn't get another
+        assert!(s.have(0, 0).is_empty());
+        // Once someone else has it, it was shared
+        assert_eq!(s.have(2, 0), vec![0]);
+        assert_eq!(s.reveal(0, &none), Some(2));
+
+        // A peer already having its piece is revealed another
+        let mut pieces = Bitfield::new(4);
+        pieces.set_bit(1);
+        assert_eq!(s.bitfield(1, &pieces), vec![1]);
+        assert_eq!(s.reveal(1, &pieces), Some(3));
+        assert_eq!(s.revealed(), 4);
+
+        let mut peers = s.set_enabled(false);
+        peers.sort();
+        assert_eq!(peers, vec![0, 1]);
+        assert_eq!(s.revealed(), 0);
+    }
+}
@@ -0,0 +1,460 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use config::WebSeedStrategy;
+use throttle::Throttle;
+use torrent::Info;
+use tracker::{self, WebSeedFetch};
+
+/// Web seeds of a torrent and whether they're currently used
+/// as a source of pieces.
+pub struct WebSeeds {
+    seeds: Vec<Seed>,
+    engaged: bool,
+    /// Bytes downloaded from web seeds this session
+    downloaded: u64,
+    /// Download tokens taken from the throttle for the next fetch
+    budget: usize,
+}
+
+struct Seed {
+    url: Arc<Url>,
+    /// Piece currently fetched from the seed
+    fetch: Option<Fetch>,
+    /// Time until which the seed is left alone after failing
+    retry: Option<Instant>,
+    /// Set once the seed turned out not to serve the torrent
+    broken: bool,
+}
+
+/// A piece being fetched, assembled from the ranges of every
+/// file it spans.
+struct Fetch {
+    piece: u32,
+    data: Vec<u8>,
+    /// Number of ranges not yet received
+    pending: usize,
+}
+
+/// How well connected peers are serving a torrent
+pub struct Swarm {
+    /// Connected peers which have pieces we need
+    pub useful_peers: usize,
+    /// Whether every piece is held by some connected peer
+    pub available: bool,
+    /// Download rate from peers in bytes/s
+    pub rate: u64,
+}
+
+/// Outcome of a fetched range being received
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    /// The range was for a fetch which was given up on
+    Stale,
+    /// Other ranges of the piece are still being fetched
+    Pending,
+    /// The seed's piece is complete
+    Piece(usize, Vec<u8>),
+    /// Fetching the seed's piece failed
+    Failed(usize),
+}
+
+/// Seconds a web seed is left alone after a failed fetch
+const RETRY_SECS: u64 = 60;
+const BLOCK_LEN: usize = 16_384;
+
+/// Id the picker knows the web seed at `idx` by, which never
+/// collides with a peer's.
+pub fn id(idx: usize) -> usize {
+    usize::max_value() - idx
+}
+
+impl WebSeeds {
+    pub fn new(urls: Vec<Arc<Url>>) -> WebSeeds {
+        WebSeeds {
+            seeds: urls
+                .into_iter()
+                .map(|url| Seed {
+                    url,
+                    fetch: None,
+                    retry: None,
+                    broken: false,
+                })
+                .collect(),
+            engaged: false,
+            downloaded: 0,
+            budget: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seeds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seeds.is_empty()
+    }
+
+    pub fn engaged(&self) -> bool {
+        self.engaged
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Decides whether web seeds should be used given the state of the
+    /// swarm, returning true if this changed. `min_rate` is the peer
+    /// download rate below which peers are considered underutilized.
+    pub fn update(&mut self, strategy: WebSeedStrategy, min_rate: u64, swarm: &Swarm) -> bool {
+        let dead = swarm.useful_peers == 0;
+        let engaged = !self.seeds.is_empty()
+            && match strategy {
+                WebSeedStrategy::Always => true,
+                WebSeedStrategy::Underutilized => dead || !swarm.available || swarm.rate < min_rate,
+                WebSeedStrategy::LastResort => dead,
+            };
+        self.set_engaged(engaged)
+    }
+
+    /// Stops using web seeds, e.g. because the torrent completed
+    /// or was paused, returning true if they were in use.
+    pub fn disengage(&mut self) -> bool {
+        self.set_engaged(false)
+    }
+
+    fn set_engaged(&mut self, engaged: bool) -> bool {
+        if engaged == self.engaged {
+            return false;
+        }
+        self.engaged = engaged;
+        true
+    }
+
+    /// Returns a seed which can be fetched from
+    pub fn idle(&self, now: Instant) -> Option<usize> {
+        if !self.engaged {
+            return None;
+        }
+        self.seeds.iter().position(|s| {
+            !s.broken && s.fetch.is_none() && s.retry.map(|r| r <= now).unwrap_or(true)
+        })
+    }
+
+    /// Takes tokens from the throttle until they cover a fetch of
+    /// `len` bytes, returning false if it ran out first.
+    pub fn reserve(&mut self, throttle: &mut Throttle, len: usize) -> bool {
+        while self.budget < len {
+            if throttle.poll_bytes_dl(BLOCK_LEN).is_err() {
+                return false;
+            }
+            self.budget += BLOCK_LEN;
+        }
+        true
+    }
+
+    /// Starts fetching `piece` from the seed at `idx`, returning
+    /// the range requests to send.
+    pub fn start(
+        &mut self,
+        idx: usize,
+        tid: usize,
+        info: &Arc<Info>,
+        piece: u32,
+    ) -> Vec<WebSeedFetch> {
+        let len = info.piece_len(piece) as usize;
+        self.budget = self.budget.saturating_sub(len);
+        let seed = &mut self.seeds[idx];
+        let reqs = ranges(&seed.url, tid, info, piece);
+        seed.fetch = Some(Fetch {
+            piece,
+            data: vec![0; len],
+            pending: reqs.len(),
+        });
+        reqs
+    }
+
+    /// Handles the response to a range request
+    pub fn received(
+        &mut self,
+        url: &Url,
+        piece: u32,
+        start: usize,
+        resp: tracker::Result<Vec<u8>>,
+        now: Instant,
+    ) -> Received {
+        let idx = match self.seeds.iter().position(|s| *s.url == *url) {
+            Some(idx) => idx,
+            None => return Received::Stale,
+        };
+        let res = {
+            let seed = &mut self.seeds[idx];
+            match seed.fetch {
+                Some(ref f) if f.piece == piece => {}
+                _ => return Received::Stale,
+            }
+            match resp {
+                Ok(data) => seed.add_range(start, &data),
+                Err(e) => {
+                    seed.fetch = None;
+                    match *e.kind() {
+                        tracker::ErrorKind::HTTPStatus(404)
+                        | tracker::ErrorKind::HTTPStatus(416) => {
+                            warn!("Web seed {} doesn't serve the torrent: {}", url, e);
+                            seed.broken = true;
+                        }
+                        _ => {
+                            debug!("Fetch from web seed {} failed: {}", url, e);
+                            seed.retry = Some(now + Duration::from_secs(RETRY_SECS));
+                        }
+                    }
+                    return Received::Failed(idx);
+                }
+            }
+        };
+        match res {
+            Some(data) => {
+                self.downloaded += data.len() as u64;
+                Received::Piece(idx, data)
+            }
+            None => Received::Pending,
+        }
+    }
+
+    /// Gives up every fetch in progress, returning the seed and
+    /// piece of each.
+    pub fn release(&mut self) -> Vec<(usize, u32)> {
+        self.seeds
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(idx, s)| s.fetch.take().map(|f| (idx, f.piece)))
+            .collect()
+    }
+}
+
+impl Seed {
+    /// Copies a received range into the piece, returning the
+    /// piece once every range was received.
+    fn add_range(&mut self, start: usize, data: &[u8]) -> Option<Vec<u8>> {
+        let done = match self.fetch {
+            Some(ref mut f) => {
+                if start + data.len() > f.data.len() {
+                    return None;
+                }
+                f.data[start..start + data.len()].copy_from_slice(data);
+                f.pending -= 1;
+                f.pending == 0
+            }
+            None => false,
+        };
+        if done {
+            self.retry = None;
+            self.fetch.take().map(|f| f.data)
+        } else {
+            None
+        }
+    }
+}
+
+/// Maps a piece to range requests of the files it spans. Seeds of multi
+/// file torrents are directories, with file paths relative to them.
+fn ranges(seed: &Arc<Url>, tid: usize, info: &Arc<Info>, piece: u32) -> Vec<WebSeedFetch> {
+    let dir = seed.path().ends_with('/');
+    Info::piece_disk_locs(info, piece)
+        .filter(|loc| loc.end > loc.start)
+        .map(|loc| {
+            let mut url = (**seed).clone();
+            if dir {
+                if let Ok(mut path) = url.path_segments_mut() {
+                    path.pop_if_empty();
+                    for c in info.files[loc.file].path.iter() {
+                        path.push(&c.to_string_lossy());
+                    }
+                }
+            }
+            WebSeedFetch {
+                id: tid,
+                seed: seed.clone(),
+                url,
+                piece,
+                start: loc.start,
+                offset: loc.offset,
+                len: loc.end - loc.start,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Received, Swarm, WebSeeds, RETRY_SECS};
+    use config::WebSeedStrategy;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use torrent::info::File;
+    use torrent::Info;
+    use tracker::ErrorKind;
+    use url::Url;
+
+    fn swarm(useful_peers: usize, available: bool, rate: u64) -> Swarm {
+        Swarm {
+            useful_peers,
+            available,
+            rate,
+        }
+    }
+
+    #[test]
+    fn test_strategies() {
+        let url = Arc::new(Url::parse("http://seed.example/files/").unwrap());
+        let healthy = swarm(20, true, 10_000);
+        let slow = swarm(20, true, 10);
+        let rare = swarm(20, false, 10_000);
+        let dead = swarm(0, false, 0);
+
+        let engaged = |strategy, s: &Swarm| {
+            let mut ws = WebSeeds::new(vec![url.clone()]);
+            ws.update(strategy, 1000, s);
+            ws.engaged()
+        };
+
+        assert!(engaged(WebSeedStrategy::Always, &healthy));
+
+        assert!(!engaged(WebSeedStrategy::Underutilized, &healthy));
+        assert!(engaged(WebSeedStrategy::Underutilized, &slow));
+        assert!(engaged(WebSeedStrategy::Underutilized, &rare));
+        assert!(engaged(WebSeedStrategy::Underutilized, &dead));
+
+        assert!(!engaged(WebSeedStrategy::LastResort, &slow));
+        assert!(!engaged(WebSeedStrategy::LastResort, &rare));
+        assert!(engaged(WebSeedStrategy::LastResort, &dead));
+
+        // Changes are only reported once
+        let mut ws = WebSeeds::new(vec![url.clone()]);
+        assert!(ws.update(WebSeedStrategy::LastResort, 1000, &dead));
+        assert!(!ws.update(WebSeedStrategy::LastResort, 1000, &dead));
+        assert!(ws.update(WebSeedStrategy::LastResort, 1000, &healthy));
+        assert!(!ws.disengage());
+
+        // Torrents without web seeds never engage them
+        let mut ws = WebSeeds::new(Vec::new());
+        assert!(!ws.update(WebSeedStrategy::Always, 1000, &dead));
+    }
+
+    #[test]
+    fn test_strategy_gates_fetches() {
+        let url = Arc::new(Url::parse("http://seed.example/files/").unwrap());
+        let info = info();
+        let mut ws = WebSeeds::new(vec![url.clone()]);
+        let now = Instant::now();
+
+        // Nothing is fetched while peers serve the torrent
+        ws.update(WebSeedStrategy::LastResort, 0, &swarm(20, true, 10_000));
+        assert_eq!(ws.idle(now), None);
+
+        // Once the swarm dies pieces come from the seed
+        assert!(ws.update(WebSeedStrategy::LastResort, 0, &swarm(0, false, 0)));
+        assert_eq!(ws.idle(now), Some(0));
+        ws.start(0, 1, &info, 0);
+        match ws.received(&url, 0, 0, Ok(vec![1; 32_768]), now) {
+            Received::Piece(0, _) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(ws.downloaded(), 32_768);
+
+        // and fetches in progress are given up when peers return
+        ws.start(0, 1, &info, 1);
+        assert!(ws.update(WebSeedStrategy::LastResort, 0, &swarm(5, true, 10_000)));
+        assert_eq!(ws.release(), vec![(0, 1)]);
+        assert_eq!(ws.idle(now), None);
+        assert_eq!(ws.downloaded(), 32_768);
+    }
+
+    fn info() -> Arc<Info> {
+        let mut info = Info::with_pieces_scale(2, 2);
+        info.files = vec![
+            File {
+                path: PathBuf::from("t/a b"),
+                length: 40_000,
+                renamed: None,
+            },
+            File {
+                path: PathBuf::from("t/c"),
+                length: 25_536,
+                renamed: None,
+            },
+        ];
+        info.piece_idx =
+            Info::generate_piece_idx(info.hashes.len(), u64::from(info.piece_len), &info.files);
+        Arc::new(info)
+    }
+
+    #[test]
+    fn test_fetch_ranges() {
+        let url = Arc::new(Url::parse("http://seed.example/files/").unwrap());
+        let info = info();
+        let mut ws = WebSeeds::new(vec![url.clone()]);
+        ws.update(WebSeedStrategy::Always, 0, &swarm(0, false, 0));
+        let now = Instant::now();
+        assert_eq!(ws.idle(now), Some(0));
+
+        // The second piece spans the end of one file and the start of the next
+        let reqs = ws.start(0, 1, &info, 1);
+        assert_eq!(ws.idle(now), None);
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(reqs[0].url.as_str(), "http://seed.example/files/t/a%20b");
+        assert_eq!(
+            (reqs[0].start, reqs[0].offset, reqs[0].len),
+            (0, 32_768, 7_232)
+        );
+        assert_eq!(reqs[1].url.as_str(), "http://seed.example/files/t/c");
+        assert_eq!(
+            (reqs[1].start, reqs[1].offset, reqs[1].len),
+            (7_232, 0, 25_536)
+        );
+
+        let second = vec![2; 25_536];
+        let res = ws.received(&url, 1, 7_232, Ok(second), now);
+        assert_eq!(res, Received::Pending);
+        match ws.received(&url, 1, 0, Ok(vec![1; 7_232]), now) {
+            Received::Piece(0, data) => {
+                assert_eq!(data.len(), 32_768);
+                assert_eq!((data[7_231], data[7_232]), (1, 2));
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(ws.downloaded(), 32_768);
+        assert_eq!(
+            ws.received(&url, 1, 0, Ok(vec![1; 7_232]), now),
+            Received::Stale
+        );
+    }
+
+    #[test]
+    fn test_fetch_failure() {
+        let url = Arc::new(Url::parse("http://seed.example/files/").unwrap());
+        let info = info();
+        let mut ws = WebSeeds::new(vec![url.clone()]);
+        ws.update(WebSeedStrategy::Always, 0, &swarm(0, false, 0));
+        let now = Instant::now();
+
+        // Seeds are retried a while after transient errors
+        ws.start(0, 1, &info, 0);
+        let res = ws.received(&url, 0, 0, Err(ErrorKind::Timeout.into()), now);
+        assert_eq!(res, Received::Failed(0));
+        assert_eq!(ws.idle(now), None);
+        let later = now + Duration::from_secs(RETRY_SECS);
+        assert_eq!(ws.idle(later), Some(0));
+
+        // but not once they turn out to lack the files
+        ws.start(0, 1, &info, 0);
+        assert_eq!(ws.release(), vec![(0, 0)]);
+        ws.start(0, 1, &info, 0);
+        let res = ws.received(&url, 0, 0, Err(ErrorKind::HTTPStatus(404).into()), later);
+        assert_eq!(res, Received::Failed(0));
+        assert_eq!(ws.idle(later + Duration::from_secs(RETRY_SECS)), None);
+    }
+}
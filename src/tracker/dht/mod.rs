@@ -1,4 +1,4 @@
-use std::fs::OpenOptions;
+use std::fs::{self, OpenOptions};
 use std::io::{self, Read};
 use std::net::{SocketAddr, UdpSocket};
 use std::path::Path;
@@ -7,9 +7,10 @@ use std::time;
 use amy;
 use num_bigint::BigUint;
 
+use config::IpFamily;
 use disk;
 use tracker;
-use CONFIG;
+use {utp, CONFIG};
 
 mod proto;
 mod rt;
@@ -28,8 +29,11 @@ pub struct Manager {
     table: rt::RoutingTable,
     dht_flush: time::Instant,
     sock: UdpSocket,
+    enabled: bool,
     buf: Vec<u8>,
     db: amy::Sender<disk::Request>,
+    /// Node count last reported
+    nodes: usize,
 }
 
 impl Manager {
@@ -37,8 +41,11 @@ impl Manager {
         let sock = UdpSocket::bind(("0.0.0.0", CONFIG.dht.port))?;
         sock.set_nonblocking(true)?;
         let id = reg.register(&sock, amy::Event::Read)?;
-        // Turn off DHT if no bootstrap is specified.
-        if CONFIG.dht.bootstrap_node.is_none() {
+        // Turn off DHT if no bootstrap is specified, or IPv4 is disabled
+        // since only the IPv4 DHT is supported. The socket is still
+        // used for uTP if enabled.
+        let enabled = CONFIG.dht.bootstrap_node.is_some() && CONFIG.net.ip_family != IpFamily::Ipv6;
+        if !enabled && !CONFIG.peer.utp {
             reg.deregister(&sock)?;
         }
 
@@ -64,10 +71,13 @@ impl Manager {
         Ok(Manager {
             table,
             sock,
+            enabled,
             id,
             db,
-            buf: vec![0u8; 500],
+            // Large enough for uTP packets as well
+            buf: vec![0u8; 2048],
             dht_flush: time::Instant::now(),
+            nodes: 0,
         })
     }
 
@@ -82,13 +92,25 @@ impl Manager {
         self.id
     }
 
-    pub fn readable(&mut self) -> Vec<tracker::Response> {
+    pub fn sock(&self) -> &UdpSocket {
+        &self.sock
+    }
+
+    /// Handles received messages, passing uTP packets to `utp_recv`.
+    pub fn readable<F: FnMut(&[u8], SocketAddr)>(
+        &mut self,
+        mut utp_recv: F,
+    ) -> Vec<tracker::Response> {
         let mut resps = Vec::new();
         loop {
             match self.sock.recv_from(&mut self.buf[..]) {
                 Ok((v, addr)) => {
                     trace!("Processing msg from {}", addr);
-                    if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
+                    if CONFIG.peer.utp && utp::is_utp(&self.buf[..v]) {
+                        utp_recv(&self.buf[..v], addr);
+                    } else if !self.enabled {
+                        continue;
+                    } else if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
                         let resp = self.table.handle_req(req, addr).encode();
                         self.send_msg(&resp, addr);
                     } else if let Ok(resp) = proto::Response::decode(&self.buf[..v]) {
@@ -136,7 +158,8 @@ impl Manager {
         }
     }
 
-    pub fn tick(&mut self) {
+    /// Runs periodic upkeep, returning the node count if it changed.
+    pub fn tick(&mut self) -> Option<tracker::Response> {
         if self.dht_flush.elapsed() > time::Duration::from_secs(60) {
             let data = self.table.serialize();
             let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
@@ -146,6 +169,25 @@ impl Manager {
         for (req, a) in self.table.tick() {
             self.send_msg(&req.encode(), a);
         }
+        let nodes = if self.enabled {
+            self.table.node_count()
+        } else {
+            0
+        };
+        if nodes == self.nodes {
+            return None;
+        }
+        self.nodes = nodes;
+        Some(tracker::Response::DHTNodes(nodes))
+    }
+
+    /// Saves the routing table directly rather than through the disk
+    /// thread, which may already be shutting down.
+    pub fn shutdown(&mut self) {
+        let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
+        if let Err(e) = save_table(&self.table, &path) {
+            error!("Failed to save DHT routing table: {}", e);
+        }
     }
 
     fn send_msg(&mut self, msg: &[u8], addr: SocketAddr) {
@@ -162,3 +204,34 @@ impl Manager {
         }
     }
 }
+
+/// Writes the routing table through a temporary file, so that an
+/// interrupted write never replaces the previously saved table.
+fn save_table(table: &rt::RoutingTable, path: &Path) -> io::Result<()> {
+    let temp = path.with_extension("temp");
+    fs::write(&temp, table.serialize()).and_then(|_| fs::rename(&temp, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rt, save_table, SESSION_FILE};
+    use std::{env, fs, process};
+
+    #[test]
+    fn test_save_table() {
+        let dir = env::temp_dir().join(format!("synapse-dht-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SESSION_FILE);
+
+        let table = rt::RoutingTable::new();
+        save_table(&table, &path).unwrap();
+        let loaded = rt::RoutingTable::deserialize(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(loaded.serialize(), table.serialize());
+        // Only the table itself is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // Failures are reported rather than panicking
+        assert!(save_table(&table, &dir.join("missing").join("dht_data")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -459,6 +459,10 @@ impl RoutingTable {
         bincode::serialize(self).unwrap()
     }
 
+    pub fn node_count(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
     pub fn is_bootstrapped(&self) -> bool {
         self.buckets.len() >= MIN_BOOTSTRAP_BKTS
     }
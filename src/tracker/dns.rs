@@ -3,7 +3,9 @@ use std::net::{IpAddr, UdpSocket};
 
 use {adns, amy};
 
+use config::IpFamily;
 use tracker::{ErrorKind, Result};
+use CONFIG;
 
 #[derive(Debug)]
 pub struct QueryResponse {
@@ -19,15 +21,18 @@ pub struct Resolver {
 
 impl Resolver {
     pub fn new(reg: &amy::Registrar) -> io::Result<Resolver> {
-        let sock = UdpSocket::bind("0.0.0.0:0")?;
+        let (bind, family) = match CONFIG.net.ip_family {
+            IpFamily::Dual => ("0.0.0.0:0", adns::Family::Any),
+            IpFamily::Ipv4 => ("0.0.0.0:0", adns::Family::V4),
+            IpFamily::Ipv6 => ("[::]:0", adns::Family::V6),
+        };
+        let sock = UdpSocket::bind(bind)?;
         sock.set_nonblocking(true)?;
         let id = reg.register(&sock, amy::Event::Read)?;
 
-        Ok(Resolver {
-            id,
-            sock,
-            res: adns::Resolver::from_resolv()?,
-        })
+        let mut res = adns::Resolver::from_resolv()?;
+        res.set_family(family);
+        Ok(Resolver { id, sock, res })
     }
 
     pub fn new_query(&mut self, id: usize, host: &str) -> io::Result<Option<IpAddr>> {
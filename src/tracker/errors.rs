@@ -15,6 +15,11 @@ error_chain! {
             display("tracker error: {}", e)
         }
 
+        HTTPStatus(code: u16) {
+            description("unexpected HTTP status")
+            display("unexpected HTTP status {}", code)
+        }
+
         EOF {
             description("the tracker closed the connection unexpectedly")
             display("tracker EOF")
@@ -13,12 +13,11 @@ use self::reader::{ReadRes, Reader};
 use self::writer::Writer;
 use socket::TSocket;
 use tracker::{
-    self, dns, Announce, Error, ErrorKind, Response, Result, ResultExt, TrackerResponse,
+    self, dns, Announce, Error, ErrorKind, Headers, Response, Result, ResultExt, Scrape,
+    ScrapeResponse, TrackerResponse, WebSeedFetch,
 };
 use util::UHashMap;
-use {amy, bencode, PEER_ID};
-
-const TIMEOUT_MS: u64 = 5_000;
+use {amy, bencode, CONFIG, PEER_ID};
 
 pub struct Handler {
     reg: amy::Registrar,
@@ -34,11 +33,32 @@ enum Event {
 struct Tracker {
     torrent: usize,
     url: Arc<Url>,
-    last_updated: Instant,
+    /// Time by which connecting and sending the request, or once
+    /// that's done reading the whole response, has to complete
+    deadline: Instant,
+    reading: bool,
     redirect: bool,
+    headers: Headers,
+    kind: Kind,
     state: TrackerState,
 }
 
+/// What is requested from the tracker
+#[derive(Clone)]
+enum Kind {
+    Announce,
+    /// Scrape of the torrent with the given hash
+    Scrape([u8; 20]),
+    /// Range of a piece from a web seed
+    WebSeed {
+        seed: Arc<Url>,
+        piece: u32,
+        start: usize,
+        offset: u64,
+        len: usize,
+    },
+}
+
 enum TrackerState {
     Error,
     ResolvingDNS {
@@ -55,13 +75,13 @@ enum TrackerState {
         reader: Reader,
     },
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(u16, Vec<u8>),
 }
 
 enum HTTPRes {
     None,
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(u16, Vec<u8>),
 }
 
 impl TrackerState {
@@ -72,7 +92,7 @@ impl TrackerState {
     fn handle(&mut self, event: Event) -> Result<HTTPRes> {
         let s = mem::replace(self, TrackerState::Error);
         match s.next(event)? {
-            TrackerState::Complete(r) => Ok(HTTPRes::Complete(r)),
+            TrackerState::Complete(s, r) => Ok(HTTPRes::Complete(s, r)),
             TrackerState::Redirect(l) => Ok(HTTPRes::Redirect(l)),
             n => {
                 mem::replace(self, n);
@@ -121,12 +141,7 @@ impl TrackerState {
                 },
                 _,
             ) => match reader.readable(&mut sock)? {
-                ReadRes::Done(data) => {
-                    let content = bencode::decode_buf(&data)
-                        .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))?;
-                    let resp = TrackerResponse::from_bencode(content)?;
-                    Ok(TrackerState::Complete(resp))
-                }
+                ReadRes::Done(status, data) => Ok(TrackerState::Complete(status, data)),
                 ReadRes::Redirect(l) => Ok(TrackerState::Redirect(l)),
                 ReadRes::None => Ok(TrackerState::Reading { sock, reader }),
             },
@@ -136,6 +151,85 @@ impl TrackerState {
     }
 }
 
+impl Tracker {
+    fn error(&self, e: Error) -> Response {
+        match self.kind {
+            Kind::Announce => Response::Tracker {
+                tid: self.torrent,
+                url: self.url.clone(),
+                resp: Err(e),
+            },
+            Kind::Scrape(_) => Response::Scrape {
+                tid: self.torrent,
+                url: self.url.clone(),
+                resp: Err(e),
+            },
+            Kind::WebSeed {
+                ref seed,
+                piece,
+                start,
+                ..
+            } => Response::WebSeed {
+                tid: self.torrent,
+                seed: seed.clone(),
+                piece,
+                start,
+                resp: Err(e),
+            },
+        }
+    }
+
+    fn web_seed(&self) -> bool {
+        match self.kind {
+            Kind::WebSeed { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Switches to the read timeout once the request was sent. Web
+    /// seeds can take a while to send a whole piece, so their timeout
+    /// is extended as long as they keep sending.
+    fn update_deadline(&mut self) {
+        if let TrackerState::Reading { .. } = self.state {
+            if !self.reading || self.web_seed() {
+                self.reading = true;
+                self.deadline = read_deadline();
+            }
+        }
+    }
+
+    /// Parses the body of a complete response
+    fn response(&self, status: u16, data: Vec<u8>) -> Response {
+        match self.kind {
+            Kind::Announce => Response::Tracker {
+                tid: self.torrent,
+                url: self.url.clone(),
+                resp: bencode::decode_buf(&data)
+                    .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))
+                    .and_then(TrackerResponse::from_bencode),
+            },
+            Kind::Scrape(ref hash) => Response::Scrape {
+                tid: self.torrent,
+                url: self.url.clone(),
+                resp: ScrapeResponse::from_bytes(&data, hash),
+            },
+            Kind::WebSeed {
+                ref seed,
+                piece,
+                start,
+                offset,
+                len,
+            } => Response::WebSeed {
+                tid: self.torrent,
+                seed: seed.clone(),
+                piece,
+                start,
+                resp: range_body(status, data, offset, len),
+            },
+        }
+    }
+}
+
 impl Handler {
     pub fn new(reg: &amy::Registrar) -> io::Result<Handler> {
         Ok(Handler {
@@ -144,8 +238,9 @@ impl Handler {
         })
     }
 
+    /// Number of announces and scrapes in progress
     pub fn active_requests(&self) -> usize {
-        self.connections.len()
+        self.connections.values().filter(|c| !c.web_seed()).count()
     }
 
     pub fn complete(&self) -> bool {
@@ -160,14 +255,11 @@ impl Handler {
         let id = resp.id;
         debug!("Received a DNS resp for {:?}", id);
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
-            trk.last_updated = Instant::now();
-            match trk.state.handle(Event::DNSResolved(resp)) {
+            let res = trk.state.handle(Event::DNSResolved(resp));
+            trk.update_deadline();
+            match res {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.error(e)),
             }
         } else {
             None
@@ -180,14 +272,11 @@ impl Handler {
 
     pub fn writable(&mut self, id: usize) -> Option<Response> {
         let resp = if let Some(trk) = self.connections.get_mut(&id) {
-            trk.last_updated = Instant::now();
-            match trk.state.handle(Event::Writable) {
+            let res = trk.state.handle(Event::Writable);
+            trk.update_deadline();
+            match res {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.error(e)),
             }
         } else {
             None
@@ -201,26 +290,19 @@ impl Handler {
     pub fn readable(&mut self, id: usize, dns: &mut dns::Resolver) -> Option<Response> {
         let mut loc = None;
         let mut resp = if let Some(trk) = self.connections.get_mut(&id) {
-            trk.last_updated = Instant::now();
-            match trk.state.handle(Event::Readable) {
-                Ok(HTTPRes::Complete(r)) => {
-                    debug!("Announce response received for {:?} succesfully", id);
-                    Some(Response::Tracker {
-                        tid: trk.torrent,
-                        url: trk.url.clone(),
-                        resp: Ok(r),
-                    })
+            let res = trk.state.handle(Event::Readable);
+            trk.update_deadline();
+            match res {
+                Ok(HTTPRes::Complete(status, data)) => {
+                    debug!("Tracker response received for {:?} succesfully", id);
+                    Some(trk.response(status, data))
                 }
                 Ok(HTTPRes::Redirect(l)) => {
                     loc = Some((l, trk.url.clone()));
                     None
                 }
                 Ok(HTTPRes::None) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.error(e)),
             }
         } else {
             None
@@ -234,22 +316,14 @@ impl Handler {
             let trk = self.connections.remove(&id).unwrap();
             // Disallow 2 levels of redirection
             if trk.redirect {
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::InvalidResponse("Too many redirects").into()),
-                });
+                resp = Some(trk.error(ErrorKind::InvalidResponse("Too many redirects").into()));
             }
-            if let Err(e) = self.try_redirect(&l, old, trk.torrent, dns) {
+            if let Err(e) = self.try_redirect(&l, old, &trk, dns) {
                 debug!(
                     "Announce response received for {:?}, redirecting!",
                     trk.torrent
                 );
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                });
+                resp = Some(trk.error(e));
             }
         }
         resp
@@ -259,7 +333,7 @@ impl Handler {
         &mut self,
         url: &str,
         original_url: Arc<Url>,
-        torrent: usize,
+        trk: &Tracker,
         dns: &mut dns::Resolver,
     ) -> Result<()> {
         let url = match Url::parse(url) {
@@ -297,7 +371,15 @@ impl Handler {
             })?;
         let port = url.port().unwrap_or(80);
         http_req.extend_from_slice(host.as_bytes());
-        http_req.extend_from_slice(b"\r\n\r\n");
+        http_req.extend_from_slice(b"\r\n");
+        // Don't leak credentials to other hosts
+        let headers = if original_url.host_str() == Some(host) {
+            append_headers(&mut http_req, &trk.headers);
+            trk.headers.clone()
+        } else {
+            Headers::default()
+        };
+        http_req.extend_from_slice(b"\r\n");
 
         let ohost = if url.scheme() == "https" {
             Some(host.to_owned())
@@ -314,10 +396,13 @@ impl Handler {
         self.connections.insert(
             id,
             Tracker {
-                last_updated: Instant::now(),
+                deadline: connect_deadline(),
+                reading: false,
                 redirect: true,
-                torrent,
+                torrent: trk.torrent,
                 url: original_url,
+                headers,
+                kind: trk.kind.clone(),
                 state: TrackerState::new(sock, http_req, port),
             },
         );
@@ -334,15 +419,12 @@ impl Handler {
     }
 
     pub fn tick(&mut self) -> Vec<Response> {
+        let now = Instant::now();
         let mut resps = Vec::new();
         self.connections.retain(|id, trk| {
-            if trk.last_updated.elapsed() > Duration::from_millis(TIMEOUT_MS) {
-                debug!("Announce {:?} timed out", id);
-                resps.push(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::Timeout.into()),
-                });
+            if now > trk.deadline {
+                debug!("Request {:?} timed out", id);
+                resps.push(trk.error(ErrorKind::Timeout.into()));
                 false
             } else {
                 true
@@ -353,66 +435,56 @@ impl Handler {
 
     pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
         debug!("Received a new announce req for {:?}", req.url);
-        let mut http_req = Vec::with_capacity(50);
-        // Encode GET req
-        http_req.extend_from_slice(b"GET ");
+        let http_req = announce_request(&req)?;
+        self.connect(req.id, req.url, req.headers, Kind::Announce, http_req, dns)
+    }
 
-        // Encode the URL
-        http_req.extend_from_slice(req.url.path().as_bytes());
-        http_req.extend_from_slice(b"?");
-        append_query_pair(&mut http_req, "info_hash", &encode_param(&req.hash));
-        append_query_pair(&mut http_req, "peer_id", &encode_param(&PEER_ID[..]));
-        append_query_pair(&mut http_req, "uploaded", &req.uploaded.to_string());
-        append_query_pair(&mut http_req, "downloaded", &req.downloaded.to_string());
-        append_query_pair(&mut http_req, "left", &req.left.to_string());
-        append_query_pair(&mut http_req, "compact", "1");
-        append_query_pair(&mut http_req, "port", &req.port.to_string());
-        if let Some(nw) = req.num_want {
-            append_query_pair(&mut http_req, "numwant", &nw.to_string());
-        }
-        match req.event {
-            Some(tracker::Event::Started) => {
-                append_query_pair(&mut http_req, "event", "started");
-            }
-            Some(tracker::Event::Stopped) => {
-                append_query_pair(&mut http_req, "event", "stopped");
-            }
-            Some(tracker::Event::Completed) => {
-                append_query_pair(&mut http_req, "event", "completed");
-            }
-            None => {}
-        }
-        for (k, v) in req.url.query_pairs() {
-            append_query_pair(&mut http_req, &k, &v);
-        }
+    pub fn new_scrape(&mut self, req: Scrape, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new scrape req for {:?}", req.url);
+        let http_req = scrape_request(&req)?;
+        let kind = Kind::Scrape(req.hash);
+        self.connect(req.id, req.url, req.headers, kind, http_req, dns)
+    }
 
-        // Encode HTTP protocol
-        http_req.extend_from_slice(b" HTTP/1.1\r\n");
-        let user_agent = format!(
-            "User-Agent: {}/{}\r\n",
-            "synapse",
-            env!("CARGO_PKG_VERSION")
+    pub fn new_web_seed(&mut self, req: WebSeedFetch, dns: &mut dns::Resolver) -> Result<()> {
+        debug!(
+            "Fetching {} bytes of piece {} from {}",
+            req.len, req.piece, req.url
         );
-        http_req.extend_from_slice(user_agent.as_bytes());
-        // Don't keep alive
-        http_req.extend_from_slice(b"Connection: close\r\n");
-        // Encode host header
-        http_req.extend_from_slice(b"Host: ");
-        let host = req.url.host_str().ok_or_else(|| {
+        // Kept on redirects to the same host, like any other header
+        let range = format!("bytes={}-{}", req.offset, req.offset + req.len as u64 - 1);
+        let headers = Headers(vec![("Range".to_owned(), range)]);
+        let http_req = web_seed_request(&req.url, &headers)?;
+        let kind = Kind::WebSeed {
+            seed: req.seed,
+            piece: req.piece,
+            start: req.start,
+            offset: req.offset,
+            len: req.len,
+        };
+        self.connect(req.id, Arc::new(req.url), headers, kind, http_req, dns)
+    }
+
+    /// Sends `http_req` to the host of the tracker at `url`
+    fn connect(
+        &mut self,
+        torrent: usize,
+        url: Arc<Url>,
+        headers: Headers,
+        kind: Kind,
+        http_req: Vec<u8>,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        let host = url.host_str().ok_or_else(|| {
             Error::from(ErrorKind::InvalidRequest(
                 "Tracker announce url has no host!".to_owned(),
             ))
         })?;
-        let port =
-            req.url
-                .port()
-                .unwrap_or_else(|| if req.url.scheme() == "https" { 443 } else { 80 });
-        http_req.extend_from_slice(host.as_bytes());
-        http_req.extend_from_slice(b"\r\n");
-        // Encode empty line to terminate request
-        http_req.extend_from_slice(b"\r\n");
+        let port = url
+            .port()
+            .unwrap_or_else(|| if url.scheme() == "https" { 443 } else { 80 });
 
-        let ohost = if req.url.scheme() == "https" {
+        let ohost = if url.scheme() == "https" {
             Some(host.to_owned())
         } else {
             None
@@ -427,11 +499,14 @@ impl Handler {
         self.connections.insert(
             id,
             Tracker {
-                url: req.url.clone(),
-                last_updated: Instant::now(),
-                torrent: req.id,
+                url: url.clone(),
+                deadline: connect_deadline(),
+                reading: false,
+                torrent,
                 state: TrackerState::new(sock, http_req, port),
                 redirect: false,
+                headers,
+                kind,
             },
         );
 
@@ -448,6 +523,144 @@ impl Handler {
     }
 }
 
+fn connect_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(CONFIG.trk.connect_timeout_secs)
+}
+
+fn read_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(CONFIG.trk.read_timeout_secs)
+}
+
+/// Encodes the HTTP request for an announce.
+fn announce_request(req: &Announce) -> Result<Vec<u8>> {
+    let mut http_req = Vec::with_capacity(50);
+    // Encode GET req
+    http_req.extend_from_slice(b"GET ");
+
+    // Encode the URL
+    http_req.extend_from_slice(req.url.path().as_bytes());
+    http_req.extend_from_slice(b"?");
+    append_query_pair(&mut http_req, "info_hash", &encode_param(&req.hash));
+    append_query_pair(&mut http_req, "peer_id", &encode_param(&PEER_ID[..]));
+    append_query_pair(&mut http_req, "uploaded", &req.uploaded.to_string());
+    append_query_pair(&mut http_req, "downloaded", &req.downloaded.to_string());
+    append_query_pair(&mut http_req, "left", &req.left.to_string());
+    append_query_pair(&mut http_req, "compact", "1");
+    append_query_pair(&mut http_req, "port", &req.port.to_string());
+    append_query_pair(&mut http_req, "key", &format!("{:08x}", req.key));
+    if let Some(nw) = req.num_want {
+        append_query_pair(&mut http_req, "numwant", &nw.to_string());
+    }
+    match req.event {
+        Some(tracker::Event::Started) => {
+            append_query_pair(&mut http_req, "event", "started");
+        }
+        Some(tracker::Event::Stopped) => {
+            append_query_pair(&mut http_req, "event", "stopped");
+        }
+        Some(tracker::Event::Completed) => {
+            append_query_pair(&mut http_req, "event", "completed");
+        }
+        None => {}
+    }
+    if let Some(ref id) = req.tracker_id {
+        append_query_pair(&mut http_req, "trackerid", &encode_param(id));
+    }
+    if let Some(ip) = req.ipv6 {
+        let ip = ip.to_string();
+        append_query_pair(&mut http_req, "ipv6", &encode_param(ip.as_bytes()));
+    }
+    for (k, v) in req.url.query_pairs() {
+        append_query_pair(&mut http_req, &k, &v);
+    }
+    append_http(&mut http_req, &req.url, &req.headers)?;
+    Ok(http_req)
+}
+
+/// Encodes the HTTP request for a scrape.
+fn scrape_request(req: &Scrape) -> Result<Vec<u8>> {
+    let url = tracker::scrape_url(&req.url).ok_or_else(|| {
+        Error::from(ErrorKind::InvalidRequest(
+            "Tracker does not support scraping!".to_owned(),
+        ))
+    })?;
+    let mut http_req = Vec::with_capacity(50);
+    http_req.extend_from_slice(b"GET ");
+    http_req.extend_from_slice(url.path().as_bytes());
+    http_req.extend_from_slice(b"?");
+    append_query_pair(&mut http_req, "info_hash", &encode_param(&req.hash));
+    for (k, v) in url.query_pairs() {
+        append_query_pair(&mut http_req, &k, &v);
+    }
+    append_http(&mut http_req, &url, &req.headers)?;
+    Ok(http_req)
+}
+
+/// Encodes the HTTP request for a range of a file from a web seed.
+fn web_seed_request(url: &Url, headers: &Headers) -> Result<Vec<u8>> {
+    let mut http_req = Vec::with_capacity(50);
+    http_req.extend_from_slice(b"GET ");
+    http_req.extend_from_slice(url.path().as_bytes());
+    if let Some(q) = url.query() {
+        http_req.extend_from_slice(b"?");
+        http_req.extend_from_slice(q.as_bytes());
+    }
+    append_http(&mut http_req, url, headers)?;
+    Ok(http_req)
+}
+
+/// Extracts the requested range from a web seed's response, which is
+/// either just the range, or the whole file if the server doesn't
+/// support range requests.
+fn range_body(status: u16, mut data: Vec<u8>, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let end = offset + len as u64;
+    match status {
+        206 if data.len() == len => Ok(data),
+        200 if data.len() as u64 >= end => {
+            data.truncate(end as usize);
+            Ok(data.split_off(offset as usize))
+        }
+        200 | 206 => Err(ErrorKind::InvalidResponse("Truncated web seed response").into()),
+        s => Err(ErrorKind::HTTPStatus(s).into()),
+    }
+}
+
+/// Terminates the request line started in `http_req` and encodes the headers.
+fn append_http(http_req: &mut Vec<u8>, url: &Url, headers: &Headers) -> Result<()> {
+    // Encode HTTP protocol
+    http_req.extend_from_slice(b" HTTP/1.1\r\n");
+    let user_agent = format!(
+        "User-Agent: {}/{}\r\n",
+        "synapse",
+        env!("CARGO_PKG_VERSION")
+    );
+    http_req.extend_from_slice(user_agent.as_bytes());
+    // Don't keep alive
+    http_req.extend_from_slice(b"Connection: close\r\n");
+    // Encode host header
+    http_req.extend_from_slice(b"Host: ");
+    let host = url.host_str().ok_or_else(|| {
+        Error::from(ErrorKind::InvalidRequest(
+            "Tracker announce url has no host!".to_owned(),
+        ))
+    })?;
+    http_req.extend_from_slice(host.as_bytes());
+    http_req.extend_from_slice(b"\r\n");
+    append_headers(http_req, headers);
+    // Encode empty line to terminate request
+    http_req.extend_from_slice(b"\r\n");
+    Ok(())
+}
+
+fn append_headers(s: &mut Vec<u8>, headers: &Headers) {
+    for (name, value) in headers.iter() {
+        s.extend_from_slice(name.as_bytes());
+        s.extend_from_slice(b": ");
+        s.extend_from_slice(value.as_bytes());
+        s.extend_from_slice(b"\r\n");
+    }
+}
+
 fn append_query_pair(s: &mut Vec<u8>, k: &str, v: &str) {
     s.extend_from_slice(k.as_bytes());
     s.extend_from_slice(b"=");
@@ -467,3 +680,211 @@ fn encode_param(data: &[u8]) -> String {
     }
     resp
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        announce_request, range_body, scrape_request, web_seed_request, Handler, Kind, Tracker,
+        TrackerState,
+    };
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tracker::{Announce, ErrorKind, Event, Headers, Response, Scrape, TrackerResponse};
+    use url::Url;
+    use {amy, bencode};
+
+    #[test]
+    fn test_announce_headers() {
+        let headers = Headers::new(vec![
+            ("Cookie".to_owned(), "uid=1; pass=secret".to_owned()),
+            ("X-Api-Key".to_owned(), "key".to_owned()),
+        ])
+        .unwrap();
+        let req = Announce {
+            id: 0,
+            url: Arc::new(Url::parse("http://tracker.example/announce").unwrap()),
+            hash: [0u8; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            num_want: None,
+            event: None,
+            headers,
+            tracker_id: None,
+            ipv6: None,
+            stable_key: false,
+            key: 0xDEAD_BEEF,
+        };
+        let http_req = String::from_utf8(announce_request(&req).unwrap()).unwrap();
+        assert!(http_req.contains("\r\nHost: tracker.example\r\n"));
+        assert!(http_req.contains("\r\nCookie: uid=1; pass=secret\r\n"));
+        assert!(http_req.ends_with("\r\nX-Api-Key: key\r\n\r\n"));
+
+        // Secrets aren't exposed through debug output
+        assert!(!format!("{:?}", req).contains("secret"));
+        assert!(Headers::new(vec![("Cookie".to_owned(), "a\r\nHost: evil".to_owned())]).is_none());
+    }
+
+    #[test]
+    fn test_announce_event() {
+        let mut req = Announce {
+            id: 0,
+            url: Arc::new(Url::parse("http://tracker.example/announce").unwrap()),
+            hash: [0u8; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            num_want: None,
+            event: Some(Event::Completed),
+            headers: Headers::default(),
+            tracker_id: None,
+            ipv6: None,
+            stable_key: false,
+            key: 0xDEAD_BEEF,
+        };
+        let http_req = String::from_utf8(announce_request(&req).unwrap()).unwrap();
+        assert!(http_req.contains("&event=completed"));
+        assert!(http_req.contains("&key=deadbeef&"));
+
+        req.event = None;
+        let http_req = String::from_utf8(announce_request(&req).unwrap()).unwrap();
+        assert!(!http_req.contains("event="));
+    }
+
+    #[test]
+    fn test_tracker_id() {
+        let mut req = Announce {
+            id: 0,
+            url: Arc::new(Url::parse("http://tracker.example/announce").unwrap()),
+            hash: [0u8; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            num_want: None,
+            event: Some(Event::Started),
+            headers: Headers::default(),
+            tracker_id: None,
+            ipv6: None,
+            stable_key: false,
+            key: 0xDEAD_BEEF,
+        };
+        let http_req = String::from_utf8(announce_request(&req).unwrap()).unwrap();
+        assert!(!http_req.contains("trackerid="));
+
+        let resp = bencode::decode_buf(b"d8:intervali1800e10:tracker id6:ab c/1e").unwrap();
+        let resp = TrackerResponse::from_bencode(resp).unwrap();
+        assert_eq!(resp.tracker_id, Some(b"ab c/1".to_vec()));
+
+        // The id is echoed on the next announce
+        req.event = None;
+        req.tracker_id = resp.tracker_id;
+        let http_req = String::from_utf8(announce_request(&req).unwrap()).unwrap();
+        assert!(http_req.contains("&trackerid=ab%20c%2F1&"));
+    }
+
+    #[test]
+    fn test_announce_ipv6() {
+        let mut req = Announce {
+            id: 0,
+            url: Arc::new(Url::parse("http://tracker.example/announce").unwrap()),
+            hash: [0u8; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            num_want: None,
+            event: None,
+            headers: Headers::default(),
+            tracker_id: None,
+            ipv6: None,
+            stable_key: false,
+            key: 0xDEAD_BEEF,
+        };
+        let http_req = String::from_utf8(announce_request(&req).unwrap()).unwrap();
+        assert!(!http_req.contains("ipv6="));
+
+        req.ipv6 = Some("2001:db8::1".parse().unwrap());
+        let http_req = String::from_utf8(announce_request(&req).unwrap()).unwrap();
+        assert!(http_req.contains("&ipv6=2001%3Adb8%3A%3A1&"));
+    }
+
+    #[test]
+    fn test_scrape_request() {
+        let mut req = Scrape {
+            id: 0,
+            url: Arc::new(Url::parse("http://tracker.example/a/announce?passkey=k").unwrap()),
+            hash: [0xFFu8; 20],
+            headers: Headers::default(),
+        };
+        let http_req = String::from_utf8(scrape_request(&req).unwrap()).unwrap();
+        assert!(http_req.starts_with(&format!(
+            "GET /a/scrape?info_hash={}&passkey=k& HTTP/1.1\r\n",
+            "%FF".repeat(20)
+        )));
+        assert!(http_req.contains("\r\nHost: tracker.example\r\n"));
+
+        req.url = Arc::new(Url::parse("http://tracker.example/a").unwrap());
+        assert!(scrape_request(&req).is_err());
+    }
+
+    #[test]
+    fn test_web_seed_range() {
+        let url = Url::parse("http://seed.example/files/a%20b/c?token=t").unwrap();
+        let headers = Headers::new(vec![("Range".to_owned(), "bytes=10-19".to_owned())]).unwrap();
+        let http_req = String::from_utf8(web_seed_request(&url, &headers).unwrap()).unwrap();
+        assert!(http_req.starts_with("GET /files/a%20b/c?token=t HTTP/1.1\r\n"));
+        assert!(http_req.ends_with("\r\nRange: bytes=10-19\r\n\r\n"));
+
+        let file: Vec<u8> = (0..30).collect();
+        let range = file[10..20].to_vec();
+        assert_eq!(range_body(206, range.clone(), 10, 10).unwrap(), range);
+        // Servers ignoring the range send the whole file
+        assert_eq!(range_body(200, file.clone(), 10, 10).unwrap(), range);
+        assert!(range_body(200, file.clone(), 25, 10).is_err());
+        assert!(range_body(206, file, 10, 10).is_err());
+        match *range_body(404, Vec::new(), 10, 10).unwrap_err().kind() {
+            ErrorKind::HTTPStatus(404) => {}
+            ref k => panic!("unexpected error {:?}", k),
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let poll = amy::Poller::new().unwrap();
+        let mut h = Handler::new(&poll.get_registrar()).unwrap();
+        let now = Instant::now();
+        let tracker = |torrent, deadline| Tracker {
+            torrent,
+            url: Arc::new(Url::parse("http://tracker.example/announce").unwrap()),
+            deadline,
+            reading: true,
+            redirect: false,
+            headers: Headers::default(),
+            kind: Kind::Announce,
+            state: TrackerState::Error,
+        };
+        // A tracker past its read deadline, and one still within it
+        let (past, future) = (now - Duration::from_secs(1), now + Duration::from_secs(60));
+        h.connections.insert(0, tracker(0, past));
+        h.connections.insert(1, tracker(1, future));
+
+        let resps = h.tick();
+        assert_eq!(resps.len(), 1);
+        match resps[0] {
+            Response::Tracker {
+                tid: 0,
+                resp: Err(ref e),
+                ..
+            } => match *e.kind() {
+                ErrorKind::Timeout => {}
+                ref k => panic!("unexpected error {:?}", k),
+            },
+            _ => panic!("expected a timed out announce"),
+        }
+        assert!(!h.contains(0));
+        assert!(h.contains(1));
+    }
+}
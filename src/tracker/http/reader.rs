@@ -10,11 +10,14 @@ pub struct Reader {
     data: Vec<u8>,
     idx: usize,
     state: ReadState,
+    /// Status code of the response, once its header is read
+    status: u16,
 }
 
 pub enum ReadRes {
     None,
-    Done(Vec<u8>),
+    /// Status code and body of the response
+    Done(u16, Vec<u8>),
     Redirect(String),
 }
 
@@ -29,6 +32,7 @@ impl Reader {
             data: vec![0; 75],
             idx: 0,
             state: ReadState::Header,
+            status: 0,
         }
     }
 
@@ -45,7 +49,8 @@ impl Reader {
                     let mut header_done = None;
                     match self.state {
                         ReadState::Header => {
-                            let mut headers = [httparse::EMPTY_HEADER; 16];
+                            // Web servers tend to send more headers than trackers
+                            let mut headers = [httparse::EMPTY_HEADER; 32];
                             let mut resp = httparse::Response::new(&mut headers);
                             match resp.parse(&self.data[..self.idx]) {
                                 Ok(httparse::Status::Complete(i)) => {
@@ -70,6 +75,7 @@ impl Reader {
                                         }
                                         return Ok(ReadRes::Redirect(loc.unwrap()));
                                     }
+                                    self.status = resp.code.unwrap_or(0);
                                     header_done = Some(i);
                                 }
                                 Ok(httparse::Status::Partial) => {}
@@ -92,7 +98,7 @@ impl Reader {
                     ReadState::Body => {
                         let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
                         data.truncate(self.idx);
-                        return Ok(ReadRes::Done(data));
+                        return Ok(ReadRes::Done(self.status, data));
                     }
                     _ => return Err(ErrorKind::EOF.into()),
                 },
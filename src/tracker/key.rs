@@ -0,0 +1,127 @@
+use bincode;
+use chrono::{DateTime, Duration, Utc};
+use rand;
+
+use config::KeyRotation;
+
+/// The key sent in announces, which lets trackers recognise us
+/// across IP changes. Trackers needing a key which never changes are
+/// sent a separate stable one, whatever the rotation policy.
+pub struct AnnounceKey {
+    data: KeyData,
+    policy: KeyRotation,
+    interval: Duration,
+    /// Whether the keys changed since they were last saved
+    changed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyData {
+    stable: u32,
+    current: u32,
+    rotated: DateTime<Utc>,
+}
+
+impl AnnounceKey {
+    /// Restores the keys saved in `data`, generating them if there
+    /// are none, and rotates the current key if the policy calls for
+    /// a new one every session.
+    pub fn new(data: &[u8], policy: KeyRotation, interval: Duration, now: DateTime<Utc>) -> Self {
+        let (data, changed) = match bincode::deserialize::<KeyData>(data) {
+            Ok(mut d) => {
+                if policy == KeyRotation::Session {
+                    d.current = rand::random();
+                    d.rotated = now;
+                }
+                (d, policy == KeyRotation::Session)
+            }
+            Err(_) => {
+                let d = KeyData {
+                    stable: rand::random(),
+                    current: rand::random(),
+                    rotated: now,
+                };
+                (d, true)
+            }
+        };
+        AnnounceKey {
+            data,
+            policy,
+            interval,
+            changed,
+        }
+    }
+
+    /// Returns the key to announce with, rotating it first if it's
+    /// past the rotation interval. `stable` forces the stable key.
+    pub fn key(&mut self, stable: bool, now: DateTime<Utc>) -> u32 {
+        if stable || self.policy == KeyRotation::Stable {
+            return self.data.stable;
+        }
+        if self.policy == KeyRotation::Interval && now - self.data.rotated >= self.interval {
+            debug!("Rotating announce key");
+            self.data.current = rand::random();
+            self.data.rotated = now;
+            self.changed = true;
+        }
+        self.data.current
+    }
+
+    /// When the current key was picked, or `None` if keys don't rotate
+    pub fn rotated(&self) -> Option<DateTime<Utc>> {
+        if self.policy == KeyRotation::Stable {
+            None
+        } else {
+            Some(self.data.rotated)
+        }
+    }
+
+    /// Returns the serialized keys if they changed since last called
+    pub fn take_changes(&mut self) -> Option<Vec<u8>> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        Some(bincode::serialize(&self.data).expect("Announce key serialization failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AnnounceKey;
+    use chrono::{Duration, Utc};
+    use config::KeyRotation;
+
+    #[test]
+    fn test_key_rotation() {
+        let now = Utc::now();
+        let hour = Duration::hours(1);
+        let mut stable = AnnounceKey::new(&[], KeyRotation::Stable, hour, now);
+        let k = stable.key(false, now);
+        let data = stable.take_changes().unwrap();
+        assert!(stable.take_changes().is_none());
+
+        // Restarting keeps a stable key
+        let mut stable = AnnounceKey::new(&data, KeyRotation::Stable, hour, now);
+        assert_eq!(stable.key(false, now), k);
+        assert!(stable.take_changes().is_none());
+
+        // but every session gets a new one otherwise, bar for
+        // trackers needing a stable key
+        let mut session = AnnounceKey::new(&data, KeyRotation::Session, hour, now);
+        let k1 = session.key(false, now);
+        let data = session.take_changes().unwrap();
+        let mut session = AnnounceKey::new(&data, KeyRotation::Session, hour, now);
+        assert_ne!(session.key(false, now), k1);
+        assert_eq!(session.key(true, now), k);
+
+        let mut interval = AnnounceKey::new(&data, KeyRotation::Interval, hour, now);
+        assert!(interval.take_changes().is_none());
+        let k = interval.key(false, now);
+        assert_eq!(interval.key(false, now + Duration::minutes(59)), k);
+        assert!(interval.take_changes().is_none());
+        interval.key(false, now + hour);
+        assert_eq!(interval.rotated(), Some(now + hour));
+        assert!(interval.take_changes().is_some());
+    }
+}
@@ -0,0 +1,181 @@
+//! Local Service Discovery (BEP 14), finding peers on the LAN through
+//! multicast announces.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time;
+
+use amy;
+use httparse;
+use net2::UdpBuilder;
+
+use tracker::Response;
+use util::{hash_to_id, id_to_hash, random_string, MHashMap};
+
+const LSD_ADDR: [u8; 4] = [239, 192, 152, 143];
+const LSD_PORT: u16 = 6771;
+/// BEP 14 asks for no more than one announce per torrent a minute
+const MIN_INTERVAL_SECS: u64 = 60;
+
+pub struct Manager {
+    id: usize,
+    sock: UdpSocket,
+    /// Sent with our announces, so they can be told apart when looped back
+    cookie: String,
+    /// Time each torrent was last announced
+    announced: MHashMap<[u8; 20], time::Instant>,
+    buf: Vec<u8>,
+}
+
+/// Announce of torrents received from a peer on the LAN
+#[derive(Debug, PartialEq)]
+struct Search {
+    port: u16,
+    hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+impl Manager {
+    pub fn new(reg: &amy::Registrar) -> io::Result<Manager> {
+        // Other clients on this host may be listening as well
+        let sock = UdpBuilder::new_v4()?
+            .reuse_address(true)?
+            .bind(("0.0.0.0", LSD_PORT))?;
+        sock.join_multicast_v4(&Ipv4Addr::from(LSD_ADDR), &Ipv4Addr::new(0, 0, 0, 0))?;
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        Ok(Manager {
+            id,
+            sock,
+            cookie: random_string(8),
+            announced: MHashMap::default(),
+            buf: vec![0u8; 1500],
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Announces that we have a torrent, listening on `port`
+    pub fn announce(&mut self, hash: [u8; 20], port: u16) {
+        let now = time::Instant::now();
+        if let Some(prev) = self.announced.get(&hash) {
+            if now.duration_since(*prev) < time::Duration::from_secs(MIN_INTERVAL_SECS) {
+                return;
+            }
+        }
+        self.announced.insert(hash, now);
+        let msg = encode(port, &[hash], &self.cookie);
+        let dest = SocketAddrV4::new(Ipv4Addr::from(LSD_ADDR), LSD_PORT);
+        if let Err(e) = self.sock.send_to(&msg, dest) {
+            debug!("Failed to send LSD announce: {}", e);
+        }
+    }
+
+    pub fn readable(&mut self) -> Vec<Response> {
+        let mut resps = Vec::new();
+        while let Ok((len, addr)) = self.sock.recv_from(&mut self.buf[..]) {
+            let search = match parse(&self.buf[..len]) {
+                Some(s) => s,
+                None => {
+                    debug!("Received invalid LSD announce from {}", addr);
+                    continue;
+                }
+            };
+            if search.cookie.as_ref() == Some(&self.cookie) {
+                continue;
+            }
+            let peer = SocketAddr::new(addr.ip(), search.port);
+            for hash in search.hashes {
+                resps.push(Response::LSD { hash, peer });
+            }
+        }
+        resps
+    }
+}
+
+fn encode(port: u16, hashes: &[[u8; 20]], cookie: &str) -> Vec<u8> {
+    let mut msg = format!(
+        "BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\n",
+        Ipv4Addr::from(LSD_ADDR),
+        LSD_PORT,
+        port
+    );
+    for hash in hashes {
+        msg.push_str(&format!("Infohash: {}\r\n", hash_to_id(hash)));
+    }
+    msg.push_str(&format!("cookie: {}\r\n\r\n\r\n", cookie));
+    msg.into_bytes()
+}
+
+fn parse(data: &[u8]) -> Option<Search> {
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(data) {
+        Ok(httparse::Status::Complete(_)) => {}
+        _ => return None,
+    }
+    if req.method != Some("BT-SEARCH") {
+        return None;
+    }
+    let mut search = Search {
+        port: 0,
+        hashes: Vec::new(),
+        cookie: None,
+    };
+    for header in req.headers.iter() {
+        let value = match ::std::str::from_utf8(header.value) {
+            Ok(v) if v.is_ascii() => v.trim(),
+            _ => continue,
+        };
+        if header.name.eq_ignore_ascii_case("port") {
+            search.port = value.parse().ok()?;
+        } else if header.name.eq_ignore_ascii_case("infohash") {
+            search.hashes.push(id_to_hash(value)?);
+        } else if header.name.eq_ignore_ascii_case("cookie") {
+            search.cookie = Some(value.to_owned());
+        }
+    }
+    if search.port == 0 || search.hashes.is_empty() {
+        return None;
+    }
+    Some(search)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, parse, Search};
+
+    #[test]
+    fn test_roundtrip() {
+        let hashes = [[0xABu8; 20], [1u8; 20]];
+        let msg = encode(6881, &hashes, "cookie1");
+        assert_eq!(
+            parse(&msg),
+            Some(Search {
+                port: 6881,
+                hashes: hashes.to_vec(),
+                cookie: Some("cookie1".to_owned()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let msg = |method: &str, port: &str, hash: &str| {
+            let msg = format!("{} * HTTP/1.1\r\nPort: {}\r\n{}\r\n", method, port, hash);
+            parse(msg.as_bytes())
+        };
+        let hash = "Infohash: abababababababababababababababababababab\r\n";
+        assert_eq!(
+            msg("BT-SEARCH", "1", hash).unwrap().hashes,
+            vec![[0xABu8; 20]]
+        );
+
+        assert_eq!(msg("NOTIFY", "1", hash), None);
+        assert_eq!(msg("BT-SEARCH", "x", hash), None);
+        assert_eq!(msg("BT-SEARCH", "1", ""), None);
+        assert_eq!(msg("BT-SEARCH", "1", "Infohash: ab\r\n"), None);
+    }
+}
@@ -2,33 +2,45 @@ mod dht;
 mod dns;
 mod errors;
 mod http;
+mod key;
+mod lsd;
 mod udp;
 
 use std::collections::VecDeque;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::Arc;
-use std::{io, result, thread};
+use std::{fmt, fs, io, result, thread};
 
 use amy;
 use byteorder::{BigEndian, ByteOrder};
+use chrono::{DateTime, Duration, Utc};
 use url::Url;
 
 pub use self::errors::{Error, ErrorKind, Result, ResultExt};
 use bencode::BEncode;
+use config::IpFamily;
 use control::cio;
 use disk;
 use handle;
 use torrent::Torrent;
-use CONFIG;
+use {bencode, util, utp, CONFIG};
 
 pub struct Tracker {
     poll: amy::Poller,
     ch: handle::Handle<Request, Response>,
     http: http::Handler,
-    queue: VecDeque<Announce>,
+    /// Announces and scrapes waiting for an open request slot
+    queue: VecDeque<Request>,
     udp: udp::Handler,
     dht: dht::Manager,
+    /// Local service discovery, if enabled
+    lsd: Option<lsd::Manager>,
+    utp: utp::Mux,
     dns: dns::Resolver,
+    key: key::AnnounceKey,
+    db: amy::Sender<disk::Request>,
     timer: usize,
     shutting_down: bool,
 }
@@ -36,9 +48,16 @@ pub struct Tracker {
 #[derive(Debug)]
 pub enum Request {
     Announce(Announce),
+    Scrape(Scrape),
     GetPeers(GetPeers),
     AddNode(SocketAddr),
     DHTAnnounce([u8; 20]),
+    /// Announces a torrent to peers on the LAN
+    LSDAnnounce([u8; 20]),
+    /// Connects to a peer over uTP, relaying data written
+    /// to the other end of the stream
+    UtpConnect(SocketAddr, UnixStream),
+    WebSeed(WebSeedFetch),
     PurgeDNS,
     Ping,
     Shutdown,
@@ -55,6 +74,46 @@ pub struct Announce {
     left: u64,
     num_want: Option<u16>,
     event: Option<Event>,
+    headers: Headers,
+    /// Tracker id from a previous response, echoed back
+    tracker_id: Option<Vec<u8>>,
+    /// Our global IPv6 address, announced as per BEP 7
+    ipv6: Option<Ipv6Addr>,
+    /// Whether the tracker must be sent the stable key
+    stable_key: bool,
+    /// Set by the tracker thread before the request is sent
+    key: u32,
+}
+
+/// Request for a tracker's statistics on a torrent
+#[derive(Debug)]
+pub struct Scrape {
+    id: usize,
+    /// Announce URL of the tracker, the scrape URL is derived from it
+    url: Arc<Url>,
+    hash: [u8; 20],
+    headers: Headers,
+}
+
+/// Additional HTTP headers sent to a tracker, e.g. cookies used
+/// for authentication. Values are secret and are never logged.
+#[derive(Clone, Default, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+/// Range request of part of a piece from a BEP 19 web seed
+#[derive(Debug)]
+pub struct WebSeedFetch {
+    pub id: usize,
+    /// Web seed the range is fetched from
+    pub seed: Arc<Url>,
+    /// URL of the file holding the range
+    pub url: Url,
+    pub piece: u32,
+    /// Offset of the range in the piece
+    pub start: usize,
+    /// Offset of the range in the file
+    pub offset: u64,
+    pub len: usize,
 }
 
 #[derive(Debug)]
@@ -77,6 +136,11 @@ pub enum Response {
         url: Arc<Url>,
         resp: Result<TrackerResponse>,
     },
+    Scrape {
+        tid: usize,
+        url: Arc<Url>,
+        resp: Result<ScrapeResponse>,
+    },
     DHT {
         tid: usize,
         peers: Vec<SocketAddr>,
@@ -85,17 +149,59 @@ pub enum Response {
         tid: usize,
         peers: Vec<SocketAddr>,
     },
+    /// Peer on the LAN announcing a torrent
+    LSD {
+        hash: [u8; 20],
+        peer: SocketAddr,
+    },
+    /// Incoming uTP connection, relayed through the stream
+    Utp {
+        conn: UnixStream,
+        addr: SocketAddr,
+    },
+    /// Number of nodes in the DHT routing table, sent when it changes
+    DHTNodes(usize),
+    /// The announce key was generated or rotated, at the given time
+    /// if it rotates
+    AnnounceKey(Option<DateTime<Utc>>),
+    /// Data of a range fetched from a web seed
+    WebSeed {
+        tid: usize,
+        seed: Arc<Url>,
+        piece: u32,
+        start: usize,
+        resp: Result<Vec<u8>>,
+    },
 }
 
 #[derive(Debug)]
 pub struct TrackerResponse {
     pub peers: Vec<SocketAddr>,
     pub interval: u32,
+    /// Minimum time in seconds between announces
+    pub min_interval: Option<u32>,
     pub leechers: u32,
     pub seeders: u32,
+    /// Our address as seen by the tracker
+    pub external_ip: Option<IpAddr>,
+    /// Non fatal advisory returned by the tracker
+    pub warning: Option<String>,
+    /// Id to send on the following announces to the tracker
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+/// Statistics of a torrent returned by a tracker scrape
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScrapeResponse {
+    pub seeders: u32,
+    pub leechers: u32,
+    /// Number of times the torrent was completed
+    pub downloaded: u32,
 }
 
 const POLL_INT_MS: usize = 1000;
+/// Session file holding the announce keys
+const KEY_FILE: &str = "trk_key";
 
 impl Tracker {
     pub fn start(
@@ -107,17 +213,37 @@ impl Tracker {
         let (ch, dh) = handle::Handle::new(creg, &mut reg)?;
         let timer = reg.set_interval(150)?;
         let udp = udp::Handler::new(&reg)?;
-        let dht = dht::Manager::new(&reg, db)?;
+        let dht = dht::Manager::new(&reg, db.clone())?;
+        let utp = utp::Mux::new(dht.sock().try_clone()?, &reg);
+        let lsd = if CONFIG.net.lsd && CONFIG.net.ip_family != IpFamily::Ipv6 {
+            lsd::Manager::new(&reg)
+                .map_err(|e| warn!("Failed to start local service discovery: {}", e))
+                .ok()
+        } else {
+            None
+        };
         let http = http::Handler::new(&reg)?;
         let dns = dns::Resolver::new(&reg)?;
+        let key_data =
+            fs::read(Path::new(&CONFIG.disk.session[..]).join(KEY_FILE)).unwrap_or_default();
+        let key = key::AnnounceKey::new(
+            &key_data,
+            CONFIG.trk.key_rotation,
+            Duration::hours(CONFIG.trk.key_rotation_hours as i64),
+            Utc::now(),
+        );
         let th = dh.run("trk", move |h| {
             Tracker {
                 poll,
                 ch: h,
                 udp,
                 dht,
+                lsd,
+                utp,
                 http,
                 dns,
+                key,
+                db,
                 timer,
                 queue: VecDeque::new(),
                 shutting_down: false,
@@ -129,6 +255,7 @@ impl Tracker {
 
     pub fn run(&mut self) {
         self.dht.init();
+        self.save_key();
 
         debug!("Initialized!");
         'outer: loop {
@@ -147,6 +274,8 @@ impl Tracker {
         }
 
         self.shutting_down = true;
+        // Nodes learned since the last periodic flush would be lost otherwise
+        self.dht.shutdown();
 
         // Shutdown loop - wait for all requests to complete
         loop {
@@ -176,6 +305,7 @@ impl Tracker {
         while let Ok(r) = self.ch.recv() {
             match r {
                 Request::Announce(req) => self.handle_announce(req),
+                Request::Scrape(req) => self.handle_scrape(req),
                 Request::GetPeers(gp) => {
                     trace!("Handling dht peer find req!");
                     self.dht.get_peers(gp.id, gp.hash);
@@ -188,6 +318,16 @@ impl Tracker {
                     trace!("Handling dht announce req!");
                     self.dht.announce(hash);
                 }
+                Request::LSDAnnounce(hash) => {
+                    if let Some(ref mut lsd) = self.lsd {
+                        lsd.announce(hash, CONFIG.port);
+                    }
+                }
+                Request::UtpConnect(addr, conn) => {
+                    trace!("Handling utp connect req!");
+                    self.utp.connect(addr, conn);
+                }
+                Request::WebSeed(req) => self.handle_web_seed(req),
                 Request::Ping => {}
                 Request::PurgeDNS => {
                     self.dns.res.purge();
@@ -200,12 +340,18 @@ impl Tracker {
         Ok(())
     }
 
+    fn slots_full(&self) -> bool {
+        self.udp.active_requests() + self.http.active_requests() > CONFIG.net.max_open_announces
+    }
+
     fn handle_announce(&mut self, req: Announce) {
         debug!("Handling announce request!");
-        if self.udp.active_requests() + self.http.active_requests() > CONFIG.net.max_open_announces
-        {
-            self.queue.push_back(req);
+        if self.slots_full() {
+            self.queue.push_back(Request::Announce(req));
         } else {
+            let mut req = req;
+            req.key = self.key.key(req.stable_key, Utc::now());
+            self.save_key();
             let id = req.id;
             let url = req.url.clone();
             let response = match url.scheme() {
@@ -225,10 +371,51 @@ impl Tracker {
         }
     }
 
+    fn handle_scrape(&mut self, req: Scrape) {
+        debug!("Handling scrape request!");
+        if self.slots_full() {
+            self.queue.push_back(Request::Scrape(req));
+        } else {
+            let id = req.id;
+            let url = req.url.clone();
+            let response = match url.scheme() {
+                "http" | "https" => self.http.new_scrape(req, &mut self.dns),
+                "udp" => self.udp.new_scrape(req, &mut self.dns),
+                s => Err(
+                    ErrorKind::InvalidRequest(format!("Unknown tracker url scheme: {}", s)).into(),
+                ),
+            };
+            if let Err(e) = response {
+                self.send_response(Response::Scrape {
+                    tid: id,
+                    url,
+                    resp: Err(e),
+                });
+            }
+        }
+    }
+
+    /// Fetches a range from a web seed. These don't take up announce
+    /// slots, since the torrent limits how many it has in flight.
+    fn handle_web_seed(&mut self, req: WebSeedFetch) {
+        let (tid, seed, piece, start) = (req.id, req.seed.clone(), req.piece, req.start);
+        if let Err(e) = self.http.new_web_seed(req, &mut self.dns) {
+            self.send_response(Response::WebSeed {
+                tid,
+                seed,
+                piece,
+                start,
+                resp: Err(e),
+            });
+        }
+    }
+
     fn dequeue_req(&mut self) {
         // Attempt to dequeue next request if we can
-        if let Some(a) = self.queue.pop_front() {
-            self.handle_announce(a);
+        match self.queue.pop_front() {
+            Some(Request::Announce(a)) => self.handle_announce(a),
+            Some(Request::Scrape(s)) => self.handle_scrape(s),
+            _ => {}
         }
     }
 
@@ -268,7 +455,10 @@ impl Tracker {
             self.send_response(r);
         }
 
-        self.dht.tick();
+        if let Some(r) = self.dht.tick() {
+            self.send_response(r);
+        }
+        self.utp.tick();
         let mut dresps = vec![];
         let res = self.dns.res.tick(&mut self.dns.sock, |resp| {
             dresps.push(resp);
@@ -296,14 +486,37 @@ impl Tracker {
                 self.send_response(resp);
             }
         } else if self.dht.id() == event.id {
-            for resp in self.dht.readable() {
+            let mut incoming = Vec::new();
+            let utp = &mut self.utp;
+            let resps = self.dht.readable(|data, addr| {
+                if let Some(conn) = utp.recv(data, addr) {
+                    incoming.push(Response::Utp { conn, addr });
+                }
+            });
+            for resp in resps.into_iter().chain(incoming) {
+                self.send_response(resp);
+            }
+        } else if self.lsd.as_ref().map(|l| l.id()) == Some(event.id) {
+            let resps = self.lsd.as_mut().map(|l| l.readable()).unwrap_or_default();
+            for resp in resps {
                 self.send_response(resp);
             }
+        } else if self.utp.contains(event.id) {
+            self.utp.stream_ready(event.id);
         } else {
             error!("Unknown event occured for tracker: {:?}", event);
         };
     }
 
+    /// Persists the announce key if it changed, letting control know
+    fn save_key(&mut self) {
+        if let Some(data) = self.key.take_changes() {
+            let path = Path::new(&CONFIG.disk.session[..]).join(KEY_FILE);
+            self.db.send(disk::Request::WriteFile { data, path }).ok();
+            self.ch.send(Response::AnnounceKey(self.key.rotated())).ok();
+        }
+    }
+
     fn send_response(&mut self, r: Response) {
         if !self.shutting_down {
             trace!("Sending trk response to control!");
@@ -316,16 +529,64 @@ impl Tracker {
     }
 }
 
+impl Headers {
+    /// Creates a set of headers, returning None if any
+    /// name or value can't be safely sent.
+    pub fn new(headers: Vec<(String, String)>) -> Option<Headers> {
+        let valid = headers.iter().all(|(name, value)| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+                && !value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+        });
+        if valid {
+            Some(Headers(headers))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.0.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+        self.0.iter()
+    }
+
+    pub fn into_inner(self) -> Vec<(String, String)> {
+        self.0
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, _)| (name, "<redacted>")))
+            .finish()
+    }
+}
+
 impl Request {
     pub fn new_announce<T: cio::CIO>(
         torrent: &Torrent<T>,
         event: Option<Event>,
     ) -> Option<Request> {
-        let url = if let Some(trk) = torrent.trackers().front() {
-            trk.url.clone()
+        let (url, headers, tracker_id) = if let Some(trk) = torrent.trackers().front() {
+            (trk.url.clone(), trk.headers.clone(), trk.tracker_id.clone())
         } else {
             return None;
         };
+        let stable_key = torrent.info().private
+            || url
+                .host_str()
+                .map(|h| CONFIG.trk.stable_key_trackers.iter().any(|s| s == h))
+                .unwrap_or(false);
         Some(Request::Announce(Announce {
             id: torrent.id(),
             url,
@@ -344,6 +605,15 @@ impl Request {
             // let existing peers connect otherwise
             num_want: if torrent.complete() { None } else { Some(50) },
             event,
+            headers,
+            tracker_id,
+            ipv6: if CONFIG.net.ip_family == IpFamily::Dual {
+                util::global_ipv6()
+            } else {
+                None
+            },
+            stable_key,
+            key: 0,
         }))
     }
 
@@ -364,22 +634,136 @@ impl Request {
     }
 
     pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, url: Arc<Url>) -> Option<Request> {
-        Request::new_announce(torrent, None).map(|mut r| {
+        Request::custom_event(torrent, url, None)
+    }
+
+    /// Announces to a specific tracker with the given event, regardless
+    /// of the torrent's state.
+    pub fn custom_event<T: cio::CIO>(
+        torrent: &Torrent<T>,
+        url: Arc<Url>,
+        event: Option<Event>,
+    ) -> Option<Request> {
+        let (headers, tracker_id) = torrent
+            .trackers()
+            .iter()
+            .find(|trk| trk.url == url)
+            .map(|trk| (trk.headers.clone(), trk.tracker_id.clone()))
+            .unwrap_or_default();
+        Request::new_announce(torrent, event).map(|mut r| {
             if let Request::Announce(ref mut a) = r {
-                a.url = url
+                a.url = url;
+                a.headers = headers;
+                a.tracker_id = tracker_id;
             }
             r
         })
     }
 }
 
+impl Scrape {
+    /// Creates scrape requests for each tracker of a torrent which
+    /// supports scraping.
+    pub fn for_torrent<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        torrent
+            .trackers()
+            .iter()
+            .filter(|trk| !trk.dead && scrape_url(&trk.url).is_some())
+            .map(|trk| {
+                Request::Scrape(Scrape {
+                    id: torrent.id(),
+                    url: trk.url.clone(),
+                    hash: torrent.info().hash,
+                    headers: trk.headers.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Derives the scrape URL of a tracker from its announce URL as per
+/// BEP 48, returning None if the tracker can't be scraped. UDP trackers
+/// are scraped at their announce URL.
+pub fn scrape_url(url: &Url) -> Option<Url> {
+    match url.scheme() {
+        "udp" => Some(url.clone()),
+        "http" | "https" => {
+            let path = url.path();
+            let last = path.rfind('/')? + 1;
+            if !path[last..].starts_with("announce") {
+                return None;
+            }
+            let mut scrape = url.clone();
+            scrape.set_path(&format!(
+                "{}scrape{}",
+                &path[..last],
+                &path[last + "announce".len()..]
+            ));
+            Some(scrape)
+        }
+        _ => None,
+    }
+}
+
+impl ScrapeResponse {
+    /// Parses a HTTP scrape response for the torrent `hash`. Entries of
+    /// the files dictionary are keyed by raw info hashes, which aren't
+    /// valid dictionary keys to our decoder, so the key of `hash` is
+    /// replaced by its hex form before decoding.
+    pub fn from_bytes(data: &[u8], hash: &[u8; 20]) -> Result<ScrapeResponse> {
+        let mut key = b"20:".to_vec();
+        key.extend_from_slice(hash);
+        let id = util::hash_to_id(hash);
+        let mut buf = Vec::with_capacity(data.len() + 20);
+        let mut rest = data;
+        while let Some(pos) = rest.windows(key.len()).position(|w| w == &key[..]) {
+            buf.extend_from_slice(&rest[..pos]);
+            buf.extend_from_slice(format!("40:{}", id).as_bytes());
+            rest = &rest[pos + key.len()..];
+        }
+        buf.extend_from_slice(rest);
+
+        let content = bencode::decode_buf(&buf)
+            .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))?;
+        let mut d = content.into_dict().ok_or(ErrorKind::InvalidResponse(
+            "Scrape response must be a dictionary type!",
+        ))?;
+        if let Some(BEncode::String(data)) = d.remove("failure reason") {
+            let reason = String::from_utf8(data)
+                .chain_err(|| ErrorKind::InvalidResponse("Failure reason must be UTF8!"))?;
+            return Err(ErrorKind::TrackerError(reason).into());
+        }
+        let mut stats = d
+            .remove("files")
+            .and_then(BEncode::into_dict)
+            .and_then(|mut files| files.remove(&id))
+            .and_then(BEncode::into_dict)
+            .ok_or(ErrorKind::InvalidResponse(
+                "Scrape response has no entry for the torrent!",
+            ))?;
+        let mut field = |name: &str| match stats.remove(name) {
+            Some(BEncode::Int(i)) if i >= 0 => i as u32,
+            _ => 0,
+        };
+        Ok(ScrapeResponse {
+            seeders: field("complete"),
+            leechers: field("incomplete"),
+            downloaded: field("downloaded"),
+        })
+    }
+}
+
 impl TrackerResponse {
     pub fn empty() -> TrackerResponse {
         TrackerResponse {
             peers: vec![],
             interval: 900,
+            min_interval: None,
             leechers: 0,
             seeders: 0,
+            external_ip: None,
+            warning: None,
+            tracker_id: None,
         }
     }
 
@@ -393,6 +777,12 @@ impl TrackerResponse {
             return Err(ErrorKind::TrackerError(reason).into());
         }
         let mut resp = TrackerResponse::empty();
+        if let Some(BEncode::String(data)) = d.remove("warning message") {
+            resp.warning = Some(String::from_utf8_lossy(&data).into_owned());
+        }
+        if let Some(BEncode::String(data)) = d.remove("tracker id") {
+            resp.tracker_id = Some(data);
+        }
         if let Some(BEncode::String(ref data)) = d.remove("peers") {
             for p in data.chunks(6) {
                 if p.len() != 6 {
@@ -404,6 +794,29 @@ impl TrackerResponse {
                 resp.peers.push(SocketAddr::V4(socket));
             }
         }
+        if let Some(BEncode::String(ref data)) = d.remove("peers6") {
+            for p in data.chunks(18) {
+                if p.len() != 18 {
+                    debug!("Unusual trailing bytes received for tracker!");
+                    continue;
+                }
+                resp.peers.push(util::bytes_to_addr6(p));
+            }
+        }
+        if let Some(BEncode::String(ref ip)) = d.remove("external ip") {
+            resp.external_ip = match ip.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))),
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(ip);
+                    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+                }
+                _ => None,
+            };
+        }
+        if let Some(BEncode::Int(i)) = d.remove("min interval") {
+            resp.min_interval = Some(i as u32);
+        }
         match d.remove("interval") {
             Some(BEncode::Int(ref i)) => {
                 resp.interval = *i as u32;
@@ -415,3 +828,95 @@ impl TrackerResponse {
         Ok(resp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{scrape_url, ScrapeResponse, TrackerResponse};
+    use bencode::BEncode;
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use url::Url;
+
+    #[test]
+    fn test_warning_message() {
+        let mut d = BTreeMap::new();
+        d.insert(
+            "warning message".to_owned(),
+            BEncode::String(b"your client is outdated".to_vec()),
+        );
+        d.insert("interval".to_owned(), BEncode::Int(1800));
+        d.insert(
+            "peers".to_owned(),
+            BEncode::String(vec![127, 0, 0, 1, 0x1A, 0xE1]),
+        );
+        let resp = TrackerResponse::from_bencode(BEncode::Dict(d)).unwrap();
+        assert_eq!(resp.warning, Some("your client is outdated".to_owned()));
+        assert_eq!(resp.interval, 1800);
+        assert_eq!(
+            resp.peers,
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_peers6() {
+        let mut d = BTreeMap::new();
+        d.insert("interval".to_owned(), BEncode::Int(1800));
+        d.insert(
+            "peers".to_owned(),
+            BEncode::String(vec![127, 0, 0, 1, 0x1A, 0xE1]),
+        );
+        let mut peers6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        peers6.extend_from_slice(&[0; 11]);
+        peers6.extend_from_slice(&[1, 0x1A, 0xE1, 0xFF]);
+        d.insert("peers6".to_owned(), BEncode::String(peers6));
+        let resp = TrackerResponse::from_bencode(BEncode::Dict(d)).unwrap();
+        assert_eq!(
+            resp.peers,
+            vec![
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:6881".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let scrape = |u| scrape_url(&Url::parse(u).unwrap()).map(|u| u.into_string());
+        assert_eq!(
+            scrape("http://example.com/announce"),
+            Some("http://example.com/scrape".to_owned())
+        );
+        assert_eq!(
+            scrape("https://example.com/x/announce.php?passkey=a"),
+            Some("https://example.com/x/scrape.php?passkey=a".to_owned())
+        );
+        // UDP trackers are scraped at their announce URL as parsed
+        assert_eq!(
+            scrape("udp://example.com:80"),
+            Some("udp://example.com:80/".to_owned())
+        );
+        assert_eq!(scrape("http://example.com/a"), None);
+        assert_eq!(scrape("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn test_scrape_response() {
+        let hash = [0xFFu8; 20];
+        let mut data = b"d5:filesd20:".to_vec();
+        data.extend_from_slice(&hash);
+        data.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        assert_eq!(
+            ScrapeResponse::from_bytes(&data, &hash).unwrap(),
+            ScrapeResponse {
+                seeders: 5,
+                leechers: 10,
+                downloaded: 50,
+            }
+        );
+
+        // Stats of other torrents aren't mistaken for ours
+        assert!(ScrapeResponse::from_bytes(&data, &[0u8; 20]).is_err());
+        assert!(ScrapeResponse::from_bytes(b"d14:failure reason4:nopee", &hash).is_err());
+    }
+}
@@ -1,21 +1,31 @@
 use std::io::{self, Cursor, Read, Write};
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
 use std::time;
 
 use amy;
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use net2::UdpBuilder;
 use rand::random;
+use url::Url;
 
+use config::IpFamily;
 use tracker::{
-    dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, TrackerResponse,
+    dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, Scrape, ScrapeResponse,
+    TrackerResponse,
 };
-use util::{bytes_to_addr, FHashMap, UHashMap};
+use util::{bytes_to_addr, bytes_to_addr6, map_addr, FHashMap, UHashMap};
 use {CONFIG, PEER_ID};
 
-// We're not going to bother with backoff, if the tracker/network aren't working now
-// the torrent can just resend a request later.
-const TIMEOUT_MS: u64 = 15_000;
-const RETRANS_MS: u64 = 5_000;
+/// Time allowed for resolving a tracker's host
+const DNS_TIMEOUT_MS: u64 = 15_000;
+/// Requests are retransmitted after 15 * 2 ^ n seconds, n being the
+/// number of retransmissions so far, as per BEP 15
+const RETRANS_BASE_SECS: u64 = 15;
+/// Transmissions after which an announce times out
+const MAX_TRIES: u32 = 8;
+/// Connection ids may be reused for a minute after being received
+const CONN_ID_SECS: u64 = 60;
 const MAGIC_NUM: u64 = 0x417_2710_1980;
 
 pub struct Handler {
@@ -23,28 +33,75 @@ pub struct Handler {
     sock: UdpSocket,
     connections: UHashMap<Connection>,
     transactions: FHashMap<u32, usize>,
+    /// Connection ids of trackers and when they were received
+    conn_ids: FHashMap<SocketAddr, (u64, time::Instant)>,
     conn_count: usize,
     buf: Vec<u8>,
 }
 
 struct Connection {
     torrent: usize,
+    url: Arc<Url>,
+    started: time::Instant,
     last_updated: time::Instant,
     last_retrans: time::Instant,
+    /// Requests sent so far, connects and announces alike
+    tries: u32,
     state: State,
-    announce: Announce,
+    req: Req,
+}
+
+enum Req {
+    Announce(Announce),
+    /// Scrape of the torrent with the given hash
+    Scrape([u8; 20]),
 }
 
 enum State {
-    ResolvingDNS { port: u16 },
-    Connecting { addr: SocketAddr, data: [u8; 16] },
-    Announcing { addr: SocketAddr, data: [u8; 98] },
+    ResolvingDNS {
+        port: u16,
+    },
+    Connecting {
+        addr: SocketAddr,
+        data: [u8; 16],
+    },
+    /// Announcing or scraping
+    Requesting {
+        addr: SocketAddr,
+        data: Vec<u8>,
+        /// When the connection id used was received
+        conn_id_at: time::Instant,
+    },
+}
+
+impl Connection {
+    fn error(&self, e: Error) -> Response {
+        match self.req {
+            Req::Announce(_) => Response::Tracker {
+                tid: self.torrent,
+                url: self.url.clone(),
+                resp: Err(e),
+            },
+            Req::Scrape(_) => Response::Scrape {
+                tid: self.torrent,
+                url: self.url.clone(),
+                resp: Err(e),
+            },
+        }
+    }
 }
 
 impl Handler {
     pub fn new(reg: &amy::Registrar) -> io::Result<Handler> {
         let port = CONFIG.trk.port;
-        let sock = UdpSocket::bind(("0.0.0.0", port))?;
+        let sock = match CONFIG.net.ip_family {
+            // IPv4 trackers are reached through mapped addresses
+            IpFamily::Dual => UdpBuilder::new_v6()?
+                .only_v6(false)?
+                .bind((Ipv6Addr::from([0u8; 16]), port))?,
+            IpFamily::Ipv6 => UdpSocket::bind(("::", port))?,
+            IpFamily::Ipv4 => UdpSocket::bind(("0.0.0.0", port))?,
+        };
         sock.set_nonblocking(true)?;
         let id = reg.register(&sock, amy::Event::Read)?;
         Ok(Handler {
@@ -52,6 +109,7 @@ impl Handler {
             sock,
             connections: UHashMap::default(),
             transactions: FHashMap::default(),
+            conn_ids: FHashMap::default(),
             conn_count: 0,
             buf: vec![0u8; 350],
         })
@@ -74,8 +132,22 @@ impl Handler {
     }
 
     pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
-        let url = req.url.clone();
-        debug!("Received a new announce req for {:?}", url);
+        debug!("Received a new announce req for {:?}", req.url);
+        self.new_request(req.id, req.url.clone(), Req::Announce(req), dns)
+    }
+
+    pub fn new_scrape(&mut self, req: Scrape, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new scrape req for {:?}", req.url);
+        self.new_request(req.id, req.url, Req::Scrape(req.hash), dns)
+    }
+
+    fn new_request(
+        &mut self,
+        torrent: usize,
+        url: Arc<Url>,
+        req: Req,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
         let host = url.host_str().ok_or_else(|| {
             Error::from(ErrorKind::InvalidRequest(
                 "Tracker announce url has no host!".to_owned(),
@@ -91,11 +163,14 @@ impl Handler {
         self.connections.insert(
             id,
             Connection {
-                torrent: req.id,
+                torrent,
+                url: url.clone(),
+                started: time::Instant::now(),
                 last_updated: time::Instant::now(),
                 last_retrans: time::Instant::now(),
+                tries: 0,
                 state: State::ResolvingDNS { port },
-                announce: req,
+                req,
             },
         );
         debug!("Dispatching DNS req for {:?}, url: {:?}", id, host);
@@ -111,49 +186,17 @@ impl Handler {
 
     pub fn dns_resolved(&mut self, resp: dns::QueryResponse) -> Option<Response> {
         let id = resp.id;
-        let mut success = false;
         debug!("Received a DNS resp for {:?}", id);
-        let resp = if let Some(conn) = self.connections.get_mut(&id) {
-            match conn.state {
-                State::ResolvingDNS { port } => {
-                    conn.last_updated = time::Instant::now();
-                    let tid = random::<u32>();
-                    let mut data = [0u8; 16];
-                    {
-                        let mut connect_req = Cursor::new(&mut data[..]);
-                        connect_req.write_u64::<BigEndian>(MAGIC_NUM).unwrap();
-                        connect_req.write_u32::<BigEndian>(0).unwrap();
-                        connect_req.write_u32::<BigEndian>(tid).unwrap();
-                    }
-                    match resp.res {
-                        Ok(ip) => {
-                            success = true;
-                            conn.state = State::Connecting {
-                                addr: SocketAddr::new(ip, port),
-                                data,
-                            };
-                            self.transactions.insert(tid, id);
-                            None
-                        }
-                        Err(e) => Some(Response::Tracker {
-                            tid: conn.torrent,
-                            url: conn.announce.url.clone(),
-                            resp: Err(e),
-                        }),
-                    }
-                }
-                _ => None,
-            }
-        } else {
-            None
+        let port = match self.connections.get(&id).map(|c| &c.state) {
+            Some(&State::ResolvingDNS { port }) => port,
+            _ => return None,
         };
-        if resp.is_some() {
-            self.connections.remove(&id);
-            resp
-        } else if success {
-            self.send_data(id)
-        } else {
-            None
+        match resp.res {
+            Ok(ip) => self.connect(id, SocketAddr::new(ip, port)),
+            Err(e) => {
+                let conn = self.connections.remove(&id).unwrap();
+                Some(conn.error(e))
+            }
         }
     }
 
@@ -172,6 +215,11 @@ impl Handler {
                         resps.push(r);
                     }
                 }
+                2 if v >= 20 => {
+                    if let Some(r) = self.process_scrape() {
+                        resps.push(r);
+                    }
+                }
                 3 if v >= 8 => {
                     if let Some(r) = self.process_error(v) {
                         resps.push(r);
@@ -186,29 +234,51 @@ impl Handler {
     }
 
     pub fn tick(&mut self) -> Vec<Response> {
+        let now = time::Instant::now();
         let mut resps = Vec::new();
         let mut retrans = Vec::new();
+        let mut reconnect = Vec::new();
         {
             self.connections.retain(|id, conn| {
-                if conn.last_updated.elapsed() > time::Duration::from_millis(TIMEOUT_MS) {
-                    resps.push(Response::Tracker {
-                        tid: conn.torrent,
-                        url: conn.announce.url.clone(),
-                        resp: Err(ErrorKind::Timeout.into()),
-                    });
-                    debug!("Announce {:?} timed out", id);
-                    false
-                } else {
-                    if conn.last_retrans.elapsed() > time::Duration::from_millis(RETRANS_MS) {
+                let timed_out = match conn.state {
+                    _ if past_deadline(conn.started, now) => true,
+                    State::ResolvingDNS { .. } => {
+                        conn.last_updated.elapsed() > time::Duration::from_millis(DNS_TIMEOUT_MS)
+                    }
+                    _ => {
+                        now.duration_since(conn.last_retrans)
+                            > retrans_timeout(conn.tries.saturating_sub(1))
+                            && conn.tries >= MAX_TRIES
+                    }
+                };
+                if timed_out {
+                    resps.push(conn.error(ErrorKind::Timeout.into()));
+                    debug!("Request {:?} timed out", id);
+                    return false;
+                }
+                match conn.state {
+                    State::ResolvingDNS { .. } => {}
+                    _ if now.duration_since(conn.last_retrans)
+                        <= retrans_timeout(conn.tries.saturating_sub(1)) => {}
+                    // An expired connection id has to be renewed first
+                    State::Requesting {
+                        addr, conn_id_at, ..
+                    } if !conn_id_valid(conn_id_at, now) => {
+                        debug!("Connection id of req {:?} expired, reconnecting", id);
+                        reconnect.push((*id, addr));
+                    }
+                    _ => {
                         debug!("Retransmiting req {:?}", id);
                         retrans.push(*id);
                     }
-                    true
                 }
+                true
             });
 
             let c = &self.connections;
             self.transactions.retain(|_, id| c.contains_key(id));
+            self.conn_ids
+                .retain(|_, &mut (_, at)| conn_id_valid(at, now));
         }
 
         for id in retrans {
@@ -216,9 +286,42 @@ impl Handler {
                 resps.push(r)
             }
         }
+        for (id, addr) in reconnect {
+            if let Some(r) = self.connect(id, addr) {
+                resps.push(r)
+            }
+        }
         resps
     }
 
+    /// Sends the request to `addr` through a new connection, or an
+    /// existing one if its id is still valid.
+    fn connect(&mut self, id: usize, addr: SocketAddr) -> Option<Response> {
+        if let Some(&(cid, at)) = self.conn_ids.get(&addr) {
+            if conn_id_valid(at, time::Instant::now()) {
+                debug!("Reusing connection id for {}", addr);
+                return self.request(id, addr, cid, at);
+            }
+        }
+        let tid = random::<u32>();
+        let mut data = [0u8; 16];
+        {
+            let mut connect_req = Cursor::new(&mut data[..]);
+            connect_req.write_u64::<BigEndian>(MAGIC_NUM).unwrap();
+            connect_req.write_u32::<BigEndian>(0).unwrap();
+            connect_req.write_u32::<BigEndian>(tid).unwrap();
+        }
+        match self.connections.get_mut(&id) {
+            Some(conn) => {
+                conn.state = State::Connecting { addr, data };
+                conn.last_updated = time::Instant::now();
+            }
+            None => return None,
+        }
+        self.transactions.insert(tid, id);
+        self.send_data(id)
+    }
+
     fn process_connect(&mut self) -> Option<Response> {
         let (transaction_id, connection_id) = {
             let mut connect_resp = Cursor::new(&self.buf[4..16]);
@@ -231,67 +334,47 @@ impl Handler {
             Some(id) => id,
             None => return None,
         };
+        let addr = match self.connections.get(&id).map(|c| &c.state) {
+            Some(&State::Connecting { addr, .. }) => addr,
+            _ => return None,
+        };
+        let now = time::Instant::now();
+        self.conn_ids.insert(addr, (connection_id, now));
+        self.request(id, addr, connection_id, now)
+    }
 
-        let mut data = [0u8; 98];
+    /// Sends the announce or scrape request using the given connection id
+    fn request(
+        &mut self,
+        id: usize,
+        addr: SocketAddr,
+        connection_id: u64,
+        conn_id_at: time::Instant,
+    ) -> Option<Response> {
         {
             let conn = match self.connections.get_mut(&id) {
                 Some(conn) => conn,
                 None => return None,
             };
-            let addr = match conn.state {
-                State::Connecting { addr, .. } => addr,
-                _ => return None,
-            };
-
-            {
-                let mut announce_req = Cursor::new(&mut data[..]);
-                announce_req.write_u64::<BigEndian>(connection_id).unwrap();
-                // announce action
-                announce_req.write_u32::<BigEndian>(1).unwrap();
-
-                let tid = random::<u32>();
-                announce_req.write_u32::<BigEndian>(tid).unwrap();
-                self.transactions.insert(tid, id);
-
-                announce_req.write_all(&conn.announce.hash).unwrap();
-                announce_req.write_all(&PEER_ID[..]).unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.downloaded as u64)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.left as u64)
-                    .unwrap();
-                announce_req
-                    .write_u64::<BigEndian>(conn.announce.uploaded as u64)
-                    .unwrap();
-                match conn.announce.event {
-                    Some(Event::Started) => {
-                        announce_req.write_u32::<BigEndian>(2).unwrap();
-                    }
-                    Some(Event::Stopped) => {
-                        announce_req.write_u32::<BigEndian>(3).unwrap();
-                    }
-                    Some(Event::Completed) => {
-                        announce_req.write_u32::<BigEndian>(1).unwrap();
-                    }
-                    None => {
-                        announce_req.write_u32::<BigEndian>(0).unwrap();
-                    }
+            let tid = random::<u32>();
+            self.transactions.insert(tid, id);
+            let data = match conn.req {
+                Req::Announce(ref announce) => announce_data(announce, connection_id, tid),
+                Req::Scrape(ref hash) => {
+                    let mut data = Vec::with_capacity(36);
+                    data.write_u64::<BigEndian>(connection_id).unwrap();
+                    // scrape action
+                    data.write_u32::<BigEndian>(2).unwrap();
+                    data.write_u32::<BigEndian>(tid).unwrap();
+                    data.extend_from_slice(hash);
+                    data
                 }
-
-                // IP
-                announce_req.write_u32::<BigEndian>(0).unwrap();
-                // Key - TODO: randomly generate this
-                announce_req.write_u32::<BigEndian>(0xFFFF_00BA).unwrap();
-                // Num want
-                let nw = conn.announce.num_want.map(|nw| i32::from(nw)).unwrap_or(-1);
-                announce_req.write_i32::<BigEndian>(nw).unwrap();
-                // port
-                announce_req
-                    .write_u16::<BigEndian>(conn.announce.port)
-                    .unwrap();
-            }
-            conn.state = State::Announcing { addr, data };
+            };
+            conn.state = State::Requesting {
+                addr,
+                data,
+                conn_id_at,
+            };
             conn.last_updated = time::Instant::now();
         }
         self.send_data(id)
@@ -311,23 +394,54 @@ impl Handler {
             Some(c) => c,
             None => return None,
         };
+        if let Req::Scrape(_) = conn.req {
+            return None;
+        }
 
         resp.interval = announce_resp.read_u32::<BigEndian>().unwrap();
         resp.leechers = announce_resp.read_u32::<BigEndian>().unwrap();
         resp.seeders = announce_resp.read_u32::<BigEndian>().unwrap();
-        if len > 20 {
-            let pos = announce_resp.position() as usize;
-            for p in announce_resp.get_ref()[pos..].chunks(6) {
-                resp.peers.push(bytes_to_addr(p));
-            }
-        }
+        let pos = announce_resp.position() as usize;
+        // Trackers reached over IPv6 respond with IPv6 peers
+        let v6 = match conn.state {
+            State::Requesting {
+                addr: SocketAddr::V6(_),
+                ..
+            } => true,
+            _ => false,
+        };
+        resp.peers = parse_peers(&announce_resp.get_ref()[pos..], v6);
         Some(Response::Tracker {
             tid: conn.torrent,
-            url: conn.announce.url,
+            url: conn.url,
             resp: Ok(resp),
         })
     }
 
+    fn process_scrape(&mut self) -> Option<Response> {
+        let mut scrape_resp = Cursor::new(&self.buf[4..20]);
+        let transaction_id = scrape_resp.read_u32::<BigEndian>().unwrap();
+
+        let id = self.transactions.remove(&transaction_id)?;
+        let conn = self.connections.remove(&id)?;
+        if let Req::Announce(_) = conn.req {
+            return None;
+        }
+
+        let seeders = scrape_resp.read_u32::<BigEndian>().unwrap();
+        let downloaded = scrape_resp.read_u32::<BigEndian>().unwrap();
+        let leechers = scrape_resp.read_u32::<BigEndian>().unwrap();
+        Some(Response::Scrape {
+            tid: conn.torrent,
+            url: conn.url,
+            resp: Ok(ScrapeResponse {
+                seeders,
+                leechers,
+                downloaded,
+            }),
+        })
+    }
+
     fn process_error(&mut self, len: usize) -> Option<Response> {
         let mut s = String::new();
         let mut connect_resp = Cursor::new(&self.buf[4..len]);
@@ -342,21 +456,21 @@ impl Handler {
             Some(c) => c,
             None => return None,
         };
+        // The tracker may have rejected an expired connection id, so
+        // don't reuse it next time
+        match conn.state {
+            State::Connecting { ref addr, .. } | State::Requesting { ref addr, .. } => {
+                self.conn_ids.remove(addr);
+            }
+            State::ResolvingDNS { .. } => {}
+        }
 
         if connect_resp.read_to_string(&mut s).is_err() {
-            let resp =
-                Err(ErrorKind::InvalidResponse("Tracker error response was invalid UTF8").into());
-            Some(Response::Tracker {
-                tid: conn.torrent,
-                url: conn.announce.url,
-                resp,
-            })
+            Some(conn.error(
+                ErrorKind::InvalidResponse("Tracker error response was invalid UTF8").into(),
+            ))
         } else {
-            Some(Response::Tracker {
-                tid: conn.torrent,
-                url: conn.announce.url,
-                resp: Err(ErrorKind::TrackerError(s).into()),
-            })
+            Some(conn.error(ErrorKind::TrackerError(s).into()))
         }
     }
 
@@ -367,20 +481,26 @@ impl Handler {
     }
 
     fn send_data(&mut self, id: usize) -> Option<Response> {
-        let tid;
         let res = {
             let conn = self.connections.get_mut(&id).unwrap();
-            tid = conn.torrent;
             // If this actually blocks, something is really fucked(prob with the NIC)
             // and i dont think we need to care
             match conn.state {
                 State::Connecting { ref addr, ref data } => {
                     conn.last_retrans = time::Instant::now();
-                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
+                    conn.tries += 1;
+                    self.sock
+                        .send_to(data, dest(addr))
+                        .chain_err(|| ErrorKind::IO)
                 }
-                State::Announcing { ref addr, ref data } => {
+                State::Requesting {
+                    ref addr, ref data, ..
+                } => {
                     conn.last_retrans = time::Instant::now();
-                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
+                    conn.tries += 1;
+                    self.sock
+                        .send_to(data, dest(addr))
+                        .chain_err(|| ErrorKind::IO)
                 }
                 _ => Ok(0),
             }
@@ -388,14 +508,169 @@ impl Handler {
 
         match res {
             Err(e) => {
-                let url = self.connections.remove(&id).unwrap().announce.url;
-                Some(Response::Tracker {
-                    tid,
-                    url,
-                    resp: Err(e),
-                })
+                let conn = self.connections.remove(&id).unwrap();
+                Some(conn.error(e))
             }
             Ok(_) => None,
         }
     }
 }
+
+/// Encodes an announce request
+fn announce_data(announce: &Announce, connection_id: u64, tid: u32) -> Vec<u8> {
+    let mut announce_req = Vec::with_capacity(98);
+    announce_req.write_u64::<BigEndian>(connection_id).unwrap();
+    // announce action
+    announce_req.write_u32::<BigEndian>(1).unwrap();
+    announce_req.write_u32::<BigEndian>(tid).unwrap();
+    announce_req.write_all(&announce.hash).unwrap();
+    announce_req.write_all(&PEER_ID[..]).unwrap();
+    announce_req
+        .write_u64::<BigEndian>(announce.downloaded)
+        .unwrap();
+    announce_req.write_u64::<BigEndian>(announce.left).unwrap();
+    announce_req
+        .write_u64::<BigEndian>(announce.uploaded)
+        .unwrap();
+    match announce.event {
+        Some(Event::Started) => {
+            announce_req.write_u32::<BigEndian>(2).unwrap();
+        }
+        Some(Event::Stopped) => {
+            announce_req.write_u32::<BigEndian>(3).unwrap();
+        }
+        Some(Event::Completed) => {
+            announce_req.write_u32::<BigEndian>(1).unwrap();
+        }
+        None => {
+            announce_req.write_u32::<BigEndian>(0).unwrap();
+        }
+    }
+
+    // IP
+    announce_req.write_u32::<BigEndian>(0).unwrap();
+    // Key
+    announce_req.write_u32::<BigEndian>(announce.key).unwrap();
+    // Num want
+    let nw = announce.num_want.map(|nw| i32::from(nw)).unwrap_or(-1);
+    announce_req.write_i32::<BigEndian>(nw).unwrap();
+    // port
+    announce_req.write_u16::<BigEndian>(announce.port).unwrap();
+    announce_req
+}
+
+/// Time to wait for a response after the `n`th retransmission
+fn retrans_timeout(n: u32) -> time::Duration {
+    time::Duration::from_secs(RETRANS_BASE_SECS << n.min(MAX_TRIES))
+}
+
+/// Whether a request started at `started` has run out of time overall
+fn past_deadline(started: time::Instant, now: time::Instant) -> bool {
+    let secs = CONFIG.trk.udp_timeout_secs;
+    secs != 0 && now.duration_since(started) > time::Duration::from_secs(secs)
+}
+
+fn conn_id_valid(received: time::Instant, now: time::Instant) -> bool {
+    now.duration_since(received) < time::Duration::from_secs(CONN_ID_SECS)
+}
+
+/// Address `addr` is reached at through the socket
+fn dest(addr: &SocketAddr) -> SocketAddr {
+    match CONFIG.net.ip_family {
+        IpFamily::Dual => map_addr(*addr),
+        _ => *addr,
+    }
+}
+
+/// Parses the compact peer list of an announce response, ignoring
+/// any trailing partial entry.
+fn parse_peers(data: &[u8], v6: bool) -> Vec<SocketAddr> {
+    if v6 {
+        data.chunks(18)
+            .filter(|p| p.len() == 18)
+            .map(bytes_to_addr6)
+            .collect()
+    } else {
+        data.chunks(6)
+            .filter(|p| p.len() == 6)
+            .map(bytes_to_addr)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{announce_data, conn_id_valid, parse_peers, past_deadline, retrans_timeout};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tracker::{Announce, Headers};
+    use url::Url;
+
+    #[test]
+    fn test_retrans_backoff() {
+        let secs: Vec<_> = (0..8).map(|n| retrans_timeout(n).as_secs()).collect();
+        assert_eq!(secs, vec![15, 30, 60, 120, 240, 480, 960, 1920]);
+    }
+
+    #[test]
+    fn test_overall_timeout() {
+        let at = Instant::now();
+        assert!(!past_deadline(at, at + Duration::from_secs(120)));
+        // Well before the retransmissions would be exhausted
+        assert!(past_deadline(at, at + Duration::from_secs(121)));
+    }
+
+    #[test]
+    fn test_conn_id_expiry() {
+        let at = Instant::now();
+        assert!(conn_id_valid(at, at + Duration::from_secs(59)));
+        assert!(!conn_id_valid(at, at + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_parse_compact_peers() {
+        let data = [10, 0, 0, 1, 0x1A, 0xE1, 192, 168, 1, 2, 0, 80, 1, 2, 3];
+        assert_eq!(
+            parse_peers(&data, false),
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "192.168.1.2:80".parse().unwrap()
+            ]
+        );
+        assert!(parse_peers(&[], false).is_empty());
+
+        let mut data = vec![0x20, 0x01, 0x0d, 0xb8];
+        data.extend_from_slice(&[0; 11]);
+        data.extend_from_slice(&[1, 0x1A, 0xE1, 10, 0, 0, 1, 0x1A, 0xE1]);
+        assert_eq!(
+            parse_peers(&data, true),
+            vec!["[2001:db8::1]:6881".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_announce_data() {
+        let req = Announce {
+            id: 0,
+            url: Arc::new(Url::parse("udp://tracker.example:80").unwrap()),
+            hash: [1u8; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            num_want: Some(50),
+            event: None,
+            headers: Headers::default(),
+            tracker_id: None,
+            ipv6: None,
+            stable_key: false,
+            key: 0xDEAD_BEEF,
+        };
+        let data = announce_data(&req, 0x41727101980, 7);
+        assert_eq!(data.len(), 98);
+        assert_eq!(&data[8..16], &[0, 0, 0, 1, 0, 0, 0, 7]);
+        assert_eq!(&data[16..36], &[1u8; 20]);
+        assert_eq!(&data[88..92], &[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(&data[92..], &[0, 0, 0, 50, 0x1A, 0xE1]);
+    }
+}
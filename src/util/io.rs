@@ -1,5 +1,7 @@
 use std::io;
 
+use nix::libc;
+
 pub fn io_err<T>(reason: &'static str) -> io::Result<T> {
     Err(io::Error::new(io::ErrorKind::Other, reason))
 }
@@ -8,6 +10,11 @@ pub fn io_err_val(reason: &'static str) -> io::Error {
     io::Error::new(io::ErrorKind::Other, reason)
 }
 
+/// Whether an error was caused by the filesystem being read-only
+pub fn is_read_only(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EROFS)
+}
+
 /// IO Result type for working with
 /// async IO
 pub enum IOR {
@@ -4,7 +4,7 @@ pub mod native;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write as FWrite;
 use std::hash::BuildHasherDefault;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
 
 use byteorder::{BigEndian, ByteOrder};
 use fnv;
@@ -22,7 +22,7 @@ pub type MHashMap<K, V> = HashMap<K, V, MBuildHasher>;
 pub type MHashSet<T> = HashSet<T, MBuildHasher>;
 pub type SHashMap<T> = MHashMap<String, T>;
 
-pub use self::io::{aread, awrite, io_err, io_err_val, IOR};
+pub use self::io::{aread, awrite, io_err, io_err_val, is_read_only, IOR};
 
 pub fn random_sample<A, T>(iter: A) -> Option<T>
 where
@@ -135,6 +135,60 @@ pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..])))
 }
 
+/// Parses a compact IPv6 address, as used by `peers6` and BEP 15
+/// responses from IPv6 trackers.
+pub fn bytes_to_addr6(p: &[u8]) -> SocketAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&p[..16]);
+    let port = BigEndian::read_u16(&p[16..]);
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+}
+
+/// Converts an IPv4-mapped address, as reported for IPv4 peers by
+/// a dual stack socket, back to a plain IPv4 one.
+pub fn unmap_addr(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(a) = addr {
+        let s = a.ip().segments();
+        if s[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            let o = a.ip().octets();
+            let ip = Ipv4Addr::new(o[12], o[13], o[14], o[15]);
+            return SocketAddr::V4(SocketAddrV4::new(ip, a.port()));
+        }
+    }
+    addr
+}
+
+/// Converts an IPv4 address to an IPv4-mapped one, so it can be
+/// reached through a dual stack socket.
+pub fn map_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(a) => {
+            SocketAddr::V6(SocketAddrV6::new(a.ip().to_ipv6_mapped(), a.port(), 0, 0))
+        }
+        _ => addr,
+    }
+}
+
+/// Returns the global IPv6 address outgoing connections use, if
+/// there is one. Connecting the UDP socket only selects a route,
+/// nothing is sent.
+pub fn global_ipv6() -> Option<Ipv6Addr> {
+    let sock = UdpSocket::bind("[::]:0").ok()?;
+    sock.connect("[2001:db8::1]:6881").ok()?;
+    match sock.local_addr().ok()? {
+        SocketAddr::V6(a) => {
+            let s = a.ip().segments();
+            // Link and unique local addresses aren't reachable by peers
+            if a.ip().is_loopback() || s[0] & 0xffc0 == 0xfe80 || s[0] & 0xfe00 == 0xfc00 {
+                None
+            } else {
+                Some(*a.ip())
+            }
+        }
+        _ => None,
+    }
+}
+
 pub fn addr_to_bytes(addr: &SocketAddr) -> [u8; 6] {
     let mut data = [0u8; 6];
     match *addr {
@@ -157,6 +211,16 @@ pub fn find_subseq(haystack: &[u8], needle: &[u8]) -> Option<usize> {
         .position(|window| window == needle)
 }
 
+#[test]
+fn test_unmap_addr() {
+    let mapped: SocketAddr = "[::ffff:10.0.0.1]:6881".parse().unwrap();
+    assert_eq!(unmap_addr(mapped), "10.0.0.1:6881".parse().unwrap());
+    let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+    assert_eq!(unmap_addr(v6), v6);
+    assert_eq!(map_addr("10.0.0.1:6881".parse().unwrap()), mapped);
+    assert_eq!(map_addr(v6), v6);
+}
+
 #[test]
 fn test_hash_enc() {
     let hash = [8u8; 20];
@@ -0,0 +1,591 @@
+use std::collections::{HashMap, VecDeque};
+use std::{cmp, mem};
+
+use super::packet::{Packet, Type};
+
+/// Payload size of data packets, small enough to avoid fragmentation
+pub const MSS: usize = 1400;
+/// Queuing delay LEDBAT aims for, in microseconds
+const TARGET_DELAY: i64 = 100_000;
+/// Most the congestion window may grow by per round trip
+const MAX_CWND_INCREASE: f64 = 3000.;
+const MIN_CWND: usize = 2 * MSS;
+const SEND_BUF: usize = 256 * 1024;
+const RECV_BUF: usize = 1024 * 1024;
+const INIT_RTO: u64 = 1_000_000;
+const MIN_RTO: u64 = 500_000;
+const MAX_RTO: u64 = 60_000_000;
+const SYN_RETRIES: u32 = 3;
+const MAX_RETRIES: u32 = 8;
+/// Base delay is the lowest delay of the current and previous interval
+const BASE_DELAY_INTERVAL: u64 = 60_000_000;
+/// Packets further ahead than this are dropped rather than buffered
+const MAX_REORDER: u16 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    SynSent,
+    Connected,
+    /// Our FIN was acknowledged
+    Closed,
+    /// Reset by the remote, or timed out
+    Failed,
+}
+
+struct Sent {
+    packet: Packet,
+    sent_at: u64,
+    resends: u32,
+}
+
+/// State of a single uTP connection. No IO is done, received packets are
+/// fed in and those to send taken out. Times are in microseconds,
+/// relative to an arbitrary but fixed instant.
+pub struct Conn {
+    state: State,
+    recv_id: u16,
+    send_id: u16,
+    /// Sequence number of the next packet sent
+    seq_nr: u16,
+    /// Last sequence number received in order
+    ack_nr: u16,
+    unacked: VecDeque<Sent>,
+    /// Payload bytes of unacked packets
+    inflight: usize,
+    /// Written data not yet packetized
+    outbuf: Vec<u8>,
+    /// Received data not yet read
+    inbuf: Vec<u8>,
+    /// Packets received ahead of ack_nr
+    reorder: HashMap<u16, Packet>,
+    transmit: Vec<Vec<u8>>,
+    need_ack: bool,
+    closing: bool,
+    fin_sent: bool,
+    /// Whether the remote closed the connection and all its data arrived
+    eof: bool,
+    cwnd: usize,
+    peer_wnd: usize,
+    srtt: Option<(u64, u64)>,
+    rto: u64,
+    timeout_at: Option<u64>,
+    retries: u32,
+    dup_acks: u32,
+    /// One way delay of the last received packet, echoed back
+    reply_delay: u32,
+    /// Lowest delays reported by the remote in the current and
+    /// previous interval
+    base_delay: [Option<u32>; 2],
+    delay_interval: u64,
+    /// Queuing delay on top of the base delay
+    our_delay: i64,
+}
+
+impl Conn {
+    fn new(state: State, recv_id: u16, send_id: u16, seq_nr: u16, now: u64) -> Conn {
+        Conn {
+            state,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            unacked: VecDeque::new(),
+            inflight: 0,
+            outbuf: Vec::new(),
+            inbuf: Vec::new(),
+            reorder: HashMap::new(),
+            transmit: Vec::new(),
+            need_ack: false,
+            closing: false,
+            fin_sent: false,
+            eof: false,
+            cwnd: MIN_CWND,
+            peer_wnd: MSS,
+            srtt: None,
+            rto: INIT_RTO,
+            timeout_at: None,
+            retries: 0,
+            dup_acks: 0,
+            reply_delay: 0,
+            base_delay: [None, None],
+            delay_interval: now,
+            our_delay: 0,
+        }
+    }
+
+    /// Starts an outgoing connection, receiving packets with `recv_id`.
+    pub fn connect(recv_id: u16, now: u64) -> Conn {
+        let mut c = Conn::new(State::SynSent, recv_id, recv_id.wrapping_add(1), 1, now);
+        c.send_new(Type::Syn, Vec::new(), now);
+        c
+    }
+
+    /// Accepts an incoming connection, starting at sequence number `seq_nr`.
+    pub fn accept(syn: &Packet, seq_nr: u16, now: u64) -> Conn {
+        let mut c = Conn::new(
+            State::Connected,
+            syn.conn_id.wrapping_add(1),
+            syn.conn_id,
+            seq_nr,
+            now,
+        );
+        c.ack_nr = syn.seq_nr;
+        c.peer_wnd = syn.wnd_size as usize;
+        c.reply_delay = (now as u32).wrapping_sub(syn.timestamp);
+        c.need_ack = true;
+        c.flush(now);
+        c
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    /// Received data, to be consumed once read
+    pub fn data(&self) -> &[u8] {
+        &self.inbuf
+    }
+
+    pub fn consume(&mut self, amnt: usize) {
+        self.inbuf.drain(..amnt);
+    }
+
+    /// Whether the remote closed the connection and all of its
+    /// data was read.
+    pub fn eof(&self) -> bool {
+        self.eof && self.inbuf.is_empty()
+    }
+
+    /// Amount of data which can be written
+    pub fn capacity(&self) -> usize {
+        if self.closing || self.state == State::Failed {
+            return 0;
+        }
+        SEND_BUF.saturating_sub(self.outbuf.len() + self.inflight)
+    }
+
+    /// Queues data for sending, returning the amount accepted.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let amnt = cmp::min(data.len(), self.capacity());
+        self.outbuf.extend_from_slice(&data[..amnt]);
+        amnt
+    }
+
+    /// Sends a FIN once all written data is sent.
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    /// Packets to send, encoded
+    pub fn take_transmit(&mut self) -> Vec<Vec<u8>> {
+        mem::replace(&mut self.transmit, Vec::new())
+    }
+
+    pub fn recv(&mut self, pkt: Packet, now: u64) {
+        if self.state == State::Failed {
+            return;
+        }
+        if pkt.kind == Type::Reset {
+            debug!("uTP connection {} reset", self.recv_id);
+            self.state = State::Failed;
+            return;
+        }
+        self.reply_delay = (now as u32).wrapping_sub(pkt.timestamp);
+        self.peer_wnd = pkt.wnd_size as usize;
+        if pkt.kind == Type::Syn {
+            // Our reply was lost
+            if self.state != State::SynSent {
+                self.need_ack = true;
+            }
+            return;
+        }
+        if self.state == State::SynSent {
+            if pkt.kind != Type::State || pkt.ack_nr != self.seq_nr.wrapping_sub(1) {
+                return;
+            }
+            self.state = State::Connected;
+            self.ack_nr = pkt.seq_nr.wrapping_sub(1);
+        }
+        self.sample_delay(pkt.timestamp_diff, now);
+        self.handle_ack(&pkt, now);
+        match pkt.kind {
+            Type::Data | Type::Fin => self.handle_data(pkt),
+            _ => {}
+        }
+    }
+
+    /// Packetizes written data as the windows allow, and acks
+    /// received data.
+    pub fn flush(&mut self, now: u64) {
+        if self.state == State::Failed {
+            return;
+        }
+        let sent = self.transmit.len();
+        if self.state == State::Connected {
+            while !self.outbuf.is_empty() {
+                let len = cmp::min(MSS, self.outbuf.len());
+                let wnd = cmp::min(self.cwnd, self.peer_wnd);
+                if self.inflight > 0 && self.inflight + len > wnd {
+                    break;
+                }
+                let payload = self.outbuf.drain(..len).collect();
+                self.send_new(Type::Data, payload, now);
+            }
+            if self.closing && self.outbuf.is_empty() && !self.fin_sent {
+                self.fin_sent = true;
+                self.send_new(Type::Fin, Vec::new(), now);
+            }
+        }
+        if self.need_ack && self.transmit.len() == sent {
+            let mut p = Packet::new(Type::State, self.send_id, self.seq_nr, self.ack_nr);
+            self.stamp(&mut p, now);
+            self.transmit.push(p.encode());
+        }
+        self.need_ack = false;
+    }
+
+    /// Retransmits on timeout, failing the connection after
+    /// too many retries.
+    pub fn tick(&mut self, now: u64) {
+        match self.timeout_at {
+            Some(t) if now >= t => {}
+            _ => return,
+        }
+        self.retries += 1;
+        let limit = if self.state == State::SynSent {
+            SYN_RETRIES
+        } else {
+            MAX_RETRIES
+        };
+        if self.retries > limit {
+            debug!("uTP connection {} timed out", self.recv_id);
+            self.state = State::Failed;
+            return;
+        }
+        self.cwnd = MIN_CWND;
+        self.rto = cmp::min(self.rto * 2, MAX_RTO);
+        self.resend(0, now);
+        self.timeout_at = Some(now + self.rto);
+    }
+
+    fn send_new(&mut self, kind: Type, payload: Vec<u8>, now: u64) {
+        let conn_id = if kind == Type::Syn {
+            self.recv_id
+        } else {
+            self.send_id
+        };
+        let mut packet = Packet::new(kind, conn_id, self.seq_nr, self.ack_nr);
+        packet.payload = payload;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.inflight += packet.payload.len();
+        self.stamp(&mut packet, now);
+        self.transmit.push(packet.encode());
+        self.unacked.push_back(Sent {
+            packet,
+            sent_at: now,
+            resends: 0,
+        });
+        if self.timeout_at.is_none() {
+            self.timeout_at = Some(now + self.rto);
+        }
+    }
+
+    fn resend(&mut self, idx: usize, now: u64) {
+        let mut packet = match self.unacked.get_mut(idx) {
+            Some(s) => {
+                s.resends += 1;
+                s.sent_at = now;
+                s.packet.clone()
+            }
+            None => return,
+        };
+        self.stamp(&mut packet, now);
+        self.transmit.push(packet.encode());
+    }
+
+    fn stamp(&self, p: &mut Packet, now: u64) {
+        p.timestamp = now as u32;
+        p.timestamp_diff = self.reply_delay;
+        p.wnd_size = RECV_BUF.saturating_sub(self.inbuf.len()) as u32;
+        p.ack_nr = self.ack_nr;
+        p.sack = self.sack();
+    }
+
+    /// Bitmask of the packets received out of order
+    fn sack(&self) -> Option<Vec<u8>> {
+        if self.reorder.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0u8; 4];
+        for seq in self.reorder.keys() {
+            let i = seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if i < 32 {
+                mask[i / 8] |= 1 << (i % 8);
+            }
+        }
+        Some(mask)
+    }
+
+    fn handle_ack(&mut self, pkt: &Packet, now: u64) {
+        let inflight = self.inflight;
+        let mut acked = 0;
+        let mut acked_pkts = 0;
+        let mut rtt = None;
+        while self
+            .unacked
+            .front()
+            .map(|s| !seq_lt(pkt.ack_nr, s.packet.seq_nr))
+            .unwrap_or(false)
+        {
+            let s = self.unacked.pop_front().unwrap();
+            acked += s.packet.payload.len();
+            acked_pkts += 1;
+            if s.resends == 0 {
+                rtt = Some(now.saturating_sub(s.sent_at));
+            }
+        }
+        let mut sacked = 0;
+        if let Some(ref sack) = pkt.sack {
+            for i in 0..sack.len() * 8 {
+                if sack[i / 8] & (1 << (i % 8)) == 0 {
+                    continue;
+                }
+                sacked += 1;
+                let seq = pkt.ack_nr.wrapping_add(2 + i as u16);
+                if let Some(pos) = self.unacked.iter().position(|s| s.packet.seq_nr == seq) {
+                    let s = self.unacked.remove(pos).unwrap();
+                    acked += s.packet.payload.len();
+                    acked_pkts += 1;
+                }
+            }
+        }
+        self.inflight -= acked;
+
+        if acked_pkts > 0 {
+            self.retries = 0;
+            self.dup_acks = 0;
+            if let Some(r) = rtt {
+                self.update_rtt(r);
+            }
+            if acked > 0 {
+                self.ledbat(acked, inflight);
+            }
+            self.timeout_at = if self.unacked.is_empty() {
+                None
+            } else {
+                Some(now + self.rto)
+            };
+            if self.fin_sent && self.unacked.is_empty() {
+                self.state = State::Closed;
+            }
+        } else if pkt.kind == Type::State && !self.unacked.is_empty() {
+            self.dup_acks += 1;
+        }
+        // The packet following the ack was lost if several after it
+        // arrived, or the same ack was repeated
+        if (sacked >= 3 && acked_pkts > 0) || self.dup_acks == 3 {
+            let lost = self
+                .unacked
+                .front()
+                .map(|s| s.packet.seq_nr == pkt.ack_nr.wrapping_add(1) && s.resends == 0)
+                .unwrap_or(false);
+            if lost {
+                self.cwnd = cmp::max(self.cwnd / 2, MIN_CWND);
+                self.resend(0, now);
+            }
+        }
+    }
+
+    fn handle_data(&mut self, pkt: Packet) {
+        self.need_ack = true;
+        let dist = pkt.seq_nr.wrapping_sub(self.ack_nr);
+        if self.eof || dist == 0 || dist > MAX_REORDER {
+            return;
+        }
+        self.reorder.insert(pkt.seq_nr, pkt);
+        while let Some(p) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = p.seq_nr;
+            if p.kind == Type::Fin {
+                self.eof = true;
+                self.reorder.clear();
+                break;
+            }
+            self.inbuf.extend_from_slice(&p.payload);
+        }
+    }
+
+    fn update_rtt(&mut self, rtt: u64) {
+        let (srtt, var) = match self.srtt {
+            None => (rtt, rtt / 2),
+            Some((srtt, var)) => {
+                let diff = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                ((srtt * 7 + rtt) / 8, (var * 3 + diff) / 4)
+            }
+        };
+        self.srtt = Some((srtt, var));
+        self.rto = cmp::max(srtt + 4 * var, MIN_RTO);
+    }
+
+    /// Records the one way delay of our packets reported by the
+    /// remote. Clocks aren't synchronized, so only the difference
+    /// to the lowest delay seen is meaningful.
+    fn sample_delay(&mut self, delay: u32, now: u64) {
+        if delay == 0 {
+            return;
+        }
+        if now >= self.delay_interval + BASE_DELAY_INTERVAL {
+            self.base_delay = [None, self.base_delay[0]];
+            self.delay_interval = now;
+        }
+        self.base_delay[0] = Some(delay_min(self.base_delay[0], delay));
+        let base = delay_min(self.base_delay[1], self.base_delay[0].unwrap());
+        self.our_delay = cmp::max(i64::from(delay.wrapping_sub(base) as i32), 0);
+    }
+
+    /// Adjusts the congestion window as per LEDBAT, growing it while the
+    /// queuing delay is below target and shrinking it above.
+    fn ledbat(&mut self, acked: usize, inflight: usize) {
+        let delay_factor = (TARGET_DELAY - self.our_delay) as f64 / TARGET_DELAY as f64;
+        let window_factor = acked as f64 / cmp::max(inflight, acked) as f64;
+        let cwnd = self.cwnd as f64 + MAX_CWND_INCREASE * delay_factor * window_factor;
+        self.cwnd = cmp::min(cmp::max(cwnd as usize, MIN_CWND), RECV_BUF);
+    }
+}
+
+/// Whether sequence number a precedes b, accounting for wrapping
+fn seq_lt(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+fn delay_min(a: Option<u32>, b: u32) -> u32 {
+    match a {
+        Some(a) if (a.wrapping_sub(b) as i32) < 0 => a,
+        _ => b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Conn, State, MIN_CWND, MSS};
+    use utp::packet::{Packet, Type};
+
+    /// Delivers packets from a to b, dropping those `drop` returns true for.
+    fn deliver<F: FnMut(usize) -> bool>(a: &mut Conn, b: &mut Conn, now: u64, mut drop: F) {
+        for (i, data) in a.take_transmit().into_iter().enumerate() {
+            if !drop(i) {
+                b.recv(Packet::decode(&data).unwrap(), now);
+            }
+        }
+    }
+
+    fn pair(now: u64) -> (Conn, Conn) {
+        let mut a = Conn::connect(100, now);
+        let syn = Packet::decode(&a.take_transmit()[0]).unwrap();
+        assert_eq!(syn.kind, Type::Syn);
+        let mut b = Conn::accept(&syn, 5000, now);
+        deliver(&mut b, &mut a, now, |_| false);
+        assert_eq!(a.state(), State::Connected);
+        (a, b)
+    }
+
+    fn transfer(loss: usize) {
+        let mut now = 0;
+        let (mut a, mut b) = pair(now);
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut sent = 0;
+        while received.len() < data.len() {
+            now += 10_000;
+            assert!(now < 600_000_000, "transfer stalled");
+            written += a.write(&data[written..]);
+            a.flush(now);
+            a.tick(now);
+            deliver(&mut a, &mut b, now, |_| {
+                sent += 1;
+                loss != 0 && sent % loss == 0
+            });
+            received.extend_from_slice(b.data());
+            let amnt = b.data().len();
+            b.consume(amnt);
+            b.flush(now);
+            deliver(&mut b, &mut a, now, |_| false);
+        }
+        assert_eq!(received, data);
+
+        a.close();
+        for _ in 0..10 {
+            now += 10_000;
+            a.flush(now);
+            a.tick(now);
+            deliver(&mut a, &mut b, now, |_| false);
+            b.flush(now);
+            deliver(&mut b, &mut a, now, |_| false);
+        }
+        assert!(b.eof());
+        assert_eq!(a.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_transfer() {
+        transfer(0);
+    }
+
+    #[test]
+    fn test_transfer_loss() {
+        transfer(7);
+    }
+
+    #[test]
+    fn test_ledbat() {
+        let (mut a, _) = pair(0);
+        a.write(&vec![0u8; 100 * MSS]);
+        a.flush(0);
+        let mut ack = 2;
+        let mut send_ack = |a: &mut Conn, delay: u32| {
+            let mut p = Packet::new(Type::State, 100, 5000, ack);
+            p.timestamp_diff = delay;
+            p.wnd_size = 1 << 20;
+            ack += 1;
+            a.recv(p, 10_000);
+            a.flush(10_000);
+        };
+        // No queuing delay grows the window
+        for _ in 0..20 {
+            send_ack(&mut a, 20_000);
+        }
+        let grown = a.cwnd;
+        assert!(grown > MIN_CWND);
+        // Delay over the target shrinks it again
+        for _ in 0..20 {
+            send_ack(&mut a, 20_000 + 250_000);
+        }
+        assert!(a.cwnd < grown);
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let mut a = Conn::connect(1, 0);
+        let mut now = 0;
+        while a.state() == State::SynSent {
+            now += 100_000;
+            a.tick(now);
+        }
+        assert_eq!(a.state(), State::Failed);
+        // Retries back off, giving up after several seconds
+        assert!(now > 5_000_000 && now < 30_000_000);
+        assert_eq!(a.take_transmit().len(), 4);
+    }
+
+    #[test]
+    fn test_reset() {
+        let (mut a, _) = pair(0);
+        a.recv(Packet::new(Type::Reset, 100, 0, 0), 0);
+        assert_eq!(a.state(), State::Failed);
+        assert_eq!(a.capacity(), 0);
+    }
+}
@@ -0,0 +1,285 @@
+//! uTP (BEP 29), a peer transport over UDP which yields bandwidth to
+//! other traffic. Connections share the DHT's socket, and each is relayed
+//! to the rest of the client through one end of a Unix socket pair, so
+//! that a peer's `Socket` works the same over either transport.
+
+mod conn;
+mod packet;
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, UdpSocket};
+use std::os::unix::net::UnixStream;
+use std::{cmp, time};
+
+use amy;
+use rand;
+
+use self::conn::{Conn, State};
+pub use self::packet::is_utp;
+use self::packet::{Packet, Type};
+use util::UHashMap;
+use CONFIG;
+
+pub struct Mux {
+    sock: UdpSocket,
+    reg: amy::Registrar,
+    conns: HashMap<(SocketAddr, u16), Entry>,
+    /// Connections by the registrar id of their stream
+    ids: UHashMap<(SocketAddr, u16)>,
+    start: time::Instant,
+    buf: Vec<u8>,
+}
+
+struct Entry {
+    conn: Conn,
+    stream: UnixStream,
+    id: usize,
+    /// Whether the remote's FIN was passed on to the stream
+    shutdown: bool,
+}
+
+impl Mux {
+    pub fn new(sock: UdpSocket, reg: &amy::Registrar) -> Mux {
+        Mux {
+            sock,
+            reg: reg.clone(),
+            conns: HashMap::new(),
+            ids: UHashMap::default(),
+            start: time::Instant::now(),
+            buf: vec![0u8; 16_384],
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.ids.contains_key(&id)
+    }
+
+    /// Connects to `addr`, relaying data written to the other end
+    /// of `stream`.
+    pub fn connect(&mut self, addr: SocketAddr, stream: UnixStream) {
+        let mut recv_id = rand::random::<u16>();
+        while self.conns.contains_key(&(addr, recv_id)) {
+            recv_id = rand::random();
+        }
+        let conn = Conn::connect(recv_id, self.now());
+        self.add(addr, conn, stream);
+    }
+
+    /// Handles a datagram received on the socket, returning the
+    /// remote end of the stream of a newly accepted connection.
+    pub fn recv(&mut self, data: &[u8], addr: SocketAddr) -> Option<UnixStream> {
+        let pkt = Packet::decode(data)?;
+        let now = self.now();
+        let key = if pkt.kind == Type::Syn {
+            (addr, pkt.conn_id.wrapping_add(1))
+        } else {
+            (addr, pkt.conn_id)
+        };
+        if let Some(e) = self.conns.get_mut(&key) {
+            e.conn.recv(pkt, now);
+        } else if pkt.kind == Type::Syn {
+            return self.accept(&pkt, addr, now);
+        } else {
+            if pkt.kind != Type::Reset {
+                let reset = Packet::new(Type::Reset, pkt.conn_id, rand::random(), pkt.seq_nr);
+                send(&self.sock, &reset.encode(), addr);
+            }
+            return None;
+        }
+        self.pump(key);
+        None
+    }
+
+    /// Relays data of the stream registered as `id`
+    pub fn stream_ready(&mut self, id: usize) {
+        if let Some(key) = self.ids.get(&id).cloned() {
+            self.pump(key);
+        }
+    }
+
+    pub fn tick(&mut self) {
+        let now = self.now();
+        let keys: Vec<_> = self.conns.keys().cloned().collect();
+        for key in keys {
+            if let Some(e) = self.conns.get_mut(&key) {
+                e.conn.tick(now);
+            }
+            self.pump(key);
+        }
+    }
+
+    fn accept(&mut self, syn: &Packet, addr: SocketAddr, now: u64) -> Option<UnixStream> {
+        if self.conns.len() >= CONFIG.net.max_open_sockets {
+            return None;
+        }
+        let (local, remote) = UnixStream::pair().ok()?;
+        debug!("Accepted uTP connection from {}", addr);
+        let conn = Conn::accept(syn, rand::random(), now);
+        if self.add(addr, conn, local) {
+            Some(remote)
+        } else {
+            None
+        }
+    }
+
+    fn add(&mut self, addr: SocketAddr, conn: Conn, stream: UnixStream) -> bool {
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let id = match self.reg.register(&stream, amy::Event::Both) {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Failed to register uTP stream: {}", e);
+                return false;
+            }
+        };
+        let key = (addr, conn.recv_id());
+        self.ids.insert(id, key);
+        self.conns.insert(
+            key,
+            Entry {
+                conn,
+                stream,
+                id,
+                shutdown: false,
+            },
+        );
+        self.pump(key);
+        true
+    }
+
+    /// Moves data between a connection and its stream, sending any
+    /// resulting packets and removing the connection once it's done.
+    fn pump(&mut self, key: (SocketAddr, u16)) {
+        let now = self.now();
+        let done = match self.conns.get_mut(&key) {
+            Some(e) => {
+                let done = e.relay(now, &mut self.buf);
+                for data in e.conn.take_transmit() {
+                    send(&self.sock, &data, key.0);
+                }
+                done
+            }
+            None => return,
+        };
+        if done {
+            let e = self.conns.remove(&key).unwrap();
+            self.ids.remove(&e.id);
+            self.reg.deregister(&e.stream).ok();
+        }
+    }
+
+    fn now(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros())
+    }
+}
+
+impl Entry {
+    /// Returns whether the connection is finished.
+    fn relay(&mut self, now: u64, buf: &mut [u8]) -> bool {
+        while !self.conn.data().is_empty() {
+            match self.stream.write(self.conn.data()) {
+                Ok(amnt) => self.conn.consume(amnt),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return true,
+            }
+        }
+        if self.conn.eof() && !self.shutdown {
+            self.stream.shutdown(Shutdown::Write).ok();
+            self.shutdown = true;
+        }
+        while self.conn.capacity() > 0 {
+            let amnt = cmp::min(buf.len(), self.conn.capacity());
+            match self.stream.read(&mut buf[..amnt]) {
+                Ok(0) => self.conn.close(),
+                Ok(amnt) => {
+                    self.conn.write(&buf[..amnt]);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => self.conn.close(),
+            }
+        }
+        self.conn.flush(now);
+        match self.conn.state() {
+            State::Closed | State::Failed => true,
+            State::SynSent | State::Connected => false,
+        }
+    }
+}
+
+fn send(sock: &UdpSocket, data: &[u8], addr: SocketAddr) {
+    if let Err(e) = sock.send_to(data, addr) {
+        debug!("Failed to send uTP packet to {}: {}", addr, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mux;
+    use amy;
+    use std::io::{Read, Write};
+    use std::net::UdpSocket;
+    use std::os::unix::net::UnixStream;
+    use std::{thread, time};
+
+    fn socket() -> UdpSocket {
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        s.set_nonblocking(true).unwrap();
+        s
+    }
+
+    /// Passes datagrams between the muxes for a while, returning the
+    /// streams of connections b accepted.
+    fn exchange(a: &mut Mux, sa: &UdpSocket, b: &mut Mux, sb: &UdpSocket) -> Vec<UnixStream> {
+        let mut accepted = Vec::new();
+        let mut buf = [0u8; 2048];
+        for _ in 0..50 {
+            while let Ok((amnt, from)) = sb.recv_from(&mut buf) {
+                accepted.extend(b.recv(&buf[..amnt], from));
+            }
+            while let Ok((amnt, from)) = sa.recv_from(&mut buf) {
+                a.recv(&buf[..amnt], from);
+            }
+            a.tick();
+            b.tick();
+            thread::sleep(time::Duration::from_millis(2));
+        }
+        accepted
+    }
+
+    #[test]
+    fn test_relay() {
+        let poll = amy::Poller::new().unwrap();
+        let reg = poll.get_registrar();
+        let (sa, sb) = (socket(), socket());
+        let mut a = Mux::new(sa.try_clone().unwrap(), &reg);
+        let mut b = Mux::new(sb.try_clone().unwrap(), &reg);
+
+        let (mut local, remote) = UnixStream::pair().unwrap();
+        local.write_all(b"hello over utp").unwrap();
+        a.connect(sb.local_addr().unwrap(), remote);
+        let mut accepted = exchange(&mut a, &sa, &mut b, &sb);
+        assert_eq!(accepted.len(), 1);
+
+        let mut s = accepted.pop().unwrap();
+        s.set_read_timeout(Some(time::Duration::from_secs(1)))
+            .unwrap();
+        let mut data = [0u8; 14];
+        s.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"hello over utp");
+
+        // Closing one end closes the other once the data arrived
+        s.write_all(b"bye").unwrap();
+        drop(s);
+        exchange(&mut a, &sa, &mut b, &sb);
+        local
+            .set_read_timeout(Some(time::Duration::from_secs(1)))
+            .unwrap();
+        let mut data = Vec::new();
+        local.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"bye");
+        assert!(b.conns.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use byteorder::{BigEndian, ByteOrder};
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+const EXT_SACK: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Type {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub kind: Type,
+    pub conn_id: u16,
+    /// Send time in microseconds, on the sender's clock
+    pub timestamp: u32,
+    /// Delay of the last packet received by the sender
+    pub timestamp_diff: u32,
+    /// Receive buffer space of the sender
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Selective ack bitmask, the first bit standing for ack_nr + 2
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+/// Whether a datagram is likely a uTP packet, rather than
+/// a DHT message on the same socket.
+pub fn is_utp(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && data[0] & 0x0f == VERSION && data[0] >> 4 <= Type::Syn as u8
+}
+
+impl Packet {
+    pub fn new(kind: Type, conn_id: u16, seq_nr: u16, ack_nr: u16) -> Packet {
+        Packet {
+            kind,
+            conn_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+            sack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Packet> {
+        if !is_utp(data) {
+            return None;
+        }
+        let kind = match data[0] >> 4 {
+            0 => Type::Data,
+            1 => Type::Fin,
+            2 => Type::State,
+            3 => Type::Reset,
+            _ => Type::Syn,
+        };
+        let mut sack = None;
+        let mut ext = data[1];
+        let mut pos = HEADER_LEN;
+        while ext != 0 {
+            if data.len() < pos + 2 || data.len() < pos + 2 + data[pos + 1] as usize {
+                return None;
+            }
+            let len = data[pos + 1] as usize;
+            if ext == EXT_SACK {
+                sack = Some(data[pos + 2..pos + 2 + len].to_vec());
+            }
+            ext = data[pos];
+            pos += 2 + len;
+        }
+        Some(Packet {
+            kind,
+            conn_id: BigEndian::read_u16(&data[2..4]),
+            timestamp: BigEndian::read_u32(&data[4..8]),
+            timestamp_diff: BigEndian::read_u32(&data[8..12]),
+            wnd_size: BigEndian::read_u32(&data[12..16]),
+            seq_nr: BigEndian::read_u16(&data[16..18]),
+            ack_nr: BigEndian::read_u16(&data[18..20]),
+            sack,
+            payload: data[pos..].to_vec(),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let sack_len = self.sack.as_ref().map(|s| s.len() + 2).unwrap_or(0);
+        let mut data = vec![0u8; HEADER_LEN + sack_len];
+        data[0] = (self.kind as u8) << 4 | VERSION;
+        BigEndian::write_u16(&mut data[2..4], self.conn_id);
+        BigEndian::write_u32(&mut data[4..8], self.timestamp);
+        BigEndian::write_u32(&mut data[8..12], self.timestamp_diff);
+        BigEndian::write_u32(&mut data[12..16], self.wnd_size);
+        BigEndian::write_u16(&mut data[16..18], self.seq_nr);
+        BigEndian::write_u16(&mut data[18..20], self.ack_nr);
+        if let Some(ref sack) = self.sack {
+            data[1] = EXT_SACK;
+            data[HEADER_LEN + 1] = sack.len() as u8;
+            data[HEADER_LEN + 2..].copy_from_slice(sack);
+        }
+        data.extend_from_slice(&self.payload);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_utp, Packet, Type};
+
+    #[test]
+    fn test_encoding() {
+        let mut p = Packet::new(Type::Data, 0x1234, 10, 7);
+        p.timestamp = 1_000_000;
+        p.wnd_size = 65536;
+        p.sack = Some(vec![0b101, 0, 0, 0]);
+        p.payload = b"hello".to_vec();
+        let data = p.encode();
+        assert_eq!(data[0], 0x01);
+        assert_eq!(data.len(), 20 + 6 + 5);
+        assert_eq!(Packet::decode(&data), Some(p));
+
+        // Unknown extensions are skipped
+        let mut data = Packet::new(Type::State, 1, 2, 3).encode();
+        data[1] = 2;
+        data.extend_from_slice(&[0, 2, 0xff, 0xff]);
+        let p = Packet::decode(&data).unwrap();
+        assert_eq!(p.kind, Type::State);
+        assert!(p.sack.is_none() && p.payload.is_empty());
+
+        // Truncated extension
+        data.pop();
+        assert!(Packet::decode(&data).is_none());
+    }
+
+    #[test]
+    fn test_dht_not_utp() {
+        assert!(!is_utp(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        ));
+        assert!(!is_utp(&[0x64; 20]));
+        assert!(is_utp(&Packet::new(Type::Syn, 1, 1, 0).encode()));
+    }
+}
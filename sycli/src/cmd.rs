@@ -54,6 +54,8 @@ fn add_file(
         path: dir.as_ref().map(|d| format!("{}", d)),
         start,
         import,
+        files: None,
+        allocation: None,
     };
     let token = if let SMessage::TransferOffer { token, .. } = c.rr(msg)? {
         token
@@ -72,6 +74,9 @@ fn add_file(
         SMessage::ResourcesExtant { ids, .. } => {
             get_(c, ids[0].as_ref(), "text")?;
         }
+        SMessage::TorrentMerged { id, .. } => {
+            get_(c, &id, "text")?;
+        }
         SMessage::InvalidRequest(message::Error { reason, .. }) => {
             bail!("{}", reason);
         }
@@ -91,6 +96,7 @@ fn add_magnet(c: &mut Client, magnet: Url, dir: Option<&str>, start: bool) -> Re
         uri: magnet.as_str().to_owned(),
         path: dir.as_ref().map(|d| format!("{}", d)),
         start,
+        allocation: None,
     };
     match c.rr(msg)? {
         SMessage::ResourcesExtant { ids, .. } => {
@@ -527,24 +533,25 @@ fn remove_res(c: &mut Client, res: &str) -> Result<()> {
     }
 }
 
-pub fn add_peers(mut c: Client, id: &str, peers: Vec<&str>) -> Result<()> {
+pub fn add_peers(mut c: Client, id: &str, peers: Vec<&str>, preferred: bool) -> Result<()> {
     let torrent = search_torrent_name(&mut c, id)?;
     if torrent.len() != 1 {
         bail!("Could not find appropriate torrent!");
     }
     for peer in peers {
-        if let Err(e) = add_peer(&mut c, torrent[0].id(), peer) {
+        if let Err(e) = add_peer(&mut c, torrent[0].id(), peer, preferred) {
             eprintln!("Failed to add peer {}: {}", peer, e);
         }
     }
     Ok(())
 }
 
-fn add_peer(c: &mut Client, id: &str, peer: &str) -> Result<()> {
+fn add_peer(c: &mut Client, id: &str, peer: &str, preferred: bool) -> Result<()> {
     let msg = CMessage::AddPeer {
         serial: c.next_serial(),
         id: id.to_owned(),
         ip: peer.to_owned(),
+        preferred,
     };
     match c.rr(msg)? {
         SMessage::ResourcePending { .. } => Ok(()),
@@ -259,6 +259,11 @@ fn main() {
                         .subcommands(vec![
                             SubCommand::with_name("add")
                                 .about("Add peers to a torrent")
+                                .arg(
+                                    Arg::with_name("preferred")
+                                        .help("Keep the peers unchoked and request from them as a primary source.")
+                                        .long("preferred"),
+                                )
                                 .arg(
                                     Arg::with_name("peer ip")
                                         .help("IPs of peers to add")
@@ -551,15 +556,12 @@ fn main() {
                     let sscmd = subcmd.subcommand_matches("peer").unwrap();
                     match sscmd.subcommand_name().unwrap() {
                         "add" => {
+                            let args = sscmd.subcommand_matches("add").unwrap();
                             if let Err(e) = cmd::add_peers(
                                 client,
                                 id,
-                                sscmd
-                                    .subcommand_matches("add")
-                                    .unwrap()
-                                    .values_of("peer ip")
-                                    .unwrap()
-                                    .collect(),
+                                args.values_of("peer ip").unwrap().collect(),
+                                args.is_present("preferred"),
                             ) {
                                 eprintln!("Failed to add peers: {}", e.display_chain());
                                 process::exit(1);
@@ -262,6 +262,7 @@ impl State {
                         resp.push(SMessage::ResourcesRemoved {
                             serial,
                             ids: removed,
+                            archived: None,
                         });
                     }
                 } else {
@@ -374,6 +375,7 @@ impl State {
                         SMessage::ResourcesRemoved {
                             serial,
                             ids: ids.into_iter().map(|s| s.into_owned()).collect(),
+                            archived: None,
                         },
                     ));
                 }